@@ -1,5 +1,7 @@
 use serde::ser::{Serialize, SerializeMap, Serializer};
 
+use crate::size_estimate::{ProfileSizeEstimate, COUNTER_SAMPLE_BYTES};
+use crate::timestamp::{clamp_timestamp, extend_bounds};
 use crate::{ProcessHandle, Timestamp};
 
 /// A counter. Can be created with [`Profile::add_counter`](crate::Profile::add_counter).
@@ -48,6 +50,49 @@ impl Counter {
             .add_sample(timestamp, value_delta, number_of_operations_delta)
     }
 
+    /// Returns the number of samples whose timestamp was changed.
+    pub fn clamp_timestamps(&mut self, start: Timestamp, end: Timestamp) -> usize {
+        self.samples
+            .time
+            .iter_mut()
+            .map(|t| clamp_timestamp(t, start, end))
+            .filter(|&changed| changed)
+            .count()
+    }
+
+    pub fn extend_timestamp_bounds(&self, bounds: &mut Option<(Timestamp, Timestamp)>) {
+        for timestamp in &self.samples.time {
+            extend_bounds(bounds, *timestamp);
+        }
+    }
+
+    /// Returns a copy with only the samples in `start..end`.
+    pub fn time_slice(&self, start: Timestamp, end: Timestamp) -> Self {
+        let mut slice = Counter {
+            name: self.name.clone(),
+            category: self.category.clone(),
+            description: self.description.clone(),
+            process: self.process,
+            pid: self.pid.clone(),
+            samples: CounterSamples::new(),
+        };
+        let samples = &self.samples;
+        for (i, timestamp) in samples.time.iter().enumerate() {
+            if (start..end).contains(timestamp) {
+                slice
+                    .samples
+                    .add_sample(*timestamp, samples.count[i], samples.number[i]);
+            }
+        }
+        slice
+    }
+
+    pub fn add_to_size_estimate(&self, estimate: &mut ProfileSizeEstimate) {
+        for timestamp in &self.samples.time {
+            estimate.add_at(*timestamp, COUNTER_SAMPLE_BYTES);
+        }
+    }
+
     pub fn as_serializable(&self, main_thread_index: usize) -> impl Serialize + '_ {
         SerializableCounter {
             counter: self,
@@ -10,6 +10,7 @@ use crate::global_lib_table::{GlobalLibIndex, GlobalLibTable};
 use crate::native_symbols::{NativeSymbolIndex, NativeSymbols};
 use crate::resource_table::ResourceTable;
 use crate::serialization_helpers::SerializableSingleValueColumn;
+use crate::size_estimate::FRAME_BYTES;
 use crate::thread_string_table::{ThreadInternalStringIndex, ThreadStringTable};
 
 #[derive(Debug, Clone, Default)]
@@ -27,6 +28,10 @@ impl FrameTable {
         Default::default()
     }
 
+    pub fn estimated_json_size(&self) -> usize {
+        self.addresses.len() * FRAME_BYTES
+    }
+
     pub fn index_for_frame(
         &mut self,
         string_table: &mut ThreadStringTable,
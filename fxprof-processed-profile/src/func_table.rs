@@ -4,6 +4,7 @@ use crate::fast_hash_map::FastHashMap;
 use crate::frame::FrameFlags;
 use crate::resource_table::ResourceIndex;
 use crate::serialization_helpers::SerializableSingleValueColumn;
+use crate::size_estimate::FUNC_BYTES;
 use crate::thread_string_table::ThreadInternalStringIndex;
 
 #[derive(Debug, Clone, Default)]
@@ -21,6 +22,10 @@ impl FuncTable {
         Default::default()
     }
 
+    pub fn estimated_json_size(&self) -> usize {
+        self.names.len() * FUNC_BYTES
+    }
+
     pub fn index_for_func(
         &mut self,
         name: ThreadInternalStringIndex,
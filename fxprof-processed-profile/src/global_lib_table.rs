@@ -1,11 +1,14 @@
 use std::sync::Arc;
 
+use debugid::DebugId;
 use serde::ser::{Serialize, Serializer};
 
 use crate::fast_hash_map::FastHashMap;
-use crate::{LibraryInfo, SymbolTable};
+use crate::library_info::SerializableLib;
+use crate::size_estimate::LIB_BYTES;
+use crate::{EmbeddedCode, LibraryInfo, SymbolTable};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GlobalLibTable {
     /// All libraries added via `Profile::add_lib`. May or may not be used.
     /// Indexed by `LibraryHandle.0`.
@@ -14,6 +17,8 @@ pub struct GlobalLibTable {
     used_libs: Vec<LibraryHandle>, // append-only for stable GlobalLibIndexes
     lib_map: FastHashMap<LibraryInfo, LibraryHandle>,
     used_lib_map: FastHashMap<LibraryHandle, GlobalLibIndex>,
+    /// The code which was embedded with `Profile::set_lib_code`.
+    code_by_lib: FastHashMap<LibraryHandle, Arc<EmbeddedCode>>,
 }
 
 impl GlobalLibTable {
@@ -23,6 +28,7 @@ impl GlobalLibTable {
             used_libs: Vec::new(),
             lib_map: FastHashMap::default(),
             used_lib_map: FastHashMap::default(),
+            code_by_lib: FastHashMap::default(),
         }
     }
 
@@ -39,6 +45,25 @@ impl GlobalLibTable {
         self.all_libs[library.0].symbol_table = Some(symbol_table);
     }
 
+    pub fn set_lib_code(&mut self, library: LibraryHandle, code: Arc<EmbeddedCode>) {
+        self.code_by_lib.insert(library, code);
+    }
+
+    pub fn set_lib_ids(
+        &mut self,
+        library: LibraryHandle,
+        debug_id: DebugId,
+        code_id: Option<String>,
+    ) {
+        let lib = &mut self.all_libs[library.0];
+        lib.debug_id = debug_id;
+        lib.code_id = code_id;
+    }
+
+    pub fn lib_info(&self, library: LibraryHandle) -> &LibraryInfo {
+        &self.all_libs[library.0]
+    }
+
     pub fn index_for_used_lib(&mut self, lib_handle: LibraryHandle) -> GlobalLibIndex {
         let used_libs = &mut self.used_libs;
         *self.used_lib_map.entry(lib_handle).or_insert_with(|| {
@@ -48,6 +73,16 @@ impl GlobalLibTable {
         })
     }
 
+    pub fn estimated_json_size(&self) -> usize {
+        let code_bytes: usize = self
+            .used_libs
+            .iter()
+            .filter_map(|handle| self.code_by_lib.get(handle))
+            .map(|code| code.byte_count() * 2)
+            .sum();
+        self.used_libs.len() * LIB_BYTES + code_bytes
+    }
+
     pub fn get_lib(&self, index: GlobalLibIndex) -> Option<&LibraryInfo> {
         let handle = self.used_libs.get(index.0)?;
         self.all_libs.get(handle.0)
@@ -56,7 +91,10 @@ impl GlobalLibTable {
 
 impl Serialize for GlobalLibTable {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.collect_seq(self.used_libs.iter().map(|handle| &self.all_libs[handle.0]))
+        serializer.collect_seq(self.used_libs.iter().map(|handle| SerializableLib {
+            info: &self.all_libs[handle.0],
+            code: self.code_by_lib.get(handle).map(|code| &**code),
+        }))
     }
 }
 
@@ -52,6 +52,7 @@ mod reference_timestamp;
 mod resource_table;
 mod sample_table;
 mod serialization_helpers;
+mod size_estimate;
 mod stack_table;
 mod string_table;
 mod thread;
@@ -65,10 +66,12 @@ pub use cpu_delta::CpuDelta;
 pub use frame::{Frame, FrameFlags, FrameInfo};
 pub use global_lib_table::LibraryHandle;
 pub use lib_mappings::LibMappings;
-pub use library_info::{LibraryInfo, Symbol, SymbolTable};
+pub use library_info::{EmbeddedCode, LibraryInfo, Symbol, SymbolTable};
 pub use markers::*;
 pub use process::ThreadHandle;
 pub use profile::{Profile, SamplingInterval, StringHandle};
 pub use reference_timestamp::ReferenceTimestamp;
+pub use sample_table::WeightType;
+pub use size_estimate::ProfileSizeEstimate;
 pub use thread::ProcessHandle;
 pub use timestamp::*;
@@ -61,9 +61,16 @@ pub struct LibraryInfo {
 
 impl Serialize for LibraryInfo {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        self.serialize_entries(&mut map)?;
+        map.end()
+    }
+}
+
+impl LibraryInfo {
+    fn serialize_entries<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
         let breakpad_id = self.debug_id.breakpad().to_string();
         let code_id = self.code_id.as_ref().map(|cid| cid.to_string());
-        let mut map = serializer.serialize_map(None)?;
         map.serialize_entry("name", &self.name)?;
         map.serialize_entry("path", &self.path)?;
         map.serialize_entry("debugName", &self.debug_name)?;
@@ -71,6 +78,82 @@ impl Serialize for LibraryInfo {
         map.serialize_entry("breakpadId", &breakpad_id)?;
         map.serialize_entry("codeId", &code_id)?;
         map.serialize_entry("arch", &self.arch)?;
+        Ok(())
+    }
+}
+
+/// A library together with the code which is embedded for it, as it's
+/// serialized into the profile's `libs` list.
+pub(crate) struct SerializableLib<'a> {
+    pub info: &'a LibraryInfo,
+    pub code: Option<&'a EmbeddedCode>,
+}
+
+impl<'a> Serialize for SerializableLib<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        self.info.serialize_entries(&mut map)?;
+        if let Some(code) = self.code {
+            map.serialize_entry("embeddedCode", code)?;
+        }
+        map.end()
+    }
+}
+
+/// Machine code of a library which is embedded into the profile, for
+/// libraries whose file won't exist anymore when the profile is viewed, e.g.
+/// JIT code. This lets a symbol server answer assembly requests for the
+/// embedded address ranges.
+///
+/// The code is serialized as the `embeddedCode` property of the library, with
+/// the arch and a list of `{ "start": relativeAddress, "bytes": "<hex>" }`
+/// ranges.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EmbeddedCode {
+    /// The CPU arch of the code, in the format of [`LibraryInfo::arch`], for
+    /// example "x86_64" or "arm64".
+    pub arch: String,
+    /// The code bytes of each range, by the relative address at which the
+    /// range starts.
+    pub ranges: Vec<(u32, Vec<u8>)>,
+}
+
+impl EmbeddedCode {
+    /// The number of code bytes in all ranges.
+    pub fn byte_count(&self) -> usize {
+        self.ranges.iter().map(|(_, bytes)| bytes.len()).sum()
+    }
+}
+
+impl Serialize for EmbeddedCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("arch", &self.arch)?;
+        map.serialize_entry("ranges", &SerializableCodeRanges(&self.ranges))?;
+        map.end()
+    }
+}
+
+struct SerializableCodeRanges<'a>(&'a [(u32, Vec<u8>)]);
+
+impl<'a> Serialize for SerializableCodeRanges<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            self.0
+                .iter()
+                .map(|(start, bytes)| SerializableCodeRange(*start, bytes)),
+        )
+    }
+}
+
+struct SerializableCodeRange<'a>(u32, &'a [u8]);
+
+impl<'a> Serialize for SerializableCodeRange<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = self.1.iter().map(|byte| format!("{byte:02x}")).collect();
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("start", &self.0)?;
+        map.serialize_entry("bytes", &hex)?;
         map.end()
     }
 }
@@ -4,7 +4,9 @@ use serde_json::Value;
 use crate::serialization_helpers::{
     SerializableOptionalTimestampColumn, SerializableSingleValueColumn,
 };
+use crate::size_estimate::{json_size, ProfileSizeEstimate, MARKER_BYTES};
 use crate::thread_string_table::ThreadInternalStringIndex;
+use crate::timestamp::{clamp_timestamp, extend_bounds};
 use crate::{MarkerTiming, Timestamp};
 
 #[derive(Debug, Clone, Default)]
@@ -39,6 +41,62 @@ impl MarkerTable {
         self.marker_phases.push(phase);
         self.marker_datas.push(data);
     }
+
+    /// Returns the number of markers whose start or end was changed.
+    pub fn clamp_timestamps(&mut self, start: Timestamp, end: Timestamp) -> usize {
+        self.marker_starts
+            .iter_mut()
+            .zip(self.marker_ends.iter_mut())
+            .map(|(s, e)| {
+                let s_changed = s.as_mut().map_or(false, |s| clamp_timestamp(s, start, end));
+                let e_changed = e.as_mut().map_or(false, |e| clamp_timestamp(e, start, end));
+                s_changed || e_changed
+            })
+            .filter(|&changed| changed)
+            .count()
+    }
+
+    pub fn extend_timestamp_bounds(&self, bounds: &mut Option<(Timestamp, Timestamp)>) {
+        for timestamp in self.marker_starts.iter().chain(&self.marker_ends).flatten() {
+            extend_bounds(bounds, *timestamp);
+        }
+    }
+
+    /// The timestamp which decides which time slice a marker belongs to: its
+    /// start, or its end if it has no start.
+    fn marker_time(&self, index: usize) -> Option<Timestamp> {
+        self.marker_starts[index].or(self.marker_ends[index])
+    }
+
+    /// Returns a copy with only the markers whose start is in `start..end`,
+    /// or whose end is, for markers without a start.
+    pub fn time_slice(&self, start: Timestamp, end: Timestamp) -> Self {
+        let mut slice = Self::new();
+        let range = start..end;
+        for i in 0..self.marker_name_string_indexes.len() {
+            if !self.marker_time(i).map_or(false, |t| range.contains(&t)) {
+                continue;
+            }
+            slice
+                .marker_name_string_indexes
+                .push(self.marker_name_string_indexes[i]);
+            slice.marker_starts.push(self.marker_starts[i]);
+            slice.marker_ends.push(self.marker_ends[i]);
+            slice.marker_phases.push(self.marker_phases[i]);
+            slice.marker_datas.push(self.marker_datas[i].clone());
+        }
+        slice
+    }
+
+    pub fn add_to_size_estimate(&self, estimate: &mut ProfileSizeEstimate) {
+        for (i, data) in self.marker_datas.iter().enumerate() {
+            let bytes = MARKER_BYTES + json_size(data);
+            match self.marker_time(i) {
+                Some(timestamp) => estimate.add_at(timestamp, bytes),
+                None => estimate.add_fixed(bytes),
+            }
+        }
+    }
 }
 
 impl Serialize for MarkerTable {
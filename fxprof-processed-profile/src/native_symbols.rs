@@ -4,6 +4,7 @@ use crate::{
     fast_hash_map::FastHashMap,
     global_lib_table::GlobalLibIndex,
     library_info::Symbol,
+    size_estimate::NATIVE_SYMBOL_BYTES,
     thread_string_table::{ThreadInternalStringIndex, ThreadStringTable},
 };
 
@@ -24,6 +25,10 @@ impl NativeSymbols {
         Default::default()
     }
 
+    pub fn estimated_json_size(&self) -> usize {
+        self.names.len() * NATIVE_SYMBOL_BYTES
+    }
+
     pub fn symbol_index_and_string_index_for_symbol(
         &mut self,
         lib_index: GlobalLibIndex,
@@ -10,7 +10,7 @@ use crate::Timestamp;
 #[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct ThreadHandle(pub(crate) usize);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Process {
     pid: String,
     name: String,
@@ -44,6 +44,10 @@ impl Process {
         self.end_time = Some(end_time);
     }
 
+    pub fn clear_end_time(&mut self) {
+        self.end_time = None;
+    }
+
     pub fn end_time(&self) -> Option<Timestamp> {
         self.end_time
     }
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use debugid::DebugId;
 use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
 use serde_json::json;
 
@@ -13,12 +14,16 @@ use crate::frame::{Frame, FrameInfo};
 use crate::frame_table::{InternalFrame, InternalFrameLocation};
 use crate::global_lib_table::{GlobalLibTable, LibraryHandle};
 use crate::lib_mappings::LibMappings;
-use crate::library_info::LibraryInfo;
+use crate::library_info::{EmbeddedCode, LibraryInfo};
 use crate::process::{Process, ThreadHandle};
 use crate::reference_timestamp::ReferenceTimestamp;
+use crate::sample_table::WeightType;
+use crate::size_estimate::{ProfileSizeEstimate, PROFILE_BYTES};
 use crate::string_table::{GlobalStringIndex, GlobalStringTable};
 use crate::thread::{ProcessHandle, Thread};
-use crate::{MarkerSchema, MarkerTiming, ProfilerMarker, SymbolTable, Timestamp};
+use crate::{
+    ClampedTimestampCounts, MarkerSchema, MarkerTiming, ProfilerMarker, SymbolTable, Timestamp,
+};
 
 /// The sampling interval used during profile recording.
 ///
@@ -111,6 +116,10 @@ pub struct Profile {
     pub(crate) reference_timestamp: ReferenceTimestamp,
     pub(crate) string_table: GlobalStringTable,
     pub(crate) marker_schemas: FastHashMap<&'static str, MarkerSchema>,
+    /// Sections of (label, value) pairs, for the "extra" profile metadata.
+    pub(crate) extra_info: Vec<(String, Vec<(String, String)>)>,
+    /// See [`Profile::set_sample_weight_unit`].
+    pub(crate) sample_weight_unit: Option<String>,
     used_pids: FastHashMap<u32, u32>,
     used_tids: FastHashMap<u32, u32>,
 }
@@ -137,6 +146,8 @@ impl Profile {
             processes: Vec::new(),
             string_table: GlobalStringTable::new(),
             marker_schemas: FastHashMap::default(),
+            extra_info: Vec::new(),
+            sample_weight_unit: None,
             categories: vec![Category {
                 name: "Other".to_string(),
                 color: CategoryColor::Gray,
@@ -153,6 +164,13 @@ impl Profile {
         self.interval = interval;
     }
 
+    /// Declare that sample weights are counts of an event, e.g. "instructions",
+    /// rather than numbers of samples. The unit is listed in the profile's
+    /// `sampleUnits` metadata.
+    pub fn set_sample_weight_unit(&mut self, unit: &str) {
+        self.sample_weight_unit = Some(unit.to_string());
+    }
+
     /// Change the reference timestamp.
     pub fn set_reference_timestamp(&mut self, reference_timestamp: ReferenceTimestamp) {
         self.reference_timestamp = reference_timestamp;
@@ -163,6 +181,17 @@ impl Profile {
         self.product = product.to_string();
     }
 
+    /// Add an entry to the profile's "extra" metadata, which the Firefox Profiler
+    /// shows in its profile info panel. Entries with the same `section` are
+    /// listed together, under the section's name.
+    pub fn add_extra_info(&mut self, section: &str, label: &str, value: &str) {
+        let entry = (label.to_string(), value.to_string());
+        match self.extra_info.iter_mut().find(|(name, _)| name == section) {
+            Some((_, entries)) => entries.push(entry),
+            None => self.extra_info.push((section.to_string(), vec![entry])),
+        }
+    }
+
     /// Add a category and return its handle.
     ///
     /// Categories are used for stack frames and markers, as part of a "category pair".
@@ -261,6 +290,12 @@ impl Profile {
         self.processes[process.0].set_end_time(end_time);
     }
 
+    /// Remove the end time of a process, e.g. because a new process continues
+    /// it.
+    pub fn clear_process_end_time(&mut self, process: ProcessHandle) {
+        self.processes[process.0].clear_end_time();
+    }
+
     /// Change the name of a process.
     pub fn set_process_name(&mut self, process: ProcessHandle, name: &str) {
         self.processes[process.0].set_name(name);
@@ -275,6 +310,11 @@ impl Profile {
         self.global_libs.handle_for_lib(library)
     }
 
+    /// Get the information which the library was added with, e.g. its name.
+    pub fn lib_info(&self, library: LibraryHandle) -> &LibraryInfo {
+        self.global_libs.lib_info(library)
+    }
+
     /// Set the symbol table for a library.
     ///
     /// This symbol table can also be specified in the [`LibraryInfo`] which is given to
@@ -288,6 +328,28 @@ impl Profile {
         self.global_libs.set_lib_symbol_table(library, symbol_table);
     }
 
+    /// Embed the machine code of some address ranges of a library into the
+    /// profile, for libraries whose file won't be available when the profile
+    /// is viewed, e.g. JIT code. See [`EmbeddedCode`].
+    ///
+    /// Only the code of libraries which are used by a frame is serialized.
+    pub fn set_lib_code(&mut self, library: LibraryHandle, code: Arc<EmbeddedCode>) {
+        self.global_libs.set_lib_code(library, code);
+    }
+
+    /// Set the debug ID and code ID of a library.
+    ///
+    /// This is for libraries whose IDs only become known after the library was added
+    /// with [`Profile::add_lib`], e.g. if they were only found at the end of a stream.
+    pub fn set_lib_ids(
+        &mut self,
+        library: LibraryHandle,
+        debug_id: DebugId,
+        code_id: Option<String>,
+    ) {
+        self.global_libs.set_lib_ids(library, debug_id, code_id);
+    }
+
     /// For a given process, define where in the virtual memory of this process the given library
     /// is mapped.
     ///
@@ -369,6 +431,28 @@ impl Profile {
         self.threads[thread.0].set_name(name);
     }
 
+    /// The name of a thread, if it has one.
+    pub fn thread_name(&self, thread: ThreadHandle) -> Option<&str> {
+        self.threads[thread.0].name()
+    }
+
+    /// The main thread of the process which the thread belongs to.
+    pub fn main_thread_of_process(&self, thread: ThreadHandle) -> Option<ThreadHandle> {
+        let process = self.threads[thread.0].process();
+        self.processes[process.0]
+            .threads()
+            .iter()
+            .copied()
+            .find(|thread| self.threads[thread.0].is_main())
+    }
+
+    /// Assign a thread to a named group of related threads, or remove it from
+    /// its group. Threads of the same group are placed next to each other in
+    /// their process's thread list, at the position of the group's first thread.
+    pub fn set_thread_group(&mut self, thread: ThreadHandle, group: Option<&str>) {
+        self.threads[thread.0].set_group(group);
+    }
+
     /// Change the start time of a thread.
     pub fn set_thread_start_time(&mut self, thread: ThreadHandle, start_time: Timestamp) {
         self.threads[thread.0].set_start_time(start_time);
@@ -379,6 +463,22 @@ impl Profile {
         self.threads[thread.0].set_end_time(end_time);
     }
 
+    /// Remove the end time of a thread, e.g. because a new thread continues
+    /// it.
+    pub fn clear_thread_end_time(&mut self, thread: ThreadHandle) {
+        self.threads[thread.0].clear_end_time();
+    }
+
+    /// Set the unit of the sample weights of a thread. By default, weights
+    /// are sample counts.
+    pub fn set_thread_samples_weight_type(
+        &mut self,
+        thread: ThreadHandle,
+        weight_type: WeightType,
+    ) {
+        self.threads[thread.0].set_samples_weight_type(weight_type);
+    }
+
     /// Turn the string into in a [`StringHandle`], for use in [`Frame::Label`].
     pub fn intern_string(&mut self, s: &str) -> StringHandle {
         StringHandle(self.string_table.index_for_string(s))
@@ -490,6 +590,88 @@ impl Profile {
         self.counters[counter.0].add_sample(timestamp, value_delta, number_of_operations_delta)
     }
 
+    /// Moves all sample, marker and counter sample timestamps which are outside
+    /// of `start..=end` to the closest end of the range.
+    ///
+    /// This is useful if the timestamps come from a source which isn't guaranteed
+    /// to be consistent, so that the front-end doesn't have to deal with items
+    /// which are far away from the rest of the profile.
+    pub fn clamp_timestamps(&mut self, start: Timestamp, end: Timestamp) -> ClampedTimestampCounts {
+        let mut counts = ClampedTimestampCounts::default();
+        for thread in &mut self.threads {
+            let (samples, markers) = thread.clamp_timestamps(start, end);
+            counts.samples += samples;
+            counts.markers += markers;
+        }
+        for counter in &mut self.counters {
+            counts.counter_samples += counter.clamp_timestamps(start, end);
+        }
+        counts
+    }
+
+    /// Returns the earliest and the latest sample, marker and counter sample
+    /// timestamp, or None if the profile has none of these.
+    pub fn timestamp_bounds(&self) -> Option<(Timestamp, Timestamp)> {
+        let mut bounds = None;
+        for thread in &self.threads {
+            thread.extend_timestamp_bounds(&mut bounds);
+        }
+        for counter in &self.counters {
+            counter.extend_timestamp_bounds(&mut bounds);
+        }
+        bounds
+    }
+
+    /// Returns a copy of the profile with only the samples, markers and
+    /// counter samples in `start..end`. Everything else, e.g. the processes,
+    /// threads, libraries and stack tables, is copied completely, so that the
+    /// slice is a valid profile on its own.
+    ///
+    /// Markers are sliced by their start time.
+    pub fn time_slice(&self, start: Timestamp, end: Timestamp) -> Profile {
+        Profile {
+            product: self.product.clone(),
+            interval: self.interval,
+            global_libs: self.global_libs.clone(),
+            kernel_libs: self.kernel_libs.clone(),
+            categories: self.categories.clone(),
+            processes: self.processes.clone(),
+            counters: self
+                .counters
+                .iter()
+                .map(|counter| counter.time_slice(start, end))
+                .collect(),
+            threads: self
+                .threads
+                .iter()
+                .map(|thread| thread.time_slice(start, end))
+                .collect(),
+            reference_timestamp: self.reference_timestamp,
+            string_table: self.string_table.clone(),
+            marker_schemas: self.marker_schemas.clone(),
+            extra_info: self.extra_info.clone(),
+            sample_weight_unit: self.sample_weight_unit.clone(),
+            used_pids: self.used_pids.clone(),
+            used_tids: self.used_tids.clone(),
+        }
+    }
+
+    /// Estimates the size of the profile's JSON from the sizes of its tables,
+    /// without serializing it. The estimate is split into the part which every
+    /// [`time_slice`](Profile::time_slice) has, and the part which is spread
+    /// over time.
+    pub fn size_estimate(&self) -> ProfileSizeEstimate {
+        let mut estimate = ProfileSizeEstimate::default();
+        estimate.add_fixed(PROFILE_BYTES + self.global_libs.estimated_json_size());
+        for thread in &self.threads {
+            thread.add_to_size_estimate(&mut estimate);
+        }
+        for counter in &self.counters {
+            counter.add_to_size_estimate(&mut estimate);
+        }
+        estimate
+    }
+
     // frames is ordered from caller to callee, i.e. root function first, pc last
     fn stack_index_for_frames(
         &mut self,
@@ -563,11 +745,34 @@ impl Profile {
                 let b = &self.threads[b_handle.0];
                 a.cmp_for_json_order(b)
             });
+            self.move_thread_group_members_together(sorted_threads_for_this_process);
         }
 
         (sorted_threads, first_thread_index_per_process)
     }
 
+    /// Reorders the threads so that the members of each thread group directly
+    /// follow the group's first thread. Ungrouped threads keep their order.
+    fn move_thread_group_members_together(&self, threads: &mut [ThreadHandle]) {
+        let group = |thread: &ThreadHandle| self.threads[thread.0].group();
+        if threads.iter().all(|thread| group(thread).is_none()) {
+            return;
+        }
+        let mut reordered = Vec::with_capacity(threads.len());
+        for (i, thread) in threads.iter().enumerate() {
+            match group(thread) {
+                None => reordered.push(*thread),
+                Some(g) => {
+                    let is_first_of_group = !threads[..i].iter().any(|t| group(t) == Some(g));
+                    if is_first_of_group {
+                        reordered.extend(threads[i..].iter().filter(|t| group(t) == Some(g)));
+                    }
+                }
+            }
+        }
+        threads.copy_from_slice(&reordered);
+    }
+
     fn serializable_threads<'a>(
         &'a self,
         sorted_threads: &'a [ThreadHandle],
@@ -632,14 +837,15 @@ impl<'a> Serialize for SerializableProfileMeta<'a> {
         map.serialize_entry("preprocessedProfileVersion", &46)?;
         map.serialize_entry("processType", &0)?;
         map.serialize_entry("product", &self.0.product)?;
-        map.serialize_entry(
-            "sampleUnits",
-            &json!({
-                "time": "ms",
-                "eventDelay": "ms",
-                "threadCPUDelta": "µs",
-            }),
-        )?;
+        let mut sample_units = json!({
+            "time": "ms",
+            "eventDelay": "ms",
+            "threadCPUDelta": "µs",
+        });
+        if let Some(unit) = &self.0.sample_weight_unit {
+            sample_units["weight"] = json!(unit);
+        }
+        map.serialize_entry("sampleUnits", &sample_units)?;
         map.serialize_entry("startTime", &self.0.reference_timestamp)?;
         map.serialize_entry("symbolicated", &false)?;
         map.serialize_entry("pausedRanges", &[] as &[()])?;
@@ -647,6 +853,30 @@ impl<'a> Serialize for SerializableProfileMeta<'a> {
         map.serialize_entry("usesOnlyOneStackType", &(!self.0.contains_js_function()))?;
         map.serialize_entry("doesNotUseFrameImplementation", &true)?;
         map.serialize_entry("sourceCodeIsNotOnSearchfox", &true)?;
+        if !self.0.extra_info.is_empty() {
+            let extra: Vec<_> = self
+                .0
+                .extra_info
+                .iter()
+                .map(|(section, entries)| {
+                    let entries: Vec<_> = entries
+                        .iter()
+                        .map(|(label, value)| {
+                            json!({
+                                "label": label,
+                                "format": "string",
+                                "value": value,
+                            })
+                        })
+                        .collect();
+                    json!({
+                        "label": section,
+                        "entries": entries,
+                    })
+                })
+                .collect();
+            map.serialize_entry("extra", &extra)?;
+        }
 
         let mut marker_schemas: Vec<MarkerSchema> =
             self.0.marker_schemas.values().cloned().collect();
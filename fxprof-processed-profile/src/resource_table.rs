@@ -3,6 +3,7 @@ use serde::ser::{Serialize, SerializeMap, Serializer};
 use crate::fast_hash_map::FastHashMap;
 use crate::global_lib_table::{GlobalLibIndex, GlobalLibTable};
 use crate::serialization_helpers::SerializableSingleValueColumn;
+use crate::size_estimate::RESOURCE_BYTES;
 use crate::thread_string_table::ThreadInternalStringIndex;
 use crate::thread_string_table::ThreadStringTable;
 
@@ -18,6 +19,10 @@ impl ResourceTable {
         Default::default()
     }
 
+    pub fn estimated_json_size(&self) -> usize {
+        self.resource_libs.len() * RESOURCE_BYTES
+    }
+
     pub fn resource_for_lib(
         &mut self,
         lib_index: GlobalLibIndex,
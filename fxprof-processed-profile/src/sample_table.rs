@@ -1,11 +1,14 @@
 use serde::ser::{Serialize, SerializeMap, Serializer};
 
 use crate::cpu_delta::CpuDelta;
+use crate::size_estimate::{ProfileSizeEstimate, SAMPLE_BYTES};
+use crate::timestamp::{clamp_timestamp, extend_bounds};
 use crate::Timestamp;
 
 #[derive(Debug, Clone, Default)]
 pub struct SampleTable {
     sample_weights: Vec<i32>,
+    weight_type: WeightType,
     sample_timestamps: Vec<Timestamp>,
     sample_stack_indexes: Vec<Option<usize>>,
     sample_cpu_deltas: Vec<CpuDelta>,
@@ -29,10 +32,54 @@ impl SampleTable {
         self.sample_cpu_deltas.push(cpu_delta);
     }
 
+    pub fn set_weight_type(&mut self, weight_type: WeightType) {
+        self.weight_type = weight_type;
+    }
+
     pub fn modify_last_sample(&mut self, timestamp: Timestamp, weight: i32) {
         *self.sample_weights.last_mut().unwrap() += weight;
         *self.sample_timestamps.last_mut().unwrap() = timestamp;
     }
+
+    /// Returns the number of samples whose timestamp was changed.
+    pub fn clamp_timestamps(&mut self, start: Timestamp, end: Timestamp) -> usize {
+        self.sample_timestamps
+            .iter_mut()
+            .map(|t| clamp_timestamp(t, start, end))
+            .filter(|&changed| changed)
+            .count()
+    }
+
+    pub fn extend_timestamp_bounds(&self, bounds: &mut Option<(Timestamp, Timestamp)>) {
+        for timestamp in &self.sample_timestamps {
+            extend_bounds(bounds, *timestamp);
+        }
+    }
+
+    /// Returns a copy with only the samples in `start..end`.
+    pub fn time_slice(&self, start: Timestamp, end: Timestamp) -> Self {
+        let mut slice = Self {
+            weight_type: self.weight_type,
+            ..Default::default()
+        };
+        for (i, timestamp) in self.sample_timestamps.iter().enumerate() {
+            if (start..end).contains(timestamp) {
+                slice.add_sample(
+                    *timestamp,
+                    self.sample_stack_indexes[i],
+                    self.sample_cpu_deltas[i],
+                    self.sample_weights[i],
+                );
+            }
+        }
+        slice
+    }
+
+    pub fn add_to_size_estimate(&self, estimate: &mut ProfileSizeEstimate) {
+        for timestamp in &self.sample_timestamps {
+            estimate.add_at(*timestamp, SAMPLE_BYTES);
+        }
+    }
 }
 
 impl Serialize for SampleTable {
@@ -43,8 +90,33 @@ impl Serialize for SampleTable {
         map.serialize_entry("stack", &self.sample_stack_indexes)?;
         map.serialize_entry("time", &self.sample_timestamps)?;
         map.serialize_entry("weight", &self.sample_weights)?;
-        map.serialize_entry("weightType", &"samples")?;
+        map.serialize_entry("weightType", &self.weight_type)?;
         map.serialize_entry("threadCPUDelta", &self.sample_cpu_deltas)?;
         map.end()
     }
 }
+
+/// The unit of the sample weights of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeightType {
+    /// Each sample counts as its weight in samples. This is the default.
+    Samples,
+    /// The weight of each sample is a number of bytes, e.g. for allocation
+    /// profiles.
+    Bytes,
+}
+
+impl Default for WeightType {
+    fn default() -> Self {
+        WeightType::Samples
+    }
+}
+
+impl Serialize for WeightType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            WeightType::Samples => serializer.serialize_str("samples"),
+            WeightType::Bytes => serializer.serialize_str("bytes"),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::Serialize;
+
+use crate::Timestamp;
+
+// Rough sizes of one row of the profile's tables in the JSON, including the
+// separators. They only need to be good enough to keep the parts of a split
+// profile under a size limit.
+pub(crate) const SAMPLE_BYTES: usize = 40;
+pub(crate) const MARKER_BYTES: usize = 40;
+pub(crate) const COUNTER_SAMPLE_BYTES: usize = 30;
+pub(crate) const STACK_BYTES: usize = 20;
+pub(crate) const FRAME_BYTES: usize = 40;
+pub(crate) const FUNC_BYTES: usize = 30;
+pub(crate) const RESOURCE_BYTES: usize = 20;
+pub(crate) const NATIVE_SYMBOL_BYTES: usize = 30;
+pub(crate) const LIB_BYTES: usize = 300;
+/// The thread's metadata and the keys of its tables.
+pub(crate) const THREAD_BYTES: usize = 2_000;
+/// The profile's metadata, e.g. the categories and marker schemas.
+pub(crate) const PROFILE_BYTES: usize = 20_000;
+
+/// An estimate of the size of the profile's JSON, see
+/// [`Profile::size_estimate`](crate::Profile::size_estimate).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileSizeEstimate {
+    /// The bytes which don't depend on the time range of the profile, e.g. the
+    /// libraries, stack tables and strings. Every [`time_slice`](crate::Profile::time_slice)
+    /// of the profile has all of them.
+    pub fixed_bytes: u64,
+    /// The bytes of the samples, markers and counter samples, by the whole
+    /// second since the reference timestamp in which they are.
+    pub bytes_by_second: BTreeMap<u64, u64>,
+}
+
+impl ProfileSizeEstimate {
+    pub fn total(&self) -> u64 {
+        self.fixed_bytes + self.bytes_by_second.values().sum::<u64>()
+    }
+
+    pub(crate) fn add_fixed(&mut self, bytes: usize) {
+        self.fixed_bytes += bytes as u64;
+    }
+
+    pub(crate) fn add_at(&mut self, timestamp: Timestamp, bytes: usize) {
+        let second = timestamp.nanos_since_reference() / 1_000_000_000;
+        *self.bytes_by_second.entry(second).or_default() += bytes as u64;
+    }
+}
+
+/// The size of the JSON of a value, e.g. of a marker's data, without
+/// allocating the JSON.
+pub(crate) fn json_size(value: &impl Serialize) -> usize {
+    struct ByteCounter(usize);
+
+    impl io::Write for ByteCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = ByteCounter(0);
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
@@ -4,6 +4,7 @@ use crate::category::{
     Category, CategoryHandle, CategoryPairHandle, SerializableSubcategoryColumn, Subcategory,
 };
 use crate::fast_hash_map::FastHashMap;
+use crate::size_estimate::STACK_BYTES;
 
 #[derive(Debug, Clone, Default)]
 pub struct StackTable {
@@ -21,6 +22,10 @@ impl StackTable {
         Default::default()
     }
 
+    pub fn estimated_json_size(&self) -> usize {
+        self.stack_prefixes.len() * STACK_BYTES
+    }
+
     pub fn index_for_stack(
         &mut self,
         prefix: Option<usize>,
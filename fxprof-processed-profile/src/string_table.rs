@@ -29,6 +29,11 @@ impl StringTable {
     pub fn get_string(&self, index: StringIndex) -> Option<&str> {
         self.strings.get(index.0 as usize).map(Deref::deref)
     }
+
+    /// The strings plus their quotes and commas, ignoring escapes.
+    pub fn estimated_json_size(&self) -> usize {
+        self.strings.iter().map(|s| s.len() + 3).sum()
+    }
 }
 
 impl Serialize for StringTable {
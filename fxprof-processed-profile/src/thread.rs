@@ -12,7 +12,8 @@ use crate::global_lib_table::GlobalLibTable;
 use crate::marker_table::MarkerTable;
 use crate::native_symbols::NativeSymbols;
 use crate::resource_table::ResourceTable;
-use crate::sample_table::SampleTable;
+use crate::sample_table::{SampleTable, WeightType};
+use crate::size_estimate::{ProfileSizeEstimate, THREAD_BYTES};
 use crate::stack_table::StackTable;
 use crate::string_table::{GlobalStringIndex, GlobalStringTable};
 use crate::thread_string_table::{ThreadInternalStringIndex, ThreadStringTable};
@@ -30,6 +31,7 @@ pub struct Thread {
     process: ProcessHandle,
     tid: String,
     name: Option<String>,
+    group: Option<String>,
     start_time: Timestamp,
     end_time: Option<Timestamp>,
     is_main: bool,
@@ -51,6 +53,7 @@ impl Thread {
             process,
             tid,
             name: None,
+            group: None,
             start_time,
             end_time: None,
             is_main,
@@ -71,14 +74,38 @@ impl Thread {
         self.name = Some(name.to_string());
     }
 
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn is_main(&self) -> bool {
+        self.is_main
+    }
+
+    pub fn set_group(&mut self, group: Option<&str>) {
+        self.group = group.map(ToOwned::to_owned);
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
     pub fn set_start_time(&mut self, start_time: Timestamp) {
         self.start_time = start_time;
     }
 
+    pub fn set_samples_weight_type(&mut self, weight_type: WeightType) {
+        self.samples.set_weight_type(weight_type);
+    }
+
     pub fn set_end_time(&mut self, end_time: Timestamp) {
         self.end_time = Some(end_time);
     }
 
+    pub fn clear_end_time(&mut self) {
+        self.end_time = None;
+    }
+
     pub fn process(&self) -> ProcessHandle {
         self.process
     }
@@ -158,6 +185,57 @@ impl Thread {
         self.markers.add_marker(name_string_index, timing, data);
     }
 
+    /// Returns the number of samples and markers whose timestamps were changed.
+    pub fn clamp_timestamps(&mut self, start: Timestamp, end: Timestamp) -> (usize, usize) {
+        (
+            self.samples.clamp_timestamps(start, end),
+            self.markers.clamp_timestamps(start, end),
+        )
+    }
+
+    pub fn extend_timestamp_bounds(&self, bounds: &mut Option<(Timestamp, Timestamp)>) {
+        self.samples.extend_timestamp_bounds(bounds);
+        self.markers.extend_timestamp_bounds(bounds);
+    }
+
+    /// Returns a copy of the thread with only the samples and markers in
+    /// `start..end`.
+    pub fn time_slice(&self, start: Timestamp, end: Timestamp) -> Self {
+        Self {
+            process: self.process,
+            tid: self.tid.clone(),
+            name: self.name.clone(),
+            group: self.group.clone(),
+            start_time: self.start_time,
+            end_time: self.end_time,
+            is_main: self.is_main,
+            stack_table: self.stack_table.clone(),
+            frame_table: self.frame_table.clone(),
+            func_table: self.func_table.clone(),
+            samples: self.samples.time_slice(start, end),
+            markers: self.markers.time_slice(start, end),
+            resources: self.resources.clone(),
+            native_symbols: self.native_symbols.clone(),
+            string_table: self.string_table.clone(),
+            last_sample_stack: self.last_sample_stack,
+            last_sample_was_zero_cpu: self.last_sample_was_zero_cpu,
+        }
+    }
+
+    pub fn add_to_size_estimate(&self, estimate: &mut ProfileSizeEstimate) {
+        estimate.add_fixed(
+            THREAD_BYTES
+                + self.stack_table.estimated_json_size()
+                + self.frame_table.estimated_json_size()
+                + self.func_table.estimated_json_size()
+                + self.resources.estimated_json_size()
+                + self.native_symbols.estimated_json_size()
+                + self.string_table.estimated_json_size(),
+        );
+        self.samples.add_to_size_estimate(estimate);
+        self.markers.add_to_size_estimate(estimate);
+    }
+
     pub fn contains_js_function(&self) -> bool {
         self.func_table.contains_js_function()
     }
@@ -218,6 +296,9 @@ impl Thread {
             &self.stack_table.serialize_with_categories(categories),
         )?;
         map.serialize_entry("stringArray", &self.string_table)?;
+        if let Some(group) = &self.group {
+            map.serialize_entry("threadGroup", group)?;
+        }
         map.serialize_entry("tid", &self.tid)?;
         map.serialize_entry("unregisterTime", &thread_unregister_time)?;
         map.end()
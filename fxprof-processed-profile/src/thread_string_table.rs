@@ -23,6 +23,10 @@ impl ThreadStringTable {
         Default::default()
     }
 
+    pub fn estimated_json_size(&self) -> usize {
+        self.table.estimated_json_size()
+    }
+
     pub fn index_for_string(&mut self, s: &str) -> ThreadInternalStringIndex {
         ThreadInternalStringIndex(self.table.index_for_string(s))
     }
@@ -18,6 +18,10 @@ impl Timestamp {
             nanos: (millis * 1_000_000.0) as u64,
         }
     }
+
+    pub fn nanos_since_reference(&self) -> u64 {
+        self.nanos
+    }
 }
 
 impl Serialize for Timestamp {
@@ -27,3 +31,35 @@ impl Serialize for Timestamp {
         serializer.serialize_f64((self.nanos as f64) / 1_000_000.0)
     }
 }
+
+/// How many timestamps [`Profile::clamp_timestamps`](crate::Profile::clamp_timestamps)
+/// moved into the range, by kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClampedTimestampCounts {
+    pub samples: usize,
+    /// Counts markers, not marker start and end times.
+    pub markers: usize,
+    pub counter_samples: usize,
+}
+
+impl ClampedTimestampCounts {
+    pub fn total(&self) -> usize {
+        self.samples + self.markers + self.counter_samples
+    }
+}
+
+/// Clamps `timestamp` to `start..=end` and returns whether it was changed.
+pub(crate) fn clamp_timestamp(timestamp: &mut Timestamp, start: Timestamp, end: Timestamp) -> bool {
+    let clamped = (*timestamp).clamp(start, end);
+    let changed = clamped != *timestamp;
+    *timestamp = clamped;
+    changed
+}
+
+/// Extends the `(min, max)` range `bounds` so that it contains `timestamp`.
+pub(crate) fn extend_bounds(bounds: &mut Option<(Timestamp, Timestamp)>, timestamp: Timestamp) {
+    *bounds = Some(match *bounds {
+        Some((min, max)) => (min.min(timestamp), max.max(timestamp)),
+        None => (timestamp, timestamp),
+    });
+}
@@ -1,5 +1,8 @@
 use samply_symbols::SourceFilePath;
 
+/// The path of a source file in the JSON API: the mapped path in its special
+/// path form if there is one, and the raw path otherwise. `/source/v1`
+/// requests refer to files by this path.
 pub fn to_api_file_path(file_path: &SourceFilePath) -> String {
     match file_path.mapped_path() {
         Some(mapped_path) => mapped_path.to_special_path_str(),
@@ -164,6 +164,43 @@ impl<'a, 'h: 'a, H: FileAndPathHelper<'h>> AsmApi<'a, 'h, H> {
     }
 }
 
+/// Answers an `/asm/v1` request from code bytes which the caller already has,
+/// for example the code of JIT functions which was embedded into a profile,
+/// instead of from the library's binary.
+///
+/// `code_for_library` is called with the debug name and debug ID of the
+/// requested library, and returns the library's architecture and its code
+/// ranges, as start addresses and bytes. Returns `None` if there's no code
+/// for the requested address, so that the caller can fall back to the binary.
+pub fn query_asm_api_json_for_code<'c>(
+    request_json: &str,
+    code_for_library: impl FnOnce(&str, DebugId) -> Option<(&'c str, &'c [(u32, Vec<u8>)])>,
+) -> Option<String> {
+    let request: request_json::Request = serde_json::from_str(request_json).ok()?;
+    let debug_name = request.debug_name.as_deref()?;
+    let debug_id = DebugId::from_breakpad(request.debug_id.as_deref()?).ok()?;
+    let (arch, ranges) = code_for_library(debug_name, debug_id)?;
+    let (offset, bytes) = ranges.iter().find_map(|(start, bytes)| {
+        let offset = request.start_address.checked_sub(*start)?;
+        (offset < bytes.len() as u32).then(|| (offset, bytes))
+    })?;
+    let bytes = &bytes[offset as usize..];
+    // Each range holds one whole function, so the function ends at the end
+    // of the range.
+    let available_len = bytes.len() as u32;
+    let disassembly_len = if request.continue_until_function_end {
+        available_len
+    } else {
+        request.size.min(available_len)
+    };
+    let response = decode_arch(bytes, Some(arch), request.start_address, disassembly_len)
+        .and_then(|response| Ok(serde_json::to_string(&response)?));
+    Some(match response {
+        Ok(response_json) => response_json,
+        Err(err) => json!({ "error": err.to_string() }).to_string(),
+    })
+}
+
 fn decode_arch(
     bytes: &[u8],
     arch: Option<&str>,
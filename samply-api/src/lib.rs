@@ -143,6 +143,9 @@
 
 pub use samply_symbols;
 pub use samply_symbols::debugid;
+
+pub use api_file_path::to_api_file_path;
+pub use asm::query_asm_api_json_for_code;
 use samply_symbols::{FileAndPathHelper, SymbolManager};
 
 use asm::AsmApi;
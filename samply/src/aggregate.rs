@@ -0,0 +1,469 @@
+use serde_derive::Serialize;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::cli_error::CliError;
+use crate::profile_json::{
+    read_profile, symbolicate_with_inline_frames, InlineFrames, ProfileJson, SymbolNames,
+    ThreadJson,
+};
+
+/// What `--aggregate-output` writes.
+#[derive(Debug, Clone)]
+pub struct AggregateOptions {
+    pub output: PathBuf,
+    pub csv: bool,
+    /// How many callers of each symbol are kept, for the inverted
+    /// (callee to caller) aggregation. 0 writes a flat list.
+    pub caller_depth: usize,
+    pub inline_mode: InlineMode,
+}
+
+/// How functions which were inlined into a frame are aggregated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InlineMode {
+    /// Inlined functions are folded into the function they were inlined
+    /// into, like in perf report.
+    #[default]
+    Collapse,
+    /// Inlined functions are aggregated as if they were real frames: the
+    /// innermost one gets the self weight, and all of them get the total
+    /// weight.
+    Expand,
+}
+
+/// Parses an `--inline` argument.
+pub fn parse_inline_mode(s: &str) -> Result<InlineMode, String> {
+    match s {
+        "collapse" => Ok(InlineMode::Collapse),
+        "expand" => Ok(InlineMode::Expand),
+        _ => Err(format!("expected collapse or expand, got {s:?}")),
+    }
+}
+
+/// Aggregates the samples of the profiles per process, symbol and library,
+/// and writes the totals to `options.output`. Library addresses are
+/// symbolicated with the same symbol manager setup as the local server.
+#[tokio::main]
+pub async fn write_aggregate_main(
+    profile_paths: &[PathBuf],
+    binaries_dirs: &[PathBuf],
+    options: &AggregateOptions,
+    verbose: bool,
+) -> Result<(), CliError> {
+    let mut aggregator = SymbolAggregator::new(options.caller_depth);
+    for path in profile_paths {
+        let profile = read_profile(path)?;
+        let (symbol_names, mut inline_frames) =
+            symbolicate_with_inline_frames(&profile, binaries_dirs, verbose).await;
+        if options.inline_mode == InlineMode::Collapse {
+            inline_frames.clear();
+        }
+        aggregator.add_profile(&profile, &symbol_names, &inline_frames);
+    }
+    let symbols = aggregator.finish();
+
+    let file = File::create(&options.output)
+        .map_err(|err| CliError::io(format!("Could not create {:?}", options.output), &err))?;
+    let mut writer = BufWriter::new(file);
+    let result = if options.csv {
+        write_csv(&mut writer, &symbols)
+    } else {
+        serde_json::to_writer(&mut writer, &AggregateJson { symbols: &symbols })
+            .map_err(std::io::Error::from)
+    };
+    result
+        .and_then(|()| writer.flush())
+        .map_err(|err| CliError::io(format!("Could not write {:?}", options.output), &err))?;
+    eprintln!(
+        "Wrote the totals of {} symbols to {:?}.",
+        symbols.len(),
+        options.output
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct AggregateJson<'a> {
+    symbols: &'a [AggregatedSymbol],
+}
+
+/// The totals of one symbol in one process.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AggregatedSymbol {
+    pub process: String,
+    pub symbol: String,
+    pub library: Option<String>,
+    pub debug_id: Option<String>,
+    pub category: Option<String>,
+    /// The weight of the samples which have this symbol as the leaf.
+    pub self_weight: i64,
+    /// The weight of the samples which have this symbol anywhere in the
+    /// stack. Recursive calls are only counted once.
+    pub total_weight: i64,
+    /// The self weight split up by the nearest callers, sorted by weight.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub callers: Vec<CallerPath>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallerPath {
+    /// The direct caller first.
+    pub callers: Vec<String>,
+    pub self_weight: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SymbolKey {
+    process: String,
+    symbol: String,
+    lib_index: Option<usize>,
+}
+
+#[derive(Debug)]
+struct SymbolEntry {
+    symbol: AggregatedSymbol,
+    /// The sample which was last added to the total weight, so that
+    /// recursive calls are only counted once.
+    last_counted_sample: Option<u64>,
+    callers: HashMap<Vec<usize>, i64>,
+}
+
+/// Adds up the sample weights per (process, symbol, library).
+pub struct SymbolAggregator {
+    caller_depth: usize,
+    entries: Vec<SymbolEntry>,
+    entry_indexes: HashMap<SymbolKey, usize>,
+    sample_count: u64,
+}
+
+impl SymbolAggregator {
+    pub fn new(caller_depth: usize) -> Self {
+        Self {
+            caller_depth,
+            entries: Vec::new(),
+            entry_indexes: HashMap::new(),
+            sample_count: 0,
+        }
+    }
+
+    /// Each frame with `inline_frames` is aggregated as its inlined functions,
+    /// innermost first, followed by the function they were inlined into.
+    fn add_profile(
+        &mut self,
+        profile: &ProfileJson,
+        symbol_names: &SymbolNames,
+        inline_frames: &InlineFrames,
+    ) {
+        for thread in &profile.threads {
+            // Allocation tracks and other non-sample weights would skew the
+            // sample totals.
+            if !matches!(
+                thread.samples.weight_type.as_deref(),
+                None | Some("samples")
+            ) {
+                continue;
+            }
+            let frame_entries: Vec<Vec<usize>> = (0..thread.frame_table.func.len())
+                .map(|frame| {
+                    let mut entries: Vec<usize> = thread
+                        .inline_frame_names(frame, inline_frames)
+                        .iter()
+                        .map(|name| self.entry_for_symbol(profile, thread, frame, name))
+                        .collect();
+                    let name = thread.frame_name(frame, symbol_names);
+                    entries.push(self.entry_for_symbol(profile, thread, frame, name));
+                    entries
+                })
+                .collect();
+            let mut stack_entries = Vec::new();
+            for sample in thread.samples() {
+                stack_entries.clear();
+                stack_entries.extend(
+                    thread
+                        .stack_frames(sample.stack)
+                        .flat_map(|frame| frame_entries[frame].iter().copied()),
+                );
+                self.add_sample(&stack_entries, sample.weight);
+            }
+        }
+    }
+
+    /// `stack_entries` starts at the leaf.
+    fn add_sample(&mut self, stack_entries: &[usize], weight: i64) {
+        let Some((&leaf, callers)) = stack_entries.split_first() else {
+            return;
+        };
+        let sample = self.sample_count;
+        self.sample_count += 1;
+        for &entry_index in stack_entries {
+            let entry = &mut self.entries[entry_index];
+            if entry.last_counted_sample != Some(sample) {
+                entry.last_counted_sample = Some(sample);
+                entry.symbol.total_weight += weight;
+            }
+        }
+        let leaf = &mut self.entries[leaf];
+        leaf.symbol.self_weight += weight;
+        if self.caller_depth != 0 {
+            let depth = self.caller_depth.min(callers.len());
+            *leaf.callers.entry(callers[..depth].to_vec()).or_default() += weight;
+        }
+    }
+
+    /// The library and category of the symbol are those of the frame.
+    fn entry_for_symbol(
+        &mut self,
+        profile: &ProfileJson,
+        thread: &ThreadJson,
+        frame: usize,
+        symbol: &str,
+    ) -> usize {
+        let lib_index = thread.func_lib(thread.frame_table.func[frame]);
+        let key = SymbolKey {
+            process: thread.process_name.clone().unwrap_or_default(),
+            symbol: symbol.to_string(),
+            lib_index,
+        };
+        if let Some(&index) = self.entry_indexes.get(&key) {
+            return index;
+        }
+
+        let lib = lib_index.and_then(|lib| profile.libs.get(lib));
+        let category = thread.frame_table.category[frame]
+            .and_then(|category| profile.meta.category_name(category))
+            .map(ToOwned::to_owned);
+        let index = self.entries.len();
+        self.entries.push(SymbolEntry {
+            symbol: AggregatedSymbol {
+                process: key.process.clone(),
+                symbol: key.symbol.clone(),
+                library: lib.and_then(|lib| lib.name.clone()),
+                debug_id: lib.and_then(|lib| lib.breakpad_id.clone()),
+                category,
+                self_weight: 0,
+                total_weight: 0,
+                callers: Vec::new(),
+            },
+            last_counted_sample: None,
+            callers: HashMap::new(),
+        });
+        self.entry_indexes.insert(key, index);
+        index
+    }
+
+    /// Returns the symbols sorted by self weight, then by total weight.
+    pub fn finish(self) -> Vec<AggregatedSymbol> {
+        let names: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| entry.symbol.symbol.clone())
+            .collect();
+        let mut symbols: Vec<AggregatedSymbol> = self
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let mut symbol = entry.symbol;
+                symbol.callers = entry
+                    .callers
+                    .into_iter()
+                    .map(|(callers, self_weight)| CallerPath {
+                        callers: callers.iter().map(|&i| names[i].clone()).collect(),
+                        self_weight,
+                    })
+                    .collect();
+                symbol.callers.sort_by(|a, b| {
+                    b.self_weight
+                        .cmp(&a.self_weight)
+                        .then(a.callers.cmp(&b.callers))
+                });
+                symbol
+            })
+            .collect();
+        symbols.sort_by(|a, b| {
+            (b.self_weight, b.total_weight)
+                .cmp(&(a.self_weight, a.total_weight))
+                .then_with(|| (&a.process, &a.symbol).cmp(&(&b.process, &b.symbol)))
+        });
+        symbols
+    }
+}
+
+/// One row per symbol, followed by one row per caller path of the symbol,
+/// which only has the caller path and its self weight.
+fn write_csv(writer: &mut impl Write, symbols: &[AggregatedSymbol]) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        "process,symbol,library,debug_id,category,self_weight,total_weight,callers"
+    )?;
+    for symbol in symbols {
+        let prefix = [
+            csv_field(&symbol.process),
+            csv_field(&symbol.symbol),
+            csv_field(symbol.library.as_deref().unwrap_or("")),
+            csv_field(symbol.debug_id.as_deref().unwrap_or("")),
+            csv_field(symbol.category.as_deref().unwrap_or("")),
+        ]
+        .join(",");
+        writeln!(
+            writer,
+            "{prefix},{},{},",
+            symbol.self_weight, symbol.total_weight
+        )?;
+        for caller_path in &symbol.callers {
+            writeln!(
+                writer,
+                "{prefix},{},,{}",
+                caller_path.self_weight,
+                csv_field(&caller_path.callers.join(" <- "))
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// main -> work -> leaf, main -> leaf, and a recursive work -> work.
+    const PROFILE: &str = r#"{
+        "meta": { "categories": [{ "name": "Other" }, { "name": "User" }] },
+        "libs": [{ "name": "app", "breakpadId": "ABCD0" }],
+        "threads": [{
+            "processName": "app",
+            "samples": { "stack": [2, 3, 1, 4, null], "weight": [1, 2, 1, 4, 1], "weightType": "samples" },
+            "stackTable": { "prefix": [null, 0, 1, 0, 1], "frame": [0, 1, 2, 2, 3] },
+            "frameTable": { "address": [16, 32, 48, 64], "category": [1, 1, 1, 1], "func": [0, 1, 2, 3] },
+            "funcTable": { "name": [0, 1, 2, 3], "resource": [0, 0, -1, 0] },
+            "resourceTable": { "lib": [0] },
+            "stringArray": ["main", "0x20", "leaf", "0x40"]
+        }]
+    }"#;
+
+    fn aggregate(caller_depth: usize) -> Vec<AggregatedSymbol> {
+        aggregate_with_inline_frames(caller_depth, &InlineFrames::new())
+    }
+
+    fn aggregate_with_inline_frames(
+        caller_depth: usize,
+        inline_frames: &InlineFrames,
+    ) -> Vec<AggregatedSymbol> {
+        let profile: ProfileJson = serde_json::from_str(PROFILE).unwrap();
+        let mut symbol_names = HashMap::new();
+        symbol_names.insert((0, 32), "work".to_string());
+        symbol_names.insert((0, 64), "work".to_string());
+        let mut aggregator = SymbolAggregator::new(caller_depth);
+        aggregator.add_profile(&profile, &symbol_names, inline_frames);
+        aggregator.finish()
+    }
+
+    fn weights(symbols: &[AggregatedSymbol]) -> Vec<(&str, i64, i64)> {
+        symbols
+            .iter()
+            .map(|s| (s.symbol.as_str(), s.self_weight, s.total_weight))
+            .collect()
+    }
+
+    #[test]
+    fn aggregates_self_and_total_weight() {
+        let symbols = aggregate(0);
+        assert_eq!(
+            weights(&symbols),
+            vec![("work", 5, 6), ("leaf", 3, 3), ("main", 0, 8)]
+        );
+        assert_eq!(symbols[0].library.as_deref(), Some("app"));
+        assert_eq!(symbols[0].debug_id.as_deref(), Some("ABCD0"));
+        assert_eq!(symbols[0].category.as_deref(), Some("User"));
+        assert_eq!(symbols[1].library, None);
+        assert!(symbols.iter().all(|s| s.callers.is_empty()));
+    }
+
+    #[test]
+    fn aggregates_callers_up_to_depth() {
+        let symbols = aggregate(2);
+        let leaf = symbols.iter().find(|s| s.symbol == "leaf").unwrap();
+        assert_eq!(
+            leaf.callers,
+            vec![
+                CallerPath {
+                    callers: vec!["main".to_string()],
+                    self_weight: 2,
+                },
+                CallerPath {
+                    callers: vec!["work".to_string(), "main".to_string()],
+                    self_weight: 1,
+                },
+            ]
+        );
+        let work = symbols.iter().find(|s| s.symbol == "work").unwrap();
+        assert_eq!(
+            work.callers,
+            vec![
+                CallerPath {
+                    callers: vec!["work".to_string(), "main".to_string()],
+                    self_weight: 4,
+                },
+                CallerPath {
+                    callers: vec!["main".to_string()],
+                    self_weight: 1,
+                },
+            ]
+        );
+    }
+
+    /// `inner` is inlined into `work` at 0x20, but not at 0x40.
+    #[test]
+    fn expands_inlined_functions() {
+        let mut inline_frames = InlineFrames::new();
+        inline_frames.insert((0, 32), vec!["inner".to_string()]);
+        let symbols = aggregate_with_inline_frames(1, &inline_frames);
+        assert_eq!(
+            weights(&symbols),
+            vec![
+                ("work", 4, 6),
+                ("leaf", 3, 3),
+                ("inner", 1, 6),
+                ("main", 0, 8)
+            ]
+        );
+        let leaf = symbols.iter().find(|s| s.symbol == "leaf").unwrap();
+        assert_eq!(
+            leaf.callers,
+            vec![
+                CallerPath {
+                    callers: vec!["main".to_string()],
+                    self_weight: 2,
+                },
+                CallerPath {
+                    callers: vec!["inner".to_string()],
+                    self_weight: 1,
+                },
+            ]
+        );
+        assert_eq!(parse_inline_mode("expand"), Ok(InlineMode::Expand));
+        assert!(parse_inline_mode("inline").is_err());
+    }
+
+    #[test]
+    fn quotes_csv_fields() {
+        assert_eq!(csv_field("main"), "main");
+        assert_eq!(
+            csv_field("Vec<T, A>::push \"x\""),
+            "\"Vec<T, A>::push \"\"x\"\"\""
+        );
+    }
+}
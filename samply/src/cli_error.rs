@@ -0,0 +1,183 @@
+use once_cell::sync::Lazy;
+
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+
+/// The category of a fatal error, which determines the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Invalid flags, nonexistent or malformed input files, or an unsupported
+    /// recording configuration.
+    UserInput,
+    /// Problems with the environment samply runs in, e.g. missing
+    /// permissions, a full disk, or a restrictive perf_event_paranoid.
+    Environment,
+    /// A bug in samply.
+    Internal,
+    /// The conversion made no progress for the duration given to
+    /// `--watchdog`, and `--watchdog-abort` was set.
+    Stalled,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::UserInput => 2,
+            ErrorKind::Environment => 3,
+            ErrorKind::Internal => 4,
+            ErrorKind::Stalled => 5,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::UserInput => "user-input",
+            ErrorKind::Environment => "environment",
+            ErrorKind::Internal => "internal",
+            ErrorKind::Stalled => "stalled",
+        }
+    }
+
+    /// Picks the category for an I/O error on a file that the user pointed
+    /// us at.
+    pub fn for_io_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::UnexpectedEof => ErrorKind::UserInput,
+            // Missing permissions, full disks, etc.
+            _ => ErrorKind::Environment,
+        }
+    }
+}
+
+/// A fatal error of the samply command line tool.
+#[derive(Debug)]
+pub struct CliError {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl CliError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn user_input(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::UserInput, message)
+    }
+
+    pub fn environment(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Environment, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Internal, message)
+    }
+
+    /// Creates an error for a failed I/O operation, categorized by the kind
+    /// of I/O error.
+    pub fn io(context: impl fmt::Display, err: &io::Error) -> Self {
+        Self::new(ErrorKind::for_io_error(err), format!("{context}: {err}"))
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Prints the error, followed by a single-line machine-readable summary,
+    /// and exits with the exit code for the error kind.
+    pub fn exit(self) -> ! {
+        eprintln!("{}", self.message);
+        print_summary(self.kind);
+        run_exit_hooks();
+        std::process::exit(self.kind.exit_code())
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<crate::import::perf::Error> for CliError {
+    fn from(err: crate::import::perf::Error) -> Self {
+        use crate::import::perf::Error;
+        let kind = match &err {
+            Error::Io(io_err) | Error::LinuxPerf(linux_perf_data::Error::IoError(io_err)) => {
+                ErrorKind::for_io_error(io_err)
+            }
+            // Everything else means that the file is malformed or that we
+            // don't support the way it was recorded.
+            Error::LinuxPerf(_)
+            | Error::NotAPerfStream
+            | Error::MalformedStream(_)
+            | Error::MalformedIntermediate(_)
+            | Error::MixedArchitectures(..) => ErrorKind::UserInput,
+        };
+        Self::new(kind, format!("Could not convert the perf.data file: {err}"))
+    }
+}
+
+/// Prints the machine-readable summary line, e.g.
+/// `ERROR code=2 kind=user-input`.
+fn print_summary(kind: ErrorKind) {
+    eprintln!("ERROR code={} kind={}", kind.exit_code(), kind.name());
+}
+
+type ExitHook = Box<dyn FnOnce() + Send>;
+
+static EXIT_HOOKS: Lazy<Mutex<Vec<ExitHook>>> = Lazy::new(Default::default);
+
+/// Runs `hook` if samply exits with an error or because of a panic on the
+/// main thread. Errors can exit right away, without running destructors, so
+/// this is for cleanup which must not be skipped, like restoring system
+/// settings.
+pub fn run_on_error_exit(hook: impl FnOnce() + Send + 'static) {
+    EXIT_HOOKS.lock().unwrap().push(Box::new(hook));
+}
+
+fn run_exit_hooks() {
+    // Don't deadlock or panic again if a hook panicked.
+    let hooks = match EXIT_HOOKS.try_lock() {
+        Ok(mut hooks) => std::mem::take(&mut *hooks),
+        Err(_) => return,
+    };
+    for hook in hooks {
+        hook();
+    }
+}
+
+/// Exits as an internal error after `run` panicked on the main thread. The
+/// default panic hook has already printed the panic message.
+pub fn exit_after_panic() -> ! {
+    print_summary(ErrorKind::Internal);
+    run_exit_hooks();
+    std::process::exit(ErrorKind::Internal.exit_code())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn categorizes_io_errors() {
+        let not_found = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(ErrorKind::for_io_error(&not_found), ErrorKind::UserInput);
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(ErrorKind::for_io_error(&denied), ErrorKind::Environment);
+        let disk_full = io::Error::from_raw_os_error(28);
+        assert_eq!(ErrorKind::for_io_error(&disk_full), ErrorKind::Environment);
+        let err = CliError::io("Could not open file \"x\"", &not_found);
+        assert_eq!(err.kind().exit_code(), 2);
+        assert!(err.to_string().starts_with("Could not open file \"x\": "));
+    }
+}
@@ -0,0 +1,653 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+
+use linux_perf_data::{AttributeDescription, Endianness};
+use tracing::info;
+
+use crate::shared::memory_access::MemoryAccessKind;
+use crate::shared::types::StackMode;
+
+/// The record types which perf writes for AUX area tracing.
+pub const PERF_RECORD_AUXTRACE: u32 = 71;
+pub const PERF_RECORD_TIME_CONV: u32 = 79;
+
+/// The size of a `PERF_RECORD_AUXTRACE` record, which is followed by the
+/// AUX data.
+const AUXTRACE_RECORD_SIZE: u64 = 48;
+
+/// The AUX data is decoded in chunks of this size, so that a buffer is never
+/// in memory as a whole.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The packet headers of the SPE trace format, from the kernel's
+/// `arm-spe-pkt-decoder.h`. Most headers have some index or size bits, so
+/// they're matched under a mask.
+const SPE_HEADER0_PAD: u8 = 0x00;
+const SPE_HEADER0_END: u8 = 0x01;
+const SPE_HEADER0_TIMESTAMP: u8 = 0x71;
+/// Under `0xcf`.
+const SPE_HEADER0_EVENTS: u8 = 0x42;
+/// Under `0xfc`. The second byte is the actual header.
+const SPE_HEADER0_EXTENDED: u8 = 0x20;
+/// Under `0xfc`.
+const SPE_HEADER0_CONTEXT: u8 = 0x64;
+const SPE_HEADER0_OP_TYPE: u8 = 0x48;
+/// Under `0xf8`.
+const SPE_HEADER0_ADDRESS: u8 = 0xb0;
+const SPE_HEADER0_COUNTER: u8 = 0x98;
+
+const SPE_ADDRESS_INDEX_INSTRUCTION: u8 = 0;
+const SPE_COUNTER_INDEX_TOTAL_LATENCY: u8 = 0;
+const SPE_OP_CLASS_LOAD_STORE: u8 = 1;
+
+const SPE_EVENT_L1D_REFILL: u64 = 1 << 3;
+const SPE_EVENT_TLB_WALK: u64 = 1 << 5;
+const SPE_EVENT_LLC_MISS: u64 = 1 << 9;
+
+/// Whether the recording has an event of the ARM Statistical Profiling
+/// Extension PMU, e.g. `arm_spe_0//`, whose samples are in the AUX data.
+pub fn has_arm_spe_event(attributes: &[AttributeDescription]) -> bool {
+    attributes
+        .iter()
+        .filter_map(|attr| attr.name())
+        .any(|name| name.starts_with("arm_spe"))
+}
+
+/// The body of a `PERF_RECORD_AUXTRACE` record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuxtraceHeader {
+    /// The size of the AUX data which follows the record.
+    pub size: u64,
+    /// Identifies the buffer in the auxtrace index of a perf.data file.
+    pub reference: u64,
+    /// None for buffers of CPU-wide recordings.
+    pub tid: Option<i32>,
+    pub cpu: u32,
+}
+
+impl AuxtraceHeader {
+    pub fn parse(data: &[u8], endian: Endianness) -> Option<Self> {
+        let tid = read_u32(data.get(28..32)?, endian);
+        Some(Self {
+            size: read_u64(data.get(..8)?, endian),
+            reference: read_u64(data.get(16..24)?, endian),
+            tid: (tid != u32::MAX).then(|| tid as i32),
+            cpu: read_u32(data.get(32..36)?, endian),
+        })
+    }
+}
+
+/// The body of a `PERF_RECORD_TIME_CONV` record, which converts the
+/// timestamps in the AUX data from CPU counter ticks to perf timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeConv {
+    shift: u32,
+    mult: u64,
+    zero: u64,
+    /// The counter value and mask for counters which are narrower than 64
+    /// bits, if the kernel set `cap_user_time_short`.
+    short: Option<(u64, u64)>,
+}
+
+impl TimeConv {
+    pub fn parse(data: &[u8], endian: Endianness) -> Option<Self> {
+        let short = match data.get(41) {
+            Some(&cap_user_time_short) if cap_user_time_short != 0 => Some((
+                read_u64(&data[24..32], endian),
+                read_u64(&data[32..40], endian),
+            )),
+            _ => None,
+        };
+        Some(Self {
+            shift: read_u64(data.get(..8)?, endian).min(63) as u32,
+            mult: read_u64(data.get(8..16)?, endian),
+            zero: read_u64(data.get(16..24)?, endian),
+            short,
+        })
+    }
+
+    /// Like perf's `tsc_to_perf_time`.
+    pub fn convert(&self, cycles: u64) -> u64 {
+        let cycles = match self.short {
+            Some((time_cycles, time_mask)) => {
+                time_cycles.wrapping_add(cycles.wrapping_sub(time_cycles) & time_mask)
+            }
+            None => cycles,
+        };
+        let quot = cycles >> self.shift;
+        let rem = cycles & ((1u64 << self.shift) - 1);
+        self.zero
+            .wrapping_add(quot.wrapping_mul(self.mult))
+            .wrapping_add(rem.wrapping_mul(self.mult) >> self.shift)
+    }
+}
+
+/// One decoded SPE record, i.e. one sampled operation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpeRecord {
+    pub pc: Option<u64>,
+    /// The exception level of the operation, 0 for user code.
+    pub exception_level: u8,
+    /// The cycles from dispatch to completion.
+    pub total_latency: Option<u16>,
+    pub events: u64,
+    pub op_class: Option<u8>,
+    /// The CONTEXTIDR value, which is the tid if the kernel was built with
+    /// `CONFIG_PID_IN_CONTEXTIDR`.
+    pub context: Option<u32>,
+    /// In CPU counter ticks.
+    pub timestamp: Option<u64>,
+}
+
+impl SpeRecord {
+    pub fn memory_access_kind(&self) -> MemoryAccessKind {
+        if self.events & SPE_EVENT_TLB_WALK != 0 {
+            MemoryAccessKind::TlbMiss
+        } else if self.events & SPE_EVENT_LLC_MISS != 0 {
+            MemoryAccessKind::LlcMiss
+        } else if self.events & SPE_EVENT_L1D_REFILL != 0 {
+            MemoryAccessKind::L1dMiss
+        } else {
+            MemoryAccessKind::Hit
+        }
+    }
+}
+
+/// Splits SPE trace data into packets and packets into records. The data
+/// can be passed in chunks of any size; a packet which is split across
+/// chunks is completed with the next chunk.
+#[derive(Debug, Default)]
+pub struct SpePacketDecoder {
+    /// The start of a packet at the end of the previous chunk.
+    partial: Vec<u8>,
+    record: SpeRecord,
+}
+
+impl SpePacketDecoder {
+    pub fn decode(&mut self, mut data: &[u8], mut on_record: impl FnMut(SpeRecord)) {
+        while !self.partial.is_empty() && !data.is_empty() {
+            self.partial.push(data[0]);
+            data = &data[1..];
+            if packet_len(&self.partial) == Some(self.partial.len()) {
+                let packet = std::mem::take(&mut self.partial);
+                self.handle_packet(&packet, &mut on_record);
+            }
+        }
+        while !data.is_empty() {
+            match packet_len(data) {
+                Some(len) if len <= data.len() => {
+                    self.handle_packet(&data[..len], &mut on_record);
+                    data = &data[len..];
+                }
+                _ => {
+                    self.partial.extend_from_slice(data);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Drops a partial packet at the end of a buffer. Buffers end at packet
+    /// boundaries, unless the kernel had to truncate one.
+    pub fn reset(&mut self) {
+        self.partial.clear();
+        self.record = SpeRecord::default();
+    }
+
+    fn handle_packet(&mut self, packet: &[u8], on_record: &mut impl FnMut(SpeRecord)) {
+        let (header, index, payload) = match packet[0] {
+            h0 if h0 & 0xfc == SPE_HEADER0_EXTENDED => (
+                packet[1],
+                ((h0 & 0x3) << 3) | (packet[1] & 0x7),
+                &packet[2..],
+            ),
+            h0 => (h0, h0 & 0x7, &packet[1..]),
+        };
+        // Payloads are always little-endian.
+        let value = payload
+            .iter()
+            .rev()
+            .fold(0u64, |value, &byte| (value << 8) | u64::from(byte));
+        match header {
+            SPE_HEADER0_PAD => {}
+            SPE_HEADER0_END => self.finish_record(on_record),
+            SPE_HEADER0_TIMESTAMP => {
+                self.record.timestamp = Some(value);
+                self.finish_record(on_record);
+            }
+            h if h & 0xcf == SPE_HEADER0_EVENTS => self.record.events = value,
+            h if h & 0xfc == SPE_HEADER0_CONTEXT => self.record.context = Some(value as u32),
+            h if h & 0xfc == SPE_HEADER0_OP_TYPE => self.record.op_class = Some(h & 0x3),
+            // Data and branch target addresses aren't needed for the samples.
+            h if h & 0xf8 == SPE_HEADER0_ADDRESS && index == SPE_ADDRESS_INDEX_INSTRUCTION => {
+                let exception_level = ((value >> 61) & 0x3) as u8;
+                let non_secure = value >> 63 != 0;
+                let mut pc = value & ((1 << 56) - 1);
+                // The top byte of kernel addresses isn't in the packet.
+                if non_secure && exception_level != 0 {
+                    pc |= 0xff << 56;
+                }
+                self.record.pc = Some(pc);
+                self.record.exception_level = exception_level;
+            }
+            h if h & 0xf8 == SPE_HEADER0_COUNTER && index == SPE_COUNTER_INDEX_TOTAL_LATENCY => {
+                self.record.total_latency = Some(value as u16);
+            }
+            _ => {}
+        }
+    }
+
+    fn finish_record(&mut self, on_record: &mut impl FnMut(SpeRecord)) {
+        let record = std::mem::take(&mut self.record);
+        if record.pc.is_some() {
+            on_record(record);
+        }
+    }
+}
+
+/// The size of the packet at the start of `data`, or None if `data` is too
+/// short to tell.
+fn packet_len(data: &[u8]) -> Option<usize> {
+    let payload_size = |header: u8| 1 << ((header >> 4) & 0x3);
+    match *data.first()? {
+        SPE_HEADER0_PAD | SPE_HEADER0_END => Some(1),
+        h0 if h0 & 0xfc == SPE_HEADER0_EXTENDED => Some(2 + payload_size(*data.get(1)?)),
+        h0 => Some(1 + payload_size(h0)),
+    }
+}
+
+/// A memory access from the SPE data, as a sample for
+/// [`Converter::handle_memory_latency_sample`](crate::linux_shared::Converter::handle_memory_latency_sample).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpeSample {
+    pub pid: i32,
+    pub timestamp: u64,
+    pub pc: u64,
+    pub mode: StackMode,
+    /// In cycles, at least 1.
+    pub latency: u32,
+    pub kind: MemoryAccessKind,
+}
+
+/// Turns the AUX data of an `arm_spe` event into memory latency samples.
+///
+/// The AUX data of a buffer arrives after the records of the time it
+/// covers, so the decoded samples wait until the records have caught up
+/// with them. That way the converter has seen the mappings and threads of
+/// each sample when it gets it, and the thread of a CPU-wide buffer is the
+/// one which the last context switch on the sample's CPU switched in.
+#[derive(Debug, Default)]
+pub struct ArmSpe {
+    decoder: SpePacketDecoder,
+    chunk: Vec<u8>,
+    pending: BinaryHeap<Reverse<PendingSample>>,
+    next_sequence: u64,
+    pid_by_tid: HashMap<i32, i32>,
+    pid_by_cpu: HashMap<u32, i32>,
+    pub stats: ArmSpeStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArmSpeStats {
+    pub buffer_count: u64,
+    pub record_count: u64,
+    pub sample_count: u64,
+    /// Records of operations other than loads and stores.
+    pub skipped_record_count: u64,
+    /// Samples whose thread wasn't known.
+    pub unattributed_sample_count: u64,
+}
+
+#[derive(Debug)]
+struct PendingSample {
+    timestamp: u64,
+    sequence: u64,
+    cpu: u32,
+    /// From the buffer or the record, if either has it.
+    tid: Option<i32>,
+    record: SpeRecord,
+}
+
+impl PendingSample {
+    fn key(&self) -> (u64, u64) {
+        (self.timestamp, self.sequence)
+    }
+}
+
+impl PartialEq for PendingSample {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for PendingSample {}
+
+impl PartialOrd for PendingSample {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PendingSample {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl ArmSpe {
+    /// Tracks which thread runs on which CPU, from the pid, tid and CPU of
+    /// a record which the converter is about to handle. A switch-out record
+    /// has the thread which stops running.
+    pub fn observe_record(
+        &mut self,
+        pid: Option<i32>,
+        tid: Option<i32>,
+        cpu: Option<u32>,
+        is_switch_out: bool,
+    ) {
+        let (Some(pid), Some(tid)) = (pid, tid) else {
+            return;
+        };
+        self.pid_by_tid.insert(tid, pid);
+        if let (Some(cpu), false) = (cpu, is_switch_out) {
+            self.pid_by_cpu.insert(cpu, pid);
+        }
+    }
+
+    /// Decodes the AUX data of a `PERF_RECORD_AUXTRACE` record from `reader`,
+    /// in chunks. Records without a timestamp, or without a `time_conv` to
+    /// convert it, get `arrival_timestamp`.
+    pub fn decode_buffer(
+        &mut self,
+        header: &AuxtraceHeader,
+        arrival_timestamp: u64,
+        time_conv: Option<&TimeConv>,
+        reader: impl Read,
+    ) -> io::Result<()> {
+        self.stats.buffer_count += 1;
+        self.chunk.resize(CHUNK_SIZE, 0);
+        let mut reader = reader.take(header.size);
+        let mut records = Vec::new();
+        loop {
+            let len = match reader.read(&mut self.chunk) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            self.decoder
+                .decode(&self.chunk[..len], |record| records.push(record));
+            for record in records.drain(..) {
+                self.add_record(header, arrival_timestamp, time_conv, record);
+            }
+        }
+        self.decoder.reset();
+        Ok(())
+    }
+
+    fn add_record(
+        &mut self,
+        header: &AuxtraceHeader,
+        arrival_timestamp: u64,
+        time_conv: Option<&TimeConv>,
+        record: SpeRecord,
+    ) {
+        self.stats.record_count += 1;
+        if record.op_class != Some(SPE_OP_CLASS_LOAD_STORE) {
+            self.stats.skipped_record_count += 1;
+            return;
+        }
+        let timestamp = match (record.timestamp, time_conv) {
+            (Some(cycles), Some(time_conv)) => time_conv.convert(cycles),
+            _ => arrival_timestamp,
+        };
+        let tid = record.context.map(|context| context as i32).or(header.tid);
+        self.pending.push(Reverse(PendingSample {
+            timestamp,
+            sequence: self.next_sequence,
+            cpu: header.cpu,
+            tid,
+            record,
+        }));
+        self.next_sequence += 1;
+    }
+
+    /// Returns the next sample up to `timestamp`, in timestamp order.
+    pub fn pop_until(&mut self, timestamp: u64) -> Option<SpeSample> {
+        loop {
+            match self.pending.peek() {
+                Some(Reverse(pending)) if pending.timestamp <= timestamp => {}
+                _ => return None,
+            }
+            let Reverse(pending) = self.pending.pop().unwrap();
+            let pid = match pending.tid {
+                Some(tid) => Some(self.pid_by_tid.get(&tid).copied().unwrap_or(tid)),
+                None => self.pid_by_cpu.get(&pending.cpu).copied(),
+            };
+            let Some(pid) = pid else {
+                self.stats.unattributed_sample_count += 1;
+                continue;
+            };
+            let record = pending.record;
+            self.stats.sample_count += 1;
+            return Some(SpeSample {
+                pid,
+                timestamp: pending.timestamp,
+                pc: record.pc.unwrap_or_default(),
+                mode: match record.exception_level {
+                    0 => StackMode::User,
+                    _ => StackMode::Kernel,
+                },
+                latency: u32::from(record.total_latency.unwrap_or(0)).max(1),
+                kind: record.memory_access_kind(),
+            });
+        }
+    }
+
+    pub fn report(&self) {
+        let ArmSpeStats {
+            buffer_count,
+            record_count,
+            sample_count,
+            skipped_record_count,
+            unattributed_sample_count,
+        } = self.stats;
+        info!(
+            record_count,
+            buffer_count,
+            sample_count,
+            skipped_record_count,
+            unattributed_sample_count,
+            "Decoded {record_count} ARM SPE records from {buffer_count} AUX buffers into \
+             {sample_count} memory latency samples. Skipped {skipped_record_count} records of \
+             other operations and {unattributed_sample_count} records of unknown threads."
+        );
+    }
+}
+
+/// Where the AUX data of each buffer is in a perf.data file, from the
+/// `HEADER_AUXTRACE` feature section.
+#[derive(Debug, Default)]
+pub struct AuxtraceIndex {
+    data_offset_by_reference: HashMap<u64, u64>,
+}
+
+impl AuxtraceIndex {
+    /// The section is a u64 count followed by the file offset and size of
+    /// each `PERF_RECORD_AUXTRACE` record. The record has the reference of
+    /// the buffer, and the data follows it.
+    pub fn read(section: &[u8], file: &File, endian: Endianness) -> io::Result<Self> {
+        let count = section.get(..8).map_or(0, |count| read_u64(count, endian));
+        let mut data_offset_by_reference = HashMap::new();
+        let entries = section.get(8..).unwrap_or_default();
+        for entry in entries.chunks_exact(16).take(count as usize) {
+            let record_offset = read_u64(&entry[..8], endian);
+            let mut record = [0; AUXTRACE_RECORD_SIZE as usize];
+            FileRange::new(file, record_offset).read_exact(&mut record)?;
+            if let Some(header) = AuxtraceHeader::parse(&record[8..], endian) {
+                data_offset_by_reference
+                    .insert(header.reference, record_offset + AUXTRACE_RECORD_SIZE);
+            }
+        }
+        Ok(Self {
+            data_offset_by_reference,
+        })
+    }
+
+    /// A reader for the data of the buffer, without moving the file position
+    /// which the record reader uses.
+    pub fn data<'a>(&self, file: &'a File, header: &AuxtraceHeader) -> Option<FileRange<'a>> {
+        let offset = *self.data_offset_by_reference.get(&header.reference)?;
+        Some(FileRange::new(file, offset))
+    }
+}
+
+/// Reads a file from an offset with positioned reads.
+pub struct FileRange<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl<'a> FileRange<'a> {
+    fn new(file: &'a File, offset: u64) -> Self {
+        Self { file, offset }
+    }
+}
+
+impl Read for FileRange<'_> {
+    #[cfg(unix)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::os::unix::fs::FileExt::read_at(self.file, buf, self.offset)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+
+    /// Positioned reads move the file position on Windows.
+    #[cfg(not(unix))]
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+fn read_u32(data: &[u8], endian: Endianness) -> u32 {
+    let bytes = data[..4].try_into().unwrap();
+    match endian {
+        Endianness::LittleEndian => u32::from_le_bytes(bytes),
+        Endianness::BigEndian => u32::from_be_bytes(bytes),
+    }
+}
+
+fn read_u64(data: &[u8], endian: Endianness) -> u64 {
+    let bytes = data[..8].try_into().unwrap();
+    match endian {
+        Endianness::LittleEndian => u64::from_le_bytes(bytes),
+        Endianness::BigEndian => u64::from_be_bytes(bytes),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// The packets of a load record: the PC, the total latency, the events,
+    /// a load/store op type, and either a timestamp or an END packet.
+    pub fn load_record(pc: u64, latency: u16, events: u16, timestamp: Option<u64>) -> Vec<u8> {
+        let mut record = vec![SPE_HEADER0_ADDRESS | SPE_ADDRESS_INDEX_INSTRUCTION];
+        record.extend_from_slice(&pc.to_le_bytes());
+        record.push(SPE_HEADER0_COUNTER | SPE_COUNTER_INDEX_TOTAL_LATENCY);
+        record.extend_from_slice(&latency.to_le_bytes());
+        // A two-byte events packet.
+        record.push(SPE_HEADER0_EVENTS | 0x10);
+        record.extend_from_slice(&events.to_le_bytes());
+        record.extend_from_slice(&[SPE_HEADER0_OP_TYPE | SPE_OP_CLASS_LOAD_STORE, 0x00]);
+        match timestamp {
+            Some(timestamp) => {
+                record.push(SPE_HEADER0_TIMESTAMP);
+                record.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            None => record.push(SPE_HEADER0_END),
+        }
+        record
+    }
+
+    #[test]
+    fn decodes_records_split_across_chunks() {
+        let mut data = load_record(0x1010, 12, 0, Some(1000));
+        data.extend_from_slice(&[SPE_HEADER0_PAD; 3]);
+        // A kernel load which missed the TLB, at EL1 in non-secure state.
+        data.extend(load_record(
+            0xa0ff_8000_8000_1234,
+            400,
+            SPE_EVENT_TLB_WALK as u16,
+            None,
+        ));
+        // A branch, with an extended header for its target address.
+        data.extend_from_slice(&[SPE_HEADER0_ADDRESS, 0x00, 0x20]);
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&[SPE_HEADER0_EXTENDED, SPE_HEADER0_ADDRESS | 1]);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[SPE_HEADER0_OP_TYPE | 2, 0, SPE_HEADER0_END]);
+
+        for chunk_size in [1, 5, data.len()] {
+            let mut decoder = SpePacketDecoder::default();
+            let mut records = Vec::new();
+            for chunk in data.chunks(chunk_size) {
+                decoder.decode(chunk, |record| records.push(record));
+            }
+            assert_eq!(records.len(), 3);
+            assert_eq!(records[0].pc, Some(0x1010));
+            assert_eq!(records[0].total_latency, Some(12));
+            assert_eq!(records[0].timestamp, Some(1000));
+            assert_eq!(records[0].memory_access_kind(), MemoryAccessKind::Hit);
+            assert_eq!(records[1].pc, Some(0xffff_8000_8000_1234));
+            assert_eq!(records[1].exception_level, 1);
+            assert_eq!(records[1].memory_access_kind(), MemoryAccessKind::TlbMiss);
+            assert_eq!(records[2].op_class, Some(2));
+        }
+    }
+
+    #[test]
+    fn attributes_samples_to_the_thread_on_the_cpu() {
+        let mut arm_spe = ArmSpe::default();
+        let header = AuxtraceHeader {
+            size: 0,
+            reference: 0,
+            tid: None,
+            cpu: 1,
+        };
+        // shift 1, mult 3, zero 100: 100 + ticks * 3 / 2
+        let time_conv = TimeConv {
+            shift: 1,
+            mult: 3,
+            zero: 100,
+            short: None,
+        };
+        let mut data = load_record(0x1000, 30, SPE_EVENT_LLC_MISS as u16, Some(200));
+        data.extend(load_record(0x1004, 5, 0, Some(100)));
+        let header = AuxtraceHeader {
+            size: data.len() as u64,
+            ..header
+        };
+        arm_spe
+            .decode_buffer(&header, 5000, Some(&time_conv), &data[..])
+            .unwrap();
+
+        arm_spe.observe_record(Some(10), Some(11), Some(1), false);
+        assert_eq!(arm_spe.pop_until(249), None);
+        let sample = arm_spe.pop_until(250).unwrap();
+        assert_eq!((sample.pc, sample.timestamp), (0x1004, 250));
+        assert_eq!(sample.pid, 10);
+        // The thread switches out, and another one in.
+        arm_spe.observe_record(Some(10), Some(11), Some(1), true);
+        arm_spe.observe_record(Some(20), Some(21), Some(1), false);
+        let sample = arm_spe.pop_until(u64::MAX).unwrap();
+        assert_eq!(sample.pid, 20);
+        assert_eq!(
+            (sample.latency, sample.kind),
+            (30, MemoryAccessKind::LlcMiss)
+        );
+        assert_eq!(arm_spe.pop_until(u64::MAX), None);
+    }
+}
@@ -0,0 +1,101 @@
+use linux_perf_data::linux_perf_event_reader::{RawEventRecord, RecordType, SampleFormat};
+use linux_perf_data::Endianness;
+use tracing::info;
+
+/// The sample_type bit for samples with an AUX area snapshot, from
+/// `perf record --aux-sample`.
+const PERF_SAMPLE_AUX: u64 = 1 << 20;
+
+/// Handles sample records with an AUX area snapshot.
+///
+/// We don't decode the AUX data. The AUX field is the last field of a sample
+/// record, so the other fields can be parsed as if the field wasn't there.
+#[derive(Debug, Clone, Default)]
+pub struct AuxSamples {
+    pub sample_count: u64,
+    pub skipped_bytes: u64,
+}
+
+impl AuxSamples {
+    /// Returns the record with the AUX field removed from its sample format,
+    /// so that the record parser doesn't need to know about it.
+    pub fn strip_aux_field<'a>(&mut self, mut record: RawEventRecord<'a>) -> RawEventRecord<'a> {
+        let sample_format = record.parse_info.sample_format.bits();
+        if record.record_type != RecordType::SAMPLE || sample_format & PERF_SAMPLE_AUX == 0 {
+            return record;
+        }
+        self.sample_count += 1;
+        let data = record.data.as_slice();
+        if let Some(size) = aux_size(&data, record.parse_info.endian) {
+            self.skipped_bytes += size;
+        }
+        record.parse_info.sample_format =
+            SampleFormat::from_bits_truncate(sample_format & !PERF_SAMPLE_AUX);
+        record
+    }
+
+    pub fn report(&self) {
+        if self.sample_count == 0 {
+            return;
+        }
+        info!(
+            skipped_bytes = self.skipped_bytes,
+            sample_count = self.sample_count,
+            "Skipped {} bytes of AUX data in {} samples recorded with --aux-sample.",
+            self.skipped_bytes,
+            self.sample_count
+        );
+    }
+}
+
+/// Returns the size of the AUX data at the end of a sample record.
+///
+/// The record ends with the u64 size of the AUX data followed by the data,
+/// whose size the kernel rounds up to a multiple of 8. We don't know where
+/// the field starts without parsing all the other fields, so we look for the
+/// largest size which matches the distance to the end. A smaller match can
+/// be inside the AUX data, e.g. if it ends in zeros.
+fn aux_size(data: &[u8], endian: Endianness) -> Option<u64> {
+    let read_u64 = |offset: usize| {
+        let bytes: [u8; 8] = data[offset..offset + 8].try_into().unwrap();
+        match endian {
+            Endianness::LittleEndian => u64::from_le_bytes(bytes),
+            Endianness::BigEndian => u64::from_be_bytes(bytes),
+        }
+    };
+    let max_size = data.len().checked_sub(8)? / 8 * 8;
+    (0..=max_size)
+        .rev()
+        .step_by(8)
+        .find(|&size| read_u64(data.len() - 8 - size) == size as u64)
+        .map(|size| size as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample_data(fields: &[u64], aux: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = fields.iter().flat_map(|f| f.to_le_bytes()).collect();
+        data.extend_from_slice(&(aux.len() as u64).to_le_bytes());
+        data.extend_from_slice(aux);
+        data
+    }
+
+    #[test]
+    fn finds_aux_size() {
+        let endian = Endianness::LittleEndian;
+        // ip, pid/tid, time
+        let fields = [0x55d1e2f0, 0x0000_1234_0000_1234, 1_000_000];
+
+        assert_eq!(aux_size(&sample_data(&fields, &[]), endian), Some(0));
+        let aux: Vec<u8> = (1..=32).collect();
+        assert_eq!(aux_size(&sample_data(&fields, &aux), endian), Some(32));
+        // Trailing zeros in the AUX data look like an empty AUX field.
+        let mut aux = vec![0xab; 16];
+        aux.extend_from_slice(&[0; 16]);
+        assert_eq!(aux_size(&sample_data(&fields, &aux), endian), Some(32));
+
+        assert_eq!(aux_size(&[0; 4], endian), None);
+    }
+}
@@ -0,0 +1,135 @@
+use linux_perf_data::linux_perf_event_reader::{RawEventRecord, RecordType};
+use linux_perf_data::{AttributeDescription, Endianness};
+
+use super::data_src::{SampleReader, PERF_SAMPLE_BRANCH_STACK, PERF_SAMPLE_READ};
+use crate::linux_shared::LbrCall;
+
+/// The branch_sample_type bit of events recorded with `--call-graph lbr`,
+/// whose branch stack is a call stack rather than the most recent branches.
+const PERF_SAMPLE_BRANCH_CALL_STACK: u64 = 1 << 11;
+/// The branch_sample_type bit for the u64 hardware index before the entries.
+const PERF_SAMPLE_BRANCH_HW_INDEX: u64 = 1 << 17;
+
+/// How the LBR call stacks of an event's samples are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbrCallStackFormat {
+    has_hw_index: bool,
+}
+
+/// Returns the LBR call stack format of each event, or None for events
+/// which weren't recorded with `--call-graph lbr`.
+pub fn lbr_call_stack_formats(attrs: &[AttributeDescription]) -> Vec<Option<LbrCallStackFormat>> {
+    attrs
+        .iter()
+        .map(|attr| {
+            let branch_sample_type = attr.attr.branch_sample_format.bits();
+            (branch_sample_type & PERF_SAMPLE_BRANCH_CALL_STACK != 0).then(|| LbrCallStackFormat {
+                has_hw_index: branch_sample_type & PERF_SAMPLE_BRANCH_HW_INDEX != 0,
+            })
+        })
+        .collect()
+}
+
+/// Returns the LBR call stack of a sample record, most recent call first.
+/// The record parser skips the branch stack, so we read it ourselves.
+///
+/// Samples with read values are skipped, because the size of that field
+/// depends on parts of the event attribute which the parse info doesn't
+/// have. `--call-graph lbr` doesn't use it.
+pub fn sample_lbr_calls(
+    record: &RawEventRecord,
+    format: Option<LbrCallStackFormat>,
+) -> Vec<LbrCall> {
+    let sample_format = record.parse_info.sample_format.bits();
+    let Some(format) = format else {
+        return Vec::new();
+    };
+    if record.record_type != RecordType::SAMPLE
+        || sample_format & PERF_SAMPLE_BRANCH_STACK == 0
+        || sample_format & PERF_SAMPLE_READ != 0
+    {
+        return Vec::new();
+    }
+    let data = record.data.as_slice();
+    read_lbr_calls(&data, sample_format, format, record.parse_info.endian).unwrap_or_default()
+}
+
+/// Reads the branch stack, which comes after the raw data. Each entry is a
+/// (from, to, flags) triple of u64s. Empty entries are dropped.
+fn read_lbr_calls(
+    data: &[u8],
+    sample_format: u64,
+    format: LbrCallStackFormat,
+    endian: Endianness,
+) -> Option<Vec<LbrCall>> {
+    let mut reader = SampleReader::new(data, endian);
+    reader.skip_fields_before_branch_stack(sample_format)?;
+    let nr = usize::try_from(reader.u64()?).ok()?;
+    if format.has_hw_index {
+        reader.skip(8)?;
+    }
+    let mut calls = Vec::with_capacity(nr.min(data.len() / 24));
+    for _ in 0..nr {
+        let (from, to) = (reader.u64()?, reader.u64()?);
+        let _flags = reader.u64()?;
+        if from != 0 {
+            calls.push(LbrCall { from, to });
+        }
+    }
+    Some(calls)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PERF_SAMPLE_IP: u64 = 1 << 0;
+    const PERF_SAMPLE_TID: u64 = 1 << 1;
+    const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+
+    fn sample_data(has_hw_index: bool, entries: &[[u64; 3]]) -> Vec<u8> {
+        let mut values = vec![0x2040u64, 0x0000_1234_0000_1234];
+        // A callchain with a kernel frame after the kernel context.
+        values.extend([2, u64::MAX - 127, 0xffff_ffff_8100_0000]);
+        values.push(entries.len() as u64);
+        if has_hw_index {
+            values.push(7);
+        }
+        values.extend(entries.iter().flatten());
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_lbr_call_stack() {
+        let endian = Endianness::LittleEndian;
+        let sample_format =
+            PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_CALLCHAIN | PERF_SAMPLE_BRANCH_STACK;
+        let entries = [[0x1050, 0x2000, 0], [0x3010, 0x1000, 0], [0, 0, 0]];
+        let expected = vec![
+            LbrCall {
+                from: 0x1050,
+                to: 0x2000,
+            },
+            LbrCall {
+                from: 0x3010,
+                to: 0x1000,
+            },
+        ];
+        for has_hw_index in [false, true] {
+            let format = LbrCallStackFormat { has_hw_index };
+            let data = sample_data(has_hw_index, &entries);
+            assert_eq!(
+                read_lbr_calls(&data, sample_format, format, endian),
+                Some(expected.clone())
+            );
+            // A truncated record.
+            assert_eq!(
+                read_lbr_calls(&data[..data.len() - 8], sample_format, format, endian),
+                None
+            );
+        }
+    }
+}
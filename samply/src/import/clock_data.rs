@@ -0,0 +1,212 @@
+use linux_perf_data::Endianness;
+
+/// The CLOCK_DATA section of a perf.data header, which `perf record -k`
+/// writes: a pair of readings of the wall clock and of the clock of the
+/// record timestamps, taken at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockData {
+    pub clockid: u32,
+    pub wall_clock_ns: u64,
+    pub clockid_time_ns: u64,
+}
+
+impl ClockData {
+    pub fn parse(data: &[u8], endian: Endianness) -> Option<Self> {
+        let version = read_u32(data.get(..4)?, endian);
+        if version != 1 {
+            return None;
+        }
+        Some(Self {
+            clockid: read_u32(data.get(4..8)?, endian),
+            wall_clock_ns: read_u64(data.get(8..16)?, endian),
+            clockid_time_ns: read_u64(data.get(16..24)?, endian),
+        })
+    }
+
+    fn wall_clock_time(&self, timestamp: u64) -> i128 {
+        i128::from(self.wall_clock_ns) + i128::from(timestamp) - i128::from(self.clockid_time_ns)
+    }
+}
+
+/// The clock of one of the perf.data files which are converted into one
+/// profile.
+#[derive(Debug, Clone)]
+pub struct InputClock {
+    pub host: String,
+    /// The record timestamp which the file's own conversion would convert
+    /// to zero.
+    pub reference_timestamp: u64,
+    pub clock_data: Option<ClockData>,
+}
+
+/// The reference timestamps which line up the inputs, see [`align_inputs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlignedInputs {
+    /// For each input, the record timestamp which is converted to zero.
+    pub reference_timestamps: Vec<u64>,
+    /// The wall-clock time of zero, in nanoseconds since the UNIX epoch, if
+    /// all inputs have clock data.
+    pub wall_clock_reference_ns: Option<u64>,
+}
+
+/// Picks a reference timestamp for each input, so that their records end up
+/// on one timeline.
+///
+/// If all inputs have clock data, their timestamps are converted through the
+/// wall clock, and zero is the earliest of their own reference times. Inputs
+/// from the same host with the same clock share their clock, so they get the
+/// same reference timestamp, from the clock data of the first of them.
+/// Otherwise, all inputs are assumed to have the same clock, which is true for
+/// recordings on the same machine with perf's default clock.
+pub fn align_inputs(inputs: &[InputClock]) -> AlignedInputs {
+    let Some(clock_data) = inputs
+        .iter()
+        .map(|input| input.clock_data)
+        .collect::<Option<Vec<ClockData>>>()
+    else {
+        let earliest_reference = inputs
+            .iter()
+            .map(|input| input.reference_timestamp)
+            .min()
+            .unwrap_or(0);
+        return AlignedInputs {
+            reference_timestamps: vec![earliest_reference; inputs.len()],
+            wall_clock_reference_ns: None,
+        };
+    };
+
+    // The clock data of the first input of each clock.
+    let shared_clock_data: Vec<ClockData> = inputs
+        .iter()
+        .map(|input| {
+            let first = inputs
+                .iter()
+                .position(|other| {
+                    other.host == input.host
+                        && other.clock_data.map(|c| c.clockid)
+                            == input.clock_data.map(|c| c.clockid)
+                })
+                .unwrap();
+            clock_data[first]
+        })
+        .collect();
+    let wall_clock_reference = inputs
+        .iter()
+        .zip(&shared_clock_data)
+        .map(|(input, clock_data)| clock_data.wall_clock_time(input.reference_timestamp))
+        .min()
+        .unwrap_or(0)
+        .max(0);
+    let reference_timestamps = shared_clock_data
+        .iter()
+        .map(|clock_data| {
+            let reference = i128::from(clock_data.clockid_time_ns) + wall_clock_reference
+                - i128::from(clock_data.wall_clock_ns);
+            reference.clamp(0, i128::from(u64::MAX)) as u64
+        })
+        .collect();
+    AlignedInputs {
+        reference_timestamps,
+        wall_clock_reference_ns: Some(wall_clock_reference as u64),
+    }
+}
+
+fn read_u32(data: &[u8], endian: Endianness) -> u32 {
+    let bytes = data[..4].try_into().unwrap();
+    match endian {
+        Endianness::LittleEndian => u32::from_le_bytes(bytes),
+        Endianness::BigEndian => u32::from_be_bytes(bytes),
+    }
+}
+
+fn read_u64(data: &[u8], endian: Endianness) -> u64 {
+    let bytes = data[..8].try_into().unwrap();
+    match endian {
+        Endianness::LittleEndian => u64::from_le_bytes(bytes),
+        Endianness::BigEndian => u64::from_be_bytes(bytes),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_MONOTONIC: u32 = 1;
+    const CLOCK_BOOTTIME: u32 = 7;
+
+    fn input(host: &str, reference_timestamp: u64, clock_data: Option<ClockData>) -> InputClock {
+        InputClock {
+            host: host.to_owned(),
+            reference_timestamp,
+            clock_data,
+        }
+    }
+
+    fn clock_data(clockid: u32, wall_clock_ns: u64, clockid_time_ns: u64) -> ClockData {
+        ClockData {
+            clockid,
+            wall_clock_ns,
+            clockid_time_ns,
+        }
+    }
+
+    #[test]
+    fn parses_clock_data() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&CLOCK_MONOTONIC.to_le_bytes());
+        data.extend_from_slice(&1_000_000u64.to_le_bytes());
+        data.extend_from_slice(&500u64.to_le_bytes());
+        assert_eq!(
+            ClockData::parse(&data, Endianness::LittleEndian),
+            Some(clock_data(CLOCK_MONOTONIC, 1_000_000, 500))
+        );
+        assert_eq!(
+            ClockData::parse(&data[..20], Endianness::LittleEndian),
+            None
+        );
+        data[0] = 2;
+        assert_eq!(ClockData::parse(&data, Endianness::LittleEndian), None);
+    }
+
+    #[test]
+    fn lines_up_inputs_through_the_wall_clock() {
+        let aligned = align_inputs(&[
+            // Starts at wall clock time 10_500.
+            input("a", 1_500, Some(clock_data(CLOCK_MONOTONIC, 10_000, 1_000))),
+            // The same clock, with a reading which drifted by 2ns. Starts at
+            // wall clock time 10_200, which becomes zero.
+            input("a", 1_200, Some(clock_data(CLOCK_MONOTONIC, 10_502, 1_500))),
+            // Another clock on the same host, and the same clock on another
+            // host. Both start at wall clock time 10_400.
+            input("a", 400, Some(clock_data(CLOCK_BOOTTIME, 10_000, 0))),
+            input(
+                "b",
+                80_400,
+                Some(clock_data(CLOCK_MONOTONIC, 10_000, 80_000)),
+            ),
+        ]);
+        assert_eq!(
+            aligned,
+            AlignedInputs {
+                reference_timestamps: vec![1_200, 1_200, 200, 80_200],
+                wall_clock_reference_ns: Some(10_200),
+            }
+        );
+    }
+
+    #[test]
+    fn assumes_a_shared_clock_without_clock_data() {
+        let aligned = align_inputs(&[
+            input("a", 1_500, Some(clock_data(CLOCK_MONOTONIC, 10_000, 1_000))),
+            input("a", 1_200, None),
+        ]);
+        assert_eq!(
+            aligned,
+            AlignedInputs {
+                reference_timestamps: vec![1_200, 1_200],
+                wall_clock_reference_ns: None,
+            }
+        );
+    }
+}
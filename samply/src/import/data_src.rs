@@ -0,0 +1,205 @@
+use linux_perf_data::linux_perf_event_reader::{RawEventRecord, RecordType};
+use linux_perf_data::Endianness;
+
+/// The sample_type bits of the fields up to `PERF_SAMPLE_DATA_SRC`. The
+/// record parser doesn't read the data source, so we find it ourselves.
+const PERF_SAMPLE_IP: u64 = 1 << 0;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_ADDR: u64 = 1 << 3;
+pub(super) const PERF_SAMPLE_READ: u64 = 1 << 4;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+const PERF_SAMPLE_ID: u64 = 1 << 6;
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+const PERF_SAMPLE_RAW: u64 = 1 << 10;
+pub(super) const PERF_SAMPLE_BRANCH_STACK: u64 = 1 << 11;
+const PERF_SAMPLE_REGS_USER: u64 = 1 << 12;
+const PERF_SAMPLE_STACK_USER: u64 = 1 << 13;
+const PERF_SAMPLE_WEIGHT: u64 = 1 << 14;
+const PERF_SAMPLE_DATA_SRC: u64 = 1 << 15;
+const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+const PERF_SAMPLE_WEIGHT_STRUCT: u64 = 1 << 24;
+
+/// The fixed-size fields before the callchain, each one u64.
+const FIXED_SIZE_FIELDS: [u64; 9] = [
+    PERF_SAMPLE_IDENTIFIER,
+    PERF_SAMPLE_IP,
+    PERF_SAMPLE_TID,
+    PERF_SAMPLE_TIME,
+    PERF_SAMPLE_ADDR,
+    PERF_SAMPLE_ID,
+    PERF_SAMPLE_STREAM_ID,
+    PERF_SAMPLE_CPU,
+    PERF_SAMPLE_PERIOD,
+];
+
+/// Returns the `perf_mem_data_src` of a sample record of a memory access
+/// event, e.g. from `perf mem record`.
+///
+/// Samples with read values or branch stacks are skipped, because the size
+/// of those fields depends on parts of the event attribute which the parse
+/// info doesn't have. `perf mem record` doesn't use either.
+pub fn sample_data_src(record: &RawEventRecord) -> Option<u64> {
+    let sample_format = record.parse_info.sample_format.bits();
+    if record.record_type != RecordType::SAMPLE
+        || sample_format & PERF_SAMPLE_DATA_SRC == 0
+        || sample_format & (PERF_SAMPLE_READ | PERF_SAMPLE_BRANCH_STACK) != 0
+    {
+        return None;
+    }
+    let data = record.data.as_slice();
+    let user_regs_count = record.parse_info.sample_regs_user.count_ones();
+    read_data_src(
+        &data,
+        sample_format,
+        user_regs_count,
+        record.parse_info.endian,
+    )
+}
+
+/// Skips the fields before the data source, in the order in which the
+/// kernel writes them.
+fn read_data_src(
+    data: &[u8],
+    sample_format: u64,
+    user_regs_count: u32,
+    endian: Endianness,
+) -> Option<u64> {
+    let mut reader = SampleReader::new(data, endian);
+    let has = |field: u64| sample_format & field != 0;
+    reader.skip_fields_before_branch_stack(sample_format)?;
+    if has(PERF_SAMPLE_REGS_USER) {
+        let abi = reader.u64()?;
+        if abi != 0 {
+            reader.skip(user_regs_count as usize * 8)?;
+        }
+    }
+    if has(PERF_SAMPLE_STACK_USER) {
+        let size = reader.u64()?;
+        reader.skip(usize::try_from(size).ok()?)?;
+        if size != 0 {
+            let _dyn_size = reader.u64()?;
+        }
+    }
+    if has(PERF_SAMPLE_WEIGHT) || has(PERF_SAMPLE_WEIGHT_STRUCT) {
+        reader.skip(8)?;
+    }
+    reader.u64()
+}
+
+pub(super) struct SampleReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    endian: Endianness,
+}
+
+impl<'a> SampleReader<'a> {
+    pub(super) fn new(data: &'a [u8], endian: Endianness) -> Self {
+        Self {
+            data,
+            offset: 0,
+            endian,
+        }
+    }
+
+    /// Skips the fields up to and including the raw data. Samples with read
+    /// values must have been ruled out by the caller.
+    pub(super) fn skip_fields_before_branch_stack(&mut self, sample_format: u64) -> Option<()> {
+        let has = |field: u64| sample_format & field != 0;
+        let fixed_size_field_count = FIXED_SIZE_FIELDS.iter().filter(|&&f| has(f)).count();
+        self.skip(fixed_size_field_count * 8)?;
+        if has(PERF_SAMPLE_CALLCHAIN) {
+            let nr = self.u64()?;
+            self.skip(usize::try_from(nr).ok()?.checked_mul(8)?)?;
+        }
+        if has(PERF_SAMPLE_RAW) {
+            // A u32 size and the data, padded so that both end at a multiple of 8.
+            let size = self.u32()?;
+            self.skip(size as usize)?;
+            self.skip((8 - self.offset % 8) % 8)?;
+        }
+        Some(())
+    }
+
+    pub(super) fn skip(&mut self, len: usize) -> Option<()> {
+        let end = self.offset.checked_add(len)?;
+        if end > self.data.len() {
+            return None;
+        }
+        self.offset = end;
+        Some(())
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + 4)?
+            .try_into()
+            .unwrap();
+        self.offset += 4;
+        Some(match self.endian {
+            Endianness::LittleEndian => u32::from_le_bytes(bytes),
+            Endianness::BigEndian => u32::from_be_bytes(bytes),
+        })
+    }
+
+    pub(super) fn u64(&mut self) -> Option<u64> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + 8)?
+            .try_into()
+            .unwrap();
+        self.offset += 8;
+        Some(match self.endian {
+            Endianness::LittleEndian => u64::from_le_bytes(bytes),
+            Endianness::BigEndian => u64::from_be_bytes(bytes),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_data_src_after_variable_size_fields() {
+        let endian = Endianness::LittleEndian;
+        let data_src: u64 = 0x1_6810_0142;
+        let sample_format = PERF_SAMPLE_IP
+            | PERF_SAMPLE_TID
+            | PERF_SAMPLE_TIME
+            | PERF_SAMPLE_CALLCHAIN
+            | PERF_SAMPLE_RAW
+            | PERF_SAMPLE_REGS_USER
+            | PERF_SAMPLE_WEIGHT
+            | PERF_SAMPLE_DATA_SRC;
+        let mut data: Vec<u8> = Vec::new();
+        // ip, pid/tid, time
+        for value in [0x55d1e2f0u64, 0x0000_1234_0000_1234, 1_000_000] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        // A callchain with two entries.
+        for value in [2u64, 0x55d1e2f0, 0x55d1e000] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        // 12 bytes of raw data, padded to 16 with the size.
+        data.extend_from_slice(&12u32.to_le_bytes());
+        data.extend_from_slice(&[0xab; 12]);
+        // ABI 2 and three registers.
+        for value in [2u64, 1, 2, 3] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        // weight, data_src
+        data.extend_from_slice(&250u64.to_le_bytes());
+        data.extend_from_slice(&data_src.to_le_bytes());
+
+        assert_eq!(
+            read_data_src(&data, sample_format, 3, endian),
+            Some(data_src)
+        );
+        // With the wrong register count, the data source is past the end.
+        assert_eq!(read_data_src(&data, sample_format, 4, endian), None);
+    }
+}
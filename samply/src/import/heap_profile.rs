@@ -0,0 +1,313 @@
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Unrecognized heap profile header {0:?}")]
+    UnrecognizedHeader(String),
+
+    #[error("Malformed line {0}: {1:?}")]
+    MalformedLine(usize, String),
+}
+
+/// A heap profile in the text format written by jemalloc (`heap_v2`) or by
+/// tcmalloc / gperftools (`heap profile:`), as read by jeprof and pprof.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapProfile {
+    /// The file name, used to tell apart the heap tracks of a process with
+    /// several heap profiles.
+    pub name: String,
+
+    /// The pid of the profiled process, if known. For files which are named
+    /// like jemalloc's dumps, e.g. "jeprof.1234.0.f.heap", this is taken from
+    /// the file name.
+    pub pid: Option<i32>,
+
+    /// The path of the profiled executable, from the MAPPED_LIBRARIES section.
+    pub executable_path: Option<PathBuf>,
+
+    /// One sample per allocation stack which has live memory.
+    pub samples: Vec<HeapProfileSample>,
+}
+
+/// The live memory which was allocated from one stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapProfileSample {
+    /// The number of live objects, adjusted for sampling.
+    pub live_objects: u64,
+    /// The number of live bytes, adjusted for sampling.
+    pub live_bytes: u64,
+    /// The addresses of the allocation stack, leaf first. All but the first
+    /// address are return addresses.
+    pub stack: Vec<u64>,
+}
+
+impl HeapProfile {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let file = std::fs::File::open(path)?;
+        let name = path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut heap_profile = Self::parse(&name, std::io::BufReader::new(file))?;
+        heap_profile.pid = jemalloc_dump_pid(&name);
+        Ok(heap_profile)
+    }
+
+    pub fn parse(name: &str, reader: impl BufRead) -> Result<Self, Error> {
+        let mut lines = reader.lines().enumerate();
+        let (format, header) = loop {
+            let Some((_, line)) = lines.next() else {
+                return Err(Error::UnrecognizedHeader(String::new()));
+            };
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            break (Format::from_header(&line), line);
+        };
+        let Some(format) = format else { return Err(Error::UnrecognizedHeader(header)) };
+
+        let mut samples = Vec::new();
+        let mut current_stack = None;
+        let mut in_mapped_libraries = false;
+        let mut executable_path = None;
+        for (line_index, line) in lines {
+            let line = line?;
+            let malformed = || Error::MalformedLine(line_index + 1, line.clone());
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if trimmed == "MAPPED_LIBRARIES:" || trimmed == "--- Memory map: ---" {
+                in_mapped_libraries = true;
+                continue;
+            }
+            if in_mapped_libraries {
+                if executable_path.is_none() {
+                    executable_path = mapping_path(trimmed).map(PathBuf::from);
+                }
+                continue;
+            }
+            match format {
+                Format::Jemalloc { sample_period } => {
+                    if let Some(addresses) = trimmed.strip_prefix('@') {
+                        current_stack = Some(parse_addresses(addresses).ok_or_else(malformed)?);
+                    } else if let Some(counts) = trimmed.strip_prefix("t*:") {
+                        // The counts for all threads. The per-thread "t<n>:"
+                        // lines which follow are skipped.
+                        let Some(stack) = current_stack.take() else {
+                            // The totals for the whole profile, before the first stack.
+                            continue;
+                        };
+                        let (objects, bytes) = parse_counts(counts).ok_or_else(malformed)?;
+                        samples.push(HeapProfileSample::new(objects, bytes, sample_period, stack));
+                    }
+                }
+                Format::Tcmalloc { sample_period } => {
+                    let (counts, addresses) = trimmed.split_once('@').ok_or_else(malformed)?;
+                    let (objects, bytes) = parse_counts(counts).ok_or_else(malformed)?;
+                    let stack = parse_addresses(addresses).ok_or_else(malformed)?;
+                    samples.push(HeapProfileSample::new(objects, bytes, sample_period, stack));
+                }
+            }
+        }
+        samples.retain(|sample| sample.live_bytes != 0);
+
+        Ok(Self {
+            name: name.to_owned(),
+            pid: None,
+            executable_path,
+            samples,
+        })
+    }
+}
+
+impl HeapProfileSample {
+    fn new(objects: u64, bytes: u64, sample_period: Option<u64>, stack: Vec<u64>) -> Self {
+        let (live_objects, live_bytes) = unsample(objects, bytes, sample_period);
+        Self {
+            live_objects,
+            live_bytes,
+            stack,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Jemalloc { sample_period: Option<u64> },
+    Tcmalloc { sample_period: Option<u64> },
+}
+
+impl Format {
+    fn from_header(header: &str) -> Option<Self> {
+        let header = header.trim();
+        if let Some(period) = header.strip_prefix("heap_v2/") {
+            let sample_period = period.trim().parse().ok()?;
+            return Some(Format::Jemalloc {
+                sample_period: Some(sample_period),
+            });
+        }
+        if header.starts_with("heap profile:") {
+            // "heap profile: 1: 2 [3: 4] @ heapprofile" is unsampled,
+            // "... @ heap_v2/524288" is sampled every 512KiB on average.
+            let (_, kind) = header.split_once('@')?;
+            let sample_period = match kind.trim().strip_prefix("heap_v2/") {
+                Some(period) => Some(period.trim().parse().ok()?),
+                None => None,
+            };
+            return Some(Format::Tcmalloc { sample_period });
+        }
+        None
+    }
+}
+
+/// Parses "<objects>: <bytes> [<objects>: <bytes>]" and returns the first
+/// pair, which counts the live memory. The bracketed pair counts all
+/// allocations, including freed ones.
+fn parse_counts(s: &str) -> Option<(u64, u64)> {
+    let (live, _) = s.split_once('[').unwrap_or((s, ""));
+    let (objects, bytes) = live.split_once(':')?;
+    Some((objects.trim().parse().ok()?, bytes.trim().parse().ok()?))
+}
+
+fn parse_addresses(s: &str) -> Option<Vec<u64>> {
+    s.split_ascii_whitespace()
+        .map(|address| {
+            let address = address.strip_prefix("0x").unwrap_or(address);
+            u64::from_str_radix(address, 16).ok()
+        })
+        .collect()
+}
+
+/// Undoes the effect of sampling, the same way as pprof and jeprof: an
+/// allocation of size `s` is sampled with probability `1 - e^(-s/period)`.
+fn unsample(objects: u64, bytes: u64, sample_period: Option<u64>) -> (u64, u64) {
+    let period = match sample_period {
+        Some(period) if period > 1 => period as f64,
+        _ => return (objects, bytes),
+    };
+    if objects == 0 || bytes == 0 {
+        return (0, 0);
+    }
+    let average_size = bytes as f64 / objects as f64;
+    let scale = 1.0 / (1.0 - (-average_size / period).exp());
+    (
+        (objects as f64 * scale).round() as u64,
+        (bytes as f64 * scale).round() as u64,
+    )
+}
+
+/// Returns the path of a line from /proc/<pid>/maps, if the mapping is
+/// backed by a file.
+fn mapping_path(line: &str) -> Option<&str> {
+    let path_start = line.find('/')?;
+    // The path is the sixth field, so the first slash must come after the
+    // address range, permissions, offset, device and inode fields.
+    if line[..path_start].split_ascii_whitespace().count() < 5 {
+        return None;
+    }
+    Some(line[path_start..].trim_end())
+}
+
+/// Returns the pid from the name of a file which jemalloc dumped, e.g.
+/// "jeprof.1234.0.f.heap" or "myprefix.1234.7.i7.heap".
+fn jemalloc_dump_pid(file_name: &str) -> Option<i32> {
+    let parts: Vec<&str> = file_name.split('.').collect();
+    match parts.as_slice() {
+        [.., pid, seq, _kind, "heap"] if seq.bytes().all(|b| b.is_ascii_digit()) => {
+            pid.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_jemalloc_heap_profile() {
+        let contents = "\
+heap_v2/524288
+  t*: 3: 3145728 [0: 0]
+  t0: 3: 3145728 [0: 0]
+@ 0x55d0c0a01234 0x55d0c0a00100 0x7f1234567890
+  t*: 2: 2097152 [0: 0]
+  t0: 2: 2097152 [0: 0]
+@ 0x55d0c0a05678
+  t*: 1: 16 [0: 0]
+  t0: 1: 16 [0: 0]
+@ 0x55d0c0a09999
+  t*: 0: 0 [0: 0]
+
+MAPPED_LIBRARIES:
+55d0c09ff000-55d0c0a10000 r-xp 00001000 08:01 1234                       /usr/bin/my app
+7f1234500000-7f1234600000 r-xp 00000000 08:01 5678                       /usr/lib/libc.so.6
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0                          [stack]
+";
+        let heap_profile = HeapProfile::parse("jeprof.42.0.f.heap", contents.as_bytes()).unwrap();
+        assert_eq!(
+            heap_profile.executable_path.as_deref(),
+            Some(Path::new("/usr/bin/my app"))
+        );
+        assert_eq!(heap_profile.samples.len(), 2);
+        let large = &heap_profile.samples[0];
+        assert_eq!(
+            large.stack,
+            vec![0x55d0c0a01234, 0x55d0c0a00100, 0x7f1234567890]
+        );
+        // 1MiB allocations are sampled with a probability of 1 - e^-2.
+        assert_eq!((large.live_objects, large.live_bytes), (2, 2425393));
+        let small = &heap_profile.samples[1];
+        assert_eq!(small.stack, vec![0x55d0c0a05678]);
+        // A 16 byte allocation is sampled with a probability of about 1/32768.
+        assert_eq!(small.live_objects, 32769);
+        assert_eq!(small.live_bytes, 524296);
+
+        assert_eq!(jemalloc_dump_pid("jeprof.42.0.f.heap"), Some(42));
+        assert_eq!(jemalloc_dump_pid("app.prof.1234.17.i17.heap"), Some(1234));
+        assert_eq!(jemalloc_dump_pid("profile.0001.heap"), None);
+    }
+
+    #[test]
+    fn parses_tcmalloc_heap_profile() {
+        let contents = "\
+heap profile:    2:    96 [     5:   480] @ heapprofile
+     1:    64 [     2:   128] @ 0x00000000004005e2 0x00007f0000001234
+     1:    32 [     3:   352] @ 0x00000000004005f0
+     0:     0 [     1:    16] @ 0x0000000000400600
+MAPPED_LIBRARIES:
+00400000-00401000 r-xp 00000000 08:01 42 /tmp/a.out
+";
+        let heap_profile = HeapProfile::parse("a.0001.heap", contents.as_bytes()).unwrap();
+        assert_eq!(
+            heap_profile.samples,
+            vec![
+                HeapProfileSample {
+                    live_objects: 1,
+                    live_bytes: 64,
+                    stack: vec![0x4005e2, 0x7f0000001234],
+                },
+                HeapProfileSample {
+                    live_objects: 1,
+                    live_bytes: 32,
+                    stack: vec![0x4005f0],
+                },
+            ]
+        );
+        assert_eq!(
+            heap_profile.executable_path.as_deref(),
+            Some(Path::new("/tmp/a.out"))
+        );
+
+        assert!(matches!(
+            HeapProfile::parse("x", "not a heap profile\n".as_bytes()),
+            Err(Error::UnrecognizedHeader(_))
+        ));
+    }
+}
@@ -0,0 +1,900 @@
+//! The samply intermediate format, a stream of the converter's inputs after
+//! the expensive parts of their normalization, so that a recording can be
+//! converted on another machine without the perf.data file.
+//!
+//! The file is a JSON object per line. The first line is the [`Header`],
+//! with the metadata of the recording, the build ID table after the perf jit
+//! fixups, and the tracing data. The other lines are the records in the
+//! order in which they were converted. The stack of each sample is already
+//! unwound, and is written as a perf callchain in a separate [`Stack`] line
+//! the first time it is used, so that samples refer to it by ID.
+//!
+//! Compatibility rules for [`Header::version`]:
+//!  - Readers accept files with their own version or an older one.
+//!  - Adding an optional field or a new record type doesn't change the
+//!    version. Readers ignore the fields and record types they don't know.
+//!  - Removing a field, or changing the meaning of one, increments the
+//!    version, and readers keep reading the older versions.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::LittleEndian;
+use framehop::{Module, Unwinder};
+use linux_perf_data::linux_perf_event_reader;
+use linux_perf_data::{DsoInfo, DsoKey, Endianness};
+use linux_perf_event_reader::constants::{
+    PERF_CONTEXT_GUEST_KERNEL, PERF_CONTEXT_GUEST_USER, PERF_CONTEXT_KERNEL, PERF_CONTEXT_USER,
+};
+use linux_perf_event_reader::{
+    CommOrExecRecord, CommonData, ContextSwitchRecord, CpuMode, EventRecord, ForkOrExitRecord,
+    Mmap2FileId, Mmap2InodeAndVersion, Mmap2Record, MmapRecord, RawData, RawDataU64, RecordType,
+    SampleRecord, TaskWasPreempted,
+};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::perf::{dispatch_record, parse_numa_topology, parse_tracepoint_formats, Error};
+use crate::linux_shared::{
+    reference_timestamp, ConversionOptions, ConvertRegs, ConvertRegsAarch64, ConvertRegsX86_64,
+    Converter, EventInterpretation, ModuleData, RecordingDelay,
+};
+use crate::shared::jitdump_manager::TimestampClock;
+use crate::shared::profile_split::ConvertedProfile;
+use crate::shared::types::{StackFrame, StackMode};
+
+pub const FORMAT_NAME: &str = "samply-intermediate";
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The start of the first line of an intermediate file.
+const FILE_START: &[u8] = b"{\"type\":\"header\",\"format\":\"samply-intermediate\"";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IntermediateRecord {
+    Header(Box<Header>),
+    Fork(ForkOrExit),
+    Exit(ForkOrExit),
+    Comm(Comm),
+    Mmap(Mmap),
+    Mmap2(Mmap2),
+    ContextSwitch(ContextSwitch),
+    Stack(Stack),
+    Sample(Sample),
+    /// A record type of a newer version of the format.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    /// Always [`FORMAT_NAME`].
+    pub format: String,
+    pub version: u32,
+    pub arch: Option<String>,
+    pub big_endian: bool,
+    pub host: String,
+    pub perf_version: String,
+    pub linux_version: Option<String>,
+    /// For the reference timestamp and the recording delay, see
+    /// [`RecordingDelay::detect`].
+    pub first_sample_time: u64,
+    pub first_record_time: Option<u64>,
+    pub interpretation: Interpretation,
+    pub build_ids: Vec<BuildId>,
+    /// The TRACING_DATA feature section, in hex.
+    #[serde(default)]
+    pub tracing_data: Option<String>,
+    /// The NUMA_TOPOLOGY feature section, in hex.
+    #[serde(default)]
+    pub numa_topology: Option<String>,
+}
+
+/// The fields of the header which every version has.
+#[derive(Deserialize)]
+struct HeaderStart {
+    format: String,
+    version: u32,
+}
+
+/// See [`EventInterpretation`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interpretation {
+    pub main_event_attr_index: usize,
+    pub main_event_name: String,
+    pub sampling_is_time_based: Option<u64>,
+    pub have_context_switches: bool,
+    pub frequency_event_attr_indexes: Option<(usize, usize)>,
+    pub event_names: Vec<String>,
+    /// "monotonic", "arch_timestamp" or "other".
+    pub clock: String,
+    #[serde(default)]
+    pub marker_only: bool,
+    #[serde(default)]
+    pub event_track_attr_indexes: Vec<usize>,
+}
+
+impl Interpretation {
+    fn new(interpretation: &EventInterpretation) -> Self {
+        let clock = match interpretation.clock {
+            TimestampClock::Monotonic => "monotonic",
+            TimestampClock::ArchTimestamp => "arch_timestamp",
+            TimestampClock::Other => "other",
+        };
+        Self {
+            main_event_attr_index: interpretation.main_event_attr_index,
+            main_event_name: interpretation.main_event_name.clone(),
+            sampling_is_time_based: interpretation.sampling_is_time_based,
+            have_context_switches: interpretation.have_context_switches,
+            frequency_event_attr_indexes: interpretation.frequency_event_attr_indexes,
+            event_names: interpretation.event_names.clone(),
+            clock: clock.to_string(),
+            marker_only: interpretation.marker_only,
+            event_track_attr_indexes: interpretation.event_track_attr_indexes.clone(),
+        }
+    }
+
+    fn event_interpretation(&self) -> EventInterpretation {
+        let clock = match self.clock.as_str() {
+            "monotonic" => TimestampClock::Monotonic,
+            "arch_timestamp" => TimestampClock::ArchTimestamp,
+            _ => TimestampClock::Other,
+        };
+        EventInterpretation {
+            main_event_attr_index: self.main_event_attr_index,
+            main_event_name: self.main_event_name.clone(),
+            sampling_is_time_based: self.sampling_is_time_based,
+            have_context_switches: self.have_context_switches,
+            frequency_event_attr_indexes: self.frequency_event_attr_indexes,
+            event_names: self.event_names.clone(),
+            clock,
+            marker_only: self.marker_only,
+            event_track_attr_indexes: self.event_track_attr_indexes.clone(),
+        }
+    }
+}
+
+/// An entry of the build ID table. The key is detected again from
+/// `key_path` when the file is read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildId {
+    pub kernel: bool,
+    pub key_path: String,
+    pub path: String,
+    pub build_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForkOrExit {
+    pub record_type: u32,
+    pub pid: i32,
+    pub ppid: i32,
+    pub tid: i32,
+    pub ptid: i32,
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Comm {
+    pub record_type: u32,
+    pub time: Option<u64>,
+    pub pid: i32,
+    pub tid: i32,
+    pub name: String,
+    pub is_execve: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mmap {
+    pub record_type: u32,
+    pub time: Option<u64>,
+    /// The timestamp which the converter used for the mapping, which is the
+    /// last timestamp before the record if the record has none.
+    pub mapping_time: u64,
+    pub pid: i32,
+    pub tid: i32,
+    pub address: u64,
+    pub length: u64,
+    pub page_offset: u64,
+    pub is_executable: bool,
+    pub cpu_mode: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mmap2 {
+    pub record_type: u32,
+    pub time: Option<u64>,
+    /// See [`Mmap::mapping_time`].
+    pub mapping_time: u64,
+    pub pid: i32,
+    pub tid: i32,
+    pub address: u64,
+    pub length: u64,
+    pub page_offset: u64,
+    pub protection: u32,
+    pub flags: u32,
+    pub cpu_mode: String,
+    /// The build ID of the mapped file, if perf recorded it in the record.
+    pub build_id: Option<String>,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextSwitch {
+    pub record_type: u32,
+    pub time: Option<u64>,
+    pub pid: Option<i32>,
+    pub tid: Option<i32>,
+    pub cpu: Option<u32>,
+    pub out: bool,
+    pub preempted: bool,
+    /// The thread which was switched in or out instead, for CPU-wide
+    /// context switch records.
+    pub other_pid: Option<i32>,
+    pub other_tid: Option<i32>,
+}
+
+/// An unwound stack, as a perf callchain with context frames.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stack {
+    pub id: u64,
+    pub callchain: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sample {
+    pub record_type: u32,
+    pub attr_index: usize,
+    pub time: Option<u64>,
+    pub pid: Option<i32>,
+    pub tid: Option<i32>,
+    pub cpu: Option<u32>,
+    pub period: Option<u64>,
+    pub ip: Option<u64>,
+    pub addr: Option<u64>,
+    pub cpu_mode: String,
+    /// The ID of the sample's [`Stack`].
+    pub stack: Option<u64>,
+    /// The raw tracepoint data, in hex.
+    pub raw: Option<String>,
+}
+
+/// Returns whether the file is an intermediate file, and rewinds it.
+pub fn is_intermediate_file(mut file: &File) -> bool {
+    let mut start = [0; FILE_START.len()];
+    let is_intermediate = file.read_exact(&mut start).is_ok() && start[..] == *FILE_START;
+    let rewound = file.seek(SeekFrom::Start(0)).is_ok();
+    is_intermediate && rewound
+}
+
+/// Writes the intermediate file, for `--emit-intermediate`.
+pub struct IntermediateWriter {
+    /// None after a write error.
+    writer: Option<Box<dyn Write + Send>>,
+    stack_ids: HashMap<Vec<u64>, u64>,
+}
+
+impl IntermediateWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer: Some(writer),
+            stack_ids: HashMap::new(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_header(
+        &mut self,
+        arch: Option<&str>,
+        endian: Endianness,
+        host: &str,
+        perf_version: &str,
+        linux_version: Option<&str>,
+        first_sample_time: u64,
+        first_record_time: Option<u64>,
+        interpretation: &EventInterpretation,
+        build_ids: &HashMap<DsoKey, DsoInfo>,
+        tracing_data: Option<&[u8]>,
+        numa_topology: Option<&[u8]>,
+    ) {
+        let mut build_ids: Vec<BuildId> = build_ids
+            .iter()
+            .map(|(key, info)| {
+                let (kernel, key_path) = match key {
+                    DsoKey::User { full_path, .. } => {
+                        (false, String::from_utf8_lossy(full_path).into_owned())
+                    }
+                    _ => (true, key.name().to_string()),
+                };
+                BuildId {
+                    kernel,
+                    key_path,
+                    path: String::from_utf8_lossy(&info.path).into_owned(),
+                    build_id: to_hex(&info.build_id),
+                }
+            })
+            .collect();
+        build_ids.sort_by(|a, b| a.key_path.cmp(&b.key_path));
+        self.write(&IntermediateRecord::Header(Box::new(Header {
+            format: FORMAT_NAME.to_string(),
+            version: FORMAT_VERSION,
+            arch: arch.map(ToOwned::to_owned),
+            big_endian: endian == Endianness::BigEndian,
+            host: host.to_string(),
+            perf_version: perf_version.to_string(),
+            linux_version: linux_version.map(ToOwned::to_owned),
+            first_sample_time,
+            first_record_time,
+            interpretation: Interpretation::new(interpretation),
+            build_ids,
+            tracing_data: tracing_data.map(to_hex),
+            numa_topology: numa_topology.map(to_hex),
+        })));
+    }
+
+    /// Writes a record which is about to be passed to the converter. `stack`
+    /// is the unwound stack of a sample.
+    pub fn write_record(
+        &mut self,
+        record_type: RecordType,
+        common: Option<&CommonData>,
+        parsed_record: &EventRecord,
+        attr_index: usize,
+        last_timestamp: u64,
+        stack: &[StackFrame],
+    ) {
+        let record_type = record_type.0;
+        let time = common.and_then(|common| common.timestamp);
+        let record = match parsed_record {
+            EventRecord::Sample(e) => {
+                let stack = self.stack_id(stack);
+                IntermediateRecord::Sample(Sample {
+                    record_type,
+                    attr_index,
+                    time: e.timestamp,
+                    pid: e.pid,
+                    tid: e.tid,
+                    cpu: e.cpu,
+                    period: e.period,
+                    ip: e.ip,
+                    addr: e.addr,
+                    cpu_mode: cpu_mode_name(e.cpu_mode).to_string(),
+                    stack,
+                    raw: e.raw.map(|raw| to_hex(&raw.as_slice())),
+                })
+            }
+            EventRecord::Fork(e) | EventRecord::Exit(e) => {
+                let record = ForkOrExit {
+                    record_type,
+                    pid: e.pid,
+                    ppid: e.ppid,
+                    tid: e.tid,
+                    ptid: e.ptid,
+                    time: e.timestamp,
+                };
+                match parsed_record {
+                    EventRecord::Fork(_) => IntermediateRecord::Fork(record),
+                    _ => IntermediateRecord::Exit(record),
+                }
+            }
+            EventRecord::Comm(e) => IntermediateRecord::Comm(Comm {
+                record_type,
+                time,
+                pid: e.pid,
+                tid: e.tid,
+                name: String::from_utf8_lossy(&e.name.as_slice()).into_owned(),
+                is_execve: e.is_execve,
+            }),
+            EventRecord::Mmap(e) => IntermediateRecord::Mmap(Mmap {
+                record_type,
+                time,
+                mapping_time: last_timestamp,
+                pid: e.pid,
+                tid: e.tid,
+                address: e.address,
+                length: e.length,
+                page_offset: e.page_offset,
+                is_executable: e.is_executable,
+                cpu_mode: cpu_mode_name(e.cpu_mode).to_string(),
+                path: String::from_utf8_lossy(&e.path.as_slice()).into_owned(),
+            }),
+            EventRecord::Mmap2(e) => IntermediateRecord::Mmap2(Mmap2 {
+                record_type,
+                time,
+                mapping_time: last_timestamp,
+                pid: e.pid,
+                tid: e.tid,
+                address: e.address,
+                length: e.length,
+                page_offset: e.page_offset,
+                protection: e.protection,
+                flags: e.flags,
+                cpu_mode: cpu_mode_name(e.cpu_mode).to_string(),
+                build_id: match &e.file_id {
+                    Mmap2FileId::BuildId(build_id) => Some(to_hex(build_id)),
+                    Mmap2FileId::InodeAndVersion(_) => None,
+                },
+                path: String::from_utf8_lossy(&e.path.as_slice()).into_owned(),
+            }),
+            EventRecord::ContextSwitch(e) => {
+                let (out, preempted, other_pid, other_tid) = match e {
+                    ContextSwitchRecord::In { prev_pid, prev_tid } => {
+                        (false, false, *prev_pid, *prev_tid)
+                    }
+                    ContextSwitchRecord::Out {
+                        next_pid,
+                        next_tid,
+                        preempted,
+                    } => (
+                        true,
+                        *preempted == TaskWasPreempted::Yes,
+                        *next_pid,
+                        *next_tid,
+                    ),
+                };
+                IntermediateRecord::ContextSwitch(ContextSwitch {
+                    record_type,
+                    time,
+                    pid: common.and_then(|common| common.pid),
+                    tid: common.and_then(|common| common.tid),
+                    cpu: common.and_then(|common| common.cpu),
+                    out,
+                    preempted,
+                    other_pid,
+                    other_tid,
+                })
+            }
+            _ => return,
+        };
+        self.write(&record);
+    }
+
+    /// Flushes the file.
+    pub fn finish(mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(err) = writer.flush() {
+                warn!("Could not write the intermediate file: {err}");
+            }
+        }
+    }
+
+    /// Returns the ID of the stack, and writes the stack if it's new.
+    fn stack_id(&mut self, stack: &[StackFrame]) -> Option<u64> {
+        if stack.is_empty() {
+            return None;
+        }
+        let callchain = callchain_for_stack(stack);
+        if let Some(&id) = self.stack_ids.get(&callchain) {
+            return Some(id);
+        }
+        let id = self.stack_ids.len() as u64;
+        self.stack_ids.insert(callchain.clone(), id);
+        self.write(&IntermediateRecord::Stack(Stack { id, callchain }));
+        Some(id)
+    }
+
+    fn write(&mut self, record: &IntermediateRecord) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let result = serde_json::to_writer(&mut *writer, record)
+            .map_err(io::Error::from)
+            .and_then(|()| writer.write_all(b"\n"));
+        if let Err(err) = result {
+            warn!("Could not write the intermediate file: {err}");
+            self.writer = None;
+        }
+    }
+}
+
+/// Converts an unwound stack into a perf callchain, with a context frame at
+/// the start and wherever the mode changes. Truncation markers are dropped.
+fn callchain_for_stack(stack: &[StackFrame]) -> Vec<u64> {
+    let mut callchain = Vec::with_capacity(stack.len() + 2);
+    let mut current_mode = None;
+    for frame in stack {
+        let (StackFrame::InstructionPointer(address, mode)
+        | StackFrame::ReturnAddress(address, mode)) = *frame
+        else {
+            continue;
+        };
+        if current_mode != Some(mode) {
+            callchain.push(match mode {
+                StackMode::User => PERF_CONTEXT_USER,
+                StackMode::Kernel => PERF_CONTEXT_KERNEL,
+                StackMode::GuestUser => PERF_CONTEXT_GUEST_USER,
+                StackMode::GuestKernel => PERF_CONTEXT_GUEST_KERNEL,
+            });
+            current_mode = Some(mode);
+        }
+        callchain.push(address);
+    }
+    callchain
+}
+
+fn cpu_mode_name(cpu_mode: CpuMode) -> &'static str {
+    match cpu_mode {
+        CpuMode::Kernel => "kernel",
+        CpuMode::User => "user",
+        CpuMode::Hypervisor => "hypervisor",
+        CpuMode::GuestKernel => "guest_kernel",
+        CpuMode::GuestUser => "guest_user",
+        _ => "unknown",
+    }
+}
+
+fn parse_cpu_mode(name: &str) -> CpuMode {
+    match name {
+        "kernel" => CpuMode::Kernel,
+        "user" => CpuMode::User,
+        "hypervisor" => CpuMode::Hypervisor,
+        "guest_kernel" => CpuMode::GuestKernel,
+        "guest_user" => CpuMode::GuestUser,
+        _ => CpuMode::Unknown,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::MalformedIntermediate(format!("invalid hex string {hex:?}"));
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(hex.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+fn parse_line(line: &str, line_number: usize) -> Result<IntermediateRecord, Error> {
+    serde_json::from_str(line)
+        .map_err(|err| Error::MalformedIntermediate(format!("line {line_number}: {err}")))
+}
+
+/// Converts an intermediate file into a profile, like the conversion of the
+/// perf.data file it was written from.
+pub fn convert<R: BufRead>(
+    reader: R,
+    mut options: ConversionOptions,
+) -> Result<ConvertedProfile, Error> {
+    let mut lines = reader.lines();
+    let first_line = lines.next().transpose()?.unwrap_or_default();
+    // Check the version before the rest of the header, whose fields may have
+    // changed in a newer version.
+    let version = match serde_json::from_str::<HeaderStart>(&first_line) {
+        Ok(start) if start.format == FORMAT_NAME => start.version,
+        _ => {
+            return Err(Error::MalformedIntermediate(
+                "the first line isn't a header".to_string(),
+            ))
+        }
+    };
+    if version > FORMAT_VERSION {
+        return Err(Error::MalformedIntermediate(format!(
+            "version {version} is newer than the supported version {FORMAT_VERSION}"
+        )));
+    }
+    let IntermediateRecord::Header(header) = parse_line(&first_line, 1)? else {
+        return Err(Error::MalformedIntermediate(
+            "the first line isn't a header".to_string(),
+        ));
+    };
+    let endian = match header.big_endian {
+        true => Endianness::BigEndian,
+        false => Endianness::LittleEndian,
+    };
+    let tracing_data = header.tracing_data.as_deref().map(from_hex).transpose()?;
+    options.tracepoint_formats = parse_tracepoint_formats(tracing_data.as_deref());
+    let numa_topology = header.numa_topology.as_deref().map(from_hex).transpose()?;
+    options.numa_topology = numa_topology
+        .as_deref()
+        .and_then(|data| parse_numa_topology(data, endian));
+
+    match header.arch.as_deref() {
+        Some("aarch64") => {
+            let cache = framehop::aarch64::CacheAarch64::new();
+            convert_impl::<framehop::aarch64::UnwinderAarch64<ModuleData>, ConvertRegsAarch64, _>(
+                *header, lines, endian, cache, options,
+            )
+        }
+        _ => {
+            let cache = framehop::x86_64::CacheX86_64::new();
+            convert_impl::<framehop::x86_64::UnwinderX86_64<ModuleData>, ConvertRegsX86_64, _>(
+                *header, lines, endian, cache, options,
+            )
+        }
+    }
+}
+
+fn convert_impl<U, C, R>(
+    header: Header,
+    lines: io::Lines<R>,
+    endian: Endianness,
+    cache: U::Cache,
+    options: ConversionOptions,
+) -> Result<ConvertedProfile, Error>
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+    C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+    R: BufRead,
+{
+    let mut build_ids = HashMap::new();
+    for entry in &header.build_ids {
+        let cpu_mode = match entry.kernel {
+            true => CpuMode::Kernel,
+            false => CpuMode::User,
+        };
+        if let Some(key) = DsoKey::detect(entry.key_path.as_bytes(), cpu_mode) {
+            let info = DsoInfo {
+                path: entry.path.as_bytes().to_vec(),
+                build_id: from_hex(&entry.build_id)?,
+            };
+            build_ids.insert(key, info);
+        }
+    }
+    let interpretation = header.interpretation.event_interpretation();
+    let recording_delay =
+        RecordingDelay::detect(header.first_sample_time, header.first_record_time);
+    let keep_recording_delay = options.keep_recording_delay;
+    let reference_time = match recording_delay {
+        Some(delay) if !keep_recording_delay => delay.trimmed_start(),
+        _ => reference_timestamp(header.first_sample_time, header.first_record_time),
+    };
+    let timings = options.timings.clone();
+    let Header {
+        host, perf_version, ..
+    } = header;
+    let mut converter = Converter::<U>::new(
+        "Converted perf profile",
+        Some(Box::new(move |name| {
+            format!("{name} on {host} (perf version {perf_version})")
+        })),
+        build_ids,
+        header.linux_version.as_deref(),
+        reference_time,
+        endian,
+        cache,
+        None,
+        interpretation.clone(),
+        options,
+    );
+    if let Some(delay) = recording_delay {
+        converter.set_recording_delay(delay, keep_recording_delay);
+    }
+
+    // The callchains of the stacks, as the little-endian bytes which a
+    // sample's callchain refers to.
+    let mut stacks: HashMap<u64, Vec<u8>> = HashMap::new();
+    for (index, line) in lines.enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record = parse_line(&line, index + 2)?;
+        let (record_type, time, mapping_time) = match &record {
+            IntermediateRecord::Sample(r) => (r.record_type, r.time, None),
+            IntermediateRecord::Fork(r) | IntermediateRecord::Exit(r) => {
+                (r.record_type, Some(r.time), None)
+            }
+            IntermediateRecord::Comm(r) => (r.record_type, r.time, None),
+            IntermediateRecord::Mmap(r) => (r.record_type, r.time, Some(r.mapping_time)),
+            IntermediateRecord::Mmap2(r) => (r.record_type, r.time, Some(r.mapping_time)),
+            IntermediateRecord::ContextSwitch(r) => (r.record_type, r.time, None),
+            IntermediateRecord::Stack(stack) => {
+                let bytes = stack
+                    .callchain
+                    .iter()
+                    .flat_map(|a| a.to_le_bytes())
+                    .collect();
+                stacks.insert(stack.id, bytes);
+                continue;
+            }
+            IntermediateRecord::Header(_) => {
+                return Err(Error::MalformedIntermediate(format!(
+                    "line {}: a second header",
+                    index + 2
+                )))
+            }
+            IntermediateRecord::Unknown => continue,
+        };
+        let raw = match &record {
+            IntermediateRecord::Sample(Sample { raw: Some(raw), .. }) => Some(from_hex(raw)?),
+            _ => None,
+        };
+        let (parsed_record, attr_index, common) = match &record {
+            IntermediateRecord::Sample(s) => {
+                let callchain = s
+                    .stack
+                    .and_then(|id| stacks.get(&id))
+                    .map(|bytes| RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(bytes)));
+                let e = SampleRecord {
+                    id: None,
+                    addr: s.addr,
+                    stream_id: None,
+                    raw: raw.as_deref().map(RawData::Single),
+                    ip: s.ip,
+                    timestamp: s.time,
+                    pid: s.pid,
+                    tid: s.tid,
+                    cpu: s.cpu,
+                    period: s.period,
+                    user_regs: None,
+                    user_stack: None,
+                    callchain,
+                    phys_addr: None,
+                    data_page_size: None,
+                    code_page_size: None,
+                    intr_regs: None,
+                    cpu_mode: parse_cpu_mode(&s.cpu_mode),
+                };
+                (EventRecord::Sample(e), s.attr_index, None)
+            }
+            IntermediateRecord::Fork(r) | IntermediateRecord::Exit(r) => {
+                let e = ForkOrExitRecord {
+                    pid: r.pid,
+                    ppid: r.ppid,
+                    tid: r.tid,
+                    ptid: r.ptid,
+                    timestamp: r.time,
+                };
+                match record {
+                    IntermediateRecord::Fork(_) => (EventRecord::Fork(e), 0, None),
+                    _ => (EventRecord::Exit(e), 0, None),
+                }
+            }
+            IntermediateRecord::Comm(r) => {
+                let e = CommOrExecRecord {
+                    pid: r.pid,
+                    tid: r.tid,
+                    name: RawData::Single(r.name.as_bytes()),
+                    is_execve: r.is_execve,
+                };
+                (EventRecord::Comm(e), 0, None)
+            }
+            IntermediateRecord::Mmap(r) => {
+                let e = MmapRecord {
+                    pid: r.pid,
+                    tid: r.tid,
+                    address: r.address,
+                    length: r.length,
+                    page_offset: r.page_offset,
+                    is_executable: r.is_executable,
+                    cpu_mode: parse_cpu_mode(&r.cpu_mode),
+                    path: RawData::Single(r.path.as_bytes()),
+                };
+                (EventRecord::Mmap(e), 0, None)
+            }
+            IntermediateRecord::Mmap2(r) => {
+                let file_id = match &r.build_id {
+                    Some(build_id) => Mmap2FileId::BuildId(from_hex(build_id)?),
+                    None => Mmap2FileId::InodeAndVersion(Mmap2InodeAndVersion {
+                        major: 0,
+                        minor: 0,
+                        inode: 0,
+                        inode_generation: 0,
+                    }),
+                };
+                let e = Mmap2Record {
+                    pid: r.pid,
+                    tid: r.tid,
+                    address: r.address,
+                    length: r.length,
+                    page_offset: r.page_offset,
+                    file_id,
+                    protection: r.protection,
+                    flags: r.flags,
+                    cpu_mode: parse_cpu_mode(&r.cpu_mode),
+                    path: RawData::Single(r.path.as_bytes()),
+                };
+                (EventRecord::Mmap2(e), 0, None)
+            }
+            IntermediateRecord::ContextSwitch(r) => {
+                let e = match r.out {
+                    true => ContextSwitchRecord::Out {
+                        next_pid: r.other_pid,
+                        next_tid: r.other_tid,
+                        preempted: match r.preempted {
+                            true => TaskWasPreempted::Yes,
+                            false => TaskWasPreempted::No,
+                        },
+                    },
+                    false => ContextSwitchRecord::In {
+                        prev_pid: r.other_pid,
+                        prev_tid: r.other_tid,
+                    },
+                };
+                let common = CommonData {
+                    pid: r.pid,
+                    tid: r.tid,
+                    timestamp: r.time,
+                    id: None,
+                    stream_id: None,
+                    cpu: r.cpu,
+                };
+                (EventRecord::ContextSwitch(e), 0, Some(common))
+            }
+            IntermediateRecord::Stack(_)
+            | IntermediateRecord::Header(_)
+            | IntermediateRecord::Unknown => unreachable!(),
+        };
+        let record_type = RecordType(record_type);
+        converter.observe_record(record_type);
+        if let Some(time) = time {
+            converter.observe_record_timestamp(record_type, time);
+        }
+        dispatch_record::<U, C>(
+            &mut converter,
+            &interpretation,
+            parsed_record,
+            attr_index,
+            time,
+            common,
+            mapping_time.unwrap_or(0),
+            timings.as_ref(),
+        );
+    }
+
+    Ok(converter.finish_in_parts())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unknown_record_types_and_fields_are_ignored() {
+        let line = r#"{"type":"sample_group","id":3}"#;
+        assert_eq!(parse_line(line, 2).unwrap(), IntermediateRecord::Unknown);
+        let line = r#"{"type":"stack","id":1,"callchain":[1,2],"weight":5}"#;
+        assert_eq!(
+            parse_line(line, 2).unwrap(),
+            IntermediateRecord::Stack(Stack {
+                id: 1,
+                callchain: vec![1, 2],
+            })
+        );
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let header = format!(
+            "{}\n",
+            r#"{"type":"header","format":"samply-intermediate","version":2}"#
+        );
+        let result = convert(header.as_bytes(), ConversionOptions::default());
+        assert!(matches!(result, Err(Error::MalformedIntermediate(_))));
+    }
+
+    #[test]
+    fn stacks_become_callchains_with_context_frames() {
+        let stack = [
+            StackFrame::InstructionPointer(0xffff_1000, StackMode::Kernel),
+            StackFrame::ReturnAddress(0xffff_2000, StackMode::Kernel),
+            StackFrame::ReturnAddress(0x1000, StackMode::User),
+            StackFrame::TruncatedStackMarker,
+        ];
+        assert_eq!(
+            callchain_for_stack(&stack),
+            vec![
+                PERF_CONTEXT_KERNEL,
+                0xffff_1000,
+                0xffff_2000,
+                PERF_CONTEXT_USER,
+                0x1000
+            ]
+        );
+    }
+}
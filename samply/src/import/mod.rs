@@ -1 +1,10 @@
+mod arm_spe;
+mod aux_sample;
+mod branch_stack;
+mod clock_data;
+mod data_src;
+pub mod heap_profile;
+pub mod intermediate;
 pub mod perf;
+pub mod perf_dir;
+mod perf_pipe;
@@ -1,16 +1,37 @@
 use framehop::{Module, Unwinder};
-use fxprof_processed_profile::Profile;
+use fxprof_processed_profile::ReferenceTimestamp;
 use linux_perf_data::linux_perf_event_reader;
-use linux_perf_data::{DsoInfo, DsoKey, PerfFileReader, PerfFileRecord};
-use linux_perf_event_reader::EventRecord;
+use linux_perf_data::{
+    DsoInfo, DsoKey, Endianness, Feature, PerfFile, PerfFileReader, PerfFileRecord,
+    UserRecordType,
+};
+use linux_perf_event_reader::{CommonData, ContextSwitchRecord, EventRecord, RawEventRecord};
+use tracing::{debug, info, trace_span, warn};
 
 use std::collections::HashMap;
-use std::io::{Read, Seek};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
+use std::time::Duration;
 
+use super::arm_spe::{has_arm_spe_event, ArmSpe, AuxtraceHeader, AuxtraceIndex, TimeConv};
+use super::aux_sample::AuxSamples;
+use super::branch_stack::{lbr_call_stack_formats, sample_lbr_calls, LbrCallStackFormat};
+use super::clock_data::{align_inputs, AlignedInputs, ClockData, InputClock};
+use super::data_src::sample_data_src;
+use super::intermediate::IntermediateWriter;
+use super::perf_pipe::PerfPipeReader;
 use crate::linux_shared::{
-    ConvertRegs, ConvertRegsAarch64, ConvertRegsX86_64, Converter, EventInterpretation,
+    check_sampled_user_regs, check_sampling_policies, reference_timestamp, ConversionOptions, ConversionTimings,
+    ConvertRegs, ConvertRegsAarch64, ConvertRegsX86_64, Converter, EventInterpretation, ModuleData,
+    NumaTopology, RecordingDelay, TimingBucket, TimingGuard, TracepointFormats, Watchdog,
 };
+use crate::shared::profile_split::ConvertedProfile;
+use crate::shared::self_profile::PhaseRecorder;
+use crate::shared::timestamp_converter::TimestampConverter;
+
+/// With `--self-profile`, a progress marker is added after every this many records.
+const RECORDS_PER_PROGRESS_PHASE: u64 = 100_000;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -19,58 +40,209 @@ pub enum Error {
 
     #[error("Linux Perf error: {0}")]
     LinuxPerf(#[from] linux_perf_data::Error),
+
+    #[error("The input is not a perf.data stream from perf record -o -")]
+    NotAPerfStream,
+
+    #[error("Malformed perf.data stream: {0}")]
+    MalformedStream(&'static str),
+
+    #[error("Malformed intermediate file: {0}")]
+    MalformedIntermediate(String),
+
+    #[error("Can't convert perf.data files of different architectures together ({0} and {1})")]
+    MixedArchitectures(String, String),
 }
 
+/// `aux_file` is the file which `cursor` reads, for reading the AUX data of
+/// `arm_spe` events without moving the cursor.
 pub fn convert<C: Read + Seek>(
     cursor: C,
     extra_dir: Option<&Path>,
-    merge_threads: bool,
-    fold_recursive_prefix: bool,
-) -> Result<Profile, Error> {
+    aux_file: Option<&File>,
+    options: ConversionOptions,
+) -> Result<ConvertedProfile, Error> {
+    let header_phase = options
+        .phases
+        .as_ref()
+        .map(|phases| phases.interval("Parse perf.data header"));
     let perf_file = PerfFileReader::parse_file(cursor)?;
+    drop(header_phase);
 
     let arch = perf_file.perf_file.arch().ok().flatten();
 
-    let profile = match arch {
+    let converted = match arch {
         Some("aarch64") => {
             let cache = framehop::aarch64::CacheAarch64::new();
-            convert_impl::<framehop::aarch64::UnwinderAarch64<Vec<u8>>, ConvertRegsAarch64, _>(
-                perf_file,
-                extra_dir,
-                cache,
-                merge_threads,
-                fold_recursive_prefix,
-            )
+            convert_impl::<framehop::aarch64::UnwinderAarch64<ModuleData>, ConvertRegsAarch64, _>(
+                perf_file, extra_dir, aux_file, cache, options,
+            )?
         }
         _ => {
             if arch != Some("x86_64") {
-                eprintln!(
+                warn!(
+                    arch = ?arch,
                     "Unknown arch {}, dwarf-based unwinding may be incorrect.",
                     arch.unwrap_or_default()
                 );
             }
             let cache = framehop::x86_64::CacheX86_64::new();
-            convert_impl::<framehop::x86_64::UnwinderX86_64<Vec<u8>>, ConvertRegsX86_64, _>(
-                perf_file,
-                extra_dir,
+            convert_impl::<framehop::x86_64::UnwinderX86_64<ModuleData>, ConvertRegsX86_64, _>(
+                perf_file, extra_dir, aux_file, cache, options,
+            )?
+        }
+    };
+    Ok(converted)
+}
+
+/// Converts the output of `perf record -o -`, read from a pipe. The records
+/// are converted as they arrive.
+pub fn convert_pipe<R: Read>(
+    reader: R,
+    options: ConversionOptions,
+) -> Result<ConvertedProfile, Error> {
+    let header_phase = options
+        .phases
+        .as_ref()
+        .map(|phases| phases.interval("Parse perf.data stream header"));
+    let pipe_reader = PerfPipeReader::parse(reader)?;
+    drop(header_phase);
+
+    let arch = pipe_reader.arch().map(ToOwned::to_owned);
+
+    let converted = match arch.as_deref() {
+        Some("aarch64") => {
+            let cache = framehop::aarch64::CacheAarch64::new();
+            convert_pipe_impl::<
+                framehop::aarch64::UnwinderAarch64<ModuleData>,
+                ConvertRegsAarch64,
+                _,
+            >(pipe_reader, cache, options)?
+        }
+        _ => {
+            if arch.as_deref() != Some("x86_64") {
+                warn!(
+                    arch = ?arch,
+                    "Unknown arch {}, dwarf-based unwinding may be incorrect.",
+                    arch.as_deref().unwrap_or_default()
+                );
+            }
+            let cache = framehop::x86_64::CacheX86_64::new();
+            convert_pipe_impl::<framehop::x86_64::UnwinderX86_64<ModuleData>, ConvertRegsX86_64, _>(
+                pipe_reader,
                 cache,
-                merge_threads,
-                fold_recursive_prefix,
-            )
+                options,
+            )?
+        }
+    };
+    Ok(converted)
+}
+
+/// Converts several perf.data files into one profile, e.g. recordings of the
+/// processes of different containers during the same time. The files are
+/// converted one after the other, and their timestamps are lined up with
+/// their clock data, see [`align_inputs`]. The files need to have the same
+/// architecture. Their AUX data is read from the files themselves.
+pub fn convert_multiple(
+    files: &[File],
+    extra_dir: Option<&Path>,
+    options: ConversionOptions,
+) -> Result<ConvertedProfile, Error> {
+    let header_phase = options
+        .phases
+        .as_ref()
+        .map(|phases| phases.interval("Parse perf.data headers"));
+    let mut arch = None;
+    let mut clocks = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        let file = PerfFileReader::parse_file(rewound(file)?)?;
+        let file_arch = file.perf_file.arch().ok().flatten().map(ToOwned::to_owned);
+        if index == 0 {
+            arch = file_arch;
+        } else if file_arch != arch {
+            return Err(Error::MixedArchitectures(
+                arch.unwrap_or_default(),
+                file_arch.unwrap_or_default(),
+            ));
+        }
+        clocks.push(input_clock(file));
+    }
+    let aligned = align_inputs(&clocks);
+    if aligned.wall_clock_reference_ns.is_none() && files.len() > 1 {
+        warn!(
+            file_count = files.len(),
+            "Not all perf.data files have clock data, so their timestamps are assumed to be from \
+             the same clock. Record with `perf record -k CLOCK_MONOTONIC` to line them up \
+             through the wall clock."
+        );
+    }
+    drop(header_phase);
+
+    let converted = match arch.as_deref() {
+        Some("aarch64") => {
+            let cache = framehop::aarch64::CacheAarch64::new();
+            convert_multiple_impl::<
+                framehop::aarch64::UnwinderAarch64<ModuleData>,
+                ConvertRegsAarch64,
+            >(files, extra_dir, cache, options, aligned)?
+        }
+        _ => {
+            if arch.as_deref() != Some("x86_64") {
+                warn!(
+                    arch = ?arch,
+                    "Unknown arch {}, dwarf-based unwinding may be incorrect.",
+                    arch.as_deref().unwrap_or_default()
+                );
+            }
+            let cache = framehop::x86_64::CacheX86_64::new();
+            convert_multiple_impl::<framehop::x86_64::UnwinderX86_64<ModuleData>, ConvertRegsX86_64>(
+                files, extra_dir, cache, options, aligned,
+            )?
         }
     };
-    Ok(profile)
+    Ok(converted)
+}
+
+/// The clock of a perf.data file, for lining it up with the others in
+/// [`convert_multiple`].
+fn input_clock<R: Read>(file: PerfFileReader<R>) -> InputClock {
+    let PerfFileReader {
+        mut perf_file,
+        mut record_iter,
+    } = file;
+    let first_sample_time = perf_file
+        .sample_time_range()
+        .ok()
+        .flatten()
+        .map_or(0, |r| r.first_sample_time);
+    // Records like COMM and MMAP can be earlier than the first sample.
+    let first_record_time = match record_iter.next_record(&mut perf_file) {
+        Ok(Some(PerfFileRecord::EventRecord { record, .. })) => record.timestamp(),
+        _ => None,
+    };
+    InputClock {
+        host: perf_file
+            .hostname()
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+            .to_owned(),
+        reference_timestamp: reference_timestamp(first_sample_time, first_record_time),
+        clock_data: perf_file
+            .feature_section_data(Feature::CLOCK_DATA)
+            .and_then(|data| ClockData::parse(data, perf_file.endian())),
+    }
 }
 
 fn convert_impl<U, C, R>(
     file: PerfFileReader<R>,
     extra_dir: Option<&Path>,
+    aux_file: Option<&File>,
     cache: U::Cache,
-    merge_threads: bool,
-    fold_recursive_prefix: bool,
-) -> Profile
+    mut options: ConversionOptions,
+) -> Result<ConvertedProfile, Error>
 where
-    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+    U: Unwinder<Module = Module<ModuleData>> + Default,
     C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
     R: Read,
 {
@@ -78,29 +250,525 @@ where
         mut perf_file,
         mut record_iter,
     } = file;
-    let mut build_ids = perf_file.build_ids().ok().unwrap_or_default();
+    let mut header = FileHeader::read::<C>(&perf_file, aux_file)?;
+    let phases = options.phases.clone();
+    let watchdog = options.watchdog.map(Watchdog::start);
+
+    // Records like COMM and MMAP can be earlier than the first sample.
+    let first_record = record_iter.next_record(&mut perf_file).ok().flatten();
+    let first_record_time = match &first_record {
+        Some(PerfFileRecord::EventRecord { record, .. }) => record.timestamp(),
+        _ => None,
+    };
+    let first_sample_time = header.first_sample_time;
+    let recording_delay = RecordingDelay::detect(first_sample_time, first_record_time);
+    let keep_recording_delay = options.keep_recording_delay;
+    let reference_time = match recording_delay {
+        Some(delay) if !keep_recording_delay => delay.trimmed_start(),
+        _ => reference_timestamp(first_sample_time, first_record_time),
+    };
+    let mut intermediate = options.intermediate.take();
+    if let Some(intermediate) = &mut intermediate {
+        intermediate.write_header(
+            perf_file.arch().ok().flatten(),
+            header.endian,
+            &header.host,
+            &header.perf_version,
+            header.linux_version.as_deref(),
+            first_sample_time,
+            first_record_time,
+            &header.interpretation,
+            &header.build_ids,
+            perf_file.feature_section_data(Feature::TRACING_DATA),
+            perf_file.feature_section_data(Feature::NUMA_TOPOLOGY),
+        );
+    }
+
+    let mut records = FileRecordHandler::new(
+        &mut header,
+        0,
+        aux_file,
+        phases.clone(),
+        options.timings.clone(),
+    );
+    let mut converter = header.into_converter::<U>(reference_time, cache, extra_dir, options);
+    if let Some(delay) = recording_delay {
+        converter.set_recording_delay(delay, keep_recording_delay);
+    }
+
+    if let Some(record) = first_record {
+        records.handle::<U, C>(
+            record,
+            &mut converter,
+            watchdog.as_ref(),
+            intermediate.as_mut(),
+        );
+    }
+    while let Ok(Some(record)) = record_iter.next_record(&mut perf_file) {
+        records.handle::<U, C>(
+            record,
+            &mut converter,
+            watchdog.as_ref(),
+            intermediate.as_mut(),
+        );
+    }
+
+    records.finish(&mut converter);
+    if let Some(intermediate) = intermediate {
+        intermediate.finish();
+    }
+
+    if let Some(watchdog) = &watchdog {
+        watchdog.set_stage("finishing the conversion");
+    }
+    let _finish_phase = phases
+        .as_ref()
+        .map(|phases| phases.interval("Finish conversion"));
+    Ok(converter.finish_in_parts())
+}
+
+/// Like `convert_impl`, for several files which are converted one after the
+/// other into the same converter. The first file creates the converter, and
+/// the others continue its conversion with their own attribute indexes and
+/// reference timestamps, see [`Converter::start_next_input`].
+fn convert_multiple_impl<U, C>(
+    files: &[File],
+    extra_dir: Option<&Path>,
+    cache: U::Cache,
+    mut options: ConversionOptions,
+    aligned: AlignedInputs,
+) -> Result<ConvertedProfile, Error>
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+    C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+{
+    let phases = options.phases.clone();
+    let timings = options.timings.clone();
+    let watchdog = options.watchdog.map(Watchdog::start);
+    if options.intermediate.take().is_some() {
+        warn!("The intermediate file isn't written when converting several perf.data files.");
+    }
+    let AlignedInputs {
+        reference_timestamps,
+        wall_clock_reference_ns,
+    } = aligned;
+    let mut reference_timestamps = reference_timestamps.into_iter();
+    let (first_file, other_files) = files.split_first().expect("no perf.data files");
+
+    let file = PerfFileReader::parse_file(rewound(first_file)?)?;
+    let mut header = FileHeader::read::<C>(&file.perf_file, Some(first_file))?;
+    let records = FileRecordHandler::new(
+        &mut header,
+        0,
+        Some(first_file),
+        phases.clone(),
+        timings.clone(),
+    );
+    let mut converter = header.into_converter::<U>(
+        reference_timestamps.next().unwrap_or_default(),
+        cache,
+        extra_dir,
+        options,
+    );
+    if let Some(nanos) = wall_clock_reference_ns {
+        converter.set_reference_time(ReferenceTimestamp::from_duration_since_unix_epoch(
+            Duration::from_nanos(nanos),
+        ));
+    }
+    convert_records::<U, C, _>(file, records, &mut converter, watchdog.as_ref());
+
+    for (input_file, reference_time) in other_files.iter().zip(reference_timestamps) {
+        let file = PerfFileReader::parse_file(rewound(input_file)?)?;
+        let mut header = FileHeader::read::<C>(&file.perf_file, Some(input_file))?;
+        let attr_index_offset = converter.start_next_input(
+            std::mem::take(&mut header.build_ids),
+            header.endian,
+            &header.interpretation,
+            std::mem::take(&mut header.tracepoint_formats),
+            TimestampConverter::with_reference_timestamp(reference_time),
+        );
+        let records = FileRecordHandler::new(
+            &mut header,
+            attr_index_offset,
+            Some(input_file),
+            phases.clone(),
+            timings.clone(),
+        );
+        convert_records::<U, C, _>(file, records, &mut converter, watchdog.as_ref());
+    }
+
+    if let Some(watchdog) = &watchdog {
+        watchdog.set_stage("finishing the conversion");
+    }
+    let _finish_phase = phases
+        .as_ref()
+        .map(|phases| phases.interval("Finish conversion"));
+    Ok(converter.finish_in_parts())
+}
+
+/// Passes all records of a perf.data file to the converter.
+fn convert_records<U, C, R>(
+    file: PerfFileReader<R>,
+    mut records: FileRecordHandler,
+    converter: &mut Converter<U>,
+    watchdog: Option<&Watchdog>,
+) where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+    C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+    R: Read,
+{
+    let PerfFileReader {
+        mut perf_file,
+        mut record_iter,
+    } = file;
+    while let Ok(Some(record)) = record_iter.next_record(&mut perf_file) {
+        records.handle::<U, C>(record, converter, watchdog, None);
+    }
+    records.finish(converter);
+}
+
+/// Returns a reader for the file from its start.
+fn rewound(file: &File) -> Result<BufReader<&File>, Error> {
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(reader)
+}
+
+/// What the conversion needs from the header of a perf.data file.
+struct FileHeader {
+    build_ids: HashMap<DsoKey, DsoInfo>,
+    endian: Endianness,
+    host: String,
+    perf_version: String,
+    linux_version: Option<String>,
+    first_sample_time: u64,
+    interpretation: EventInterpretation,
+    lbr_formats: Vec<Option<LbrCallStackFormat>>,
+    tracepoint_formats: TracepointFormats,
+    numa_topology: Option<NumaTopology>,
+    arm_spe: Option<ArmSpe>,
+    auxtrace_index: Option<AuxtraceIndex>,
+}
+
+impl FileHeader {
+    fn read<C: ConvertRegs>(perf_file: &PerfFile, aux_file: Option<&File>) -> Result<Self, Error> {
+        let mut build_ids = perf_file.build_ids().ok().unwrap_or_default();
+        fixup_perf_jit_build_ids(&mut build_ids);
+        let first_sample_time = perf_file
+            .sample_time_range()
+            .unwrap()
+            .map_or(0, |r| r.first_sample_time);
+        let endian = perf_file.endian();
+        let host = perf_file
+            .hostname()
+            .unwrap()
+            .unwrap_or("<unknown host>")
+            .to_owned();
+        let perf_version = perf_file
+            .perf_version()
+            .unwrap()
+            .unwrap_or("<unknown version>")
+            .to_owned();
+        let linux_version = perf_file.os_release().unwrap().map(ToOwned::to_owned);
+        let attributes = perf_file.event_attributes();
+        for event_name in attributes.iter().filter_map(|attr| attr.name()) {
+            debug!(event = event_name, "Recorded event {event_name}");
+        }
+        let interpretation = EventInterpretation::divine_from_attrs(attributes);
+        check_sampled_user_regs::<C>(attributes);
+        check_sampling_policies(attributes);
+        let lbr_formats = lbr_call_stack_formats(attributes);
+        let tracepoint_formats =
+            parse_tracepoint_formats(perf_file.feature_section_data(Feature::TRACING_DATA));
+        let numa_topology = perf_file
+            .feature_section_data(Feature::NUMA_TOPOLOGY)
+            .and_then(|data| parse_numa_topology(data, endian));
+        let arm_spe = has_arm_spe_event(attributes).then(ArmSpe::default);
+        let auxtrace_index = match (&arm_spe, aux_file) {
+            (Some(_), Some(aux_file)) => perf_file
+                .feature_section_data(Feature::AUXTRACE)
+                .map(|section| AuxtraceIndex::read(section, aux_file, endian))
+                .transpose()
+                .unwrap_or_else(|err| {
+                    warn!(error = %err, "Could not read the auxtrace index: {err}");
+                    None
+                }),
+            _ => None,
+        };
+        if arm_spe.is_some() && auxtrace_index.is_none() {
+            warn!("The recording has an arm_spe event, but its AUX data can't be read.");
+        }
+        Ok(Self {
+            build_ids,
+            endian,
+            host,
+            perf_version,
+            linux_version,
+            first_sample_time,
+            interpretation,
+            lbr_formats,
+            tracepoint_formats,
+            numa_topology,
+            arm_spe,
+            auxtrace_index,
+        })
+    }
+
+    fn into_converter<U>(
+        self,
+        reference_time: u64,
+        cache: U::Cache,
+        extra_dir: Option<&Path>,
+        mut options: ConversionOptions,
+    ) -> Converter<U>
+    where
+        U: Unwinder<Module = Module<ModuleData>> + Default,
+    {
+        let FileHeader {
+            build_ids,
+            endian,
+            host,
+            perf_version,
+            linux_version,
+            interpretation,
+            tracepoint_formats,
+            numa_topology,
+            ..
+        } = self;
+        options.tracepoint_formats = tracepoint_formats;
+        options.numa_topology = numa_topology;
+        let product = "Converted perf profile";
+        Converter::<U>::new(
+            product,
+            Some(Box::new(move |name| {
+                format!("{name} on {host} (perf version {perf_version})")
+            })),
+            build_ids,
+            linux_version.as_deref(),
+            reference_time,
+            endian,
+            cache,
+            extra_dir,
+            interpretation,
+            options,
+        )
+    }
+}
+
+/// Passes the records of a perf.data file to the converter, one at a time,
+/// and decodes the AUX data of its `arm_spe` events on the way.
+struct FileRecordHandler<'a> {
+    /// The interpretation with the converter's attribute indexes.
+    interpretation: EventInterpretation,
+    /// Added to the attribute indexes of the records, see
+    /// [`Converter::start_next_input`].
+    attr_index_offset: usize,
+    lbr_formats: Vec<Option<LbrCallStackFormat>>,
+    endian: Endianness,
+    arm_spe: Option<ArmSpe>,
+    auxtrace_index: Option<AuxtraceIndex>,
+    aux_file: Option<&'a File>,
+    time_conv: Option<TimeConv>,
+    aux_samples: AuxSamples,
+    last_timestamp: u64,
+    record_count: u64,
+    phases: Option<PhaseRecorder>,
+    timings: Option<ConversionTimings>,
+}
+
+impl<'a> FileRecordHandler<'a> {
+    fn new(
+        header: &mut FileHeader,
+        attr_index_offset: usize,
+        aux_file: Option<&'a File>,
+        phases: Option<PhaseRecorder>,
+        timings: Option<ConversionTimings>,
+    ) -> Self {
+        Self {
+            interpretation: header
+                .interpretation
+                .clone()
+                .with_attr_index_offset(attr_index_offset),
+            attr_index_offset,
+            lbr_formats: std::mem::take(&mut header.lbr_formats),
+            endian: header.endian,
+            arm_spe: header.arm_spe.take(),
+            auxtrace_index: header.auxtrace_index.take(),
+            aux_file,
+            time_conv: None,
+            aux_samples: AuxSamples::default(),
+            last_timestamp: 0,
+            record_count: 0,
+            phases,
+            timings,
+        }
+    }
+
+    fn handle<U, C>(
+        &mut self,
+        record: PerfFileRecord,
+        converter: &mut Converter<U>,
+        watchdog: Option<&Watchdog>,
+        intermediate: Option<&mut IntermediateWriter>,
+    ) where
+        U: Unwinder<Module = Module<ModuleData>> + Default,
+        C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+    {
+        self.record_count += 1;
+        if let Some(phases) = &self.phases {
+            if self.record_count % RECORDS_PER_PROGRESS_PHASE == 0 {
+                phases.instant(format!("{} records", self.record_count));
+            }
+        }
+        let (record, parsed_record, attr_index) = match record {
+            PerfFileRecord::EventRecord { attr_index, record } => {
+                let record = self.aux_samples.strip_aux_field(record);
+                match record.parse() {
+                    Ok(r) => (record, r, attr_index),
+                    Err(_) => return,
+                }
+            }
+            PerfFileRecord::UserRecord(record) => {
+                let data = record.data.as_slice();
+                match record.record_type {
+                    UserRecordType::PERF_TIME_CONV => {
+                        self.time_conv = TimeConv::parse(&data, self.endian)
+                    }
+                    UserRecordType::PERF_AUXTRACE => {
+                        let (Some(arm_spe), Some(index), Some(aux_file)) =
+                            (&mut self.arm_spe, &self.auxtrace_index, self.aux_file)
+                        else {
+                            return;
+                        };
+                        let Some(header) = AuxtraceHeader::parse(&data, self.endian) else {
+                            return;
+                        };
+                        let Some(reader) = index.data(aux_file, &header) else {
+                            return;
+                        };
+                        if let Err(err) = arm_spe.decode_buffer(
+                            &header,
+                            self.last_timestamp,
+                            self.time_conv.as_ref(),
+                            reader,
+                        ) {
+                            warn!(error = %err, "Could not read the AUX data of a buffer: {err}");
+                        }
+                    }
+                    _ => {}
+                }
+                return;
+            }
+        };
+        if let Some(timestamp) = record.timestamp() {
+            let last_timestamp = self.last_timestamp;
+            if timestamp < last_timestamp {
+                warn!(
+                    timestamp,
+                    last_timestamp,
+                    "bad timestamp ordering; {timestamp} is earlier but arrived after {last_timestamp}"
+                );
+            }
+            self.last_timestamp = timestamp;
+        }
+        if let Some(arm_spe) = &mut self.arm_spe {
+            add_arm_spe_samples(
+                arm_spe,
+                &record,
+                &parsed_record,
+                self.last_timestamp,
+                converter,
+            );
+        }
+
+        handle_record::<U, C>(
+            converter,
+            &self.interpretation,
+            &record,
+            parsed_record,
+            self.attr_index_offset + attr_index,
+            self.lbr_formats.get(attr_index).copied().flatten(),
+            self.last_timestamp,
+            watchdog,
+            self.timings.as_ref(),
+            intermediate,
+        );
+    }
+
+    fn finish<U>(mut self, converter: &mut Converter<U>)
+    where
+        U: Unwinder<Module = Module<ModuleData>> + Default,
+    {
+        self.aux_samples.report();
+        if let Some(arm_spe) = &mut self.arm_spe {
+            finish_arm_spe_samples(arm_spe, converter);
+        }
+    }
+}
+
+/// Like `convert_impl`, but the metadata which a perf.data file has in its
+/// feature sections is only available if perf wrote it into the stream. The
+/// first sample time is the time of the first record, and the build IDs which
+/// arrive at the end of the stream are applied to the libraries afterwards.
+fn convert_pipe_impl<U, C, R>(
+    mut pipe_reader: PerfPipeReader<R>,
+    cache: U::Cache,
+    mut options: ConversionOptions,
+) -> Result<ConvertedProfile, Error>
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+    C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+    R: Read,
+{
+    let mut build_ids = pipe_reader.take_build_ids();
     fixup_perf_jit_build_ids(&mut build_ids);
-    let first_sample_time = perf_file
-        .sample_time_range()
-        .unwrap()
-        .map_or(0, |r| r.first_sample_time);
-    let endian = perf_file.endian();
-    let host = perf_file
+    let endian = pipe_reader.endian();
+    let host = pipe_reader
         .hostname()
-        .unwrap()
         .unwrap_or("<unknown host>")
         .to_owned();
-    let perf_version = perf_file
+    let perf_version = pipe_reader
         .perf_version()
-        .unwrap()
         .unwrap_or("<unknown version>")
         .to_owned();
-    let linux_version = perf_file.os_release().unwrap();
-    let attributes = perf_file.event_attributes();
+    let linux_version = pipe_reader.os_release().map(ToOwned::to_owned);
+    let attributes = pipe_reader.event_attributes();
     for event_name in attributes.iter().filter_map(|attr| attr.name()) {
-        println!("event {event_name}");
+        debug!(event = event_name, "Recorded event {event_name}");
     }
     let interpretation = EventInterpretation::divine_from_attrs(attributes);
+    check_sampled_user_regs::<C>(attributes);
+    check_sampling_policies(attributes);
+    let lbr_formats = lbr_call_stack_formats(attributes);
+    options.tracepoint_formats = parse_tracepoint_formats(pipe_reader.tracing_data());
+    options.numa_topology = pipe_reader
+        .numa_topology_data()
+        .and_then(|data| parse_numa_topology(data, endian));
+    let phases = options.phases.clone();
+    let timings = options.timings.clone();
+    let watchdog = options.watchdog.map(Watchdog::start);
+
+    let mut next_record = pipe_reader.next_record()?;
+    let first_sample_time = next_record
+        .as_ref()
+        .and_then(|record| record.raw().timestamp())
+        .unwrap_or(0);
+    let mut intermediate = options.intermediate.take();
+    if let Some(intermediate) = &mut intermediate {
+        intermediate.write_header(
+            pipe_reader.arch(),
+            endian,
+            &host,
+            &perf_version,
+            linux_version.as_deref(),
+            first_sample_time,
+            None,
+            &interpretation,
+            &build_ids,
+            pipe_reader.tracing_data(),
+            pipe_reader.numa_topology_data(),
+        );
+    }
 
     let product = "Converted perf profile";
     let mut converter = Converter::<U>::new(
@@ -109,77 +777,320 @@ where
             format!("{name} on {host} (perf version {perf_version})")
         })),
         build_ids,
-        linux_version,
+        linux_version.as_deref(),
         first_sample_time,
         endian,
         cache,
-        extra_dir,
+        None,
         interpretation.clone(),
-        merge_threads,
-        fold_recursive_prefix,
+        options,
     );
 
     let mut last_timestamp = 0;
+    let mut record_count = 0;
+    let mut aux_samples = AuxSamples::default();
 
-    while let Ok(Some(record)) = record_iter.next_record(&mut perf_file) {
-        let (record, parsed_record, attr_index) = match record {
-            PerfFileRecord::EventRecord { attr_index, record } => match record.parse() {
-                Ok(r) => (record, r, attr_index),
-                Err(_) => continue,
-            },
-            PerfFileRecord::UserRecord(_) => continue,
-        };
-        if let Some(timestamp) = record.timestamp() {
-            if timestamp < last_timestamp {
-                eprintln!(
-                    "bad timestamp ordering; {timestamp} is earlier but arrived after {last_timestamp}"
-                );
+    while let Some(pipe_record) = next_record {
+        record_count += 1;
+        if let Some(phases) = &phases {
+            if record_count % RECORDS_PER_PROGRESS_PHASE == 0 {
+                phases.instant(format!("{record_count} records"));
             }
-            last_timestamp = timestamp;
         }
-
-        match parsed_record {
-            EventRecord::Sample(e) => {
-                if attr_index == interpretation.main_event_attr_index {
-                    converter.handle_sample::<C>(&e);
-                } else if interpretation.sched_switch_attr_index == Some(attr_index) {
-                    converter.handle_sched_switch::<C>(&e);
-                } else if interpretation.rss_stat_attr_index != Some(attr_index) {
-                    converter.handle_other_event_sample::<C>(&e, attr_index);
-                }
-                if interpretation.rss_stat_attr_index == Some(attr_index) {
-                    converter.handle_rss_stat::<C>(&e);
-                }
-            }
-            EventRecord::Fork(e) => {
-                converter.handle_thread_start(e);
+        let record = aux_samples.strip_aux_field(pipe_record.raw());
+        if let Ok(parsed_record) = record.parse() {
+            if let Some(timestamp) = record.timestamp() {
+                last_timestamp = timestamp;
             }
-            EventRecord::Comm(e) => {
-                converter.handle_thread_name_update(e, record.timestamp());
+            if let Some(arm_spe) = pipe_reader.arm_spe_mut() {
+                add_arm_spe_samples(
+                    arm_spe,
+                    &record,
+                    &parsed_record,
+                    last_timestamp,
+                    &mut converter,
+                );
             }
-            EventRecord::Exit(e) => {
-                converter.handle_thread_end(e);
+            handle_record::<U, C>(
+                &mut converter,
+                &interpretation,
+                &record,
+                parsed_record,
+                pipe_record.attr_index,
+                lbr_formats.get(pipe_record.attr_index).copied().flatten(),
+                last_timestamp,
+                watchdog.as_ref(),
+                timings.as_ref(),
+                intermediate.as_mut(),
+            );
+        }
+        next_record = match pipe_reader.next_record() {
+            Ok(record) => record,
+            Err(err) => {
+                warn!(error = %err, "Stopped reading the perf.data stream after an error: {err}");
+                None
             }
-            EventRecord::Mmap(e) => {
-                converter.handle_mmap(e, last_timestamp);
+        };
+    }
+
+    aux_samples.report();
+    pipe_reader.report();
+    if let Some(intermediate) = intermediate {
+        intermediate.finish();
+    }
+    if let Some(arm_spe) = pipe_reader.arm_spe_mut() {
+        finish_arm_spe_samples(arm_spe, &mut converter);
+    }
+    if let Some(timings) = &timings {
+        let stats = pipe_reader.reorder_stats();
+        timings.record_reorder_buffer_usage(stats.peak_record_count, stats.peak_bytes);
+    }
+
+    // Second pass: the build IDs which arrived after the mappings which
+    // needed them.
+    let mut late_build_ids = pipe_reader.take_build_ids();
+    if !late_build_ids.is_empty() {
+        fixup_perf_jit_build_ids(&mut late_build_ids);
+        let updated_count = converter.add_late_build_ids(&late_build_ids);
+        info!(
+            late_build_id_count = late_build_ids.len(),
+            updated_count,
+            "Received {} build IDs at the end of the stream, and used them for {updated_count} \
+             libraries whose files couldn't be opened.",
+            late_build_ids.len()
+        );
+    }
+
+    if let Some(watchdog) = &watchdog {
+        watchdog.set_stage("finishing the conversion");
+    }
+    let _finish_phase = phases
+        .as_ref()
+        .map(|phases| phases.interval("Finish conversion"));
+    Ok(converter.finish_in_parts())
+}
+
+/// Passes a parsed record to the converter. Shared by perf.data files and
+/// streams.
+#[allow(clippy::too_many_arguments)]
+fn handle_record<U, C>(
+    converter: &mut Converter<U>,
+    interpretation: &EventInterpretation,
+    record: &RawEventRecord,
+    parsed_record: EventRecord,
+    attr_index: usize,
+    lbr_format: Option<LbrCallStackFormat>,
+    last_timestamp: u64,
+    watchdog: Option<&Watchdog>,
+    timings: Option<&ConversionTimings>,
+    intermediate: Option<&mut IntermediateWriter>,
+) where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+    C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+{
+    let _span = trace_span!("record", record_type = record.record_type.0, attr_index).entered();
+    if let Some(watchdog) = watchdog {
+        watchdog.record_started(record.record_type);
+    }
+    converter.observe_record(record.record_type);
+    if let Some(timestamp) = record.timestamp() {
+        converter.observe_record_timestamp(record.record_type, timestamp);
+    }
+    let common = record.common_data().ok();
+    if let EventRecord::Sample(_) = &parsed_record {
+        converter.set_sample_lbr_calls(sample_lbr_calls(record, lbr_format));
+    }
+    if let Some(intermediate) = intermediate {
+        let stack = match &parsed_record {
+            EventRecord::Sample(e) => converter.sample_stack::<C>(e),
+            _ => Vec::new(),
+        };
+        intermediate.write_record(
+            record.record_type,
+            common.as_ref(),
+            &parsed_record,
+            attr_index,
+            last_timestamp,
+            &stack,
+        );
+    }
+    // The data source isn't in the intermediate format, so mem access samples
+    // are handled here rather than in `dispatch_record`.
+    if let (EventRecord::Sample(e), Some(data_src)) = (&parsed_record, sample_data_src(record)) {
+        let _timing = TimingGuard::start(timings, sample_timing_bucket(interpretation, attr_index));
+        converter.handle_mem_access_sample(e, data_src);
+    }
+    dispatch_record::<U, C>(
+        converter,
+        interpretation,
+        parsed_record,
+        attr_index,
+        record.timestamp(),
+        common,
+        last_timestamp,
+        timings,
+    );
+    if let Some(watchdog) = watchdog {
+        if watchdog.wants_snapshot() {
+            watchdog.publish_snapshot(converter.metrics());
+        }
+    }
+}
+
+/// Calls the converter's handler for the record. Shared by perf.data files
+/// and intermediate files. `common` is only needed for context switches.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn dispatch_record<U, C>(
+    converter: &mut Converter<U>,
+    interpretation: &EventInterpretation,
+    parsed_record: EventRecord,
+    attr_index: usize,
+    timestamp: Option<u64>,
+    common: Option<CommonData>,
+    last_timestamp: u64,
+    timings: Option<&ConversionTimings>,
+) where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+    C: ConvertRegs<UnwindRegs = U::UnwindRegs>,
+{
+    match parsed_record {
+        EventRecord::Sample(e) => {
+            let _timing =
+                TimingGuard::start(timings, sample_timing_bucket(interpretation, attr_index));
+            if interpretation.frequency_event_attr_indexes.is_some() {
+                converter.handle_cpu_frequency_event_sample(&e, attr_index);
             }
-            EventRecord::Mmap2(e) => {
-                converter.handle_mmap2(e, last_timestamp);
+            let is_main_event = attr_index == interpretation.main_event_attr_index;
+            if is_main_event && !interpretation.marker_only {
+                converter.handle_sample::<C>(&e);
+            } else if interpretation
+                .event_track_attr_indexes
+                .contains(&attr_index)
+            {
+                converter.handle_event_track_sample::<C>(&e, attr_index);
+            } else if !converter.handle_tracepoint_sample::<C>(&e, attr_index) || is_main_event {
+                // Without samples, the main event is at least shown as markers,
+                // even if a tracepoint handler uses it too.
+                converter.handle_other_event_sample::<C>(&e, attr_index);
             }
-            EventRecord::ContextSwitch(e) => {
-                let common = match record.common_data() {
-                    Ok(common) => common,
-                    Err(_) => continue,
-                };
+        }
+        EventRecord::Fork(e) => {
+            let _timing = TimingGuard::start(timings, TimingBucket::ForkExit);
+            converter.handle_thread_start(e);
+        }
+        EventRecord::Comm(e) => {
+            let _timing = TimingGuard::start(timings, TimingBucket::Comm);
+            converter.handle_thread_name_update(e, timestamp);
+        }
+        EventRecord::Exit(e) => {
+            let _timing = TimingGuard::start(timings, TimingBucket::ForkExit);
+            converter.handle_thread_end(e);
+        }
+        EventRecord::Mmap(e) => {
+            let _timing = TimingGuard::start(timings, TimingBucket::Mmap);
+            converter.handle_mmap(e, last_timestamp);
+        }
+        EventRecord::Mmap2(e) => {
+            let _timing = TimingGuard::start(timings, TimingBucket::Mmap);
+            converter.handle_mmap2(e, last_timestamp);
+        }
+        EventRecord::ContextSwitch(e) => {
+            let _timing = TimingGuard::start(timings, TimingBucket::ContextSwitch);
+            if let Some(common) = common {
                 converter.handle_context_switch(e, common);
             }
-            _ => {
-                // println!("{:?}", record.record_type);
-            }
+        }
+        _ => {
+            // println!("{:?}", record.record_type);
         }
     }
+}
 
-    converter.finish()
+fn sample_timing_bucket(interpretation: &EventInterpretation, attr_index: usize) -> TimingBucket {
+    match attr_index == interpretation.main_event_attr_index && !interpretation.marker_only {
+        true => TimingBucket::Sample,
+        false => TimingBucket::OtherEvent,
+    }
+}
+
+/// Passes the ARM SPE samples up to `timestamp` to the converter, before the
+/// record at `timestamp` is handled. The record tells which thread runs on
+/// its CPU.
+fn add_arm_spe_samples<U>(
+    arm_spe: &mut ArmSpe,
+    record: &RawEventRecord,
+    parsed_record: &EventRecord,
+    timestamp: u64,
+    converter: &mut Converter<U>,
+) where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+{
+    while let Some(sample) = arm_spe.pop_until(timestamp) {
+        converter.handle_memory_latency_sample(
+            sample.pid,
+            sample.timestamp,
+            sample.pc,
+            sample.mode,
+            sample.latency,
+            sample.kind,
+        );
+    }
+    if let Ok(common) = record.common_data() {
+        let is_switch_out = matches!(
+            parsed_record,
+            EventRecord::ContextSwitch(ContextSwitchRecord::Out { .. })
+        );
+        arm_spe.observe_record(common.pid, common.tid, common.cpu, is_switch_out);
+    }
+}
+
+/// Passes the remaining ARM SPE samples to the converter.
+fn finish_arm_spe_samples<U>(arm_spe: &mut ArmSpe, converter: &mut Converter<U>)
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+{
+    while let Some(sample) = arm_spe.pop_until(u64::MAX) {
+        converter.handle_memory_latency_sample(
+            sample.pid,
+            sample.timestamp,
+            sample.pc,
+            sample.mode,
+            sample.latency,
+            sample.kind,
+        );
+    }
+    arm_spe.report();
+}
+
+/// Parses the tracepoint formats from the tracing data, if the recording has
+/// any. Without them, probe markers don't have arguments.
+pub(super) fn parse_tracepoint_formats(tracing_data: Option<&[u8]>) -> TracepointFormats {
+    let Some(tracing_data) = tracing_data else {
+        return TracepointFormats::default();
+    };
+    match TracepointFormats::parse(tracing_data) {
+        Ok(formats) => formats,
+        Err(err) => {
+            warn!(
+                error = err,
+                "Could not parse the tracepoint formats in the tracing data: {err}"
+            );
+            TracepointFormats::default()
+        }
+    }
+}
+
+/// Parses the CPU to NUMA node mapping, which is used for the per-node sample
+/// counters. Topologies with a single node are ignored.
+pub(super) fn parse_numa_topology(data: &[u8], endian: Endianness) -> Option<NumaTopology> {
+    match NumaTopology::parse(data, endian) {
+        Some(topology) if topology.node_count() > 1 => Some(topology),
+        Some(_) => None,
+        None => {
+            warn!("Could not parse the NUMA topology in the perf.data header.");
+            None
+        }
+    }
 }
 
 /// This is a terrible hack to work around ambiguous build IDs in old versions
@@ -210,3 +1121,564 @@ fn fixup_perf_jit_build_ids(build_ids: &mut HashMap<DsoKey, DsoInfo>) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::import::arm_spe::test::load_record;
+    use crate::import::arm_spe::PERF_RECORD_AUXTRACE;
+
+    /// A perf.data stream of a cpu-clock recording of one process: a fork,
+    /// a comm, an mmap, an mmap2, three samples, a context switch and an
+    /// exit. With `arm_spe_data`, the recording also has an `arm_spe_0//`
+    /// event, and an AUX buffer with the data after the first sample.
+    fn pipe_stream(arm_spe_data: Option<&[u8]>) -> Vec<u8> {
+        pipe_stream_with_sample_ips(arm_spe_data, [0x1010; 3])
+    }
+
+    /// Like [`pipe_stream`], with the given instruction pointers for the
+    /// three samples.
+    fn pipe_stream_with_sample_ips(arm_spe_data: Option<&[u8]>, ips: [u64; 3]) -> Vec<u8> {
+        let mut stream = b"PERFILE2".to_vec();
+        stream.extend_from_slice(&16u64.to_le_bytes());
+        let mut push_record = |record_type: u32, misc: u16, body: &[u8]| {
+            // The AUX data follows an AUXTRACE record but isn't part of it.
+            let size = match record_type {
+                PERF_RECORD_AUXTRACE => 48,
+                _ => 8 + body.len() as u16,
+            };
+            stream.extend_from_slice(&record_type.to_le_bytes());
+            stream.extend_from_slice(&misc.to_le_bytes());
+            stream.extend_from_slice(&size.to_le_bytes());
+            stream.extend_from_slice(body);
+        };
+
+        // PERF_TYPE_SOFTWARE, config 0 (cpu-clock), a period of 1ms, samples
+        // with IP, TID and TIME, and sample_id_all.
+        let mut attr = [0u8; 64];
+        attr[0..4].copy_from_slice(&1u32.to_le_bytes());
+        attr[4..8].copy_from_slice(&64u32.to_le_bytes());
+        attr[16..24].copy_from_slice(&1_000_000u64.to_le_bytes());
+        attr[24..32].copy_from_slice(&0b111u64.to_le_bytes());
+        attr[40..48].copy_from_slice(&(1u64 << 18).to_le_bytes());
+        let mut attr_record = attr.to_vec();
+        attr_record.extend_from_slice(&1u64.to_le_bytes());
+        push_record(64, 0, &attr_record); // PERF_RECORD_HEADER_ATTR
+        if arm_spe_data.is_some() {
+            // A dynamic PMU type, with the same sample format.
+            attr[0..4].copy_from_slice(&8u32.to_le_bytes());
+            let mut attr_record = attr.to_vec();
+            attr_record.extend_from_slice(&2u64.to_le_bytes());
+            push_record(64, 0, &attr_record);
+            let mut name = 2u64.to_le_bytes().to_vec(); // PERF_EVENT_UPDATE_NAME
+            name.extend_from_slice(&2u64.to_le_bytes());
+            name.extend_from_slice(b"arm_spe_0//\0\0\0\0\0");
+            push_record(78, 0, &name); // PERF_RECORD_EVENT_UPDATE
+        }
+
+        let (pid, tid) = (100u32, 100u32);
+        // The TID and TIME which sample_id_all appends to non-sample records.
+        let with_sample_id = |mut body: Vec<u8>, time: u64| {
+            body.extend_from_slice(&pid.to_le_bytes());
+            body.extend_from_slice(&tid.to_le_bytes());
+            body.extend_from_slice(&time.to_le_bytes());
+            body
+        };
+        let fork_or_exit = |time: u64| {
+            let mut body = Vec::new();
+            for id in [pid, 1, tid, 1] {
+                body.extend_from_slice(&id.to_le_bytes());
+            }
+            body.extend_from_slice(&time.to_le_bytes());
+            with_sample_id(body, time)
+        };
+        let mapping = |time: u64, mmap2: bool| {
+            let mut body = Vec::new();
+            body.extend_from_slice(&pid.to_le_bytes());
+            body.extend_from_slice(&tid.to_le_bytes());
+            for value in [0x1000u64, 0x1000, 0] {
+                body.extend_from_slice(&value.to_le_bytes());
+            }
+            if mmap2 {
+                // maj, min, ino, ino_generation, prot, flags
+                body.extend_from_slice(&[0; 32]);
+            }
+            body.extend_from_slice(b"//anon\0\0");
+            with_sample_id(body, time)
+        };
+
+        push_record(7, 0, &fork_or_exit(1000)); // PERF_RECORD_FORK
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&pid.to_le_bytes());
+        comm.extend_from_slice(&tid.to_le_bytes());
+        comm.extend_from_slice(b"app\0\0\0\0\0");
+        push_record(3, 0, &with_sample_id(comm, 1100)); // PERF_RECORD_COMM
+        push_record(1, 0, &mapping(1200, false)); // PERF_RECORD_MMAP
+        push_record(10, 0, &mapping(1300, true)); // PERF_RECORD_MMAP2
+        for (time, ip) in [2000u64, 3000, 4000].into_iter().zip(ips) {
+            let mut sample = Vec::new();
+            sample.extend_from_slice(&ip.to_le_bytes());
+            sample.extend_from_slice(&pid.to_le_bytes());
+            sample.extend_from_slice(&tid.to_le_bytes());
+            sample.extend_from_slice(&time.to_le_bytes());
+            push_record(9, 2, &sample); // PERF_RECORD_SAMPLE, PERF_RECORD_MISC_USER
+            if let (2000, Some(data)) = (time, arm_spe_data) {
+                // size, offset, reference, idx, tid, cpu, reserved
+                let mut auxtrace = Vec::new();
+                for value in [data.len() as u64, 0, 1] {
+                    auxtrace.extend_from_slice(&value.to_le_bytes());
+                }
+                for value in [0, tid, 0, 0] {
+                    auxtrace.extend_from_slice(&value.to_le_bytes());
+                }
+                auxtrace.extend_from_slice(data);
+                push_record(PERF_RECORD_AUXTRACE, 0, &auxtrace);
+            }
+        }
+        // PERF_RECORD_SWITCH, PERF_RECORD_MISC_SWITCH_OUT
+        push_record(14, 0x2000, &with_sample_id(Vec::new(), 4500));
+        push_record(4, 0, &fork_or_exit(5000)); // PERF_RECORD_EXIT
+        stream
+    }
+
+    #[test]
+    fn timings_count_the_records_of_each_type() {
+        let timings = ConversionTimings::default();
+        let options = ConversionOptions {
+            timings: Some(timings.clone()),
+            ..Default::default()
+        };
+        convert_pipe(&pipe_stream(None)[..], options).unwrap();
+        let counts: Vec<(&str, u64)> = TimingBucket::ALL
+            .iter()
+            .map(|&bucket| (bucket.name(), timings.count(bucket)))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("sample", 3),
+                ("other-event", 0),
+                ("mmap/mmap2", 2),
+                ("comm", 1),
+                ("fork/exit", 2),
+                ("context switch", 1),
+                // The samples only have frame pointer stacks.
+                ("stack unwinding", 0),
+                ("stack interning", 3),
+                // The process has no jitdump files, so they're never polled.
+                ("jitdump", 0),
+            ]
+        );
+        let (peak_records, _peak_bytes) = timings.reorder_buffer_usage().unwrap();
+        assert!(peak_records > 0);
+    }
+
+    /// Checks the timings counts against the records of the stream, counted
+    /// by walking the record headers rather than taken from the converter.
+    #[test]
+    fn timings_counts_match_the_records_in_the_stream() {
+        use linux_perf_event_reader::constants::{
+            PERF_RECORD_COMM, PERF_RECORD_EXIT, PERF_RECORD_FORK, PERF_RECORD_MMAP,
+            PERF_RECORD_MMAP2, PERF_RECORD_SAMPLE, PERF_RECORD_SWITCH, PERF_RECORD_SWITCH_CPU_WIDE,
+        };
+
+        let stream = pipe_stream(None);
+        let mut record_counts: HashMap<u32, u64> = HashMap::new();
+        // Skip the pipe header: the magic and the header size.
+        let mut offset = 16;
+        while offset < stream.len() {
+            let header = &stream[offset..offset + 8];
+            let record_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let size = u16::from_le_bytes(header[6..8].try_into().unwrap());
+            *record_counts.entry(record_type).or_default() += 1;
+            offset += usize::from(size);
+        }
+        assert_eq!(offset, stream.len());
+        let count = |types: &[u32]| -> u64 {
+            types
+                .iter()
+                .map(|t| record_counts.get(t).copied().unwrap_or(0))
+                .sum()
+        };
+
+        let timings = ConversionTimings::default();
+        let options = ConversionOptions {
+            timings: Some(timings.clone()),
+            ..Default::default()
+        };
+        convert_pipe(&stream[..], options).unwrap();
+        let samples = count(&[PERF_RECORD_SAMPLE]);
+        assert!(samples > 0);
+        assert_eq!(timings.count(TimingBucket::Sample), samples);
+        assert_eq!(
+            timings.count(TimingBucket::Mmap),
+            count(&[PERF_RECORD_MMAP, PERF_RECORD_MMAP2])
+        );
+        assert_eq!(
+            timings.count(TimingBucket::Comm),
+            count(&[PERF_RECORD_COMM])
+        );
+        assert_eq!(
+            timings.count(TimingBucket::ForkExit),
+            count(&[PERF_RECORD_FORK, PERF_RECORD_EXIT])
+        );
+        assert_eq!(
+            timings.count(TimingBucket::ContextSwitch),
+            count(&[PERF_RECORD_SWITCH, PERF_RECORD_SWITCH_CPU_WIDE])
+        );
+        // Every sample of the main event interns its stack once.
+        assert_eq!(timings.count(TimingBucket::StackInterning), samples);
+    }
+
+    #[test]
+    fn intermediate_files_convert_like_the_recording() {
+        use crate::import::intermediate::{self, IntermediateWriter};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profile.samply-intermediate");
+        let options = ConversionOptions {
+            intermediate: Some(IntermediateWriter::create(&path).unwrap()),
+            ..Default::default()
+        };
+        let direct = convert_pipe(&pipe_stream(None)[..], options).unwrap();
+        let file = File::open(&path).unwrap();
+        assert!(intermediate::is_intermediate_file(&file));
+        let reader = std::io::BufReader::new(file);
+        let replayed = intermediate::convert(reader, ConversionOptions::default()).unwrap();
+
+        // The profiles only differ in their start time, which is the time of
+        // the conversion.
+        let direct = serde_json::to_value(&direct.profile).unwrap();
+        let replayed = serde_json::to_value(&replayed.profile).unwrap();
+        for key in ["threads", "libs", "counters"] {
+            assert_eq!(replayed[key], direct[key], "{key} differ");
+        }
+        assert_eq!(replayed["meta"]["product"], direct["meta"]["product"]);
+    }
+
+    #[test]
+    fn converted_recordings_have_no_validation_violations() {
+        use crate::profile_json::ProfileJson;
+        use crate::shared::jitdump_manager::test::{write_jitdump, CODE_ADDR};
+        use crate::validate::validate_profile;
+
+        let dir = tempfile::tempdir().unwrap();
+        let jitdump_path = dir.path().join("jit-100.dump");
+        // The JIT function is loaded before the samples.
+        write_jitdump(&jitdump_path, 0, 1000);
+        let aux_data = load_record(0x1010, 12, 0, None);
+        let recordings = [
+            (pipe_stream(None), HashMap::new()),
+            (pipe_stream(Some(&aux_data)), HashMap::new()),
+            (
+                pipe_stream_with_sample_ips(None, [0x1010, CODE_ADDR + 4, CODE_ADDR + 8]),
+                HashMap::from([(100, vec![jitdump_path])]),
+            ),
+        ];
+        for (index, (stream, jitdump_paths_by_pid)) in recordings.into_iter().enumerate() {
+            let options = ConversionOptions {
+                jitdump_paths_by_pid,
+                ..Default::default()
+            };
+            let converted = convert_pipe(&stream[..], options).unwrap();
+            let profile = serde_json::to_value(&converted.profile).unwrap();
+            if index == 2 {
+                assert!(profile.to_string().contains("jitted_function"));
+            }
+            let profile: ProfileJson = serde_json::from_value(profile).unwrap();
+            assert_eq!(validate_profile(&profile), Vec::new(), "recording {index}");
+        }
+    }
+
+    #[test]
+    fn converts_arm_spe_loads_to_memory_latency_samples() {
+        // A hit, an LLC miss and a TLB miss.
+        let mut aux_data = Vec::new();
+        for (pc, latency, events) in [
+            (0x1010, 12, 0),
+            (0x1020, 300, 1 << 9),
+            (0x1030, 800, 1 << 5),
+        ] {
+            aux_data.extend(load_record(pc, latency, events, None));
+        }
+        let stream = pipe_stream(Some(&aux_data));
+        let converted = convert_pipe(&stream[..], ConversionOptions::default()).unwrap();
+
+        let profile = serde_json::to_value(&converted.profile).unwrap();
+        let thread = profile["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|thread| thread["name"] == "Memory latency")
+            .unwrap();
+        assert_eq!(
+            thread["samples"]["weight"],
+            serde_json::json!([12, 300, 800])
+        );
+        let json = profile.to_string();
+        for label in ["[Cache hit]", "[LLC miss]", "[TLB miss]"] {
+            assert!(json.contains(label), "{label} is missing");
+        }
+    }
+
+    /// Pins the samples and markers of a recording with the tracepoints which
+    /// have built-in handlers: a sched_switch before the thread sleeps, a
+    /// probe, and a syscall tracepoint which becomes a generic event marker.
+    #[test]
+    fn tracepoint_samples_become_markers_and_off_cpu_samples() {
+        let mut stream = b"PERFILE2".to_vec();
+        stream.extend_from_slice(&16u64.to_le_bytes());
+        let mut push_record = |record_type: u32, misc: u16, body: &[u8]| {
+            stream.extend_from_slice(&record_type.to_le_bytes());
+            stream.extend_from_slice(&misc.to_le_bytes());
+            stream.extend_from_slice(&(8 + body.len() as u16).to_le_bytes());
+            stream.extend_from_slice(body);
+        };
+        // IP, TID, TIME and IDENTIFIER for all events, with sample_id_all,
+        // and context switch records for the main event.
+        let sample_type = 0b111u64 | (1 << 16);
+        let mut push_attr = |attr_type: u32, flags: u64, id: u64, name: &str| {
+            let mut attr = [0u8; 64];
+            attr[0..4].copy_from_slice(&attr_type.to_le_bytes());
+            attr[4..8].copy_from_slice(&64u32.to_le_bytes());
+            attr[16..24].copy_from_slice(&1_000_000u64.to_le_bytes());
+            attr[24..32].copy_from_slice(&sample_type.to_le_bytes());
+            attr[40..48].copy_from_slice(&(flags | (1 << 18)).to_le_bytes());
+            let mut attr_record = attr.to_vec();
+            attr_record.extend_from_slice(&id.to_le_bytes());
+            push_record(64, 0, &attr_record); // PERF_RECORD_HEADER_ATTR
+            let mut update = 2u64.to_le_bytes().to_vec(); // PERF_EVENT_UPDATE_NAME
+            update.extend_from_slice(&id.to_le_bytes());
+            let mut name = name.as_bytes().to_vec();
+            name.resize((name.len() / 8 + 1) * 8, 0);
+            update.extend_from_slice(&name);
+            push_record(78, 0, &update); // PERF_RECORD_EVENT_UPDATE
+        };
+        push_attr(1, 1 << 26, 1, "cpu-clock");
+        push_attr(2, 0, 2, "sched:sched_switch");
+        push_attr(2, 0, 3, "probe:my_function");
+        push_attr(2, 0, 4, "syscalls:sys_enter_read");
+
+        let (pid, tid) = (100u32, 100u32);
+        let with_sample_id = |mut body: Vec<u8>, time: u64| {
+            body.extend_from_slice(&pid.to_le_bytes());
+            body.extend_from_slice(&tid.to_le_bytes());
+            body.extend_from_slice(&time.to_le_bytes());
+            body.extend_from_slice(&1u64.to_le_bytes());
+            body
+        };
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&pid.to_le_bytes());
+        comm.extend_from_slice(&tid.to_le_bytes());
+        comm.extend_from_slice(b"app\0\0\0\0\0");
+        push_record(3, 0, &with_sample_id(comm, 1000)); // PERF_RECORD_COMM
+        let mut mmap = Vec::new();
+        mmap.extend_from_slice(&pid.to_le_bytes());
+        mmap.extend_from_slice(&tid.to_le_bytes());
+        for value in [0x1000u64, 0x1000, 0] {
+            mmap.extend_from_slice(&value.to_le_bytes());
+        }
+        mmap.extend_from_slice(b"//anon\0\0");
+        push_record(1, 0, &with_sample_id(mmap, 1100)); // PERF_RECORD_MMAP
+
+        let sample = |id: u64, time: u64, ip: u64| {
+            let mut sample = id.to_le_bytes().to_vec();
+            sample.extend_from_slice(&ip.to_le_bytes());
+            sample.extend_from_slice(&pid.to_le_bytes());
+            sample.extend_from_slice(&tid.to_le_bytes());
+            sample.extend_from_slice(&time.to_le_bytes());
+            sample
+        };
+        // PERF_RECORD_SAMPLE, PERF_RECORD_MISC_USER
+        push_record(9, 2, &sample(1, 2_000_000, 0x1010));
+        push_record(9, 2, &sample(3, 2_100_000, 0x1020));
+        push_record(9, 2, &sample(4, 2_200_000, 0x1030));
+        push_record(9, 2, &sample(1, 3_000_000, 0x1010));
+        push_record(9, 2, &sample(2, 3_500_000, 0x1040));
+        // PERF_RECORD_SWITCH, out with PERF_RECORD_MISC_SWITCH_OUT, and in.
+        push_record(14, 0x2000, &with_sample_id(Vec::new(), 3_500_000));
+        push_record(14, 0, &with_sample_id(Vec::new(), 6_500_000));
+        push_record(9, 2, &sample(1, 7_000_000, 0x1050));
+        push_record(9, 2, &sample(3, 7_100_000, 0x1020));
+
+        let converted = convert_pipe(&stream[..], ConversionOptions::default()).unwrap();
+        let profile = serde_json::to_value(&converted.profile).unwrap();
+        let thread = profile["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|thread| thread["tid"] == "100")
+            .unwrap();
+        let string = |index: &serde_json::Value| {
+            thread["stringArray"][index.as_u64().unwrap() as usize]
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        let leaf_name = |stack: &serde_json::Value| {
+            let frame = &thread["stackTable"]["frame"][stack.as_u64().unwrap() as usize];
+            let func = &thread["frameTable"]["func"][frame.as_u64().unwrap() as usize];
+            string(&thread["funcTable"]["name"][func.as_u64().unwrap() as usize])
+        };
+        let samples = &thread["samples"];
+        let samples: Vec<(f64, i64, String)> = (0..samples["length"].as_u64().unwrap() as usize)
+            .map(|i| {
+                (
+                    samples["time"][i].as_f64().unwrap(),
+                    samples["weight"][i].as_i64().unwrap_or(1),
+                    leaf_name(&samples["stack"][i]),
+                )
+            })
+            .collect();
+        let markers = &thread["markers"];
+        let markers: Vec<(String, f64)> = (0..markers["length"].as_u64().unwrap() as usize)
+            .map(|i| {
+                (
+                    string(&markers["name"][i]),
+                    markers["startTime"][i].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        // The thread sleeps from 3.5ms to 6.5ms, in the off-CPU samples with
+        // the stack of the sched_switch sample.
+        let expected_samples = [
+            (1.999, 1, "0x1010"),
+            (2.999, 1, "0x1010"),
+            (4.499, 1, "0x1040"),
+            (6.499, 2, "0x1040"),
+            (6.999, 1, "0x1050"),
+        ];
+        let expected_samples: Vec<(f64, i64, String)> = expected_samples
+            .into_iter()
+            .map(|(time, weight, leaf)| (time, weight, leaf.to_string()))
+            .collect();
+        assert_eq!(samples, expected_samples);
+        assert_eq!(
+            markers,
+            vec![
+                ("probe:my_function".to_string(), 2.099),
+                ("syscalls:sys_enter_read".to_string(), 2.199),
+                ("probe:my_function".to_string(), 7.099),
+            ]
+        );
+    }
+
+    #[test]
+    fn converts_events_with_different_sample_formats() {
+        let mut stream = b"PERFILE2".to_vec();
+        stream.extend_from_slice(&16u64.to_le_bytes());
+        let mut push_record = |record_type: u32, misc: u16, body: &[u8]| {
+            stream.extend_from_slice(&record_type.to_le_bytes());
+            stream.extend_from_slice(&misc.to_le_bytes());
+            stream.extend_from_slice(&(8 + body.len() as u16).to_le_bytes());
+            stream.extend_from_slice(body);
+        };
+        const IP: u64 = 1 << 0;
+        const TID: u64 = 1 << 1;
+        const TIME: u64 = 1 << 2;
+        const CPU: u64 = 1 << 7;
+        const PERIOD: u64 = 1 << 8;
+        const IDENTIFIER: u64 = 1 << 16;
+        let mut push_attr = |attr_type: u32, sample_type: u64, id: u64, name: &str| {
+            let mut attr = [0u8; 64];
+            attr[0..4].copy_from_slice(&attr_type.to_le_bytes());
+            attr[4..8].copy_from_slice(&64u32.to_le_bytes());
+            attr[16..24].copy_from_slice(&1u64.to_le_bytes());
+            attr[24..32].copy_from_slice(&sample_type.to_le_bytes());
+            attr[40..48].copy_from_slice(&(1u64 << 18).to_le_bytes());
+            let mut attr_record = attr.to_vec();
+            attr_record.extend_from_slice(&id.to_le_bytes());
+            push_record(64, 0, &attr_record); // PERF_RECORD_HEADER_ATTR
+            let mut update = 2u64.to_le_bytes().to_vec(); // PERF_EVENT_UPDATE_NAME
+            update.extend_from_slice(&id.to_le_bytes());
+            let mut name = name.as_bytes().to_vec();
+            name.resize((name.len() / 8 + 1) * 8, 0);
+            update.extend_from_slice(&name);
+            push_record(78, 0, &update); // PERF_RECORD_EVENT_UPDATE
+        };
+
+        // The cpu-clock main event, followed by 32 tracepoint events which
+        // have every combination of the optional fields.
+        push_attr(1, IDENTIFIER | IP | TID | TIME, 1, "cpu-clock");
+        let event_format = |event: u64| {
+            [IP, TID, TIME, CPU, PERIOD]
+                .into_iter()
+                .enumerate()
+                .filter(|(bit, _)| event & (1 << bit) != 0)
+                .fold(IDENTIFIER, |format, (_, field)| format | field)
+        };
+        for event in 0..32 {
+            let name = format!("stress:event_{event}");
+            push_attr(2, event_format(event), 100 + event, &name);
+        }
+
+        let (pid, tid) = (100u32, 100u32);
+        // The sample_id_all fields of the main event: TID, TIME and
+        // IDENTIFIER.
+        let with_sample_id = |mut body: Vec<u8>, time: u64| {
+            body.extend_from_slice(&pid.to_le_bytes());
+            body.extend_from_slice(&tid.to_le_bytes());
+            body.extend_from_slice(&time.to_le_bytes());
+            body.extend_from_slice(&1u64.to_le_bytes());
+            body
+        };
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&pid.to_le_bytes());
+        comm.extend_from_slice(&tid.to_le_bytes());
+        comm.extend_from_slice(b"app\0\0\0\0\0");
+        push_record(3, 0, &with_sample_id(comm, 1000)); // PERF_RECORD_COMM
+
+        let mut time = 2000u64;
+        let mut push_sample = |id: u64, sample_type: u64| {
+            time += 100;
+            let mut sample = id.to_le_bytes().to_vec();
+            if sample_type & IP != 0 {
+                sample.extend_from_slice(&0x1010u64.to_le_bytes());
+            }
+            if sample_type & TID != 0 {
+                sample.extend_from_slice(&pid.to_le_bytes());
+                sample.extend_from_slice(&tid.to_le_bytes());
+            }
+            if sample_type & TIME != 0 {
+                sample.extend_from_slice(&time.to_le_bytes());
+            }
+            if sample_type & CPU != 0 {
+                sample.extend_from_slice(&[0; 8]);
+            }
+            if sample_type & PERIOD != 0 {
+                sample.extend_from_slice(&1u64.to_le_bytes());
+            }
+            push_record(9, 2, &sample); // PERF_RECORD_SAMPLE, PERF_RECORD_MISC_USER
+        };
+        push_sample(1, IDENTIFIER | IP | TID | TIME);
+        for event in 0..32 {
+            for _ in 0..event % 3 + 1 {
+                push_sample(100 + event, event_format(event));
+            }
+        }
+
+        let converted = convert_pipe(&stream[..], ConversionOptions::default()).unwrap();
+        let profile = serde_json::to_value(&converted.profile).unwrap();
+        let mut marker_counts: HashMap<String, u64> = HashMap::new();
+        for thread in profile["threads"].as_array().unwrap() {
+            for name in thread["markers"]["name"].as_array().unwrap() {
+                let name = &thread["stringArray"][name.as_u64().unwrap() as usize];
+                *marker_counts
+                    .entry(name.as_str().unwrap().to_string())
+                    .or_default() += 1;
+            }
+        }
+        // Only the samples with a TID and a TIME can become markers, the
+        // others are skipped.
+        for event in 0..32 {
+            let has_tid_and_time = (event_format(event) & (TID | TIME)) == TID | TIME;
+            let expected = if has_tid_and_time { event % 3 + 1 } else { 0 };
+            let name = format!("stress:event_{event}");
+            assert_eq!(
+                marker_counts.get(&name).copied().unwrap_or(0),
+                expected,
+                "{name}"
+            );
+        }
+    }
+}
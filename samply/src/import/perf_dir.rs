@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Could not read the directory {0:?}: {1}")]
+    Io(PathBuf, #[source] std::io::Error),
+
+    #[error("No perf.data files found in {0:?}")]
+    NoPerfData(PathBuf),
+
+    #[error(
+        "Found several perf.data files in {0:?}, but not all of them have a timestamp suffix, \
+         so it's unclear which one is the newest: {}. Please specify the file instead of the directory.",
+        .1.join(", ")
+    )]
+    Ambiguous(PathBuf, Vec<String>),
+}
+
+/// The contents of a directory with the output of one or more
+/// `perf record --timestamp-filename` runs, plus auxiliary files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PerfDir {
+    /// The perf.data files, oldest first. If the directory only has one
+    /// file without a timestamp suffix, this is just that file.
+    pub perf_data_files: Vec<PathBuf>,
+
+    /// The directory in which to look for binaries and debug files: a
+    /// "binaries" or "debug" subdirectory if present, otherwise the directory
+    /// itself.
+    pub extra_binary_artifact_dir: PathBuf,
+
+    /// The jit-<pid>.dump files in the directory, by pid.
+    pub jitdump_paths_by_pid: HashMap<i32, Vec<PathBuf>>,
+}
+
+impl PerfDir {
+    pub fn scan(dir: &Path) -> Result<Self, Error> {
+        let mut timestamped_files = Vec::new();
+        let mut other_perf_data_files = Vec::new();
+        let mut jitdump_paths_by_pid: HashMap<i32, Vec<PathBuf>> = HashMap::new();
+
+        let entries = std::fs::read_dir(dir).map_err(|e| Error::Io(dir.to_owned(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::Io(dir.to_owned(), e))?;
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            let Some(file_name) = path.file_name().and_then(|f| f.to_str()) else { continue };
+            if let Some(pid) = jitdump_pid(file_name) {
+                jitdump_paths_by_pid.entry(pid).or_default().push(path);
+            } else if let Some(suffix) = file_name.strip_prefix("perf.data.") {
+                if !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()) {
+                    timestamped_files.push((suffix.to_owned(), path));
+                } else {
+                    other_perf_data_files.push(path);
+                }
+            } else if file_name == "perf.data" {
+                other_perf_data_files.push(path);
+            }
+        }
+
+        let perf_data_files = match (timestamped_files.is_empty(), other_perf_data_files.len()) {
+            (true, 0) => return Err(Error::NoPerfData(dir.to_owned())),
+            (true, 1) => other_perf_data_files,
+            (false, 0) => {
+                // The timestamps are zero-padded (YYYYMMDDhhmmssSS), so
+                // sorting the strings sorts by time.
+                timestamped_files.sort();
+                timestamped_files
+                    .into_iter()
+                    .map(|(_, path)| path)
+                    .collect()
+            }
+            _ => {
+                let mut found: Vec<String> = timestamped_files
+                    .into_iter()
+                    .map(|(_, path)| path)
+                    .chain(other_perf_data_files)
+                    .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+                    .collect();
+                found.sort();
+                return Err(Error::Ambiguous(dir.to_owned(), found));
+            }
+        };
+
+        for paths in jitdump_paths_by_pid.values_mut() {
+            paths.sort();
+        }
+
+        let extra_binary_artifact_dir = ["binaries", "debug"]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|subdir| subdir.is_dir())
+            .unwrap_or_else(|| dir.to_owned());
+
+        Ok(PerfDir {
+            perf_data_files,
+            extra_binary_artifact_dir,
+            jitdump_paths_by_pid,
+        })
+    }
+
+    /// The newest perf.data file.
+    pub fn newest_perf_data_file(&self) -> &Path {
+        self.perf_data_files.last().unwrap()
+    }
+}
+
+/// Returns the pid for file names of the form "jit-<pid>.dump".
+fn jitdump_pid(file_name: &str) -> Option<i32> {
+    file_name
+        .strip_prefix("jit-")?
+        .strip_suffix(".dump")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use super::{Error, PerfDir};
+
+    fn create_files(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+    }
+
+    #[test]
+    fn selects_timestamped_files_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        create_files(
+            dir,
+            &[
+                "perf.data.2023061514302512",
+                "perf.data.2023061509000001",
+                "perf.data.2023061512000000",
+                "jit-1234.dump",
+                "jit-99.dump",
+                "jit-abc.dump",
+                "binaries/libxul.so",
+                "README",
+            ],
+        );
+        let perf_dir = PerfDir::scan(dir).unwrap();
+        assert_eq!(
+            perf_dir.perf_data_files,
+            vec![
+                dir.join("perf.data.2023061509000001"),
+                dir.join("perf.data.2023061512000000"),
+                dir.join("perf.data.2023061514302512"),
+            ]
+        );
+        assert_eq!(
+            perf_dir.newest_perf_data_file(),
+            dir.join("perf.data.2023061514302512")
+        );
+        assert_eq!(perf_dir.extra_binary_artifact_dir, dir.join("binaries"));
+        assert_eq!(
+            perf_dir.jitdump_paths_by_pid,
+            HashMap::from([
+                (1234, vec![dir.join("jit-1234.dump")]),
+                (99, vec![dir.join("jit-99.dump")]),
+            ])
+        );
+    }
+
+    #[test]
+    fn accepts_single_untimestamped_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        create_files(dir, &["perf.data", "debug/vmlinux"]);
+        let perf_dir = PerfDir::scan(dir).unwrap();
+        assert_eq!(perf_dir.perf_data_files, vec![dir.join("perf.data")]);
+        assert_eq!(perf_dir.extra_binary_artifact_dir, dir.join("debug"));
+    }
+
+    #[test]
+    fn rejects_ambiguous_and_empty_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        create_files(dir, &["perf.data.old", "jit-1.dump"]);
+        assert_eq!(
+            PerfDir::scan(dir).unwrap().perf_data_files,
+            vec![dir.join("perf.data.old")]
+        );
+        create_files(dir, &["perf.data.2023061509000001"]);
+        match PerfDir::scan(dir) {
+            Err(Error::Ambiguous(_, found)) => {
+                assert_eq!(found, vec!["perf.data.2023061509000001", "perf.data.old"])
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+
+        let empty_dir = tempfile::tempdir().unwrap();
+        create_files(empty_dir.path(), &["jit-1.dump"]);
+        assert!(matches!(
+            PerfDir::scan(empty_dir.path()),
+            Err(Error::NoPerfData(_))
+        ));
+    }
+}
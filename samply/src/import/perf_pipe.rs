@@ -0,0 +1,1015 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io::{ErrorKind, Read};
+
+use linux_perf_data::linux_perf_event_reader::{
+    AttrFlags, CpuMode, PerfEventAttr, RawData, RawEventRecord, RecordParseInfo, RecordType,
+    SampleFormat,
+};
+use linux_perf_data::{AttributeDescription, DsoInfo, DsoKey, Endianness};
+use tracing::{debug, warn};
+
+use super::arm_spe::{
+    has_arm_spe_event, ArmSpe, AuxtraceHeader, TimeConv, PERF_RECORD_AUXTRACE,
+    PERF_RECORD_TIME_CONV,
+};
+use super::perf::Error;
+
+/// The size of the header of a perf.data stream. The header of a perf.data
+/// file starts with the same magic but is larger, because it has the offsets
+/// of the sections which a stream doesn't have.
+const PIPE_HEADER_SIZE: u64 = 16;
+
+/// The record types which perf writes into a stream. Everything from
+/// `PERF_RECORD_USER_TYPE_START` on is written by perf, not by the kernel.
+const PERF_RECORD_USER_TYPE_START: u32 = 64;
+const PERF_RECORD_HEADER_ATTR: u32 = 64;
+const PERF_RECORD_HEADER_TRACING_DATA: u32 = 66;
+const PERF_RECORD_HEADER_BUILD_ID: u32 = 67;
+const PERF_RECORD_FINISHED_ROUND: u32 = 68;
+const PERF_RECORD_EVENT_UPDATE: u32 = 78;
+const PERF_RECORD_HEADER_FEATURE: u32 = 80;
+const PERF_RECORD_COMPRESSED: u32 = 81;
+
+/// The feature IDs in `PERF_RECORD_HEADER_FEATURE` records.
+const HEADER_HOSTNAME: u64 = 3;
+const HEADER_OSRELEASE: u64 = 4;
+const HEADER_VERSION: u64 = 5;
+const HEADER_ARCH: u64 = 6;
+const HEADER_NRCPUS: u64 = 7;
+const HEADER_EVENT_DESC: u64 = 12;
+const HEADER_NUMA_TOPOLOGY: u64 = 14;
+
+/// The `PERF_RECORD_EVENT_UPDATE` type which sets an event's name.
+const PERF_EVENT_UPDATE_NAME: u64 = 2;
+
+/// Set in the misc field of `PERF_RECORD_HEADER_BUILD_ID` records which
+/// have the size of the build ID.
+const PERF_RECORD_MISC_BUILD_ID_SIZE: u16 = 1 << 15;
+
+/// The size above which the oldest records are passed on even if later
+/// records could still have earlier timestamps, see [`RecordSorter`].
+const MAX_REORDER_BUFFER_BYTES: usize = 256 * 1024 * 1024;
+
+/// Reads the output of `perf record -o -` from a pipe, without seeking.
+///
+/// In a stream, perf writes the event attributes and the metadata from the
+/// feature sections as records before the first event record, so they're
+/// read by [`PerfPipeReader::parse`]. Metadata which perf doesn't write is
+/// missing. Build IDs usually arrive at the end of the stream, after the
+/// records which needed them; [`PerfPipeReader::take_build_ids`] returns the
+/// ones which arrived since the last call.
+///
+/// Like with a perf.data file, the records are sorted by timestamp up to the
+/// last-but-one `PERF_RECORD_FINISHED_ROUND`, so only about two rounds of
+/// records are kept in memory. Streams without rounds are sorted by the
+/// timestamps of each CPU instead, see [`RecordSorter`].
+pub struct PerfPipeReader<R: Read> {
+    reader: R,
+    endian: Endianness,
+    attributes: Vec<AttributeDescription>,
+    parse_infos: Vec<RecordParseInfo>,
+    attr_index_by_id: HashMap<u64, usize>,
+    hostname: Option<String>,
+    os_release: Option<String>,
+    perf_version: Option<String>,
+    arch: Option<String>,
+    build_ids: HashMap<DsoKey, DsoInfo>,
+    /// The payload of the `PERF_RECORD_HEADER_TRACING_DATA` record, with the
+    /// formats of the tracepoint events.
+    tracing_data: Option<Vec<u8>>,
+    /// The payload of the `HEADER_NUMA_TOPOLOGY` feature record.
+    numa_topology_data: Option<Vec<u8>>,
+    sorter: RecordSorter,
+    /// Decodes the AUX data if the recording has an `arm_spe` event.
+    arm_spe: Option<ArmSpe>,
+    time_conv: Option<TimeConv>,
+    /// The record which ended the header, if it was an event record or the
+    /// end of a round.
+    first_record: Option<(u32, u16, Vec<u8>)>,
+    reached_end: bool,
+    last_timestamp: u64,
+    compressed_record_count: u64,
+}
+
+/// An event record from the stream, with the index of its event attribute.
+pub struct PipeRecord {
+    pub attr_index: usize,
+    record_type: u32,
+    misc: u16,
+    data: Vec<u8>,
+    parse_info: RecordParseInfo,
+}
+
+impl PipeRecord {
+    pub fn raw(&self) -> RawEventRecord<'_> {
+        RawEventRecord {
+            record_type: RecordType(self.record_type),
+            misc: self.misc,
+            data: RawData::Single(&self.data),
+            parse_info: self.parse_info,
+        }
+    }
+}
+
+impl<R: Read> PerfPipeReader<R> {
+    /// Reads the stream header and the records up to the first event record.
+    pub fn parse(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        let endian = match &header[..8] {
+            b"PERFILE2" => Endianness::LittleEndian,
+            b"2ELIFREP" => Endianness::BigEndian,
+            _ => return Err(Error::NotAPerfStream),
+        };
+        if read_u64(&header[8..], endian) != PIPE_HEADER_SIZE {
+            return Err(Error::NotAPerfStream);
+        }
+
+        let mut pipe_reader = Self {
+            reader,
+            endian,
+            attributes: Vec::new(),
+            parse_infos: Vec::new(),
+            attr_index_by_id: HashMap::new(),
+            hostname: None,
+            os_release: None,
+            perf_version: None,
+            arch: None,
+            build_ids: HashMap::new(),
+            tracing_data: None,
+            numa_topology_data: None,
+            sorter: RecordSorter::new(MAX_REORDER_BUFFER_BYTES),
+            arm_spe: None,
+            time_conv: None,
+            first_record: None,
+            reached_end: false,
+            last_timestamp: 0,
+            compressed_record_count: 0,
+        };
+        while let Some((record_type, misc, data)) = pipe_reader.read_raw_record()? {
+            if record_type < PERF_RECORD_USER_TYPE_START
+                || record_type == PERF_RECORD_FINISHED_ROUND
+            {
+                pipe_reader.first_record = Some((record_type, misc, data));
+                break;
+            }
+            pipe_reader.handle_user_record(record_type, misc, &data)?;
+        }
+        if pipe_reader.attributes.is_empty() {
+            return Err(Error::MalformedStream(
+                "no event attributes before the first record",
+            ));
+        }
+        Ok(pipe_reader)
+    }
+
+    pub fn endian(&self) -> Endianness {
+        self.endian
+    }
+
+    pub fn event_attributes(&self) -> &[AttributeDescription] {
+        &self.attributes
+    }
+
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    pub fn os_release(&self) -> Option<&str> {
+        self.os_release.as_deref()
+    }
+
+    pub fn perf_version(&self) -> Option<&str> {
+        self.perf_version.as_deref()
+    }
+
+    pub fn arch(&self) -> Option<&str> {
+        self.arch.as_deref()
+    }
+
+    /// The tracing data, which perf writes before the first event record if
+    /// any tracepoint events were recorded.
+    pub fn tracing_data(&self) -> Option<&[u8]> {
+        self.tracing_data.as_deref()
+    }
+
+    /// The NUMA topology section, which perf writes before the first event
+    /// record.
+    pub fn numa_topology_data(&self) -> Option<&[u8]> {
+        self.numa_topology_data.as_deref()
+    }
+
+    /// The decoded ARM SPE samples, whose AUX data is read with the records.
+    pub fn arm_spe_mut(&mut self) -> Option<&mut ArmSpe> {
+        self.arm_spe.as_mut()
+    }
+
+    /// Returns the build IDs which arrived since the last call.
+    pub fn take_build_ids(&mut self) -> HashMap<DsoKey, DsoInfo> {
+        std::mem::take(&mut self.build_ids)
+    }
+
+    /// Returns the next event record in timestamp order, or None at the end
+    /// of the stream. A stream which ends in the middle of a record, e.g.
+    /// because perf was killed, ends at the last complete record.
+    pub fn next_record(&mut self) -> Result<Option<PipeRecord>, Error> {
+        loop {
+            if let Some(record) = self.sorter.pop() {
+                return Ok(Some(record));
+            }
+            if self.reached_end {
+                return Ok(None);
+            }
+            let raw_record = match self.first_record.take() {
+                Some(raw_record) => Some(raw_record),
+                None => self.read_raw_record()?,
+            };
+            match raw_record {
+                None => {
+                    self.reached_end = true;
+                    self.sorter.finish();
+                }
+                Some((PERF_RECORD_FINISHED_ROUND, _, _)) => self.sorter.finish_round(),
+                Some((record_type, misc, data)) if record_type >= PERF_RECORD_USER_TYPE_START => {
+                    self.handle_user_record(record_type, misc, &data)?;
+                }
+                Some((record_type, misc, data)) => {
+                    let attr_index = self.attr_index_for_record(record_type, &data);
+                    let record = PipeRecord {
+                        attr_index,
+                        record_type,
+                        misc,
+                        data,
+                        parse_info: self.parse_infos[attr_index],
+                    };
+                    let common = record.raw().common_data().ok();
+                    if let Some(timestamp) = common.as_ref().and_then(|c| c.timestamp) {
+                        self.last_timestamp = timestamp;
+                    }
+                    let cpu = common.and_then(|c| c.cpu);
+                    self.sorter.insert(self.last_timestamp, cpu, record);
+                }
+            }
+        }
+    }
+
+    /// How large the buffer for sorting the records got, and how many
+    /// records couldn't be sorted.
+    pub fn reorder_stats(&self) -> ReorderStats {
+        self.sorter.stats
+    }
+
+    pub fn report(&self) {
+        let late_record_count = self.sorter.stats.late_record_count;
+        if late_record_count != 0 {
+            warn!(
+                late_record_count,
+                "{late_record_count} records arrived after records with later timestamps had \
+                 been converted, e.g. because the stream has no FINISHED_ROUND records, and \
+                 were converted out of order."
+            );
+        }
+        let forced_record_count = self.sorter.stats.forced_record_count;
+        if forced_record_count != 0 {
+            debug!(
+                forced_record_count,
+                "{forced_record_count} records were passed on before the stream guaranteed \
+                 their order, because the reorder buffer was full."
+            );
+        }
+        if self.compressed_record_count != 0 {
+            warn!(
+                compressed_record_count = self.compressed_record_count,
+                "Skipped {} compressed records. Compression (perf record -z) isn't supported \
+                 when reading from a pipe.",
+                self.compressed_record_count
+            );
+        }
+    }
+
+    /// Returns the type, misc field and body of the next record, or None at
+    /// the end of the stream. Skips the payload which follows some records,
+    /// except for the tracing data, which is kept, and the AUX data of
+    /// `arm_spe` events, which is decoded as it's read.
+    fn read_raw_record(&mut self) -> Result<Option<(u32, u16, Vec<u8>)>, Error> {
+        let mut header = [0; 8];
+        if !read_exact_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let record_type = read_u32(&header[..4], self.endian);
+        let misc = read_u16(&header[4..6], self.endian);
+        let size = read_u16(&header[6..], self.endian) as usize;
+        let body_size = size
+            .checked_sub(header.len())
+            .ok_or(Error::MalformedStream("record smaller than its header"))?;
+        let mut data = vec![0; body_size];
+        if !read_exact_or_eof(&mut self.reader, &mut data)? {
+            return Ok(None);
+        }
+        let payload_size = match record_type {
+            PERF_RECORD_HEADER_TRACING_DATA if data.len() >= 4 => {
+                let tracing_data_size = read_u32(&data[..4], self.endian) as usize;
+                let mut tracing_data = vec![0; tracing_data_size];
+                if !read_exact_or_eof(&mut self.reader, &mut tracing_data)? {
+                    return Ok(None);
+                }
+                self.tracing_data = Some(tracing_data);
+                // The tracing data is padded to 8 bytes.
+                (8 - tracing_data_size as u64 % 8) % 8
+            }
+            PERF_RECORD_AUXTRACE if data.len() >= 8 => {
+                // All event attributes are known by the first AUX buffer.
+                if self.arm_spe.is_none() && has_arm_spe_event(&self.attributes) {
+                    self.arm_spe = Some(ArmSpe::default());
+                }
+                match (&mut self.arm_spe, AuxtraceHeader::parse(&data, self.endian)) {
+                    (Some(arm_spe), Some(header)) => {
+                        arm_spe.decode_buffer(
+                            &header,
+                            self.last_timestamp,
+                            self.time_conv.as_ref(),
+                            &mut self.reader,
+                        )?;
+                        0
+                    }
+                    _ => read_u64(&data[..8], self.endian),
+                }
+            }
+            _ => 0,
+        };
+        if payload_size != 0 {
+            let mut payload = (&mut self.reader).take(payload_size);
+            std::io::copy(&mut payload, &mut std::io::sink())?;
+        }
+        Ok(Some((record_type, misc, data)))
+    }
+
+    fn handle_user_record(
+        &mut self,
+        record_type: u32,
+        misc: u16,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        match record_type {
+            PERF_RECORD_HEADER_ATTR => self.add_attribute(data)?,
+            PERF_RECORD_EVENT_UPDATE if data.len() >= 16 => {
+                let update_type = read_u64(&data[..8], self.endian);
+                let id = read_u64(&data[8..16], self.endian);
+                if update_type == PERF_EVENT_UPDATE_NAME {
+                    self.set_event_name(Some(id), None, read_c_string(&data[16..]));
+                }
+            }
+            PERF_RECORD_HEADER_FEATURE if data.len() >= 8 => {
+                let feature = read_u64(&data[..8], self.endian);
+                self.handle_feature(feature, &data[8..]);
+            }
+            PERF_RECORD_HEADER_BUILD_ID => self.add_build_id(misc, data),
+            PERF_RECORD_COMPRESSED => self.compressed_record_count += 1,
+            PERF_RECORD_TIME_CONV => self.time_conv = TimeConv::parse(data, self.endian),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// A `PERF_RECORD_HEADER_ATTR` record has the attribute followed by the
+    /// IDs of its events.
+    fn add_attribute(&mut self, data: &[u8]) -> Result<(), Error> {
+        let attr = match self.endian {
+            Endianness::LittleEndian => {
+                PerfEventAttr::parse::<_, byteorder::LittleEndian>(data, None)?
+            }
+            Endianness::BigEndian => PerfEventAttr::parse::<_, byteorder::BigEndian>(data, None)?,
+        };
+        let attr_size = data
+            .get(4..8)
+            .map_or(0, |size| read_u32(size, self.endian) as usize);
+        let event_ids: Vec<u64> = data
+            .get(attr_size..)
+            .unwrap_or_default()
+            .chunks_exact(8)
+            .map(|id| read_u64(id, self.endian))
+            .collect();
+        let attr_index = self.attributes.len();
+        for id in &event_ids {
+            self.attr_index_by_id.insert(*id, attr_index);
+        }
+        self.parse_infos
+            .push(RecordParseInfo::new(&attr, self.endian));
+        self.attributes.push(AttributeDescription {
+            attr,
+            name: None,
+            event_ids,
+        });
+        Ok(())
+    }
+
+    /// Sets the name of the event with this ID, or with this attribute index
+    /// if the ID is unknown.
+    fn set_event_name(&mut self, id: Option<u64>, attr_index: Option<usize>, name: &[u8]) {
+        let attr_index = id
+            .and_then(|id| self.attr_index_by_id.get(&id).copied())
+            .or(attr_index);
+        if let Some(attribute) = attr_index.and_then(|i| self.attributes.get_mut(i)) {
+            attribute.name = Some(String::from_utf8_lossy(name).into_owned());
+        }
+    }
+
+    fn handle_feature(&mut self, feature: u64, data: &[u8]) {
+        let string = || {
+            let len = read_u32(data.get(..4)?, self.endian) as usize;
+            let bytes = read_c_string(data.get(4..4 + len)?);
+            Some(String::from_utf8_lossy(bytes).into_owned())
+        };
+        match feature {
+            HEADER_HOSTNAME => self.hostname = string(),
+            HEADER_OSRELEASE => self.os_release = string(),
+            HEADER_VERSION => self.perf_version = string(),
+            HEADER_ARCH => self.arch = string(),
+            HEADER_NRCPUS => {
+                // The number of available CPUs, followed by the number of
+                // online CPUs, which are the ones with records.
+                if let Some(online) = data.get(4..8) {
+                    let online = read_u32(online, self.endian) as usize;
+                    self.sorter.set_cpu_count(online);
+                }
+            }
+            HEADER_EVENT_DESC => self.handle_event_desc(data),
+            HEADER_NUMA_TOPOLOGY => self.numa_topology_data = Some(data.to_vec()),
+            _ => {}
+        }
+    }
+
+    /// The event descriptions have the event names. Each description is the
+    /// attribute, the number of IDs, the name and the IDs.
+    fn handle_event_desc(&mut self, data: &[u8]) {
+        let mut reader = ByteReader::new(data, self.endian);
+        let (Some(count), Some(attr_size)) = (reader.u32(), reader.u32()) else {
+            return;
+        };
+        for attr_index in 0..count as usize {
+            let Some(_attr) = reader.bytes(attr_size as usize) else {
+                return;
+            };
+            let (Some(id_count), Some(name)) = (reader.u32(), reader.string()) else {
+                return;
+            };
+            let Some(ids) = reader.bytes(id_count as usize * 8) else {
+                return;
+            };
+            let first_id = ids.get(..8).map(|id| read_u64(id, self.endian));
+            self.set_event_name(first_id, Some(attr_index), name);
+        }
+    }
+
+    /// A `PERF_RECORD_HEADER_BUILD_ID` record has the pid, the build ID padded
+    /// to 24 bytes and the path of the binary.
+    fn add_build_id(&mut self, misc: u16, data: &[u8]) {
+        if data.len() < 28 {
+            return;
+        }
+        let build_id_bytes = &data[4..24];
+        let build_id = if misc & PERF_RECORD_MISC_BUILD_ID_SIZE != 0 {
+            let len = usize::from(data[24]).min(build_id_bytes.len());
+            build_id_bytes[..len].to_vec()
+        } else {
+            // Old versions of perf don't write the size. Guess it by
+            // stripping trailing zeros in 4-byte chunks, like for files.
+            let mut len = build_id_bytes.len();
+            while len >= 4 && build_id_bytes[len - 4..len] == [0; 4] {
+                len -= 4;
+            }
+            build_id_bytes[..len].to_vec()
+        };
+        let path = read_c_string(&data[28..]);
+        if let Some(dso_key) = DsoKey::detect(path, CpuMode::from_misc(misc)) {
+            let path = path.to_vec();
+            self.build_ids.insert(dso_key, DsoInfo { path, build_id });
+        }
+    }
+
+    /// Finds the attribute of a record by its event ID, if the records have
+    /// one. The ID is at the start of a sample with `PERF_SAMPLE_IDENTIFIER`,
+    /// and at the end of other records with `sample_id_all`.
+    fn attr_index_for_record(&self, record_type: u32, data: &[u8]) -> usize {
+        if self.attributes.len() <= 1 {
+            return 0;
+        }
+        // The attributes can have different sample formats, so the ID can be
+        // at a different position for each of them. An ID is only trusted if
+        // the attribute it belongs to puts its ID at the position where it
+        // was found. perf uses IDENTIFIER in that case, which puts the ID at
+        // the same position for all attributes.
+        let mut tried_offsets = Vec::new();
+        for attr_index in 0..self.attributes.len() {
+            let Some(offset) = self.id_offset(attr_index, record_type, data.len()) else {
+                continue;
+            };
+            if tried_offsets.contains(&offset) {
+                continue;
+            }
+            tried_offsets.push(offset);
+            let Some(id) = data.get(offset..offset + 8) else {
+                continue;
+            };
+            if let Some(&owner) = self.attr_index_by_id.get(&read_u64(id, self.endian)) {
+                if self.id_offset(owner, record_type, data.len()) == Some(offset) {
+                    return owner;
+                }
+            }
+        }
+        0
+    }
+
+    /// The position of the ID in a record of the given attribute, based on
+    /// that attribute's own sample format.
+    fn id_offset(&self, attr_index: usize, record_type: u32, data_len: usize) -> Option<usize> {
+        let sample_format = self.parse_infos[attr_index].sample_format;
+        let count_fields = |fields: &[SampleFormat]| {
+            fields
+                .iter()
+                .filter(|field| sample_format.contains(**field))
+                .count()
+        };
+        if record_type == RecordType::SAMPLE.0 {
+            if sample_format.contains(SampleFormat::IDENTIFIER) {
+                Some(0)
+            } else if sample_format.contains(SampleFormat::ID) {
+                let fields_before_id = [
+                    SampleFormat::IP,
+                    SampleFormat::TID,
+                    SampleFormat::TIME,
+                    SampleFormat::ADDR,
+                ];
+                Some(8 * count_fields(&fields_before_id))
+            } else {
+                None
+            }
+        } else if self.attributes[attr_index]
+            .attr
+            .flags
+            .contains(AttrFlags::SAMPLE_ID_ALL)
+        {
+            if sample_format.contains(SampleFormat::IDENTIFIER) {
+                data_len.checked_sub(8)
+            } else if sample_format.contains(SampleFormat::ID) {
+                let fields_after_id = [SampleFormat::STREAM_ID, SampleFormat::CPU];
+                data_len.checked_sub(8 * (1 + count_fields(&fields_after_id)))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// Sorts records by timestamp in the same way as perf does for streams.
+///
+/// Every CPU's records are in order, and at the end of each round, every CPU
+/// has written out its records up to the largest timestamp of the previous
+/// round. So the records up to that timestamp can be emitted.
+///
+/// Some tools don't write rounds. If the records have a CPU and the number
+/// of CPUs is known, the records up to the minimum of the latest timestamps
+/// of the CPUs are emitted as well, because no CPU has earlier records left.
+/// If neither works, e.g. because a CPU stopped writing records, the oldest
+/// records are emitted once the buffered records exceed a size limit.
+/// Records which arrive after later records were emitted are emitted right
+/// away and counted as late; the converter handles them like other records
+/// which arrive late, e.g. samples of threads which have exited since.
+struct RecordSorter {
+    pending: BinaryHeap<Reverse<PendingRecord>>,
+    pending_bytes: usize,
+    max_pending_bytes: usize,
+    /// The arrival order, which keeps records with the same timestamp in
+    /// order.
+    next_sequence: u64,
+    ready: VecDeque<PipeRecord>,
+    max_timestamp: u64,
+    previous_round_max_timestamp: Option<u64>,
+    /// The number of online CPUs, if the stream has it.
+    cpu_count: Option<usize>,
+    /// The latest timestamp of each CPU. None once a record without a CPU
+    /// arrived.
+    latest_timestamp_by_cpu: Option<HashMap<u32, u64>>,
+    /// The timestamp of the latest record which was emitted.
+    emitted_timestamp: u64,
+    stats: ReorderStats,
+}
+
+/// The memory use of the reorder buffer of a perf.data stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    pub peak_record_count: usize,
+    pub peak_bytes: usize,
+    /// Records whose timestamp was earlier than a record which had already
+    /// been emitted.
+    pub late_record_count: u64,
+    /// Records which were emitted because the buffer was full.
+    pub forced_record_count: u64,
+}
+
+struct PendingRecord {
+    timestamp: u64,
+    sequence: u64,
+    record: PipeRecord,
+}
+
+impl PendingRecord {
+    fn key(&self) -> (u64, u64) {
+        (self.timestamp, self.sequence)
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.record.data.len()
+    }
+}
+
+impl PartialEq for PendingRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for PendingRecord {}
+
+impl PartialOrd for PendingRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PendingRecord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl RecordSorter {
+    fn new(max_pending_bytes: usize) -> Self {
+        Self {
+            pending: BinaryHeap::new(),
+            pending_bytes: 0,
+            max_pending_bytes,
+            next_sequence: 0,
+            ready: VecDeque::new(),
+            max_timestamp: 0,
+            previous_round_max_timestamp: None,
+            cpu_count: None,
+            latest_timestamp_by_cpu: Some(HashMap::new()),
+            emitted_timestamp: 0,
+            stats: ReorderStats::default(),
+        }
+    }
+
+    fn set_cpu_count(&mut self, cpu_count: usize) {
+        self.cpu_count = Some(cpu_count);
+    }
+
+    fn insert(&mut self, timestamp: u64, cpu: Option<u32>, record: PipeRecord) {
+        if timestamp < self.emitted_timestamp {
+            // All buffered records are later, so this is still the right
+            // place relative to them.
+            self.stats.late_record_count += 1;
+            self.ready.push_back(record);
+            return;
+        }
+        self.max_timestamp = self.max_timestamp.max(timestamp);
+        let pending = PendingRecord {
+            timestamp,
+            sequence: self.next_sequence,
+            record,
+        };
+        self.next_sequence += 1;
+        self.pending_bytes += pending.size();
+        self.pending.push(Reverse(pending));
+        self.stats.peak_record_count = self.stats.peak_record_count.max(self.pending.len());
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.pending_bytes);
+
+        match (&mut self.latest_timestamp_by_cpu, cpu) {
+            (Some(latest_timestamp_by_cpu), Some(cpu)) => {
+                let latest = latest_timestamp_by_cpu.entry(cpu).or_insert(timestamp);
+                *latest = (*latest).max(timestamp);
+            }
+            (Some(_), None) => self.latest_timestamp_by_cpu = None,
+            (None, _) => {}
+        }
+        if let Some(watermark) = self.watermark() {
+            self.flush(watermark);
+        }
+        while self.pending_bytes > self.max_pending_bytes {
+            self.stats.forced_record_count += 1;
+            self.emit_oldest();
+        }
+    }
+
+    /// The timestamp up to which no CPU has records left, if every CPU has
+    /// written records.
+    fn watermark(&self) -> Option<u64> {
+        let latest_timestamp_by_cpu = self.latest_timestamp_by_cpu.as_ref()?;
+        if latest_timestamp_by_cpu.len() < self.cpu_count? {
+            return None;
+        }
+        latest_timestamp_by_cpu.values().min().copied()
+    }
+
+    fn finish_round(&mut self) {
+        let limit = self
+            .previous_round_max_timestamp
+            .replace(self.max_timestamp);
+        if let Some(limit) = limit {
+            self.flush(limit);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.flush(u64::MAX);
+    }
+
+    fn pop(&mut self) -> Option<PipeRecord> {
+        self.ready.pop_front()
+    }
+
+    fn flush(&mut self, limit: u64) {
+        while matches!(self.pending.peek(), Some(Reverse(pending)) if pending.timestamp <= limit) {
+            self.emit_oldest();
+        }
+    }
+
+    fn emit_oldest(&mut self) {
+        let Some(Reverse(pending)) = self.pending.pop() else {
+            return;
+        };
+        self.pending_bytes -= pending.size();
+        self.emitted_timestamp = self.emitted_timestamp.max(pending.timestamp);
+        self.ready.push_back(pending.record);
+    }
+}
+
+/// Returns false if the stream ends before `buf` is filled.
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, Error> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns the bytes up to the first nul byte.
+fn read_c_string(data: &[u8]) -> &[u8] {
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    &data[..len]
+}
+
+fn read_u16(data: &[u8], endian: Endianness) -> u16 {
+    let bytes = data[..2].try_into().unwrap();
+    match endian {
+        Endianness::LittleEndian => u16::from_le_bytes(bytes),
+        Endianness::BigEndian => u16::from_be_bytes(bytes),
+    }
+}
+
+fn read_u32(data: &[u8], endian: Endianness) -> u32 {
+    let bytes = data[..4].try_into().unwrap();
+    match endian {
+        Endianness::LittleEndian => u32::from_le_bytes(bytes),
+        Endianness::BigEndian => u32::from_be_bytes(bytes),
+    }
+}
+
+fn read_u64(data: &[u8], endian: Endianness) -> u64 {
+    let bytes = data[..8].try_into().unwrap();
+    match endian {
+        Endianness::LittleEndian => u64::from_le_bytes(bytes),
+        Endianness::BigEndian => u64::from_be_bytes(bytes),
+    }
+}
+
+/// Reads the fields of a feature record in order.
+struct ByteReader<'a> {
+    data: &'a [u8],
+    endian: Endianness,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8], endian: Endianness) -> Self {
+        Self { data, endian }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(read_u32(self.bytes(4)?, self.endian))
+    }
+
+    /// A string is its padded length followed by the nul-padded bytes.
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        Some(read_c_string(self.bytes(len)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(timestamp: u64) -> PipeRecord {
+        PipeRecord {
+            attr_index: 0,
+            record_type: RecordType::SAMPLE.0,
+            misc: 0,
+            data: timestamp.to_le_bytes().to_vec(),
+            parse_info: RecordParseInfo::new(
+                &PerfEventAttr::parse::<_, byteorder::LittleEndian>(&[0u8; 64][..], Some(64))
+                    .unwrap(),
+                Endianness::LittleEndian,
+            ),
+        }
+    }
+
+    fn pop_all(sorter: &mut RecordSorter) -> Vec<u64> {
+        std::iter::from_fn(|| sorter.pop())
+            .map(|record| read_u64(&record.data, Endianness::LittleEndian))
+            .collect()
+    }
+
+    #[test]
+    fn sorts_records_by_round() {
+        let mut sorter = RecordSorter::new(MAX_REORDER_BUFFER_BYTES);
+        // Two CPUs, each in order.
+        for timestamp in [10, 30, 20, 40] {
+            sorter.insert(timestamp, None, record(timestamp));
+        }
+        sorter.finish_round();
+        assert!(pop_all(&mut sorter).is_empty());
+        for timestamp in [50, 35, 60] {
+            sorter.insert(timestamp, None, record(timestamp));
+        }
+        sorter.finish_round();
+        assert_eq!(pop_all(&mut sorter), vec![10, 20, 30, 35, 40]);
+        sorter.insert(45, None, record(45));
+        sorter.finish();
+        assert_eq!(pop_all(&mut sorter), vec![45, 50, 60]);
+        assert_eq!(sorter.stats.late_record_count, 0);
+    }
+
+    #[test]
+    fn sorts_records_by_cpu_watermark() {
+        let mut sorter = RecordSorter::new(MAX_REORDER_BUFFER_BYTES);
+        sorter.set_cpu_count(2);
+        for (timestamp, cpu) in [(10, 0), (30, 0), (20, 1)] {
+            sorter.insert(timestamp, Some(cpu), record(timestamp));
+        }
+        // CPU 1 is at 20, so nothing earlier can follow.
+        assert_eq!(pop_all(&mut sorter), vec![10, 20]);
+        sorter.insert(40, Some(1), record(40));
+        assert_eq!(pop_all(&mut sorter), vec![30]);
+        // A record which perf would have had to reorder across a round.
+        sorter.insert(25, Some(0), record(25));
+        assert_eq!(pop_all(&mut sorter), vec![25]);
+        assert_eq!(sorter.stats.late_record_count, 1);
+        sorter.finish();
+        assert_eq!(pop_all(&mut sorter), vec![40]);
+    }
+
+    #[test]
+    fn sorts_interleaved_cpus_without_rounds_in_bounded_memory() {
+        let mut sorter = RecordSorter::new(MAX_REORDER_BUFFER_BYTES);
+        sorter.set_cpu_count(4);
+        // Each CPU writes chunks of 8 records, as perf does with its per-CPU
+        // buffers, with interleaved timestamps and no rounds.
+        let mut output = Vec::new();
+        for chunk in 0..1000u64 {
+            for cpu in 0..4u64 {
+                for i in 0..8u64 {
+                    let timestamp = (chunk * 8 + i) * 4 + cpu;
+                    sorter.insert(timestamp, Some(cpu as u32), record(timestamp));
+                }
+            }
+            output.extend(pop_all(&mut sorter));
+        }
+        sorter.finish();
+        output.extend(pop_all(&mut sorter));
+
+        assert_eq!(output.len(), 32000);
+        assert!(output.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(sorter.stats.late_record_count, 0);
+        assert!(sorter.stats.peak_record_count <= 40);
+    }
+
+    #[test]
+    fn limits_reorder_buffer_size() {
+        let record_size = PendingRecord {
+            timestamp: 0,
+            sequence: 0,
+            record: record(0),
+        }
+        .size();
+        let mut sorter = RecordSorter::new(record_size * 10);
+        // A CPU count which the records never reach.
+        sorter.set_cpu_count(2);
+        for timestamp in 0..100 {
+            sorter.insert(timestamp, Some(0), record(timestamp));
+        }
+        assert_eq!(sorter.stats.peak_record_count, 11);
+        assert_eq!(sorter.stats.forced_record_count, 90);
+        sorter.finish();
+        assert_eq!(pop_all(&mut sorter), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reads_feature_strings_and_event_names() {
+        let mut stream = b"PERFILE2".to_vec();
+        stream.extend_from_slice(&16u64.to_le_bytes());
+        fn push_record(stream: &mut Vec<u8>, record_type: u32, body: &[u8]) {
+            stream.extend_from_slice(&record_type.to_le_bytes());
+            stream.extend_from_slice(&0u16.to_le_bytes());
+            stream.extend_from_slice(&(8 + body.len() as u16).to_le_bytes());
+            stream.extend_from_slice(body);
+        }
+        let mut attr = [0u8; 64];
+        attr[4..8].copy_from_slice(&64u32.to_le_bytes());
+        let mut attr_record = attr.to_vec();
+        attr_record.extend_from_slice(&7u64.to_le_bytes());
+        push_record(&mut stream, PERF_RECORD_HEADER_ATTR, &attr_record);
+        let mut hostname = HEADER_HOSTNAME.to_le_bytes().to_vec();
+        hostname.extend_from_slice(&8u32.to_le_bytes());
+        hostname.extend_from_slice(b"box\0\0\0\0\0");
+        push_record(&mut stream, PERF_RECORD_HEADER_FEATURE, &hostname);
+        push_record(
+            &mut stream,
+            PERF_RECORD_HEADER_TRACING_DATA,
+            &5u32.to_le_bytes(),
+        );
+        stream.extend_from_slice(b"\x17\x08\x44tr\0\0\0");
+        let mut name = PERF_EVENT_UPDATE_NAME.to_le_bytes().to_vec();
+        name.extend_from_slice(&7u64.to_le_bytes());
+        name.extend_from_slice(b"cycles\0\0");
+        push_record(&mut stream, PERF_RECORD_EVENT_UPDATE, &name);
+        push_record(&mut stream, PERF_RECORD_FINISHED_ROUND, &[]);
+
+        let mut reader = PerfPipeReader::parse(&stream[..]).unwrap();
+        assert_eq!(reader.hostname(), Some("box"));
+        assert_eq!(reader.perf_version(), None);
+        assert_eq!(reader.tracing_data(), Some(&b"\x17\x08\x44tr"[..]));
+        assert_eq!(reader.event_attributes().len(), 1);
+        assert_eq!(reader.event_attributes()[0].name.as_deref(), Some("cycles"));
+        assert_eq!(reader.event_attributes()[0].event_ids, vec![7]);
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn finds_attributes_by_the_id_position_of_their_own_format() {
+        let mut stream = b"PERFILE2".to_vec();
+        stream.extend_from_slice(&16u64.to_le_bytes());
+        let mut push_record = |record_type: u32, body: &[u8]| {
+            stream.extend_from_slice(&record_type.to_le_bytes());
+            stream.extend_from_slice(&0u16.to_le_bytes());
+            stream.extend_from_slice(&(8 + body.len() as u16).to_le_bytes());
+            stream.extend_from_slice(body);
+        };
+        // IP, TID, TIME and ID, which puts the ID at offset 24, and TID and
+        // ID, which puts it at offset 8.
+        for (sample_type, id) in [(0b100_0111u64, 7u64), (0b100_0010, 8)] {
+            let mut attr = [0u8; 64];
+            attr[4..8].copy_from_slice(&64u32.to_le_bytes());
+            attr[24..32].copy_from_slice(&sample_type.to_le_bytes());
+            let mut attr_record = attr.to_vec();
+            attr_record.extend_from_slice(&id.to_le_bytes());
+            push_record(PERF_RECORD_HEADER_ATTR, &attr_record);
+        }
+        let sample = |fields: &[u64]| -> Vec<u8> {
+            fields
+                .iter()
+                .flat_map(|field| field.to_le_bytes())
+                .collect()
+        };
+        push_record(RecordType::SAMPLE.0, &sample(&[0x1000, 1, 10, 7]));
+        push_record(RecordType::SAMPLE.0, &sample(&[1, 8]));
+        push_record(RecordType::SAMPLE.0, &sample(&[0x1000, 1, 20, 7]));
+
+        let mut reader = PerfPipeReader::parse(&stream[..]).unwrap();
+        let attr_indexes: Vec<usize> = std::iter::from_fn(|| reader.next_record().unwrap())
+            .map(|record| record.attr_index)
+            .collect();
+        assert_eq!(attr_indexes, vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn rejects_perf_data_files() {
+        let mut file_header = b"PERFILE2".to_vec();
+        file_header.extend_from_slice(&104u64.to_le_bytes());
+        assert!(matches!(
+            PerfPipeReader::parse(&file_header[..]),
+            Err(Error::NotAPerfStream)
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::thread;
+
+use crossbeam_channel::{Receiver, Sender};
+use nix::sys::stat::Mode;
+use tracing::warn;
+
+use crate::linux_shared::ControlCommand;
+
+/// The environment variable through which the launched command learns the
+/// path of the control pipe.
+pub const CONTROL_PIPE_ENV_VAR: &str = "SAMPLY_CONTROL_PIPE";
+
+/// A named pipe through which the profiled application can pause and resume
+/// sampling and add markers, by writing lines like "pause", "resume" or
+/// "marker <name>".
+pub struct ControlPipe {
+    dir: tempfile::TempDir,
+    receiver: Receiver<(ControlCommand, u64)>,
+}
+
+impl ControlPipe {
+    /// Creates the pipe in a new temporary directory and starts a thread
+    /// which reads commands from it.
+    pub fn create() -> std::io::Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("samply-control")
+            .tempdir()?;
+        let path = dir.path().join("control");
+        nix::unistd::mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+
+        // Open for reading and writing so that the open doesn't block until
+        // the application opens the pipe, and so that we don't see EOF when
+        // the application closes it.
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(&path)?;
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        thread::spawn(move || read_commands(file, sender));
+        Ok(Self { dir, receiver })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.path().join("control")
+    }
+
+    /// Returns the commands which have been received since the last call,
+    /// with their CLOCK_MONOTONIC timestamps.
+    pub fn take_commands(&self) -> Vec<(ControlCommand, u64)> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+fn read_commands(file: File, sender: Sender<(ControlCommand, u64)>) {
+    for line in BufReader::new(file).lines() {
+        let Ok(line) = line else { return };
+        let timestamp = monotonic_timestamp();
+        match ControlCommand::parse(&line) {
+            Some(command) => {
+                if sender.send((command, timestamp)).is_err() {
+                    // Recording has finished.
+                    return;
+                }
+            }
+            None if line.trim().is_empty() => {}
+            None => warn!(
+                command = line.as_str(),
+                "Ignoring unknown command on the control pipe: {line:?}"
+            ),
+        }
+    }
+}
+
+/// The current time on the clock which the perf events use.
+pub fn monotonic_timestamp() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
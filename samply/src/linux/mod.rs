@@ -1,6 +1,8 @@
+mod control_pipe;
 mod perf_event;
 mod perf_group;
-mod proc_maps;
+mod preflight;
 mod process;
 pub mod profiler;
 mod sys;
+mod top;
@@ -363,13 +363,8 @@ impl PerfBuilder {
             return Err(err);
         }
 
-        const STACK_COUNT_PER_BUFFER: u32 = 32;
-        let required_space = max(stack_size, 4096) * STACK_COUNT_PER_BUFFER;
         let page_size = 4096;
-        let n = (1..26)
-            .find(|n| (1_u32 << n) * 4096_u32 >= required_space)
-            .expect("cannot find appropriate page count for given stack size");
-        let page_count: u32 = max(1 << n, 16);
+        let page_count = Perf::ring_buffer_page_count(stack_size);
         // debug!(
         //     "Allocating {} + 1 pages for the ring buffer for PID {} on CPU {}",
         //     page_count, pid, cpu
@@ -429,6 +424,18 @@ impl Perf {
         data.trim().parse::<u64>().ok()
     }
 
+    /// The number of data pages of the ring buffer of each event, which has
+    /// room for 32 samples with `stack_size` bytes of user stack. The kernel
+    /// maps one more page for the header.
+    pub fn ring_buffer_page_count(stack_size: u32) -> u32 {
+        const STACK_COUNT_PER_BUFFER: u32 = 32;
+        let required_space = max(stack_size, 4096) * STACK_COUNT_PER_BUFFER;
+        let n = (1..26)
+            .find(|n| (1_u32 << n) * 4096_u32 >= required_space)
+            .expect("cannot find appropriate page count for given stack size");
+        max(1 << n, 16)
+    }
+
     pub fn build() -> PerfBuilder {
         PerfBuilder {
             pid: 0,
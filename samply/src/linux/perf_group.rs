@@ -114,6 +114,9 @@ fn get_threads(pid: u32) -> Result<Vec<u32>, io::Error> {
 pub enum AttachMode {
     AttachWithEnableOnExec,
     StopAttachEnableResume,
+    /// Attach without stopping the process, and enable right away. Used for
+    /// profiling samply itself, which can't be stopped.
+    AttachEnable,
 }
 
 impl PerfGroup {
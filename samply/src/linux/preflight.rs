@@ -0,0 +1,457 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use tracing::warn;
+
+use super::perf_event::Perf;
+use super::profiler::read_string_lossy;
+use crate::cli_error::{self, CliError};
+
+const PERF_EVENT_PARANOID: &str = "perf_event_paranoid";
+const KPTR_RESTRICT: &str = "kptr_restrict";
+const PERF_EVENT_MLOCK_KB: &str = "perf_event_mlock_kb";
+
+const CAP_IPC_LOCK: u32 = 14;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_SYSLOG: u32 = 34;
+const CAP_PERFMON: u32 = 38;
+
+/// Reads and writes the files in /proc/sys/kernel. Tests use fake values.
+pub trait KernelSysctls {
+    fn read(&self, name: &str) -> Option<String>;
+    fn write(&mut self, name: &str, value: &str) -> io::Result<()>;
+}
+
+pub struct ProcSysctls;
+
+impl KernelSysctls for ProcSysctls {
+    fn read(&self, name: &str) -> Option<String> {
+        read_string_lossy(format!("/proc/sys/kernel/{name}")).ok()
+    }
+
+    fn write(&mut self, name: &str, value: &str) -> io::Result<()> {
+        std::fs::write(format!("/proc/sys/kernel/{name}"), value)
+    }
+}
+
+/// The sysctls which decide what an unprivileged user can record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PerfSettings {
+    pub paranoid: Option<i32>,
+    pub kptr_restrict: Option<u32>,
+    pub mlock_kb: Option<u64>,
+}
+
+impl PerfSettings {
+    pub fn read(sysctls: &dyn KernelSysctls) -> Self {
+        Self {
+            paranoid: read_number(sysctls, PERF_EVENT_PARANOID),
+            kptr_restrict: read_number(sysctls, KPTR_RESTRICT),
+            mlock_kb: read_number(sysctls, PERF_EVENT_MLOCK_KB),
+        }
+    }
+}
+
+fn read_number<T: std::str::FromStr>(sysctls: &dyn KernelSysctls, name: &str) -> Option<T> {
+    sysctls.read(name)?.trim().parse().ok()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Privileges {
+    pub is_root: bool,
+    /// The CapEff bit mask from /proc/self/status.
+    pub effective_caps: u64,
+}
+
+impl Privileges {
+    pub fn current() -> Self {
+        let effective_caps = read_string_lossy("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let line = status.lines().find(|l| l.starts_with("CapEff:"))?;
+                u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+            })
+            .unwrap_or(0);
+        Self {
+            is_root: nix::unistd::geteuid().is_root(),
+            effective_caps,
+        }
+    }
+
+    fn has_cap(&self, cap: u32) -> bool {
+        self.effective_caps & (1 << cap) != 0
+    }
+}
+
+/// What the recording asks of the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordingNeeds {
+    pub kernel_stacks: bool,
+    pub kernel_symbols: bool,
+    /// The size of the ring buffers which are mapped for each CPU.
+    pub ring_buffer_kb_per_cpu: u64,
+}
+
+impl RecordingNeeds {
+    /// `events_per_cpu` is the number of perf events which are opened on
+    /// each CPU, i.e. one per thread of the recorded process.
+    pub fn new(stack_size: u32, events_per_cpu: u64) -> Self {
+        // One more page for the header.
+        let pages_per_event = u64::from(Perf::ring_buffer_page_count(stack_size)) + 1;
+        Self {
+            kernel_stacks: true,
+            kernel_symbols: true,
+            ring_buffer_kb_per_cpu: pages_per_event * 4 * events_per_cpu,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// Kernel samples need perf_event_paranoid <= 1, or CAP_PERFMON.
+    ParanoidTooHigh { level: i32 },
+    /// /proc/kallsyms shows zero addresses with kptr_restrict=1 to users
+    /// without CAP_SYSLOG, and to everyone with kptr_restrict=2.
+    KernelSymbolsHidden { kptr_restrict: u32 },
+    /// Ring buffers beyond perf_event_mlock_kb per CPU count against the
+    /// RLIMIT_MEMLOCK of the user, unless it has CAP_IPC_LOCK.
+    MlockLimitTooLow { limit_kb: u64, needed_kb: u64 },
+}
+
+impl Problem {
+    /// Whether recording fails with this problem, rather than producing a
+    /// less useful profile.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Problem::ParanoidTooHigh { .. })
+    }
+
+    /// The sysctl and the value which fixes the problem.
+    fn fix(&self) -> (&'static str, String) {
+        match *self {
+            Problem::ParanoidTooHigh { .. } => (PERF_EVENT_PARANOID, "1".to_string()),
+            Problem::KernelSymbolsHidden { .. } => (KPTR_RESTRICT, "0".to_string()),
+            Problem::MlockLimitTooLow { needed_kb, .. } => {
+                (PERF_EVENT_MLOCK_KB, needed_kb.to_string())
+            }
+        }
+    }
+
+    pub fn description(&self) -> String {
+        match *self {
+            Problem::ParanoidTooHigh { level } => format!(
+                "'/proc/sys/kernel/perf_event_paranoid' is currently set to {level}. Recording \
+                 kernel stacks without CAP_PERFMON needs it to be 1 or lower."
+            ),
+            Problem::KernelSymbolsHidden { kptr_restrict } => format!(
+                "'/proc/sys/kernel/kptr_restrict' is currently set to {kptr_restrict}, which \
+                 hides the kernel symbol addresses. Kernel frames won't be symbolicated unless \
+                 it's set to 0, or samply runs as root."
+            ),
+            Problem::MlockLimitTooLow {
+                limit_kb,
+                needed_kb,
+            } => format!(
+                "'/proc/sys/kernel/perf_event_mlock_kb' is currently set to {limit_kb}, but the \
+                 perf ring buffers need {needed_kb} KB per CPU. The rest counts against \
+                 'ulimit -l', and recording fails if that is exceeded."
+            ),
+        }
+    }
+
+    pub fn remediation(&self) -> String {
+        let (name, value) = self.fix();
+        format!("echo '{value}' | sudo tee /proc/sys/kernel/{name}")
+    }
+}
+
+/// Returns the settings which keep the recording from working fully.
+pub fn check(
+    settings: &PerfSettings,
+    privileges: &Privileges,
+    needs: &RecordingNeeds,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if let Some(level) = settings.paranoid {
+        // CAP_SYS_ADMIN covers perf on kernels before CAP_PERFMON existed.
+        let privileged = privileges.has_cap(CAP_PERFMON) || privileges.has_cap(CAP_SYS_ADMIN);
+        if needs.kernel_stacks && level > 1 && !privileged {
+            problems.push(Problem::ParanoidTooHigh { level });
+        }
+    }
+    if let Some(kptr_restrict) = settings.kptr_restrict {
+        let hidden = match kptr_restrict {
+            0 => false,
+            1 => !privileges.has_cap(CAP_SYSLOG),
+            _ => true,
+        };
+        if needs.kernel_symbols && hidden {
+            problems.push(Problem::KernelSymbolsHidden { kptr_restrict });
+        }
+    }
+    if let Some(limit_kb) = settings.mlock_kb {
+        // Without paranoia, the kernel doesn't enforce the mlock limit.
+        let enforced = settings.paranoid != Some(-1) && !privileges.has_cap(CAP_IPC_LOCK);
+        if enforced && limit_kb < needs.ring_buffer_kb_per_cpu {
+            problems.push(Problem::MlockLimitTooLow {
+                limit_kb,
+                needed_kb: needs.ring_buffer_kb_per_cpu,
+            });
+        }
+    }
+    problems
+}
+
+struct SavedSysctls {
+    sysctls: Box<dyn KernelSysctls + Send>,
+    /// In the order in which they were changed.
+    previous_values: Vec<(&'static str, String)>,
+}
+
+impl SavedSysctls {
+    fn restore(&mut self) {
+        while let Some((name, value)) = self.previous_values.pop() {
+            if let Err(err) = self.sysctls.write(name, &value) {
+                warn!(
+                    sysctl = name,
+                    value = value.as_str(),
+                    error = %err,
+                    "Could not restore /proc/sys/kernel/{name} to {value}: {err}"
+                );
+            }
+        }
+    }
+}
+
+/// Puts the sysctls which were changed by [`tune`] back to their previous
+/// values when dropped, or when samply exits with an error or a panic.
+pub struct SysctlGuard {
+    saved: Arc<Mutex<SavedSysctls>>,
+}
+
+impl Drop for SysctlGuard {
+    fn drop(&mut self) {
+        if let Ok(mut saved) = self.saved.lock() {
+            saved.restore();
+        }
+    }
+}
+
+/// Changes the sysctls to fix `problems`, until the returned guard is
+/// dropped. Needs root. If one of the changes fails, the ones before it are
+/// undone.
+pub fn tune(
+    sysctls: Box<dyn KernelSysctls + Send>,
+    problems: &[Problem],
+) -> io::Result<SysctlGuard> {
+    let saved = Arc::new(Mutex::new(SavedSysctls {
+        sysctls,
+        previous_values: Vec::new(),
+    }));
+    let guard = SysctlGuard {
+        saved: saved.clone(),
+    };
+    cli_error::run_on_error_exit({
+        let saved = saved.clone();
+        move || {
+            if let Ok(mut saved) = saved.lock() {
+                saved.restore();
+            }
+        }
+    });
+
+    let mut saved = saved.lock().unwrap();
+    for problem in problems {
+        let (name, value) = problem.fix();
+        let previous_value = saved.sysctls.read(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("/proc/sys/kernel/{name} doesn't exist"),
+            )
+        })?;
+        saved.sysctls.write(name, &value)?;
+        saved
+            .previous_values
+            .push((name, previous_value.trim().to_string()));
+    }
+    drop(saved);
+    Ok(guard)
+}
+
+/// Checks the perf_event settings before recording and prints a remediation
+/// for each problem. Exits if the recording can't work. With `auto_tune`,
+/// the settings are adjusted for this recording instead, if samply runs as
+/// root.
+pub fn run_preflight(needs: &RecordingNeeds, auto_tune: bool) -> Option<SysctlGuard> {
+    let settings = PerfSettings::read(&ProcSysctls);
+    let privileges = Privileges::current();
+    let problems = check(&settings, &privileges, needs);
+    if problems.is_empty() {
+        return None;
+    }
+
+    if auto_tune {
+        if privileges.is_root {
+            match tune(Box::new(ProcSysctls), &problems) {
+                Ok(guard) => {
+                    for problem in &problems {
+                        let (name, value) = problem.fix();
+                        eprintln!("Set /proc/sys/kernel/{name} to {value} for this recording.");
+                    }
+                    return Some(guard);
+                }
+                Err(err) => warn!(
+                    error = %err,
+                    "Could not adjust the perf_event settings: {err}"
+                ),
+            }
+        } else {
+            eprintln!("--auto-tune only works if samply runs as root, e.g. with sudo.");
+        }
+    }
+
+    eprintln!();
+    for problem in &problems {
+        eprintln!("{}", problem.description());
+        eprintln!("You can execute the following command and then try again:");
+        eprintln!("    {}", problem.remediation());
+        eprintln!();
+    }
+    if !auto_tune {
+        eprintln!("Or run samply with sudo and --auto-tune to adjust these settings just for");
+        eprintln!("the recording.");
+        eprintln!();
+    }
+    if problems.iter().any(Problem::is_fatal) {
+        CliError::environment("perf_event_paranoid is too restrictive").exit()
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct FakeSysctls(Arc<Mutex<HashMap<String, String>>>);
+
+    impl FakeSysctls {
+        fn new(values: &[(&str, &str)]) -> Self {
+            let values = values
+                .iter()
+                .map(|(name, value)| (name.to_string(), format!("{value}\n")))
+                .collect();
+            Self(Arc::new(Mutex::new(values)))
+        }
+
+        fn get(&self, name: &str) -> String {
+            self.0.lock().unwrap()[name].trim().to_string()
+        }
+    }
+
+    impl KernelSysctls for FakeSysctls {
+        fn read(&self, name: &str) -> Option<String> {
+            self.0.lock().unwrap().get(name).cloned()
+        }
+
+        fn write(&mut self, name: &str, value: &str) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    const NEEDS: RecordingNeeds = RecordingNeeds {
+        kernel_stacks: true,
+        kernel_symbols: true,
+        ring_buffer_kb_per_cpu: 1028,
+    };
+
+    fn problems(values: &[(&str, &str)], effective_caps: u64) -> Vec<Problem> {
+        let settings = PerfSettings::read(&FakeSysctls::new(values));
+        let privileges = Privileges {
+            is_root: false,
+            effective_caps,
+        };
+        check(&settings, &privileges, &NEEDS)
+    }
+
+    #[test]
+    fn detects_restrictive_settings() {
+        let restrictive = [
+            (PERF_EVENT_PARANOID, "2"),
+            (KPTR_RESTRICT, "1"),
+            (PERF_EVENT_MLOCK_KB, "516"),
+        ];
+        assert_eq!(
+            problems(&restrictive, 0),
+            vec![
+                Problem::ParanoidTooHigh { level: 2 },
+                Problem::KernelSymbolsHidden { kptr_restrict: 1 },
+                Problem::MlockLimitTooLow {
+                    limit_kb: 516,
+                    needed_kb: 1028
+                },
+            ]
+        );
+        let caps = (1 << CAP_PERFMON) | (1 << CAP_SYSLOG) | (1 << CAP_IPC_LOCK);
+        assert_eq!(problems(&restrictive, caps), vec![]);
+
+        let permissive = [
+            (PERF_EVENT_PARANOID, "1"),
+            (KPTR_RESTRICT, "0"),
+            (PERF_EVENT_MLOCK_KB, "2048"),
+        ];
+        assert_eq!(problems(&permissive, 0), vec![]);
+        // No mlock limit without paranoia.
+        assert_eq!(
+            problems(
+                &[(PERF_EVENT_PARANOID, "-1"), (PERF_EVENT_MLOCK_KB, "516")],
+                0
+            ),
+            vec![]
+        );
+        assert_eq!(
+            problems(&[(KPTR_RESTRICT, "2")], 1 << CAP_SYSLOG),
+            vec![Problem::KernelSymbolsHidden { kptr_restrict: 2 }]
+        );
+        assert_eq!(problems(&[], 0), vec![]);
+    }
+
+    #[test]
+    fn remediation_is_a_command() {
+        assert_eq!(
+            Problem::ParanoidTooHigh { level: 3 }.remediation(),
+            "echo '1' | sudo tee /proc/sys/kernel/perf_event_paranoid"
+        );
+    }
+
+    #[test]
+    fn tuned_settings_are_restored_on_drop() {
+        let sysctls = FakeSysctls::new(&[(PERF_EVENT_PARANOID, "2"), (KPTR_RESTRICT, "1")]);
+        let problems = [
+            Problem::ParanoidTooHigh { level: 2 },
+            Problem::KernelSymbolsHidden { kptr_restrict: 1 },
+        ];
+        let guard = tune(Box::new(sysctls.clone()), &problems).unwrap();
+        assert_eq!(sysctls.get(PERF_EVENT_PARANOID), "1");
+        assert_eq!(sysctls.get(KPTR_RESTRICT), "0");
+        drop(guard);
+        assert_eq!(sysctls.get(PERF_EVENT_PARANOID), "2");
+        assert_eq!(sysctls.get(KPTR_RESTRICT), "1");
+
+        // A missing sysctl undoes the changes before it.
+        let problems = [
+            Problem::ParanoidTooHigh { level: 2 },
+            Problem::MlockLimitTooLow {
+                limit_kb: 516,
+                needed_kb: 1028,
+            },
+        ];
+        assert!(tune(Box::new(sysctls.clone()), &problems).is_err());
+        assert_eq!(sysctls.get(PERF_EVENT_PARANOID), "2");
+    }
+}
@@ -1,25 +1,34 @@
-use linux_perf_data::linux_perf_event_reader::EventRecord;
-use linux_perf_data::linux_perf_event_reader::{
-    CpuMode, Endianness, Mmap2FileId, Mmap2InodeAndVersion, Mmap2Record, RawData,
-};
+use fxprof_processed_profile::Profile;
+use linux_perf_data::linux_perf_event_reader::{Endianness, EventRecord};
+use tracing::warn;
 
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::BufWriter;
-use std::path::Path;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+use super::control_pipe::{monotonic_timestamp, ControlPipe, CONTROL_PIPE_ENV_VAR};
 use super::perf_event::EventSource;
 use super::perf_group::{AttachMode, PerfGroup};
-use super::proc_maps;
+use super::preflight::{run_preflight, RecordingNeeds};
 use super::process::SuspendedLaunchedProcess;
-use crate::linux_shared::{ConvertRegs, Converter, EventInterpretation};
+use super::top::show_live_view;
+use crate::cli_error::CliError;
+use crate::linux_shared::{
+    ContainerBinaries, ConversionOptions, ConvertRegs, Converter, EventInterpretation, ExitReason,
+    LiveSampleSink, ModuleData,
+};
 use crate::server::{start_server_main, ServerProps};
+use crate::shared::jitdump_manager::TimestampClock;
+use crate::shared::rotation::{Rotation, RotationOptions};
+use crate::shared::self_profile::{PhaseRecorder, SelfProfiler};
 
 #[cfg(target_arch = "x86_64")]
 pub type ConvertRegsNative = crate::linux_shared::ConvertRegsX86_64;
@@ -27,14 +36,39 @@ pub type ConvertRegsNative = crate::linux_shared::ConvertRegsX86_64;
 #[cfg(target_arch = "aarch64")]
 pub type ConvertRegsNative = crate::linux_shared::ConvertRegsAarch64;
 
+/// The number of bytes of the user stack which are copied into each sample,
+/// for unwinding.
+const USER_STACK_SIZE: u32 = 32000;
+
+fn user_stack_size(leaf_only: bool) -> u32 {
+    // In leaf-only mode, we don't unwind, so we don't need the user stack
+    // bytes and registers in the samples. This makes the samples much smaller.
+    match leaf_only {
+        true => 0,
+        false => USER_STACK_SIZE,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn start_recording(
     output_file: &Path,
     command_name: OsString,
     command_args: &[OsString],
     time_limit: Option<Duration>,
     interval: Duration,
+    idle_thread_rate_divisor: Option<u32>,
+    leaf_only: bool,
+    auto_tune: bool,
+    rotation: Option<RotationOptions>,
     server_props: Option<ServerProps>,
-) -> Result<ExitStatus, ()> {
+) -> Result<ExitStatus, CliError> {
+    if idle_thread_rate_divisor.is_some() {
+        CliError::user_input("--idle-thread-rate-divisor is only supported on macOS.").exit()
+    }
+    // The launched process has a single thread when the events are opened.
+    let needs = RecordingNeeds::new(user_stack_size(leaf_only), 1);
+    let sysctl_guard = run_preflight(&needs, auto_tune);
+
     // Ignore SIGINT while the subcommand is running. The signal still reaches the process
     // under observation while we continue to record it. (ctrl+c will send the SIGINT signal
     // to all processes in the foreground process group).
@@ -46,16 +80,32 @@ pub fn start_recording(
     )
     .expect("cannot register signal handler");
 
+    // Create the control pipe, and tell the launched command where it is.
+    let control_pipe = match ControlPipe::create() {
+        Ok(control_pipe) => {
+            std::env::set_var(CONTROL_PIPE_ENV_VAR, control_pipe.path());
+            Some(control_pipe)
+        }
+        Err(err) => {
+            warn!(error = %err, "Could not create the control pipe: {err}");
+            None
+        }
+    };
+
     // Start a new process for the launched command and get its pid.
     // The command will not start running until we tell it to.
-    let process =
-        SuspendedLaunchedProcess::launch_in_suspended_state(&command_name, command_args).unwrap();
+    let process = SuspendedLaunchedProcess::launch_in_suspended_state(&command_name, command_args)
+        .unwrap_or_else(|err| CliError::io("Could not prepare the child process", &err).exit());
     let pid = process.pid();
 
     // Create a channel for the observer thread to notify the main thread once
     // profiling has been initialized and the launched process can start.
     let (s, r) = crossbeam_channel::bounded(1);
 
+    // The main thread reaps the child process and sends its exit status to
+    // the observer thread, for the profile.
+    let (exit_status_sender, exit_status_receiver) = crossbeam_channel::bounded(1);
+
     // Launch the observer thread. This thread will manage the perf events.
     let output_file_copy = output_file.to_owned();
     let command_name_copy = command_name.to_string_lossy().to_string();
@@ -63,8 +113,17 @@ pub fn start_recording(
         let product = command_name_copy;
 
         // Create the perf events, setting ENABLE_ON_EXEC.
-        let (perf_group, converter) =
-            init_profiler(interval, pid, AttachMode::AttachWithEnableOnExec, &product);
+        let (perf_group, converter) = init_profiler(
+            interval,
+            pid,
+            AttachMode::AttachWithEnableOnExec,
+            &product,
+            leaf_only,
+            None,
+            Some(ContainerBinaries::new(ContainerBinaries::dir_for_profile(
+                &output_file_copy,
+            ))),
+        );
 
         // Tell the main thread to tell the child process to begin executing.
         s.send(()).unwrap();
@@ -78,13 +137,29 @@ pub fn start_recording(
         let stop_flag = Arc::new(AtomicBool::new(false));
 
         // Start profiling the process.
-        run_profiler(
+        let mut rotating = rotation.map(|options| {
+            RotatingOutput::new(options, &output_file_copy, interval, &product, leaf_only)
+        });
+        let mut converter = run_profiler(
             perf_group,
             converter,
-            &output_file_copy,
             time_limit,
             stop_flag,
+            control_pipe.map(|control_pipe| (control_pipe, pid)),
+            rotating.as_mut(),
         );
+        // The perf events are closed once the child has exited, so the exit
+        // status is on its way. The EXIT record may have been converted
+        // already; the converter matches them up.
+        if let Ok(exit_status) = exit_status_receiver.recv() {
+            if let Some(reason) = exit_reason(exit_status) {
+                converter.handle_child_exit(pid as i32, reason);
+            }
+        }
+        match rotating {
+            Some(mut rotating) => rotating.save(converter, monotonic_timestamp()),
+            None => save_profile_to_file(&converter.finish(), &output_file_copy),
+        }
     });
 
     // We're on the main thread here and the observer thread has just been launched.
@@ -96,10 +171,7 @@ pub fn start_recording(
     // Now tell the child process to start executing.
     let process = match process.unsuspend_and_run() {
         Ok(process) => process,
-        Err(run_err) => {
-            eprintln!("Could not launch child process: {run_err}");
-            std::process::exit(1)
-        }
+        Err(run_err) => CliError::io("Could not launch child process", &run_err).exit(),
     };
 
     // Phew, we're profiling!
@@ -107,6 +179,7 @@ pub fn start_recording(
     // Wait for the child process to quit.
     // This is where the main thread spends all its time during profiling.
     let exit_status = process.wait().unwrap();
+    let _ = exit_status_sender.send(exit_status);
 
     // The child has quit.
     // From now on, we want to terminate if the user presses Ctrl+C.
@@ -118,6 +191,7 @@ pub fn start_recording(
     observer_thread
         .join()
         .expect("couldn't join observer thread");
+    drop(sysctl_guard);
 
     if let Some(server_props) = server_props {
         start_server_main(output_file, server_props);
@@ -126,13 +200,27 @@ pub fn start_recording(
     Ok(exit_status)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn start_profiling_pid(
     output_file: &Path,
     pid: u32,
     time_limit: Option<Duration>,
     interval: Duration,
+    idle_thread_rate_divisor: Option<u32>,
+    leaf_only: bool,
+    auto_tune: bool,
+    rotation: Option<RotationOptions>,
     server_props: Option<ServerProps>,
 ) {
+    if idle_thread_rate_divisor.is_some() {
+        CliError::user_input("--idle-thread-rate-divisor is only supported on macOS.").exit()
+    }
+    let thread_count = std::fs::read_dir(format!("/proc/{pid}/task"))
+        .map(|entries| entries.count() as u64)
+        .unwrap_or(1);
+    let needs = RecordingNeeds::new(user_stack_size(leaf_only), thread_count);
+    let sysctl_guard = run_preflight(&needs, auto_tune);
+
     // When the first Ctrl+C is received, stop recording.
     // The server launches after the recording finishes. On the second Ctrl+C, terminate the server.
     let stop = Arc::new(AtomicBool::new(false));
@@ -152,14 +240,37 @@ pub fn start_profiling_pid(
     let observer_thread = thread::spawn({
         let stop = stop.clone();
         move || {
-            let (perf_group, converter) =
-                init_profiler(interval, pid, AttachMode::StopAttachEnableResume, &product);
+            let (perf_group, converter) = init_profiler(
+                interval,
+                pid,
+                AttachMode::StopAttachEnableResume,
+                &product,
+                leaf_only,
+                None,
+                Some(ContainerBinaries::new(ContainerBinaries::dir_for_profile(
+                    &output_file_copy,
+                ))),
+            );
 
             // Tell the main thread that we are now executing.
             s.send(()).unwrap();
             drop(s);
 
-            run_profiler(perf_group, converter, &output_file_copy, time_limit, stop)
+            let mut rotating = rotation.map(|options| {
+                RotatingOutput::new(options, &output_file_copy, interval, &product, leaf_only)
+            });
+            let converter = run_profiler(
+                perf_group,
+                converter,
+                time_limit,
+                stop,
+                None,
+                rotating.as_mut(),
+            );
+            match rotating {
+                Some(mut rotating) => rotating.save(converter, monotonic_timestamp()),
+                None => save_profile_to_file(&converter.finish(), &output_file_copy),
+            }
         }
     });
 
@@ -182,12 +293,152 @@ pub fn start_profiling_pid(
     // From now on we want Ctrl+C to always quit our process. The stop flag might still be
     // false if the observer thread finished because the observed processes terminated.
     stop.store(true, Ordering::SeqCst);
+    drop(sysctl_guard);
 
     if let Some(server_props) = server_props {
         start_server_main(output_file, server_props);
     }
 }
 
+/// What `samply top` records: a command which it launches, or a running
+/// process.
+pub enum TopTarget {
+    Command(OsString, Vec<OsString>),
+    Pid(u32),
+}
+
+/// Records the target and shows its hottest functions while recording, until
+/// the user quits the view or the recorded processes exit. If `output_file`
+/// is set, everything which was recorded is saved to it as a profile. A
+/// launched command keeps running if the view is quit before it exits.
+pub fn start_top(
+    target: TopTarget,
+    interval: Duration,
+    row_count: usize,
+    output_file: Option<&Path>,
+) {
+    let (pid, attach_mode, product, process, thread_count) = match target {
+        TopTarget::Command(command_name, command_args) => {
+            let process =
+                SuspendedLaunchedProcess::launch_in_suspended_state(&command_name, &command_args)
+                    .unwrap_or_else(|err| {
+                        CliError::io("Could not prepare the child process", &err).exit()
+                    });
+            let pid = process.pid();
+            let product = command_name.to_string_lossy().to_string();
+            let attach_mode = AttachMode::AttachWithEnableOnExec;
+            (pid, attach_mode, product, Some(process), 1)
+        }
+        TopTarget::Pid(pid) => {
+            let thread_count = std::fs::read_dir(format!("/proc/{pid}/task"))
+                .map(|entries| entries.count() as u64)
+                .unwrap_or(1);
+            let attach_mode = AttachMode::StopAttachEnableResume;
+            (pid, attach_mode, format!("PID {pid}"), None, thread_count)
+        }
+    };
+    let needs = RecordingNeeds::new(USER_STACK_SIZE, thread_count);
+    let sysctl_guard = run_preflight(&needs, false);
+
+    // Ctrl+C quits the view, like q.
+    let stop = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGINT, stop.clone())
+        .expect("cannot register signal handler");
+
+    let (s, r) = crossbeam_channel::bounded(1);
+    let sink = LiveSampleSink::default();
+    let output_file_copy = output_file.map(ToOwned::to_owned);
+    let observer_thread = thread::spawn({
+        let stop = stop.clone();
+        let sink = sink.clone();
+        move || {
+            let container_binaries = output_file_copy.as_deref().map(|output_file| {
+                ContainerBinaries::new(ContainerBinaries::dir_for_profile(output_file))
+            });
+            let (perf_group, converter) = init_profiler(
+                interval,
+                pid,
+                attach_mode,
+                &product,
+                false,
+                Some(sink),
+                container_binaries,
+            );
+            s.send(()).unwrap();
+            drop(s);
+
+            let converter = run_profiler(perf_group, converter, None, stop, None, None);
+            if let Some(output_file) = output_file_copy {
+                save_profile_to_file(&converter.finish(), &output_file);
+            }
+        }
+    });
+
+    let () = r.recv().unwrap();
+    drop(r);
+
+    if let Some(process) = process {
+        let process = match process.unsuspend_and_run() {
+            Ok(process) => process,
+            Err(run_err) => CliError::io("Could not launch child process", &run_err).exit(),
+        };
+        // Reap the command when it exits, so that its perf events close.
+        thread::spawn(move || process.wait());
+    }
+
+    show_live_view(&sink, row_count, &stop, || !observer_thread.is_finished());
+
+    stop.store(true, Ordering::SeqCst);
+    observer_thread
+        .join()
+        .expect("couldn't join observer thread");
+    drop(sysctl_guard);
+}
+
+/// Starts recording samply's own process for `--self-profile`. The profile is
+/// saved to `output_file` when [`SelfProfiler::finish`] is called.
+pub fn start_self_profiling(output_file: &Path) -> SelfProfiler {
+    let pid = std::process::id();
+    // The phases are recorded by the calling thread.
+    let tid = nix::unistd::gettid().as_raw() as u32;
+    let phases = PhaseRecorder::new(tid, monotonic_timestamp);
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Create a channel for the observer thread to notify the calling thread
+    // once profiling has been initialized.
+    let (s, r) = crossbeam_channel::bounded(1);
+
+    let output_file_copy = output_file.to_owned();
+    let observer_thread = thread::spawn({
+        let stop = stop.clone();
+        let phases = phases.clone();
+        move || {
+            let (perf_group, converter) = init_profiler(
+                Duration::from_millis(1),
+                pid,
+                AttachMode::AttachEnable,
+                "samply",
+                false,
+                None,
+                None,
+            );
+
+            s.send(()).unwrap();
+            drop(s);
+
+            let mut converter = run_profiler(perf_group, converter, None, stop, None, None);
+            converter.add_self_profile_phases(pid as i32, &phases);
+            save_profile_to_file(&converter.finish(), &output_file_copy);
+        }
+    });
+
+    let () = r.recv().unwrap();
+    drop(r);
+
+    SelfProfiler::new(output_file.to_owned(), phases, stop, observer_thread)
+}
+
 fn paranoia_level() -> Option<u32> {
     let level = read_string_lossy("/proc/sys/kernel/perf_event_paranoid").ok()?;
     let level = level.trim().parse::<u32>().ok()?;
@@ -199,19 +450,20 @@ fn init_profiler(
     pid: u32,
     attach_mode: AttachMode,
     product_name: &str,
+    leaf_only: bool,
+    live_samples: Option<LiveSampleSink>,
+    container_binaries: Option<ContainerBinaries>,
 ) -> (
     PerfGroup,
-    Converter<framehop::UnwinderNative<Vec<u8>, framehop::MayAllocateDuringUnwind>>,
+    Converter<framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>>,
 ) {
-    let interval_nanos = if interval.as_nanos() > 0 {
-        interval.as_nanos() as u64
-    } else {
-        1_000_000 // 1 million nano seconds = 1 milli second
-    };
-
+    let interval_nanos = sampling_interval_nanos(interval);
     let frequency = (1_000_000_000 / interval_nanos) as u32;
-    let stack_size = 32000;
-    let regs_mask = ConvertRegsNative::regs_mask();
+    let stack_size = user_stack_size(leaf_only);
+    let regs_mask = match leaf_only {
+        true => 0,
+        false => ConvertRegsNative::regs_mask(),
+    };
 
     let perf = PerfGroup::open(
         pid,
@@ -236,7 +488,7 @@ fn init_profiler(
                     eprintln!("You can execute the following command and then try again:");
                     eprintln!("    echo '1' | sudo tee /proc/sys/kernel/perf_event_paranoid");
                     eprintln!();
-                    std::process::exit(1);
+                    CliError::environment("perf_event_paranoid is too restrictive").exit()
                 }
                 _ => {
                     // Permission denied even though parania was probably not the reason.
@@ -254,19 +506,78 @@ fn init_profiler(
                     match perf {
                         Ok(perf) => perf, // Success!
                         Err(error) => {
-                            eprintln!("Failed to start profiling: {error}");
-                            std::process::exit(1);
+                            CliError::environment(format!("Failed to start profiling: {error}"))
+                                .exit()
                         }
                     }
                 }
             }
         }
-        Err(error) => {
-            eprintln!("Failed to start profiling: {error}");
-            std::process::exit(1);
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            // The pid we were asked to attach to doesn't exist.
+            CliError::user_input(format!("Failed to start profiling: {error}")).exit()
         }
+        Err(error) => CliError::environment(format!("Failed to start profiling: {error}")).exit(),
     };
 
+    let mut converter = new_converter(
+        interval_nanos,
+        product_name,
+        leaf_only,
+        live_samples,
+        container_binaries,
+    );
+
+    // TODO: Gather threads / processes recursively, here and in PerfGroup setup.
+    for entry in std::fs::read_dir(format!("/proc/{pid}/task"))
+        .unwrap_or_else(|err| {
+            CliError::io(format!("Could not list the threads of process {pid}"), &err).exit()
+        })
+        .flatten()
+    {
+        let tid: u32 = entry.file_name().to_string_lossy().parse().unwrap();
+        let comm_path = format!("/proc/{pid}/task/{tid}/comm");
+        if let Ok(buffer) = std::fs::read(comm_path) {
+            let length = memchr::memchr(b'\0', &buffer).unwrap_or(buffer.len());
+            let name = std::str::from_utf8(&buffer[..length]).unwrap().trim_end();
+            converter.set_thread_name(pid as i32, tid as i32, name, true);
+        }
+    }
+
+    if let Err(err) = synthesize_mmaps_from_proc_maps(&mut converter, pid, 0) {
+        CliError::io(
+            format!("Could not read the mappings of process {pid}"),
+            &err,
+        )
+        .exit()
+    }
+
+    // eprintln!("Enabling perf events...");
+    match attach_mode {
+        AttachMode::StopAttachEnableResume | AttachMode::AttachEnable => perf.enable(),
+        AttachMode::AttachWithEnableOnExec => {
+            // The perf event will get enabled automatically once the forked child process execs.
+        }
+    }
+
+    (perf, converter)
+}
+
+fn sampling_interval_nanos(interval: Duration) -> u64 {
+    if interval.as_nanos() > 0 {
+        interval.as_nanos() as u64
+    } else {
+        1_000_000 // 1 million nano seconds = 1 milli second
+    }
+}
+
+fn new_converter(
+    interval_nanos: u64,
+    product_name: &str,
+    leaf_only: bool,
+    live_samples: Option<LiveSampleSink>,
+    container_binaries: Option<ContainerBinaries>,
+) -> Converter<framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>> {
     let first_sample_time = 0;
 
     let endian = if cfg!(target_endian = "little") {
@@ -280,102 +591,137 @@ fn init_profiler(
         main_event_name: "cycles".to_string(),
         sampling_is_time_based: Some(interval_nanos),
         have_context_switches: true,
-        sched_switch_attr_index: None,
-        rss_stat_attr_index: None,
+        frequency_event_attr_indexes: None,
         event_names: vec!["cycles".to_string()],
+        clock: TimestampClock::Monotonic,
+        marker_only: false,
+        event_track_attr_indexes: Vec::new(),
     };
 
-    let mut converter =
-        Converter::<framehop::UnwinderNative<Vec<u8>, framehop::MayAllocateDuringUnwind>>::new(
-            product_name,
-            None,
-            HashMap::new(),
-            machine_info.as_ref().map(|info| info.release.as_str()),
-            first_sample_time,
-            endian,
-            framehop::CacheNative::new(),
-            None,
-            interpretation,
-            false,
-            false,
-        );
-
-    // TODO: Gather threads / processes recursively, here and in PerfGroup setup.
-    for entry in std::fs::read_dir(format!("/proc/{pid}/task"))
-        .unwrap()
-        .flatten()
-    {
-        let tid: u32 = entry.file_name().to_string_lossy().parse().unwrap();
-        let comm_path = format!("/proc/{pid}/task/{tid}/comm");
-        if let Ok(buffer) = std::fs::read(comm_path) {
-            let length = memchr::memchr(b'\0', &buffer).unwrap_or(buffer.len());
-            let name = std::str::from_utf8(&buffer[..length]).unwrap().trim_end();
-            converter.set_thread_name(pid as i32, tid as i32, name, true);
-        }
-    }
-
-    let maps = read_string_lossy(format!("/proc/{pid}/maps")).expect("couldn't read proc maps");
-    let maps = proc_maps::parse(&maps);
+    Converter::<framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>>::new(
+        product_name,
+        None,
+        HashMap::new(),
+        machine_info.as_ref().map(|info| info.release.as_str()),
+        first_sample_time,
+        endian,
+        framehop::CacheNative::new(),
+        None,
+        interpretation,
+        ConversionOptions {
+            leaf_only,
+            take_mapping_snapshots: true,
+            // So that a rotating recording can carry the mappings into the
+            // next file.
+            track_mappings_for_checkpoints: true,
+            live_samples,
+            container_binaries,
+            ..Default::default()
+        },
+    )
+}
 
-    for region in maps {
-        let mut protection = 0;
-        if region.is_read {
-            protection |= libc::PROT_READ;
-        }
-        if region.is_write {
-            protection |= libc::PROT_WRITE;
-        }
-        if region.is_executable {
-            protection |= libc::PROT_EXEC;
-        }
+/// The output of `samply record --rotate`: at every boundary of the rotation
+/// interval, the current converter's profile is saved to its own file and a
+/// new converter continues from a checkpoint of the old one.
+struct RotatingOutput {
+    rotation: Rotation,
+    output_file: PathBuf,
+    interval_nanos: u64,
+    product_name: String,
+    leaf_only: bool,
+}
 
-        let mut flags = 0;
-        if region.is_shared {
-            flags |= libc::MAP_SHARED;
-        } else {
-            flags |= libc::MAP_PRIVATE;
+impl RotatingOutput {
+    fn new(
+        options: RotationOptions,
+        output_file: &Path,
+        interval: Duration,
+        product_name: &str,
+        leaf_only: bool,
+    ) -> Self {
+        // The records' timestamps are from the monotonic clock.
+        let rotation = Rotation::new(
+            options,
+            output_file,
+            monotonic_timestamp(),
+            SystemTime::now(),
+        );
+        Self {
+            rotation,
+            output_file: output_file.to_owned(),
+            interval_nanos: sampling_interval_nanos(interval),
+            product_name: product_name.to_owned(),
+            leaf_only,
         }
+    }
 
-        converter.handle_mmap2(
-            Mmap2Record {
-                pid: pid as i32,
-                tid: pid as i32,
-                address: region.start,
-                length: region.end - region.start,
-                page_offset: region.file_offset,
-                file_id: Mmap2FileId::InodeAndVersion(Mmap2InodeAndVersion {
-                    major: region.major,
-                    minor: region.minor,
-                    inode: region.inode,
-                    inode_generation: 0,
-                }),
-                protection: protection as _,
-                flags: flags as _,
-                path: RawData::Single(&region.name.into_bytes()),
-                cpu_mode: CpuMode::User,
-            },
-            0,
+    /// Saves the profile up to `boundary` and returns the converter for the
+    /// records after it.
+    fn rotate(
+        &mut self,
+        mut converter: Converter<
+            framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>,
+        >,
+        boundary: u64,
+    ) -> Converter<framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>> {
+        let checkpoint = converter.checkpoint();
+        converter.cut_at_checkpoint(boundary);
+        self.save(converter, boundary);
+        let mut converter = new_converter(
+            self.interval_nanos,
+            &self.product_name,
+            self.leaf_only,
+            None,
+            Some(ContainerBinaries::new(ContainerBinaries::dir_for_profile(
+                &self.output_file,
+            ))),
         );
+        converter.restore_checkpoint(&checkpoint, boundary);
+        converter
     }
 
-    // eprintln!("Enabling perf events...");
-    match attach_mode {
-        AttachMode::StopAttachEnableResume => perf.enable(),
-        AttachMode::AttachWithEnableOnExec => {
-            // The perf event will get enabled automatically once the forked child process execs.
-        }
+    /// Saves the profile of the current file, which ends at `end`.
+    fn save(
+        &mut self,
+        converter: Converter<
+            framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>,
+        >,
+        end: u64,
+    ) {
+        let path = self.rotation.file_path(end);
+        save_profile_to_file(&converter.finish(), &path);
+        self.rotation.file_written(path, end);
     }
+}
 
-    (perf, converter)
+/// Passes the mappings in /proc/<pid>/maps to the converter as if they had
+/// been mmapped at `timestamp`. Returns the number of executable mappings.
+fn synthesize_mmaps_from_proc_maps(
+    converter: &mut Converter<
+        framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>,
+    >,
+    pid: u32,
+    timestamp: u64,
+) -> std::io::Result<usize> {
+    let maps = read_string_lossy(format!("/proc/{pid}/maps"))?;
+    Ok(converter.handle_proc_maps(pid as i32, &maps, timestamp))
 }
 
+/// Feeds the perf events to the converter until `stop` is set or until all
+/// perf events are closed. Returns the converter so that the caller can add
+/// more data before finishing the profile. With `rotating`, that's the
+/// converter of the last file.
 fn run_profiler(
     mut perf: PerfGroup,
-    mut converter: Converter<framehop::UnwinderNative<Vec<u8>, framehop::MayAllocateDuringUnwind>>,
-    output_filename: &Path,
+    mut converter: Converter<
+        framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>,
+    >,
     _time_limit: Option<Duration>,
     stop: Arc<AtomicBool>,
-) {
+    control_pipe: Option<(ControlPipe, u32)>,
+    mut rotating: Option<&mut RotatingOutput>,
+) -> Converter<framehop::UnwinderNative<ModuleData, framehop::MayAllocateDuringUnwind>> {
     // eprintln!("Running...");
 
     let mut wait = false;
@@ -392,6 +738,12 @@ fn run_profiler(
             perf.wait();
         }
 
+        if let Some((control_pipe, pid)) = &control_pipe {
+            for (command, timestamp) in control_pipe.take_commands() {
+                converter.handle_control_command(*pid as i32, command, timestamp);
+            }
+        }
+
         let iter = perf.iter();
         if iter.len() == 0 {
             wait = true;
@@ -404,6 +756,12 @@ fn run_profiler(
             // debug!("Recording parsed_record: {:#?}", parsed_record);
 
             if let Some(timestamp) = record.timestamp() {
+                // Each record goes into the file whose time range it's in.
+                if let Some(rotating) = rotating.as_deref_mut() {
+                    if let Some(boundary) = rotating.rotation.boundary_before(timestamp) {
+                        converter = rotating.rotate(converter, boundary);
+                    }
+                }
                 if timestamp < last_timestamp {
                     // eprintln!(
                     //     "bad timestamp ordering; {timestamp} is earlier but arrived after {last_timestamp}"
@@ -415,6 +773,16 @@ fn run_profiler(
             match parsed_record {
                 EventRecord::Sample(e) => {
                     converter.handle_sample::<ConvertRegsNative>(&e);
+                    for (pid, exec_timestamp) in converter.take_processes_needing_mapping_snapshot()
+                    {
+                        if let Ok(count) = synthesize_mmaps_from_proc_maps(
+                            &mut converter,
+                            pid as u32,
+                            exec_timestamp,
+                        ) {
+                            converter.handle_mapping_snapshot(pid, count);
+                        }
+                    }
                     /*
                     } else if interpretation.sched_switch_attr_index == Some(attr_index) {
                         converter.handle_sched_switch::<C>(e);
@@ -457,15 +825,36 @@ fn run_profiler(
         }
     }
 
+    // Pick up any commands which were sent just before the end.
+    if let Some((control_pipe, pid)) = &control_pipe {
+        for (command, timestamp) in control_pipe.take_commands() {
+            converter.handle_control_command(*pid as i32, command, timestamp);
+        }
+    }
+
     if total_lost_events > 0 {
-        eprintln!("Lost {total_lost_events} events.");
+        warn!(total_lost_events, "Lost {total_lost_events} events.");
     }
 
-    let profile = converter.finish();
+    converter
+}
+
+fn exit_reason(exit_status: ExitStatus) -> Option<ExitReason> {
+    match (exit_status.code(), exit_status.signal()) {
+        (Some(code), _) => Some(ExitReason::Code(code)),
+        (None, Some(signal)) => Some(ExitReason::Signal(signal)),
+        (None, None) => None,
+    }
+}
 
-    let output_file = File::create(output_filename).unwrap();
+fn save_profile_to_file(profile: &Profile, output_filename: &Path) {
+    let output_file = File::create(output_filename).unwrap_or_else(|err| {
+        CliError::io(format!("Could not create {output_filename:?}"), &err).exit()
+    });
     let writer = BufWriter::new(output_file);
-    serde_json::to_writer(writer, &profile).expect("Couldn't write JSON");
+    if let Err(err) = serde_json::to_writer(writer, profile) {
+        CliError::environment(format!("Could not write {output_filename:?}: {err}")).exit()
+    }
 }
 
 pub fn read_string_lossy<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
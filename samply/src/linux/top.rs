@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use fxprof_processed_profile::LibraryHandle;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{self, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+use wholesym::{SymbolManager, SymbolMap};
+
+use super::control_pipe::monotonic_timestamp;
+use crate::linux_shared::{LiveBatch, LiveFrame, LiveLib, LiveSample, LiveSampleSink};
+use crate::server::symbol_manager_config;
+
+/// The samples of this many recent nanoseconds are counted in the table.
+const RECENT_WINDOW_NS: u64 = 5_000_000_000;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether a sample counts for its leaf function only, or for every function
+/// on its stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopMode {
+    Leaf,
+    Inclusive,
+}
+
+/// A row of the table: a function, or a group of addresses which couldn't be
+/// symbolicated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FrameLabel {
+    pub function: String,
+    pub lib: String,
+}
+
+/// The samples of the recent window, for `samply top`.
+#[derive(Debug, Default)]
+pub struct TopTable {
+    samples: VecDeque<LiveSample>,
+    libs: HashMap<LibraryHandle, LiveLib>,
+    process_names: HashMap<i32, String>,
+}
+
+impl TopTable {
+    pub fn add_batch(&mut self, batch: LiveBatch) {
+        self.libs.extend(batch.libs);
+        self.process_names.extend(batch.process_names);
+        self.samples.extend(batch.samples);
+    }
+
+    /// Drops the samples which are older than the window before `now`.
+    pub fn expire(&mut self, now: u64) {
+        let start = now.saturating_sub(RECENT_WINDOW_NS);
+        while matches!(self.samples.front(), Some(sample) if sample.timestamp < start) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The pids of the processes which have samples in the window.
+    pub fn pids(&self) -> Vec<i32> {
+        let mut pids: Vec<i32> = self.samples.iter().map(|sample| sample.pid).collect();
+        pids.sort_unstable();
+        pids.dedup();
+        pids
+    }
+
+    pub fn process_name(&self, pid: i32) -> Option<&str> {
+        self.process_names.get(&pid).map(String::as_str)
+    }
+
+    pub fn lib(&self, lib_handle: LibraryHandle) -> Option<&LiveLib> {
+        self.libs.get(&lib_handle)
+    }
+
+    /// The functions by their sample count in the window, heaviest first,
+    /// and the total sample count. `label` names the function of a frame.
+    pub fn rows(
+        &self,
+        mode: TopMode,
+        pid: Option<i32>,
+        mut label: impl FnMut(&LiveFrame) -> FrameLabel,
+    ) -> (Vec<(FrameLabel, u64)>, u64) {
+        let mut counts: HashMap<FrameLabel, u64> = HashMap::new();
+        let mut total = 0;
+        let mut sample_labels = HashSet::new();
+        for sample in &self.samples {
+            if matches!(pid, Some(pid) if pid != sample.pid) {
+                continue;
+            }
+            total += 1;
+            let frames = match mode {
+                TopMode::Leaf => &sample.frames[..sample.frames.len().min(1)],
+                TopMode::Inclusive => &sample.frames[..],
+            };
+            // Recursive functions only count once per sample.
+            sample_labels.clear();
+            sample_labels.extend(frames.iter().map(&mut label));
+            for frame_label in sample_labels.drain() {
+                *counts.entry(frame_label).or_default() += 1;
+            }
+        }
+        let mut rows: Vec<_> = counts.into_iter().collect();
+        rows.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.function.cmp(&b.0.function)));
+        (rows, total)
+    }
+}
+
+/// The function names of library addresses, by (library, relative address).
+/// Each library's symbols are loaded when one of its addresses is looked up
+/// for the first time.
+struct SymbolCache {
+    symbol_manager: SymbolManager,
+    symbol_maps: HashMap<LibraryHandle, Option<SymbolMap>>,
+    names: HashMap<(LibraryHandle, u32), Option<String>>,
+}
+
+impl SymbolCache {
+    fn new() -> Self {
+        Self {
+            symbol_manager: SymbolManager::with_config(symbol_manager_config(&[], false)),
+            symbol_maps: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    /// Looks up the frames which `frames` yields, so that [`Self::label`]
+    /// can name them.
+    async fn symbolicate<'a>(
+        &mut self,
+        table: &TopTable,
+        frames: impl Iterator<Item = &'a LiveFrame>,
+    ) {
+        for frame in frames {
+            let LiveFrame::Lib(lib_handle, address) = *frame else {
+                continue;
+            };
+            if self.names.contains_key(&(lib_handle, address)) {
+                continue;
+            }
+            if !self.symbol_maps.contains_key(&lib_handle) {
+                let symbol_map = match table.lib(lib_handle) {
+                    Some(lib) => self
+                        .symbol_manager
+                        .load_symbol_map_for_binary_at_path(Path::new(&lib.path), None)
+                        .await
+                        .ok(),
+                    None => None,
+                };
+                self.symbol_maps.insert(lib_handle, symbol_map);
+            }
+            let name = self.symbol_maps[&lib_handle]
+                .as_ref()
+                .and_then(|symbol_map| symbol_map.lookup_relative_address(address))
+                .map(|info| info.symbol.name);
+            self.names.insert((lib_handle, address), name);
+        }
+    }
+
+    fn label(&self, table: &TopTable, frame: &LiveFrame) -> FrameLabel {
+        match *frame {
+            LiveFrame::Lib(lib_handle, address) => {
+                let lib = table
+                    .lib(lib_handle)
+                    .map_or_else(String::new, |lib| lib.name.clone());
+                let function = match self.names.get(&(lib_handle, address)) {
+                    Some(Some(name)) => name.clone(),
+                    _ => format!("0x{address:x}"),
+                };
+                FrameLabel { function, lib }
+            }
+            LiveFrame::Kernel => FrameLabel {
+                function: "[kernel]".to_string(),
+                lib: String::new(),
+            },
+            LiveFrame::Unknown => FrameLabel {
+                function: "[unknown]".to_string(),
+                lib: String::new(),
+            },
+        }
+    }
+}
+
+/// Puts the terminal into a mode in which key presses are read one by one,
+/// without echo, until it's dropped.
+struct RawTerminal {
+    original: Termios,
+}
+
+impl RawTerminal {
+    /// Returns None if stdin isn't a terminal.
+    fn enter() -> Option<Self> {
+        let fd = std::io::stdin().as_raw_fd();
+        let original = termios::tcgetattr(fd).ok()?;
+        let mut raw = original.clone();
+        raw.local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw).ok()?;
+        // Hide the cursor.
+        print!("\x1b[?25l");
+        Some(Self { original })
+    }
+
+    /// Waits up to `timeout` for a key press.
+    fn read_key(&self, timeout: Duration) -> Option<u8> {
+        let fd = std::io::stdin().as_raw_fd();
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        match poll(&mut fds, timeout.as_millis() as i32) {
+            Ok(n) if n > 0 => {
+                let mut key = [0u8];
+                match std::io::stdin().read(&mut key) {
+                    Ok(1) => Some(key[0]),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h");
+        let _ = std::io::stdout().flush();
+        let fd = std::io::stdin().as_raw_fd();
+        let _ = termios::tcsetattr(fd, SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Shows the functions with the most samples in the recent window, refreshed
+/// every second, until the user presses q or `stop` is set, e.g. by Ctrl+C.
+/// `is_recording` returns false once the recording has ended; the last table
+/// stays on screen until the user quits.
+///
+/// Keys: q quits, i switches between leaf-only and inclusive counts, and p
+/// cycles the process filter through the sampled processes.
+#[tokio::main]
+pub async fn show_live_view(
+    sink: &LiveSampleSink,
+    row_count: usize,
+    stop: &AtomicBool,
+    is_recording: impl Fn() -> bool,
+) {
+    let terminal = RawTerminal::enter();
+    let mut table = TopTable::default();
+    let mut symbols = SymbolCache::new();
+    let mut mode = TopMode::Leaf;
+    let mut pid_filter = None;
+    let mut next_refresh = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        let timeout = next_refresh.saturating_duration_since(Instant::now());
+        let key = match &terminal {
+            Some(terminal) => terminal.read_key(timeout),
+            None => {
+                std::thread::sleep(timeout);
+                None
+            }
+        };
+        let recording = is_recording();
+        match key {
+            Some(b'q') => break,
+            Some(b'i') => {
+                mode = match mode {
+                    TopMode::Leaf => TopMode::Inclusive,
+                    TopMode::Inclusive => TopMode::Leaf,
+                };
+            }
+            Some(b'p') => {
+                let pids = table.pids();
+                pid_filter = match pid_filter {
+                    None => pids.first().copied(),
+                    Some(pid) => pids.into_iter().find(|&p| p > pid),
+                };
+            }
+            Some(_) => continue,
+            None if Instant::now() < next_refresh => continue,
+            None => {
+                table.add_batch(sink.take());
+                // Once the recording has ended, the last table stays.
+                if recording {
+                    table.expire(monotonic_timestamp());
+                }
+                next_refresh = Instant::now() + REFRESH_INTERVAL;
+            }
+        }
+
+        let frames = table.samples.iter().flat_map(|sample| match mode {
+            TopMode::Leaf => &sample.frames[..sample.frames.len().min(1)],
+            TopMode::Inclusive => &sample.frames[..],
+        });
+        symbols.symbolicate(&table, frames).await;
+        let (rows, total) = table.rows(mode, pid_filter, |frame| symbols.label(&table, frame));
+        let screen = render(&table, &rows, total, mode, pid_filter, row_count, recording);
+        print!("{screen}");
+        let _ = std::io::stdout().flush();
+
+        // Without a terminal, there's no key to quit with.
+        if !recording && terminal.is_none() {
+            break;
+        }
+    }
+}
+
+fn render(
+    table: &TopTable,
+    rows: &[(FrameLabel, u64)],
+    total: u64,
+    mode: TopMode,
+    pid_filter: Option<i32>,
+    row_count: usize,
+    recording: bool,
+) -> String {
+    let mut s = String::new();
+    // Move to the top left and clear the screen.
+    s.push_str("\x1b[H\x1b[2J");
+    let mode = match mode {
+        TopMode::Leaf => "leaf functions",
+        TopMode::Inclusive => "inclusive",
+    };
+    let processes = match pid_filter {
+        Some(pid) => match table.process_name(pid) {
+            Some(name) => format!("{name} ({pid})"),
+            None => format!("PID {pid}"),
+        },
+        None => "all processes".to_string(),
+    };
+    let _ = writeln!(
+        s,
+        "samply top: {total} samples in the last {} s, {mode}, {processes}",
+        RECENT_WINDOW_NS / 1_000_000_000
+    );
+    let status = match recording {
+        true => "",
+        false => "  (recording ended)",
+    };
+    let _ = writeln!(s, "q: quit  i: leaf/inclusive  p: next process{status}");
+    let _ = writeln!(s);
+    let _ = writeln!(s, "{:>7} {:>8}  function", "percent", "samples");
+    for (label, count) in rows.iter().take(row_count) {
+        let percentage = *count as f64 * 100.0 / total as f64;
+        let _ = write!(s, "{percentage:>6.1}% {count:>8}  {}", label.function);
+        if !label.lib.is_empty() {
+            let _ = write!(s, "  [{}]", label.lib);
+        }
+        let _ = writeln!(s);
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use debugid::DebugId;
+    use fxprof_processed_profile::{LibraryInfo, Profile, ReferenceTimestamp, SamplingInterval};
+
+    use super::*;
+
+    fn sample(pid: i32, timestamp: u64, frames: &[LiveFrame]) -> LiveSample {
+        LiveSample {
+            pid,
+            timestamp,
+            frames: frames.to_vec(),
+        }
+    }
+
+    #[test]
+    fn counts_leaf_and_inclusive_functions_in_the_window() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let lib = profile.add_lib(LibraryInfo {
+            name: "app".to_string(),
+            debug_name: "app".to_string(),
+            path: "/app".to_string(),
+            debug_path: "/app".to_string(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        });
+        let (main, work) = (LiveFrame::Lib(lib, 0x10), LiveFrame::Lib(lib, 0x20));
+        let mut table = TopTable::default();
+        table.add_batch(LiveBatch {
+            samples: vec![
+                sample(1, 1_000_000_000, &[main]),
+                sample(1, 7_000_000_000, &[work, main]),
+                sample(1, 8_000_000_000, &[work, work, main]),
+                sample(2, 9_000_000_000, &[LiveFrame::Kernel, main]),
+            ],
+            libs: Vec::new(),
+            process_names: Vec::new(),
+        });
+        table.expire(10_000_000_000);
+        assert_eq!(table.pids(), vec![1, 2]);
+
+        let label = |frame: &LiveFrame| FrameLabel {
+            function: match *frame {
+                LiveFrame::Lib(_, address) => format!("0x{address:x}"),
+                _ => "[kernel]".to_string(),
+            },
+            lib: String::new(),
+        };
+        let counts = |(rows, total): (Vec<(FrameLabel, u64)>, u64)| {
+            let rows: Vec<_> = rows
+                .into_iter()
+                .map(|(label, count)| (label.function, count))
+                .collect();
+            (rows, total)
+        };
+        assert_eq!(
+            counts(table.rows(TopMode::Leaf, None, label)),
+            (
+                vec![("0x20".to_string(), 2), ("[kernel]".to_string(), 1)],
+                3
+            )
+        );
+        assert_eq!(
+            counts(table.rows(TopMode::Inclusive, Some(1), label)),
+            (vec![("0x10".to_string(), 2), ("0x20".to_string(), 2)], 2)
+        );
+    }
+}
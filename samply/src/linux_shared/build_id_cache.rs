@@ -0,0 +1,89 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// perf's build ID caches, e.g. ~/.debug, which `perf record` and
+/// `perf buildid-cache -a` fill with copies of the recorded binaries. They're
+/// used for binaries which were updated or removed since the recording.
+#[derive(Debug, Clone, Default)]
+pub struct BuildIdCaches {
+    /// The cache directories, in the order in which they're searched.
+    dirs: Vec<PathBuf>,
+}
+
+impl BuildIdCaches {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self { dirs }
+    }
+
+    /// perf's default cache directory, ~/.debug, if it exists.
+    pub fn default_dirs() -> Vec<PathBuf> {
+        dirs::home_dir()
+            .map(|home_dir| home_dir.join(".debug"))
+            .filter(|dir| dir.is_dir())
+            .into_iter()
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    /// Opens the cached copy of the binary with this build ID from the first
+    /// cache which has it.
+    pub fn open(&self, build_id: &[u8]) -> Option<(std::fs::File, PathBuf)> {
+        self.dirs.iter().find_map(|dir| {
+            let path = path_in_cache(dir, build_id)?;
+            let file = std::fs::File::open(&path).ok()?;
+            Some((file, path))
+        })
+    }
+}
+
+/// The path of a binary in the cache: `.build-id/<xx>/<rest>/elf`, where
+/// `<xx><rest>` is the hex build ID. Older versions of perf put a symlink to
+/// the binary at `.build-id/<xx>/<rest>` instead of a directory.
+fn path_in_cache(dir: &Path, build_id: &[u8]) -> Option<PathBuf> {
+    let (first, rest) = build_id.split_first()?;
+    let mut rest_hex = String::with_capacity(rest.len() * 2);
+    for byte in rest {
+        write!(rest_hex, "{byte:02x}").unwrap();
+    }
+    let path = dir
+        .join(".build-id")
+        .join(format!("{first:02x}"))
+        .join(rest_hex);
+    if path.is_dir() {
+        Some(path.join("elf"))
+    } else {
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_binaries_by_build_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_id = [0xab, 0xcd, 0x01, 0x23];
+        let entry_dir = dir.path().join(".build-id/ab/cd0123");
+        std::fs::create_dir_all(&entry_dir).unwrap();
+        std::fs::write(entry_dir.join("elf"), b"ELF").unwrap();
+        let old_style_dir = dir.path().join(".build-id/12");
+        std::fs::create_dir_all(&old_style_dir).unwrap();
+        std::fs::write(old_style_dir.join("34"), b"ELF").unwrap();
+
+        let caches = BuildIdCaches::new(vec![dir.path().join("missing"), dir.path().to_owned()]);
+        assert_eq!(
+            caches.open(&build_id).map(|(_, path)| path),
+            Some(entry_dir.join("elf"))
+        );
+        assert_eq!(
+            caches.open(&[0x12, 0x34]).map(|(_, path)| path),
+            Some(old_style_dir.join("34"))
+        );
+        assert!(caches.open(&[0x12, 0x35]).is_none());
+        assert!(caches.open(&[]).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, ThreadHandle,
+};
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+use serde_json::json;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::unresolved_samples::UnresolvedStacks;
+
+const SCHED_CFS_THROTTLE: &str = "sched:sched_cfs_throttle";
+const SCHED_CFS_UNTHROTTLE: &str = "sched:sched_cfs_unthrottle";
+
+/// The fields which identify the throttled cgroup, in order of preference.
+/// Which of them the events have depends on the kernel.
+const CGROUP_FIELDS: &[&str] = &["cgroup_id", "cgrp_id", "path", "cgroup"];
+
+/// The name of the cgroup if its events don't have any of [`CGROUP_FIELDS`].
+const UNKNOWN_CGROUP: &str = "(unknown cgroup)";
+
+/// Handles the sched:sched_cfs_throttle and sched:sched_cfs_unthrottle
+/// tracepoints, and shows when a cgroup was throttled because it had used up
+/// its CPU quota (the CFS bandwidth controller's cpu.max).
+///
+/// A cgroup is throttled separately on each CPU, so the throttles and
+/// unthrottles of a cgroup are paired up like nested intervals: the cgroup
+/// counts as throttled from its first throttle until all of them have been
+/// undone. Unthrottles without a throttle, e.g. of a throttle from before the
+/// recording started, are ignored.
+///
+/// The throttle is hit by the task which used up the quota, so the process
+/// of each throttle sample is taken to be a member of the cgroup. The
+/// unthrottle comes from a timer and says nothing about the members. When
+/// all samples have been handled, every member process gets a
+/// "CFS throttled" interval marker on its main thread for each throttled
+/// period of its cgroup, and the total throttled time of each process is
+/// printed. Periods which are still throttled at the end of the profile
+/// become markers without an end, and count until the last throttle event.
+#[derive(Debug, Default)]
+pub struct CfsThrottleHandler {
+    cgroups: BTreeMap<String, CgroupThrottling>,
+    last_timestamp: u64,
+}
+
+#[derive(Debug, Default)]
+struct CgroupThrottling {
+    /// The number of throttles which haven't been undone yet.
+    depth: usize,
+    /// When the current throttled period started, if depth > 0.
+    start_timestamp: u64,
+    /// The finished throttled periods, as (start, end).
+    periods: Vec<(u64, u64)>,
+    /// The pid and main thread of each process which hit the throttle.
+    members: BTreeSet<(i32, ThreadHandle)>,
+}
+
+impl CfsThrottleHandler {
+    /// The value of the first of [`CGROUP_FIELDS`] which the event has,
+    /// without the quotes of string fields.
+    fn cgroup_name(ctx: &ConvertCtx, e: &SampleRecord) -> Option<String> {
+        let format = ctx.tracepoint_formats.get(ctx.attr_name)?;
+        let raw = e.raw?.as_slice();
+        let value = CGROUP_FIELDS
+            .iter()
+            .find_map(|field| format.decode_field(&raw, ctx.endian, field))?;
+        Some(value.trim_matches('"').to_string())
+    }
+
+    /// The throttled periods of each cgroup, with the periods which are still
+    /// throttled ending at `None`.
+    fn periods(&self) -> impl Iterator<Item = (&str, &CgroupThrottling, u64, Option<u64>)> {
+        self.cgroups.iter().flat_map(|(name, cgroup)| {
+            let open_period = (cgroup.depth > 0).then(|| (cgroup.start_timestamp, None));
+            cgroup
+                .periods
+                .iter()
+                .map(|&(start, end)| (start, Some(end)))
+                .chain(open_period)
+                .map(move |(start, end)| (name.as_str(), cgroup, start, end))
+        })
+    }
+
+    /// The total throttled time of each process, by pid, with its cgroup.
+    fn total_throttled_ns_by_pid(&self) -> BTreeMap<i32, (u64, &str)> {
+        let mut totals = BTreeMap::new();
+        for (name, cgroup, start, end) in self.periods() {
+            let duration = end.unwrap_or(self.last_timestamp).saturating_sub(start);
+            for &(pid, _) in &cgroup.members {
+                totals.entry(pid).or_insert((0, name)).0 += duration;
+            }
+        }
+        totals
+    }
+}
+
+impl TracepointHandler for CfsThrottleHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        matches!(attr_name, SCHED_CFS_THROTTLE | SCHED_CFS_UNTHROTTLE)
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(pid), Some(timestamp)) = (e.pid, e.timestamp) else {
+            return;
+        };
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        let cgroup = self
+            .cgroups
+            .entry(Self::cgroup_name(ctx, e).unwrap_or_else(|| UNKNOWN_CGROUP.to_string()))
+            .or_default();
+        if ctx.attr_name == SCHED_CFS_THROTTLE {
+            if cgroup.depth == 0 {
+                cgroup.start_timestamp = timestamp;
+            }
+            cgroup.depth += 1;
+            cgroup.members.insert((pid, ctx.main_thread));
+            return;
+        }
+        match cgroup.depth {
+            0 => {}
+            1 => {
+                cgroup.depth = 0;
+                cgroup.periods.push((cgroup.start_timestamp, timestamp));
+            }
+            _ => cgroup.depth -= 1,
+        }
+    }
+
+    fn flush(&mut self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        for (name, cgroup, start, end) in self.periods() {
+            let start_time = timestamp_converter.convert_time(start);
+            let timing = match end {
+                Some(end) => {
+                    MarkerTiming::Interval(start_time, timestamp_converter.convert_time(end))
+                }
+                None => MarkerTiming::IntervalStart(start_time),
+            };
+            for &(_, main_thread) in &cgroup.members {
+                profile.add_marker(
+                    main_thread,
+                    "CFS throttled",
+                    CfsThrottledMarker {
+                        cgroup: name.to_string(),
+                    },
+                    timing.clone(),
+                );
+            }
+        }
+    }
+
+    fn finish(&mut self, _unresolved_stacks: &UnresolvedStacks) {
+        let totals = self.total_throttled_ns_by_pid();
+        if totals.is_empty() {
+            return;
+        }
+        eprintln!("CFS bandwidth throttling, by total throttled time:");
+        eprintln!("  {:>8}  {:>12}  cgroup", "pid", "throttled");
+        let mut totals: Vec<_> = totals.into_iter().collect();
+        totals.sort_by(|(_, (a, _)), (_, (b, _))| b.cmp(a));
+        for (pid, (total_ns, cgroup)) in totals {
+            eprintln!(
+                "  {pid:>8}  {:>10.1}ms  {cgroup}",
+                total_ns as f64 / 1_000_000.0
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CfsThrottledMarker {
+    cgroup: String,
+}
+
+impl ProfilerMarker for CfsThrottledMarker {
+    const MARKER_TYPE_NAME: &'static str = "CfsThrottled";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "cgroup": self.cgroup,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("CFS throttled"),
+            tooltip_label: Some("CFS throttled: {marker.data.cgroup}"),
+            table_label: Some("CFS throttled: {marker.data.cgroup}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "cgroup",
+                    label: "Cgroup",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The process's cgroup had used up its CPU quota, so none of its \
+                            threads could run until the next CFS bandwidth period.",
+                }),
+            ],
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+
+use linux_perf_data::linux_perf_event_reader::Mmap2FileId;
+
+use crate::shared::types::StackFrame;
+
+/// The state which the converter of the next file of a rotating recording
+/// starts with, see [`Converter::checkpoint`](super::Converter::checkpoint):
+/// the live processes with their names, threads and executable mappings, but
+/// none of the samples or markers. This way the libraries which were loaded
+/// before a rotation are known in the later files, without new MMAP records.
+#[derive(Debug, Clone, Default)]
+pub struct ConverterCheckpoint {
+    pub processes: Vec<ProcessCheckpoint>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessCheckpoint {
+    pub pid: i32,
+    pub name: Option<String>,
+    pub threads: Vec<ThreadCheckpoint>,
+    pub mappings: Vec<CarriedMapping>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ThreadCheckpoint {
+    pub tid: i32,
+    pub name: Option<String>,
+    /// The stack at the switch-out, if the thread was off-CPU at the
+    /// checkpoint. The next file's off-CPU samples for the rest of the
+    /// off-CPU period get this stack.
+    pub off_cpu_stack: Option<Vec<StackFrame>>,
+}
+
+/// An executable mapping, as its MMAP2 record described it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarriedMapping {
+    pub address: u64,
+    pub length: u64,
+    pub page_offset: u64,
+    pub protection: u32,
+    pub flags: u32,
+    pub file_id: Mmap2FileId,
+    pub path: Vec<u8>,
+}
+
+impl CarriedMapping {
+    fn end(&self) -> u64 {
+        self.address.saturating_add(self.length)
+    }
+}
+
+/// The current executable mappings of each process, for checkpoints. A new
+/// mapping replaces the parts of older mappings which it overlaps, and an
+/// exec drops the process's mappings.
+#[derive(Debug, Default)]
+pub struct MappingTracker {
+    mappings_by_pid: BTreeMap<i32, BTreeMap<u64, CarriedMapping>>,
+}
+
+impl MappingTracker {
+    pub fn on_mmap(&mut self, pid: i32, mapping: CarriedMapping) {
+        let mappings = self.mappings_by_pid.entry(pid).or_default();
+        let (start, end) = (mapping.address, mapping.end());
+        let overlapped: Vec<u64> = mappings
+            .range(..end)
+            .rev()
+            .take_while(|(_, existing)| existing.end() > start)
+            .map(|(&address, _)| address)
+            .collect();
+        for address in overlapped {
+            let existing = mappings.remove(&address).unwrap();
+            if existing.address < start {
+                let mut before = existing.clone();
+                before.length = start - existing.address;
+                mappings.insert(before.address, before);
+            }
+            if existing.end() > end {
+                let mut after = existing.clone();
+                after.address = end;
+                after.length = existing.end() - end;
+                after.page_offset += end - existing.address;
+                mappings.insert(after.address, after);
+            }
+        }
+        mappings.insert(start, mapping);
+    }
+
+    /// Drops the mappings of a process which exec'd or exited.
+    pub fn on_process_image_gone(&mut self, pid: i32) {
+        self.mappings_by_pid.remove(&pid);
+    }
+
+    pub fn mappings(&self, pid: i32) -> Vec<CarriedMapping> {
+        self.mappings_by_pid
+            .get(&pid)
+            .map(|mappings| mappings.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use linux_perf_data::linux_perf_event_reader::Mmap2InodeAndVersion;
+
+    use super::*;
+
+    fn mapping(address: u64, length: u64, page_offset: u64, path: &str) -> CarriedMapping {
+        CarriedMapping {
+            address,
+            length,
+            page_offset,
+            protection: 0b101,
+            flags: 0b10,
+            file_id: Mmap2FileId::InodeAndVersion(Mmap2InodeAndVersion {
+                major: 0,
+                minor: 0,
+                inode: 0,
+                inode_generation: 0,
+            }),
+            path: path.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn new_mappings_replace_the_parts_they_overlap() {
+        let mut tracker = MappingTracker::default();
+        tracker.on_mmap(1, mapping(0x1000, 0x4000, 0, "/lib/a.so"));
+        tracker.on_mmap(1, mapping(0x8000, 0x1000, 0, "/lib/b.so"));
+        tracker.on_mmap(2, mapping(0x1000, 0x1000, 0, "/lib/c.so"));
+        // Overlaps the middle of a.so.
+        tracker.on_mmap(1, mapping(0x2000, 0x1000, 0, "/jit"));
+        assert_eq!(
+            tracker.mappings(1),
+            [
+                mapping(0x1000, 0x1000, 0, "/lib/a.so"),
+                mapping(0x2000, 0x1000, 0, "/jit"),
+                mapping(0x3000, 0x2000, 0x2000, "/lib/a.so"),
+                mapping(0x8000, 0x1000, 0, "/lib/b.so"),
+            ]
+        );
+
+        // Covers b.so and the end of a.so.
+        tracker.on_mmap(1, mapping(0x4000, 0x6000, 0, "/lib/d.so"));
+        assert_eq!(
+            tracker.mappings(1),
+            [
+                mapping(0x1000, 0x1000, 0, "/lib/a.so"),
+                mapping(0x2000, 0x1000, 0, "/jit"),
+                mapping(0x3000, 0x1000, 0x2000, "/lib/a.so"),
+                mapping(0x4000, 0x6000, 0, "/lib/d.so"),
+            ]
+        );
+
+        tracker.on_process_image_gone(1);
+        assert!(tracker.mappings(1).is_empty());
+        assert_eq!(tracker.mappings(2).len(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+/// The compression formats that distros use for kernel modules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModuleCompression {
+    Xz,
+    Zstd,
+}
+
+impl ModuleCompression {
+    const ALL: [ModuleCompression; 2] = [ModuleCompression::Xz, ModuleCompression::Zstd];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            ModuleCompression::Xz => ".xz",
+            ModuleCompression::Zstd => ".zst",
+        }
+    }
+
+    fn from_path(path: &Path) -> Option<Self> {
+        let path = path.to_str()?;
+        Self::ALL
+            .into_iter()
+            .find(|compression| path.ends_with(&format!(".ko{}", compression.suffix())))
+    }
+
+    fn decompress(self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut decompressed = Vec::new();
+        match self {
+            ModuleCompression::Xz => {
+                lzma_rs::xz_decompress(&mut std::io::Cursor::new(data), &mut decompressed)
+                    .map_err(|err| err.to_string())?;
+            }
+            ModuleCompression::Zstd => {
+                let mut data = data;
+                ruzstd::StreamingDecoder::new(&mut data)
+                    .map_err(|err| err.to_string())?
+                    .read_to_end(&mut decompressed)
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Decompresses `.ko.xz` and `.ko.zst` kernel modules into plain ELF files, so
+/// that we can read their build IDs and symbolicate them like uncompressed modules.
+///
+/// Decompressed modules are written to a directory in the system's temp directory,
+/// with file names derived from a hash of the compressed contents. This way, the same
+/// module is only decompressed once, even across runs, and a module which changes
+/// on disk (e.g. after a kernel update) doesn't pick up a stale decompressed file.
+#[derive(Debug)]
+pub struct CompressedModuleCache {
+    cache_dir: PathBuf,
+    decompressed_paths: HashMap<PathBuf, Option<PathBuf>>,
+}
+
+impl Default for CompressedModuleCache {
+    fn default() -> Self {
+        Self::new(std::env::temp_dir().join("samply-kernel-modules"))
+    }
+}
+
+impl CompressedModuleCache {
+    pub fn new(cache_dir: PathBuf) -> Self {
+        Self {
+            cache_dir,
+            decompressed_paths: HashMap::new(),
+        }
+    }
+
+    /// Returns the path to a decompressed copy of the kernel module at `path`, if
+    /// the module is compressed.
+    ///
+    /// `path` can either be the path of the compressed file, or the path of the
+    /// uncompressed `.ko` file if only the compressed file exists on disk.
+    ///
+    /// Returns `None` if the module isn't compressed or if decompression fails. In
+    /// the latter case, a warning is printed.
+    pub fn decompressed_path(&mut self, path: &Path) -> Option<PathBuf> {
+        if let Some(decompressed_path) = self.decompressed_paths.get(path) {
+            return decompressed_path.clone();
+        }
+        let decompressed_path = self.decompress(path);
+        self.decompressed_paths
+            .insert(path.to_owned(), decompressed_path.clone());
+        decompressed_path
+    }
+
+    fn decompress(&self, path: &Path) -> Option<PathBuf> {
+        let (compressed_path, compression) = find_compressed_module(path)?;
+        match self.decompress_to_cache(&compressed_path, compression) {
+            Ok(decompressed_path) => Some(decompressed_path),
+            Err(err) => {
+                warn!(
+                    path = %compressed_path.display(),
+                    error = %err,
+                    "Could not decompress kernel module {}: {err}",
+                    compressed_path.display()
+                );
+                None
+            }
+        }
+    }
+
+    fn decompress_to_cache(
+        &self,
+        compressed_path: &Path,
+        compression: ModuleCompression,
+    ) -> Result<PathBuf, String> {
+        let data = std::fs::read(compressed_path).map_err(|err| err.to_string())?;
+        let file_name = compressed_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(compression.suffix()))
+            .ok_or_else(|| "Unexpected file name".to_string())?;
+        let hash = fxhash::hash64(&data);
+        let decompressed_path = self.cache_dir.join(format!("{hash:016x}-{file_name}"));
+        if decompressed_path.exists() {
+            return Ok(decompressed_path);
+        }
+
+        let decompressed = compression.decompress(&data)?;
+        std::fs::create_dir_all(&self.cache_dir).map_err(|err| err.to_string())?;
+        // Write to a temporary file first so that a concurrent samply instance never
+        // sees a partially written module.
+        let mut temp_file =
+            tempfile::NamedTempFile::new_in(&self.cache_dir).map_err(|err| err.to_string())?;
+        std::io::Write::write_all(&mut temp_file, &decompressed).map_err(|err| err.to_string())?;
+        temp_file
+            .persist(&decompressed_path)
+            .map_err(|err| err.to_string())?;
+        Ok(decompressed_path)
+    }
+}
+
+/// Finds the compressed module file for `path`, which is either the path of a
+/// compressed module or the path of an uncompressed module which doesn't exist on
+/// disk but has a compressed sibling.
+fn find_compressed_module(path: &Path) -> Option<(PathBuf, ModuleCompression)> {
+    if let Some(compression) = ModuleCompression::from_path(path) {
+        return Some((path.to_owned(), compression));
+    }
+    if path.extension()? != "ko" || path.exists() {
+        return None;
+    }
+    ModuleCompression::ALL.into_iter().find_map(|compression| {
+        let mut compressed_path = path.as_os_str().to_owned();
+        compressed_path.push(compression.suffix());
+        let compressed_path = PathBuf::from(compressed_path);
+        compressed_path
+            .exists()
+            .then(|| (compressed_path, compression))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // "hello module", compressed with `zstd`.
+    const ZSTD_DATA: &[u8] = &[
+        0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58, 0x61, 0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20,
+        0x6d, 0x6f, 0x64, 0x75, 0x6c, 0x65, 0xc2, 0x36, 0xdb, 0x0e,
+    ];
+
+    fn xz_data(contents: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        lzma_rs::xz_compress(&mut std::io::Cursor::new(contents), &mut compressed).unwrap();
+        compressed
+    }
+
+    #[test]
+    fn decompresses_xz_and_zstd_modules() {
+        let modules_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let xz_path = modules_dir.path().join("a.ko.xz");
+        let zstd_path = modules_dir.path().join("b.ko.zst");
+        std::fs::write(&xz_path, xz_data(b"hello module")).unwrap();
+        std::fs::write(&zstd_path, ZSTD_DATA).unwrap();
+
+        let mut cache = CompressedModuleCache::new(cache_dir.path().to_owned());
+        let decompressed = cache.decompressed_path(&xz_path).unwrap();
+        assert!(decompressed.to_str().unwrap().ends_with("-a.ko"));
+        assert_eq!(std::fs::read(decompressed).unwrap(), b"hello module");
+        let decompressed = cache.decompressed_path(&zstd_path).unwrap();
+        assert!(decompressed.to_str().unwrap().ends_with("-b.ko"));
+        assert_eq!(std::fs::read(decompressed).unwrap(), b"hello module");
+    }
+
+    #[test]
+    fn finds_compressed_sibling_of_missing_module() {
+        let modules_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        std::fs::write(modules_dir.path().join("a.ko.zst"), ZSTD_DATA).unwrap();
+        std::fs::write(modules_dir.path().join("b.ko"), b"plain module").unwrap();
+
+        let mut cache = CompressedModuleCache::new(cache_dir.path().to_owned());
+        let decompressed = cache
+            .decompressed_path(&modules_dir.path().join("a.ko"))
+            .unwrap();
+        assert_eq!(std::fs::read(decompressed).unwrap(), b"hello module");
+        assert_eq!(
+            cache.decompressed_path(&modules_dir.path().join("b.ko")),
+            None
+        );
+    }
+
+    #[test]
+    fn cache_is_keyed_by_content() {
+        let modules_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let path = modules_dir.path().join("a.ko.xz");
+        std::fs::write(&path, xz_data(b"old module")).unwrap();
+        let first = CompressedModuleCache::new(cache_dir.path().to_owned())
+            .decompressed_path(&path)
+            .unwrap();
+        std::fs::write(&path, xz_data(b"new module")).unwrap();
+        let second = CompressedModuleCache::new(cache_dir.path().to_owned())
+            .decompressed_path(&path)
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(std::fs::read(second).unwrap(), b"new module");
+    }
+
+    #[test]
+    fn corrupt_module_is_not_decompressed() {
+        let modules_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let path = modules_dir.path().join("a.ko.zst");
+        std::fs::write(&path, b"not zstd").unwrap();
+        let mut cache = CompressedModuleCache::new(cache_dir.path().to_owned());
+        assert_eq!(cache.decompressed_path(&path), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use tracing::{debug, info};
+
+/// Copies the binaries which processes in other mount namespaces map, e.g.
+/// the processes of a container launched with `samply record podman run`.
+///
+/// The paths in their mmap records are paths in the container's file
+/// system, so they're read through /proc/<pid>/root while the process is
+/// alive. The copies keep the path inside the container, under a directory
+/// per mount namespace, and the profile's libraries point at them, so that
+/// symbolication still finds them after the container is gone.
+#[derive(Debug)]
+pub struct ContainerBinaries {
+    dir: PathBuf,
+    /// The link target of /proc/self/ns/mnt, e.g. "mnt:[4026531841]".
+    own_mount_namespace: Option<PathBuf>,
+    /// The copy of each (mount namespace, path), or None if it failed.
+    copies: HashMap<(PathBuf, PathBuf), Option<PathBuf>>,
+}
+
+impl ContainerBinaries {
+    /// The directory is created when the first binary is copied.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            own_mount_namespace: std::fs::read_link("/proc/self/ns/mnt").ok(),
+            copies: HashMap::new(),
+        }
+    }
+
+    /// The directory next to a recorded profile which its container binaries
+    /// are copied to.
+    pub fn dir_for_profile(output_file: &Path) -> PathBuf {
+        let mut name = output_file.file_name().unwrap_or_default().to_owned();
+        name.push(".binaries");
+        output_file.with_file_name(name)
+    }
+
+    /// Returns the path of the copy if the process is in a different mount
+    /// namespace, and None if the path can be opened as it is.
+    pub fn copy_if_in_container(&mut self, pid: i32, path: &Path) -> Option<PathBuf> {
+        let own_mount_namespace = self.own_mount_namespace.as_ref()?;
+        let relative_path = path.strip_prefix("/").ok()?;
+        if !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+        let mount_namespace = std::fs::read_link(format!("/proc/{pid}/ns/mnt")).ok()?;
+        if &mount_namespace == own_mount_namespace {
+            return None;
+        }
+        let key = (mount_namespace, path.to_owned());
+        if let Some(copy) = self.copies.get(&key) {
+            return copy.clone();
+        }
+        let copy = namespace_dir_name(&key.0).and_then(|dir_name| {
+            let source = Path::new("/proc")
+                .join(pid.to_string())
+                .join("root")
+                .join(relative_path);
+            let destination = self.dir.join(dir_name).join(relative_path);
+            match copy_file(&source, &destination) {
+                Ok(()) => Some(destination),
+                Err(err) => {
+                    debug!(path = %path.display(), "Could not copy {} from pid {pid}'s mount namespace: {err}", path.display());
+                    None
+                }
+            }
+        });
+        self.copies.insert(key, copy.clone());
+        copy
+    }
+
+    pub fn report(&self) {
+        let count = self.copies.values().filter(|copy| copy.is_some()).count();
+        if count != 0 {
+            info!(
+                count,
+                dir = %self.dir.display(),
+                "Copied {count} binaries from other mount namespaces, e.g. containers, to {}.",
+                self.dir.display()
+            );
+        }
+    }
+}
+
+/// "mnt:[4026532291]" becomes "mnt-4026532291".
+fn namespace_dir_name(mount_namespace: &Path) -> Option<String> {
+    let link = mount_namespace.to_str()?;
+    let inode = link.strip_prefix("mnt:[")?.strip_suffix(']')?;
+    inode
+        .bytes()
+        .all(|b| b.is_ascii_digit())
+        .then(|| format!("mnt-{inode}"))
+}
+
+fn copy_file(source: &Path, destination: &Path) -> std::io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(source, destination)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_the_copies_by_namespace_and_profile() {
+        assert_eq!(
+            namespace_dir_name(Path::new("mnt:[4026532291]")).as_deref(),
+            Some("mnt-4026532291")
+        );
+        assert_eq!(namespace_dir_name(Path::new("mnt:[../x]")), None);
+        assert_eq!(
+            ContainerBinaries::dir_for_profile(Path::new("/tmp/profile.json.gz")),
+            Path::new("/tmp/profile.json.gz.binaries")
+        );
+
+        let mut binaries = ContainerBinaries::new(PathBuf::from("/nonexistent"));
+        let own_pid = std::process::id() as i32;
+        assert_eq!(
+            binaries.copy_if_in_container(own_pid, Path::new("/bin/sh")),
+            None
+        );
+    }
+}
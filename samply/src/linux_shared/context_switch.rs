@@ -61,8 +61,9 @@ impl ContextSwitchHandler {
             } => {
                 // The thread was running and is now context-switched out.
                 // Accumulate the running time since we last saw it. This delta will be picked
-                // up by the next sample we emit.
-                let on_duration = timestamp - last_observed_on_timestamp;
+                // up by the next sample we emit. A switch record which arrived out of order
+                // doesn't add any time.
+                let on_duration = timestamp.saturating_sub(*last_observed_on_timestamp);
                 thread.on_cpu_duration_since_last_sample += on_duration;
 
                 thread.state = ThreadState::Off {
@@ -89,17 +90,13 @@ impl ContextSwitchHandler {
         thread: &mut ThreadContextSwitchData,
     ) -> Option<OffCpuSampleGroup> {
         let off_cpu_sample = match thread.state {
-            ThreadState::On {
-                last_observed_on_timestamp,
-            } => {
-                // We are already in the On state, most likely due to a Sample record which
-                // arrived just before the Switch-In record.
-                // This is quite normal. Thread switching is done by some kernel code which
-                // executes on the CPU, and this CPU work can get sampled before the CPU gets
-                // to the code that emits the Switch-In record.
-                let on_duration = timestamp - last_observed_on_timestamp;
-                thread.on_cpu_duration_since_last_sample += on_duration;
-
+            ThreadState::On { .. } => {
+                // We are already in the On state. This happens if the thread was first
+                // observed through a sample, or if a Switch-In record was duplicated.
+                // Either way, a switch-out that we didn't see must have happened since
+                // we last saw the thread running, and we don't know when. Don't credit
+                // any running time for this period; we'd rather under-count CPU time
+                // than attribute a sleep to the CPU.
                 None
             }
             ThreadState::Off {
@@ -107,7 +104,7 @@ impl ContextSwitchHandler {
             } => {
                 // The thread was sleeping and is now starting to run again.
                 // Accumulate the off-cpu time.
-                let off_duration = timestamp - off_switch_timestamp;
+                let off_duration = timestamp.saturating_sub(off_switch_timestamp);
                 thread.off_cpu_duration_since_last_off_cpu_sample += off_duration;
 
                 // We just added some off-cpu time. If the accumulated off-cpu time exceeds the
@@ -133,50 +130,42 @@ impl ContextSwitchHandler {
         off_cpu_sample
     }
 
-    pub fn handle_sample(
-        &self,
-        timestamp: u64,
-        thread: &mut ThreadContextSwitchData,
-    ) -> Option<OffCpuSampleGroup> {
-        let off_cpu_sample = match thread.state {
+    /// Accounts for an on-CPU sample and returns the sample's CPU delta, i.e. the
+    /// running time accumulated since the last consumed delta.
+    pub fn handle_sample(&self, timestamp: u64, thread: &mut ThreadContextSwitchData) -> u64 {
+        match thread.state {
             ThreadState::On {
                 last_observed_on_timestamp,
             } => {
                 // The last time we heard from this thread, it was already running.
                 // Accumulate the running time.
-                let on_duration = timestamp - last_observed_on_timestamp;
+                let on_duration = timestamp.saturating_sub(last_observed_on_timestamp);
                 thread.on_cpu_duration_since_last_sample += on_duration;
-
-                None
+                thread.state = ThreadState::On {
+                    last_observed_on_timestamp: timestamp,
+                };
             }
-            ThreadState::Off {
-                off_switch_timestamp,
-            } => {
-                // The last time we heard from this thread, it was being context switched away from.
-                // We are processing a sample on it so we know it is running again. Treat this sample
-                // as a switch-in event.
-                let off_duration = timestamp - off_switch_timestamp;
-                thread.off_cpu_duration_since_last_off_cpu_sample += off_duration;
-
-                // We just added some off-cpu time. If the accumulated off-cpu time exceeds the
-                // off-cpu sampling interval, we want to consume some of it and turn it into an
-                // off-cpu sampling group.
-                self.maybe_consume_off_cpu(timestamp, thread)
+            ThreadState::Off { .. } => {
+                // The last time we heard from this thread, it was being context switched
+                // away from. Samples in this state are taken in the scheduler code around
+                // the context switch, either just after the Switch-Out record or just
+                // before the Switch-In record, and we can't tell which one it is.
+                // Stay in the Off state and let the Switch-In record account for the
+                // entire off-cpu period. If we treated this sample as a switch-in, the
+                // time until the actual Switch-In record would be counted as running
+                // time, even if the thread spent all of it sleeping.
             }
             ThreadState::Unknown => {
                 // This sample is the first time we've ever head from a thread.
                 // We don't know whether it was running or sleeping.
-                // Do nothing. The first sample will have a CPU delta of 0.
-
-                None
+                // The first sample will have a CPU delta of 0.
+                thread.state = ThreadState::On {
+                    last_observed_on_timestamp: timestamp,
+                };
             }
-        };
-
-        thread.state = ThreadState::On {
-            last_observed_on_timestamp: timestamp,
-        };
+        }
 
-        off_cpu_sample
+        self.consume_cpu_delta(thread)
     }
 
     fn maybe_consume_off_cpu(
@@ -217,8 +206,34 @@ impl ContextSwitchHandler {
         })
     }
 
+    /// Splits an off-CPU sample group into two halves, with the extra sample
+    /// of an odd count in the first half. A group of one sample isn't split.
+    pub fn split_group(
+        &self,
+        group: OffCpuSampleGroup,
+    ) -> (OffCpuSampleGroup, Option<OffCpuSampleGroup>) {
+        if group.sample_count < 2 {
+            return (group, None);
+        }
+        let interval = self.off_cpu_sampling_interval_ns;
+        let first_count = (group.sample_count + 1) / 2;
+        let first = OffCpuSampleGroup {
+            begin_timestamp: group.begin_timestamp,
+            end_timestamp: group.begin_timestamp + (first_count - 1) * interval,
+            sample_count: first_count,
+        };
+        let second = OffCpuSampleGroup {
+            begin_timestamp: group.begin_timestamp + first_count * interval,
+            end_timestamp: group.end_timestamp,
+            sample_count: group.sample_count - first_count,
+        };
+        (first, Some(second))
+    }
+
+    /// Takes the running time accumulated since the last consumed delta. Every
+    /// nanosecond of running time is returned by exactly one call to this method.
     pub fn consume_cpu_delta(&self, thread: &mut ThreadContextSwitchData) -> u64 {
-        std::mem::replace(&mut thread.on_cpu_duration_since_last_sample, 0)
+        std::mem::take(&mut thread.on_cpu_duration_since_last_sample)
     }
 }
 
@@ -229,13 +244,25 @@ pub struct OffCpuSampleGroup {
     pub sample_count: u64,
 }
 
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
+/// The per-thread state of the [`ContextSwitchHandler`].
+///
+/// This is deliberately not `Clone`: it holds running time which hasn't been
+/// consumed as a CPU delta yet, and a copy would allow that time to be consumed
+/// a second time.
+#[derive(Default, Debug)]
 pub struct ThreadContextSwitchData {
     state: ThreadState,
     on_cpu_duration_since_last_sample: u64,
     off_cpu_duration_since_last_off_cpu_sample: u64,
 }
 
+impl ThreadContextSwitchData {
+    /// Whether the last thing we heard from this thread was a switch-out.
+    pub fn is_off_cpu(&self) -> bool {
+        matches!(self.state, ThreadState::Off { .. })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum ThreadState {
     Unknown,
@@ -275,9 +302,7 @@ mod test {
         handler.handle_switch_out(3, &mut thread);
         let s = handler.handle_switch_in(5, &mut thread);
         assert_eq!(s, None);
-        let s = handler.handle_sample(12, &mut thread);
-        let delta = handler.consume_cpu_delta(&mut thread);
-        assert_eq!(s, None);
+        let delta = handler.handle_sample(12, &mut thread);
         assert_eq!(delta, 10);
         handler.handle_switch_out(13, &mut thread);
         let s = handler.handle_switch_in(15, &mut thread);
@@ -309,13 +334,143 @@ mod test {
         );
         let delta = handler.consume_cpu_delta(&mut thread);
         assert_eq!(delta, 3);
-        let s = handler.handle_sample(51, &mut thread);
-        let delta = handler.consume_cpu_delta(&mut thread);
-        assert_eq!(s, None);
+        let delta = handler.handle_sample(51, &mut thread);
         assert_eq!(delta, 3);
-        let s = handler.handle_sample(61, &mut thread);
-        let delta = handler.consume_cpu_delta(&mut thread);
-        assert_eq!(s, None);
+        let delta = handler.handle_sample(61, &mut thread);
         assert_eq!(delta, 10);
     }
+
+    /// The records a thread can receive, for driving the handler in tests.
+    enum Event {
+        SwitchIn(u64),
+        SwitchOut(u64),
+        Sample(u64),
+    }
+
+    /// Feeds the events to the handler and returns all CPU deltas, in the order in
+    /// which they were consumed. Every emitted off-cpu sample group consumes a delta,
+    /// just like the converter does for threads with an off-cpu stack.
+    ///
+    /// Also asserts that the sum of the CPU deltas never exceeds the time the thread
+    /// was switched in, according to the Switch-In and Switch-Out records.
+    fn run_events(events: &[Event]) -> Vec<u64> {
+        let mut thread = ThreadContextSwitchData::default();
+        let handler = ContextSwitchHandler::new(10);
+        let mut deltas = Vec::new();
+        let mut switched_in_since = None;
+        let mut switched_in_duration = 0;
+        for event in events {
+            match *event {
+                Event::SwitchIn(timestamp) => {
+                    if handler.handle_switch_in(timestamp, &mut thread).is_some() {
+                        deltas.push(handler.consume_cpu_delta(&mut thread));
+                    }
+                    switched_in_since.get_or_insert(timestamp);
+                }
+                Event::SwitchOut(timestamp) => {
+                    handler.handle_switch_out(timestamp, &mut thread);
+                    if let Some(since) = switched_in_since.take() {
+                        switched_in_duration += timestamp - since;
+                    }
+                }
+                Event::Sample(timestamp) => {
+                    deltas.push(handler.handle_sample(timestamp, &mut thread));
+                    if let Some(since) = switched_in_since.as_mut() {
+                        switched_in_duration += timestamp - *since;
+                        *since = timestamp;
+                    }
+                }
+            }
+            let delta_sum: u64 = deltas.iter().sum();
+            assert!(
+                delta_sum <= switched_in_duration,
+                "CPU deltas add up to {delta_sum}, but the thread was only switched in for {switched_in_duration}"
+            );
+        }
+        deltas
+    }
+
+    #[test]
+    fn sample_after_switch_out_does_not_count_sleep_as_running() {
+        // A sample of the scheduler code arrives right after the Switch-Out record.
+        // The thread then sleeps until 100. The sleep must not be counted as
+        // running time.
+        use Event::*;
+        let deltas = run_events(&[
+            SwitchIn(0),
+            Sample(10),
+            SwitchOut(12),
+            Sample(13),
+            SwitchIn(100),
+            Sample(110),
+        ]);
+        assert_eq!(deltas, vec![10, 2, 0, 10]);
+    }
+
+    #[test]
+    fn sample_before_switch_in_does_not_count_sleep_as_running() {
+        // A sample of the scheduler code arrives right before the Switch-In record.
+        use Event::*;
+        let deltas = run_events(&[
+            SwitchIn(0),
+            SwitchOut(5),
+            Sample(49),
+            SwitchIn(50),
+            SwitchOut(52),
+            SwitchIn(53),
+            Sample(60),
+        ]);
+        assert_eq!(deltas, vec![5, 0, 9]);
+    }
+
+    #[test]
+    fn consumed_delta_is_not_observed_again() {
+        // A sample immediately followed by switch-out and switch-in, without an
+        // intervening sample.
+        use Event::*;
+        let deltas = run_events(&[
+            SwitchIn(0),
+            Sample(7),
+            SwitchOut(8),
+            SwitchIn(30),
+            SwitchOut(31),
+            SwitchIn(60),
+            Sample(65),
+        ]);
+        assert_eq!(deltas, vec![7, 1, 1, 5]);
+    }
+
+    #[test]
+    fn split_group_into_halves() {
+        let handler = ContextSwitchHandler::new(10);
+        let group = OffCpuSampleGroup {
+            begin_timestamp: 105,
+            end_timestamp: 145,
+            sample_count: 5,
+        };
+        let (first, second) = handler.split_group(group);
+        assert_eq!(
+            first,
+            OffCpuSampleGroup {
+                begin_timestamp: 105,
+                end_timestamp: 125,
+                sample_count: 3
+            }
+        );
+        assert_eq!(
+            second,
+            Some(OffCpuSampleGroup {
+                begin_timestamp: 135,
+                end_timestamp: 145,
+                sample_count: 2
+            })
+        );
+
+        let group = OffCpuSampleGroup {
+            begin_timestamp: 5,
+            end_timestamp: 5,
+            sample_count: 1,
+        };
+        assert_eq!(handler.split_group(group.clone()), (group, None));
+    }
 }
@@ -0,0 +1,505 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use linux_perf_data::linux_perf_event_reader::RecordType;
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::cli_error::CliError;
+
+/// The version of the log format, in the log's first entry.
+const LOG_VERSION: u32 = 1;
+
+/// Writes the decisions of a conversion which affect how addresses are
+/// attributed to libraries, for `--record-conversion-log`: the mapping of
+/// each module load with the inputs of its base address, the module loads
+/// which were skipped, and the number of records per type. No sample
+/// contents and no paths are written, only hashes of the paths, so the log
+/// can be shared when the perf.data file can't.
+///
+/// The log is a JSON object per line. `samply explain-log` prints it and
+/// checks it for inconsistencies, see [`check_log`].
+pub struct ConversionLog {
+    /// None after a write error.
+    writer: Option<Box<dyn Write + Send>>,
+    record_counts: BTreeMap<u32, u64>,
+}
+
+impl ConversionLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        let mut log = Self {
+            writer: Some(writer),
+            record_counts: BTreeMap::new(),
+        };
+        log.write(&LogEntry::Start {
+            version: LOG_VERSION,
+        });
+        log
+    }
+
+    pub fn count_record(&mut self, record_type: RecordType) {
+        *self.record_counts.entry(record_type.0).or_default() += 1;
+    }
+
+    pub fn add_module(&mut self, module: &LoggedModule, outcome: ModuleOutcome) {
+        self.write(&LogEntry::Module {
+            module: module.clone(),
+            outcome,
+        });
+    }
+
+    /// Writes the record counts and flushes the log.
+    pub fn finish(mut self) {
+        let counts = self
+            .record_counts
+            .iter()
+            .map(|(record_type, count)| (format!("{:?}", RecordType(*record_type)), *count))
+            .collect();
+        self.write(&LogEntry::RecordCounts { counts });
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(err) = writer.flush() {
+                warn!("Could not write the conversion log: {err}");
+            }
+        }
+    }
+
+    fn write(&mut self, entry: &LogEntry) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let result = serde_json::to_writer(&mut *writer, entry)
+            .map_err(io::Error::from)
+            .and_then(|()| writer.write_all(b"\n"));
+        if let Err(err) = result {
+            warn!("Could not write the conversion log: {err}");
+            self.writer = None;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LogEntry {
+    Start {
+        version: u32,
+    },
+    Module {
+        #[serde(flatten)]
+        module: LoggedModule,
+        outcome: ModuleOutcome,
+    },
+    /// The number of converted records by type, at the end of the log.
+    RecordCounts {
+        counts: BTreeMap<String, u64>,
+    },
+}
+
+/// A file mapping which a module load was attempted for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoggedModule {
+    pub pid: i32,
+    /// The perf timestamp of the mapping.
+    pub timestamp: u64,
+    /// The hash of the mapped path, see [`path_hash`].
+    pub path_hash: String,
+    pub build_id: Option<String>,
+    pub file_offset: u64,
+    pub start_avma: u64,
+    pub size: u64,
+}
+
+impl LoggedModule {
+    pub fn new(
+        pid: i32,
+        timestamp: u64,
+        path: &[u8],
+        build_id: Option<&[u8]>,
+        file_offset: u64,
+        start_avma: u64,
+        size: u64,
+    ) -> Self {
+        Self {
+            pid,
+            timestamp,
+            path_hash: path_hash(path),
+            build_id: build_id.map(|id| id.iter().map(|byte| format!("{byte:02x}")).collect()),
+            file_offset,
+            start_avma,
+            size,
+        }
+    }
+
+    fn end_avma(&self) -> u64 {
+        self.start_avma + self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModuleOutcome {
+    /// The mapping was added. Relative addresses are addresses minus
+    /// `base_avma`.
+    Mapped {
+        base: BaseSource,
+        /// The relative address base of the binary, if it was opened.
+        base_svma: Option<u64>,
+        base_avma: u64,
+    },
+    Skipped(SkipReason),
+}
+
+/// Where the base address of a mapping came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BaseSource {
+    /// From the segment of the binary which contains the mapped file range.
+    Bias,
+    /// From the mapping of the PE header, for Wine.
+    PeHeader,
+    /// The binary couldn't be opened, so its addresses were assumed to be
+    /// its file offsets.
+    Guess,
+    /// A device mapping, whose relative addresses are file offsets.
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The binary couldn't be mapped into memory or parsed.
+    Unparseable,
+    BuildIdMismatch,
+    /// The mapping has a build ID but the binary has none.
+    MissingBuildId,
+    /// No segment of the binary contains the mapped file range.
+    NoBias,
+    /// The binary has no identifier to derive a debug ID from.
+    NoDebugId,
+}
+
+/// A stable hash of a path: the 64-bit FNV-1a hash, in hex.
+pub fn path_hash(path: &[u8]) -> String {
+    let hash = path.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{hash:016x}")
+}
+
+pub fn read_log(reader: impl BufRead) -> Result<Vec<LogEntry>, String> {
+    let mut entries = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|err| err.to_string())?;
+        if line.is_empty() {
+            continue;
+        }
+        let entry =
+            serde_json::from_str(&line).map_err(|err| format!("line {}: {err}", index + 1))?;
+        entries.push(entry);
+    }
+    match entries.first() {
+        Some(LogEntry::Start { version }) if *version == LOG_VERSION => Ok(entries),
+        Some(LogEntry::Start { version }) => Err(format!("unsupported log version {version}")),
+        _ => Err("not a conversion log".to_string()),
+    }
+}
+
+/// An inconsistency in a conversion log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogIssue {
+    /// A mapping partially overlaps an earlier mapping of a different file
+    /// in the same process, so the rest of the earlier mapping is still
+    /// attributed to its file.
+    OverlappingMappings {
+        earlier: LoggedModule,
+        later: LoggedModule,
+    },
+    /// Two mappings of the same file range of a build have different
+    /// relative addresses.
+    BiasDisagreement {
+        first: LoggedModule,
+        first_relative_address: u64,
+        other: LoggedModule,
+        other_relative_address: u64,
+    },
+    /// The base address was guessed for a mapping which doesn't start at
+    /// the beginning of the file, so the relative addresses are only right
+    /// if the binary's addresses are its file offsets.
+    GuessedBase { module: LoggedModule },
+    /// The mapping's relative addresses don't fit into 32 bits.
+    RelativeAddressOutOfRange {
+        module: LoggedModule,
+        base_avma: u64,
+    },
+}
+
+impl fmt::Display for LogIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogIssue::OverlappingMappings { earlier, later } => write!(
+                f,
+                "pid {}: mapping {} of file {} partially overlaps mapping {} of file {}",
+                later.pid,
+                MappingRange(later),
+                later.path_hash,
+                MappingRange(earlier),
+                earlier.path_hash
+            ),
+            LogIssue::BiasDisagreement {
+                first,
+                first_relative_address,
+                other,
+                other_relative_address,
+            } => write!(
+                f,
+                "build {}: file offset 0x{:x} is at relative address 0x{:x} in pid {}, \
+                 but at 0x{:x} in pid {}",
+                first.build_id.as_deref().unwrap_or_default(),
+                first.file_offset,
+                first_relative_address,
+                first.pid,
+                other_relative_address,
+                other.pid
+            ),
+            LogIssue::GuessedBase { module } => write!(
+                f,
+                "pid {}: the base address of mapping {} of file {} at file offset 0x{:x} \
+                 was guessed because the file couldn't be opened",
+                module.pid,
+                MappingRange(module),
+                module.path_hash,
+                module.file_offset
+            ),
+            LogIssue::RelativeAddressOutOfRange { module, base_avma } => write!(
+                f,
+                "pid {}: mapping {} of file {} has base address 0x{base_avma:x}, so its \
+                 relative addresses don't fit into 32 bits",
+                module.pid,
+                MappingRange(module),
+                module.path_hash
+            ),
+        }
+    }
+}
+
+struct MappingRange<'a>(&'a LoggedModule);
+
+impl fmt::Display for MappingRange<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:x}..0x{:x}", self.0.start_avma, self.0.end_avma())
+    }
+}
+
+/// Checks the mappings of a log for overlaps, disagreeing biases and
+/// suspicious base addresses.
+pub fn check_log(entries: &[LogEntry]) -> Vec<LogIssue> {
+    let mut issues = Vec::new();
+    let mut mappings_by_pid: HashMap<i32, Vec<&LoggedModule>> = HashMap::new();
+    let mut relative_addresses_by_build: HashMap<(&str, u64), (&LoggedModule, u64)> =
+        HashMap::new();
+    for entry in entries {
+        let LogEntry::Module {
+            module,
+            outcome: ModuleOutcome::Mapped {
+                base, base_avma, ..
+            },
+        } = entry
+        else {
+            continue;
+        };
+
+        let earlier_mappings = mappings_by_pid.entry(module.pid).or_default();
+        for earlier in earlier_mappings.iter() {
+            let overlaps =
+                earlier.start_avma < module.end_avma() && module.start_avma < earlier.end_avma();
+            let covers_earlier =
+                module.start_avma <= earlier.start_avma && earlier.end_avma() <= module.end_avma();
+            if overlaps && !covers_earlier && earlier.path_hash != module.path_hash {
+                issues.push(LogIssue::OverlappingMappings {
+                    earlier: (*earlier).clone(),
+                    later: module.clone(),
+                });
+            }
+        }
+        earlier_mappings.push(module);
+
+        let relative_address = module.start_avma.wrapping_sub(*base_avma);
+        if relative_address > u64::from(u32::MAX) {
+            issues.push(LogIssue::RelativeAddressOutOfRange {
+                module: module.clone(),
+                base_avma: *base_avma,
+            });
+        }
+        if *base == BaseSource::Guess && module.file_offset != 0 {
+            issues.push(LogIssue::GuessedBase {
+                module: module.clone(),
+            });
+        }
+        if let Some(build_id) = &module.build_id {
+            let (first, first_relative_address) = *relative_addresses_by_build
+                .entry((build_id.as_str(), module.file_offset))
+                .or_insert((module, relative_address));
+            if first_relative_address != relative_address {
+                issues.push(LogIssue::BiasDisagreement {
+                    first: first.clone(),
+                    first_relative_address,
+                    other: module.clone(),
+                    other_relative_address: relative_address,
+                });
+            }
+        }
+    }
+    issues
+}
+
+/// Prints the conversion log at `path` for `samply explain-log`, and fails
+/// if the checks find inconsistencies.
+pub fn explain_log_main(path: &Path) -> Result<(), CliError> {
+    let file = File::open(path)
+        .map_err(|err| CliError::io(format!("Could not open conversion log {path:?}"), &err))?;
+    let entries = read_log(BufReader::new(file)).map_err(|err| {
+        CliError::user_input(format!("Could not read conversion log {path:?}: {err}"))
+    })?;
+    print!("{}", LogExplanation(&entries));
+
+    let issues = check_log(&entries);
+    if issues.is_empty() {
+        println!("No inconsistencies found.");
+        return Ok(());
+    }
+    println!("Inconsistencies:");
+    for issue in &issues {
+        println!("  {issue}");
+    }
+    Err(CliError::internal(format!(
+        "The conversion log has {} inconsistencies.",
+        issues.len()
+    )))
+}
+
+/// The human-readable reconstruction of a log: the record counts, and the
+/// module loads of each process in time order.
+struct LogExplanation<'a>(&'a [LogEntry]);
+
+impl fmt::Display for LogExplanation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut modules_by_pid: BTreeMap<i32, Vec<(&LoggedModule, &ModuleOutcome)>> =
+            BTreeMap::new();
+        for entry in self.0 {
+            match entry {
+                LogEntry::Start { version } => writeln!(f, "Conversion log version {version}")?,
+                LogEntry::Module { module, outcome } => modules_by_pid
+                    .entry(module.pid)
+                    .or_default()
+                    .push((module, outcome)),
+                LogEntry::RecordCounts { counts } => {
+                    writeln!(f, "Records:")?;
+                    for (record_type, count) in counts {
+                        writeln!(f, "  {record_type:<20} {count:>12}")?;
+                    }
+                }
+            }
+        }
+        for (pid, mut modules) in modules_by_pid {
+            writeln!(f, "Process {pid}:")?;
+            modules.sort_by_key(|(module, _)| module.timestamp);
+            for (module, outcome) in modules {
+                write!(
+                    f,
+                    "  {:.6}s {} offset 0x{:x} file {}",
+                    module.timestamp as f64 / 1_000_000_000.0,
+                    MappingRange(module),
+                    module.file_offset,
+                    module.path_hash
+                )?;
+                if let Some(build_id) = &module.build_id {
+                    write!(f, " build {build_id}")?;
+                }
+                match outcome {
+                    ModuleOutcome::Mapped {
+                        base,
+                        base_svma,
+                        base_avma,
+                    } => {
+                        write!(f, ": base 0x{base_avma:x} ({base:?}")?;
+                        if let Some(base_svma) = base_svma {
+                            write!(f, ", base svma 0x{base_svma:x}")?;
+                        }
+                        writeln!(f, ")")?;
+                    }
+                    ModuleOutcome::Skipped(reason) => writeln!(f, ": skipped ({reason:?})")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn module(pid: i32, path: &str, file_offset: u64, start_avma: u64) -> LoggedModule {
+        LoggedModule::new(
+            pid,
+            1_000_000_000,
+            path.as_bytes(),
+            Some(&[0xab, 0xcd]),
+            file_offset,
+            start_avma,
+            0x2000,
+        )
+    }
+
+    #[test]
+    fn round_trips_and_checks_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("conversion.log");
+        let mut log = ConversionLog::create(&path).unwrap();
+        let mapped = |base_avma| ModuleOutcome::Mapped {
+            base: BaseSource::Bias,
+            base_svma: Some(0),
+            base_avma,
+        };
+        // The same file range of the build at different relative addresses.
+        log.add_module(&module(1, "/lib/a.so", 0x1000, 0x10000), mapped(0xf000));
+        log.add_module(&module(2, "/lib/a.so", 0x1000, 0x20000), mapped(0x1e000));
+        // A mapping which covers only part of the first one.
+        log.add_module(&module(1, "/lib/b.so", 0, 0x11000), mapped(0x11000));
+        log.add_module(
+            &module(1, "/lib/c.so", 0, 0x30000),
+            ModuleOutcome::Skipped(SkipReason::NoBias),
+        );
+        log.count_record(RecordType(10));
+        log.finish();
+
+        let entries = read_log(BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(entries.len(), 6);
+        assert!(matches!(
+            &entries[5],
+            LogEntry::RecordCounts { counts } if counts.values().eq([&1])
+        ));
+        let issues = check_log(&entries);
+        assert_eq!(issues.len(), 2, "{issues:?}");
+        assert!(matches!(
+            &issues[0],
+            LogIssue::BiasDisagreement {
+                first_relative_address: 0x1000,
+                other_relative_address: 0x2000,
+                ..
+            }
+        ));
+        assert!(matches!(&issues[1], LogIssue::OverlappingMappings { .. }));
+
+        let explanation = LogExplanation(&entries).to_string();
+        assert!(explanation.contains("skipped (NoBias)"));
+        assert!(!explanation.contains("/lib/"));
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+use crate::shared::jitdump_manager::JitDumpMetrics;
+
+/// The number of processes which are listed individually in the
+/// [`ConversionMetrics`] output, by sample count.
+const MAX_LISTED_PROCESSES: usize = 20;
+
+/// The sizes of the converter's state at some point during a conversion,
+/// for `--watchdog`. Cheap enough to collect every few thousand records.
+#[derive(Debug, Clone, Default)]
+pub struct ConversionMetrics {
+    /// The processes which are currently alive.
+    pub processes: Vec<ProcessMetrics>,
+    /// Ended processes which are kept around in case their name is reused.
+    pub reusable_process_count: usize,
+    /// Ended processes whose samples are waiting to be added to the profile.
+    pub ended_process_count: usize,
+    /// The number of distinct stack nodes across all processes.
+    pub unresolved_stack_count: usize,
+    /// The binaries whose unwind data and text bytes have been loaded.
+    pub module_count: usize,
+    pub kernel_mapping_count: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessMetrics {
+    pub pid: i32,
+    pub name: Option<String>,
+    pub thread_count: usize,
+    /// Includes markers, which are buffered together with the samples.
+    pub sample_count: usize,
+    pub mapping_op_count: usize,
+    pub jit: JitDumpMetrics,
+}
+
+impl fmt::Display for ConversionMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} live processes, {} reusable ended processes, {} ended processes with pending samples",
+            self.processes.len(),
+            self.reusable_process_count,
+            self.ended_process_count
+        )?;
+        writeln!(
+            f,
+            "{} stack nodes, {} loaded modules, {} kernel mappings",
+            self.unresolved_stack_count, self.module_count, self.kernel_mapping_count
+        )?;
+
+        let mut processes: Vec<&ProcessMetrics> = self.processes.iter().collect();
+        processes.sort_by_key(|p| std::cmp::Reverse(p.sample_count));
+        for p in processes.iter().take(MAX_LISTED_PROCESSES) {
+            writeln!(
+                f,
+                "  pid {} ({}): {} threads, {} samples and markers, {} mapping ops, \
+                 {} jitdump files ({} pending), {} JIT functions, {} JIT mapping ops",
+                p.pid,
+                p.name.as_deref().unwrap_or("<unknown>"),
+                p.thread_count,
+                p.sample_count,
+                p.mapping_op_count,
+                p.jit.file_count,
+                p.jit.pending_file_count,
+                p.jit.function_count,
+                p.jit.mapping_op_count
+            )?;
+        }
+        if processes.len() > MAX_LISTED_PROCESSES {
+            writeln!(
+                f,
+                "  ... and {} more processes",
+                processes.len() - MAX_LISTED_PROCESSES
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::shared::unresolved_samples::StackTableStats;
+
+/// What the time measured by a [`TimingGuard`] is attributed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingBucket {
+    /// The handling of a sample of the main event.
+    Sample,
+    /// The handling of a sample of any other event, e.g. a tracepoint.
+    OtherEvent,
+    /// MMAP and MMAP2 records.
+    Mmap,
+    Comm,
+    ForkExit,
+    ContextSwitch,
+    /// DWARF unwinding of sample user stacks. Part of the sample buckets.
+    Unwinding,
+    /// Turning sample stacks into stack indexes. Part of the sample buckets.
+    StackInterning,
+    /// Reading and applying jitdump records. Mostly part of the sample
+    /// buckets.
+    Jitdump,
+}
+
+impl TimingBucket {
+    pub const ALL: [TimingBucket; 9] = [
+        TimingBucket::Sample,
+        TimingBucket::OtherEvent,
+        TimingBucket::Mmap,
+        TimingBucket::Comm,
+        TimingBucket::ForkExit,
+        TimingBucket::ContextSwitch,
+        TimingBucket::Unwinding,
+        TimingBucket::StackInterning,
+        TimingBucket::Jitdump,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TimingBucket::Sample => "sample",
+            TimingBucket::OtherEvent => "other-event",
+            TimingBucket::Mmap => "mmap/mmap2",
+            TimingBucket::Comm => "comm",
+            TimingBucket::ForkExit => "fork/exit",
+            TimingBucket::ContextSwitch => "context switch",
+            TimingBucket::Unwinding => "stack unwinding",
+            TimingBucket::StackInterning => "stack interning",
+            TimingBucket::Jitdump => "jitdump",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BucketTotals {
+    count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// How often each [`TimingBucket`] was entered during a conversion, and for
+/// how long in total, for `--timings`. Clones share the totals, so the
+/// caller can keep a clone to read them after the conversion.
+#[derive(Debug, Clone, Default)]
+pub struct ConversionTimings {
+    inner: Arc<TimingsInner>,
+}
+
+#[derive(Debug, Default)]
+struct TimingsInner {
+    totals: [BucketTotals; TimingBucket::ALL.len()],
+    /// The peak size of the buffer which sorts the records of a perf.data
+    /// stream.
+    reorder_buffer_peak_records: AtomicU64,
+    reorder_buffer_peak_bytes: AtomicU64,
+    /// The size of the stack table at the end of the conversion.
+    stack_table_node_count: AtomicU64,
+    stack_table_unique_stack_count: AtomicU64,
+}
+
+impl ConversionTimings {
+    pub fn count(&self, bucket: TimingBucket) -> u64 {
+        self.inner.totals[bucket as usize]
+            .count
+            .load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self, bucket: TimingBucket) -> Duration {
+        Duration::from_nanos(
+            self.inner.totals[bucket as usize]
+                .nanos
+                .load(Ordering::Relaxed),
+        )
+    }
+
+    /// Records how many records, and how many bytes, the reorder buffer of
+    /// the input held at most.
+    pub fn record_reorder_buffer_usage(&self, peak_records: usize, peak_bytes: usize) {
+        self.inner
+            .reorder_buffer_peak_records
+            .fetch_max(peak_records as u64, Ordering::Relaxed);
+        self.inner
+            .reorder_buffer_peak_bytes
+            .fetch_max(peak_bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The peak record count and byte size of the reorder buffer, if the
+    /// input had one.
+    pub fn reorder_buffer_usage(&self) -> Option<(u64, u64)> {
+        let peak_records = self
+            .inner
+            .reorder_buffer_peak_records
+            .load(Ordering::Relaxed);
+        let peak_bytes = self.inner.reorder_buffer_peak_bytes.load(Ordering::Relaxed);
+        (peak_records != 0).then(|| (peak_records, peak_bytes))
+    }
+
+    /// Records the size of the stack table at the end of the conversion.
+    pub fn record_stack_table_stats(&self, stats: StackTableStats) {
+        self.inner
+            .stack_table_node_count
+            .store(stats.node_count as u64, Ordering::Relaxed);
+        self.inner
+            .stack_table_unique_stack_count
+            .store(stats.unique_stack_count as u64, Ordering::Relaxed);
+    }
+
+    /// The node count and unique stack count of the stack table, if they
+    /// have been recorded.
+    pub fn stack_table_size(&self) -> Option<(u64, u64)> {
+        let node_count = self.inner.stack_table_node_count.load(Ordering::Relaxed);
+        let unique_stack_count = self
+            .inner
+            .stack_table_unique_stack_count
+            .load(Ordering::Relaxed);
+        (node_count != 0).then(|| (node_count, unique_stack_count))
+    }
+
+    /// Prints the table to stderr, and logs each row as an event so that the
+    /// `--log-file` has the numbers too.
+    pub fn report(&self) {
+        eprint!("{self}");
+        for bucket in TimingBucket::ALL {
+            let count = self.count(bucket);
+            let total_ns = self.total(bucket).as_nanos() as u64;
+            info!(
+                bucket = bucket.name(),
+                count,
+                total_ns,
+                "Conversion timing for {}: {count} in {total_ns} ns",
+                bucket.name()
+            );
+        }
+        if let Some((peak_records, peak_bytes)) = self.reorder_buffer_usage() {
+            info!(
+                peak_records,
+                peak_bytes, "Reorder buffer peak: {peak_records} records, {peak_bytes} bytes"
+            );
+        }
+        if let Some((node_count, unique_stack_count)) = self.stack_table_size() {
+            info!(
+                node_count,
+                unique_stack_count,
+                "Stack table: {node_count} nodes, {unique_stack_count} unique stacks"
+            );
+        }
+    }
+}
+
+impl fmt::Display for ConversionTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<16} {:>12} {:>12} {:>10}",
+            "Timings", "count", "total ms", "avg us"
+        )?;
+        for bucket in TimingBucket::ALL {
+            let count = self.count(bucket);
+            let total = self.total(bucket);
+            let avg_us = match count {
+                0 => 0.0,
+                count => total.as_secs_f64() * 1_000_000.0 / count as f64,
+            };
+            writeln!(
+                f,
+                "{:<16} {:>12} {:>12.3} {:>10.3}",
+                bucket.name(),
+                count,
+                total.as_secs_f64() * 1000.0,
+                avg_us
+            )?;
+        }
+        if let Some((peak_records, peak_bytes)) = self.reorder_buffer_usage() {
+            writeln!(
+                f,
+                "Reorder buffer peak: {peak_records} records, {:.3} MiB",
+                peak_bytes as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        if let Some((node_count, unique_stack_count)) = self.stack_table_size() {
+            writeln!(
+                f,
+                "Stack table: {node_count} nodes, {unique_stack_count} unique stacks"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the time until it's dropped to a bucket of the [`ConversionTimings`].
+/// Does nothing, not even read the clock, if the timings are off.
+#[must_use]
+pub struct TimingGuard<'a> {
+    started: Option<(&'a BucketTotals, Instant)>,
+}
+
+impl<'a> TimingGuard<'a> {
+    pub fn start(timings: Option<&'a ConversionTimings>, bucket: TimingBucket) -> Self {
+        Self {
+            started: timings
+                .map(|timings| (&timings.inner.totals[bucket as usize], Instant::now())),
+        }
+    }
+}
+
+impl Drop for TimingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some((totals, start)) = self.started {
+            totals.count.fetch_add(1, Ordering::Relaxed);
+            totals
+                .nanos
+                .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use fxprof_processed_profile::SymbolTable;
+
+use crate::shared::types::{StackFrame, StackMode};
+
+/// The kernel functions which handle a write to a write-protected page, which,
+/// after a fork, is almost always a copy-on-write page shared with the parent.
+const COW_FAULT_KERNEL_FUNCTIONS: &[&str] = &[
+    "do_wp_page",
+    "wp_page_copy",
+    "wp_page_reuse",
+    "wp_page_shared",
+    "wp_huge_pmd",
+    "do_huge_pmd_wp_page",
+];
+
+/// Copy-on-write faults within this time after a process was forked are counted
+/// for the report at the end of the conversion.
+pub const COW_FAULT_WINDOW_AFTER_FORK_NS: u64 = 100_000_000; // 100ms
+
+/// Detects samples which were taken in a copy-on-write page fault, by looking
+/// for the kernel's COW fault handlers on the sampled stack.
+///
+/// This needs the symbols of the running kernel, so it only works if the
+/// profile was recorded on this machine and if the stacks contain kernel frames.
+#[derive(Debug, Clone)]
+pub struct CowFaultDetector {
+    kernel_base_avma: u64,
+    kernel_symbol_table: Arc<SymbolTable>,
+}
+
+impl CowFaultDetector {
+    pub fn new(kernel_base_avma: u64, kernel_symbol_table: Arc<SymbolTable>) -> Self {
+        Self {
+            kernel_base_avma,
+            kernel_symbol_table,
+        }
+    }
+
+    pub fn is_cow_fault(&self, stack: &[StackFrame]) -> bool {
+        stack.iter().any(|frame| match *frame {
+            StackFrame::InstructionPointer(address, StackMode::Kernel) => {
+                self.is_in_cow_fault_function(address)
+            }
+            StackFrame::ReturnAddress(address, StackMode::Kernel) => {
+                self.is_in_cow_fault_function(address.saturating_sub(1))
+            }
+            _ => false,
+        })
+    }
+
+    fn is_in_cow_fault_function(&self, address: u64) -> bool {
+        let Some(relative_address) = address.checked_sub(self.kernel_base_avma) else {
+            return false;
+        };
+        let Ok(relative_address) = u32::try_from(relative_address) else {
+            return false;
+        };
+        match self.kernel_symbol_table.lookup(relative_address) {
+            Some(symbol) => COW_FAULT_KERNEL_FUNCTIONS.contains(&symbol.name.as_str()),
+            None => false,
+        }
+    }
+}
+
+/// The time a forked process spent in copy-on-write faults right after the fork.
+#[derive(Debug, Clone)]
+pub struct CowFaultsAfterFork {
+    pub pid: i32,
+    pub name: Option<String>,
+    /// The timestamp of the fork record.
+    pub fork_timestamp: u64,
+    /// The CPU time of the samples in copy-on-write faults within
+    /// [`COW_FAULT_WINDOW_AFTER_FORK_NS`] after the fork.
+    pub cow_fault_ns: u64,
+    pub sample_count: u64,
+}
+
+impl CowFaultsAfterFork {
+    pub fn new(pid: i32, fork_timestamp: u64) -> Self {
+        Self {
+            pid,
+            name: None,
+            fork_timestamp,
+            cow_fault_ns: 0,
+            sample_count: 0,
+        }
+    }
+
+    /// Counts a sample in a copy-on-write fault, if it's within the window
+    /// after the fork.
+    pub fn add_sample(&mut self, timestamp: u64, cpu_delta_ns: u64, name: Option<&str>) {
+        if timestamp.saturating_sub(self.fork_timestamp) > COW_FAULT_WINDOW_AFTER_FORK_NS {
+            return;
+        }
+        self.cow_fault_ns += cpu_delta_ns;
+        self.sample_count += 1;
+        if let Some(name) = name {
+            self.name = Some(name.to_string());
+        }
+    }
+
+    pub fn report(&self) {
+        let pid = self.pid;
+        let name = self.name.as_deref().unwrap_or("<unknown>");
+        eprintln!(
+            "Process {name} (pid {pid}) spent ~{:.1}ms in copy-on-write faults in the first {}ms after fork ({} samples).",
+            self.cow_fault_ns as f64 / 1_000_000.0,
+            COW_FAULT_WINDOW_AFTER_FORK_NS / 1_000_000,
+            self.sample_count
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::Symbol;
+
+    use super::*;
+
+    const KERNEL_BASE: u64 = 0xffffffff81000000;
+
+    fn detector() -> CowFaultDetector {
+        let symbol = |address, name: &str| Symbol {
+            address,
+            size: None,
+            name: name.to_string(),
+        };
+        let symbol_table = SymbolTable::new(vec![
+            symbol(0x0, "_text"),
+            symbol(0x1000, "handle_mm_fault"),
+            symbol(0x2000, "do_wp_page"),
+            symbol(0x2400, "wp_page_copy"),
+            symbol(0x2800, "clear_page_erms"),
+        ]);
+        CowFaultDetector::new(KERNEL_BASE, Arc::new(symbol_table))
+    }
+
+    #[test]
+    fn detects_cow_fault_handlers_on_the_stack() {
+        let detector = detector();
+        let kernel_ip =
+            |offset| StackFrame::InstructionPointer(KERNEL_BASE + offset, StackMode::Kernel);
+        let kernel_ra = |offset| StackFrame::ReturnAddress(KERNEL_BASE + offset, StackMode::Kernel);
+        let user_ra = StackFrame::ReturnAddress(0x2010, StackMode::User);
+
+        assert!(detector.is_cow_fault(&[kernel_ip(0x2800), kernel_ra(0x2410), kernel_ra(0x1010)]));
+        assert!(detector.is_cow_fault(&[kernel_ip(0x2004), user_ra]));
+        assert!(!detector.is_cow_fault(&[kernel_ip(0x2800), kernel_ra(0x1010), user_ra]));
+        // A return address right after a call at the end of the function before
+        // do_wp_page belongs to that function.
+        assert!(!detector.is_cow_fault(&[kernel_ra(0x2000)]));
+        // User addresses are never in the kernel.
+        assert!(!detector.is_cow_fault(&[StackFrame::InstructionPointer(
+            KERNEL_BASE + 0x2004,
+            StackMode::User
+        )]));
+    }
+
+    #[test]
+    fn only_counts_faults_shortly_after_fork() {
+        let mut faults = CowFaultsAfterFork::new(100, 1_000_000);
+        faults.add_sample(2_000_000, 1_000_000, Some("server"));
+        faults.add_sample(
+            1_000_000 + COW_FAULT_WINDOW_AFTER_FORK_NS + 1,
+            1_000_000,
+            None,
+        );
+        assert_eq!(faults.cow_fault_ns, 1_000_000);
+        assert_eq!(faults.sample_count, 1);
+        assert_eq!(faults.name.as_deref(), Some("server"));
+    }
+}
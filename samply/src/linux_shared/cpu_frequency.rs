@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+
+/// Computes the effective CPU frequency over time, per CPU, from a pair of
+/// events: one which counts actual CPU cycles ("cycles" or "msr/aperf/"), and
+/// one which counts cycles at the constant reference rate ("ref-cycles" or
+/// "msr/mperf/"). Both only tick while the CPU is not idle.
+///
+/// The sample periods of both events are accumulated into fixed-size time
+/// buckets per CPU. For each bucket, the effective frequency is the ratio
+/// cycles / ref-cycles, multiplied with the reference rate.
+///
+/// The reference rate isn't recorded anywhere, so we estimate it as the highest
+/// ref-cycles rate seen in any bucket; that's the rate of a bucket during which
+/// the CPU was busy the entire time.
+///
+/// Buckets in which either event has no samples, for example because the CPU
+/// was idle, don't produce a value; they leave a gap in the track rather than
+/// a dip to zero.
+///
+/// Multiplexing: We don't get the `read_format` time_enabled / time_running
+/// values from sample records, so we can't scale the counts of multiplexed
+/// events. The ratio is only skewed if the two events are multiplexed
+/// differently; recording them in the same event group avoids this.
+pub struct CpuFrequencyCalculator {
+    cycles_attr_index: usize,
+    ref_cycles_attr_index: usize,
+    bucket_duration_ns: u64,
+    buckets: BTreeMap<(u32, u64), BucketCounts>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct BucketCounts {
+    cycles: u64,
+    ref_cycles: u64,
+}
+
+impl CpuFrequencyCalculator {
+    pub fn new(
+        cycles_attr_index: usize,
+        ref_cycles_attr_index: usize,
+        bucket_duration_ns: u64,
+    ) -> Self {
+        Self {
+            cycles_attr_index,
+            ref_cycles_attr_index,
+            bucket_duration_ns,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    /// Accumulate the period of a sample, if the sample belongs to one of the
+    /// two events.
+    pub fn add_sample(&mut self, attr_index: usize, cpu: u32, timestamp: u64, period: u64) {
+        let bucket_index = timestamp / self.bucket_duration_ns;
+        if attr_index == self.cycles_attr_index {
+            self.buckets.entry((cpu, bucket_index)).or_default().cycles += period;
+        } else if attr_index == self.ref_cycles_attr_index {
+            self.buckets
+                .entry((cpu, bucket_index))
+                .or_default()
+                .ref_cycles += period;
+        }
+    }
+
+    /// Returns, for each CPU, a list of (bucket start timestamp, effective
+    /// frequency in GHz), sorted by timestamp.
+    pub fn effective_frequencies(&self) -> BTreeMap<u32, Vec<(u64, f64)>> {
+        let max_ref_cycles = self
+            .buckets
+            .values()
+            .map(|counts| counts.ref_cycles)
+            .max()
+            .unwrap_or(0);
+        let mut frequencies: BTreeMap<u32, Vec<(u64, f64)>> = BTreeMap::new();
+        if max_ref_cycles == 0 {
+            return frequencies;
+        }
+
+        // Cycles per nanosecond is GHz.
+        let reference_ghz = max_ref_cycles as f64 / self.bucket_duration_ns as f64;
+        for (&(cpu, bucket_index), counts) in &self.buckets {
+            if counts.cycles == 0 || counts.ref_cycles == 0 {
+                continue;
+            }
+            let ratio = counts.cycles as f64 / counts.ref_cycles as f64;
+            frequencies.entry(cpu).or_default().push((
+                bucket_index * self.bucket_duration_ns,
+                ratio * reference_ghz,
+            ));
+        }
+        frequencies
+    }
+}
+
+/// Finds a pair of (cycles, reference cycles) events among the event names,
+/// and returns their attribute indexes.
+pub fn find_frequency_event_pair(event_names: &[String]) -> Option<(usize, usize)> {
+    const PAIRS: &[(&[&str], &str)] = &[
+        (&["cycles", "cpu-cycles"], "ref-cycles"),
+        (&["msr/aperf/"], "msr/mperf/"),
+    ];
+
+    // Ignore modifiers, e.g. "cycles:u".
+    let base_name = |name: &str| name.split(':').next().unwrap_or_default().to_string();
+    let position = |wanted: &[&str]| {
+        event_names
+            .iter()
+            .position(|name| wanted.contains(&base_name(name).as_str()))
+    };
+    PAIRS.iter().find_map(|(cycles_names, ref_cycles_name)| {
+        Some((position(cycles_names)?, position(&[ref_cycles_name])?))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_frequency_event_pair, CpuFrequencyCalculator};
+
+    #[test]
+    fn it_works() {
+        // Two CPUs, 10ns buckets, with a reference rate of 1 GHz.
+        let mut calc = CpuFrequencyCalculator::new(0, 1, 10);
+
+        // CPU 0, bucket 0: fully busy, running at twice the reference rate.
+        calc.add_sample(1, 0, 2, 5);
+        calc.add_sample(0, 0, 3, 10);
+        calc.add_sample(1, 0, 8, 5);
+        calc.add_sample(0, 0, 9, 10);
+
+        // CPU 0, bucket 1: idle, no samples.
+
+        // CPU 0, bucket 2: half busy, running at half the reference rate.
+        calc.add_sample(0, 0, 21, 1);
+        calc.add_sample(1, 0, 22, 4);
+        calc.add_sample(0, 0, 23, 1);
+
+        // CPU 1, bucket 0: only cycles samples, no value.
+        calc.add_sample(0, 1, 4, 20);
+
+        // CPU 1, bucket 1: running at the reference rate.
+        calc.add_sample(0, 1, 14, 7);
+        calc.add_sample(1, 1, 15, 7);
+
+        // Samples from other events are ignored.
+        calc.add_sample(2, 1, 16, 1000);
+
+        let frequencies = calc.effective_frequencies();
+        assert_eq!(frequencies.len(), 2);
+        assert_eq!(frequencies[&0], vec![(0, 2.0), (20, 0.5)]);
+        assert_eq!(frequencies[&1], vec![(10, 1.0)]);
+    }
+
+    #[test]
+    fn finds_event_pairs() {
+        let names = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            find_frequency_event_pair(&names(&["cycles:u", "ref-cycles:u"])),
+            Some((0, 1))
+        );
+        assert_eq!(
+            find_frequency_event_pair(&names(&["msr/mperf/", "cpu-clock", "msr/aperf/"])),
+            Some((2, 0))
+        );
+        assert_eq!(
+            find_frequency_event_pair(&names(&["cycles", "msr/mperf/"])),
+            None
+        );
+    }
+}
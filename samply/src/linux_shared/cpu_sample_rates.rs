@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use linux_perf_data::linux_perf_event_reader::SamplingPolicy;
+use linux_perf_data::AttributeDescription;
+use tracing::{info, warn};
+
+/// The number of consecutive sample spacings of a CPU whose median is taken
+/// as the CPU's sampling interval.
+const WINDOW_LEN: usize = 64;
+
+/// CPUs whose median sample spacings differ by more than this factor are
+/// reported.
+const RATE_TOLERANCE: f64 = 1.5;
+
+/// Logs a warning if the attributes of the main event, e.g. the ones which
+/// were opened for different CPUs, don't all use the same sampling policy.
+pub fn check_sampling_policies(attrs: &[AttributeDescription]) {
+    let main_name = attrs[0].name.as_deref();
+    let mut policies: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (attr_index, attr) in attrs.iter().enumerate() {
+        if attr.name.as_deref() == main_name {
+            let policy = match attr.attr.sampling_policy {
+                SamplingPolicy::NoSampling => "no sampling".to_string(),
+                SamplingPolicy::Frequency(freq) => format!("{freq} Hz"),
+                SamplingPolicy::Period(period) => format!("a period of {period}"),
+            };
+            policies.entry(policy).or_default().push(attr_index);
+        }
+    }
+    if policies.len() > 1 {
+        let main_event = main_name.unwrap_or("<unnamed event>");
+        warn!(
+            main_event,
+            policy_count = policies.len(),
+            "The main event {main_event} was recorded with different sampling policies, so its \
+             samples don't have comparable weights:"
+        );
+        for (policy, attr_indexes) in policies {
+            warn!(
+                policy = policy.as_str(),
+                attr_indexes = ?attr_indexes,
+                "  {policy}: attributes {attr_indexes:?}"
+            );
+        }
+    }
+}
+
+/// Observes how far apart the samples of the main event are on each CPU, for
+/// time-based sampling, where they should be one sampling interval apart
+/// while the CPU is busy. The median spacing ignores the gaps while a CPU is
+/// idle.
+///
+/// With `--normalize-cpu-rates`, each sample gets the number of nominal
+/// sampling intervals which its CPU's samples are apart as its weight, so
+/// that a CPU which was sampled at a tenth of the rate has samples with ten
+/// times the weight, and each CPU-second contributes the same weight. The
+/// weights are rounded, and never less than one, so CPUs which sampled
+/// faster than requested keep their weights.
+#[derive(Debug)]
+pub struct CpuSampleRates {
+    nominal_interval_ns: u64,
+    normalize: bool,
+    cpus: BTreeMap<u32, CpuSpacings>,
+}
+
+#[derive(Debug, Default)]
+struct CpuSpacings {
+    last_timestamp: Option<u64>,
+    /// The spacings since the last full window.
+    window: Vec<u64>,
+    /// The median spacing of each full window.
+    window_medians: Vec<u64>,
+}
+
+impl CpuSpacings {
+    /// The median spacing of the latest full window, or of the spacings so
+    /// far if there's no full window yet.
+    fn current_median(&self) -> Option<u64> {
+        match self.window_medians.last() {
+            Some(&median) => Some(median),
+            None => median(&self.window),
+        }
+    }
+
+    /// The median spacing over the whole recording.
+    fn overall_median(&self) -> Option<u64> {
+        median(&self.window_medians).or_else(|| median(&self.window))
+    }
+}
+
+impl CpuSampleRates {
+    pub fn new(nominal_interval_ns: u64, normalize: bool) -> Self {
+        Self {
+            nominal_interval_ns: nominal_interval_ns.max(1),
+            normalize,
+            cpus: BTreeMap::new(),
+        }
+    }
+
+    /// Records a sample of the main event, and returns its weight. The first
+    /// sample of each CPU has a weight of one, because its spacing is unknown.
+    pub fn add_sample(&mut self, cpu: u32, timestamp: u64) -> i32 {
+        let spacings = self.cpus.entry(cpu).or_default();
+        if let Some(spacing) = spacings
+            .last_timestamp
+            .and_then(|last| timestamp.checked_sub(last))
+            .filter(|&spacing| spacing != 0)
+        {
+            spacings.window.push(spacing);
+            if spacings.window.len() == WINDOW_LEN {
+                spacings.window_medians.extend(median(&spacings.window));
+                spacings.window.clear();
+            }
+        }
+        spacings.last_timestamp = Some(timestamp);
+        if !self.normalize {
+            return 1;
+        }
+        match spacings.current_median() {
+            Some(spacing) => self.weight_for_spacing(spacing),
+            None => 1,
+        }
+    }
+
+    fn weight_for_spacing(&self, spacing: u64) -> i32 {
+        let intervals = (spacing + self.nominal_interval_ns / 2) / self.nominal_interval_ns;
+        i32::try_from(intervals.max(1)).unwrap_or(i32::MAX)
+    }
+
+    /// The observed sampling rate of each CPU in Hz, if the rates of the
+    /// slowest and the fastest CPU differ by more than [`RATE_TOLERANCE`].
+    fn mismatched_rates(&self) -> Option<Vec<(u32, f64)>> {
+        let rates: Vec<(u32, f64)> = self
+            .cpus
+            .iter()
+            .filter_map(|(&cpu, spacings)| {
+                let spacing = spacings.overall_median()?;
+                Some((cpu, 1_000_000_000.0 / spacing as f64))
+            })
+            .collect();
+        let fastest = rates.iter().map(|&(_, rate)| rate).reduce(f64::max)?;
+        let slowest = rates.iter().map(|&(_, rate)| rate).reduce(f64::min)?;
+        (fastest > slowest * RATE_TOLERANCE).then(|| rates)
+    }
+
+    pub fn report(&self) {
+        let Some(rates) = self.mismatched_rates() else {
+            return;
+        };
+        let nominal_rate = 1_000_000_000.0 / self.nominal_interval_ns as f64;
+        warn!(
+            nominal_rate,
+            "The CPUs were sampled at different rates, so the samples of some CPUs stand for \
+             more time than others. The requested rate was {nominal_rate:.0} Hz."
+        );
+        // Group the CPUs by their rounded rate, so that the usual case of two
+        // groups stays short.
+        let mut cpus_by_rate: BTreeMap<u64, Vec<u32>> = BTreeMap::new();
+        for (cpu, rate) in rates {
+            cpus_by_rate
+                .entry(rate.round() as u64)
+                .or_default()
+                .push(cpu);
+        }
+        for (rate, cpus) in cpus_by_rate.iter().rev() {
+            warn!(rate, cpus = ?cpus, "  {rate} Hz: CPUs {cpus:?}");
+        }
+        if !self.normalize {
+            info!(
+                "Use --normalize-cpu-rates to weight the samples of each CPU by its sampling \
+                 interval."
+            );
+        }
+    }
+}
+
+fn median(values: &[u64]) -> Option<u64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut values = values.to_vec();
+    let middle = values.len() / 2;
+    Some(*values.select_nth_unstable(middle).1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn rates_of_busy_cpus_ignore_idle_gaps() {
+        let mut rates = CpuSampleRates::new(MS, false);
+        // CPU 0 at 1 kHz with an idle gap, CPU 1 at 100 Hz.
+        for timestamp in (0..50).chain(500..600) {
+            rates.add_sample(0, timestamp * MS);
+        }
+        for timestamp in 0..20 {
+            rates.add_sample(1, timestamp * 10 * MS);
+        }
+        let rates = rates.mismatched_rates().unwrap();
+        assert_eq!(rates, vec![(0, 1000.0), (1, 100.0)]);
+    }
+
+    #[test]
+    fn similar_rates_are_not_reported() {
+        let mut rates = CpuSampleRates::new(MS, false);
+        for timestamp in 0..100 {
+            rates.add_sample(0, timestamp * MS);
+            rates.add_sample(1, timestamp * 1_200_000);
+        }
+        assert_eq!(rates.mismatched_rates(), None);
+    }
+
+    #[test]
+    fn normalized_weights_are_whole_intervals() {
+        let mut rates = CpuSampleRates::new(MS, true);
+        let weights: Vec<i32> = (0..4).map(|i| rates.add_sample(0, i * 2_600_000)).collect();
+        assert_eq!(weights, vec![1, 3, 3, 3]);
+        // Faster than requested.
+        let weights: Vec<i32> = (0..3).map(|i| rates.add_sample(1, i * MS / 4)).collect();
+        assert_eq!(weights, vec![1, 1, 1]);
+    }
+}
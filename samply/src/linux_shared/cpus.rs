@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerTiming, ProcessHandle, Profile, ProfilerMarker, ThreadHandle,
+};
+use serde_json::json;
+use tracing::warn;
+
+use crate::shared::timestamp_converter::TimestampConverter;
+
+/// The profile threads of `--per-cpu-threads`: one thread per CPU, in a
+/// "CPUs" process, which the samples are attributed to instead of the
+/// sampled software threads.
+///
+/// Which software thread ran on a CPU is shown with an interval marker on
+/// the CPU's thread, named after the software thread. With context switch records, a run lasts from
+/// the switch-in to the switch-out. Without them, a run lasts from the first
+/// to the last of the consecutive samples of a thread on the CPU.
+#[derive(Debug, Default)]
+pub struct Cpus {
+    process: Option<ProcessHandle>,
+    cpus: BTreeMap<u32, Cpu>,
+    /// The number of samples without a CPU, which stay on their threads.
+    samples_without_cpu: u64,
+}
+
+#[derive(Debug)]
+struct Cpu {
+    thread: ThreadHandle,
+    /// The software thread which is running on the CPU, or which was
+    /// sampled last.
+    current_run: Option<ThreadRun>,
+}
+
+#[derive(Debug)]
+struct ThreadRun {
+    pid: i32,
+    tid: i32,
+    name: String,
+    start_timestamp: u64,
+    end_timestamp: u64,
+}
+
+impl Cpus {
+    /// Returns the thread of the CPU, and starts a run of the sampled thread
+    /// unless it's already running on the CPU.
+    #[allow(clippy::too_many_arguments)]
+    pub fn on_sample(
+        &mut self,
+        cpu: u32,
+        pid: i32,
+        tid: i32,
+        thread_name: Option<&str>,
+        timestamp: u64,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+    ) -> ThreadHandle {
+        let cpu_state = self.get_or_create_cpu(cpu, timestamp, profile, timestamp_converter);
+        match &mut cpu_state.current_run {
+            Some(run) if run.tid == tid => run.end_timestamp = run.end_timestamp.max(timestamp),
+            current_run => {
+                if let Some(run) = current_run.take() {
+                    add_run_marker(profile, cpu_state.thread, &run, timestamp_converter);
+                }
+                *current_run = Some(ThreadRun::new(pid, tid, thread_name, timestamp));
+            }
+        }
+        cpu_state.thread
+    }
+
+    pub fn on_sample_without_cpu(&mut self) {
+        self.samples_without_cpu += 1;
+    }
+
+    /// Ends the run of the thread which was running on the CPU, and starts a
+    /// run of the switched-in thread.
+    #[allow(clippy::too_many_arguments)]
+    pub fn on_switch_in(
+        &mut self,
+        cpu: u32,
+        pid: i32,
+        tid: i32,
+        thread_name: Option<&str>,
+        timestamp: u64,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        let cpu_state = self.get_or_create_cpu(cpu, timestamp, profile, timestamp_converter);
+        if let Some(run) = cpu_state.current_run.take() {
+            add_run_marker(profile, cpu_state.thread, &run, timestamp_converter);
+        }
+        cpu_state.current_run = Some(ThreadRun::new(pid, tid, thread_name, timestamp));
+    }
+
+    /// Ends the run of the switched-out thread, if it's the one which is
+    /// running on the CPU.
+    pub fn on_switch_out(
+        &mut self,
+        cpu: u32,
+        tid: i32,
+        timestamp: u64,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        let Some(cpu_state) = self.cpus.get_mut(&cpu) else {
+            return;
+        };
+        let Some(run) = cpu_state.current_run.as_mut().filter(|run| run.tid == tid) else {
+            return;
+        };
+        run.end_timestamp = timestamp;
+        add_run_marker(profile, cpu_state.thread, run, timestamp_converter);
+        cpu_state.current_run = None;
+    }
+
+    /// Adds the markers of the runs which haven't ended.
+    pub fn finish(self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        for cpu in self.cpus.into_values() {
+            if let Some(run) = cpu.current_run {
+                add_run_marker(profile, cpu.thread, &run, timestamp_converter);
+            }
+        }
+        if self.samples_without_cpu != 0 {
+            warn!(
+                samples_without_cpu = self.samples_without_cpu,
+                "{} samples had no CPU and stayed on their threads. Record with a sample format \
+                 which includes the CPU, e.g. with `perf record -a` or `--sample-cpu`.",
+                self.samples_without_cpu
+            );
+        }
+    }
+
+    fn get_or_create_cpu(
+        &mut self,
+        cpu: u32,
+        timestamp: u64,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+    ) -> &mut Cpu {
+        let start_time = timestamp_converter.convert_time(timestamp);
+        let process = *self
+            .process
+            .get_or_insert_with(|| profile.add_process("CPUs", 0, start_time));
+        self.cpus.entry(cpu).or_insert_with(|| {
+            let thread = profile.add_thread(process, cpu, start_time, false);
+            profile.set_thread_name(thread, &format!("CPU {cpu}"));
+            Cpu {
+                thread,
+                current_run: None,
+            }
+        })
+    }
+}
+
+impl ThreadRun {
+    fn new(pid: i32, tid: i32, name: Option<&str>, timestamp: u64) -> Self {
+        Self {
+            pid,
+            tid,
+            name: name.map_or_else(|| format!("<{tid}>"), ToOwned::to_owned),
+            start_timestamp: timestamp,
+            end_timestamp: timestamp,
+        }
+    }
+}
+
+fn add_run_marker(
+    profile: &mut Profile,
+    thread: ThreadHandle,
+    run: &ThreadRun,
+    timestamp_converter: &TimestampConverter,
+) {
+    profile.add_marker(
+        thread,
+        &run.name,
+        ThreadRunMarker {
+            pid: run.pid,
+            tid: run.tid,
+        },
+        MarkerTiming::Interval(
+            timestamp_converter.convert_time(run.start_timestamp),
+            timestamp_converter.convert_time(run.end_timestamp),
+        ),
+    );
+}
+
+#[derive(Debug, Clone)]
+pub struct ThreadRunMarker {
+    pid: i32,
+    tid: i32,
+}
+
+impl ProfilerMarker for ThreadRunMarker {
+    const MARKER_TYPE_NAME: &'static str = "ThreadRun";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "pid": self.pid,
+            "tid": self.tid,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.name}"),
+            tooltip_label: Some("{marker.name} ({marker.data.pid}/{marker.data.tid})"),
+            table_label: Some("{marker.name} ({marker.data.pid}/{marker.data.tid})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "pid",
+                    label: "Process ID",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "tid",
+                    label: "Thread ID",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: true,
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{ReferenceTimestamp, SamplingInterval};
+
+    use super::*;
+
+    #[test]
+    fn tracks_the_running_thread_of_each_cpu() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let converter = TimestampConverter::with_reference_timestamp(0);
+        let mut cpus = Cpus::default();
+        let run = |cpus: &Cpus, cpu: u32| {
+            let run = cpus.cpus[&cpu].current_run.as_ref()?;
+            Some((run.tid, run.start_timestamp, run.end_timestamp))
+        };
+
+        let cpu0 = cpus.on_sample(0, 10, 11, Some("worker"), 100, &mut profile, &converter);
+        cpus.on_sample(0, 10, 11, Some("worker"), 200, &mut profile, &converter);
+        assert_eq!(run(&cpus, 0), Some((11, 100, 200)));
+
+        // Another thread is sampled on the same CPU.
+        let cpu0_again = cpus.on_sample(0, 20, 21, None, 300, &mut profile, &converter);
+        assert_eq!(cpu0, cpu0_again);
+        assert_eq!(cpus.cpus[&0].current_run.as_ref().unwrap().name, "<21>");
+        assert_eq!(run(&cpus, 0), Some((21, 300, 300)));
+
+        let cpu1 = cpus.on_sample(1, 10, 11, Some("worker"), 300, &mut profile, &converter);
+        assert_ne!(cpu0, cpu1);
+
+        // Switching out a thread which isn't running doesn't end the run.
+        cpus.on_switch_out(1, 21, 350, &mut profile, &converter);
+        assert_eq!(run(&cpus, 1), Some((11, 300, 300)));
+        cpus.on_switch_out(1, 11, 400, &mut profile, &converter);
+        assert_eq!(run(&cpus, 1), None);
+        cpus.on_switch_in(1, 20, 21, None, 500, &mut profile, &converter);
+        assert_eq!(run(&cpus, 1), Some((21, 500, 500)));
+    }
+}
@@ -0,0 +1,189 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::shared::path_map::PathMap;
+use crate::shared::utils::open_file_with_fallback_using;
+
+/// Remembers which files couldn't be opened or parsed during a conversion, so
+/// that each path is only tried once. Otherwise every process which maps the
+/// same missing library opens it again, which is slow if the path is on a
+/// network file system.
+///
+/// Opens can also have a timeout, after which the file is skipped as if it
+/// was missing.
+#[derive(Debug, Default)]
+pub struct FileOpenCache {
+    /// None if opens block until they complete.
+    timeout: Option<Duration>,
+    /// The paths which couldn't be opened.
+    failed_paths: RefCell<HashSet<PathBuf>>,
+    /// The paths which could be opened but not parsed.
+    unparseable_paths: RefCell<HashSet<PathBuf>>,
+    /// The (path, fallback directory) pairs of [`FileOpenCache::open_with_fallback`]
+    /// calls which failed, with the kind of the error.
+    failed_fallback_opens: RefCell<HashMap<(PathBuf, Option<PathBuf>), io::ErrorKind>>,
+    /// The number of opens which were skipped because they failed before.
+    skipped_open_count: Cell<u64>,
+}
+
+impl FileOpenCache {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    /// Like [`open_file_with_fallback`](crate::shared::utils::open_file_with_fallback),
+    /// but fails right away for a path and fallback directory which failed
+    /// before, and skips candidate paths which failed before. Fails with
+    /// [`io::ErrorKind::InvalidData`] if the file was opened before but
+    /// couldn't be parsed.
+    pub fn open_with_fallback(
+        &self,
+        path: &Path,
+        extra_dir: Option<&Path>,
+        path_map: Option<&PathMap>,
+    ) -> io::Result<(File, PathBuf)> {
+        let key = (path.to_owned(), extra_dir.map(Path::to_owned));
+        if let Some(&kind) = self.failed_fallback_opens.borrow().get(&key) {
+            self.count_skipped_open();
+            return Err(kind.into());
+        }
+        let result =
+            open_file_with_fallback_using(path, extra_dir, path_map, |path| self.open(path));
+        if let Err(err) = &result {
+            self.failed_fallback_opens
+                .borrow_mut()
+                .insert(key, err.kind());
+        }
+        result
+    }
+
+    /// Opens the file at `path`, unless opening or parsing it failed before.
+    pub fn open(&self, path: &Path) -> io::Result<File> {
+        if self.unparseable_paths.borrow().contains(path) {
+            self.count_skipped_open();
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        if self.failed_paths.borrow().contains(path) {
+            self.count_skipped_open();
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let result = match self.timeout {
+            Some(timeout) => open_with_timeout(path, timeout),
+            None => File::open(path),
+        };
+        match &result {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                warn!(path = %path.display(), "Skipping {}: {err}", path.display());
+            }
+            Err(err) => {
+                debug!(path = %path.display(), "Could not open {}: {err}", path.display());
+            }
+            Ok(_) => return result,
+        }
+        self.failed_paths.borrow_mut().insert(path.to_owned());
+        result
+    }
+
+    /// Records that the file at `path` was opened but couldn't be parsed, so
+    /// that it isn't opened again.
+    pub fn record_parse_failure(&self, path: &Path) {
+        self.unparseable_paths.borrow_mut().insert(path.to_owned());
+    }
+
+    /// The number of opens which were skipped because the same open failed
+    /// before.
+    pub fn skipped_open_count(&self) -> u64 {
+        self.skipped_open_count.get()
+    }
+
+    fn count_skipped_open(&self) {
+        self.skipped_open_count
+            .set(self.skipped_open_count.get() + 1);
+    }
+}
+
+/// Opens the file on a helper thread and waits for at most `timeout`. A
+/// blocking open, e.g. on an unresponsive network file system, can't be
+/// cancelled portably, so the helper thread of an open which timed out is
+/// left to finish in the background.
+fn open_with_timeout(path: &Path, timeout: Duration) -> io::Result<File> {
+    let (sender, receiver) = mpsc::channel();
+    let thread_path = path.to_owned();
+    std::thread::Builder::new()
+        .name("samply-file-open".to_string())
+        .spawn(move || {
+            let _ = sender.send(File::open(thread_path));
+        })?;
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("opening the file took longer than {timeout:?}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tries_each_missing_path_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("libexisting.so");
+        std::fs::write(&existing, b"data").unwrap();
+        let cache = FileOpenCache::new(Some(Duration::from_secs(10)));
+
+        // A thousand mappings of ten missing libraries, as if from many
+        // processes, with the extra directory as the fallback root.
+        let missing: Vec<PathBuf> = (0..10)
+            .map(|i| PathBuf::from(format!("/nonexistent/samply-test/lib{i}.so")))
+            .collect();
+        for i in 0..1000 {
+            let path = &missing[i % missing.len()];
+            let result = cache.open_with_fallback(path, Some(dir.path()), None);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(cache.skipped_open_count(), 990);
+
+        // A different fallback root is a different pair, but the missing
+        // path itself isn't opened again.
+        assert!(cache.open_with_fallback(&missing[0], None, None).is_err());
+        assert_eq!(cache.skipped_open_count(), 991);
+
+        let (_, path) = cache.open_with_fallback(&existing, None, None).unwrap();
+        assert_eq!(path, existing);
+        cache.record_parse_failure(&existing);
+        let result = cache.open_with_fallback(&existing, Some(dir.path()), None);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_open_after_timeout() {
+        // Opening a FIFO for reading blocks until there's a writer.
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("fifo");
+        let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let cache = FileOpenCache::new(Some(Duration::from_millis(50)));
+        let err = cache.open(&fifo).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(cache.open(&fifo).is_err());
+        assert_eq!(cache.skipped_open_count(), 1);
+
+        // Unblock the helper thread.
+        let _ = std::fs::OpenOptions::new().write(true).open(&fifo);
+    }
+}
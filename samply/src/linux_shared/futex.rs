@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use super::virtual_memory::SyscallArgs;
+use crate::shared::types::StackFrame;
+use crate::shared::unresolved_samples::{UnresolvedStackHandle, UnresolvedStacks};
+
+const SYS_ENTER_FUTEX: &str = "syscalls:sys_enter_futex";
+const SYS_EXIT_FUTEX: &str = "syscalls:sys_exit_futex";
+
+/// The number of futex addresses listed in the contention report.
+const REPORTED_ADDRESS_COUNT: usize = 10;
+
+/// The number of innermost frames of the most common waiting stack which are
+/// listed in the contention report.
+const REPORTED_FRAME_COUNT: usize = 4;
+
+const FUTEX_PRIVATE_FLAG: u64 = 128;
+const FUTEX_CLOCK_REALTIME: u64 = 256;
+const EAGAIN: i64 = 11;
+
+/// The futex operations from linux/futex.h, grouped by whether the calling thread
+/// waits for the futex or wakes its waiters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FutexOpKind {
+    Wait,
+    Wake,
+    Other,
+}
+
+impl FutexOpKind {
+    fn from_op(op: u64) -> Self {
+        match op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+            // FUTEX_WAIT, FUTEX_LOCK_PI, FUTEX_WAIT_BITSET, FUTEX_WAIT_REQUEUE_PI,
+            // FUTEX_LOCK_PI2
+            0 | 6 | 9 | 11 | 13 => FutexOpKind::Wait,
+            // FUTEX_WAKE, FUTEX_REQUEUE, FUTEX_CMP_REQUEUE, FUTEX_WAKE_OP,
+            // FUTEX_UNLOCK_PI, FUTEX_WAKE_BITSET, FUTEX_CMP_REQUEUE_PI
+            1 | 3 | 4 | 5 | 7 | 10 | 12 => FutexOpKind::Wake,
+            _ => FutexOpKind::Other,
+        }
+    }
+}
+
+/// Handles the syscalls:sys_enter_futex and syscalls:sys_exit_futex tracepoints,
+/// and shows lock contention.
+///
+/// The futex address and operation are only known from sys_enter and the outcome
+/// only from sys_exit, so the two are paired up per thread. Calls which wait on a
+/// futex get an interval marker from entry to exit, and calls which wake waiters
+/// get an instant marker. Both carry the futex address, so that waits can be
+/// matched up with the wakes that ended them.
+///
+/// The wait times are also summed up per futex address, and the addresses with
+/// the longest total wait time are printed at the end of the conversion.
+#[derive(Debug, Default)]
+pub struct FutexHandler {
+    /// The futex call which each thread is currently in, by tid.
+    pending_call_by_tid: HashMap<i32, PendingFutexCall>,
+
+    /// The accumulated wait times, by (pid, futex address).
+    contention_by_address: HashMap<(i32, u64), FutexContention>,
+}
+
+#[derive(Debug)]
+struct PendingFutexCall {
+    address: u64,
+    kind: FutexOpKind,
+    enter_timestamp: u64,
+    stack: UnresolvedStackHandle,
+}
+
+#[derive(Debug, Default)]
+struct FutexContention {
+    total_wait_ns: u64,
+    wait_count: u64,
+    wait_count_by_stack: HashMap<UnresolvedStackHandle, u64>,
+}
+
+impl FutexContention {
+    fn most_common_stack(&self) -> Option<UnresolvedStackHandle> {
+        self.wait_count_by_stack
+            .iter()
+            .max_by_key(|(_, count)| **count)
+            .map(|(stack, _)| *stack)
+    }
+}
+
+impl TracepointHandler for FutexHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        matches!(attr_name, SYS_ENTER_FUTEX | SYS_EXIT_FUTEX)
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(raw), Some(pid), Some(tid), Some(timestamp_mono)) =
+            (e.raw, e.pid, e.tid, e.timestamp)
+        else {
+            return;
+        };
+        let Ok(args) = SyscallArgs::parse(raw, ctx.endian) else { return };
+
+        if ctx.attr_name == SYS_ENTER_FUTEX {
+            let kind = FutexOpKind::from_op(args.args[1]);
+            if kind == FutexOpKind::Other {
+                return;
+            }
+            // Use the stack of the tracepoint sample if it has one, otherwise the
+            // stack of the thread's most recent sample.
+            let thread = ctx.thread_handle(tid);
+            let stack = ctx.stack_or_last_sample_stack(thread);
+            self.pending_call_by_tid.insert(
+                tid,
+                PendingFutexCall {
+                    address: args.args[0],
+                    kind,
+                    enter_timestamp: timestamp_mono,
+                    stack,
+                },
+            );
+            return;
+        }
+
+        let Some(call) = self.pending_call_by_tid.remove(&tid) else { return };
+        let ret = args.args[0] as i64;
+        let thread = ctx.thread_handle(tid);
+        let timestamp = ctx.timestamp_converter.convert_time(timestamp_mono);
+        match call.kind {
+            FutexOpKind::Wait => {
+                if ret == -EAGAIN {
+                    // The futex value had already changed, so the thread never slept.
+                    return;
+                }
+                ctx.unresolved_samples.add_futex_wait_marker(
+                    thread,
+                    ctx.timestamp_converter.convert_time(call.enter_timestamp),
+                    call.enter_timestamp,
+                    timestamp,
+                    timestamp_mono,
+                    call.stack,
+                    call.address,
+                );
+                let contention = self
+                    .contention_by_address
+                    .entry((pid, call.address))
+                    .or_default();
+                contention.total_wait_ns += timestamp_mono - call.enter_timestamp;
+                contention.wait_count += 1;
+                *contention
+                    .wait_count_by_stack
+                    .entry(call.stack)
+                    .or_default() += 1;
+            }
+            FutexOpKind::Wake => {
+                if ret < 0 {
+                    return;
+                }
+                ctx.unresolved_samples.add_futex_wake_marker(
+                    thread,
+                    timestamp,
+                    timestamp_mono,
+                    call.stack,
+                    call.address,
+                    ret as u64,
+                );
+            }
+            FutexOpKind::Other => {}
+        }
+    }
+
+    fn finish(&mut self, unresolved_stacks: &UnresolvedStacks) {
+        if self.contention_by_address.is_empty() {
+            return;
+        }
+        let mut contentions: Vec<_> = self.contention_by_address.iter().collect();
+        contentions.sort_by_key(|(_, c)| std::cmp::Reverse(c.total_wait_ns));
+
+        eprintln!("Futex contention, by total wait time:");
+        eprintln!(
+            "  {:>8}  {:>18}  {:>12}  {:>8}  most common waiting stack (innermost first)",
+            "pid", "address", "total wait", "waits"
+        );
+        let mut frames = Vec::new();
+        for ((pid, address), contention) in contentions.into_iter().take(REPORTED_ADDRESS_COUNT) {
+            frames.clear();
+            if let Some(stack) = contention.most_common_stack() {
+                unresolved_stacks.convert_back(stack, &mut frames);
+            }
+            let stack = format_frames(&frames);
+            eprintln!(
+                "  {pid:>8}  {:>18}  {:>10.1}ms  {:>8}  {stack}",
+                format!("0x{address:x}"),
+                contention.total_wait_ns as f64 / 1_000_000.0,
+                contention.wait_count,
+            );
+        }
+    }
+}
+
+/// Formats the innermost frames of a stack as addresses, e.g.
+/// "0x7f32a1c0 <- 0x55d1e2f0". The stack is not symbolicated yet at this point.
+fn format_frames(frames: &[StackFrame]) -> String {
+    let addresses: Vec<String> = frames
+        .iter()
+        .filter_map(|frame| match frame {
+            StackFrame::InstructionPointer(address, _) | StackFrame::ReturnAddress(address, _) => {
+                Some(format!("0x{address:x}"))
+            }
+            StackFrame::TruncatedStackMarker => None,
+        })
+        .take(REPORTED_FRAME_COUNT)
+        .collect();
+    if addresses.is_empty() {
+        return "<no stack>".to_string();
+    }
+    addresses.join(" <- ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::FutexOpKind;
+
+    #[test]
+    fn decodes_futex_ops() {
+        // FUTEX_WAIT_PRIVATE
+        assert_eq!(FutexOpKind::from_op(128), FutexOpKind::Wait);
+        // FUTEX_WAIT_BITSET_PRIVATE | FUTEX_CLOCK_REALTIME
+        assert_eq!(FutexOpKind::from_op(9 | 128 | 256), FutexOpKind::Wait);
+        // FUTEX_WAKE_PRIVATE
+        assert_eq!(FutexOpKind::from_op(129), FutexOpKind::Wake);
+        assert_eq!(FutexOpKind::from_op(5), FutexOpKind::Wake);
+        // FUTEX_TRYLOCK_PI
+        assert_eq!(FutexOpKind::from_op(8), FutexOpKind::Other);
+    }
+}
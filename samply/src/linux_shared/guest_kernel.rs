@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use fxprof_processed_profile::{
+    LibMappings, LibraryHandle, LibraryInfo, Profile, Symbol, SymbolTable,
+};
+
+use super::kernel_symbols::{parse_kallsyms, KallSymIter, KernelSymbolsError};
+use crate::shared::lib_mappings::LibMappingInfo;
+
+#[derive(Debug, thiserror::Error)]
+pub enum GuestKernelError {
+    #[error("Could not read the guest kallsyms file {0:?}: {1}")]
+    CouldNotReadKallsyms(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not read the guest modules file {0:?}: {1}")]
+    CouldNotReadModules(PathBuf, #[source] std::io::Error),
+
+    #[error("Could not parse the guest kallsyms file: {0}")]
+    KernelSymbols(#[from] KernelSymbolsError),
+}
+
+/// A kernel module entry from a copy of the guest's /proc/modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GuestModule {
+    name: String,
+    start: u64,
+    size: u64,
+}
+
+/// Creates libraries for the guest kernel and the guest kernel modules, with
+/// symbol tables from a copy of the guest's /proc/kallsyms, and returns their
+/// mappings in the guest's kernel address space. This is what `perf kvm` does
+/// with its `--guestkallsyms` and `--guestmodules` arguments.
+///
+/// `modules_path` is either a copy of the guest's /proc/modules, or a directory
+/// which contains such a copy as a file named "modules". Without it, the guest
+/// kernel library covers the module addresses too.
+pub fn guest_kernel_lib_mappings(
+    profile: &mut Profile,
+    kallsyms_path: &Path,
+    modules_path: Option<&Path>,
+) -> Result<LibMappings<LibMappingInfo>, GuestKernelError> {
+    let kallsyms = std::fs::read(kallsyms_path)
+        .map_err(|e| GuestKernelError::CouldNotReadKallsyms(kallsyms_path.to_owned(), e))?;
+    let (text_address, kernel_symbol_table) = parse_kallsyms(&kallsyms)?;
+
+    let mut modules = match modules_path {
+        Some(path) => {
+            let path = if path.is_dir() {
+                path.join("modules")
+            } else {
+                path.to_owned()
+            };
+            let data = std::fs::read(&path)
+                .map_err(|e| GuestKernelError::CouldNotReadModules(path.clone(), e))?;
+            parse_proc_modules(&data)
+        }
+        None => Vec::new(),
+    };
+    modules.sort_by_key(|module| module.start);
+
+    let mut mappings = LibMappings::new();
+
+    // The kernel image ends where the first module after it starts.
+    let kernel_end = modules
+        .iter()
+        .map(|module| module.start)
+        .find(|start| *start > text_address)
+        .unwrap_or(u64::MAX);
+    let kernel_lib = add_guest_lib(
+        profile,
+        "[guest.kernel.kallsyms]",
+        kallsyms_path,
+        kernel_symbol_table,
+    );
+    mappings.add_mapping(
+        text_address,
+        kernel_end,
+        0,
+        LibMappingInfo::new_lib(kernel_lib),
+    );
+
+    for module in modules {
+        let end = module.start + module.size;
+        let symbols = KallSymIter::new(&kallsyms)
+            .filter(|(address, _)| (module.start..end).contains(address))
+            .filter_map(|(address, name)| {
+                // Module symbols have the module name appended, e.g. "snd_seq_open\t[snd_seq]".
+                let name = match memchr::memchr(b'\t', name) {
+                    Some(tab_pos) => &name[..tab_pos],
+                    None => name,
+                };
+                Some(Symbol {
+                    address: u32::try_from(address - module.start).ok()?,
+                    size: None,
+                    name: String::from_utf8_lossy(name).to_string(),
+                })
+            })
+            .collect();
+        let lib = add_guest_lib(
+            profile,
+            &format!("[{}]", module.name),
+            kallsyms_path,
+            SymbolTable::new(symbols),
+        );
+        mappings.add_mapping(module.start, end, 0, LibMappingInfo::new_lib(lib));
+    }
+
+    Ok(mappings)
+}
+
+fn add_guest_lib(
+    profile: &mut Profile,
+    name: &str,
+    kallsyms_path: &Path,
+    symbol_table: SymbolTable,
+) -> LibraryHandle {
+    let path = kallsyms_path.to_string_lossy().to_string();
+    profile.add_lib(LibraryInfo {
+        name: name.to_string(),
+        debug_name: name.to_string(),
+        path: path.clone(),
+        debug_path: path,
+        debug_id: Default::default(),
+        code_id: None,
+        arch: None,
+        symbol_table: Some(Arc::new(symbol_table)),
+    })
+}
+
+/// Parses the contents of /proc/modules.
+///
+/// Format: `<name> <size> <refcount> <dependencies> <state> <address> [<taint flags>]`
+fn parse_proc_modules(data: &[u8]) -> Vec<GuestModule> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?.to_string();
+            let size = fields.next()?.parse().ok()?;
+            let address = fields.nth(3)?;
+            let start = u64::from_str_radix(address.trim_start_matches("0x"), 16).ok()?;
+            if start == 0 {
+                // Addresses are hidden without root.
+                return None;
+            }
+            Some(GuestModule { name, start, size })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_proc_modules, GuestModule};
+
+    #[test]
+    fn test_parse_proc_modules() {
+        let modules = br#"snd_seq 94208 0 - Live 0xffffffffc0a3c000
+snd_timer 49152 1 snd_seq, Live 0xffffffffc0a2f000
+kvm_intel 372736 0 - Live 0xffffffffc0b40000 (E)
+hidden 4096 0 - Live 0x0000000000000000"#;
+        assert_eq!(
+            parse_proc_modules(modules),
+            vec![
+                GuestModule {
+                    name: "snd_seq".to_string(),
+                    start: 0xffffffffc0a3c000,
+                    size: 94208
+                },
+                GuestModule {
+                    name: "snd_timer".to_string(),
+                    start: 0xffffffffc0a2f000,
+                    size: 49152
+                },
+                GuestModule {
+                    name: "kvm_intel".to_string(),
+                    start: 0xffffffffc0b40000,
+                    size: 372736
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use fxprof_processed_profile::{CpuDelta, ProcessHandle, Profile, Timestamp, WeightType};
+use tracing::warn;
+
+use crate::import::heap_profile::HeapProfile;
+use crate::shared::types::{StackFrame, StackMode};
+use crate::shared::unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
+
+/// A heap profile which doesn't know its pid. It's assigned to the first
+/// process which maps its executable.
+#[derive(Debug)]
+struct UnassignedHeapProfile {
+    heap_profile: HeapProfile,
+    executable_build_id: Option<Vec<u8>>,
+}
+
+/// Keeps track of the heap profiles which were passed alongside the perf.data
+/// file, and of the processes they belong to.
+#[derive(Debug, Default)]
+pub struct HeapProfiles {
+    unassigned: Vec<UnassignedHeapProfile>,
+    by_pid: HashMap<i32, Vec<HeapProfile>>,
+    /// Heap profiles whose process never showed up in the perf.data file.
+    unused: Vec<HeapProfile>,
+}
+
+impl HeapProfiles {
+    /// `build_id_for_file` returns the build ID of the executable at the
+    /// given path, if the file can be found.
+    pub fn new(
+        heap_profiles: Vec<HeapProfile>,
+        build_id_for_file: impl Fn(&Path) -> Option<Vec<u8>>,
+    ) -> Self {
+        let mut this = Self::default();
+        for heap_profile in heap_profiles {
+            match heap_profile.pid {
+                Some(pid) => this.by_pid.entry(pid).or_default().push(heap_profile),
+                None => {
+                    let executable_build_id = heap_profile
+                        .executable_path
+                        .as_deref()
+                        .and_then(&build_id_for_file);
+                    this.unassigned.push(UnassignedHeapProfile {
+                        heap_profile,
+                        executable_build_id,
+                    });
+                }
+            }
+        }
+        this
+    }
+
+    /// Called for every file mapping of every process. Assigns the heap
+    /// profiles whose executable is this file to the process: by build ID if
+    /// both build IDs are known, otherwise by path.
+    pub fn on_file_mapped(&mut self, pid: i32, path: &str, build_id: Option<&[u8]>) {
+        let mut i = 0;
+        while i < self.unassigned.len() {
+            let unassigned = &self.unassigned[i];
+            let is_match = match (&unassigned.executable_build_id, build_id) {
+                (Some(executable_build_id), Some(build_id)) => executable_build_id == build_id,
+                _ => unassigned.heap_profile.executable_path.as_deref() == Some(Path::new(path)),
+            };
+            if is_match {
+                let heap_profile = self.unassigned.remove(i).heap_profile;
+                self.by_pid.entry(pid).or_default().push(heap_profile);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// The pids of the processes which have heap profiles.
+    pub fn pids(&self) -> Vec<i32> {
+        self.by_pid.keys().copied().collect()
+    }
+
+    pub fn take_for_pid(&mut self, pid: i32) -> Vec<HeapProfile> {
+        self.by_pid.remove(&pid).unwrap_or_default()
+    }
+
+    /// Called for heap profiles whose process wasn't found.
+    pub fn mark_unused(&mut self, heap_profiles: Vec<HeapProfile>) {
+        self.unused.extend(heap_profiles);
+    }
+
+    /// Logs a warning for every heap profile which didn't make it into the
+    /// profile.
+    pub fn report_unused(&self) {
+        for heap_profile in &self.unused {
+            warn!(
+                heap_profile = heap_profile.name.as_str(),
+                "Could not find the process for the heap profile {}.", heap_profile.name
+            );
+        }
+        for unassigned in &self.unassigned {
+            warn!(
+                heap_profile = unassigned.heap_profile.name.as_str(),
+                "Could not find a process which ran the executable of the heap profile {}. \
+                 Use --heap-profile-pid to specify the process.",
+                unassigned.heap_profile.name
+            );
+        }
+    }
+}
+
+/// Adds the stacks of a process's heap profiles to the profile. Each heap
+/// profile gets its own "Heap" thread, with one sample per stack whose weight
+/// is the number of live bytes.
+#[allow(clippy::too_many_arguments)]
+pub fn add_heap_profile_samples(
+    heap_profiles: &[HeapProfile],
+    process: ProcessHandle,
+    pid: i32,
+    timestamp: Timestamp,
+    timestamp_mono: u64,
+    profile: &mut Profile,
+    unresolved_stacks: &mut UnresolvedStacks,
+    unresolved_samples: &mut UnresolvedSamples,
+) {
+    for heap_profile in heap_profiles {
+        let thread = profile.add_thread(process, pid as u32, timestamp, false);
+        let name = match heap_profiles.len() {
+            1 => "Heap".to_string(),
+            _ => format!("Heap ({})", heap_profile.name),
+        };
+        profile.set_thread_name(thread, &name);
+        profile.set_thread_samples_weight_type(thread, WeightType::Bytes);
+        for sample in &heap_profile.samples {
+            let frames = sample
+                .stack
+                .iter()
+                .enumerate()
+                .map(|(i, &address)| match i {
+                    0 => StackFrame::InstructionPointer(address, StackMode::User),
+                    _ => StackFrame::ReturnAddress(address, StackMode::User),
+                });
+            let frames: Vec<StackFrame> = frames.collect();
+            let stack = unresolved_stacks.convert(frames.into_iter().rev());
+            // Weights are i32, so more than 2GiB from a single stack are clamped.
+            let weight = i32::try_from(sample.live_bytes).unwrap_or(i32::MAX);
+            unresolved_samples.add_sample(
+                thread,
+                timestamp,
+                timestamp_mono,
+                stack,
+                CpuDelta::ZERO,
+                weight,
+            );
+        }
+    }
+}
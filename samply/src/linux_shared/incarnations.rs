@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use fxprof_processed_profile::ThreadHandle;
+use tracing::{info, warn};
+
+/// Which thread a sample belongs to, based on its timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleRouting {
+    /// The thread's current incarnation, or a thread without a known history.
+    Current,
+    /// An earlier incarnation of the pid and tid. The sample arrived after
+    /// the incarnation's EXIT record, or after the FORK record of the next
+    /// incarnation.
+    Earlier(ThreadHandle),
+    /// No known incarnation of the pid and tid was alive at the sample's time.
+    Unmatched,
+}
+
+#[derive(Debug, Clone)]
+struct Incarnation {
+    start: u64,
+    end: Option<u64>,
+    thread: ThreadHandle,
+}
+
+/// The incarnations of each pid and tid, with the time ranges from their
+/// FORK and EXIT records.
+///
+/// perf's per-CPU buffers can deliver a thread's samples after its EXIT
+/// record, and even after the FORK record of the next thread with the same
+/// tid. The time ranges tell us which incarnation such a sample belongs to.
+#[derive(Debug, Default)]
+pub struct ThreadIncarnations {
+    /// Sorted by start time.
+    by_pid_and_tid: HashMap<(i32, i32), Vec<Incarnation>>,
+    earlier_sample_count: u64,
+    unmatched_sample_count: u64,
+}
+
+impl ThreadIncarnations {
+    pub fn on_fork(&mut self, pid: i32, tid: i32, timestamp: u64, thread: ThreadHandle) {
+        let incarnations = self.by_pid_and_tid.entry((pid, tid)).or_default();
+        let index = incarnations.partition_point(|i| i.start <= timestamp);
+        incarnations.insert(
+            index,
+            Incarnation {
+                start: timestamp,
+                end: None,
+                thread,
+            },
+        );
+    }
+
+    /// Ends the incarnation which was alive at `timestamp`. A thread without
+    /// a FORK record existed before the recording started.
+    pub fn on_exit(&mut self, pid: i32, tid: i32, timestamp: u64, thread: ThreadHandle) {
+        let incarnations = self.by_pid_and_tid.entry((pid, tid)).or_default();
+        let index = incarnations.partition_point(|i| i.start <= timestamp);
+        match index.checked_sub(1).map(|i| &mut incarnations[i]) {
+            Some(incarnation) if incarnation.end.is_none() => {
+                incarnation.end = Some(timestamp);
+            }
+            _ => incarnations.insert(
+                0,
+                Incarnation {
+                    start: 0,
+                    end: Some(timestamp),
+                    thread,
+                },
+            ),
+        }
+    }
+
+    pub fn route(&mut self, pid: i32, tid: i32, timestamp: u64) -> SampleRouting {
+        let Some(incarnations) = self.by_pid_and_tid.get(&(pid, tid)) else {
+            return SampleRouting::Current;
+        };
+        let index = incarnations.partition_point(|i| i.start <= timestamp);
+        let routing = match index.checked_sub(1) {
+            // Before the first known incarnation.
+            None => SampleRouting::Unmatched,
+            Some(i) => {
+                let incarnation = &incarnations[i];
+                let is_last = i == incarnations.len() - 1;
+                match incarnation.end {
+                    None if is_last => SampleRouting::Current,
+                    // The EXIT record is missing; the incarnation ended when
+                    // the next one started.
+                    None => SampleRouting::Earlier(incarnation.thread),
+                    Some(end) if timestamp <= end => SampleRouting::Earlier(incarnation.thread),
+                    Some(_) => SampleRouting::Unmatched,
+                }
+            }
+        };
+        match routing {
+            SampleRouting::Current => {}
+            SampleRouting::Earlier(_) => self.earlier_sample_count += 1,
+            SampleRouting::Unmatched => self.unmatched_sample_count += 1,
+        }
+        routing
+    }
+
+    pub fn report(&self) {
+        if self.earlier_sample_count != 0 {
+            info!(
+                earlier_sample_count = self.earlier_sample_count,
+                "{} samples arrived after their thread had exited and were put on the thread \
+                 which was alive at the time of the sample.",
+                self.earlier_sample_count
+            );
+        }
+        if self.unmatched_sample_count != 0 {
+            warn!(
+                unmatched_sample_count = self.unmatched_sample_count,
+                "{} samples didn't match the lifetime of any thread with their tid and were put \
+                 on \"Late samples\" threads.",
+                self.unmatched_sample_count
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{Profile, ReferenceTimestamp, SamplingInterval, Timestamp};
+
+    use super::*;
+
+    #[test]
+    fn routes_late_samples_by_time_range() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let process = profile.add_process("p", 100, Timestamp::from_millis_since_reference(0.0));
+        let mut add_thread = || {
+            profile.add_thread(
+                process,
+                101,
+                Timestamp::from_millis_since_reference(0.0),
+                false,
+            )
+        };
+        let (first, second, existing) = (add_thread(), add_thread(), add_thread());
+
+        let mut incarnations = ThreadIncarnations::default();
+        incarnations.on_fork(100, 101, 100, first);
+        // The next incarnation's FORK arrives before the first one's EXIT.
+        incarnations.on_fork(100, 101, 300, second);
+        incarnations.on_exit(100, 101, 200, first);
+
+        assert_eq!(incarnations.route(100, 101, 350), SampleRouting::Current);
+        assert_eq!(
+            incarnations.route(100, 101, 150),
+            SampleRouting::Earlier(first)
+        );
+        assert_eq!(incarnations.route(100, 101, 250), SampleRouting::Unmatched);
+        assert_eq!(incarnations.route(100, 101, 50), SampleRouting::Unmatched);
+        assert_eq!(incarnations.route(100, 102, 50), SampleRouting::Current);
+
+        incarnations.on_exit(100, 101, 400, second);
+        assert_eq!(
+            incarnations.route(100, 101, 390),
+            SampleRouting::Earlier(second)
+        );
+        assert_eq!(incarnations.route(100, 101, 410), SampleRouting::Unmatched);
+
+        // A thread which existed before the recording.
+        incarnations.on_exit(100, 103, 500, existing);
+        assert_eq!(
+            incarnations.route(100, 103, 10),
+            SampleRouting::Earlier(existing)
+        );
+        assert_eq!(incarnations.earlier_sample_count, 3);
+        assert_eq!(incarnations.unmatched_sample_count, 3);
+    }
+}
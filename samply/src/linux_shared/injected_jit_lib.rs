@@ -0,0 +1,193 @@
+use std::borrow::Cow;
+use std::ops::Range;
+
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+/// Returns the name of the function in a `jitted-<pid>-<code_index>.so`
+/// file, which `perf inject --jit` creates for each JIT_CODE_LOAD record of
+/// a jitdump file.
+///
+/// The name is taken from the first text symbol. Some `perf inject` versions
+/// write files without a usable symbol, so we fall back to the DWARF name of
+/// the function, then to the name in the jitdump record with the file's code
+/// index, which `jitdump_function_name` looks up if the jitdump file is
+/// available. As a last resort, the name is "<unknown>" with the address
+/// range, so that different functions still get different names, which the
+/// [`JitFunctionRecycler`](crate::shared::jit_function_recycler::JitFunctionRecycler)
+/// uses as keys.
+pub fn jit_function_name(
+    obj: &object::File,
+    file_name: &str,
+    avma_range: Range<u64>,
+    jitdump_function_name: impl FnOnce(u64) -> Option<String>,
+) -> String {
+    if let Some(name) = text_symbol_name(obj) {
+        return name.into_owned();
+    }
+    if let Some(name) = dwarf_function_name(obj) {
+        return name;
+    }
+    if let Some(name) = code_index_from_file_name(file_name).and_then(jitdump_function_name) {
+        return name;
+    }
+    format!("<unknown> {:#x}-{:#x}", avma_range.start, avma_range.end)
+}
+
+/// The name of the first text symbol which has a non-empty name.
+fn text_symbol_name<'data>(obj: &object::File<'data>) -> Option<Cow<'data, str>> {
+    obj.symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text)
+        .filter_map(|symbol| symbol.name_bytes().ok())
+        .find(|name| !name.is_empty())
+        .map(String::from_utf8_lossy)
+}
+
+/// The DW_AT_name of the first subprogram in the DWARF debug info.
+fn dwarf_function_name(obj: &object::File) -> Option<String> {
+    let endian = if obj.is_little_endian() {
+        gimli::RunTimeEndian::Little
+    } else {
+        gimli::RunTimeEndian::Big
+    };
+    let load_section = |id: gimli::SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+        Ok(obj
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[])))
+    };
+    let dwarf_sections = gimli::Dwarf::load(load_section).ok()?;
+    let dwarf = dwarf_sections.borrow(|section| gimli::EndianSlice::new(section, endian));
+    let mut units = dwarf.units();
+    while let Ok(Some(header)) = units.next() {
+        let Ok(unit) = dwarf.unit(header) else {
+            continue;
+        };
+        let mut entries = unit.entries();
+        while let Ok(Some((_, entry))) = entries.next_dfs() {
+            if entry.tag() != gimli::DW_TAG_subprogram {
+                continue;
+            }
+            let Ok(Some(value)) = entry.attr_value(gimli::DW_AT_name) else {
+                continue;
+            };
+            let Ok(name) = dwarf.attr_string(&unit, value) else {
+                continue;
+            };
+            if !name.is_empty() {
+                return Some(name.to_string_lossy().into_owned());
+            }
+        }
+    }
+    None
+}
+
+/// Returns the code index of a `jitted-<pid>-<code_index>.so` file name.
+fn code_index_from_file_name(file_name: &str) -> Option<u64> {
+    let numbers = file_name.strip_prefix("jitted-")?.strip_suffix(".so")?;
+    let (pid, code_index) = numbers.split_once('-')?;
+    pid.parse::<u32>().ok()?;
+    code_index.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use object::write::{Object as WriteObject, StandardSegment, Symbol, SymbolSection};
+    use object::{Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolScope};
+
+    use super::*;
+
+    const FILE_NAME: &str = "jitted-123-45.so";
+    const AVMA_RANGE: Range<u64> = 0x7f00_0000_1000..0x7f00_0000_1040;
+
+    /// Writes an ELF file with a 64 byte text section, a text symbol with
+    /// the given name if there is one, and DWARF debug info with a
+    /// subprogram of the given name if there is one.
+    fn jitted_elf(symbol_name: Option<&str>, dwarf_name: Option<&str>) -> Vec<u8> {
+        let mut obj = WriteObject::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let text = obj.add_section(
+            obj.segment_name(StandardSegment::Text).to_vec(),
+            b".text".to_vec(),
+            SectionKind::Text,
+        );
+        obj.append_section_data(text, &[0xc3; 64], 16);
+        if let Some(name) = symbol_name {
+            obj.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value: 0,
+                size: 64,
+                kind: SymbolKind::Text,
+                scope: SymbolScope::Dynamic,
+                weak: false,
+                section: SymbolSection::Section(text),
+                flags: SymbolFlags::None,
+            });
+        }
+        if let Some(name) = dwarf_name {
+            let mut add_debug_section = |section_name: &[u8], data: &[u8]| {
+                let section =
+                    obj.add_section(Vec::new(), section_name.to_vec(), SectionKind::Debug);
+                obj.append_section_data(section, data, 1);
+            };
+            // Abbreviation 1: a compile unit with children and no attributes.
+            // Abbreviation 2: a subprogram with a DW_FORM_strp DW_AT_name.
+            add_debug_section(
+                b".debug_abbrev",
+                &[1, 0x11, 1, 0, 0, 2, 0x2e, 0, 0x03, 0x0e, 0, 0, 0],
+            );
+            // A DWARF 4 unit with the compile unit, the subprogram whose name
+            // is at offset 0 of .debug_str, and the end of the children.
+            let mut debug_info = Vec::new();
+            debug_info.extend_from_slice(&14u32.to_le_bytes());
+            debug_info.extend_from_slice(&4u16.to_le_bytes());
+            debug_info.extend_from_slice(&0u32.to_le_bytes());
+            debug_info.extend_from_slice(&[8, 1, 2, 0, 0, 0, 0, 0]);
+            add_debug_section(b".debug_info", &debug_info);
+            add_debug_section(b".debug_str", format!("{name}\0").as_bytes());
+        }
+        obj.write().unwrap()
+    }
+
+    fn name_of(data: &[u8], jitdump_name: Option<&str>) -> String {
+        let obj = object::File::parse(data).unwrap();
+        jit_function_name(&obj, FILE_NAME, AVMA_RANGE, |code_index| {
+            assert_eq!(code_index, 45);
+            jitdump_name.map(ToOwned::to_owned)
+        })
+    }
+
+    #[test]
+    fn uses_text_symbol() {
+        let data = jitted_elf(Some("JsFunction"), Some("dwarf_name"));
+        assert_eq!(name_of(&data, Some("jitdump_name")), "JsFunction");
+    }
+
+    #[test]
+    fn falls_back_to_dwarf_name() {
+        // The symbol is there, but its name was stripped.
+        let data = jitted_elf(Some(""), Some("dwarf_name"));
+        assert_eq!(name_of(&data, Some("jitdump_name")), "dwarf_name");
+    }
+
+    #[test]
+    fn falls_back_to_jitdump_name() {
+        let data = jitted_elf(None, None);
+        assert_eq!(name_of(&data, Some("jitdump_name")), "jitdump_name");
+    }
+
+    #[test]
+    fn falls_back_to_unknown_with_address_range() {
+        let data = jitted_elf(None, None);
+        assert_eq!(
+            name_of(&data, None),
+            "<unknown> 0x7f0000001000-0x7f0000001040"
+        );
+    }
+
+    #[test]
+    fn parses_code_index_from_file_name() {
+        assert_eq!(code_index_from_file_name("jitted-123-45.so"), Some(45));
+        assert_eq!(code_index_from_file_name("jitted-123.so"), None);
+        assert_eq!(code_index_from_file_name("jitted-x-45.so"), None);
+        assert_eq!(code_index_from_file_name("libc.so"), None);
+    }
+}
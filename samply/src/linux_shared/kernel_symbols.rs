@@ -3,6 +3,7 @@ use std::{fmt::Debug, sync::Arc};
 use fxprof_processed_profile::{Symbol, SymbolTable};
 use object::{elf, read, NativeEndian};
 use read::elf::NoteHeader;
+use tracing::warn;
 
 #[derive(Debug, thiserror::Error)]
 pub enum KernelSymbolsError {
@@ -18,15 +19,32 @@ pub enum KernelSymbolsError {
     #[error("Did not find a _text symbol in the kernel symbol list")]
     NoTextSymbol,
 
+    #[error(
+        "The kernel symbol addresses are hidden by kptr_restrict. Run `echo '0' | sudo tee \
+         /proc/sys/kernel/kptr_restrict` to show them"
+    )]
+    AddressesHidden,
+
     #[error("Relative address {0:#x} does not fit into u32")]
     RelativeAddressTooLarge(u64),
 }
 
+/// The build ID and the symbols of the running kernel.
+///
+/// The symbol addresses are relative to the `_text` symbol, so the table
+/// applies to the kernel mapping wherever it's loaded. The converter rebases
+/// it to the address of the `[kernel.kallsyms]_text` mapping of the
+/// recording, rather than the address in /proc/kallsyms, which can differ
+/// if the recording is from an earlier boot of the same kernel.
 #[derive(Debug, Clone)]
 pub struct KernelSymbols {
     pub build_id: Vec<u8>,
-    pub base_avma: u64,
-    pub symbol_table: Arc<SymbolTable>,
+    /// The address of `_text` in /proc/kallsyms, None if kptr_restrict hides
+    /// the addresses.
+    pub base_avma: Option<u64>,
+    /// None if the addresses are hidden. The build ID still tells which
+    /// kernel mapping is the running kernel.
+    pub symbol_table: Option<Arc<SymbolTable>>,
 }
 
 impl KernelSymbols {
@@ -38,8 +56,14 @@ impl KernelSymbols {
             .to_owned();
         let kallsyms = std::fs::read("/proc/kallsyms")
             .map_err(KernelSymbolsError::CouldNotReadProcKallsyms)?;
-        let (base_avma, symbol_table) = parse_kallsyms(&kallsyms)?;
-        let symbol_table = Arc::new(symbol_table);
+        let (base_avma, symbol_table) = match parse_kallsyms(&kallsyms) {
+            Ok((base_avma, symbol_table)) => (Some(base_avma), Some(Arc::new(symbol_table))),
+            Err(err @ KernelSymbolsError::AddressesHidden) => {
+                warn!("Could not obtain kernel symbols: {err}");
+                (None, None)
+            }
+            Err(err) => return Err(err),
+        };
         Ok(KernelSymbols {
             build_id,
             base_avma,
@@ -59,7 +83,7 @@ pub fn build_id_from_notes_section_data(section_data: &[u8]) -> Option<&[u8]> {
     None
 }
 
-struct KallSymIter<'a> {
+pub struct KallSymIter<'a> {
     remaining_data: &'a [u8],
 }
 
@@ -125,6 +149,11 @@ pub fn parse_kallsyms(data: &[u8]) -> Result<(u64, SymbolTable), KernelSymbolsEr
         }
     }
     let text_addr = text_addr.ok_or(KernelSymbolsError::NoTextSymbol)?;
+    // With kptr_restrict, all addresses read as zero, so the symbols can't
+    // be told apart.
+    if text_addr == 0 {
+        return Err(KernelSymbolsError::AddressesHidden);
+    }
     Ok((text_addr, SymbolTable::new(symbols)))
 }
 
@@ -274,7 +303,7 @@ impl<'data, Elf: read::elf::FileHeader> Note<'data, Elf> {
 mod test {
     use debugid::CodeId;
 
-    use crate::linux_shared::kernel_symbols::parse_kallsyms;
+    use crate::linux_shared::kernel_symbols::{parse_kallsyms, KernelSymbolsError};
 
     use super::build_id_from_notes_section_data;
 
@@ -446,4 +475,17 @@ ffff80000b5c5744 t bpf_prog_654d7024997e7811   [bpf]"#;
             "tegra_clk_periph_fixed_is_enabled"
         );
     }
+
+    #[test]
+    fn hidden_addresses() {
+        // What a user without CAP_SYSLOG sees with kptr_restrict.
+        let kallsyms = br#"0000000000000000 T startup_64
+0000000000000000 T _stext
+0000000000000000 T _text
+0000000000000000 T secondary_startup_64"#;
+        assert!(matches!(
+            parse_kallsyms(kallsyms),
+            Err(KernelSymbolsError::AddressesHidden)
+        ));
+    }
 }
@@ -0,0 +1,70 @@
+use crate::shared::types::{StackFrame, StackMode};
+
+/// An entry of the LBR call stack of a sample recorded with
+/// `perf record --call-graph lbr`: the address of a call instruction and
+/// the address it called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbrCall {
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Appends the user frames of an LBR call stack to `stack`, the way
+/// `perf report` does. The calls are ordered from the most recent one, whose
+/// target is the start of the function which the sample is in.
+///
+/// `user_ip` is the sampled user instruction pointer, if there is one. It is
+/// more precise than the first call's target and is in the same function, so
+/// the target is only used as the leaf frame if there's no user IP.
+pub fn push_lbr_user_frames(stack: &mut Vec<StackFrame>, user_ip: Option<u64>, calls: &[LbrCall]) {
+    let Some(first_call) = calls.first() else {
+        return;
+    };
+    let leaf = user_ip.unwrap_or(first_call.to);
+    stack.push(StackFrame::InstructionPointer(leaf, StackMode::User));
+    // The call instructions are the callers' frames. They don't need to be
+    // adjusted like return addresses.
+    stack.extend(
+        calls
+            .iter()
+            .map(|call| StackFrame::InstructionPointer(call.from, StackMode::User)),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn synthesizes_user_frames() {
+        let calls = [
+            LbrCall {
+                from: 0x1050,
+                to: 0x2000,
+            },
+            LbrCall {
+                from: 0x3010,
+                to: 0x1000,
+            },
+        ];
+        let ip = |address| StackFrame::InstructionPointer(address, StackMode::User);
+
+        let mut stack = vec![StackFrame::InstructionPointer(
+            0xffff_ffff_8100_0000,
+            StackMode::Kernel,
+        )];
+        push_lbr_user_frames(&mut stack, None, &calls);
+        assert_eq!(stack[1..], [ip(0x2000), ip(0x1050), ip(0x3010)]);
+
+        // The IP replaces the first call's target, also if they're equal.
+        for user_ip in [0x2040, 0x2000] {
+            let mut stack = Vec::new();
+            push_lbr_user_frames(&mut stack, Some(user_ip), &calls);
+            assert_eq!(stack, [ip(user_ip), ip(0x1050), ip(0x3010)]);
+        }
+
+        let mut stack = Vec::new();
+        push_lbr_user_frames(&mut stack, Some(0x2040), &[]);
+        assert!(stack.is_empty());
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use fxprof_processed_profile::{LibMappings, LibraryHandle, Profile};
+
+use crate::shared::lib_mappings::{LibMappingInfo, LibMappingOpQueue};
+use crate::shared::types::{StackFrame, StackMode};
+
+/// A frame of a [`LiveSample`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LiveFrame {
+    /// A relative address in a library. For return addresses, this is the
+    /// address of the call instruction's last byte.
+    Lib(LibraryHandle, u32),
+    Kernel,
+    /// An address outside of the known libraries, e.g. in JIT code.
+    Unknown,
+}
+
+/// A sample of the main event, for `samply top`.
+#[derive(Debug, Clone)]
+pub struct LiveSample {
+    pub pid: i32,
+    pub timestamp: u64,
+    /// The frames, leaf first.
+    pub frames: Vec<LiveFrame>,
+}
+
+/// A library which a [`LiveFrame`] refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveLib {
+    pub name: String,
+    pub path: String,
+}
+
+/// What arrived since the last [`LiveSampleSink::take`]. Each library and
+/// process name is only sent once, before the first sample which needs it.
+#[derive(Debug, Default)]
+pub struct LiveBatch {
+    pub samples: Vec<LiveSample>,
+    pub libs: Vec<(LibraryHandle, LiveLib)>,
+    pub process_names: Vec<(i32, String)>,
+}
+
+/// Where the converter sends the samples of the main event while it records,
+/// for `samply top`. Clones share the pending batch, so the recording thread
+/// can fill it while the display thread takes it.
+#[derive(Debug, Clone, Default)]
+pub struct LiveSampleSink(Arc<Mutex<LiveBatch>>);
+
+impl LiveSampleSink {
+    pub fn take(&self) -> LiveBatch {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Resolves sample stacks to libraries while the recording runs, with the
+/// library mappings which the converter has seen up to the sample.
+///
+/// The converter only resolves addresses when the profile is finished, so
+/// this keeps its own mappings for each process, which it updates from the
+/// process's mapping operations. Operations which arrive out of order, after
+/// a later one has already been applied, are missed.
+#[derive(Debug)]
+pub struct LiveStackResolver {
+    sink: LiveSampleSink,
+    mappings_by_pid: HashMap<i32, LiveMappings>,
+    sent_libs: HashSet<LibraryHandle>,
+    sent_process_names: HashMap<i32, String>,
+}
+
+#[derive(Debug, Default)]
+struct LiveMappings {
+    mappings: LibMappings<LibMappingInfo>,
+    applied_op_count: usize,
+}
+
+impl LiveStackResolver {
+    pub fn new(sink: LiveSampleSink) -> Self {
+        Self {
+            sink,
+            mappings_by_pid: HashMap::new(),
+            sent_libs: HashSet::new(),
+            sent_process_names: HashMap::new(),
+        }
+    }
+
+    /// `stack` is leaf first, as returned by the unwinder.
+    pub fn add_sample(
+        &mut self,
+        pid: i32,
+        process_name: Option<&str>,
+        timestamp: u64,
+        stack: &[StackFrame],
+        lib_mapping_ops: &LibMappingOpQueue,
+        profile: &Profile,
+    ) {
+        let live_mappings = self.mappings_by_pid.entry(pid).or_default();
+        let ops = lib_mapping_ops.ops();
+        if ops.len() < live_mappings.applied_op_count {
+            // The process was replaced, e.g. after its pid was reused.
+            *live_mappings = LiveMappings::default();
+        }
+        for (op_timestamp, op) in &ops[live_mappings.applied_op_count..] {
+            if *op_timestamp > timestamp {
+                break;
+            }
+            op.clone().apply_to(&mut live_mappings.mappings);
+            live_mappings.applied_op_count += 1;
+        }
+
+        let frames: Vec<LiveFrame> = stack
+            .iter()
+            .filter_map(|frame| {
+                let (address, mode) = match *frame {
+                    StackFrame::InstructionPointer(address, mode) => (address, mode),
+                    StackFrame::ReturnAddress(address, mode) => (address.checked_sub(1)?, mode),
+                    StackFrame::TruncatedStackMarker => return None,
+                };
+                Some(match mode {
+                    StackMode::Kernel | StackMode::GuestKernel => LiveFrame::Kernel,
+                    StackMode::GuestUser => LiveFrame::Unknown,
+                    StackMode::User => match live_mappings.mappings.convert_address(address) {
+                        Some((relative_address, info)) => {
+                            LiveFrame::Lib(info.lib_handle, relative_address)
+                        }
+                        None => LiveFrame::Unknown,
+                    },
+                })
+            })
+            .collect();
+
+        let mut batch = self.sink.0.lock().unwrap();
+        for frame in &frames {
+            if let LiveFrame::Lib(lib_handle, _) = *frame {
+                if self.sent_libs.insert(lib_handle) {
+                    let lib_info = profile.lib_info(lib_handle);
+                    let lib = LiveLib {
+                        name: lib_info.name.clone(),
+                        path: lib_info.path.clone(),
+                    };
+                    batch.libs.push((lib_handle, lib));
+                }
+            }
+        }
+        if let Some(name) = process_name {
+            if self.sent_process_names.get(&pid).map(String::as_str) != Some(name) {
+                self.sent_process_names.insert(pid, name.to_owned());
+                batch.process_names.push((pid, name.to_owned()));
+            }
+        }
+        batch.samples.push(LiveSample {
+            pid,
+            timestamp,
+            frames,
+        });
+    }
+}
@@ -0,0 +1,309 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+use linux_perf_data::{DsoInfo, DsoKey};
+
+const DELETED_SUFFIX: &[u8] = b" (deleted)";
+
+/// The prefix which aufs, and overlay setups which emulate it, put in front of
+/// the file name of a whiteout, i.e. a file which was deleted in an upper layer.
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// The path of a file mapping from an mmap record or from /proc/<pid>/maps,
+/// cleaned up for `DsoKey` detection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedPath<'a> {
+    /// The path without a " (deleted)" suffix and without a whiteout prefix
+    /// on the file name.
+    pub path: Cow<'a, [u8]>,
+    /// Whether the file was deleted or replaced after it was mapped, e.g.
+    /// because the library was updated while the process was running. The
+    /// file which is now at `path`, if any, can be a different file. This is
+    /// always the case for memfd mappings.
+    pub is_deleted: bool,
+}
+
+impl<'a> MappedPath<'a> {
+    pub fn parse(path: &'a [u8]) -> Self {
+        let (path, is_deleted) = match path.strip_suffix(DELETED_SUFFIX) {
+            Some(path) => (path, true),
+            None => (path, false),
+        };
+        let file_name_start = path
+            .iter()
+            .rposition(|b| *b == b'/')
+            .map_or(0, |pos| pos + 1);
+        let path = match path[file_name_start..].strip_prefix(WHITEOUT_PREFIX) {
+            Some(file_name) if !file_name.is_empty() => {
+                Cow::Owned([&path[..file_name_start], file_name].concat())
+            }
+            _ => Cow::Borrowed(path),
+        };
+        Self { path, is_deleted }
+    }
+}
+
+/// Whether a mapped file is a device node, e.g. a GPU's /dev/dri/renderD128,
+/// /dev/kfd or /dev/mem, which some drivers map executable. Reading from a
+/// device can block or have side effects, so these mappings must not be
+/// opened and parsed as object files.
+///
+/// If the path exists, its file type decides. Otherwise, e.g. when converting
+/// a recording from another machine, paths under /dev are assumed to be
+/// devices, except for the files in the /dev/shm tmpfs.
+pub fn is_device_path(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            let file_type = metadata.file_type();
+            return file_type.is_char_device() || file_type.is_block_device();
+        }
+    }
+    path.starts_with("/dev") && !path.starts_with("/dev/shm")
+}
+
+/// Whether a mapping's path is that of an anonymous mapping, e.g. "//anon"
+/// or "[anon:v8 code]". JIT compilers put their code into these, and describe
+/// it in perf map files.
+pub fn is_anonymous_mapping_path(path: &[u8]) -> bool {
+    path == b"//anon" || path.starts_with(b"[anon:") || path.starts_with(b"/anon_hugepage")
+}
+
+/// Returns the path inside the mount namespace for paths of snap and flatpak
+/// mounts, e.g. "/usr/lib/libfoo.so" for "/snap/foo/123/usr/lib/libfoo.so".
+/// perf can record either form, depending on how it resolved the path.
+fn path_in_mount_namespace(path: &[u8]) -> Option<Cow<'_, [u8]>> {
+    // /snap/<name>/<revision>/...
+    if let Some(rest) = path.strip_prefix(b"/snap/") {
+        return skip_components(rest, 2).map(Cow::Borrowed);
+    }
+    // .../flatpak/{app,runtime}/<id>/<arch>/<branch>/<commit>/files/...
+    // Inside the sandbox, the files of the app are mounted at /app and the
+    // files of the runtime at /usr.
+    let flatpak_pos = memchr::memmem::find(path, b"/flatpak/")?;
+    let rest = &path[flatpak_pos + b"/flatpak/".len()..];
+    let (mount_point, rest): (&[u8], _) = match rest.strip_prefix(b"app/") {
+        Some(rest) => (b"/app", rest),
+        None => (b"/usr", rest.strip_prefix(b"runtime/")?),
+    };
+    let rest = skip_components(rest, 4)?.strip_prefix(b"/files")?;
+    Some(Cow::Owned([mount_point, rest].concat()))
+}
+
+/// Skips `count` path components of a relative path and returns the rest,
+/// with a leading slash.
+fn skip_components(path: &[u8], count: usize) -> Option<&[u8]> {
+    let mut rest = path;
+    for _ in 0..count {
+        let slash_pos = memchr::memchr(b'/', rest)?;
+        rest = &rest[slash_pos + 1..];
+    }
+    let start = path.len() - rest.len() - 1;
+    Some(&path[start..])
+}
+
+/// The build IDs from the perf.data file, by `DsoKey`.
+///
+/// perf doesn't always record the same path in the build ID table as in the
+/// mmap records, e.g. for libraries in snap or flatpak mounts. If there's no
+/// entry for a key, [`BuildIdTable::get`] falls back to the entries for the
+/// same file name.
+#[derive(Debug, Default)]
+pub struct BuildIdTable {
+    by_key: HashMap<DsoKey, DsoInfo>,
+    /// The keys of the user-space entries, by file name.
+    user_keys_by_name: HashMap<String, Vec<DsoKey>>,
+}
+
+impl BuildIdTable {
+    pub fn new(by_key: HashMap<DsoKey, DsoInfo>) -> Self {
+        let mut user_keys_by_name: HashMap<String, Vec<DsoKey>> = HashMap::new();
+        for key in by_key.keys() {
+            if let DsoKey::User { file_name, .. } = key {
+                user_keys_by_name
+                    .entry(file_name.clone())
+                    .or_default()
+                    .push(key.clone());
+            }
+        }
+        Self {
+            by_key,
+            user_keys_by_name,
+        }
+    }
+
+    pub fn get(&self, key: &DsoKey) -> Option<&DsoInfo> {
+        if let Some(info) = self.by_key.get(key) {
+            return Some(info);
+        }
+        let DsoKey::User {
+            file_name,
+            full_path,
+        } = key
+        else {
+            return None;
+        };
+        let candidates = self.user_keys_by_name.get(file_name)?;
+        let candidate = match candidates.as_slice() {
+            [candidate] => candidate,
+            _ => {
+                // Several libraries with this file name. Only pick one if the
+                // mapped path is that library's path inside a snap or flatpak
+                // mount.
+                let path = path_in_mount_namespace(full_path)?;
+                let mut matching = candidates.iter().filter(|candidate| match candidate {
+                    DsoKey::User { full_path, .. } => full_path[..] == path[..],
+                    _ => false,
+                });
+                match (matching.next(), matching.next()) {
+                    (Some(candidate), None) => candidate,
+                    _ => return None,
+                }
+            }
+        };
+        self.by_key.get(candidate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use linux_perf_data::linux_perf_event_reader::CpuMode;
+
+    use super::*;
+
+    fn user_key(path: &str) -> DsoKey {
+        DsoKey::detect(path.as_bytes(), CpuMode::User).unwrap()
+    }
+
+    fn dso_info(path: &str, build_id: u8) -> DsoInfo {
+        DsoInfo {
+            path: path.as_bytes().to_vec(),
+            build_id: vec![build_id; 20],
+        }
+    }
+
+    #[test]
+    fn normalizes_unusual_paths() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("/usr/lib/libc.so.6", "/usr/lib/libc.so.6", false),
+            ("/usr/lib/libc.so.6 (deleted)", "/usr/lib/libc.so.6", true),
+            ("/memfd:wasm-code (deleted)", "/memfd:wasm-code", true),
+            ("/usr/lib/.wh.libfoo.so", "/usr/lib/libfoo.so", false),
+            (
+                "/usr/lib/.wh.libfoo.so (deleted)",
+                "/usr/lib/libfoo.so",
+                true,
+            ),
+            ("/usr/lib/.wh.", "/usr/lib/.wh.", false),
+            (
+                "/snap/foo/123/usr/lib/libfoo.so",
+                "/snap/foo/123/usr/lib/libfoo.so",
+                false,
+            ),
+        ];
+        for &(path, expected_path, expected_is_deleted) in cases {
+            let mapped = MappedPath::parse(path.as_bytes());
+            assert_eq!(&*mapped.path, expected_path.as_bytes(), "{path}");
+            assert_eq!(mapped.is_deleted, expected_is_deleted, "{path}");
+        }
+
+        let mapped = MappedPath::parse(b"/usr/lib/x86_64-linux-gnu/libc.so.6 (deleted)");
+        assert_eq!(
+            DsoKey::detect(&mapped.path, CpuMode::User),
+            Some(DsoKey::User {
+                file_name: "libc.so.6".to_string(),
+                full_path: b"/usr/lib/x86_64-linux-gnu/libc.so.6".to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn finds_paths_in_mount_namespaces() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("/snap/foo/123/usr/lib/libfoo.so", Some("/usr/lib/libfoo.so")),
+            ("/snap/foo/123", None),
+            (
+                "/var/lib/flatpak/app/org.example.App/x86_64/stable/0123abcd/files/lib/libapp.so",
+                Some("/app/lib/libapp.so"),
+            ),
+            (
+                "/home/user/.local/share/flatpak/runtime/org.gnome.Platform/x86_64/45/4567cdef/files/lib/libgtk-4.so.1",
+                Some("/usr/lib/libgtk-4.so.1"),
+            ),
+            ("/usr/lib/libfoo.so", None),
+        ];
+        for &(path, expected) in cases {
+            assert_eq!(
+                path_in_mount_namespace(path.as_bytes()).as_deref(),
+                expected.map(str::as_bytes),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn falls_back_to_file_name_for_build_ids() {
+        let table = BuildIdTable::new(HashMap::from([
+            (
+                user_key("/usr/lib/libc.so.6"),
+                dso_info("/usr/lib/libc.so.6", 1),
+            ),
+            (
+                user_key("/usr/lib/libfoo.so"),
+                dso_info("/usr/lib/libfoo.so", 2),
+            ),
+            (
+                user_key("/opt/other/lib/libfoo.so"),
+                dso_info("/opt/other/lib/libfoo.so", 3),
+            ),
+        ]));
+        let build_id = |path: &str| {
+            let mapped = MappedPath::parse(path.as_bytes());
+            let key = DsoKey::detect(&mapped.path, CpuMode::User).unwrap();
+            table.get(&key).map(|info| info.build_id[0])
+        };
+
+        assert_eq!(build_id("/usr/lib/libc.so.6"), Some(1));
+        assert_eq!(build_id("/usr/lib/libc.so.6 (deleted)"), Some(1));
+        assert_eq!(build_id("/snap/core22/1033/usr/lib/libc.so.6"), Some(1));
+        assert_eq!(build_id("/usr/lib/.wh.libc.so.6"), Some(1));
+        // Ambiguous file name: only the path inside the snap or flatpak decides.
+        assert_eq!(build_id("/snap/foo/123/usr/lib/libfoo.so"), Some(2));
+        assert_eq!(build_id("/snap/foo/123/opt/other/lib/libfoo.so"), Some(3));
+        assert_eq!(
+            build_id(
+                "/var/lib/flatpak/runtime/org.example.Platform/x86_64/1/abcd/files/lib/libfoo.so"
+            ),
+            Some(2)
+        );
+        assert_eq!(build_id("/home/user/libfoo.so"), None);
+        assert_eq!(build_id("/usr/lib/libbar.so"), None);
+    }
+
+    #[test]
+    fn detects_device_paths() {
+        // Paths which don't exist here are judged by their prefix.
+        assert!(is_device_path(Path::new("/dev/dri/renderD128-not-here")));
+        assert!(!is_device_path(Path::new("/dev/shm/jit-1234.so")));
+        assert!(!is_device_path(Path::new("/devices/libfoo.so")));
+        assert!(!is_device_path(Path::new("/usr/lib/libfoo-not-here.so")));
+        #[cfg(unix)]
+        {
+            assert!(is_device_path(Path::new("/dev/null")));
+            // Existing paths are judged by their file type.
+            let file = tempfile::NamedTempFile::new().unwrap();
+            assert!(!is_device_path(file.path()));
+        }
+    }
+
+    #[test]
+    fn detects_anonymous_mappings() {
+        assert!(is_anonymous_mapping_path(b"//anon"));
+        assert!(is_anonymous_mapping_path(b"[anon:v8 code]"));
+        assert!(is_anonymous_mapping_path(b"/anon_hugepage (deleted)"));
+        assert!(!is_anonymous_mapping_path(b"/usr/lib/libanon.so"));
+        assert!(!is_anonymous_mapping_path(b"[heap]"));
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{BTreeMap, HashMap};
+
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerTiming, Profile, ProfilerMarker, ThreadHandle,
+};
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+use serde_json::json;
+use tracing::warn;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::unresolved_samples::UnresolvedStacks;
+
+/// A pair of events which mark the beginning and the end of an interval,
+/// from `--marker-pair begin=<event>,end=<event>[,name-field=<field>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarkerPairSpec {
+    pub begin: String,
+    pub end: String,
+    /// The field of the begin event which names the interval. If set, begin
+    /// and end are paired by the value of this field within a process, so
+    /// that overlapping intervals with different names don't get mixed up.
+    /// The end event needs to have the same field.
+    pub name_field: Option<String>,
+}
+
+/// Parses "begin=sdt_myapp:phase_start,end=sdt_myapp:phase_end,name-field=phase".
+pub fn parse_marker_pair(s: &str) -> Result<MarkerPairSpec, String> {
+    let (mut begin, mut end, mut name_field) = (None, None, None);
+    for part in s.split(',') {
+        match part.split_once('=') {
+            Some(("begin", event)) if !event.is_empty() => begin = Some(event.to_string()),
+            Some(("end", event)) if !event.is_empty() => end = Some(event.to_string()),
+            Some(("name-field", field)) if !field.is_empty() => {
+                name_field = Some(field.to_string())
+            }
+            _ => return Err(format!("unexpected \"{part}\"")),
+        }
+    }
+    match (begin, end) {
+        (Some(begin), Some(end)) => Ok(MarkerPairSpec {
+            begin,
+            end,
+            name_field,
+        }),
+        _ => Err("both begin=<event-name> and end=<event-name> are required".to_string()),
+    }
+}
+
+/// Which begin events an end event can close.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PairKey {
+    Thread(i32),
+    /// The process and the value of the name field.
+    Name(i32, String),
+}
+
+#[derive(Debug)]
+struct OpenInterval {
+    thread: ThreadHandle,
+    name: String,
+    begin_timestamp_mono: u64,
+}
+
+/// Turns the samples of the `--marker-pair` events into interval markers.
+///
+/// Each end event closes the innermost open interval of its pair on the same
+/// thread, or, if the pair has a name field, the innermost open interval
+/// with the same name in the same process. Nested intervals of the same pair
+/// therefore nest in the marker chart. Intervals which are still open when
+/// the profile ends become markers without an end, and end events without a
+/// begin are ignored.
+#[derive(Debug)]
+pub struct MarkerPairHandler {
+    specs: Vec<MarkerPairSpec>,
+    /// The open intervals of each (spec index, key), innermost last.
+    open_intervals: HashMap<(usize, PairKey), Vec<OpenInterval>>,
+    /// The number of end events without a begin, by event name.
+    unmatched_end_counts: BTreeMap<String, u64>,
+}
+
+impl MarkerPairHandler {
+    pub fn new(specs: Vec<MarkerPairSpec>) -> Self {
+        Self {
+            specs,
+            open_intervals: HashMap::new(),
+            unmatched_end_counts: BTreeMap::new(),
+        }
+    }
+
+    /// The value of the name field, without the quotes of string fields.
+    fn name_field_value(ctx: &ConvertCtx, e: &SampleRecord, field: &str) -> Option<String> {
+        let format = ctx.tracepoint_formats.get(ctx.attr_name)?;
+        let value = format.decode_field(&e.raw?.as_slice(), ctx.endian, field)?;
+        Some(value.trim_matches('"').to_string())
+    }
+}
+
+impl TracepointHandler for MarkerPairHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        self.specs
+            .iter()
+            .any(|spec| spec.begin == attr_name || spec.end == attr_name)
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(pid), Some(tid), Some(timestamp_mono)) = (e.pid, e.tid, e.timestamp) else {
+            return;
+        };
+        for (spec_index, spec) in self.specs.iter().enumerate() {
+            let is_begin = spec.begin == ctx.attr_name;
+            if !is_begin && spec.end != ctx.attr_name {
+                continue;
+            }
+            let (key, name) = match &spec.name_field {
+                Some(field) => match Self::name_field_value(ctx, e, field) {
+                    Some(value) => (PairKey::Name(pid, value.clone()), value),
+                    None => (PairKey::Thread(tid), spec.begin.clone()),
+                },
+                None => (PairKey::Thread(tid), spec.begin.clone()),
+            };
+            if is_begin {
+                let thread = ctx.thread_handle(tid);
+                self.open_intervals
+                    .entry((spec_index, key))
+                    .or_default()
+                    .push(OpenInterval {
+                        thread,
+                        name,
+                        begin_timestamp_mono: timestamp_mono,
+                    });
+                continue;
+            }
+
+            let Some(interval) = self
+                .open_intervals
+                .get_mut(&(spec_index, key))
+                .and_then(Vec::pop)
+            else {
+                *self
+                    .unmatched_end_counts
+                    .entry(spec.end.clone())
+                    .or_default() += 1;
+                continue;
+            };
+            let start = ctx
+                .timestamp_converter
+                .convert_time(interval.begin_timestamp_mono);
+            let end = ctx.timestamp_converter.convert_time(timestamp_mono);
+            ctx.profile.add_marker(
+                interval.thread,
+                &interval.name,
+                MarkerPairMarker {
+                    begin_event: spec.begin.clone(),
+                    end_event: spec.end.clone(),
+                },
+                MarkerTiming::Interval(start, end),
+            );
+        }
+    }
+
+    fn only_uses_stack_for_markers(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        let mut open_intervals: Vec<_> = self
+            .open_intervals
+            .drain()
+            .flat_map(|((spec_index, _), intervals)| {
+                intervals
+                    .into_iter()
+                    .map(move |interval| (spec_index, interval))
+            })
+            .collect();
+        open_intervals.sort_by_key(|(_, interval)| interval.begin_timestamp_mono);
+        for (spec_index, interval) in open_intervals {
+            let spec = &self.specs[spec_index];
+            let start = timestamp_converter.convert_time(interval.begin_timestamp_mono);
+            profile.add_marker(
+                interval.thread,
+                &interval.name,
+                MarkerPairMarker {
+                    begin_event: spec.begin.clone(),
+                    end_event: spec.end.clone(),
+                },
+                MarkerTiming::IntervalStart(start),
+            );
+        }
+    }
+
+    fn finish(&mut self, _unresolved_stacks: &UnresolvedStacks) {
+        for (event, count) in &self.unmatched_end_counts {
+            warn!(
+                event = event.as_str(),
+                count, "Ignored {count} {event} events without a matching begin event."
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MarkerPairMarker {
+    begin_event: String,
+    end_event: String,
+}
+
+impl ProfilerMarker for MarkerPairMarker {
+    const MARKER_TYPE_NAME: &'static str = "MarkerPair";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "beginEvent": self.begin_event,
+            "endEvent": self.end_event,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.name}"),
+            tooltip_label: Some("{marker.name}"),
+            table_label: Some("{marker.name} ({marker.data.beginEvent} to {marker.data.endEvent})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "beginEvent",
+                    label: "Begin event",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "endEvent",
+                    label: "End event",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_marker_pairs() {
+        assert_eq!(
+            parse_marker_pair("begin=sdt_app:phase_start,end=sdt_app:phase_end"),
+            Ok(MarkerPairSpec {
+                begin: "sdt_app:phase_start".to_string(),
+                end: "sdt_app:phase_end".to_string(),
+                name_field: None,
+            })
+        );
+        assert_eq!(
+            parse_marker_pair("end=b:y,begin=a:x,name-field=phase")
+                .unwrap()
+                .name_field
+                .as_deref(),
+            Some("phase")
+        );
+        assert!(parse_marker_pair("begin=a:x").is_err());
+        assert!(parse_marker_pair("begin=a:x,end=b:y,name=phase").is_err());
+    }
+}
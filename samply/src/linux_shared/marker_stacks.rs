@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use tracing::info;
+
+/// Which markers of high-frequency events, e.g. rss_stat, keep their stack.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MarkerStacks {
+    /// Every marker keeps its stack.
+    #[default]
+    All,
+    /// At most one marker per thread, event and time bucket keeps its stack.
+    Sampled,
+    /// No marker keeps its stack.
+    None,
+}
+
+/// Parses a `--marker-stacks` argument.
+pub fn parse_marker_stacks(s: &str) -> Result<MarkerStacks, String> {
+    match s {
+        "all" => Ok(MarkerStacks::All),
+        "sampled" => Ok(MarkerStacks::Sampled),
+        "none" => Ok(MarkerStacks::None),
+        _ => Err(format!("expected all, sampled or none, got {s:?}")),
+    }
+}
+
+/// Decides which markers keep their stack, before the stack is unwound, so
+/// that the unwinding can be skipped for the others.
+#[derive(Debug, Clone)]
+pub struct MarkerStackFilter {
+    mode: MarkerStacks,
+    bucket_duration_ns: u64,
+    /// The last time bucket in which a marker kept its stack, by (tid, attr index).
+    last_bucket_with_stack: HashMap<(i32, usize), u64>,
+    elided_count: u64,
+}
+
+impl MarkerStackFilter {
+    /// For [`MarkerStacks::Sampled`], one marker per `bucket_duration_ns`
+    /// keeps its stack.
+    pub fn new(mode: MarkerStacks, bucket_duration_ns: u64) -> Self {
+        Self {
+            mode,
+            bucket_duration_ns: bucket_duration_ns.max(1),
+            last_bucket_with_stack: HashMap::new(),
+            elided_count: 0,
+        }
+    }
+
+    /// Whether the marker for this sample should have a stack.
+    pub fn wants_stack(&mut self, tid: i32, attr_index: usize, timestamp: u64) -> bool {
+        let wants_stack = match self.mode {
+            MarkerStacks::All => true,
+            MarkerStacks::None => false,
+            MarkerStacks::Sampled => {
+                let bucket = timestamp / self.bucket_duration_ns;
+                let last_bucket = self
+                    .last_bucket_with_stack
+                    .insert((tid, attr_index), bucket);
+                last_bucket != Some(bucket)
+            }
+        };
+        if !wants_stack {
+            self.elided_count += 1;
+        }
+        wants_stack
+    }
+
+    pub fn report(&self) {
+        if self.elided_count != 0 {
+            info!(
+                elided_count = self.elided_count,
+                "Dropped the stacks of {} markers because of --marker-stacks.", self.elided_count
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_one_stack_per_bucket() {
+        let mut filter = MarkerStackFilter::new(MarkerStacks::Sampled, 1000);
+        assert!(filter.wants_stack(1, 0, 100));
+        assert!(!filter.wants_stack(1, 0, 900));
+        // Different thread or event.
+        assert!(filter.wants_stack(2, 0, 900));
+        assert!(filter.wants_stack(1, 1, 900));
+        assert!(filter.wants_stack(1, 0, 1000));
+        assert!(!filter.wants_stack(1, 0, 1999));
+        assert_eq!(filter.elided_count, 2);
+
+        let mut filter = MarkerStackFilter::new(MarkerStacks::None, 1000);
+        assert!(!filter.wants_stack(1, 0, 100));
+        let mut filter = MarkerStackFilter::new(MarkerStacks::All, 1000);
+        assert!(filter.wants_stack(1, 0, 100));
+        assert!(filter.wants_stack(1, 0, 101));
+    }
+}
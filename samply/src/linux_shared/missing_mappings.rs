@@ -0,0 +1,89 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+use tracing::warn;
+
+/// A process which had user-space samples after an exec, but for which we
+/// never received any executable mmap records. The kernel suppresses these
+/// records when a process execs a set-uid binary, depending on
+/// perf_event_paranoid.
+#[derive(Debug, Clone)]
+pub struct ProcessWithMissingMappings {
+    pub pid: i32,
+    pub name: Option<String>,
+    /// The timestamp of the exec record.
+    pub exec_timestamp: u64,
+    /// The number of executable mappings which were recovered from a
+    /// /proc/<pid>/maps snapshot, if a snapshot was taken.
+    pub snapshot_mapping_count: Option<usize>,
+}
+
+impl ProcessWithMissingMappings {
+    /// Logs a warning which explains why this process's samples may not be
+    /// symbolicated.
+    pub fn report(&self) {
+        let pid = self.pid;
+        let name = self.name.as_deref().unwrap_or("<unknown>");
+        match self.snapshot_mapping_count {
+            Some(count) => warn!(
+                pid,
+                name,
+                snapshot_mapping_count = count,
+                "Process {name} (pid {pid}) had no mmap records after exec, probably because it \
+                 runs a set-uid binary. Used {count} executable mappings from a snapshot of \
+                 /proc/{pid}/maps instead; their start times are approximate."
+            ),
+            None => warn!(
+                pid,
+                name,
+                "Process {name} (pid {pid}) had no mmap records after exec, probably because it \
+                 runs a set-uid binary, so its samples can't be symbolicated. Recording as root or \
+                 with a lower /proc/sys/kernel/perf_event_paranoid avoids this."
+            ),
+        }
+    }
+}
+
+/// Put on the main thread of a process whose mappings were taken from a
+/// /proc/<pid>/maps snapshot, at the time the snapshot was taken.
+#[derive(Debug, Clone)]
+pub struct MappingSnapshotMarker {
+    pub mapping_count: usize,
+}
+
+impl ProfilerMarker for MappingSnapshotMarker {
+    const MARKER_TYPE_NAME: &'static str = "MappingSnapshot";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "mappingCount": self.mapping_count,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("Mappings from /proc snapshot"),
+            tooltip_label: Some("{marker.data.mappingCount} mappings from /proc snapshot"),
+            table_label: Some("{marker.data.mappingCount} mappings from /proc snapshot"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "mappingCount",
+                    label: "Executable mappings",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The mmap records for this process were missing after exec, so its \
+                            mappings were read from /proc/<pid>/maps. They are treated as if \
+                            they had been mapped at the time of the exec.",
+                }),
+            ],
+        }
+    }
+}
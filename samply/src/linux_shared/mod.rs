@@ -1,17 +1,100 @@
+mod build_id_cache;
+mod cfs_throttle;
+mod checkpoint;
+mod compressed_module;
+mod container_binaries;
 mod context_switch;
+mod conversion_log;
+mod conversion_metrics;
+mod conversion_timings;
+mod cow_faults;
+mod cpu_frequency;
+mod cpu_sample_rates;
+mod cpus;
+mod file_open_cache;
+mod futex;
+mod guest_kernel;
+mod heap_profiles;
+mod incarnations;
+mod injected_jit_lib;
 mod kernel_symbols;
+mod lbr;
+mod live_samples;
+mod mapped_path;
+mod marker_pairs;
+mod marker_stacks;
+mod missing_mappings;
+mod module_data_cache;
+mod numa;
 mod object_rewriter;
-
-use byteorder::{ByteOrder, LittleEndian};
+mod off_cpu_stack;
+mod probes;
+mod proc_maps;
+mod proc_synthesis;
+mod process_exits;
+mod profiling_control;
+mod record_timestamps;
+mod recording_delay;
+mod reuse_pool;
+mod rss_stat;
+mod sampling_bias;
+mod sched_switch;
+mod signals;
+mod startup;
+mod syscall_failure;
+mod tracepoint_format;
+mod tracepoint_handler;
+mod unwind_validation;
+mod virtual_memory;
+mod watchdog;
+
+pub use build_id_cache::BuildIdCaches;
+pub use checkpoint::ConverterCheckpoint;
+pub use container_binaries::ContainerBinaries;
+pub use conversion_log::{explain_log_main, ConversionLog};
+pub use conversion_metrics::{ConversionMetrics, ProcessMetrics};
+pub use conversion_timings::{ConversionTimings, TimingBucket, TimingGuard};
+pub use cpu_sample_rates::check_sampling_policies;
+pub use lbr::LbrCall;
+pub use live_samples::{LiveBatch, LiveFrame, LiveLib, LiveSample, LiveSampleSink};
+pub use marker_pairs::{parse_marker_pair, MarkerPairHandler, MarkerPairSpec};
+pub use marker_stacks::{parse_marker_stacks, MarkerStacks};
+pub use module_data_cache::ModuleData;
+pub use numa::NumaTopology;
+pub use off_cpu_stack::{parse_off_cpu_stack, OffCpuStack};
+pub use process_exits::ExitReason;
+pub use profiling_control::ControlCommand;
+pub use record_timestamps::reference_timestamp;
+pub use recording_delay::RecordingDelay;
+pub use signals::parse_signal;
+pub use syscall_failure::{parse_errno, SyscallFailureHandler, DEFAULT_SYSCALL_FAILURE_ERRNOS};
+pub use tracepoint_format::TracepointFormats;
+pub use tracepoint_handler::{ConvertCtx, TracepointHandler};
+pub use unwind_validation::{parse_unwind_validation, UnwindValidation};
+pub use watchdog::{Watchdog, WatchdogConfig};
+
+use byteorder::LittleEndian;
+use cfs_throttle::CfsThrottleHandler;
+use checkpoint::{CarriedMapping, MappingTracker, ProcessCheckpoint, ThreadCheckpoint};
+use compressed_module::CompressedModuleCache;
 use context_switch::{ContextSwitchHandler, OffCpuSampleGroup, ThreadContextSwitchData};
+use cow_faults::{CowFaultDetector, CowFaultsAfterFork};
+use cpu_frequency::{find_frequency_event_pair, CpuFrequencyCalculator};
+use cpu_sample_rates::CpuSampleRates;
+use cpus::Cpus;
 use debugid::{CodeId, DebugId};
+use file_open_cache::FileOpenCache;
 use framehop::aarch64::UnwindRegsAarch64;
 use framehop::x86_64::UnwindRegsX86_64;
 use framehop::{FrameAddress, Module, ModuleSvmaInfo, ModuleUnwindData, TextByteData, Unwinder};
+use futex::FutexHandler;
 use fxprof_processed_profile::{
-    CategoryColor, CounterHandle, CpuDelta, LibraryHandle, LibraryInfo, MarkerTiming,
-    ProcessHandle, Profile, ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp,
+    CategoryColor, CpuDelta, LibMappings, LibraryHandle, LibraryInfo, MarkerTiming, ProcessHandle,
+    Profile, ReferenceTimestamp, SamplingInterval, ThreadHandle, Timestamp,
 };
+use guest_kernel::guest_kernel_lib_mappings;
+use incarnations::{SampleRouting, ThreadIncarnations};
+use injected_jit_lib::jit_function_name;
 use linux_perf_data::linux_perf_event_reader;
 use linux_perf_data::{AttributeDescription, DsoInfo, DsoKey, Endianness};
 use linux_perf_event_reader::constants::{
@@ -19,73 +102,146 @@ use linux_perf_event_reader::constants::{
     PERF_REG_X86_BP, PERF_REG_X86_IP, PERF_REG_X86_SP,
 };
 use linux_perf_event_reader::{
-    AttrFlags, CommOrExecRecord, CommonData, ContextSwitchRecord, ForkOrExitRecord, Mmap2FileId,
-    Mmap2Record, MmapRecord, PerfEventType, RawData, RawDataU64, Regs, SampleRecord,
-    SamplingPolicy, SoftwareCounterType,
+    AttrFlags, ClockId, CommOrExecRecord, CommonData, ContextSwitchRecord, CpuMode,
+    ForkOrExitRecord, Mmap2FileId, Mmap2InodeAndVersion, Mmap2Record, MmapRecord, PerfClock,
+    PerfEventType, RawData, RawDataU64, RecordType, Regs, SampleRecord, SamplingPolicy,
+    SoftwareCounterType,
 };
+use marker_stacks::MarkerStackFilter;
 use memmap2::Mmap;
+use missing_mappings::{MappingSnapshotMarker, ProcessWithMissingMappings};
+use numa::{MemAccessStats, NodeSampleCounts};
 use object::pe::{ImageNtHeaders32, ImageNtHeaders64};
 use object::read::pe::{ImageNtHeaders, ImageOptionalHeader, PeFile};
-use object::{
-    FileKind, Object, ObjectSection, ObjectSegment, ObjectSymbol, SectionKind, SymbolKind,
-};
+use object::{FileKind, Object, ObjectSection, ObjectSegment, SectionKind};
+use off_cpu_stack::{DeferredOffCpuGroup, MAX_DEFERRED_OFF_CPU_GROUPS};
+use probes::ProbeHandler;
+use proc_synthesis::{read_proc_process_state, read_proc_thread_name, ProcSynthesis};
+use process_exits::{ExitGroupHandler, ProcessExits};
+use record_timestamps::RecordTimestamps;
+use recording_delay::RecordingStartedMarker;
+use regex::Regex;
+use reuse_pool::{ReusePool, MAX_REUSABLE_PROCESSES, MAX_REUSABLE_THREADS};
+use rss_stat::RssStatHandler;
+use sampling_bias::SamplingBiasDetector;
 use samply_symbols::{debug_id_for_object, DebugIdExt};
+use sched_switch::SchedSwitchHandler;
+use signals::SignalHandler;
+use startup::{StartupMarker, StartupTracker};
+use tracing::{debug, debug_span, info, warn};
+use unwind_validation::{
+    follows_call_aarch64, follows_call_x86_64, CodeRanges, SkippedUnwindReason, UnwindValidator,
+};
+use virtual_memory::VirtualMemoryHandler;
 use wholesym::samply_symbols;
 
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt::Debug;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use std::{ops::Range, path::Path};
 
+use self::conversion_log::{BaseSource, LoggedModule, ModuleOutcome, SkipReason};
+use self::heap_profiles::{add_heap_profile_samples, HeapProfiles};
 use self::kernel_symbols::KernelSymbols;
+use self::live_samples::LiveStackResolver;
+use self::mapped_path::{is_anonymous_mapping_path, is_device_path, BuildIdTable, MappedPath};
+use self::module_data_cache::{ModuleDataCache, ModuleSectionData};
+use self::profiling_control::{ControlMarker, PauseState, ProfilingPausedMarker};
+use crate::import::heap_profile::HeapProfile;
+use crate::import::intermediate::IntermediateWriter;
+use crate::shared::address_space_timeline::{AddressQuery, AddressSpaceTimeline};
+use crate::shared::dynamic_linking::{
+    is_dynamic_linker_name, DynamicLinkingFrameConversion, DynamicLinkingRanges,
+};
+use crate::shared::frame_filter::{
+    FrameFilter, HiddenFrameConversion, HiddenFrameRanges, HideRule,
+};
+use crate::shared::gc_detection::{GcConversion, GcDetection};
 use crate::shared::jit_category_manager::JitCategoryManager;
+use crate::shared::jit_code_embedding::JitCodeEmbedding;
 use crate::shared::jit_function_add_marker::JitFunctionAddMarker;
 use crate::shared::jit_function_recycler::JitFunctionRecycler;
-use crate::shared::jitdump_manager::JitDumpManager;
+use crate::shared::jitdump_manager::{JitDumpManager, TimestampClock};
 use crate::shared::lib_mappings::{LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue};
-use crate::shared::perf_map::try_load_perf_map;
-use crate::shared::process_sample_data::{ProcessSampleData, RssStatMember};
+use crate::shared::marker_addresses::{MarkerAddressMode, MarkerAddresses};
+use crate::shared::memory_access::{MemoryAccessCategories, MemoryAccessKind};
+use crate::shared::path_map::PathMap;
+use crate::shared::perf_map::{process_owner_uid, try_load_perf_map, PerfMapLimits};
+use crate::shared::process_sample_data::ProcessSampleData;
+use crate::shared::profile_split::{split_ranges, ConvertedProfile};
+use crate::shared::profiler_overhead::{KernelFrameClassifier, ProfilerOverheadFrameConversion};
+use crate::shared::request_attribution::{RequestAttribution, RequestAttributionConversion};
+use crate::shared::request_spans::{RequestSpanConversion, RequestSpans};
+use crate::shared::self_profile::{add_phase_markers, PhaseRecorder};
+use crate::shared::stack_converter::GuestFrameConversion;
+use crate::shared::symbol_map::SymbolMaps;
+use crate::shared::thread_groups::ThreadGroups;
 use crate::shared::timestamp_converter::TimestampConverter;
 use crate::shared::types::{StackFrame, StackMode};
 use crate::shared::unresolved_samples::{
-    UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
+    LastStackCache, StackTableStats, UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
 };
-use crate::shared::utils::open_file_with_fallback;
+use crate::shared::wine::{WineFrameConversion, WineModuleInfo};
 
+/// Extracts the registers for DWARF unwinding from a sample's user registers.
+///
+/// The kernel only delivers the registers in the event's sample_regs_user
+/// mask, ordered by register number, and `Regs::get` finds a register's slot
+/// from that mask. Recordings from other tools can use a mask without some of
+/// the registers we need, and some kernels leave out registers of samples
+/// taken during a syscall. `convert_regs` returns the name of the first
+/// missing register for such samples, and their stacks only contain the
+/// callchain.
 pub trait ConvertRegs {
     type UnwindRegs;
-    fn convert_regs(regs: &Regs) -> (u64, u64, Self::UnwindRegs);
+    /// The architecture name, for the conversion report.
+    const ARCH: &'static str;
+    fn convert_regs(regs: &Regs) -> Result<(u64, u64, Self::UnwindRegs), &'static str>;
     fn regs_mask() -> u64;
+    /// The maximum length of a call instruction.
+    const MAX_CALL_LEN: usize;
+    /// Returns whether `code` ends with a call instruction, i.e. whether the
+    /// address after it can be a return address.
+    fn follows_call(code: &[u8]) -> bool;
 }
 
 pub struct ConvertRegsX86_64;
 impl ConvertRegs for ConvertRegsX86_64 {
     type UnwindRegs = UnwindRegsX86_64;
-    fn convert_regs(regs: &Regs) -> (u64, u64, UnwindRegsX86_64) {
-        let ip = regs.get(PERF_REG_X86_IP).unwrap();
-        let sp = regs.get(PERF_REG_X86_SP).unwrap();
-        let bp = regs.get(PERF_REG_X86_BP).unwrap();
+    const ARCH: &'static str = "x86_64";
+    fn convert_regs(regs: &Regs) -> Result<(u64, u64, UnwindRegsX86_64), &'static str> {
+        let ip = regs.get(PERF_REG_X86_IP).ok_or("ip")?;
+        let sp = regs.get(PERF_REG_X86_SP).ok_or("sp")?;
+        let bp = regs.get(PERF_REG_X86_BP).ok_or("bp")?;
         let regs = UnwindRegsX86_64::new(ip, sp, bp);
-        (ip, sp, regs)
+        Ok((ip, sp, regs))
     }
 
     fn regs_mask() -> u64 {
         1 << PERF_REG_X86_IP | 1 << PERF_REG_X86_SP | 1 << PERF_REG_X86_BP
     }
+
+    const MAX_CALL_LEN: usize = 7;
+
+    fn follows_call(code: &[u8]) -> bool {
+        follows_call_x86_64(code)
+    }
 }
 
 pub struct ConvertRegsAarch64;
 impl ConvertRegs for ConvertRegsAarch64 {
     type UnwindRegs = UnwindRegsAarch64;
-    fn convert_regs(regs: &Regs) -> (u64, u64, UnwindRegsAarch64) {
-        let ip = regs.get(PERF_REG_ARM64_PC).unwrap();
-        let lr = regs.get(PERF_REG_ARM64_LR).unwrap();
-        let sp = regs.get(PERF_REG_ARM64_SP).unwrap();
-        let fp = regs.get(PERF_REG_ARM64_X29).unwrap();
+    const ARCH: &'static str = "aarch64";
+    fn convert_regs(regs: &Regs) -> Result<(u64, u64, UnwindRegsAarch64), &'static str> {
+        let ip = regs.get(PERF_REG_ARM64_PC).ok_or("pc")?;
+        let lr = regs.get(PERF_REG_ARM64_LR).ok_or("lr")?;
+        let sp = regs.get(PERF_REG_ARM64_SP).ok_or("sp")?;
+        let fp = regs.get(PERF_REG_ARM64_X29).ok_or("x29")?;
         let regs = UnwindRegsAarch64::new(lr, sp, fp);
-        (ip, sp, regs)
+        Ok((ip, sp, regs))
     }
 
     fn regs_mask() -> u64 {
@@ -94,18 +250,51 @@ impl ConvertRegs for ConvertRegsAarch64 {
             | 1 << PERF_REG_ARM64_SP
             | 1 << PERF_REG_ARM64_X29
     }
+
+    const MAX_CALL_LEN: usize = 4;
+
+    fn follows_call(code: &[u8]) -> bool {
+        follows_call_aarch64(code)
+    }
+}
+
+/// Warns if the main event samples user registers, but not all of the ones
+/// which DWARF unwinding needs.
+pub fn check_sampled_user_regs<C: ConvertRegs>(attrs: &[AttributeDescription]) {
+    let sampled_mask = attrs[0].attr.sample_regs_user;
+    let missing_mask = C::regs_mask() & !sampled_mask;
+    if sampled_mask != 0 && missing_mask != 0 {
+        warn!(
+            sampled_mask,
+            missing_mask,
+            "The recording's user register mask 0x{sampled_mask:x} lacks the registers \
+             0x{missing_mask:x}, which are needed for DWARF unwinding. The stacks only contain \
+             the callchains."
+        );
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct EventInterpretation {
     pub main_event_attr_index: usize,
-    #[allow(unused)]
     pub main_event_name: String,
     pub sampling_is_time_based: Option<u64>,
     pub have_context_switches: bool,
-    pub sched_switch_attr_index: Option<usize>,
-    pub rss_stat_attr_index: Option<usize>,
+    /// The attribute indexes of a (cycles, reference cycles) event pair, from
+    /// which the effective CPU frequency can be computed.
+    pub frequency_event_attr_indexes: Option<(usize, usize)>,
     pub event_names: Vec<String>,
+    /// The clock of the sample timestamps.
+    pub clock: TimestampClock,
+    /// Whether the main event isn't a sampling event, e.g. in a recording of
+    /// only tracepoints. The profile then only has markers, and off-CPU
+    /// samples from the context switches.
+    pub marker_only: bool,
+    /// The attribute indexes of the sampled events other than the main event,
+    /// e.g. instructions in a recording of cycles and instructions. Their
+    /// samples go on a track per event and process instead of becoming
+    /// markers.
+    pub event_track_attr_indexes: Vec<usize>,
 }
 
 impl EventInterpretation {
@@ -116,10 +305,12 @@ impl EventInterpretation {
             .as_deref()
             .unwrap_or("<unnamed event>")
             .to_string();
+        // Tracepoints are recorded with a period of 1, but they say when
+        // something happened, not where the time went.
+        let marker_only = matches!(attrs[0].attr.type_, PerfEventType::Tracepoint(_))
+            || matches!(attrs[0].attr.sampling_policy, SamplingPolicy::NoSampling);
         let sampling_is_time_based = match (attrs[0].attr.type_, attrs[0].attr.sampling_policy) {
-            (_, SamplingPolicy::NoSampling) => {
-                panic!("Can only convert profiles with sampled events")
-            }
+            _ if marker_only => None,
             (_, SamplingPolicy::Frequency(freq)) => {
                 let nanos = 1_000_000_000 / freq;
                 Some(nanos)
@@ -134,15 +325,9 @@ impl EventInterpretation {
                 let nanos = u64::from(period);
                 Some(nanos)
             }
-            (_, SamplingPolicy::Period(_)) => None,
+            (_, SamplingPolicy::NoSampling | SamplingPolicy::Period(_)) => None,
         };
         let have_context_switches = attrs[0].attr.flags.contains(AttrFlags::CONTEXT_SWITCH);
-        let sched_switch_attr_index = attrs
-            .iter()
-            .position(|attr_desc| attr_desc.name.as_deref() == Some("sched:sched_switch"));
-        let rss_stat_attr_index = attrs
-            .iter()
-            .position(|attr_desc| attr_desc.name.as_deref() == Some("kmem:rss_stat"));
         let event_names = attrs
             .iter()
             .enumerate()
@@ -152,6 +337,21 @@ impl EventInterpretation {
                     .clone()
                     .unwrap_or_else(|| format!("<unknown event {attr_index}>"))
             })
+            .collect::<Vec<_>>();
+        let frequency_event_attr_indexes = find_frequency_event_pair(&event_names);
+        let clock = match attrs[0].attr.clock {
+            PerfClock::ClockId(ClockId::Monotonic) => TimestampClock::Monotonic,
+            _ => TimestampClock::Other,
+        };
+        let event_track_attr_indexes = attrs
+            .iter()
+            .enumerate()
+            .filter(|(attr_index, attr_desc)| {
+                *attr_index != main_event_attr_index
+                    && !matches!(attr_desc.attr.type_, PerfEventType::Tracepoint(_))
+                    && !matches!(attr_desc.attr.sampling_policy, SamplingPolicy::NoSampling)
+            })
+            .map(|(attr_index, _)| attr_index)
             .collect();
 
         Self {
@@ -159,11 +359,196 @@ impl EventInterpretation {
             main_event_name,
             sampling_is_time_based,
             have_context_switches,
-            sched_switch_attr_index,
-            rss_stat_attr_index,
+            frequency_event_attr_indexes,
             event_names,
+            clock,
+            marker_only,
+            event_track_attr_indexes,
         }
     }
+
+    /// The interpretation with the attribute indexes which the converter
+    /// uses for an input whose attributes start at `offset`, see
+    /// [`Converter::start_next_input`].
+    pub fn with_attr_index_offset(mut self, offset: usize) -> Self {
+        self.main_event_attr_index += offset;
+        self.frequency_event_attr_indexes = self
+            .frequency_event_attr_indexes
+            .map(|(cycles, ref_cycles)| (cycles + offset, ref_cycles + offset));
+        for attr_index in &mut self.event_track_attr_indexes {
+            *attr_index += offset;
+        }
+        self
+    }
+}
+
+/// Options for samples which were taken while a KVM guest was running.
+#[derive(Debug, Clone, Default)]
+pub struct GuestOptions {
+    /// Whether guest samples should be dropped from the profile.
+    pub drop_samples: bool,
+    /// A copy of the guest's /proc/kallsyms, for symbolicating guest kernel frames.
+    pub kallsyms: Option<PathBuf>,
+    /// A copy of the guest's /proc/modules, or a directory containing it.
+    pub modules: Option<PathBuf>,
+}
+
+/// Options which control how perf events are converted into a profile.
+#[derive(Default)]
+pub struct ConversionOptions {
+    /// Whether a new thread should be merged into a previously exited
+    /// thread of the same name.
+    pub merge_threads: bool,
+    /// Whether repeated frames at the base of the stack should be folded
+    /// into one frame.
+    pub fold_recursive_prefix: bool,
+    /// Whether threads which exit without any samples should get a
+    /// synthesized sample with their last known stack.
+    pub synthesize_samples_for_short_threads: bool,
+    /// Whether a PLT stub frame should be merged into the frame of the real
+    /// callee if both are on the stack.
+    pub fold_plt: bool,
+    /// Whether the Unix-side frames of a Wine system call or callback should
+    /// be replaced by a single "Windows syscall" frame, if they're called by
+    /// a PE frame and lead back into PE code.
+    pub fold_wine_syscalls: bool,
+    /// Whether the kernel frames of perf's sampling interrupt should be
+    /// removed from the stacks. The interrupted frame gets the "Profiler
+    /// overhead (kernel)" category instead.
+    pub strip_profiler_frames: bool,
+    /// The frames which are removed from sample and marker stacks, see
+    /// [`FrameFilter`].
+    pub hidden_frame_rules: Vec<HideRule>,
+    /// The regexes for `--attribute-by-frame`, see [`RequestAttribution`].
+    pub request_attribution_regexes: Vec<Regex>,
+    /// The spans of `--request-spans`, whose request IDs are added to the
+    /// samples in them. See [`RequestSpans`].
+    pub request_spans: Option<RequestSpans>,
+    /// Whether to add markers for the times at which a process is mostly
+    /// collecting garbage, for `--detect-gc`.
+    pub detect_gc_pauses: bool,
+    /// Whether sample stacks should be reduced to the sampled instruction
+    /// pointer, ignoring any callchain or user stack in the samples.
+    pub leaf_only: bool,
+    /// The maximum number of frames of a sample stack, for
+    /// `--max-stack-depth`. Deeper stacks keep their callee-most frames and
+    /// end in a [`StackFrame::TruncatedStackMarker`].
+    pub max_stack_depth: Option<usize>,
+    /// The number of stack table nodes after which new stacks are cut off,
+    /// for `--max-stack-nodes`. See [`UnresolvedStacks::with_max_nodes`].
+    pub max_stack_nodes: Option<usize>,
+    /// How the return addresses found by DWARF unwinding are checked, for
+    /// `--unwind-validation`.
+    pub unwind_validation: UnwindValidation,
+    /// Whether the samples of time-based sampling are weighted by the
+    /// observed sampling interval of their CPU, for `--normalize-cpu-rates`.
+    /// See [`CpuSampleRates`].
+    pub normalize_cpu_rates: bool,
+    /// The budget in bytes for embedding the code of the hottest JIT
+    /// functions into the profile, for `--embed-jit-code`. See
+    /// [`JitCodeEmbedding`].
+    pub embed_jit_code: Option<u64>,
+    /// How the addresses in marker payloads are written, for
+    /// `--resolve-marker-addresses` and `--redact-marker-addresses`.
+    pub marker_address_mode: MarkerAddressMode,
+    /// Which stack the off-CPU samples get, for `--off-cpu-stack`.
+    pub off_cpu_stack: OffCpuStack,
+    /// The size of the time buckets into which the samples of the memory
+    /// counters are aggregated, see
+    /// [`CounterBuckets`](crate::shared::counter_buckets::CounterBuckets).
+    /// None means the sampling interval, and 0 means that samples aren't
+    /// aggregated.
+    pub counter_bucket_duration_ns: Option<u64>,
+    pub guest: GuestOptions,
+    /// Handlers for additional tracepoint events. These run before the
+    /// built-in handlers for the same event. This is a crate-internal
+    /// extension point, see [`TracepointHandler`].
+    pub tracepoint_handlers: Vec<Box<dyn TracepointHandler>>,
+    /// Signal numbers which get no signal_deliver markers, unless the signal
+    /// terminated the process.
+    pub ignored_signals: Vec<i32>,
+    /// The tracepoint formats from the tracing data of the recording, which
+    /// are used to decode the arguments of uprobe and kprobe events, and the
+    /// name fields of `--marker-pair` events.
+    pub tracepoint_formats: TracepointFormats,
+    /// The NUMA node of each CPU of the recording machine, for the per-node
+    /// sample counters. None if the recording has no NUMA topology, or only
+    /// one node.
+    pub numa_topology: Option<NumaTopology>,
+    /// Named thread groups with the regexes which select their threads by
+    /// name. A thread belongs to the first group whose regex matches.
+    pub thread_groups: Vec<(String, Regex)>,
+    /// Jitdump files which aren't referenced by the perf.data file, e.g. copies
+    /// which were collected next to it, by pid.
+    pub jitdump_paths_by_pid: HashMap<i32, Vec<PathBuf>>,
+    /// Whether the caller can read /proc/<pid>/maps for processes whose mmap
+    /// records are missing after an exec. See
+    /// [`Converter::take_processes_needing_mapping_snapshot`].
+    pub take_mapping_snapshots: bool,
+    /// Whether the state of processes which have samples but no COMM or MMAP
+    /// records is read from /proc at import time, for
+    /// `--synthesize-from-proc`. See [`ProcSynthesis`].
+    pub synthesize_from_proc: bool,
+    /// The limits for /tmp/perf-<pid>.map files, for `--perf-map-max-bytes`
+    /// and `--perf-map-max-entries`.
+    pub perf_map_limits: PerfMapLimits,
+    /// Whether samples are attributed to one thread per CPU instead of to
+    /// the sampled threads, for `--per-cpu-threads`. See [`Cpus`].
+    pub per_cpu_threads: bool,
+    /// Whether the executable mappings of the processes are kept, so that
+    /// [`Converter::checkpoint`] can carry them into the next file of a
+    /// rotating recording.
+    pub track_mappings_for_checkpoints: bool,
+    /// jemalloc or tcmalloc heap profiles of the recorded processes, which are
+    /// added as "Heap" threads whose sample weights are live bytes.
+    pub heap_profiles: Vec<HeapProfile>,
+    /// Records the stages of the conversion, e.g. module loads, for
+    /// `--self-profile`.
+    pub phases: Option<PhaseRecorder>,
+    /// Path prefix translations which are tried first when opening binaries,
+    /// jitdump files and perf map files, for `--path-map`.
+    pub path_map: PathMap,
+    /// Which rss_stat and other-event markers keep their stack.
+    pub marker_stacks: MarkerStacks,
+    /// perf build ID caches with copies of binaries which have been updated
+    /// or removed since the recording.
+    pub build_id_caches: BuildIdCaches,
+    /// How long opening a binary may take before it's skipped, see
+    /// [`FileOpenCache`]. None means that opens aren't timed.
+    pub file_open_timeout: Option<Duration>,
+    /// Symbol tables for libraries without symbols, for `--symbol-map`.
+    pub symbol_maps: SymbolMaps,
+    /// Accumulates the time spent per record type and in the costly parts of
+    /// sample handling, for `--timings`. The table is printed at the end of
+    /// the conversion.
+    pub timings: Option<ConversionTimings>,
+    /// Lookups of the library which an address belonged to at some time,
+    /// which are answered at the end of the conversion, for `--query`.
+    pub address_queries: Vec<AddressQuery>,
+    /// Receives the module loads and record counts of the conversion, for
+    /// `--record-conversion-log`.
+    pub conversion_log: Option<ConversionLog>,
+    /// Receives the records which are converted, with their unwound stacks,
+    /// for `--emit-intermediate`. Taken out by the record loop, which writes
+    /// the records before it passes them to the converter.
+    pub intermediate: Option<IntermediateWriter>,
+    /// Dumps the converter's state if the conversion stalls, for
+    /// `--watchdog`. The watchdog is started by the record loop which drives
+    /// the converter, see [`Converter::metrics`].
+    pub watchdog: Option<WatchdogConfig>,
+    /// The estimated JSON size above which the profile is split into parts
+    /// by time, for `--max-output-size`. See [`Converter::finish_in_parts`].
+    pub max_output_size: Option<u64>,
+    /// Whether a `perf record --delay` at the start of a perf.data file is
+    /// kept in the profile, instead of starting the profile just before the
+    /// first sample. See [`RecordingDelay`].
+    pub keep_recording_delay: bool,
+    /// Copies the binaries of processes in other mount namespaces while
+    /// recording, so that containerized processes can be symbolicated.
+    pub container_binaries: Option<ContainerBinaries>,
+    /// Receives the samples of the main event as they're converted, with
+    /// their frames resolved to libraries, for `samply top`.
+    pub live_samples: Option<LiveSampleSink>,
 }
 
 pub type BoxedProductNameGenerator = Box<dyn FnOnce(&str) -> String>;
@@ -176,34 +561,142 @@ struct SuspectedPeMapping {
     size: u64,
 }
 
+/// A kernel image or kernel module mapping which was added to the profile.
+///
+/// perf can emit the kernel mmap record more than once, e.g. once per CPU as
+/// "[kernel.kallsyms]_text", so we keep track of these to avoid adding the same
+/// mapping multiple times.
+#[derive(Debug, Clone)]
+struct KernelMapping {
+    end: u64,
+    dso_key: DsoKey,
+    build_id: Option<Vec<u8>>,
+}
+
 pub struct Converter<U>
 where
-    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+    U: Unwinder<Module = Module<ModuleData>> + Default,
 {
     cache: U::Cache,
     profile: Profile,
     processes: Processes<U>,
     timestamp_converter: TimestampConverter,
     current_sample_time: u64,
-    build_ids: HashMap<DsoKey, DsoInfo>,
+    build_ids: BuildIdTable,
     endian: Endianness,
     have_product_name: bool,
     delayed_product_name_generator: Option<BoxedProductNameGenerator>,
     linux_version: Option<String>,
     extra_binary_artifact_dir: Option<PathBuf>,
+    /// See [`ConversionOptions::path_map`]. `None` if there are no rules.
+    path_map: Option<Arc<PathMap>>,
+
+    /// See [`ConversionOptions::container_binaries`].
+    container_binaries: Option<ContainerBinaries>,
+    build_id_caches: BuildIdCaches,
     context_switch_handler: ContextSwitchHandler,
     unresolved_stacks: UnresolvedStacks,
+    /// The number of samples whose stack was cut off by `--max-stack-nodes`.
+    /// This is fewer than the cut off stacks of the stack table, which also
+    /// counts the stacks of markers and of off-CPU state.
+    truncated_sample_count: u64,
     off_cpu_weight_per_sample: i32,
     have_context_switches: bool,
+    /// Whether the main event counts something other than time, e.g.
+    /// instructions. Its samples are then weighted by their period, and only
+    /// context switches give them a CPU delta.
+    event_count_weights: bool,
+    /// The sampling rate of each CPU, for time-based sampling.
+    cpu_sample_rates: Option<CpuSampleRates>,
+
+    /// Set for `--embed-jit-code`.
+    jit_code_embedding: Option<JitCodeEmbedding>,
+
+    /// Formats the addresses in marker payloads.
+    marker_addresses: MarkerAddresses,
+    /// See [`ConversionOptions::off_cpu_stack`].
+    off_cpu_stack: OffCpuStack,
+    /// The number of threads with a [`DeferredOffCpuGroup`], which is at most
+    /// [`MAX_DEFERRED_OFF_CPU_GROUPS`].
+    deferred_off_cpu_group_count: usize,
     event_names: Vec<String>,
+    main_event_attr_index: usize,
+    /// See [`EventInterpretation::marker_only`]. Off-CPU sample groups aren't
+    /// deferred then, because no sample comes along with the resumed stack.
+    marker_only: bool,
+    /// The number of samples of each event attribute which were skipped
+    /// because the attribute's sample format lacks a field which the
+    /// conversion needs, e.g. the pid or the timestamp.
+    skipped_sample_counts: BTreeMap<usize, u64>,
+    /// The number of context switch records which were skipped because they
+    /// lack the pid, tid or timestamp.
+    skipped_context_switch_count: u64,
     kernel_symbols: Option<KernelSymbols>,
 
     /// Mapping of start address to potential mapped PE binaries.
     /// The key is equal to the start field of the value.
     suspected_pe_mappings: BTreeMap<u64, SuspectedPeMapping>,
 
+    /// The kernel image and kernel module mappings which have been added to
+    /// the profile, keyed by start address.
+    kernel_mappings: BTreeMap<u64, KernelMapping>,
+
+    /// The libraries of the `kernel_mappings`. These come from the kernel's
+    /// mmap records and are the only mappings which kernel frames are looked
+    /// up in; user frames are never looked up in them.
+    kernel_lib_mappings: LibMappings<LibMappingInfo>,
+
     jit_category_manager: JitCategoryManager,
 
+    /// Present if the profile has a pair of events from which we can compute
+    /// the effective CPU frequency.
+    cpu_frequency_calculator: Option<CpuFrequencyCalculator>,
+
+    /// Present if the recording has a NUMA topology with more than one node.
+    node_sample_counts: Option<NodeSampleCounts>,
+
+    /// The local and remote accesses of memory access events, e.g. from
+    /// `perf mem record`.
+    mem_access_stats: MemAccessStats,
+
+    /// Whether samples from KVM guests should be dropped.
+    drop_guest_samples: bool,
+
+    /// Whether we've seen any samples from KVM guests. Guest frames in the
+    /// stacks of host samples are found in `finish`.
+    have_guest_samples: bool,
+
+    /// Whether any PE images or Wine modules have been mapped.
+    have_wine_modules: bool,
+
+    /// Present if any frames should be hidden.
+    frame_filter: Option<FrameFilter>,
+
+    /// Present if samples should be attributed to requests.
+    request_attribution: Option<RequestAttribution>,
+
+    /// Present if samples should be labeled with the request spans they're in.
+    request_spans: Option<RequestSpans>,
+
+    /// Recognizes the samples of garbage collectors, which get a GC category.
+    gc_detection: GcDetection,
+
+    /// The mappings of the guest kernel and its modules, if the user supplied
+    /// the guest's kallsyms.
+    guest_kernel_lib_mappings: Option<LibMappings<LibMappingInfo>>,
+
+    /// The registered tracepoint handlers, followed by the built-in ones.
+    tracepoint_handlers: Vec<Box<dyn TracepointHandler>>,
+
+    /// For each event attribute, the indexes of the tracepoint handlers in
+    /// `tracepoint_handlers` which handle its samples.
+    tracepoint_handler_indexes_by_attr_index: Vec<Vec<usize>>,
+
+    /// See [`ConversionOptions::tracepoint_formats`].
+    tracepoint_formats: TracepointFormats,
+
+    thread_groups: ThreadGroups,
+
     /// Whether a new thread should be merged into a previously exited
     /// thread of the same name.
     merge_threads: bool,
@@ -211,13 +704,153 @@ where
     /// Whether repeated frames at the base of the stack should be folded
     /// into one frame.
     fold_recursive_prefix: bool,
+
+    /// See [`ConversionOptions::leaf_only`].
+    leaf_only: bool,
+
+    /// See [`ConversionOptions::max_stack_depth`].
+    max_stack_depth: Option<usize>,
+
+    /// See [`ConversionOptions::unwind_validation`].
+    unwind_validator: UnwindValidator,
+
+    /// See [`ConversionOptions::take_mapping_snapshots`].
+    take_mapping_snapshots: bool,
+
+    /// Processes which were sampled after an exec without any executable mmap
+    /// records, for the report at the end of the conversion.
+    processes_with_missing_mappings: Vec<ProcessWithMissingMappings>,
+
+    /// Indexes into `processes_with_missing_mappings` of the processes for
+    /// which a /proc/<pid>/maps snapshot should be taken.
+    pending_mapping_snapshots: Vec<usize>,
+
+    /// Some() for `--synthesize-from-proc`.
+    proc_synthesis: Option<ProcSynthesis>,
+
+    /// The heap profiles which haven't been added to the profile yet.
+    heap_profiles: HeapProfiles,
+
+    /// See [`ConversionOptions::phases`].
+    phases: Option<PhaseRecorder>,
+
+    /// The unwind data and text bytes of the binaries which have been mapped
+    /// into any process, shared between the processes' unwinders.
+    module_data_cache: ModuleDataCache,
+
+    /// The time ranges during which the profiled application paused sampling.
+    pause_state: PauseState,
+
+    /// Decompressed copies of `.ko.xz` and `.ko.zst` kernel modules.
+    compressed_modules: CompressedModuleCache,
+
+    /// The binaries which couldn't be opened or parsed, so that they're only
+    /// tried once.
+    open_cache: FileOpenCache,
+
+    /// See [`ConversionOptions::symbol_maps`].
+    symbol_maps: SymbolMaps,
+
+    /// See [`ConversionOptions::timings`].
+    timings: Option<ConversionTimings>,
+
+    /// Set once the kernel image mapping with the running kernel's symbols
+    /// has been added.
+    cow_fault_detector: Option<CowFaultDetector>,
+
+    /// Set at the same time as `cow_fault_detector`.
+    sampling_bias_detector: Option<SamplingBiasDetector>,
+
+    /// Set at the same time as `cow_fault_detector`.
+    kernel_frame_classifier: Option<KernelFrameClassifier>,
+
+    /// The copy-on-write faults after each fork, for the report at the end of
+    /// the conversion.
+    cow_faults_after_fork: Vec<CowFaultsAfterFork>,
+
+    have_cow_fault_samples: bool,
+
+    /// Whether any samples were added with
+    /// [`Converter::handle_memory_latency_sample`].
+    have_memory_latency_samples: bool,
+
+    /// The startup of the processes which were launched during the recording,
+    /// for the startup markers and the report at the end of the conversion.
+    startups: StartupTracker,
+
+    /// Decides which rss_stat and other-event markers get a stack. The
+    /// time bucket is the sampling interval.
+    marker_stack_filter: MarkerStackFilter,
+
+    /// The time ranges of the threads with a FORK or EXIT record, for
+    /// routing samples which arrive after their thread has exited.
+    thread_incarnations: ThreadIncarnations,
+
+    /// The libraries which were added without a build ID because their file
+    /// couldn't be opened, so that [`Converter::add_late_build_ids`] can fill
+    /// in their IDs.
+    libs_without_build_id: Vec<(DsoKey, LibraryHandle)>,
+
+    /// See [`ConversionOptions::address_queries`].
+    address_queries: Vec<AddressQuery>,
+
+    /// See [`ConversionOptions::conversion_log`].
+    conversion_log: Option<ConversionLog>,
+
+    /// Present if the samples are sent to a [`LiveSampleSink`].
+    live_stack_resolver: Option<LiveStackResolver>,
+
+    /// The range of the record timestamps, which the profile's timestamps
+    /// are clamped to at the end.
+    record_timestamps: RecordTimestamps,
+
+    /// The `perf record --delay` of the recording, until the "Recording
+    /// started" markers are added at the first sample.
+    pending_recording_delay: Option<RecordingDelay>,
+
+    /// The LBR call stack of the sample which is being handled, for
+    /// recordings with `--call-graph lbr`. See [`Self::set_sample_lbr_calls`].
+    sample_lbr_calls: Vec<LbrCall>,
+
+    /// See [`ConversionOptions::max_output_size`].
+    max_output_size: Option<u64>,
+
+    /// The time ranges of the off-CPU sample groups with more than one
+    /// sample, which a split profile must not be split within. Only collected
+    /// if there's a `max_output_size`.
+    off_cpu_ranges: Vec<(Timestamp, Timestamp)>,
+
+    /// The per-CPU threads, if samples are attributed to them. See
+    /// [`ConversionOptions::per_cpu_threads`].
+    cpus: Option<Cpus>,
+
+    /// The executable mappings of the processes, if they're tracked. See
+    /// [`ConversionOptions::track_mappings_for_checkpoints`].
+    mapping_tracker: Option<MappingTracker>,
+
+    /// Why the processes ended, for their "Process exit" markers.
+    process_exits: ProcessExits,
 }
 
 const DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS: u64 = 1_000_000; // 1ms
 
+/// The size of the time windows for which we compute the effective CPU frequency.
+const CPU_FREQUENCY_BUCKET_DURATION_NS: u64 = 10_000_000; // 10ms
+
+/// The size of the time windows for which we count the samples per NUMA node.
+const NUMA_NODE_BUCKET_DURATION_NS: u64 = 10_000_000; // 10ms
+
+/// If an ended thread is reused for a new thread within this time window, and
+/// the ended thread was blocked at the time it ended, the new thread inherits
+/// the blocked state. See [`Thread::reset_for_reuse`].
+const THREAD_REUSE_CARRY_OVER_WINDOW_NS: u64 = 5_000_000; // 5ms
+
+/// The number of previous names which are kept for each thread.
+const THREAD_NAME_HISTORY_LEN: usize = 8;
+
 impl<U> Converter<U>
 where
-    U: Unwinder<Module = Module<Vec<u8>>> + Default,
+    U: Unwinder<Module = Module<ModuleData>> + Default,
 {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -230,2068 +863,6456 @@ where
         cache: U::Cache,
         extra_binary_artifact_dir: Option<&Path>,
         interpretation: EventInterpretation,
-        merge_threads: bool,
-        fold_recursive_prefix: bool,
+        options: ConversionOptions,
     ) -> Self {
+        let ConversionOptions {
+            merge_threads,
+            fold_recursive_prefix,
+            synthesize_samples_for_short_threads,
+            fold_plt,
+            fold_wine_syscalls,
+            strip_profiler_frames,
+            hidden_frame_rules,
+            request_attribution_regexes,
+            request_spans,
+            detect_gc_pauses,
+            leaf_only,
+            max_stack_depth,
+            max_stack_nodes,
+            unwind_validation,
+            normalize_cpu_rates,
+            embed_jit_code,
+            marker_address_mode,
+            off_cpu_stack,
+            counter_bucket_duration_ns,
+            guest: guest_options,
+            mut tracepoint_handlers,
+            ignored_signals,
+            tracepoint_formats,
+            numa_topology,
+            thread_groups,
+            jitdump_paths_by_pid,
+            take_mapping_snapshots,
+            synthesize_from_proc,
+            perf_map_limits,
+            per_cpu_threads,
+            track_mappings_for_checkpoints,
+            heap_profiles,
+            phases,
+            path_map,
+            marker_stacks,
+            build_id_caches,
+            file_open_timeout,
+            symbol_maps,
+            timings,
+            address_queries,
+            conversion_log,
+            // Used by the caller.
+            intermediate: _,
+            watchdog: _,
+            keep_recording_delay: _,
+            container_binaries,
+            max_output_size,
+            live_samples,
+        } = options;
+        let path_map = (!path_map.is_empty()).then(|| Arc::new(path_map));
         let interval = match interpretation.sampling_is_time_based {
             Some(nanos) => SamplingInterval::from_nanos(nanos),
             None => SamplingInterval::from_millis(1),
         };
-        let profile = Profile::new(
+        let mut profile = Profile::new(
             product,
             ReferenceTimestamp::from_system_time(SystemTime::now()),
             interval,
         );
-        let (off_cpu_sampling_interval_ns, off_cpu_weight_per_sample) =
-            match &interpretation.sampling_is_time_based {
-                Some(interval_ns) => (*interval_ns, 1),
-                None => (DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS, 0),
-            };
+        let off_cpu_sampling_interval_ns = interpretation
+            .sampling_is_time_based
+            .unwrap_or(DEFAULT_OFF_CPU_SAMPLING_INTERVAL_NS);
+        // Without samples, the off-CPU samples are all there is, so they get
+        // a weight even though there's no sampling interval.
+        let off_cpu_weight_per_sample = i32::from(
+            interpretation.sampling_is_time_based.is_some() || interpretation.marker_only,
+        );
         let kernel_symbols = match KernelSymbols::new_for_running_kernel() {
             Ok(kernel_symbols) => Some(kernel_symbols),
             Err(err) => {
-                eprintln!("Could not obtain kernel symbols: {err}");
+                warn!(error = %err, "Could not obtain kernel symbols: {err}");
                 None
             }
         };
+        let cpu_frequency_calculator = interpretation.frequency_event_attr_indexes.map(
+            |(cycles_attr_index, ref_cycles_attr_index)| {
+                CpuFrequencyCalculator::new(
+                    cycles_attr_index,
+                    ref_cycles_attr_index,
+                    CPU_FREQUENCY_BUCKET_DURATION_NS,
+                )
+            },
+        );
+        let guest_kernel_lib_mappings = match &guest_options.kallsyms {
+            Some(kallsyms) => match guest_kernel_lib_mappings(
+                &mut profile,
+                kallsyms,
+                guest_options.modules.as_deref(),
+            ) {
+                Ok(mappings) => Some(mappings),
+                Err(err) => {
+                    warn!(error = %err, "Could not load the guest kernel symbols: {err}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let event_count_weights =
+            interpretation.sampling_is_time_based.is_none() && !interpretation.marker_only;
+        if interpretation.marker_only {
+            warn!(
+                "The main event {} isn't a sampling event, so the profile only has markers, and \
+                 off-CPU samples if context switches were recorded",
+                interpretation.main_event_name
+            );
+            profile.add_extra_info(
+                "Samply",
+                "Samples",
+                &format!(
+                    "Marker only: the main event {} isn't a sampling event, so there are no \
+                     CPU samples. Samples are off-CPU time from the context switches.",
+                    interpretation.main_event_name
+                ),
+            );
+        }
+        if event_count_weights {
+            profile.set_sample_weight_unit(&interpretation.main_event_name);
+            profile.add_extra_info(
+                "Samply",
+                "Sample weight",
+                &format!(
+                    "{} count: each sample is weighted by its sample period",
+                    interpretation.main_event_name
+                ),
+            );
+        }
+        if !interpretation.event_track_attr_indexes.is_empty() {
+            let event_names: Vec<&str> = interpretation
+                .event_track_attr_indexes
+                .iter()
+                .map(|&attr_index| interpretation.event_names[attr_index].as_str())
+                .collect();
+            profile.add_extra_info(
+                "Samply",
+                "Event tracks",
+                &format!(
+                    "{}: each process has a track per event, whose samples are weighted by \
+                     their sample period",
+                    event_names.join(", ")
+                ),
+            );
+        }
+        if leaf_only {
+            profile.add_extra_info(
+                "Samply",
+                "Stacks",
+                "Leaf only: samples only contain the sampled function, without its callers",
+            );
+        }
+        if request_spans.is_some() && interpretation.clock != TimestampClock::Monotonic {
+            warn!(
+                "The --request-spans timestamps are expected to be CLOCK_MONOTONIC, but the \
+                 recording uses a different clock, so samples may get the wrong request. Record \
+                 with `perf record -k CLOCK_MONOTONIC` to use the same clock."
+            );
+        }
+        tracepoint_handlers.push(Box::new(SchedSwitchHandler));
+        let counter_bucket_duration_ns = counter_bucket_duration_ns.unwrap_or(interval.nanos());
+        tracepoint_handlers.push(Box::new(RssStatHandler::new(counter_bucket_duration_ns)));
+        tracepoint_handlers.push(Box::new(VirtualMemoryHandler::new(
+            counter_bucket_duration_ns,
+        )));
+        tracepoint_handlers.push(Box::<FutexHandler>::default());
+        tracepoint_handlers.push(Box::<CfsThrottleHandler>::default());
+        tracepoint_handlers.push(Box::new(SignalHandler::new(ignored_signals)));
+        tracepoint_handlers.push(Box::<ExitGroupHandler>::default());
+        tracepoint_handlers.push(Box::<ProbeHandler>::default());
+        let tracepoint_handler_indexes_by_attr_index = interpretation
+            .event_names
+            .iter()
+            .map(|name| tracepoint_handler_indexes(&tracepoint_handlers, name))
+            .collect();
+        let open_cache = FileOpenCache::new(file_open_timeout);
+        let heap_profiles = HeapProfiles::new(heap_profiles, |path| {
+            build_id_for_file(
+                path,
+                extra_binary_artifact_dir,
+                path_map.as_deref(),
+                &open_cache,
+            )
+        });
         Self {
             profile,
             cache,
-            processes: Processes::new(merge_threads),
+            processes: Processes::new(
+                merge_threads,
+                synthesize_samples_for_short_threads,
+                fold_plt,
+                fold_wine_syscalls,
+                strip_profiler_frames,
+                jitdump_paths_by_pid,
+                path_map.clone(),
+                perf_map_limits,
+                take_mapping_snapshots,
+                interpretation.clock,
+            ),
             timestamp_converter: TimestampConverter::with_reference_timestamp(first_sample_time),
             current_sample_time: first_sample_time,
-            build_ids,
+            build_ids: BuildIdTable::new(build_ids),
             endian,
             have_product_name: delayed_product_name_generator.is_none(),
             delayed_product_name_generator,
             linux_version: linux_version.map(ToOwned::to_owned),
             extra_binary_artifact_dir: extra_binary_artifact_dir.map(ToOwned::to_owned),
+            path_map,
+            container_binaries,
+            build_id_caches,
             off_cpu_weight_per_sample,
             context_switch_handler: ContextSwitchHandler::new(off_cpu_sampling_interval_ns),
-            unresolved_stacks: UnresolvedStacks::default(),
+            unresolved_stacks: UnresolvedStacks::with_max_nodes(max_stack_nodes),
+            truncated_sample_count: 0,
             have_context_switches: interpretation.have_context_switches,
+            event_count_weights,
+            cpu_sample_rates: interpretation
+                .sampling_is_time_based
+                .map(|interval_ns| CpuSampleRates::new(interval_ns, normalize_cpu_rates)),
+            jit_code_embedding: embed_jit_code.map(JitCodeEmbedding::new),
+            marker_addresses: MarkerAddresses::new(marker_address_mode),
+            off_cpu_stack,
+            deferred_off_cpu_group_count: 0,
             event_names: interpretation.event_names,
+            main_event_attr_index: interpretation.main_event_attr_index,
+            marker_only: interpretation.marker_only,
+            skipped_sample_counts: BTreeMap::new(),
+            skipped_context_switch_count: 0,
             kernel_symbols,
             suspected_pe_mappings: BTreeMap::new(),
+            kernel_mappings: BTreeMap::new(),
+            kernel_lib_mappings: LibMappings::new(),
             jit_category_manager: JitCategoryManager::new(),
+            cpu_frequency_calculator,
+            node_sample_counts: numa_topology
+                .map(|topology| NodeSampleCounts::new(topology, NUMA_NODE_BUCKET_DURATION_NS)),
+            mem_access_stats: MemAccessStats::default(),
+            drop_guest_samples: guest_options.drop_samples,
+            have_guest_samples: false,
+            have_wine_modules: false,
+            frame_filter: FrameFilter::new(hidden_frame_rules),
+            request_attribution: RequestAttribution::new(request_attribution_regexes),
+            request_spans,
+            gc_detection: GcDetection::new(detect_gc_pauses),
+            guest_kernel_lib_mappings,
+            tracepoint_handlers,
+            tracepoint_handler_indexes_by_attr_index,
+            tracepoint_formats,
+            thread_groups: ThreadGroups::new(thread_groups),
             merge_threads,
             fold_recursive_prefix,
+            leaf_only,
+            max_stack_depth,
+            unwind_validator: UnwindValidator::new(unwind_validation),
+            take_mapping_snapshots,
+            processes_with_missing_mappings: Vec::new(),
+            pending_mapping_snapshots: Vec::new(),
+            proc_synthesis: synthesize_from_proc.then(|| ProcSynthesis::new(first_sample_time)),
+            heap_profiles,
+            phases,
+            module_data_cache: ModuleDataCache::default(),
+            pause_state: PauseState::default(),
+            compressed_modules: CompressedModuleCache::default(),
+            open_cache,
+            symbol_maps,
+            timings,
+            cow_fault_detector: None,
+            sampling_bias_detector: None,
+            kernel_frame_classifier: None,
+            cow_faults_after_fork: Vec::new(),
+            have_cow_fault_samples: false,
+            have_memory_latency_samples: false,
+            startups: StartupTracker::default(),
+            marker_stack_filter: MarkerStackFilter::new(
+                marker_stacks,
+                off_cpu_sampling_interval_ns,
+            ),
+            thread_incarnations: ThreadIncarnations::default(),
+            libs_without_build_id: Vec::new(),
+            address_queries,
+            conversion_log,
+            live_stack_resolver: live_samples.map(LiveStackResolver::new),
+            record_timestamps: RecordTimestamps::default(),
+            pending_recording_delay: None,
+            sample_lbr_calls: Vec::new(),
+            max_output_size,
+            off_cpu_ranges: Vec::new(),
+            cpus: per_cpu_threads.then(Cpus::default),
+            mapping_tracker: track_mappings_for_checkpoints.then(MappingTracker::default),
+            process_exits: ProcessExits::default(),
         }
     }
 
-    pub fn finish(mut self) -> Profile {
-        let mut profile = self.profile;
-        self.processes.finish(
-            &mut profile,
-            &self.unresolved_stacks,
-            &self.event_names,
-            &mut self.jit_category_manager,
-            &self.timestamp_converter,
-        );
-        profile
+    /// Sets the debug ID and code ID of the libraries which were added before
+    /// their build ID was known and whose file couldn't be opened. This is for
+    /// build IDs which only arrive at the end of a perf.data stream. Returns
+    /// the number of libraries which were updated.
+    pub fn add_late_build_ids(&mut self, build_ids: &HashMap<DsoKey, DsoInfo>) -> usize {
+        let mut updated_count = 0;
+        self.libs_without_build_id.retain(|(dso_key, lib_handle)| {
+            let Some(dso_info) = build_ids.get(dso_key) else {
+                return true;
+            };
+            let build_id = &dso_info.build_id;
+            self.profile.set_lib_ids(
+                *lib_handle,
+                DebugId::from_identifier(build_id, true), // TODO: endian
+                Some(CodeId::from_binary(build_id).to_string()),
+            );
+            updated_count += 1;
+            false
+        });
+        updated_count
     }
 
-    pub fn handle_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(&mut self, e: &SampleRecord) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        let tid = e.tid.expect("Can't handle samples without tids");
-        let timestamp = e
-            .timestamp
-            .expect("Can't handle samples without timestamps");
-        self.current_sample_time = timestamp;
+    /// Continues the conversion with the records of another perf.data file,
+    /// e.g. a recording of other processes during the same time, which end up
+    /// in the same profile. The build IDs, the byte order and the tracepoint
+    /// formats are replaced by the new file's, and its timestamps are
+    /// converted with `timestamp_converter`, which lines it up with the
+    /// earlier files.
+    ///
+    /// The event attributes of each file get their own attribute indexes,
+    /// starting at the returned offset: the records' attribute indexes need
+    /// to be shifted by it, and the interpretation for dispatching them is
+    /// [`EventInterpretation::with_attr_index_offset`]. The CPU frequency
+    /// counters are only computed for the events of the first file.
+    pub fn start_next_input(
+        &mut self,
+        build_ids: HashMap<DsoKey, DsoInfo>,
+        endian: Endianness,
+        interpretation: &EventInterpretation,
+        tracepoint_formats: TracepointFormats,
+        timestamp_converter: TimestampConverter,
+    ) -> usize {
+        let attr_index_offset = self.event_names.len();
+        for name in &interpretation.event_names {
+            let handler_indexes = tracepoint_handler_indexes(&self.tracepoint_handlers, name);
+            self.tracepoint_handler_indexes_by_attr_index
+                .push(handler_indexes);
+            self.event_names.push(name.clone());
+        }
+        self.main_event_attr_index = attr_index_offset + interpretation.main_event_attr_index;
+        self.marker_only = interpretation.marker_only;
+        self.have_context_switches = interpretation.have_context_switches;
+        self.build_ids = BuildIdTable::new(build_ids);
+        self.endian = endian;
+        self.tracepoint_formats = tracepoint_formats;
+        self.timestamp_converter = timestamp_converter;
+        attr_index_offset
+    }
 
-        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+    /// Sets the wall-clock time of the profile's zero timestamp. Without it,
+    /// the profile starts at the time of the conversion.
+    pub fn set_reference_time(&mut self, reference_time: ReferenceTimestamp) {
+        self.profile.set_reference_timestamp(reference_time);
+    }
 
-        let process = self.processes.get_by_pid(pid, &mut self.profile);
-        process.check_jitdump(
-            &mut self.jit_category_manager,
-            &mut self.profile,
-            &self.timestamp_converter,
-        );
+    /// Called for every record, before the record is handled.
+    pub fn observe_record(&mut self, record_type: RecordType) {
+        if let Some(log) = &mut self.conversion_log {
+            log.count_record(record_type);
+        }
+    }
 
-        let mut stack = Vec::new();
-        Self::get_sample_stack::<C>(
-            e,
-            &process.unwinder,
-            &mut self.cache,
-            &mut stack,
-            self.fold_recursive_prefix,
-        );
+    /// Adds a "Recording started" marker to the processes at the first sample
+    /// of the delay. If the delay isn't kept, the converter's reference
+    /// timestamp must be its [`RecordingDelay::trimmed_start`].
+    pub fn set_recording_delay(&mut self, delay: RecordingDelay, keep_delay: bool) {
+        if !keep_delay {
+            self.record_timestamps
+                .expect_early_records_from(delay.first_record_time);
+        }
+        self.pending_recording_delay = Some(delay);
+    }
 
-        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+    /// Sets the LBR call stack of the next sample record, which the record
+    /// parser doesn't read. It's used instead of the user part of the
+    /// callchain, unless the sample can be unwound with DWARF. Must be called
+    /// before each sample record, with an empty list if it has none.
+    pub fn set_sample_lbr_calls(&mut self, calls: impl IntoIterator<Item = LbrCall>) {
+        self.sample_lbr_calls.clear();
+        self.sample_lbr_calls.extend(calls);
+    }
 
-        if thread.last_sample_timestamp == Some(timestamp) {
-            // Duplicate sample. Ignore.
-            return;
+    fn add_recording_started_markers(&mut self, delay: RecordingDelay) {
+        let timestamp = self
+            .timestamp_converter
+            .convert_time(delay.first_sample_time);
+        let name = delay.marker_name();
+        for process in self.processes.processes_by_pid.values() {
+            self.profile.add_marker(
+                process.threads.main_thread.profile_thread,
+                &name,
+                RecordingStartedMarker {
+                    delay_ms: delay.duration_ns() as f64 / 1_000_000.0,
+                },
+                MarkerTiming::Instant(timestamp),
+            );
         }
+    }
 
-        thread.last_sample_timestamp = Some(timestamp);
-        let thread_handle = thread.profile_thread;
+    /// Called for every record with a timestamp, before the record is handled.
+    pub fn observe_record_timestamp(&mut self, record_type: RecordType, timestamp: u64) {
+        self.record_timestamps
+            .observe(record_type, timestamp, &self.timestamp_converter);
+    }
 
-        // Consume off-cpu time and clear any saved off-CPU stack.
-        let off_cpu_sample = self
-            .context_switch_handler
-            .handle_sample(timestamp, &mut thread.context_switch_data);
-        if let (Some(off_cpu_sample), Some(off_cpu_stack)) =
-            (off_cpu_sample, thread.off_cpu_stack.take())
-        {
-            let cpu_delta_ns = self
-                .context_switch_handler
-                .consume_cpu_delta(&mut thread.context_switch_data);
-            process_off_cpu_sample_group(
-                off_cpu_sample,
-                thread_handle,
-                cpu_delta_ns,
-                &self.timestamp_converter,
-                self.off_cpu_weight_per_sample,
-                off_cpu_stack,
-                &mut process.unresolved_samples,
-            );
+    /// The sizes of the converter's state, for `--watchdog`.
+    pub fn metrics(&self) -> ConversionMetrics {
+        ConversionMetrics {
+            unresolved_stack_count: self.unresolved_stacks.stacks.len(),
+            module_count: self.module_data_cache.len(),
+            kernel_mapping_count: self.kernel_mappings.len(),
+            ..self.processes.metrics()
         }
+    }
 
-        let cpu_delta = if self.have_context_switches {
-            CpuDelta::from_nanos(
-                self.context_switch_handler
-                    .consume_cpu_delta(&mut thread.context_switch_data),
-            )
-        } else if let Some(period) = e.period {
-            // If the observed perf event is one of the clock time events, or cycles, then we should convert it to a CpuDelta.
-            // TODO: Detect event type
-            CpuDelta::from_nanos(period)
-        } else {
-            CpuDelta::from_nanos(0)
+    /// Returns the state which the converter of the next file of a rotating
+    /// recording starts with. Call [`Converter::cut_at_checkpoint`] before
+    /// finishing this converter, and [`Converter::restore_checkpoint`] on the
+    /// new one.
+    pub fn checkpoint(&self) -> ConverterCheckpoint {
+        let thread_checkpoint = |tid: i32, thread: &Thread| {
+            let off_cpu_stack = thread
+                .off_cpu_stack
+                .filter(|_| thread.context_switch_data.is_off_cpu())
+                .map(|stack| {
+                    let mut frames = Vec::new();
+                    self.unresolved_stacks.convert_back(stack, &mut frames);
+                    frames
+                });
+            ThreadCheckpoint {
+                tid,
+                name: thread.name.clone(),
+                off_cpu_stack,
+            }
         };
+        let processes = self
+            .processes
+            .processes_by_pid
+            .iter()
+            .map(|(&pid, process)| {
+                let mut threads = vec![thread_checkpoint(pid, &process.threads.main_thread)];
+                threads.extend(
+                    process
+                        .threads
+                        .threads_by_tid
+                        .iter()
+                        .map(|(&tid, thread)| thread_checkpoint(tid, thread)),
+                );
+                let mappings = self
+                    .mapping_tracker
+                    .as_ref()
+                    .map(|tracker| tracker.mappings(pid))
+                    .unwrap_or_default();
+                ProcessCheckpoint {
+                    pid,
+                    name: process.name.clone(),
+                    threads,
+                    mappings,
+                }
+            })
+            .collect();
+        ConverterCheckpoint { processes }
+    }
 
-        let stack_index = self.unresolved_stacks.convert(stack.iter().rev().cloned());
-        process.unresolved_samples.add_sample(
-            thread_handle,
-            profile_timestamp,
-            timestamp,
-            stack_index,
-            cpu_delta,
-            1,
-        );
+    /// Ends the off-CPU periods of the blocked threads at `timestamp`, the end
+    /// of this file of a rotating recording. The next file's converter
+    /// continues them from there.
+    pub fn cut_at_checkpoint(&mut self, timestamp: u64) {
+        let mut off_cpu_threads = Vec::new();
+        for (&pid, process) in &self.processes.processes_by_pid {
+            let main_thread = (&pid, &process.threads.main_thread);
+            for (&tid, thread) in
+                std::iter::once(main_thread).chain(&process.threads.threads_by_tid)
+            {
+                if thread.context_switch_data.is_off_cpu() {
+                    off_cpu_threads.push((pid, tid));
+                }
+            }
+        }
+        for (pid, tid) in off_cpu_threads {
+            self.handle_context_switch(
+                ContextSwitchRecord::In {
+                    prev_pid: None,
+                    prev_tid: None,
+                },
+                CommonData {
+                    pid: Some(pid),
+                    tid: Some(tid),
+                    timestamp: Some(timestamp),
+                    id: None,
+                    stream_id: None,
+                    cpu: None,
+                },
+            );
+        }
     }
 
-    pub fn handle_sched_switch<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
-        &mut self,
-        e: &SampleRecord,
-    ) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        let tid = e.tid.expect("Can't handle samples without tids");
-        let process = self.processes.get_by_pid(pid, &mut self.profile);
-        process.check_jitdump(
-            &mut self.jit_category_manager,
-            &mut self.profile,
-            &self.timestamp_converter,
-        );
+    /// Starts this converter with the processes, threads and executable
+    /// mappings of the previous file of a rotating recording, as if their
+    /// records had been seen at `timestamp`. Threads which were off-CPU are
+    /// switched out at `timestamp`.
+    pub fn restore_checkpoint(&mut self, checkpoint: &ConverterCheckpoint, timestamp: u64) {
+        for process in &checkpoint.processes {
+            let pid = process.pid;
+            for thread in &process.threads {
+                let name = match (&thread.name, thread.tid == pid) {
+                    (Some(name), _) => Some(name),
+                    (None, true) => process.name.as_ref(),
+                    (None, false) => None,
+                };
+                match name {
+                    Some(name) => self.set_thread_name(pid, thread.tid, name, false),
+                    None => {
+                        self.processes
+                            .get_by_pid(pid, &mut self.profile)
+                            .threads
+                            .get_thread_by_tid(thread.tid, &mut self.profile);
+                    }
+                }
+            }
+            for mapping in &process.mappings {
+                self.handle_mmap2(
+                    Mmap2Record {
+                        pid,
+                        tid: pid,
+                        address: mapping.address,
+                        length: mapping.length,
+                        page_offset: mapping.page_offset,
+                        file_id: mapping.file_id.clone(),
+                        protection: mapping.protection,
+                        flags: mapping.flags,
+                        path: RawData::Single(&mapping.path),
+                        cpu_mode: CpuMode::User,
+                    },
+                    timestamp,
+                );
+            }
+            for thread in &process.threads {
+                let Some(frames) = &thread.off_cpu_stack else {
+                    continue;
+                };
+                let stack = self.unresolved_stacks.convert(frames.iter().rev().cloned());
+                let restored_thread = self
+                    .processes
+                    .get_by_pid(pid, &mut self.profile)
+                    .threads
+                    .get_thread_by_tid(thread.tid, &mut self.profile);
+                restored_thread.off_cpu_stack = Some(stack);
+                self.context_switch_handler
+                    .handle_switch_out(timestamp, &mut restored_thread.context_switch_data);
+            }
+        }
+    }
 
-        let mut stack = Vec::new();
-        Self::get_sample_stack::<C>(
-            e,
-            &process.unwinder,
-            &mut self.cache,
-            &mut stack,
-            self.fold_recursive_prefix,
-        );
+    pub fn finish(self) -> Profile {
+        self.finish_impl(false).0
+    }
 
-        let stack_index = self
-            .unresolved_stacks
-            .convert_no_kernel(stack.iter().rev().cloned());
-        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
-        thread.off_cpu_stack = Some(stack_index);
+    /// Like [`Converter::finish`], but also returns the mapping history of
+    /// the processes with samples, for finding out which library an address
+    /// was attributed to.
+    #[allow(unused)]
+    pub fn finish_with_address_space_timeline(self) -> (Profile, AddressSpaceTimeline) {
+        let (profile, timeline) = self.finish_impl(true);
+        (profile, timeline.unwrap_or_default())
     }
 
-    pub fn handle_rss_stat<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
-        &mut self,
-        e: &SampleRecord,
-    ) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        // let tid = e.tid.expect("Can't handle samples without tids");
-        let process = self.processes.get_by_pid(pid, &mut self.profile);
-
-        let Some(raw) = e.raw else { return };
-        let Ok(rss_stat) = RssStat::parse(
-            raw,
-            self.endian,
-
-        ) else { return };
-
-        let Some(timestamp_mono) = e.timestamp else {
-            eprintln!("rss_stat record doesn't have a timestamp");
-            return;
+    /// Like [`Converter::finish`], but also returns the time ranges of the
+    /// parts which the profile should be written in if its estimated size
+    /// exceeds [`ConversionOptions::max_output_size`].
+    pub fn finish_in_parts(mut self) -> ConvertedProfile {
+        let max_output_size = self.max_output_size;
+        let off_cpu_ranges = std::mem::take(&mut self.off_cpu_ranges);
+        let (profile, _) = self.finish_impl(false);
+        let Some(max_output_size) = max_output_size else {
+            return ConvertedProfile::whole(profile);
         };
-        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
-
-        let (prev_size_of_this_member, member) = match rss_stat.member {
-            MM_FILEPAGES => (
-                &mut process.prev_mm_filepages_size,
-                RssStatMember::ResidentFileMappingPages,
-            ),
-            MM_ANONPAGES => (
-                &mut process.prev_mm_anonpages_size,
-                RssStatMember::ResidentAnonymousPages,
+        let estimate = profile.size_estimate();
+        let parts = split_ranges(&estimate, max_output_size, &off_cpu_ranges);
+        let estimated_mb = estimate.total() / 1_000_000;
+        match parts.len() {
+            0 if estimate.total() > max_output_size => warn!(
+                estimated_mb,
+                max_output_size,
+                "The profile's estimated size of {estimated_mb} MB exceeds --max-output-size, \
+                 but it can't be split at a whole second without splitting an off-CPU sample \
+                 group."
             ),
-            MM_SHMEMPAGES => (
-                &mut process.prev_mm_shmempages_size,
-                RssStatMember::ResidentSharedMemoryPages,
+            0 => {}
+            part_count => info!(
+                estimated_mb,
+                max_output_size,
+                part_count,
+                "The profile's estimated size of {estimated_mb} MB exceeds --max-output-size, \
+                 so it's split into {part_count} parts."
             ),
-            MM_SWAPENTS => (
-                &mut process.prev_mm_swapents_size,
-                RssStatMember::AnonymousSwapEntries,
-            ),
-            _ => return,
-        };
-
-        let delta = rss_stat.size - *prev_size_of_this_member;
-        *prev_size_of_this_member = rss_stat.size;
-
-        if rss_stat.member == MM_ANONPAGES {
-            let counter = process.get_or_make_mem_counter(&mut self.profile);
-            self.profile
-                .add_counter_sample(counter, timestamp, delta as f64, 1);
         }
+        ConvertedProfile { profile, parts }
+    }
 
-        process.check_jitdump(
+    fn finish_impl(mut self, keep_timeline: bool) -> (Profile, Option<AddressSpaceTimeline>) {
+        let mut timeline = (keep_timeline
+            || !self.address_queries.is_empty()
+            || !self.mem_access_stats.is_empty())
+        .then(AddressSpaceTimeline::default);
+        for pid in self.heap_profiles.pids() {
+            self.add_heap_profile_samples(pid, self.current_sample_time);
+        }
+        let pids: Vec<i32> = self.processes.processes_by_pid.keys().copied().collect();
+        for pid in pids {
+            self.flush_deferred_off_cpu_groups(pid, None);
+        }
+        self.heap_profiles.report_unused();
+        for (&attr_index, &count) in &self.skipped_sample_counts {
+            warn!(
+                "Skipped {count} samples of {} because its sample format lacks the pid, tid or \
+                 timestamp",
+                self.event_names[attr_index]
+            );
+        }
+        if self.skipped_context_switch_count != 0 {
+            warn!(
+                "Skipped {} context switch records because they lack the pid, tid or timestamp",
+                self.skipped_context_switch_count
+            );
+        }
+        let skipped_open_count = self.open_cache.skipped_open_count();
+        if skipped_open_count != 0 {
+            debug!("Skipped {skipped_open_count} opens of binaries which had failed before");
+        }
+        let stack_table_stats = self.unresolved_stacks.stats();
+        debug!(
+            "Stack table: {} nodes, {} unique stacks",
+            stack_table_stats.node_count, stack_table_stats.unique_stack_count
+        );
+        if stack_table_stats.truncated_stack_count != 0 {
+            let StackTableStats {
+                node_count,
+                unique_stack_count,
+                truncated_stack_count,
+            } = stack_table_stats;
+            warn!(
+                truncated_stack_count,
+                truncated_sample_count = self.truncated_sample_count,
+                node_count,
+                unique_stack_count,
+                "Cut off the stacks of {} samples because the stack table reached the \
+                 --max-stack-nodes limit. The table has {node_count} nodes and \
+                 {unique_stack_count} unique stacks.",
+                self.truncated_sample_count
+            );
+        }
+        if let Some(timings) = &self.timings {
+            timings.record_stack_table_stats(stack_table_stats);
+            timings.report();
+        }
+        if let Some(cpus) = self.cpus.take() {
+            cpus.finish(&mut self.profile, &self.timestamp_converter);
+        }
+        self.process_exits
+            .add_to_profile(&mut self.profile, &self.timestamp_converter);
+        let mut profile = self.profile;
+        self.processes.finish(
+            &mut profile,
+            &self.unresolved_stacks,
+            &self.event_names,
             &mut self.jit_category_manager,
-            &mut self.profile,
             &self.timestamp_converter,
+            self.have_guest_samples || self.unresolved_stacks.has_guest_frames(),
+            self.have_wine_modules,
+            self.have_cow_fault_samples,
+            self.have_memory_latency_samples,
+            &self.kernel_lib_mappings,
+            self.guest_kernel_lib_mappings.as_ref(),
+            self.kernel_frame_classifier.as_ref(),
+            self.frame_filter.as_ref(),
+            self.request_attribution.as_ref(),
+            self.request_spans.as_ref(),
+            &self.gc_detection,
+            timeline.as_mut(),
+            self.jit_code_embedding.as_mut(),
+            &mut self.marker_addresses,
         );
+        if let Some(embedding) = self.jit_code_embedding.take() {
+            embedding.finish(&mut profile);
+        }
+        if let Some(calculator) = &self.cpu_frequency_calculator {
+            Self::add_cpu_frequency_counters(calculator, &mut profile, &self.timestamp_converter);
+        }
+        if let Some(counts) = &self.node_sample_counts {
+            counts.add_counters(&mut profile, &self.timestamp_converter);
+        }
+        if let Some(detector) = &self.sampling_bias_detector {
+            detector.finish(&mut profile);
+        }
+        let clamped = self
+            .record_timestamps
+            .clamp_profile(&mut profile, &self.timestamp_converter);
+        self.record_timestamps.report(&clamped);
+        for (group_name, thread_count) in self.thread_groups.group_sizes() {
+            println!("Thread group {group_name}: {thread_count} threads");
+        }
+        for process in &self.processes_with_missing_mappings {
+            process.report();
+        }
+        if let Some(proc_synthesis) = &self.proc_synthesis {
+            proc_synthesis.report();
+        }
+        for process in &self.cow_faults_after_fork {
+            if process.sample_count != 0 {
+                process.report();
+            }
+        }
+        self.startups.report();
+        self.process_exits.report();
+        self.marker_stack_filter.report();
+        self.unwind_validator.report();
+        if let Some(cpu_sample_rates) = &self.cpu_sample_rates {
+            cpu_sample_rates.report();
+        }
+        if let Some(frame_filter) = &self.frame_filter {
+            frame_filter.report();
+        }
+        if let Some(request_attribution) = &self.request_attribution {
+            request_attribution.report();
+        }
+        if let Some(request_spans) = &self.request_spans {
+            request_spans.report();
+        }
+        self.thread_incarnations.report();
+        if let Some(container_binaries) = &self.container_binaries {
+            container_binaries.report();
+        }
+        if let Some(path_map) = &self.path_map {
+            path_map.report();
+        }
+        for handler in &mut self.tracepoint_handlers {
+            handler.flush(&mut profile, &self.timestamp_converter);
+            handler.finish(&self.unresolved_stacks);
+        }
+        self.mem_access_stats.report(timeline.as_ref(), &profile);
+        if let Some(timeline) = &timeline {
+            for query in &self.address_queries {
+                query.print_answer(timeline, &profile);
+            }
+        }
+        if let Some(log) = self.conversion_log.take() {
+            log.finish();
+        }
+        #[cfg(debug_assertions)]
+        crate::validate::debug_assert_valid(&profile);
+        (profile, timeline)
+    }
 
-        let mut stack = Vec::new();
-        Self::get_sample_stack::<C>(
-            e,
-            &process.unwinder,
-            &mut self.cache,
-            &mut stack,
-            self.fold_recursive_prefix,
-        );
-        let unresolved_stack = self.unresolved_stacks.convert(stack.into_iter().rev());
-        let thread_handle = process.threads.main_thread.profile_thread;
-        process.unresolved_samples.add_rss_stat_marker(
-            thread_handle,
+    /// Adds the heap profiles of a process which is about to exit, or which
+    /// is still alive at the end of the profile.
+    fn add_heap_profile_samples(&mut self, pid: i32, timestamp: u64) {
+        let heap_profiles = self.heap_profiles.take_for_pid(pid);
+        if heap_profiles.is_empty() {
+            return;
+        }
+        let Some(process) = self.processes.get_if_alive(pid) else {
+            self.heap_profiles.mark_unused(heap_profiles);
+            return;
+        };
+        add_heap_profile_samples(
+            &heap_profiles,
+            process.profile_process,
+            pid,
+            self.timestamp_converter.convert_time(timestamp),
             timestamp,
-            timestamp_mono,
-            unresolved_stack,
-            member,
-            rss_stat.size,
-            delta,
+            &mut self.profile,
+            &mut self.unresolved_stacks,
+            &mut process.unresolved_samples,
         );
     }
 
-    pub fn handle_other_event_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
-        &mut self,
-        e: &SampleRecord,
-        attr_index: usize,
+    /// Add one "effective frequency" counter per CPU, in GHz. The counters are
+    /// attached to a separate "CPU frequency" process.
+    fn add_cpu_frequency_counters(
+        calculator: &CpuFrequencyCalculator,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
     ) {
-        let pid = e.pid.expect("Can't handle samples without pids");
-        let timestamp_mono = e
-            .timestamp
-            .expect("Can't handle samples without timestamps");
-        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
-        // let tid = e.tid.expect("Can't handle samples without tids");
-        let process = self.processes.get_by_pid(pid, &mut self.profile);
-        process.check_jitdump(
-            &mut self.jit_category_manager,
-            &mut self.profile,
-            &self.timestamp_converter,
-        );
+        let frequencies = calculator.effective_frequencies();
+        let Some(first_timestamp) = frequencies
+            .values()
+            .filter_map(|f| f.first())
+            .map(|(t, _)| *t)
+            .min()
+        else { return };
+        let start_time = timestamp_converter.convert_time(first_timestamp);
+        let process = profile.add_process("CPU frequency", 0, start_time);
+        let thread = profile.add_thread(process, 0, start_time, true);
+        profile.set_thread_name(thread, "CPU frequency");
+        for (cpu, cpu_frequencies) in frequencies {
+            let counter = profile.add_counter(
+                process,
+                &format!("CPU {cpu} frequency"),
+                "CPU frequency",
+                &format!("Effective frequency of CPU {cpu}, in GHz"),
+            );
+            // Counter samples are deltas from the previous value.
+            let mut prev_ghz = 0.0;
+            for (timestamp, ghz) in cpu_frequencies {
+                let timestamp = timestamp_converter.convert_time(timestamp);
+                profile.add_counter_sample(counter, timestamp, ghz - prev_ghz, 1);
+                prev_ghz = ghz;
+            }
+        }
+    }
 
-        let mut stack = Vec::new();
-        Self::get_sample_stack::<C>(
-            e,
-            &process.unwinder,
-            &mut self.cache,
-            &mut stack,
-            self.fold_recursive_prefix,
-        );
+    pub fn handle_cpu_frequency_event_sample(&mut self, e: &SampleRecord, attr_index: usize) {
+        let Some(calculator) = self.cpu_frequency_calculator.as_mut() else { return };
+        if let (Some(cpu), Some(timestamp), Some(period)) = (e.cpu, e.timestamp, e.period) {
+            calculator.add_sample(attr_index, cpu, timestamp, period);
+        }
+    }
 
-        let thread_handle = match e.tid {
-            Some(tid) => {
-                process
-                    .threads
-                    .get_thread_by_tid(tid, &mut self.profile)
-                    .profile_thread
-            }
-            None => process.threads.main_thread.profile_thread,
+    /// Called for every sample with a `perf_mem_data_src`, in addition to the
+    /// regular handling of the sample.
+    pub fn handle_mem_access_sample(&mut self, e: &SampleRecord, data_src: u64) {
+        let (Some(pid), Some(timestamp)) = (e.pid, e.timestamp) else {
+            return;
         };
+        let process_name = self
+            .processes
+            .get_if_alive(pid)
+            .and_then(|process| process.name.as_deref());
+        self.mem_access_stats
+            .add_sample(pid, process_name, e.ip, timestamp, data_src);
+    }
 
-        let unresolved_stack = self.unresolved_stacks.convert(stack.into_iter().rev());
-        process.unresolved_samples.add_other_event_marker(
+    /// Called for a sampled memory access which isn't a sample record, e.g.
+    /// one decoded from ARM SPE data. The sample goes on the process's
+    /// memory latency track, with the instruction as its only frame and the
+    /// latency in cycles as its weight.
+    pub fn handle_memory_latency_sample(
+        &mut self,
+        pid: i32,
+        timestamp: u64,
+        pc: u64,
+        mode: StackMode,
+        latency: u32,
+        kind: MemoryAccessKind,
+    ) {
+        if self.pause_state.is_paused_at(timestamp) {
+            return;
+        }
+        self.have_memory_latency_samples = true;
+        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread_handle = process
+            .threads
+            .get_memory_latency_thread(&mut self.profile);
+        let stack_index = {
+            let _timing = TimingGuard::start(self.timings.as_ref(), TimingBucket::StackInterning);
+            self.unresolved_stacks
+                .convert(std::iter::once(StackFrame::InstructionPointer(pc, mode)))
+        };
+        process.unresolved_samples.add_memory_access_sample(
             thread_handle,
+            profile_timestamp,
             timestamp,
-            timestamp_mono,
-            unresolved_stack,
-            attr_index,
+            stack_index,
+            i32::try_from(latency).unwrap_or(i32::MAX),
+            kind,
         );
     }
 
-    /// Get the stack contained in this sample, and put it into `stack`.
-    ///
-    /// We can have both the kernel stack and the user stack, or just one of
-    /// them, or neither. The stack is appended onto the `stack` outparameter,
-    /// ordered from callee-most ("innermost") to caller-most. The kernel
-    /// stack comes before the user stack.
-    ///
-    /// If the `SampleRecord` has a kernel stack, it's always in `e.callchain`.
-    ///
-    /// If this sample has a user stack, its source depends on the method of
-    /// stackwalking that was requested during recording:
-    ///
-    ///  - With frame pointer unwinding (the default on x86, `perf record -g`,
-    ///    or more explicitly `perf record --call-graph fp`), the user stack
-    ///    is walked during sampling by the kernel and appended to e.callchain.
-    ///  - With DWARF unwinding (`perf record --call-graph dwarf`), the raw
-    ///    bytes on the stack are just copied into the perf.data file, and we
-    ///    need to do the unwinding now, based on the register values in
-    ///    `e.user_regs` and the raw stack bytes in `e.user_stack`.
-    fn get_sample_stack<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
-        e: &SampleRecord,
-        unwinder: &U,
-        cache: &mut U::Cache,
-        stack: &mut Vec<StackFrame>,
-        fold_recursive_prefix: bool,
-    ) {
-        stack.truncate(0);
+    pub fn handle_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(&mut self, e: &SampleRecord) {
+        let (Some(pid), Some(tid), Some(timestamp)) = (e.pid, e.tid, e.timestamp) else {
+            self.skip_sample(self.main_event_attr_index);
+            return;
+        };
+        if !self.check_guest_sample(e) {
+            return;
+        }
+        self.current_sample_time = timestamp;
+        if let Some(delay) = self.pending_recording_delay.take() {
+            self.processes.get_by_pid(pid, &mut self.profile);
+            self.add_recording_started_markers(delay);
+        }
 
-        // CpuMode::from_misc(e.raw.misc)
+        if e.cpu_mode == CpuMode::User {
+            self.synthesize_from_proc_if_needed(pid, tid);
+        }
 
-        // Get the first fragment of the stack from e.callchain.
-        if let Some(callchain) = e.callchain {
-            let mut is_first_frame = true;
-            let mut mode = StackMode::from(e.cpu_mode);
-            for i in 0..callchain.len() {
-                let address = callchain.get(i).unwrap();
-                if address >= PERF_CONTEXT_MAX {
-                    if let Some(new_mode) = StackMode::from_context_frame(address) {
-                        mode = new_mode;
-                    }
-                    continue;
-                }
+        let profile_timestamp = self.timestamp_converter.convert_time(timestamp);
+        let routing = self.thread_incarnations.route(pid, tid, timestamp);
+        let request_span = self
+            .request_spans
+            .as_ref()
+            .and_then(|spans| spans.request_for_sample(tid, timestamp));
 
-                let stack_frame = match is_first_frame {
-                    true => StackFrame::InstructionPointer(address, mode),
-                    false => StackFrame::ReturnAddress(address, mode),
-                };
-                stack.push(stack_frame);
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process.check_jitdump(
+            Some(timestamp),
+            &mut self.jit_category_manager,
+            &mut self.profile,
+            &self.timestamp_converter,
+            self.timings.as_ref(),
+        );
 
-                is_first_frame = false;
+        if let Some(startup) = self
+            .startups
+            .on_sample(pid, timestamp, process.name.as_deref())
+        {
+            let start_time = self
+                .timestamp_converter
+                .convert_time(startup.start_timestamp());
+            self.profile.add_marker(
+                process.threads.main_thread.profile_thread,
+                startup.marker_name(),
+                StartupMarker {
+                    library_loading_ms: startup.library_loading_ns().unwrap_or(0) as f64
+                        / 1_000_000.0,
+                    mmap_count: startup.mmap_count,
+                },
+                MarkerTiming::Interval(start_time, profile_timestamp),
+            );
+        }
+
+        if e.cpu_mode == CpuMode::User && process.executable_mapping_count == 0 {
+            if let Some(exec_timestamp) = process.exec_timestamp.take() {
+                if self.take_mapping_snapshots {
+                    self.pending_mapping_snapshots
+                        .push(self.processes_with_missing_mappings.len());
+                }
+                self.processes_with_missing_mappings
+                    .push(ProcessWithMissingMappings {
+                        pid,
+                        name: process.name.clone(),
+                        exec_timestamp,
+                        snapshot_mapping_count: None,
+                    });
             }
         }
 
-        // Append the user stack with the help of DWARF unwinding.
-        if let (Some(regs), Some((user_stack, _))) = (&e.user_regs, e.user_stack) {
-            let ustack_bytes = RawDataU64::from_raw_data::<LittleEndian>(user_stack);
-            let (pc, sp, regs) = C::convert_regs(regs);
-            let mut read_stack = |addr: u64| {
-                // ustack_bytes has the stack bytes starting from the current stack pointer.
-                let offset = addr.checked_sub(sp).ok_or(())?;
-                let index = usize::try_from(offset / 8).map_err(|_| ())?;
-                ustack_bytes.get(index).ok_or(())
-            };
+        // While sampling is paused, we still need to update the thread's
+        // context switch state, but we don't need the stack.
+        let is_paused = self.pause_state.is_paused_at(timestamp);
+        if let (Some(counts), Some(cpu), false) = (&mut self.node_sample_counts, e.cpu, is_paused) {
+            counts.add_sample(cpu, timestamp);
+        }
+        let mut stack = Vec::new();
+        if !is_paused {
+            Self::get_sample_stack::<C>(
+                e,
+                &process.unwinder,
+                &process.code_ranges,
+                &mut self.unwind_validator,
+                &mut self.cache,
+                &mut stack,
+                &self.sample_lbr_calls,
+                self.fold_recursive_prefix,
+                self.leaf_only,
+                self.max_stack_depth,
+                self.timings.as_ref(),
+            );
+        }
 
-            // Unwind.
-            let mut frames = unwinder.iter_frames(pc, regs, cache, &mut read_stack);
-            loop {
-                let frame = match frames.next() {
-                    Ok(Some(frame)) => frame,
-                    Ok(None) => break,
-                    Err(_) => {
-                        stack.push(StackFrame::TruncatedStackMarker);
-                        break;
-                    }
-                };
-                let stack_frame = match frame {
-                    FrameAddress::InstructionPointer(addr) => {
-                        StackFrame::InstructionPointer(addr, StackMode::User)
-                    }
-                    FrameAddress::ReturnAddress(addr) => {
-                        StackFrame::ReturnAddress(addr.into(), StackMode::User)
-                    }
+        let weight = match (self.event_count_weights, &mut self.cpu_sample_rates, e.cpu) {
+            (true, _, _) => period_weight(e.period),
+            (false, Some(cpu_sample_rates), Some(cpu)) => {
+                cpu_sample_rates.add_sample(cpu, timestamp)
+            }
+            (false, _, _) => 1,
+        };
+        // CPU deltas derived from the period only make sense if the period is
+        // time.
+        let period_cpu_delta_ns = match self.event_count_weights {
+            true => 0,
+            false => e.period.unwrap_or(0),
+        };
+
+        if let Some(cpus) = &mut self.cpus {
+            if let Some(cpu) = e.cpu {
+                if is_paused {
+                    return;
+                }
+                let thread_name = process
+                    .threads
+                    .get_thread_by_tid(tid, &mut self.profile)
+                    .name
+                    .clone();
+                let cpu_thread = cpus.on_sample(
+                    cpu,
+                    pid,
+                    tid,
+                    thread_name.as_deref(),
+                    timestamp,
+                    &mut self.profile,
+                    &self.timestamp_converter,
+                );
+                let stack_index = {
+                    let _timing =
+                        TimingGuard::start(self.timings.as_ref(), TimingBucket::StackInterning);
+                    self.unresolved_stacks.convert(stack.iter().rev().cloned())
                 };
-                stack.push(stack_frame);
+                if self.unresolved_stacks.is_truncated(stack_index) {
+                    self.truncated_sample_count += 1;
+                }
+                process.unresolved_samples.add_sample(
+                    cpu_thread,
+                    profile_timestamp,
+                    timestamp,
+                    stack_index,
+                    CpuDelta::from_nanos(period_cpu_delta_ns),
+                    weight,
+                );
+                process
+                    .unresolved_samples
+                    .set_request_span_of_last_sample(request_span);
+                return;
             }
+            cpus.on_sample_without_cpu();
         }
 
-        if stack.is_empty() {
-            if let Some(ip) = e.ip {
-                stack.push(StackFrame::InstructionPointer(ip, e.cpu_mode.into()));
+        let late_thread_handle = match routing {
+            SampleRouting::Current => None,
+            SampleRouting::Earlier(thread_handle) => Some(thread_handle),
+            SampleRouting::Unmatched => {
+                Some(process.threads.get_late_sample_thread(&mut self.profile))
             }
-        } else if fold_recursive_prefix {
-            let last_frame = *stack.last().unwrap();
-            while stack.len() >= 2 && stack[stack.len() - 2] == last_frame {
-                stack.pop();
+        };
+        if let Some(thread_handle) = late_thread_handle {
+            // The thread's context switch state has moved on, so we don't
+            // know the CPU time of this sample.
+            if is_paused {
+                return;
             }
+            let cpu_delta_ns = match self.have_context_switches {
+                true => 0,
+                false => period_cpu_delta_ns,
+            };
+            let stack_index = {
+                let _timing =
+                    TimingGuard::start(self.timings.as_ref(), TimingBucket::StackInterning);
+                self.unresolved_stacks.convert(stack.iter().rev().cloned())
+            };
+            if self.unresolved_stacks.is_truncated(stack_index) {
+                self.truncated_sample_count += 1;
+            }
+            process.unresolved_samples.add_sample(
+                thread_handle,
+                profile_timestamp,
+                timestamp,
+                stack_index,
+                CpuDelta::from_nanos(cpu_delta_ns),
+                weight,
+            );
+            process
+                .unresolved_samples
+                .set_request_span_of_last_sample(request_span);
+            return;
         }
-    }
 
-    /// This is a terrible hack to get binary correlation working with apps on Wine.
-    ///
-    /// Unlike ELF, PE has the notion of "file alignment" that is different from page alignment.
-    /// Hence, even if the virtual address is page aligned, its on-disk offset may not be. This
-    /// leads to obvious trouble with using mmap, since mmap requires the file offset to be page
-    /// aligned. Wine's workaround is straightforward: for misaligned sections, Wine will simply
-    /// copy the image from disk instead of mmapping them. For example, `/proc/<pid>/maps` can look
-    /// like this:
-    ///
-    /// ```plain
-    /// <PE header> 140000000-140001000 r--p 00000000 00:25 272185   game.exe
-    /// <.text>     140001000-143be8000 r-xp 00000000 00:00 0
-    ///             143be8000-144c0c000 r--p 00000000 00:00 0
-    /// ```
-    ///
-    /// When this misalignment happens, most of the sections in the memory will not be a file
-    /// mapping. However, the PE header is always mapped, and it resides at the beginning of the
-    /// file, which means it's also always *aligned*. Finally, it's always mapped first, because
-    /// the information from the header is required to determine the load address of the other
-    /// sections. Hence, if we find a mapping that seems to pointing to a PE file, and has a file
-    /// offset of 0, we'll add it to the list of "suspected PE images". When we see a later mapping
-    /// that belongs to one of the suspected PE ranges, we'll match the mapping with the file,
-    /// which allows binary correlation and unwinding to work.
-    fn check_for_pe_mapping(&mut self, path_slice: &[u8], mapping_start_avma: u64) {
-        // Do a quick extension check first, to avoid end up trying to parse every mmapped file.
-        let filename_is_pe = path_slice.ends_with(b".exe")
-            || path_slice.ends_with(b".dll")
-            || path_slice.ends_with(b".EXE")
-            || path_slice.ends_with(b".DLL");
-        if !filename_is_pe {
+        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+
+        if thread.last_sample_timestamp == Some(timestamp) {
+            // Duplicate sample. Ignore.
             return;
         }
 
-        // There are a few assumptions here:
-        // - The SizeOfImage field in the PE header is defined to be a multiple of SectionAlignment.
-        //   SectionAlignment is usually the page size. When it's not the page size, additional
-        //   layout restrictions apply and Wine will always map the file in its entirety, which
-        //   means we're safe without the workaround. So we can safely assume it to be page aligned
-        //   here.
-        // - VirtualAddress of the sections are defined to be adjacent after page-alignment. This
-        //   means that we can treat the image as a contiguous region.
-        if let Some(size) = get_pe_mapping_size(path_slice) {
-            let mapping = SuspectedPeMapping {
-                path: path_slice.to_owned(),
-                start: mapping_start_avma,
-                size,
-            };
-            self.suspected_pe_mappings.insert(mapping.start, mapping);
-        }
-    }
+        thread.last_sample_timestamp = Some(timestamp);
+        let thread_handle = thread.profile_thread;
 
-    pub fn handle_mmap(&mut self, e: MmapRecord, timestamp: u64) {
-        let mut path = e.path.as_slice();
-        if let Some(jitdump_path) = get_path_if_jitdump(&path) {
-            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
-            process
-                .jitdump_manager
-                .add_jitdump_path(jitdump_path, self.extra_binary_artifact_dir.clone());
+        // Off-cpu time is only consumed at switch-in, so that samples which are
+        // taken around a context switch don't split the off-cpu period.
+        let cpu_delta_ns = self
+            .context_switch_handler
+            .handle_sample(timestamp, &mut thread.context_switch_data);
+        let cpu_delta_ns = if self.have_context_switches {
+            cpu_delta_ns
+        } else {
+            period_cpu_delta_ns
+        };
+        let cpu_delta = CpuDelta::from_nanos(cpu_delta_ns);
+
+        if is_paused {
             return;
         }
 
-        if e.page_offset == 0 {
-            self.check_for_pe_mapping(&e.path.as_slice(), e.address);
+        if let Some(detector) = &mut self.sampling_bias_detector {
+            detector.add_sample(&stack, thread_handle, profile_timestamp);
         }
-
-        if !e.is_executable {
-            return;
+        if let Some(resolver) = &mut self.live_stack_resolver {
+            resolver.add_sample(
+                pid,
+                process.name.as_deref(),
+                timestamp,
+                &stack,
+                &process.lib_mapping_ops,
+                &self.profile,
+            );
         }
 
-        let dso_key = match DsoKey::detect(&path, e.cpu_mode) {
-            Some(dso_key) => dso_key,
-            None => return,
+        let stack_index = {
+            let _timing = TimingGuard::start(self.timings.as_ref(), TimingBucket::StackInterning);
+            self.unresolved_stacks
+                .convert_with_cache(stack.iter().rev().cloned(), &mut thread.stack_cache)
         };
-        let mut build_id = None;
-        if let Some(dso_info) = self.build_ids.get(&dso_key) {
-            build_id = Some(dso_info.build_id.to_owned());
-            // Overwrite the path from the mmap record with the path from the build ID info.
-            // These paths are usually the same, but in some cases the path from the build
-            // ID info can be "better". For example, the synthesized mmap event for the
-            // kernel vmlinux image usually has "[kernel.kallsyms]_text" whereas the build
-            // ID info might have the full path to a kernel debug file, e.g.
-            // "/usr/lib/debug/boot/vmlinux-4.16.0-1-amd64".
-            path = dso_info.path.to_owned().into();
+        if self.unresolved_stacks.is_truncated(stack_index) {
+            self.truncated_sample_count += 1;
         }
-
-        if e.pid == -1 {
-            self.add_kernel_module(e.address, e.length, dso_key, build_id.as_deref(), &path);
-        } else {
-            self.add_module_to_process(
-                e.pid,
-                &path,
-                e.page_offset,
-                e.address,
-                e.length,
-                build_id.as_deref(),
-                timestamp,
+        if let Some(deferred) = thread.deferred_off_cpu_group.take() {
+            self.deferred_off_cpu_group_count -= 1;
+            let resumed_stack = deferred.resumed_stack(timestamp, stack_index);
+            process_deferred_off_cpu_sample_group(
+                deferred,
+                resumed_stack,
+                self.off_cpu_stack,
+                &self.context_switch_handler,
+                thread_handle,
+                &self.timestamp_converter,
+                self.off_cpu_weight_per_sample,
+                &mut process.unresolved_samples,
             );
         }
-    }
-
-    pub fn handle_mmap2(&mut self, e: Mmap2Record, timestamp: u64) {
-        let path = e.path.as_slice();
-        if let Some(jitdump_path) = get_path_if_jitdump(&path) {
-            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+        let is_cow_fault = match &self.cow_fault_detector {
+            Some(detector) => detector.is_cow_fault(&stack),
+            None => false,
+        };
+        if !is_cow_fault {
+            process.unresolved_samples.add_sample(
+                thread_handle,
+                profile_timestamp,
+                timestamp,
+                stack_index,
+                cpu_delta,
+                weight,
+            );
             process
-                .jitdump_manager
-                .add_jitdump_path(jitdump_path, self.extra_binary_artifact_dir.clone());
+                .unresolved_samples
+                .set_request_span_of_last_sample(request_span);
             return;
         }
 
-        if e.page_offset == 0 {
-            self.check_for_pe_mapping(&e.path.as_slice(), e.address);
-        }
-
-        const PROT_EXEC: u32 = 0b100;
-        if e.protection & PROT_EXEC == 0 {
-            // Ignore non-executable mappings.
-            return;
+        self.have_cow_fault_samples = true;
+        process.unresolved_samples.add_cow_fault_sample(
+            thread_handle,
+            profile_timestamp,
+            timestamp,
+            stack_index,
+            cpu_delta,
+            weight,
+        );
+        process
+            .unresolved_samples
+            .set_request_span_of_last_sample(request_span);
+        if let Some(faults) = self
+            .cow_faults_after_fork
+            .iter_mut()
+            .rfind(|faults| faults.pid == pid)
+        {
+            faults.add_sample(timestamp, cpu_delta_ns, process.name.as_deref());
         }
+    }
 
-        let build_id = match &e.file_id {
-            Mmap2FileId::BuildId(build_id) => Some(build_id.to_owned()),
-            Mmap2FileId::InodeAndVersion(_) => {
-                let dso_key = match DsoKey::detect(&path, e.cpu_mode) {
-                    Some(dso_key) => dso_key,
-                    None => return,
-                };
-                self.build_ids
-                    .get(&dso_key)
-                    .map(|db| db.build_id.to_owned())
+    /// Called for a command which the profiled application sent through the
+    /// control pipe. Markers are put on the main thread of the process `pid`.
+    pub fn handle_control_command(&mut self, pid: i32, command: ControlCommand, timestamp: u64) {
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread = process.threads.main_thread.profile_thread;
+        let time = self.timestamp_converter.convert_time(timestamp);
+        match command {
+            ControlCommand::Pause => {
+                if self.pause_state.is_paused() {
+                    return;
+                }
+                self.pause_state.pause(timestamp);
+                // If sampling is never resumed, the marker extends to the end
+                // of the profile.
+                self.profile.add_marker(
+                    thread,
+                    "Profiling paused",
+                    ProfilingPausedMarker,
+                    MarkerTiming::IntervalStart(time),
+                );
             }
-        };
+            ControlCommand::Resume => {
+                if self.pause_state.resume(timestamp).is_some() {
+                    self.profile.add_marker(
+                        thread,
+                        "Profiling paused",
+                        ProfilingPausedMarker,
+                        MarkerTiming::IntervalEnd(time),
+                    );
+                }
+            }
+            ControlCommand::Marker(name) => {
+                self.profile.add_marker(
+                    thread,
+                    &name,
+                    ControlMarker { name: name.clone() },
+                    MarkerTiming::Instant(time),
+                );
+            }
+        }
+    }
 
-        self.add_module_to_process(
-            e.pid,
-            &path,
-            e.page_offset,
-            e.address,
-            e.length,
-            build_id.as_deref(),
-            timestamp,
+    /// Adds the recorded phases of a conversion as markers to the thread
+    /// which recorded them. This is used when samply profiles itself, with
+    /// `pid` being samply's own pid.
+    pub fn add_self_profile_phases(&mut self, pid: i32, phases: &PhaseRecorder) {
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread = process
+            .threads
+            .get_thread_by_tid(phases.tid() as i32, &mut self.profile)
+            .profile_thread;
+        add_phase_markers(
+            &mut self.profile,
+            thread,
+            &phases.take_phases(),
+            &self.timestamp_converter,
         );
     }
 
-    pub fn handle_context_switch(&mut self, e: ContextSwitchRecord, common: CommonData) {
-        let pid = common.pid.expect("Can't handle samples without pids");
-        let tid = common.tid.expect("Can't handle samples without tids");
-        let timestamp = common
-            .timestamp
-            .expect("Can't handle context switch without time");
+    /// Returns the pids and exec timestamps of the processes which have been
+    /// sampled after an exec without any executable mmap records. The caller
+    /// should read /proc/<pid>/maps for each of them, pass the executable
+    /// mappings to `handle_mmap2` with the exec timestamp, and then call
+    /// `handle_mapping_snapshot`.
+    ///
+    /// This only returns something if `take_mapping_snapshots` was set.
+    pub fn take_processes_needing_mapping_snapshot(&mut self) -> Vec<(i32, u64)> {
+        self.pending_mapping_snapshots
+            .drain(..)
+            .map(|i| {
+                let process = &self.processes_with_missing_mappings[i];
+                (process.pid, process.exec_timestamp)
+            })
+            .collect()
+    }
+
+    /// Called once the mappings from a /proc/<pid>/maps snapshot have been
+    /// added to the process. Marks them as snapshot-derived in the profile.
+    pub fn handle_mapping_snapshot(&mut self, pid: i32, mapping_count: usize) {
+        let Some(missing) = self
+            .processes_with_missing_mappings
+            .iter_mut()
+            .rfind(|p| p.pid == pid)
+        else { return };
+        missing.snapshot_mapping_count = Some(mapping_count);
         let process = self.processes.get_by_pid(pid, &mut self.profile);
-        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+        let main_thread = process.threads.main_thread.profile_thread;
+        let timestamp = self
+            .timestamp_converter
+            .convert_time(self.current_sample_time);
+        self.profile.add_marker(
+            main_thread,
+            "Mappings from /proc snapshot",
+            MappingSnapshotMarker { mapping_count },
+            MarkerTiming::Instant(timestamp),
+        );
+    }
 
-        match e {
-            ContextSwitchRecord::In { .. } => {
-                // Consume off-cpu time and clear the saved off-CPU stack.
-                let off_cpu_sample = self
-                    .context_switch_handler
-                    .handle_switch_in(timestamp, &mut thread.context_switch_data);
-                if let (Some(off_cpu_sample), Some(off_cpu_stack)) =
-                    (off_cpu_sample, thread.off_cpu_stack.take())
-                {
-                    let cpu_delta_ns = self
-                        .context_switch_handler
-                        .consume_cpu_delta(&mut thread.context_switch_data);
-                    process_off_cpu_sample_group(
-                        off_cpu_sample,
-                        thread.profile_thread,
-                        cpu_delta_ns,
-                        &self.timestamp_converter,
-                        self.off_cpu_weight_per_sample,
-                        off_cpu_stack,
-                        &mut process.unresolved_samples,
-                    );
+    /// For `--synthesize-from-proc`: If the process has no executable mappings
+    /// and hasn't been looked up yet, reads its state from /proc and passes it
+    /// to `set_thread_name` and `handle_mmap2`, as if the recording had
+    /// contained its COMM and MMAP2 records.
+    fn synthesize_from_proc_if_needed(&mut self, pid: i32, tid: i32) {
+        let Some(proc_synthesis) = &mut self.proc_synthesis else { return };
+        let has_mappings = self
+            .processes
+            .get_if_alive(pid)
+            .map_or(false, |process| process.executable_mapping_count != 0);
+        if !has_mappings && proc_synthesis.should_look_up_process(pid) {
+            let timestamp = proc_synthesis.timestamp();
+            match read_proc_process_state(pid) {
+                Ok(state) => {
+                    if let Some(name) = &state.name {
+                        self.set_thread_name(pid, pid, name, false);
+                    }
+                    let count = self.handle_proc_maps(pid, &state.maps, timestamp);
+                    let proc_synthesis = self.proc_synthesis.as_mut().unwrap();
+                    proc_synthesis.on_process_found(pid, state.exe, count);
                 }
+                Err(_) => proc_synthesis.on_process_missing(pid),
             }
-            ContextSwitchRecord::Out { .. } => {
-                self.context_switch_handler
-                    .handle_switch_out(timestamp, &mut thread.context_switch_data);
+        }
+        let proc_synthesis = self.proc_synthesis.as_mut().unwrap();
+        if tid != pid && proc_synthesis.should_look_up_thread_name(pid, tid) {
+            if let Some(name) = read_proc_thread_name(pid, tid) {
+                self.set_thread_name(pid, tid, &name, false);
             }
         }
     }
 
-    /// Called for a FORK record.
-    ///
-    /// FORK records are emitted if a new thread is started or if a new
-    /// process is created. The name is inherited from the forking thread.
-    pub fn handle_thread_start(&mut self, e: ForkOrExitRecord) {
-        let start_time = self.timestamp_converter.convert_time(e.timestamp);
-
-        let is_main = e.pid == e.tid;
-        let parent_process = self.processes.get_by_pid(e.ppid, &mut self.profile);
-        if e.pid != e.ppid {
-            // We've created a new process.
-            if !is_main {
-                eprintln!("Unexpected data in FORK record: If we fork into a different process, the forked child thread should be the main thread of the new process");
+    /// Passes the mappings in `maps`, the contents of a /proc/<pid>/maps
+    /// file, to `handle_mmap2` as if they had been mmapped at `timestamp`.
+    /// Returns the number of executable mappings.
+    pub fn handle_proc_maps(&mut self, pid: i32, maps: &str, timestamp: u64) -> usize {
+        // The values from the Linux headers, which perf.data files use on
+        // every host.
+        const PROT_READ: u32 = 0b1;
+        const PROT_WRITE: u32 = 0b10;
+        const PROT_EXEC: u32 = 0b100;
+        const MAP_SHARED: u32 = 0b1;
+        const MAP_PRIVATE: u32 = 0b10;
+
+        let mut executable_mapping_count = 0;
+        for region in proc_maps::parse(maps) {
+            let mut protection = 0;
+            if region.is_read {
+                protection |= PROT_READ;
             }
-            let parent_process_name = parent_process.name.clone();
-            let parent_thread = parent_process
-                .threads
-                .get_thread_by_tid(e.ptid, &mut self.profile);
-            let parent_thread_name = parent_thread.name.clone();
-            let is_reused = if let Some(name) = parent_process_name.as_deref() {
-                self.processes.attempt_reuse(e.pid, name).is_some()
-            } else {
-                false
-            };
-            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
-            process.name = parent_process_name;
-            let process_handle = process.profile_process;
-            let thread = process.threads.get_main_thread();
-            thread.name = parent_thread_name;
-            let thread_handle = thread.profile_thread;
-            if let Some(thread_name) = thread.name.as_deref() {
-                self.profile.set_thread_name(thread_handle, thread_name);
+            if region.is_write {
+                protection |= PROT_WRITE;
             }
-            if !is_reused {
-                self.profile
-                    .set_process_start_time(process_handle, start_time);
-                self.profile
-                    .set_thread_start_time(thread_handle, start_time);
+            if region.is_executable {
+                protection |= PROT_EXEC;
+                executable_mapping_count += 1;
             }
-        } else {
-            let parent_thread = parent_process
-                .threads
-                .get_thread_by_tid(e.ptid, &mut self.profile);
-            let parent_thread_name = parent_thread.name.clone();
-            let is_reused = if let Some(name) = parent_thread_name.as_deref() {
-                parent_process
-                    .threads
-                    .attempt_thread_reuse(e.tid, name)
-                    .is_some()
+            let flags = if region.is_shared {
+                MAP_SHARED
             } else {
-                false
+                MAP_PRIVATE
             };
-            let mut thread = parent_process
-                .threads
-                .get_thread_by_tid(e.tid, &mut self.profile);
-            thread.name = parent_thread_name;
-            if !is_reused {
-                let thread_handle = thread.profile_thread;
-                if let Some(thread_name) = thread.name.as_deref() {
-                    self.profile.set_thread_name(thread_handle, thread_name);
-                }
-                self.profile
-                    .set_thread_start_time(thread_handle, start_time);
-            }
-        };
-    }
 
-    /// Called for an EXIT record.
-    pub fn handle_thread_end(&mut self, e: ForkOrExitRecord) {
-        let is_main = e.pid == e.tid;
-        let end_time = self.timestamp_converter.convert_time(e.timestamp);
-        if is_main {
-            self.processes.remove(
-                e.pid,
-                end_time,
-                &mut self.profile,
-                &mut self.jit_category_manager,
-                &self.timestamp_converter,
-            );
-        } else {
-            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
-            process.threads.remove_non_main_thread(
-                e.tid,
-                end_time,
-                self.merge_threads,
-                &mut self.profile,
+            self.handle_mmap2(
+                Mmap2Record {
+                    pid,
+                    tid: pid,
+                    address: region.start,
+                    length: region.end - region.start,
+                    page_offset: region.file_offset,
+                    file_id: Mmap2FileId::InodeAndVersion(Mmap2InodeAndVersion {
+                        major: region.major,
+                        minor: region.minor,
+                        inode: region.inode,
+                        inode_generation: 0,
+                    }),
+                    protection,
+                    flags,
+                    path: RawData::Single(&region.name.into_bytes()),
+                    cpu_mode: CpuMode::User,
+                },
+                timestamp,
             );
         }
+        executable_mapping_count
     }
 
-    pub fn set_thread_name(&mut self, pid: i32, tid: i32, name: &str, is_thread_creation: bool) {
-        let is_main = pid == tid;
+    fn skip_sample(&mut self, attr_index: usize) {
+        *self.skipped_sample_counts.entry(attr_index).or_default() += 1;
+    }
 
-        let process = self.processes.get_by_pid(pid, &mut self.profile);
-        let process_handle = process.profile_process;
+    /// Pass the sample to the tracepoint handlers for its event, if there are
+    /// any. Returns false if no handler wants this event.
+    pub fn handle_tracepoint_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: &SampleRecord,
+        attr_index: usize,
+    ) -> bool {
+        let handler_indexes = match self
+            .tracepoint_handler_indexes_by_attr_index
+            .get(attr_index)
+        {
+            Some(handler_indexes) if !handler_indexes.is_empty() => handler_indexes,
+            _ => return false,
+        };
 
-        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
-        let thread_handle = thread.profile_thread;
+        // The handlers need all of these, and skip the sample otherwise.
+        let (Some(pid), Some(_), Some(_)) = (e.pid, e.tid, e.timestamp) else {
+            self.skip_sample(attr_index);
+            return true;
+        };
+        let only_marker_stacks = handler_indexes
+            .iter()
+            .all(|&i| self.tracepoint_handlers[i].only_uses_stack_for_markers());
+        let wants_stack = !only_marker_stacks
+            || self.marker_stack_filter.wants_stack(
+                e.tid.unwrap_or(pid),
+                attr_index,
+                e.timestamp.unwrap_or(0),
+            );
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process.check_jitdump(
+            e.timestamp,
+            &mut self.jit_category_manager,
+            &mut self.profile,
+            &self.timestamp_converter,
+            self.timings.as_ref(),
+        );
 
-        self.profile.set_thread_name(thread_handle, name);
-        thread.name = Some(name.to_owned());
-        if is_main {
-            self.profile.set_process_name(process_handle, name);
-            process.name = Some(name.to_owned());
+        let mut stack = Vec::new();
+        if wants_stack {
+            Self::get_sample_stack::<C>(
+                e,
+                &process.unwinder,
+                &process.code_ranges,
+                &mut self.unwind_validator,
+                &mut self.cache,
+                &mut stack,
+                &self.sample_lbr_calls,
+                self.fold_recursive_prefix,
+                self.leaf_only,
+                self.max_stack_depth,
+                self.timings.as_ref(),
+            );
         }
 
-        if is_thread_creation {
-            // Mark this as the start time of the new thread / process.
-            let time = self
-                .timestamp_converter
-                .convert_time(self.current_sample_time);
-            self.profile.set_thread_start_time(thread_handle, time);
-            if is_main {
-                self.profile.set_process_start_time(process_handle, time);
-            }
+        let mut ctx = ConvertCtx {
+            profile: &mut self.profile,
+            timestamp_converter: &self.timestamp_converter,
+            unresolved_stacks: &mut self.unresolved_stacks,
+            endian: self.endian,
+            tracepoint_formats: &self.tracepoint_formats,
+            process: process.profile_process,
+            main_thread: process.threads.main_thread.profile_thread,
+            unresolved_samples: &mut process.unresolved_samples,
+            stack: &stack,
+            attr_name: &self.event_names[attr_index],
+            threads: &mut process.threads,
+            process_exits: &mut self.process_exits,
+        };
+        for &handler_index in handler_indexes {
+            self.tracepoint_handlers[handler_index].handle(&mut ctx, e);
         }
+        true
+    }
 
-        if self.delayed_product_name_generator.is_some() && name != "perf-exec" {
-            let generator = self.delayed_product_name_generator.take().unwrap();
-            let product = generator(name);
-            self.profile.set_product(&product);
-            self.have_product_name = true;
+    pub fn handle_other_event_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: &SampleRecord,
+        attr_index: usize,
+    ) {
+        let (Some(pid), Some(timestamp_mono)) = (e.pid, e.timestamp) else {
+            self.skip_sample(attr_index);
+            return;
+        };
+        if !self.check_guest_sample(e) {
+            return;
         }
-    }
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process.check_jitdump(
+            Some(timestamp_mono),
+            &mut self.jit_category_manager,
+            &mut self.profile,
+            &self.timestamp_converter,
+            self.timings.as_ref(),
+        );
 
-    pub fn handle_thread_name_update(&mut self, e: CommOrExecRecord, timestamp: Option<u64>) {
-        let is_main = e.pid == e.tid;
-        let name = e.name.as_slice();
-        let name = String::from_utf8_lossy(&name);
+        let mut stack = Vec::new();
+        if self
+            .marker_stack_filter
+            .wants_stack(e.tid.unwrap_or(pid), attr_index, timestamp_mono)
+        {
+            Self::get_sample_stack::<C>(
+                e,
+                &process.unwinder,
+                &process.code_ranges,
+                &mut self.unwind_validator,
+                &mut self.cache,
+                &mut stack,
+                &self.sample_lbr_calls,
+                self.fold_recursive_prefix,
+                self.leaf_only,
+                self.max_stack_depth,
+                self.timings.as_ref(),
+            );
+        }
 
-        let is_thread_creation = if e.is_execve {
-            // Mark the old thread / process as ended.
-            // If the COMM record doesn't have a timestamp, take the last seen
-            // timestamp from the previous sample.
-            let timestamp = match timestamp {
-                Some(0) | None => self.current_sample_time,
-                Some(ts) => ts,
-            };
-            let end_time = self.timestamp_converter.convert_time(timestamp);
-            if is_main {
-                self.processes.remove(
-                    e.pid,
-                    end_time,
-                    &mut self.profile,
-                    &mut self.jit_category_manager,
-                    &self.timestamp_converter,
-                );
-                let maybe_reused_process = self.processes.attempt_reuse(e.pid, &name);
-                maybe_reused_process.is_none()
-            } else {
-                eprintln!(
-                    "Unexpected is_execve on non-main thread! pid: {}, tid: {}",
-                    e.pid, e.tid
-                );
-                let process = self.processes.get_by_pid(e.pid, &mut self.profile);
-                process.threads.remove_non_main_thread(
-                    e.tid,
-                    end_time,
-                    self.merge_threads,
-                    &mut self.profile,
-                );
-                let maybe_reused_thread = process.threads.attempt_thread_reuse(e.tid, &name);
-                maybe_reused_thread.is_none()
+        let thread_handle = match e.tid {
+            Some(tid) => {
+                process
+                    .threads
+                    .get_thread_by_tid(tid, &mut self.profile)
+                    .profile_thread
             }
-        } else if self.merge_threads && !is_main {
-            // Mark the old thread / process as ended.
-            // If the COMM record doesn't have a timestamp, take the last seen
-            // timestamp from the previous sample.
-            let timestamp = match timestamp {
-                Some(0) | None => self.current_sample_time,
-                Some(ts) => ts,
-            };
-            let end_time = self.timestamp_converter.convert_time(timestamp);
-            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
-            process.threads.remove_non_main_thread(
-                e.tid,
-                end_time,
-                self.merge_threads,
-                &mut self.profile,
-            );
-            let maybe_reused_thread = process.threads.attempt_thread_reuse(e.tid, &name);
-            maybe_reused_thread.is_none()
-        } else {
-            false
+            None => process.threads.main_thread.profile_thread,
         };
 
-        self.set_thread_name(e.pid, e.tid, &name, is_thread_creation);
+        let unresolved_stack = {
+            let _timing = TimingGuard::start(self.timings.as_ref(), TimingBucket::StackInterning);
+            self.unresolved_stacks.convert(stack.into_iter().rev())
+        };
+        process.unresolved_samples.add_other_event_marker(
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            unresolved_stack,
+            attr_index,
+        );
     }
 
-    fn add_kernel_module(
+    /// Called for the samples of a sampled event other than the main event,
+    /// e.g. instructions in a recording of cycles and instructions. The
+    /// sample goes on the process's track for the event, weighted by its
+    /// period, so that the events can be compared per stack.
+    pub fn handle_event_track_sample<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
         &mut self,
-        base_address: u64,
-        len: u64,
-        dso_key: DsoKey,
-        build_id: Option<&[u8]>,
-        path: &[u8],
+        e: &SampleRecord,
+        attr_index: usize,
     ) {
-        let path = std::str::from_utf8(path).unwrap().to_string();
-        let build_id: Option<Vec<u8>> = match (build_id, self.kernel_symbols.as_ref()) {
-            (None, Some(kernel_symbols)) if kernel_symbols.base_avma == base_address => {
-                Some(kernel_symbols.build_id.clone())
-            }
-            (None, _) => {
-                kernel_module_build_id(Path::new(&path), self.extra_binary_artifact_dir.as_deref())
-            }
-            (Some(build_id), _) => Some(build_id.to_owned()),
+        let (Some(pid), Some(timestamp_mono)) = (e.pid, e.timestamp) else {
+            self.skip_sample(attr_index);
+            return;
         };
-        let debug_id = build_id
-            .as_deref()
-            .map(|id| DebugId::from_identifier(id, self.endian == Endianness::LittleEndian));
+        if !self.check_guest_sample(e) || self.pause_state.is_paused_at(timestamp_mono) {
+            return;
+        }
+        let timestamp = self.timestamp_converter.convert_time(timestamp_mono);
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process.check_jitdump(
+            Some(timestamp_mono),
+            &mut self.jit_category_manager,
+            &mut self.profile,
+            &self.timestamp_converter,
+            self.timings.as_ref(),
+        );
 
-        let debug_path = match self.linux_version.as_deref() {
-            Some(linux_version) if path.starts_with("[kernel.kallsyms]") => {
-                // Take a guess at the vmlinux debug file path.
-                format!("/usr/lib/debug/boot/vmlinux-{linux_version}")
-            }
-            _ => path.clone(),
+        let mut stack = Vec::new();
+        Self::get_sample_stack::<C>(
+            e,
+            &process.unwinder,
+            &process.code_ranges,
+            &mut self.unwind_validator,
+            &mut self.cache,
+            &mut stack,
+            &self.sample_lbr_calls,
+            self.fold_recursive_prefix,
+            self.leaf_only,
+            self.max_stack_depth,
+            self.timings.as_ref(),
+        );
+
+        let thread_handle = process.threads.get_event_track_thread(
+            attr_index,
+            &self.event_names[attr_index],
+            &mut self.profile,
+        );
+        let unresolved_stack = {
+            let _timing = TimingGuard::start(self.timings.as_ref(), TimingBucket::StackInterning);
+            self.unresolved_stacks.convert(stack.into_iter().rev())
         };
-        let symbol_table = match (&dso_key, &build_id, self.kernel_symbols.as_ref()) {
-            (DsoKey::Kernel, Some(build_id), Some(kernel_symbols))
-                if build_id == &kernel_symbols.build_id && kernel_symbols.base_avma != 0 =>
-            {
-                // Run `echo '0' | sudo tee /proc/sys/kernel/kptr_restrict` to get here without root.
-                Some(kernel_symbols.symbol_table.clone())
-            }
-            _ => None,
+        process.unresolved_samples.add_sample(
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            unresolved_stack,
+            CpuDelta::ZERO,
+            period_weight(e.period),
+        );
+    }
+
+    /// Returns the stack of the sample, unwound with the current mappings of
+    /// its process, for the intermediate file. The frames which this rejects
+    /// aren't counted, because the sample's handler unwinds it again.
+    pub fn sample_stack<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        &mut self,
+        e: &SampleRecord,
+    ) -> Vec<StackFrame> {
+        let default_unwinder = U::default();
+        let default_code_ranges = CodeRanges::default();
+        let (unwinder, code_ranges) = match e
+            .pid
+            .and_then(|pid| self.processes.processes_by_pid.get(&pid))
+        {
+            Some(process) => (&process.unwinder, &process.code_ranges),
+            None => (&default_unwinder, &default_code_ranges),
         };
+        let mut stack = Vec::new();
+        Self::get_sample_stack::<C>(
+            e,
+            unwinder,
+            code_ranges,
+            &mut self.unwind_validator.clone(),
+            &mut self.cache,
+            &mut stack,
+            &self.sample_lbr_calls,
+            self.fold_recursive_prefix,
+            self.leaf_only,
+            self.max_stack_depth,
+            None,
+        );
+        stack
+    }
 
-        let lib_handle = self.profile.add_lib(LibraryInfo {
-            debug_id: debug_id.unwrap_or_default(),
-            path,
-            debug_path,
-            code_id: build_id.map(|build_id| CodeId::from_binary(&build_id).to_string()),
-            name: dso_key.name().to_string(),
-            debug_name: dso_key.name().to_string(),
-            arch: None,
-            symbol_table,
-        });
-        self.profile
-            .add_kernel_lib_mapping(lib_handle, base_address, base_address + len, 0);
+    /// Returns false if this sample is from a KVM guest and guest samples
+    /// should be dropped.
+    ///
+    /// Guest samples are attributed to the host thread which was running the
+    /// guest, i.e. the vCPU thread of the qemu process.
+    fn check_guest_sample(&mut self, e: &SampleRecord) -> bool {
+        if !StackMode::from(e.cpu_mode).is_guest() {
+            return true;
+        }
+        if self.drop_guest_samples {
+            return false;
+        }
+        self.have_guest_samples = true;
+        true
     }
 
-    /// Tell the unwinder about this module, and alsos create a ProfileModule
-    /// and add it to the profile.
+    /// Get the stack contained in this sample, and put it into `stack`.
     ///
-    /// The unwinder needs to know about it in case we need to do DWARF stack
-    /// unwinding - it needs to get the unwinding information from the binary.
-    /// The profile needs to know about this module so that it can assign
-    /// addresses in the stack to the right module and so that symbolication
-    /// knows where to get symbols for this module.
+    /// We can have both the kernel stack and the user stack, or just one of
+    /// them, or neither. The stack is appended onto the `stack` outparameter,
+    /// ordered from callee-most ("innermost") to caller-most. The kernel
+    /// stack comes before the user stack.
+    ///
+    /// If the `SampleRecord` has a kernel stack, it's always in `e.callchain`.
+    ///
+    /// If this sample has a user stack, its source depends on the method of
+    /// stackwalking that was requested during recording:
+    ///
+    ///  - With frame pointer unwinding (the default on x86, `perf record -g`,
+    ///    or more explicitly `perf record --call-graph fp`), the user stack
+    ///    is walked during sampling by the kernel and appended to e.callchain.
+    ///  - With DWARF unwinding (`perf record --call-graph dwarf`), the raw
+    ///    bytes on the stack are just copied into the perf.data file, and we
+    ///    need to do the unwinding now, based on the register values in
+    ///    `e.user_regs` and the raw stack bytes in `e.user_stack`.
     #[allow(clippy::too_many_arguments)]
-    fn add_module_to_process(
-        &mut self,
-        process_pid: i32,
-        path_slice: &[u8],
-        mapping_start_file_offset: u64,
-        mapping_start_avma: u64,
-        mapping_size: u64,
-        build_id: Option<&[u8]>,
-        timestamp: u64,
+    fn get_sample_stack<C: ConvertRegs<UnwindRegs = U::UnwindRegs>>(
+        e: &SampleRecord,
+        unwinder: &U,
+        code_ranges: &CodeRanges,
+        unwind_validator: &mut UnwindValidator,
+        cache: &mut U::Cache,
+        stack: &mut Vec<StackFrame>,
+        lbr_calls: &[LbrCall],
+        fold_recursive_prefix: bool,
+        leaf_only: bool,
+        max_stack_depth: Option<usize>,
+        timings: Option<&ConversionTimings>,
     ) {
-        let process = self.processes.get_by_pid(process_pid, &mut self.profile);
+        stack.truncate(0);
 
-        let path = std::str::from_utf8(path_slice).unwrap();
-        let (mut file, mut path): (Option<_>, String) = match open_file_with_fallback(
-            Path::new(path),
-            self.extra_binary_artifact_dir.as_deref(),
-        ) {
-            Ok((file, path)) => (Some(file), path.to_string_lossy().to_string()),
-            _ => (None, path.to_owned()),
-        };
+        if leaf_only {
+            if let Some(ip) = e.ip {
+                stack.push(StackFrame::InstructionPointer(ip, e.cpu_mode.into()));
+            }
+            return;
+        }
 
-        let mut suspected_pe_mapping = None;
-        if file.is_none() {
-            suspected_pe_mapping = self
-                .suspected_pe_mappings
-                .range(..=mapping_start_avma)
-                .next_back()
-                .map(|(_, m)| m)
-                .filter(|m| {
-                    mapping_start_avma >= m.start
-                        && mapping_start_avma + mapping_size <= m.start + m.size
-                });
-            if let Some(mapping) = suspected_pe_mapping {
-                if let Ok((pe_file, pe_path)) = open_file_with_fallback(
-                    Path::new(std::str::from_utf8(&mapping.path).unwrap()),
-                    self.extra_binary_artifact_dir.as_deref(),
-                ) {
-                    file = Some(pe_file);
-                    path = pe_path.to_string_lossy().to_string();
-                }
-            }
-        }
+        // CpuMode::from_misc(e.raw.misc)
 
-        if file.is_none() && !path.starts_with('[') {
-            // eprintln!("Could not open file {:?}", objpath);
-        }
+        // With `--call-graph lbr`, the user frames come from the LBR call
+        // stack, and only the kernel frames are taken from the callchain.
+        // Samples which can be unwound with DWARF use the unwinder instead.
+        let cpu_mode = StackMode::from(e.cpu_mode);
+        let use_lbr = !lbr_calls.is_empty() && e.user_stack.is_none() && !cpu_mode.is_guest();
 
-        // Fix up bad files from `perf inject --jit`.
-        if let Some(file_inner) = &file {
-            if let Some((fixed_file, fixed_path)) = correct_bad_perf_jit_so_file(file_inner, &path)
-            {
-                file = Some(fixed_file);
-                path = fixed_path;
-            }
-        }
+        // With --max-stack-depth, the callee-most frames are kept, and the
+        // rest isn't collected, so that deep stacks don't even get unwound.
+        let max_depth = max_stack_depth.unwrap_or(usize::MAX);
+        let mut is_truncated = false;
 
-        let mapping_end_avma = mapping_start_avma + mapping_size;
-        let avma_range = mapping_start_avma..mapping_end_avma;
+        // Get the first fragment of the stack from e.callchain.
+        if let Some(callchain) = e.callchain {
+            let mut is_first_frame = true;
+            let mut mode = cpu_mode;
+            for i in 0..callchain.len() {
+                let address = callchain.get(i).unwrap();
+                if address >= PERF_CONTEXT_MAX {
+                    if let Some(new_mode) = StackMode::from_context_frame(address) {
+                        mode = new_mode;
+                    }
+                    continue;
+                }
+                if use_lbr && mode == StackMode::User {
+                    break;
+                }
+                if stack.len() >= max_depth {
+                    is_truncated = true;
+                    break;
+                }
 
-        let name = Path::new(&path)
-            .file_name()
-            .map_or("<unknown>".into(), |f| f.to_string_lossy().to_string());
+                let stack_frame = match is_first_frame {
+                    true => StackFrame::InstructionPointer(address, mode),
+                    false => StackFrame::ReturnAddress(address, mode),
+                };
+                stack.push(stack_frame);
 
-        if let Some(file) = file {
-            let mmap = match unsafe { memmap2::MmapOptions::new().map(&file) } {
-                Ok(mmap) => mmap,
-                Err(err) => {
-                    eprintln!("Could not mmap file {path}: {err:?}");
-                    return;
-                }
-            };
+                is_first_frame = false;
+            }
+        }
 
-            fn section_data<'a>(section: &impl ObjectSection<'a>) -> Option<Vec<u8>> {
-                section.uncompressed_data().ok().map(|data| data.to_vec())
+        if use_lbr && !is_truncated {
+            let user_ip = e.ip.filter(|_| cpu_mode == StackMode::User);
+            lbr::push_lbr_user_frames(stack, user_ip, lbr_calls);
+            if stack.len() > max_depth {
+                stack.truncate(max_depth);
+                is_truncated = true;
             }
+        }
 
-            let file = match object::File::parse(&mmap[..]) {
-                Ok(file) => file,
-                Err(_) => {
-                    eprintln!("File {path} has unrecognized format");
-                    return;
+        // Append the user stack with the help of DWARF unwinding. The user stack
+        // of a guest sample belongs to the host process, not to the guest code
+        // which was interrupted, so don't unwind it.
+        let is_guest_sample = cpu_mode.is_guest();
+        let user_stack = e.user_stack.filter(|_| !is_guest_sample && !is_truncated);
+        let unwind_input = match (e.user_regs.as_ref(), user_stack) {
+            (Some(_), Some((user_stack, _))) if user_stack.is_empty() => {
+                unwind_validator.count_skipped_unwind::<C>(SkippedUnwindReason::EmptyUserStack);
+                None
+            }
+            (Some(regs), Some((user_stack, _))) => match C::convert_regs(regs) {
+                Ok(unwind_regs) => Some((unwind_regs, user_stack)),
+                Err(register) => {
+                    unwind_validator
+                        .count_skipped_unwind::<C>(SkippedUnwindReason::MissingRegister(register));
+                    None
                 }
+            },
+            _ => None,
+        };
+        if let Some(((pc, sp, regs), user_stack)) = unwind_input {
+            let ustack_bytes = RawDataU64::from_raw_data::<LittleEndian>(user_stack);
+            let mut read_stack = |addr: u64| {
+                // ustack_bytes has the stack bytes starting from the current stack pointer.
+                let offset = addr.checked_sub(sp).ok_or(())?;
+                let index = usize::try_from(offset / 8).map_err(|_| ())?;
+                ustack_bytes.get(index).ok_or(())
             };
 
-            // Verify build ID.
-            if let Some(build_id) = build_id {
-                match file.build_id().ok().flatten() {
-                    Some(file_build_id) if build_id == file_build_id => {
-                        // Build IDs match. Good.
+            // Unwind.
+            let _timing = TimingGuard::start(timings, TimingBucket::Unwinding);
+            let mut frames = unwinder.iter_frames(pc, regs, cache, &mut read_stack);
+            loop {
+                let frame = match frames.next() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(_) => {
+                        stack.push(StackFrame::TruncatedStackMarker);
+                        break;
                     }
-                    Some(file_build_id) => {
-                        let file_build_id = CodeId::from_binary(file_build_id);
-                        let expected_build_id = CodeId::from_binary(build_id);
-                        eprintln!(
-                            "File {path} has non-matching build ID {file_build_id} (expected {expected_build_id})"
-                        );
-                        return;
+                };
+                if stack.len() >= max_depth {
+                    is_truncated = true;
+                    break;
+                }
+                let stack_frame = match frame {
+                    FrameAddress::InstructionPointer(addr) => {
+                        StackFrame::InstructionPointer(addr, StackMode::User)
                     }
-                    None => {
-                        eprintln!(
-                            "File {path} does not contain a build ID, but we expected it to have one"
-                        );
-                        return;
+                    FrameAddress::ReturnAddress(addr) => {
+                        // Stop at the first implausible return address, rather
+                        // than following a stale CFI or garbage on the stack.
+                        if !unwind_validator.accepts::<C>(code_ranges, addr.into()) {
+                            stack.push(StackFrame::TruncatedStackMarker);
+                            break;
+                        }
+                        StackFrame::ReturnAddress(addr.into(), StackMode::User)
                     }
-                }
+                };
+                stack.push(stack_frame);
             }
+        }
 
-            let base_svma = samply_symbols::relative_address_base(&file);
-            let base_avma = if let Some(mapping) = suspected_pe_mapping {
-                // For the PE correlation hack, we can't use the mapping offsets as they correspond to
-                // an anonymous mapping. Instead, the base address is pre-determined from the PE header
-                // mapping.
-                mapping.start
-            } else if let Some(bias) = compute_vma_bias(
-                &file,
-                mapping_start_file_offset,
-                mapping_start_avma,
-                mapping_size,
-            ) {
-                base_svma.wrapping_add(bias)
-            } else {
-                return;
-            };
+        if is_truncated {
+            // The base of the stack is cut off, so there's no recursive
+            // prefix to fold.
+            stack.push(StackFrame::TruncatedStackMarker);
+        } else if stack.is_empty() {
+            if let Some(ip) = e.ip {
+                stack.push(StackFrame::InstructionPointer(ip, e.cpu_mode.into()));
+            }
+        } else if fold_recursive_prefix {
+            let last_frame = *stack.last().unwrap();
+            while stack.len() >= 2 && stack[stack.len() - 2] == last_frame {
+                stack.pop();
+            }
+        }
+    }
 
-            let text = file.section_by_name(".text");
-            let text_env = file.section_by_name("text_env");
-            let eh_frame = file.section_by_name(".eh_frame");
-            let got = file.section_by_name(".got");
-            let eh_frame_hdr = file.section_by_name(".eh_frame_hdr");
+    /// This is a terrible hack to get binary correlation working with apps on Wine.
+    ///
+    /// Unlike ELF, PE has the notion of "file alignment" that is different from page alignment.
+    /// Hence, even if the virtual address is page aligned, its on-disk offset may not be. This
+    /// leads to obvious trouble with using mmap, since mmap requires the file offset to be page
+    /// aligned. Wine's workaround is straightforward: for misaligned sections, Wine will simply
+    /// copy the image from disk instead of mmapping them. For example, `/proc/<pid>/maps` can look
+    /// like this:
+    ///
+    /// ```plain
+    /// <PE header> 140000000-140001000 r--p 00000000 00:25 272185   game.exe
+    /// <.text>     140001000-143be8000 r-xp 00000000 00:00 0
+    ///             143be8000-144c0c000 r--p 00000000 00:00 0
+    /// ```
+    ///
+    /// When this misalignment happens, most of the sections in the memory will not be a file
+    /// mapping. However, the PE header is always mapped, and it resides at the beginning of the
+    /// file, which means it's also always *aligned*. Finally, it's always mapped first, because
+    /// the information from the header is required to determine the load address of the other
+    /// sections. Hence, if we find a mapping that seems to pointing to a PE file, and has a file
+    /// offset of 0, we'll add it to the list of "suspected PE images". When we see a later mapping
+    /// that belongs to one of the suspected PE ranges, we'll match the mapping with the file,
+    /// which allows binary correlation and unwinding to work.
+    fn check_for_pe_mapping(&mut self, path_slice: &[u8], mapping_start_avma: u64) {
+        // Do a quick extension check first, to avoid end up trying to parse every mmapped file.
+        let filename_is_pe = path_slice.ends_with(b".exe")
+            || path_slice.ends_with(b".dll")
+            || path_slice.ends_with(b".EXE")
+            || path_slice.ends_with(b".DLL");
+        if !filename_is_pe {
+            return;
+        }
 
-            let unwind_data = match (
-                eh_frame.as_ref().and_then(section_data),
-                eh_frame_hdr.as_ref().and_then(section_data),
-            ) {
-                (Some(eh_frame), Some(eh_frame_hdr)) => {
-                    ModuleUnwindData::EhFrameHdrAndEhFrame(eh_frame_hdr, eh_frame)
-                }
-                (Some(eh_frame), None) => ModuleUnwindData::EhFrame(eh_frame),
-                (None, _) => ModuleUnwindData::None,
+        // There are a few assumptions here:
+        // - The SizeOfImage field in the PE header is defined to be a multiple of SectionAlignment.
+        //   SectionAlignment is usually the page size. When it's not the page size, additional
+        //   layout restrictions apply and Wine will always map the file in its entirety, which
+        //   means we're safe without the workaround. So we can safely assume it to be page aligned
+        //   here.
+        // - VirtualAddress of the sections are defined to be adjacent after page-alignment. This
+        //   means that we can treat the image as a contiguous region.
+        if let Some(size) = get_pe_mapping_size(path_slice, &self.open_cache) {
+            let mapping = SuspectedPeMapping {
+                path: path_slice.to_owned(),
+                start: mapping_start_avma,
+                size,
             };
+            self.suspected_pe_mappings.insert(mapping.start, mapping);
+        }
+    }
 
-            let text_data = if let Some(text_segment) = file
-                .segments()
-                .find(|segment| segment.name_bytes() == Ok(Some(b"__TEXT")))
-            {
-                let (start, size) = text_segment.file_range();
-                let address_range = base_avma + start..base_avma + start + size;
-                text_segment
-                    .data()
-                    .ok()
-                    .map(|data| TextByteData::new(data.to_owned(), address_range))
-            } else if let Some(text_section) = &text {
-                if let Some((start, size)) = text_section.file_range() {
-                    let address_range = base_avma + start..base_avma + start + size;
-                    text_section
-                        .data()
-                        .ok()
-                        .map(|data| TextByteData::new(data.to_owned(), address_range))
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
+    pub fn handle_mmap(&mut self, e: MmapRecord, timestamp: u64) {
+        let raw_path = e.path.as_slice();
+        if let Some(tracker) = self.mapping_tracker.as_mut().filter(|_| e.pid != -1) {
+            if e.is_executable {
+                // MMAP records have no protection, flags or inode, so the
+                // carried mapping is a private read+exec mapping of the file.
+                tracker.on_mmap(
+                    e.pid,
+                    CarriedMapping {
+                        address: e.address,
+                        length: e.length,
+                        page_offset: e.page_offset,
+                        protection: 0b101,
+                        flags: 0b10,
+                        file_id: Mmap2FileId::InodeAndVersion(Mmap2InodeAndVersion {
+                            major: 0,
+                            minor: 0,
+                            inode: 0,
+                            inode_generation: 0,
+                        }),
+                        path: raw_path.to_vec(),
+                    },
+                );
+            }
+        }
+        if let Some(jitdump_path) = get_path_if_jitdump(&raw_path) {
+            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+            process.jitdump_manager.add_jitdump_path(
+                jitdump_path,
+                self.extra_binary_artifact_dir.clone(),
+                self.path_map.clone(),
+                Some(timestamp),
+            );
+            return;
+        }
+
+        if e.page_offset == 0 {
+            self.check_for_pe_mapping(&e.path.as_slice(), e.address);
+        }
+
+        if !e.is_executable {
+            return;
+        }
+        if e.pid != -1 && is_anonymous_mapping_path(&raw_path) {
+            self.add_anonymous_executable_mapping(e.pid, e.address, e.length);
+        }
+
+        let mapped_path = MappedPath::parse(&raw_path);
+        let dso_key = match DsoKey::detect(&mapped_path.path, e.cpu_mode) {
+            Some(dso_key) => dso_key,
+            None => return,
+        };
+        let is_deleted = mapped_path.is_deleted;
+        let mut path = mapped_path.path.into_owned();
+        let mut build_id = None;
+        if let Some(dso_info) = self.build_ids.get(&dso_key) {
+            build_id = Some(dso_info.build_id.to_owned());
+            // Overwrite the path from the mmap record with the path from the build ID info.
+            // These paths are usually the same, but in some cases the path from the build
+            // ID info can be "better". For example, the synthesized mmap event for the
+            // kernel vmlinux image usually has "[kernel.kallsyms]_text" whereas the build
+            // ID info might have the full path to a kernel debug file, e.g.
+            // "/usr/lib/debug/boot/vmlinux-4.16.0-1-amd64".
+            path = dso_info.path.to_owned();
+        }
+
+        if e.pid == -1 {
+            self.add_kernel_module(e.address, e.length, dso_key, build_id.as_deref(), &path);
+        } else {
+            self.add_module_to_process(
+                e.pid,
+                &path,
+                is_deleted,
+                e.page_offset,
+                e.address,
+                e.length,
+                build_id.as_deref(),
+                timestamp,
+            );
+        }
+    }
+
+    /// Remembers an anonymous executable mapping, which the entries of the
+    /// process's perf map file must lie within.
+    fn add_anonymous_executable_mapping(&mut self, pid: i32, address: u64, length: u64) {
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        process
+            .anonymous_executable_ranges
+            .push(address..address.saturating_add(length));
+    }
+
+    pub fn handle_mmap2(&mut self, e: Mmap2Record, timestamp: u64) {
+        const PROT_EXEC: u32 = 0b100;
+        let raw_path = e.path.as_slice();
+        if let Some(tracker) = self.mapping_tracker.as_mut().filter(|_| e.pid != -1) {
+            // This includes the mappings of jitdump files, so that the next
+            // file reads them again.
+            if e.protection & PROT_EXEC != 0 {
+                tracker.on_mmap(
+                    e.pid,
+                    CarriedMapping {
+                        address: e.address,
+                        length: e.length,
+                        page_offset: e.page_offset,
+                        protection: e.protection,
+                        flags: e.flags,
+                        file_id: e.file_id.clone(),
+                        path: raw_path.to_vec(),
+                    },
+                );
+            }
+        }
+        if let Some(jitdump_path) = get_path_if_jitdump(&raw_path) {
+            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+            process.jitdump_manager.add_jitdump_path(
+                jitdump_path,
+                self.extra_binary_artifact_dir.clone(),
+                self.path_map.clone(),
+                Some(timestamp),
+            );
+            return;
+        }
+
+        if e.page_offset == 0 {
+            self.check_for_pe_mapping(&e.path.as_slice(), e.address);
+        }
+
+        if e.protection & PROT_EXEC == 0 {
+            // Ignore non-executable mappings.
+            return;
+        }
+        if is_anonymous_mapping_path(&raw_path) {
+            self.add_anonymous_executable_mapping(e.pid, e.address, e.length);
+        }
+
+        let MappedPath { path, is_deleted } = MappedPath::parse(&raw_path);
+        let build_id = match &e.file_id {
+            Mmap2FileId::BuildId(build_id) => Some(build_id.to_owned()),
+            Mmap2FileId::InodeAndVersion(_) => {
+                let dso_key = match DsoKey::detect(&path, e.cpu_mode) {
+                    Some(dso_key) => dso_key,
+                    None => return,
+                };
+                self.build_ids
+                    .get(&dso_key)
+                    .map(|db| db.build_id.to_owned())
+            }
+        };
+
+        self.add_module_to_process(
+            e.pid,
+            &path,
+            is_deleted,
+            e.page_offset,
+            e.address,
+            e.length,
+            build_id.as_deref(),
+            timestamp,
+        );
+    }
+
+    pub fn handle_context_switch(&mut self, e: ContextSwitchRecord, common: CommonData) {
+        let (Some(pid), Some(tid), Some(timestamp)) = (common.pid, common.tid, common.timestamp)
+        else {
+            self.skipped_context_switch_count += 1;
+            return;
+        };
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+
+        // With per-CPU threads, the switches show which thread ran on the
+        // CPU, and there are no off-CPU samples.
+        if let (Some(cpus), Some(cpu)) = (&mut self.cpus, common.cpu) {
+            match e {
+                ContextSwitchRecord::In { .. } => cpus.on_switch_in(
+                    cpu,
+                    pid,
+                    tid,
+                    thread.name.as_deref(),
+                    timestamp,
+                    &mut self.profile,
+                    &self.timestamp_converter,
+                ),
+                ContextSwitchRecord::Out { .. } => cpus.on_switch_out(
+                    cpu,
+                    tid,
+                    timestamp,
+                    &mut self.profile,
+                    &self.timestamp_converter,
+                ),
+            }
+            return;
+        }
+
+        match e {
+            ContextSwitchRecord::In { .. } => {
+                // Consume off-cpu time and clear the saved off-CPU stack.
+                let off_cpu_sample = self
+                    .context_switch_handler
+                    .handle_switch_in(timestamp, &mut thread.context_switch_data)
+                    .filter(|group| {
+                        !self
+                            .pause_state
+                            .overlaps(group.begin_timestamp, group.end_timestamp)
+                    });
+                if let (Some(off_cpu_sample), Some(off_cpu_stack)) =
+                    (off_cpu_sample, thread.off_cpu_stack.take())
+                {
+                    let cpu_delta_ns = self
+                        .context_switch_handler
+                        .consume_cpu_delta(&mut thread.context_switch_data);
+                    if self.max_output_size.is_some() && off_cpu_sample.sample_count > 1 {
+                        self.off_cpu_ranges.push((
+                            self.timestamp_converter
+                                .convert_time(off_cpu_sample.begin_timestamp),
+                            self.timestamp_converter
+                                .convert_time(off_cpu_sample.end_timestamp),
+                        ));
+                    }
+                    let deferred = DeferredOffCpuGroup {
+                        group: off_cpu_sample,
+                        cpu_delta_ns,
+                        blocked_stack: off_cpu_stack,
+                        switch_in_timestamp: timestamp,
+                    };
+                    if self.off_cpu_stack != OffCpuStack::Blocked
+                        && !self.marker_only
+                        && self.deferred_off_cpu_group_count < MAX_DEFERRED_OFF_CPU_GROUPS
+                        && thread.deferred_off_cpu_group.is_none()
+                    {
+                        // Wait for the thread's first sample, whose stack is the
+                        // resumed stack.
+                        thread.deferred_off_cpu_group = Some(deferred);
+                        self.deferred_off_cpu_group_count += 1;
+                    } else {
+                        process_deferred_off_cpu_sample_group(
+                            deferred,
+                            None,
+                            self.off_cpu_stack,
+                            &self.context_switch_handler,
+                            thread.profile_thread,
+                            &self.timestamp_converter,
+                            self.off_cpu_weight_per_sample,
+                            &mut process.unresolved_samples,
+                        );
+                    }
+                }
+            }
+            ContextSwitchRecord::Out { .. } => {
+                // The thread ran without a sample since it was switched in, so
+                // there's no resumed stack for its deferred group.
+                if let Some(deferred) = thread.deferred_off_cpu_group.take() {
+                    self.deferred_off_cpu_group_count -= 1;
+                    process_deferred_off_cpu_sample_group(
+                        deferred,
+                        None,
+                        self.off_cpu_stack,
+                        &self.context_switch_handler,
+                        thread.profile_thread,
+                        &self.timestamp_converter,
+                        self.off_cpu_weight_per_sample,
+                        &mut process.unresolved_samples,
+                    );
+                }
+                self.context_switch_handler
+                    .handle_switch_out(timestamp, &mut thread.context_switch_data);
+            }
+        }
+    }
+
+    /// Called for a FORK record.
+    ///
+    /// FORK records are emitted if a new thread is started or if a new
+    /// process is created. The name is inherited from the forking thread.
+    pub fn handle_thread_start(&mut self, e: ForkOrExitRecord) {
+        let start_time = self.timestamp_converter.convert_time(e.timestamp);
+
+        let is_main = e.pid == e.tid;
+        let parent_process = self.processes.get_by_pid(e.ppid, &mut self.profile);
+        if e.pid != e.ppid {
+            // We've created a new process.
+            self.cow_faults_after_fork
+                .push(CowFaultsAfterFork::new(e.pid, e.timestamp));
+            self.startups.on_fork(e.pid, e.timestamp);
+            if !is_main {
+                warn!(
+                    pid = e.pid,
+                    tid = e.tid,
+                    "Unexpected data in FORK record: If we fork into a different process, the forked child thread should be the main thread of the new process"
+                );
+            }
+            let parent_process_name = parent_process.name.clone();
+            let parent_thread = parent_process
+                .threads
+                .get_thread_by_tid(e.ptid, &mut self.profile);
+            let parent_thread_name = parent_thread.name.clone();
+            let is_reused = if let Some(name) = parent_process_name.as_deref() {
+                self.processes
+                    .attempt_reuse(e.pid, name, e.timestamp, &mut self.profile)
+                    .is_some()
+            } else {
+                false
+            };
+            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+            process.name = parent_process_name;
+            let process_handle = process.profile_process;
+            let thread = process.threads.get_main_thread();
+            thread.name = parent_thread_name;
+            let thread_handle = thread.profile_thread;
+            if let Some(thread_name) = thread.name.as_deref() {
+                self.profile.set_thread_name(thread_handle, thread_name);
+                self.thread_groups.on_thread_name_change(
+                    &mut self.profile,
+                    thread_handle,
+                    thread_name,
+                );
+            }
+            if !is_reused {
+                self.profile
+                    .set_process_start_time(process_handle, start_time);
+                self.profile
+                    .set_thread_start_time(thread_handle, start_time);
+            }
+            self.thread_incarnations
+                .on_fork(e.pid, e.tid, e.timestamp, thread_handle);
+        } else {
+            let parent_thread = parent_process
+                .threads
+                .get_thread_by_tid(e.ptid, &mut self.profile);
+            let parent_thread_name = parent_thread.name.clone();
+            let fork_stack = parent_process
+                .unresolved_samples
+                .last_sample_stack(parent_thread.profile_thread);
+            let is_reused = if let Some(name) = parent_thread_name.as_deref() {
+                parent_process
+                    .threads
+                    .attempt_thread_reuse(e.tid, name, e.timestamp, &mut self.profile)
+                    .is_some()
+            } else {
+                false
+            };
+            let mut thread = parent_process
+                .threads
+                .get_thread_by_tid(e.tid, &mut self.profile);
+            thread.name = parent_thread_name;
+            thread.fork_stack = fork_stack;
+            let thread_handle = thread.profile_thread;
+            if !is_reused {
+                if let Some(thread_name) = thread.name.as_deref() {
+                    self.profile.set_thread_name(thread_handle, thread_name);
+                    self.thread_groups.on_thread_name_change(
+                        &mut self.profile,
+                        thread_handle,
+                        thread_name,
+                    );
+                }
+                self.profile
+                    .set_thread_start_time(thread_handle, start_time);
+            }
+            self.thread_incarnations
+                .on_fork(e.pid, e.tid, e.timestamp, thread_handle);
+        };
+    }
+
+    /// Called for an EXIT record.
+    /// Adds the deferred off-CPU sample groups of the thread, or of all
+    /// threads of the process if `tid` is None, with their blocked stack.
+    /// Called before the threads are removed.
+    fn flush_deferred_off_cpu_groups(&mut self, pid: i32, tid: Option<i32>) {
+        if self.deferred_off_cpu_group_count == 0 {
+            return;
+        }
+        let Some(process) = self.processes.get_if_alive(pid) else { return };
+        let threads: Vec<&mut Thread> = match tid {
+            Some(tid) => Vec::from_iter(process.threads.existing_thread_mut(tid)),
+            None => process.threads.iter_mut().collect(),
+        };
+        for thread in threads {
+            let Some(deferred) = thread.deferred_off_cpu_group.take() else { continue };
+            self.deferred_off_cpu_group_count -= 1;
+            process_deferred_off_cpu_sample_group(
+                deferred,
+                None,
+                self.off_cpu_stack,
+                &self.context_switch_handler,
+                thread.profile_thread,
+                &self.timestamp_converter,
+                self.off_cpu_weight_per_sample,
+                &mut process.unresolved_samples,
+            );
+        }
+    }
+
+    pub fn handle_thread_end(&mut self, e: ForkOrExitRecord) {
+        let is_main = e.pid == e.tid;
+        self.flush_deferred_off_cpu_groups(e.pid, (!is_main).then(|| e.tid));
+        if let Some(thread_handle) = self.processes.existing_thread_handle(e.pid, e.tid) {
+            self.thread_incarnations
+                .on_exit(e.pid, e.tid, e.timestamp, thread_handle);
+        }
+        self.processes.synthesize_sample_for_exiting_thread(
+            e.pid,
+            e.tid,
+            e.timestamp,
+            &self.timestamp_converter,
+        );
+        if is_main {
+            self.add_heap_profile_samples(e.pid, e.timestamp);
+            if let Some(process) = self.processes.get_if_alive(e.pid) {
+                self.process_exits.on_process_end(
+                    e.pid,
+                    process.name.clone(),
+                    e.timestamp,
+                    process.threads.main_thread.profile_thread,
+                );
+            }
+            if let Some(tracker) = &mut self.mapping_tracker {
+                tracker.on_process_image_gone(e.pid);
+            }
+            self.processes.remove(
+                e.pid,
+                e.timestamp,
+                &mut self.profile,
+                &mut self.jit_category_manager,
+                &self.timestamp_converter,
+            );
+        } else {
+            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+            process.threads.remove_non_main_thread(
+                e.tid,
+                e.timestamp,
+                &self.timestamp_converter,
+                self.merge_threads,
+                &mut self.profile,
+            );
+        }
+    }
+
+    /// Called with the wait status of a process which the recording launched.
+    /// It can come before or after the process's EXIT record.
+    pub fn handle_child_exit(&mut self, pid: i32, reason: ExitReason) {
+        self.process_exits.set_wait_status(pid, reason);
+    }
+
+    pub fn set_thread_name(&mut self, pid: i32, tid: i32, name: &str, is_thread_creation: bool) {
+        let is_main = pid == tid;
+
+        let process = self.processes.get_by_pid(pid, &mut self.profile);
+        let process_handle = process.profile_process;
+
+        let thread = process.threads.get_thread_by_tid(tid, &mut self.profile);
+        let thread_handle = thread.profile_thread;
+
+        self.profile.set_thread_name(thread_handle, name);
+        self.thread_groups
+            .on_thread_name_change(&mut self.profile, thread_handle, name);
+        thread.set_name(name);
+        if is_main {
+            self.profile.set_process_name(process_handle, name);
+            process.name = Some(name.to_owned());
+        }
+
+        if is_thread_creation {
+            // Mark this as the start time of the new thread / process.
+            let time = self
+                .timestamp_converter
+                .convert_time(self.current_sample_time);
+            self.profile.set_thread_start_time(thread_handle, time);
+            if is_main {
+                self.profile.set_process_start_time(process_handle, time);
+            }
+        }
+
+        if self.delayed_product_name_generator.is_some() && name != "perf-exec" {
+            let generator = self.delayed_product_name_generator.take().unwrap();
+            let product = generator(name);
+            self.profile.set_product(&product);
+            self.have_product_name = true;
+        }
+    }
+
+    pub fn handle_thread_name_update(&mut self, e: CommOrExecRecord, timestamp: Option<u64>) {
+        let is_main = e.pid == e.tid;
+        let name = e.name.as_slice();
+        let name = String::from_utf8_lossy(&name);
+
+        // If the COMM record doesn't have a timestamp, take the last seen
+        // timestamp from the previous sample.
+        let timestamp = match timestamp {
+            Some(0) | None => self.current_sample_time,
+            Some(ts) => ts,
+        };
+        let is_thread_creation = if e.is_execve {
+            self.flush_deferred_off_cpu_groups(e.pid, (!is_main).then(|| e.tid));
+            // Mark the old thread / process as ended.
+            if is_main {
+                self.processes.remove(
+                    e.pid,
+                    timestamp,
+                    &mut self.profile,
+                    &mut self.jit_category_manager,
+                    &self.timestamp_converter,
+                );
+                let maybe_reused_process =
+                    self.processes
+                        .attempt_reuse(e.pid, &name, timestamp, &mut self.profile);
+                maybe_reused_process.is_none()
+            } else {
+                warn!(
+                    pid = e.pid,
+                    tid = e.tid,
+                    "Unexpected is_execve on non-main thread! pid: {}, tid: {}",
+                    e.pid,
+                    e.tid
+                );
+                let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+                process.threads.remove_non_main_thread(
+                    e.tid,
+                    timestamp,
+                    &self.timestamp_converter,
+                    self.merge_threads,
+                    &mut self.profile,
+                );
+                let maybe_reused_thread = process.threads.attempt_thread_reuse(
+                    e.tid,
+                    &name,
+                    timestamp,
+                    &mut self.profile,
+                );
+                maybe_reused_thread.is_none()
+            }
+        } else if self.merge_threads && !is_main && !self.processes.has_own_name(e.pid, e.tid) {
+            // This is the first name which the thread gives itself, after
+            // inheriting the name of its parent at the fork. Merge it with an
+            // ended thread of that name. Renames after that are applied in
+            // place, so that a thread which renames itself for every request
+            // doesn't end up as one thread per request.
+            self.flush_deferred_off_cpu_groups(e.pid, Some(e.tid));
+            // Mark the old thread / process as ended.
+            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+            process.threads.remove_non_main_thread(
+                e.tid,
+                timestamp,
+                &self.timestamp_converter,
+                self.merge_threads,
+                &mut self.profile,
+            );
+            let maybe_reused_thread =
+                process
+                    .threads
+                    .attempt_thread_reuse(e.tid, &name, timestamp, &mut self.profile);
+            maybe_reused_thread.is_none()
+        } else {
+            false
+        };
+
+        self.set_thread_name(e.pid, e.tid, &name, is_thread_creation);
+        if !e.is_execve {
+            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+            process
+                .threads
+                .get_thread_by_tid(e.tid, &mut self.profile)
+                .has_own_name = true;
+        }
+
+        if e.is_execve && is_main {
+            let process = self.processes.get_by_pid(e.pid, &mut self.profile);
+            process.exec_timestamp = Some(timestamp);
+            process.executable_mapping_count = 0;
+            process.code_ranges.clear();
+            self.startups.on_exec(e.pid, timestamp);
+            if let Some(tracker) = &mut self.mapping_tracker {
+                tracker.on_process_image_gone(e.pid);
+            }
+        }
+    }
+
+    fn add_kernel_module(
+        &mut self,
+        base_address: u64,
+        len: u64,
+        dso_key: DsoKey,
+        build_id: Option<&[u8]>,
+        path: &[u8],
+    ) {
+        // perf's synthesized kernel mapping can extend to the end of the
+        // address space.
+        let end_address = base_address.saturating_add(len);
+        if let Some(existing) = self.kernel_mappings.get(&base_address) {
+            if existing.end == end_address && existing.dso_key == dso_key {
+                // We've already added this exact mapping.
+                return;
+            }
+        }
+
+        let path = std::str::from_utf8(path).unwrap().to_string();
+        let _phase = self
+            .phases
+            .as_ref()
+            .map(|phases| phases.interval(format!("Load kernel module {path}")));
+        let _span = debug_span!("kernel_module_load", path = %path).entered();
+        // Most distros ship compressed kernel modules. Their build ID and symbols
+        // are read from a decompressed copy.
+        let decompressed_path = match dso_key {
+            DsoKey::KernelModule { .. } => {
+                self.compressed_modules.decompressed_path(Path::new(&path))
+            }
+            _ => None,
+        };
+        // Without addresses in /proc/kallsyms, the kernel mapping is assumed
+        // to be the running kernel.
+        let build_id: Option<Vec<u8>> = match (build_id, self.kernel_symbols.as_ref()) {
+            (None, Some(kernel_symbols))
+                if kernel_symbols.base_avma == Some(base_address)
+                    || (kernel_symbols.base_avma.is_none() && dso_key == DsoKey::Kernel) =>
+            {
+                Some(kernel_symbols.build_id.clone())
+            }
+            (None, _) => build_id_for_file(
+                decompressed_path.as_deref().unwrap_or(Path::new(&path)),
+                self.extra_binary_artifact_dir.as_deref(),
+                self.path_map.as_deref(),
+                &self.open_cache,
+            ),
+            (Some(build_id), _) => Some(build_id.to_owned()),
+        };
+        let debug_id = build_id
+            .as_deref()
+            .map(|id| DebugId::from_identifier(id, self.endian == Endianness::LittleEndian));
+
+        // Resolve conflicts with existing mappings which overlap this one but
+        // aren't identical to it. Prefer the mapping whose build ID matches the
+        // kernel symbols.
+        let overlapping_starts: Vec<u64> = self
+            .kernel_mappings
+            .range(..end_address)
+            .filter(|(_, existing)| existing.end > base_address)
+            .map(|(start, _)| *start)
+            .collect();
+        let new_matches_kernel_symbols = self.build_id_matches_kernel_symbols(build_id.as_deref());
+        for start in &overlapping_starts {
+            let existing = &self.kernel_mappings[start];
+            if self.build_id_matches_kernel_symbols(existing.build_id.as_deref())
+                && !new_matches_kernel_symbols
+            {
+                warn!(
+                    path = %path,
+                    "Ignoring kernel mapping {path} at 0x{base_address:x}-0x{end_address:x} because it overlaps the mapping for {} at 0x{start:x}-0x{:x}, whose build ID matches the kernel symbols.",
+                    existing.dso_key.name(),
+                    existing.end
+                );
+                return;
+            }
+        }
+        for start in overlapping_starts {
+            let existing = self.kernel_mappings.remove(&start).unwrap();
+            warn!(
+                path = %path,
+                "Kernel mapping {path} at 0x{base_address:x}-0x{end_address:x} replaces the overlapping mapping for {} at 0x{start:x}-0x{:x}.",
+                existing.dso_key.name(),
+                existing.end
+            );
+            self.kernel_lib_mappings.remove_mapping(start);
+        }
+        self.kernel_mappings.insert(
+            base_address,
+            KernelMapping {
+                end: end_address,
+                dso_key: dso_key.clone(),
+                build_id: build_id.clone(),
+            },
+        );
+
+        let debug_path = match (self.linux_version.as_deref(), decompressed_path) {
+            (Some(linux_version), _) if path.starts_with("[kernel.kallsyms]") => {
+                // Take a guess at the vmlinux debug file path.
+                format!("/usr/lib/debug/boot/vmlinux-{linux_version}")
+            }
+            (_, Some(decompressed_path)) => decompressed_path.to_string_lossy().into_owned(),
+            _ => path.clone(),
+        };
+        let kernel_symbol_table = match (&dso_key, &build_id, self.kernel_symbols.as_ref()) {
+            (DsoKey::Kernel, Some(build_id), Some(kernel_symbols))
+                if build_id == &kernel_symbols.build_id =>
+            {
+                kernel_symbols.symbol_table.clone()
+            }
+            _ => None,
+        };
+        if let Some(symbol_table) = &kernel_symbol_table {
+            // The symbol addresses are relative to _text, so they're rebased
+            // to the mapping's address, which is where _text was loaded while
+            // recording.
+            self.cow_fault_detector =
+                Some(CowFaultDetector::new(base_address, symbol_table.clone()));
+            self.sampling_bias_detector = Some(SamplingBiasDetector::new(
+                base_address,
+                symbol_table.clone(),
+            ));
+            self.kernel_frame_classifier = Some(KernelFrameClassifier::new(
+                base_address,
+                symbol_table.clone(),
+            ));
+        }
+        // The relative addresses of kernel mappings start at the load address,
+        // so linker map addresses are taken to be load addresses.
+        let symbol_table = self.symbol_maps.symbol_table_for(
+            dso_key.name(),
+            &path,
+            build_id.as_deref(),
+            base_address,
+            kernel_symbol_table,
+        );
+
+        let lib_handle = self.profile.add_lib(LibraryInfo {
+            debug_id: debug_id.unwrap_or_default(),
+            path,
+            debug_path,
+            code_id: build_id.map(|build_id| CodeId::from_binary(&build_id).to_string()),
+            name: dso_key.name().to_string(),
+            debug_name: dso_key.name().to_string(),
+            arch: None,
+            symbol_table,
+        });
+        self.kernel_lib_mappings.add_mapping(
+            base_address,
+            end_address,
+            0,
+            LibMappingInfo::new_lib(lib_handle),
+        );
+    }
+
+    fn build_id_matches_kernel_symbols(&self, build_id: Option<&[u8]>) -> bool {
+        match (build_id, self.kernel_symbols.as_ref()) {
+            (Some(build_id), Some(kernel_symbols)) => build_id == kernel_symbols.build_id,
+            _ => false,
+        }
+    }
+
+    /// Tell the unwinder about this module, and alsos create a ProfileModule
+    /// and add it to the profile.
+    ///
+    /// The unwinder needs to know about it in case we need to do DWARF stack
+    /// unwinding - it needs to get the unwinding information from the binary.
+    /// The profile needs to know about this module so that it can assign
+    /// addresses in the stack to the right module and so that symbolication
+    /// knows where to get symbols for this module.
+    #[allow(clippy::too_many_arguments)]
+    fn add_module_to_process(
+        &mut self,
+        process_pid: i32,
+        path_slice: &[u8],
+        is_deleted: bool,
+        mapping_start_file_offset: u64,
+        mapping_start_avma: u64,
+        mapping_size: u64,
+        build_id: Option<&[u8]>,
+        timestamp: u64,
+    ) {
+        let path = std::str::from_utf8(path_slice).unwrap();
+        let _phase = self
+            .phases
+            .as_ref()
+            .map(|phases| phases.interval(format!("Load module {path}")));
+        let _span = debug_span!("module_load", pid = process_pid, path).entered();
+        self.heap_profiles
+            .on_file_mapped(process_pid, path, build_id);
+
+        self.startups.on_mmap(process_pid, timestamp);
+
+        let process = self.processes.get_by_pid(process_pid, &mut self.profile);
+        process.executable_mapping_count += 1;
+        process
+            .code_ranges
+            .add_mapping(mapping_start_avma..mapping_start_avma + mapping_size);
+
+        let logged_module = self.conversion_log.is_some().then(|| {
+            LoggedModule::new(
+                process_pid,
+                timestamp,
+                path_slice,
+                build_id,
+                mapping_start_file_offset,
+                mapping_start_avma,
+                mapping_size,
+            )
+        });
+        let mut log_outcome = |outcome| {
+            if let (Some(log), Some(module)) = (&mut self.conversion_log, &logged_module) {
+                log.add_module(module, outcome);
+            }
+        };
+
+        if is_device_path(Path::new(path)) {
+            // Don't open the device. The library has no file, but samples in
+            // the mapping are grouped under the device's name.
+            debug!(path, "Not parsing the device mapping {path}");
+            let name = Path::new(path)
+                .file_name()
+                .map_or(path.into(), |f| f.to_string_lossy().to_string());
+            let lib_handle = self.profile.add_lib(LibraryInfo {
+                debug_id: DebugId::nil(),
+                code_id: None,
+                path: path.to_owned(),
+                debug_path: path.to_owned(),
+                debug_name: name.clone(),
+                name,
+                arch: None,
+                symbol_table: None,
+            });
+            process.add_regular_lib_mapping(
+                timestamp,
+                mapping_start_avma,
+                mapping_start_avma + mapping_size,
+                mapping_start_file_offset as u32,
+                lib_handle,
+                None,
+                None,
+                None,
+            );
+            log_outcome(ModuleOutcome::Mapped {
+                base: BaseSource::Device,
+                base_svma: None,
+                base_avma: mapping_start_avma.wrapping_sub(mapping_start_file_offset),
+            });
+            return;
+        }
+
+        // The path of a process in a container is a path in the container's
+        // file system, which can be different from the file at the same path
+        // on the host.
+        let container_copy = self
+            .container_binaries
+            .as_mut()
+            .and_then(|binaries| binaries.copy_if_in_container(process_pid, Path::new(path)));
+        let open_result = match container_copy {
+            Some(copy) => self.open_cache.open(&copy).map(|file| (file, copy)),
+            None => self.open_cache.open_with_fallback(
+                Path::new(path),
+                self.extra_binary_artifact_dir.as_deref(),
+                self.path_map.as_deref(),
+            ),
+        };
+        let mut is_unparseable = false;
+        let (mut file, mut path): (Option<_>, String) = match open_result {
+            Ok((file, path)) => (Some(file), path.to_string_lossy().to_string()),
+            Err(err) => {
+                is_unparseable = err.kind() == std::io::ErrorKind::InvalidData;
+                (None, path.to_owned())
+            }
+        };
+
+        let mut suspected_pe_mapping = None;
+        if file.is_none() {
+            suspected_pe_mapping = self
+                .suspected_pe_mappings
+                .range(..=mapping_start_avma)
+                .next_back()
+                .map(|(_, m)| m)
+                .filter(|m| {
+                    mapping_start_avma >= m.start
+                        && mapping_start_avma + mapping_size <= m.start + m.size
+                });
+            if let Some(mapping) = suspected_pe_mapping {
+                if let Ok((pe_file, pe_path)) = self.open_cache.open_with_fallback(
+                    Path::new(std::str::from_utf8(&mapping.path).unwrap()),
+                    self.extra_binary_artifact_dir.as_deref(),
+                    self.path_map.as_deref(),
+                ) {
+                    file = Some(pe_file);
+                    path = pe_path.to_string_lossy().to_string();
+                }
+            }
+        }
+
+        if file.is_none() && !path.starts_with('[') && !is_unparseable {
+            debug!(path = %path, "Could not open file {path}");
+        }
+
+        // If the mapped file was deleted, the file at this path, if any, is a
+        // different file, e.g. a newer version of the library. Only use it if
+        // it's the same build.
+        if is_deleted && file.is_some() {
+            let file_build_id = build_id_for_file(Path::new(&path), None, None, &self.open_cache);
+            if build_id.is_none() || file_build_id.as_deref() != build_id {
+                file = None;
+            }
+        }
+
+        // If the file is missing or is a different build, e.g. because the
+        // library was updated since the recording, use the copy from perf's
+        // build ID cache. The library keeps the name of the mapped file.
+        let mut original_path = None;
+        if let (Some(build_id), false) = (build_id, self.build_id_caches.is_empty()) {
+            let file_matches = file.is_some()
+                && build_id_for_file(Path::new(&path), None, None, &self.open_cache).as_deref()
+                    == Some(build_id);
+            if !file_matches {
+                if let Some((cached_file, cached_path)) = self.build_id_caches.open(build_id) {
+                    file = Some(cached_file);
+                    let cached_path = cached_path.to_string_lossy().to_string();
+                    original_path = Some(std::mem::replace(&mut path, cached_path));
+                }
+            }
+        }
+
+        if file.is_none() && is_unparseable {
+            // We warned about the file's format when we first tried to parse it.
+            log_outcome(ModuleOutcome::Skipped(SkipReason::Unparseable));
+            return;
+        }
+
+        // Fix up bad files from `perf inject --jit`.
+        if let Some(file_inner) = &file {
+            if let Some((fixed_file, fixed_path)) = correct_bad_perf_jit_so_file(file_inner, &path)
+            {
+                file = Some(fixed_file);
+                path = fixed_path;
+            }
+        }
+
+        let mapping_end_avma = mapping_start_avma + mapping_size;
+        let avma_range = mapping_start_avma..mapping_end_avma;
+
+        let name = Path::new(original_path.as_deref().unwrap_or(&path))
+            .file_name()
+            .map_or("<unknown>".into(), |f| f.to_string_lossy().to_string());
+
+        if let Some(file) = file {
+            let mmap = match unsafe { memmap2::MmapOptions::new().map(&file) } {
+                Ok(mmap) => mmap,
+                Err(err) => {
+                    warn!(path = %path, error = %err, "Could not mmap file {path}: {err:?}");
+                    self.open_cache.record_parse_failure(Path::new(&path));
+                    log_outcome(ModuleOutcome::Skipped(SkipReason::Unparseable));
+                    return;
+                }
+            };
+
+            let file = match object::File::parse(&mmap[..]) {
+                Ok(file) => file,
+                Err(_) => {
+                    warn!(path = %path, "File {path} has unrecognized format");
+                    self.open_cache.record_parse_failure(Path::new(&path));
+                    log_outcome(ModuleOutcome::Skipped(SkipReason::Unparseable));
+                    return;
+                }
+            };
+
+            // Verify build ID.
+            if let Some(build_id) = build_id {
+                match file.build_id().ok().flatten() {
+                    Some(file_build_id) if build_id == file_build_id => {
+                        // Build IDs match. Good.
+                    }
+                    Some(file_build_id) => {
+                        let file_build_id = CodeId::from_binary(file_build_id);
+                        let expected_build_id = CodeId::from_binary(build_id);
+                        warn!(
+                            path = %path,
+                            %file_build_id,
+                            %expected_build_id,
+                            "File {path} has non-matching build ID {file_build_id} (expected {expected_build_id})"
+                        );
+                        log_outcome(ModuleOutcome::Skipped(SkipReason::BuildIdMismatch));
+                        return;
+                    }
+                    None => {
+                        warn!(
+                            path = %path,
+                            "File {path} does not contain a build ID, but we expected it to have one"
+                        );
+                        log_outcome(ModuleOutcome::Skipped(SkipReason::MissingBuildId));
+                        return;
+                    }
+                }
+            }
+
+            let base_svma = samply_symbols::relative_address_base(&file);
+            let base = match suspected_pe_mapping {
+                Some(_) => BaseSource::PeHeader,
+                None => BaseSource::Bias,
+            };
+            let base_avma = if let Some(mapping) = suspected_pe_mapping {
+                // For the PE correlation hack, we can't use the mapping offsets as they correspond to
+                // an anonymous mapping. Instead, the base address is pre-determined from the PE header
+                // mapping.
+                mapping.start
+            } else if let Some(bias) = compute_vma_bias(
+                &file,
+                mapping_start_file_offset,
+                mapping_start_avma,
+                mapping_size,
+            ) {
+                base_svma.wrapping_add(bias)
+            } else {
+                log_outcome(ModuleOutcome::Skipped(SkipReason::NoBias));
+                return;
+            };
+
+            let text = file.section_by_name(".text");
+            let text_env = file.section_by_name("text_env");
+            let eh_frame = file.section_by_name(".eh_frame");
+            let got = file.section_by_name(".got");
+            let eh_frame_hdr = file.section_by_name(".eh_frame_hdr");
+
+            let section_data = self.module_data_cache.get_or_insert_with(
+                &path,
+                file.build_id().ok().flatten(),
+                || ModuleSectionData::from_object(&file),
+            );
+            let unwind_data = match (&section_data.eh_frame, &section_data.eh_frame_hdr) {
+                (Some(eh_frame), Some(eh_frame_hdr)) => {
+                    ModuleUnwindData::EhFrameHdrAndEhFrame(eh_frame_hdr.clone(), eh_frame.clone())
+                }
+                (Some(eh_frame), None) => ModuleUnwindData::EhFrame(eh_frame.clone()),
+                (None, _) => ModuleUnwindData::None,
+            };
+            if let Some((data, start)) = &section_data.text {
+                process
+                    .code_ranges
+                    .add_text(base_avma + start, data.clone());
+            }
+            let text_data = section_data.text.as_ref().map(|(data, start)| {
+                let address_range = base_avma + start..base_avma + start + data.len() as u64;
+                TextByteData::new(data.clone(), address_range)
+            });
+
+            fn svma_range<'a>(section: &impl ObjectSection<'a>) -> Range<u64> {
+                section.address()..section.address() + section.size()
+            }
+
+            let module = Module::new(
+                path.to_string(),
+                avma_range.clone(),
+                base_avma,
+                ModuleSvmaInfo {
+                    base_svma,
+                    text: text.as_ref().map(svma_range),
+                    text_env: text_env.as_ref().map(svma_range),
+                    stubs: None,
+                    stub_helper: None,
+                    eh_frame: eh_frame.as_ref().map(svma_range),
+                    eh_frame_hdr: eh_frame_hdr.as_ref().map(svma_range),
+                    got: got.as_ref().map(svma_range),
+                },
+                unwind_data,
+                text_data,
+            );
+            process.unwinder.add_module(module);
+
+            let debug_id = if let Some(debug_id) = debug_id_for_object(&file) {
+                debug_id
+            } else {
+                log_outcome(ModuleOutcome::Skipped(SkipReason::NoDebugId));
+                return;
+            };
+            let code_id = file
+                .build_id()
+                .ok()
+                .flatten()
+                .map(|build_id| CodeId::from_binary(build_id).to_string());
+            let wine_module = WineModuleInfo::from_object(&file, base_svma, &path);
+            self.have_wine_modules |= wine_module.is_some();
+            let hidden_frames = self
+                .frame_filter
+                .as_ref()
+                .and_then(|filter| filter.hidden_ranges_for_object(&file, base_svma, &name, &path));
+            let symbol_table = self.symbol_maps.symbol_table_for(
+                &name,
+                &path,
+                file.build_id().ok().flatten(),
+                base_svma,
+                None,
+            );
+            let lib_handle = self.profile.add_lib(LibraryInfo {
+                debug_id,
+                code_id,
+                path: path.clone(),
+                debug_path: path,
+                debug_name: name.clone(),
+                name: name.clone(),
+                arch: None,
+                symbol_table,
+            });
+            if let Some(attribution) = &mut self.request_attribution {
+                attribution.add_object(lib_handle, &file, base_svma);
+            }
+            self.gc_detection.add_object(lib_handle, &file, base_svma);
+
+            let relative_address_at_start = (avma_range.start - base_avma) as u32;
+
+            if name.starts_with("jitted-") && name.ends_with(".so") {
+                let symbol_name = jit_function_name(
+                    &file,
+                    &name,
+                    mapping_start_avma..mapping_end_avma,
+                    |code_index| {
+                        // The jitdump file's records may not have been read yet.
+                        process.read_all_jitdump_records(
+                            &mut self.jit_category_manager,
+                            &mut self.profile,
+                            &self.timestamp_converter,
+                            self.timings.as_ref(),
+                        );
+                        let name = process.jitdump_manager.function_name(code_index)?;
+                        Some(name.to_owned())
+                    },
+                );
+                process.add_lib_mapping_for_injected_jit_lib(
+                    timestamp,
+                    self.timestamp_converter.convert_time(timestamp),
+                    &symbol_name,
+                    mapping_start_avma,
+                    mapping_end_avma,
+                    relative_address_at_start,
+                    lib_handle,
+                    &mut self.jit_category_manager,
+                    &mut self.profile,
+                );
+            } else {
+                let dynamic_linking_ranges = DynamicLinkingRanges::from_object(
+                    &file,
+                    base_svma,
+                    is_dynamic_linker_name(&name),
+                );
+                process.add_regular_lib_mapping(
+                    timestamp,
+                    mapping_start_avma,
+                    mapping_end_avma,
+                    relative_address_at_start,
+                    lib_handle,
+                    dynamic_linking_ranges,
+                    wine_module,
+                    hidden_frames,
+                );
+            }
+            log_outcome(ModuleOutcome::Mapped {
+                base,
+                base_svma: Some(base_svma),
+                base_avma,
+            });
+        } else {
+            // Without access to the binary file, make some guesses. We can't really
+            // know what the right base address is because we don't have the section
+            // information which lets us map between addresses and file offsets, but
+            // often svmas and file offsets are the same, so this is a reasonable guess.
+            let base_avma = mapping_start_avma - mapping_start_file_offset;
+            let relative_address_at_start = (mapping_start_avma - base_avma) as u32;
+
+            // If we have a build ID, convert it to a debug_id and a code_id.
+            let debug_id = build_id
+                .map(|id| DebugId::from_identifier(id, true)) // TODO: endian
+                .unwrap_or_default();
+            let code_id = build_id.map(|build_id| CodeId::from_binary(build_id).to_string());
+            let hidden_frames = self
+                .frame_filter
+                .as_ref()
+                .and_then(|filter| filter.hidden_ranges_for_library(&name, &path));
+            // Without the file, the relative address base is unknown. It's 0
+            // for most shared libraries.
+            let symbol_table = self
+                .symbol_maps
+                .symbol_table_for(&name, &path, build_id, 0, None);
+
+            let lib_handle = self.profile.add_lib(LibraryInfo {
+                debug_id,
+                code_id,
+                path: path.clone(),
+                debug_path: path,
+                debug_name: name.clone(),
+                name,
+                arch: None,
+                symbol_table,
+            });
+            if build_id.is_none() {
+                if let Some(dso_key) = DsoKey::detect(path_slice, CpuMode::User) {
+                    self.libs_without_build_id.push((dso_key, lib_handle));
+                }
+            }
+            process.add_regular_lib_mapping(
+                timestamp,
+                mapping_start_avma,
+                mapping_end_avma,
+                relative_address_at_start,
+                lib_handle,
+                None,
+                None,
+                hidden_frames,
+            );
+            log_outcome(ModuleOutcome::Mapped {
+                base: BaseSource::Guess,
+                base_svma: None,
+                base_avma,
+            });
+        }
+    }
+}
+
+// #[test]
+// fn test_my_jit() {
+//     let data = std::fs::read("/Users/mstange/Downloads/jitted-123175-0-fixed.so").unwrap();
+//     let file = object::File::parse(&data[..]).unwrap();
+//     dbg!(jit_function_name(&file, "jitted-123175-0-fixed.so", 0..0, |_| None));
+// }
+
+/// The indexes of the tracepoint handlers which handle the samples of the
+/// event.
+fn tracepoint_handler_indexes(handlers: &[Box<dyn TracepointHandler>], name: &str) -> Vec<usize> {
+    (0..handlers.len())
+        .filter(|&i| handlers[i].wants(name))
+        .collect()
+}
+
+/// The weight of a sample of an event which counts something other than
+/// time: its period, i.e. the number of events since the previous sample.
+fn period_weight(period: Option<u64>) -> i32 {
+    period.map_or(1, |period| i32::try_from(period).unwrap_or(i32::MAX))
+}
+
+fn process_off_cpu_sample_group(
+    off_cpu_sample: OffCpuSampleGroup,
+    thread_handle: ThreadHandle,
+    cpu_delta_ns: u64,
+    timestamp_converter: &TimestampConverter,
+    off_cpu_weight_per_sample: i32,
+    off_cpu_stack: UnresolvedStackHandle,
+    samples: &mut UnresolvedSamples,
+) {
+    let OffCpuSampleGroup {
+        begin_timestamp,
+        end_timestamp,
+        sample_count,
+    } = off_cpu_sample;
+
+    // Add a sample at the beginning of the paused range.
+    // This "first sample" will carry any leftover accumulated running time ("cpu delta").
+    let cpu_delta = CpuDelta::from_nanos(cpu_delta_ns);
+    let weight = off_cpu_weight_per_sample;
+    let stack = off_cpu_stack;
+    let profile_timestamp = timestamp_converter.convert_time(begin_timestamp);
+    samples.add_sample(
+        thread_handle,
+        profile_timestamp,
+        begin_timestamp,
+        stack,
+        cpu_delta,
+        weight,
+    );
+
+    if sample_count > 1 {
+        // Emit a "rest sample" with a CPU delta of zero covering the rest of the paused range.
+        let cpu_delta = CpuDelta::from_nanos(0);
+        let weight = i32::try_from(sample_count - 1).unwrap_or(0) * off_cpu_weight_per_sample;
+        let profile_timestamp = timestamp_converter.convert_time(end_timestamp);
+        samples.add_sample(
+            thread_handle,
+            profile_timestamp,
+            begin_timestamp,
+            stack,
+            cpu_delta,
+            weight,
+        );
+    }
+}
+
+/// Adds the samples of a deferred off-CPU sample group, with the stacks
+/// chosen by `mode`. Without a resumed stack, all samples get the blocked
+/// stack.
+#[allow(clippy::too_many_arguments)]
+fn process_deferred_off_cpu_sample_group(
+    deferred: DeferredOffCpuGroup,
+    resumed_stack: Option<UnresolvedStackHandle>,
+    mode: OffCpuStack,
+    context_switch_handler: &ContextSwitchHandler,
+    thread_handle: ThreadHandle,
+    timestamp_converter: &TimestampConverter,
+    off_cpu_weight_per_sample: i32,
+    samples: &mut UnresolvedSamples,
+) {
+    for (group, cpu_delta_ns, stack) in
+        deferred.into_parts(mode, resumed_stack, context_switch_handler)
+    {
+        process_off_cpu_sample_group(
+            group,
+            thread_handle,
+            cpu_delta_ns,
+            timestamp_converter,
+            off_cpu_weight_per_sample,
+            stack,
+            samples,
+        );
+    }
+}
+
+struct Processes<U>
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+{
+    processes_by_pid: HashMap<i32, Process<U>>,
+    ended_processes_for_reuse_by_name: ReusePool<Process<U>>,
+
+    /// The sample data for all removed processes, with their pid.
+    process_sample_datas: Vec<(i32, ProcessSampleData)>,
+
+    allow_reuse: bool,
+
+    /// Whether threads which exit without any samples should get a synthesized
+    /// sample with their last known stack.
+    synthesize_samples_for_short_threads: bool,
+
+    /// Whether PLT stub frames should be merged into the frame of their callee.
+    fold_plt: bool,
+
+    /// See [`ConversionOptions::fold_wine_syscalls`].
+    fold_wine_syscalls: bool,
+
+    /// See [`ConversionOptions::strip_profiler_frames`].
+    strip_profiler_frames: bool,
+
+    /// Jitdump files which were found next to the perf.data file, by pid. They
+    /// are added to a process when it is created.
+    jitdump_paths_by_pid: HashMap<i32, Vec<PathBuf>>,
+
+    /// See [`ConversionOptions::path_map`].
+    path_map: Option<Arc<PathMap>>,
+
+    /// See [`ConversionOptions::perf_map_limits`].
+    perf_map_limits: PerfMapLimits,
+
+    /// Whether the uids of new processes are looked up in /proc, which is
+    /// only possible while recording.
+    look_up_process_owners: bool,
+
+    /// The clock of the sample timestamps, for lining up jitdump timestamps.
+    sample_clock: TimestampClock,
+}
+
+impl<U> Processes<U>
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        allow_reuse: bool,
+        synthesize_samples_for_short_threads: bool,
+        fold_plt: bool,
+        fold_wine_syscalls: bool,
+        strip_profiler_frames: bool,
+        jitdump_paths_by_pid: HashMap<i32, Vec<PathBuf>>,
+        path_map: Option<Arc<PathMap>>,
+        perf_map_limits: PerfMapLimits,
+        look_up_process_owners: bool,
+        sample_clock: TimestampClock,
+    ) -> Self {
+        Self {
+            processes_by_pid: HashMap::new(),
+            ended_processes_for_reuse_by_name: ReusePool::new(MAX_REUSABLE_PROCESSES),
+            process_sample_datas: Vec::new(),
+            allow_reuse,
+            synthesize_samples_for_short_threads,
+            fold_plt,
+            fold_wine_syscalls,
+            strip_profiler_frames,
+            jitdump_paths_by_pid,
+            path_map,
+            perf_map_limits,
+            look_up_process_owners,
+            sample_clock,
+        }
+    }
+
+    /// Called for an EXIT record, before the thread is removed.
+    ///
+    /// If the thread never had any samples but we know a stack for it, add a
+    /// single synthesized sample with that stack at the exit timestamp, so that
+    /// the thread doesn't show up as an empty row. The stack is the one the
+    /// thread blocked with, or else the parent's stack at the fork. The sample
+    /// has weight zero, so it doesn't add to any totals, and is marked with a
+    /// "Synthesized" label frame at the leaf.
+    pub fn synthesize_sample_for_exiting_thread(
+        &mut self,
+        pid: i32,
+        tid: i32,
+        timestamp: u64,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        if !self.synthesize_samples_for_short_threads {
+            return;
+        }
+        let Some(process) = self.processes_by_pid.get_mut(&pid) else { return };
+        let thread = if tid == pid {
+            &process.threads.main_thread
+        } else {
+            let Some(thread) = process.threads.threads_by_tid.get(&tid) else { return };
+            thread
+        };
+        let Some(stack) = thread.off_cpu_stack.or(thread.fork_stack) else { return };
+        if process
+            .unresolved_samples
+            .has_samples_for_thread(thread.profile_thread)
+        {
+            return;
+        }
+        process.unresolved_samples.add_synthesized_sample(
+            thread.profile_thread,
+            timestamp_converter.convert_time(timestamp),
+            timestamp,
+            stack,
+        );
+    }
+
+    pub fn attempt_reuse(
+        &mut self,
+        pid: i32,
+        name: &str,
+        timestamp: u64,
+        profile: &mut Profile,
+    ) -> Option<&mut Process<U>> {
+        if let Entry::Vacant(entry) = self.processes_by_pid.entry(pid) {
+            if let Some(mut process) = self.ended_processes_for_reuse_by_name.take(name) {
+                process.reset_for_reuse(pid, timestamp);
+                profile.clear_process_end_time(process.profile_process);
+                return Some(entry.insert(process));
+            }
+        }
+        None
+    }
+
+    pub fn get_if_alive(&mut self, pid: i32) -> Option<&mut Process<U>> {
+        self.processes_by_pid.get_mut(&pid)
+    }
+
+    pub fn get_by_pid(&mut self, pid: i32, profile: &mut Profile) -> &mut Process<U> {
+        self.processes_by_pid.entry(pid).or_insert_with(|| {
+            let name = format!("<{pid}>");
+            let handle = profile.add_process(
+                &name,
+                pid as u32,
+                Timestamp::from_millis_since_reference(0.0),
+            );
+            let profile_thread = profile.add_thread(
+                handle,
+                pid as u32,
+                Timestamp::from_millis_since_reference(0.0),
+                true,
+            );
+            let main_thread = Thread::new(profile_thread);
+            let jit_function_recycler = if self.allow_reuse {
+                Some(JitFunctionRecycler::default())
+            } else {
+                None
+            };
+            let mut jitdump_manager =
+                JitDumpManager::new_for_process(profile_thread, self.sample_clock);
+            for path in self.jitdump_paths_by_pid.remove(&pid).unwrap_or_default() {
+                jitdump_manager.add_jitdump_path(path, None, self.path_map.clone(), None);
+            }
+            Process {
+                profile_process: handle,
+                unwinder: U::default(),
+                code_ranges: CodeRanges::default(),
+                jitdump_manager,
+                lib_mapping_ops: Default::default(),
+                name: None,
+                pid,
+                threads: ProcessThreads {
+                    pid,
+                    profile_process: handle,
+                    main_thread,
+                    threads_by_tid: HashMap::new(),
+                    ended_threads_for_reuse_by_name: ReusePool::new(MAX_REUSABLE_THREADS),
+                    late_sample_thread: None,
+                    memory_latency_thread: None,
+                    event_track_threads: HashMap::new(),
+                },
+                jit_function_recycler,
+                unresolved_samples: Default::default(),
+                exec_timestamp: None,
+                executable_mapping_count: 0,
+                path_map: self.path_map.clone(),
+                perf_map_limits: self.perf_map_limits,
+                owner_uid: self
+                    .look_up_process_owners
+                    .then(|| process_owner_uid(pid))
+                    .flatten(),
+                anonymous_executable_ranges: Vec::new(),
+                sample_clock: self.sample_clock,
+            }
+        })
+    }
+
+    /// Whether the thread named itself since its fork. False if it doesn't
+    /// exist.
+    pub fn has_own_name(&self, pid: i32, tid: i32) -> bool {
+        let Some(process) = self.processes_by_pid.get(&pid) else { return false };
+        match process.threads.threads_by_tid.get(&tid) {
+            Some(thread) => thread.has_own_name,
+            None => tid == pid && process.threads.main_thread.has_own_name,
+        }
+    }
+
+    /// Doesn't create the process or thread if it doesn't exist.
+    pub fn existing_thread_handle(&self, pid: i32, tid: i32) -> Option<ThreadHandle> {
+        self.processes_by_pid.get(&pid)?.threads.thread_handle(tid)
+    }
+
+    pub fn remove(
+        &mut self,
+        pid: i32,
+        timestamp: u64,
+        profile: &mut Profile,
+        jit_category_manager: &mut JitCategoryManager,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        let Some(mut process) = self.processes_by_pid.remove(&pid) else { return };
+        let time = timestamp_converter.convert_time(timestamp);
+        profile.set_process_end_time(process.profile_process, time);
+
+        let process_sample_data = process.on_remove(
+            self.allow_reuse.then(|| timestamp),
+            profile,
+            jit_category_manager,
+            timestamp_converter,
+        );
+        if !process_sample_data.is_empty() {
+            self.process_sample_datas.push((pid, process_sample_data));
+        }
+
+        if self.allow_reuse {
+            if let Some(name) = process.name.clone() {
+                self.ended_processes_for_reuse_by_name.push(&name, process);
+            }
+        }
+    }
+
+    /// Only fills in the process counts of the metrics.
+    pub fn metrics(&self) -> ConversionMetrics {
+        ConversionMetrics {
+            processes: self
+                .processes_by_pid
+                .values()
+                .map(Process::metrics)
+                .collect(),
+            reusable_process_count: self.ended_processes_for_reuse_by_name.len(),
+            ended_process_count: self.process_sample_datas.len(),
+            ..Default::default()
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn finish(
+        mut self,
+        profile: &mut Profile,
+        unresolved_stacks: &UnresolvedStacks,
+        event_names: &[String],
+        jit_category_manager: &mut JitCategoryManager,
+        timestamp_converter: &TimestampConverter,
+        have_guest_samples: bool,
+        have_wine_modules: bool,
+        have_cow_fault_samples: bool,
+        have_memory_latency_samples: bool,
+        kernel_lib_mappings: &LibMappings<LibMappingInfo>,
+        guest_kernel_lib_mappings: Option<&LibMappings<LibMappingInfo>>,
+        kernel_frame_classifier: Option<&KernelFrameClassifier>,
+        frame_filter: Option<&FrameFilter>,
+        request_attribution: Option<&RequestAttribution>,
+        request_spans: Option<&RequestSpans>,
+        gc_detection: &GcDetection,
+        mut address_space_timeline: Option<&mut AddressSpaceTimeline>,
+        mut jit_code_embedding: Option<&mut JitCodeEmbedding>,
+        marker_addresses: &mut MarkerAddresses,
+    ) {
+        // Gather the ProcessSampleData from any processes which are still alive at the end of profiling.
+        for (pid, mut process) in self.processes_by_pid {
+            // Nothing can be reused after this point, so don't keep any threads around.
+            let process_sample_data =
+                process.on_remove(None, profile, jit_category_manager, timestamp_converter);
+            if !process_sample_data.is_empty() {
+                self.process_sample_datas.push((pid, process_sample_data));
+            }
+        }
+
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let synthesized_category = self.synthesize_samples_for_short_threads.then(|| {
+            profile
+                .add_category("Synthesized", CategoryColor::Gray)
+                .into()
+        });
+        let cow_fault_category = have_cow_fault_samples.then(|| {
+            profile
+                .add_category("Copy-on-write fault", CategoryColor::Red)
+                .into()
+        });
+        let memory_access_categories =
+            have_memory_latency_samples.then(|| MemoryAccessCategories::new(profile));
+        let dynamic_linking = DynamicLinkingFrameConversion {
+            category: profile
+                .add_category("Dynamic linking", CategoryColor::Brown)
+                .into(),
+            loader_category: profile
+                .add_category("Dynamic loader", CategoryColor::Brown)
+                .into(),
+            fold_plt: self.fold_plt,
+        };
+        let guest = have_guest_samples.then(|| GuestFrameConversion {
+            category: profile.add_category("Guest", CategoryColor::Purple).into(),
+            user_label: profile.intern_string("[guest user code]"),
+            kernel_label: profile.intern_string("[guest kernel code]"),
+            kernel_lib_mappings: guest_kernel_lib_mappings,
+        });
+        let wine = have_wine_modules.then(|| WineFrameConversion {
+            category: profile.add_category("Wine", CategoryColor::Magenta).into(),
+            fold_syscalls: self.fold_wine_syscalls,
+            syscall_label: profile.intern_string("Windows syscall"),
+        });
+        let profiler_overhead =
+            kernel_frame_classifier.map(|classifier| ProfilerOverheadFrameConversion {
+                category: profile
+                    .add_category("Profiler overhead (kernel)", CategoryColor::Gray)
+                    .into(),
+                strip: self.strip_profiler_frames,
+                classifier,
+            });
+        let hidden_frame_labels: Vec<_> = frame_filter
+            .map(|filter| filter.rules())
+            .unwrap_or_default()
+            .iter()
+            .map(|rule| profile.intern_string(&format!("(hidden: {rule})")))
+            .collect();
+        let hidden_frames = frame_filter.map(|filter| HiddenFrameConversion {
+            filter,
+            labels: &hidden_frame_labels,
+        });
+        let request_attribution = request_attribution
+            .map(|attribution| RequestAttributionConversion::new(attribution, profile));
+        let mut request_spans = request_spans.map(RequestSpanConversion::new);
+        let mut gc = GcConversion::new(gc_detection);
+        let mut stack_frame_scratch_buf = Vec::new();
+        for (pid, process_sample_data) in self.process_sample_datas {
+            let _span = debug_span!("flush", pid).entered();
+            if let Some(timeline) = address_space_timeline.as_deref_mut() {
+                timeline.add_process(pid, process_sample_data.address_space());
+            }
+            process_sample_data.flush_samples_to_profile(
+                profile,
+                user_category,
+                kernel_category,
+                Some(kernel_lib_mappings),
+                guest,
+                Some(dynamic_linking),
+                wine,
+                profiler_overhead,
+                hidden_frames,
+                synthesized_category,
+                cow_fault_category,
+                memory_access_categories,
+                request_attribution.as_ref(),
+                request_spans.as_mut(),
+                Some(&mut gc),
+                jit_code_embedding.as_deref_mut(),
+                marker_addresses,
+                &mut stack_frame_scratch_buf,
+                unresolved_stacks,
+                event_names,
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Thread {
+    profile_thread: ThreadHandle,
+    context_switch_data: ThreadContextSwitchData,
+    last_sample_timestamp: Option<u64>,
+
+    /// Some() between sched_switch and the next context switch IN
+    ///
+    /// Refers to a stack in the containing Process's UnresolvedSamples stack table.
+    off_cpu_stack: Option<UnresolvedStackHandle>,
+
+    /// Some() between a switch-in and the thread's next sample or switch-out,
+    /// if the off-CPU samples wait for the resumed stack.
+    deferred_off_cpu_group: Option<DeferredOffCpuGroup>,
+
+    /// The stack of the parent thread's last sample before it forked this
+    /// thread, for the synthesized sample of a thread which is never sampled.
+    fork_stack: Option<UnresolvedStackHandle>,
+    name: Option<String>,
+
+    /// The names which this thread had before `name`, oldest first, up to
+    /// [`THREAD_NAME_HISTORY_LEN`] of them.
+    previous_names: VecDeque<String>,
+
+    /// The number of times this thread was renamed, including the renames
+    /// which fell out of `previous_names`.
+    rename_count: usize,
+
+    /// Whether the thread named itself since its fork, rather than having the
+    /// name of its parent thread.
+    has_own_name: bool,
+
+    /// Some() between the removal of this thread and its reuse, if the thread
+    /// was blocked when it was removed.
+    blocked_state_at_removal: Option<BlockedThreadState>,
+
+    /// The stack of this thread's previous sample, for interning its next stack.
+    stack_cache: LastStackCache,
+}
+
+/// The off-CPU state of a thread which was blocked at the time it was removed.
+#[derive(Debug)]
+struct BlockedThreadState {
+    removal_timestamp: u64,
+    context_switch_data: ThreadContextSwitchData,
+    off_cpu_stack: Option<UnresolvedStackHandle>,
+}
+
+impl Thread {
+    pub fn new(profile_thread: ThreadHandle) -> Self {
+        Thread {
+            profile_thread,
+            context_switch_data: Default::default(),
+            last_sample_timestamp: None,
+            off_cpu_stack: None,
+            deferred_off_cpu_group: None,
+            fork_stack: None,
+            name: None,
+            previous_names: VecDeque::new(),
+            rename_count: 0,
+            has_own_name: false,
+            blocked_state_at_removal: None,
+            stack_cache: LastStackCache::default(),
+        }
+    }
+
+    /// Keeps the old name in the bounded name history.
+    pub fn set_name(&mut self, name: &str) {
+        match self.name.replace(name.to_owned()) {
+            Some(old_name) if old_name != name => {
+                if self.previous_names.len() == THREAD_NAME_HISTORY_LEN {
+                    self.previous_names.pop_front();
+                }
+                self.previous_names.push_back(old_name);
+                self.rename_count += 1;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn on_remove(&mut self, timestamp: u64) {
+        if self.rename_count > 1 {
+            debug!(
+                "Thread {} ended after {} renames, with the previous names {:?}",
+                self.name.as_deref().unwrap_or_default(),
+                self.rename_count,
+                self.previous_names
+            );
+        }
+        let context_switch_data = std::mem::take(&mut self.context_switch_data);
+        let off_cpu_stack = self.off_cpu_stack.take();
+        self.last_sample_timestamp = None;
+        self.blocked_state_at_removal =
+            context_switch_data
+                .is_off_cpu()
+                .then(|| BlockedThreadState {
+                    removal_timestamp: timestamp,
+                    context_switch_data,
+                    off_cpu_stack,
+                });
+    }
+
+    /// Called when this ended thread is picked up for a new thread of the same name,
+    /// and for the main thread when its ended process is reused.
+    ///
+    /// If the old thread was blocked when it ended and the new thread starts shortly
+    /// afterwards, we treat the two as one continuously blocked thread: the new thread
+    /// takes over the old thread's off-CPU state, so that the next switch-in produces
+    /// one uninterrupted off-CPU sample group rather than two groups with a seam at the
+    /// reuse point.
+    pub fn reset_for_reuse(&mut self, _tid: i32, timestamp: u64) {
+        self.has_own_name = false;
+        let Some(blocked_state) = self.blocked_state_at_removal.take() else { return };
+        if timestamp.saturating_sub(blocked_state.removal_timestamp)
+            <= THREAD_REUSE_CARRY_OVER_WINDOW_NS
+        {
+            self.context_switch_data = blocked_state.context_switch_data;
+            self.off_cpu_stack = blocked_state.off_cpu_stack;
+        }
+    }
+}
+
+struct Process<U>
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+{
+    pub profile_process: ProcessHandle,
+    pub unwinder: U,
+    /// The executable mappings, for checking the frames found by DWARF
+    /// unwinding.
+    pub code_ranges: CodeRanges,
+    pub jitdump_manager: JitDumpManager,
+    pub lib_mapping_ops: LibMappingOpQueue,
+    pub name: Option<String>,
+    pub threads: ProcessThreads,
+    pid: i32,
+    pub unresolved_samples: UnresolvedSamples,
+    jit_function_recycler: Option<JitFunctionRecycler>,
+
+    /// The timestamp of the exec which started the current image of this
+    /// process, until we've checked whether its mmap records are missing.
+    exec_timestamp: Option<u64>,
+
+    /// The number of executable mappings added since the last exec.
+    executable_mapping_count: usize,
+
+    /// For finding the process's perf map file.
+    path_map: Option<Arc<PathMap>>,
+
+    /// See [`ConversionOptions::perf_map_limits`].
+    perf_map_limits: PerfMapLimits,
+
+    /// The uid of the process, if it was looked up while recording. Its perf
+    /// map file must be owned by this uid.
+    owner_uid: Option<u32>,
+
+    /// The anonymous executable mappings, e.g. of JIT code, which the
+    /// entries of the perf map file must lie within.
+    anonymous_executable_ranges: Vec<Range<u64>>,
+
+    /// See [`EventInterpretation::clock`].
+    sample_clock: TimestampClock,
+}
+
+impl<U> Process<U>
+where
+    U: Unwinder<Module = Module<ModuleData>> + Default,
+{
+    /// Reads the process's pending jitdump records from time to time, see
+    /// [`JitDumpManager::poll`]. Processes without jitdump files only pay for
+    /// a boolean check.
+    pub fn check_jitdump(
+        &mut self,
+        timestamp: Option<u64>,
+        jit_category_manager: &mut JitCategoryManager,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+        timings: Option<&ConversionTimings>,
+    ) {
+        if !self.jitdump_manager.has_jitdumps() {
+            return;
+        }
+        let _timing = TimingGuard::start(timings, TimingBucket::Jitdump);
+        self.jitdump_manager.poll(
+            timestamp,
+            jit_category_manager,
+            profile,
+            self.jit_function_recycler.as_mut(),
+            timestamp_converter,
+        );
+    }
+
+    /// Reads all jitdump records which are available so far, e.g. because a
+    /// function name is needed now.
+    pub fn read_all_jitdump_records(
+        &mut self,
+        jit_category_manager: &mut JitCategoryManager,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+        timings: Option<&ConversionTimings>,
+    ) {
+        let _timing = TimingGuard::start(timings, TimingBucket::Jitdump);
+        self.jitdump_manager.process_pending_records(
+            jit_category_manager,
+            profile,
+            self.jit_function_recycler.as_mut(),
+            timestamp_converter,
+        );
+    }
+
+    pub fn reset_for_reuse(&mut self, new_pid: i32, timestamp: u64) {
+        self.pid = new_pid;
+        self.threads.pid = new_pid;
+        self.threads.main_thread.reset_for_reuse(new_pid, timestamp);
+        self.owner_uid = None;
+        self.anonymous_executable_ranges.clear();
+    }
+
+    pub fn metrics(&self) -> ProcessMetrics {
+        ProcessMetrics {
+            pid: self.pid,
+            name: self.name.clone(),
+            // The main thread isn't in `threads_by_tid`.
+            thread_count: self.threads.threads_by_tid.len() + 1,
+            sample_count: self.unresolved_samples.len(),
+            mapping_op_count: self.lib_mapping_ops.len(),
+            jit: self.jitdump_manager.metrics(),
+        }
+    }
+
+    /// `thread_reuse_timestamp` is Some(removal timestamp) if the threads of this
+    /// process should be kept around for reuse.
+    pub fn on_remove(
+        &mut self,
+        thread_reuse_timestamp: Option<u64>,
+        profile: &mut Profile,
+        jit_category_manager: &mut JitCategoryManager,
+        timestamp_converter: &TimestampConverter,
+    ) -> ProcessSampleData {
+        self.unwinder = U::default();
+        self.code_ranges.clear();
+
+        if let Some(timestamp) = thread_reuse_timestamp {
+            self.threads.prepare_for_reuse(timestamp);
+        }
+
+        let perf_map_mappings = if !self.unresolved_samples.is_empty() {
+            try_load_perf_map(
+                self.pid as u32,
+                self.path_map.as_deref(),
+                self.owner_uid,
+                &self.perf_map_limits,
+                &self.anonymous_executable_ranges,
+                profile,
+                jit_category_manager,
+                self.jit_function_recycler.as_mut(),
+            )
+        } else {
+            None
+        };
+
+        if let Some(recycler) = self.jit_function_recycler.as_mut() {
+            recycler.finish_round();
+        }
+
+        let jitdump_manager = std::mem::replace(
+            &mut self.jitdump_manager,
+            JitDumpManager::new_for_process(
+                self.threads.main_thread.profile_thread,
+                self.sample_clock,
+            ),
+        );
+        let jitdump_ops = jitdump_manager.finish(
+            jit_category_manager,
+            profile,
+            self.jit_function_recycler.as_mut(),
+            timestamp_converter,
+        );
+
+        ProcessSampleData::new(
+            std::mem::take(&mut self.unresolved_samples),
+            std::mem::take(&mut self.lib_mapping_ops),
+            jitdump_ops,
+            perf_map_mappings,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_regular_lib_mapping(
+        &mut self,
+        timestamp: u64,
+        start_address: u64,
+        end_address: u64,
+        relative_address_at_start: u32,
+        lib_handle: LibraryHandle,
+        dynamic_linking_ranges: Option<Arc<DynamicLinkingRanges>>,
+        wine_module: Option<Arc<WineModuleInfo>>,
+        hidden_frames: Option<Arc<HiddenFrameRanges>>,
+    ) {
+        self.lib_mapping_ops.push(
+            timestamp,
+            LibMappingOp::Add(LibMappingAdd {
+                start_avma: start_address,
+                end_avma: end_address,
+                relative_address_at_start,
+                info: LibMappingInfo {
+                    wine_module,
+                    hidden_frames,
+                    ..LibMappingInfo::new_lib_with_dynamic_linking_ranges(
+                        lib_handle,
+                        dynamic_linking_ranges,
+                    )
+                },
+            }),
+        );
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_lib_mapping_for_injected_jit_lib(
+        &mut self,
+        timestamp: u64,
+        profile_timestamp: Timestamp,
+        symbol_name: &str,
+        start_address: u64,
+        end_address: u64,
+        mut relative_address_at_start: u32,
+        mut lib_handle: LibraryHandle,
+        jit_category_manager: &mut JitCategoryManager,
+        profile: &mut Profile,
+    ) {
+        let main_thread = self.threads.main_thread.profile_thread;
+        let timing = MarkerTiming::Instant(profile_timestamp);
+        profile.add_marker(
+            main_thread,
+            "JitFunctionAdd",
+            JitFunctionAddMarker(symbol_name.to_owned()),
+            timing,
+        );
+
+        if let Some(recycler) = self.jit_function_recycler.as_mut() {
+            (lib_handle, relative_address_at_start) = recycler.recycle(
+                start_address,
+                end_address,
+                relative_address_at_start,
+                symbol_name,
+                lib_handle,
+            );
+        }
+
+        let (category, js_frame) = jit_category_manager.classify_jit_symbol(symbol_name, profile);
+        self.lib_mapping_ops.push(
+            timestamp,
+            LibMappingOp::Add(LibMappingAdd {
+                start_avma: start_address,
+                end_avma: end_address,
+                relative_address_at_start,
+                info: LibMappingInfo::new_jit_function(lib_handle, category, js_frame),
+            }),
+        );
+    }
+}
+
+struct ProcessThreads {
+    pid: i32,
+    profile_process: ProcessHandle,
+    main_thread: Thread,
+    threads_by_tid: HashMap<i32, Thread>,
+    ended_threads_for_reuse_by_name: ReusePool<Thread>,
+    /// For samples which don't match the lifetime of any thread with their
+    /// tid. Created on first use.
+    late_sample_thread: Option<ThreadHandle>,
+    /// For the samples of [`Converter::handle_memory_latency_sample`].
+    /// Created on first use.
+    memory_latency_thread: Option<ThreadHandle>,
+    /// For the samples of [`Converter::handle_event_track_sample`], by
+    /// attribute index. Created on first use.
+    event_track_threads: HashMap<usize, ThreadHandle>,
+}
+
+impl ProcessThreads {
+    pub fn prepare_for_reuse(&mut self, timestamp: u64) {
+        self.main_thread.on_remove(timestamp);
+        for (_tid, mut thread) in self.threads_by_tid.drain() {
+            thread.on_remove(timestamp);
+
+            if let Some(name) = thread.name.clone() {
+                self.ended_threads_for_reuse_by_name.push(&name, thread);
+            }
+        }
+    }
+
+    pub fn attempt_thread_reuse(
+        &mut self,
+        tid: i32,
+        name: &str,
+        timestamp: u64,
+        profile: &mut Profile,
+    ) -> Option<&mut Thread> {
+        if let Entry::Vacant(entry) = self.threads_by_tid.entry(tid) {
+            if let Some(mut thread) = self.ended_threads_for_reuse_by_name.take(name) {
+                thread.reset_for_reuse(tid, timestamp);
+                profile.clear_thread_end_time(thread.profile_thread);
+                return Some(entry.insert(thread));
+            }
+        }
+        None
+    }
+
+    pub fn get_main_thread(&mut self) -> &mut Thread {
+        &mut self.main_thread
+    }
+
+    pub fn get_late_sample_thread(&mut self, profile: &mut Profile) -> ThreadHandle {
+        *self.late_sample_thread.get_or_insert_with(|| {
+            let thread = profile.add_thread(
+                self.profile_process,
+                self.pid as u32,
+                Timestamp::from_millis_since_reference(0.0),
+                false,
+            );
+            profile.set_thread_name(thread, "Late samples");
+            thread
+        })
+    }
+
+    pub fn get_memory_latency_thread(&mut self, profile: &mut Profile) -> ThreadHandle {
+        *self.memory_latency_thread.get_or_insert_with(|| {
+            let thread = profile.add_thread(
+                self.profile_process,
+                self.pid as u32,
+                Timestamp::from_millis_since_reference(0.0),
+                false,
+            );
+            profile.set_thread_name(thread, "Memory latency");
+            thread
+        })
+    }
+
+    pub fn get_event_track_thread(
+        &mut self,
+        attr_index: usize,
+        event_name: &str,
+        profile: &mut Profile,
+    ) -> ThreadHandle {
+        *self
+            .event_track_threads
+            .entry(attr_index)
+            .or_insert_with(|| {
+                let thread = profile.add_thread(
+                    self.profile_process,
+                    self.pid as u32,
+                    Timestamp::from_millis_since_reference(0.0),
+                    false,
+                );
+                profile.set_thread_name(thread, event_name);
+                thread
+            })
+    }
+
+    /// Doesn't create the thread if it doesn't exist.
+    pub fn existing_thread_mut(&mut self, tid: i32) -> Option<&mut Thread> {
+        if tid == self.pid {
+            return Some(&mut self.main_thread);
+        }
+        self.threads_by_tid.get_mut(&tid)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Thread> {
+        std::iter::once(&mut self.main_thread).chain(self.threads_by_tid.values_mut())
+    }
+
+    /// Doesn't create the thread if it doesn't exist.
+    pub fn thread_handle(&self, tid: i32) -> Option<ThreadHandle> {
+        if tid == self.pid {
+            return Some(self.main_thread.profile_thread);
+        }
+        self.threads_by_tid.get(&tid).map(|t| t.profile_thread)
+    }
+
+    pub fn get_thread_by_tid(&mut self, tid: i32, profile: &mut Profile) -> &mut Thread {
+        if tid == self.pid {
+            return &mut self.main_thread;
+        }
+        self.threads_by_tid.entry(tid).or_insert_with(|| {
+            let profile_thread = profile.add_thread(
+                self.profile_process,
+                tid as u32,
+                Timestamp::from_millis_since_reference(0.0),
+                false,
+            );
+            Thread::new(profile_thread)
+        })
+    }
+
+    pub fn remove_non_main_thread(
+        &mut self,
+        tid: i32,
+        timestamp: u64,
+        timestamp_converter: &TimestampConverter,
+        allow_reuse: bool,
+        profile: &mut Profile,
+    ) {
+        let Some(mut thread) = self.threads_by_tid.remove(&tid) else { return };
+        let time = timestamp_converter.convert_time(timestamp);
+        profile.set_thread_end_time(thread.profile_thread, time);
+
+        thread.on_remove(timestamp);
+
+        if allow_reuse {
+            if let Some(name) = thread.name.clone() {
+                self.ended_threads_for_reuse_by_name.push(&name, thread);
+            }
+        }
+    }
+}
+
+// A file range in an object file, such as a segment or a section,
+// for which we know the corresponding Stated Virtual Memory Address (SVMA).
+#[derive(Clone)]
+struct SvmaFileRange {
+    svma: u64,
+    file_offset: u64,
+    size: u64,
+}
+
+impl SvmaFileRange {
+    pub fn from_segment<'data, S: ObjectSegment<'data>>(segment: S) -> Self {
+        let svma = segment.address();
+        let (file_offset, size) = segment.file_range();
+        SvmaFileRange {
+            svma,
+            file_offset,
+            size,
+        }
+    }
+
+    pub fn from_section<'data, S: ObjectSection<'data>>(section: S) -> Option<Self> {
+        let svma = section.address();
+        let (file_offset, size) = section.file_range()?;
+        Some(SvmaFileRange {
+            svma,
+            file_offset,
+            size,
+        })
+    }
+
+    pub fn encompasses_file_range(&self, other_file_offset: u64, other_file_size: u64) -> bool {
+        let self_file_range_end = self.file_offset + self.size;
+        let other_file_range_end = other_file_offset + other_file_size;
+        self.file_offset <= other_file_offset && other_file_range_end <= self_file_range_end
+    }
+
+    pub fn is_encompassed_by_file_range(
+        &self,
+        other_file_offset: u64,
+        other_file_size: u64,
+    ) -> bool {
+        let self_file_range_end = self.file_offset + self.size;
+        let other_file_range_end = other_file_offset + other_file_size;
+        other_file_offset <= self.file_offset && self_file_range_end <= other_file_range_end
+    }
+}
+
+impl Debug for SvmaFileRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SvmaFileRange")
+            .field("svma", &format!("{:#x}", &self.svma))
+            .field("file_offset", &format!("{:#x}", &self.file_offset))
+            .field("size", &format!("{:#x}", &self.size))
+            .finish()
+    }
+}
+
+/// Compute the bias from the stated virtual memory address (SVMA), the VMA defined in the file's
+/// section table, to the actual virtual memory address (AVMA), the VMA the file is actually mapped
+/// at.
+///
+/// We have the section + segment information of the mapped object file, and we know the file offset
+/// and size of the mapping, as well as the AVMA at the mapping start.
+///
+/// An image is mapped into memory using ELF load commands ("segments"). Usually there are multiple
+/// ELF load commands, resulting in multiple mappings. Some of these mappings will be read-only,
+/// and some will be executable.
+///
+/// Commonly, the executable mapping is the second of four mappings.
+///
+/// If we know about all mappings of an image, then the base AVMA is the start AVMA of the first mapping.
+/// But sometimes we only have one mapping of an image, for example only the second mapping. This
+/// happens if the `perf.data` file only contains mmap information of the executable mappings. In that
+/// case, we have to look at the "page offset" of that mapping and find out which part of the file
+/// was mapped in that range.
+///
+/// In that case it's tempting to say "The AVMA is the address at which the file would start, if
+/// the entire file was mapped contiguously into memory around our mapping." While this works in
+/// many cases, it doesn't work if there are "SVMA gaps" between the segments which have been elided
+/// in the file, i.e. it doesn't work for files where the file offset <-> SVMA translation
+/// is different for each segment.
+///
+/// Easy case A:
+/// ```plain
+/// File offset:  0x0 |----------------| |-------------|
+/// SVMA:         0x0 |----------------| |-------------|
+/// AVMA:   0x1750000 |----------------| |-------------|
+/// ```
+///
+/// Easy case B:
+/// ```plain
+/// File offset:  0x0 |----------------| |-------------|
+/// SVMA:     0x40000 |----------------| |-------------|
+/// AVMA:   0x1750000 |----------------| |-------------|
+/// ```
+///
+/// Hard case:
+/// ```plain
+/// File offset:  0x0 |----------------| |-------------|
+/// SVMA:         0x0 |----------------|         |-------------|
+/// AVMA:   0x1750000 |----------------|         |-------------|
+/// ```
+///
+/// One example of the hard case has been observed in `libxul.so`: The `.text` section
+/// was in the second segment. In the first segment, SVMAs were equal to their
+/// corresponding file offsets. In the second segment, SMAs were 0x1000 bytes higher
+/// than their corresponding file offsets. In other words, there was a 0x1000-wide gap
+/// between the segments in virtual address space, but this gap was omitted in the file.
+/// The SVMA gap exists in the AVMAs too - the "bias" between SVMAs and AVMAs is the same
+/// for all segments of an image. So we have to find the SVMA for the mapping by finding
+/// a segment or section which overlaps the mapping in file offset space, and then use
+/// the matching segment's / section's SVMA to find the SVMA-to-AVMA "bias" for the
+/// mapped bytes.
+///
+/// Another interesting edge case we observed was a case where a mapping was seemingly
+/// not initiated by an ELF LOAD command: Part of the d8 binary (the V8 shell) was mapped
+/// into memory with a mapping that covered only a small part of the `.text` section.
+/// Usually, you'd expect a section to be mapped in its entirety, but this was not the
+/// case here. So the segment finding code below checks for containment both ways: Whether
+/// the mapping is contained in the segment, or whether the segment is contained in the
+/// mapping. We also tried a solution where we just check for overlap between the segment
+/// and the mapping, but this sometimes got the wrong segment, because the mapping is
+/// larger than the segment due to alignment, and can extend into other segments.
+fn compute_vma_bias<'data, 'file, O>(
+    file: &'file O,
+    mapping_start_file_offset: u64,
+    mapping_start_avma: u64,
+    mapping_size: u64,
+) -> Option<u64>
+where
+    'data: 'file,
+    O: Object<'data, 'file>,
+{
+    let mut contributions: Vec<SvmaFileRange> =
+        file.segments().map(SvmaFileRange::from_segment).collect();
+
+    if contributions.is_empty() {
+        // If no segment is found, fall back to using section information.
+        // This fallback only exists for the synthetic .so files created by `perf inject --jit`
+        // - those don't have LOAD commands.
+        contributions = file
+            .sections()
+            .filter(|s| s.kind() == SectionKind::Text)
+            .filter_map(SvmaFileRange::from_section)
+            .collect();
+    }
+
+    compute_vma_bias_impl(
+        &contributions,
+        mapping_start_file_offset,
+        mapping_start_avma,
+        mapping_size,
+    )
+}
+
+fn compute_vma_bias_impl(
+    contributions: &[SvmaFileRange],
+    mapping_file_offset: u64,
+    mapping_avma: u64,
+    mapping_size: u64,
+) -> Option<u64> {
+    // Find a contribution which either fully contains the mapping, or which is fully contained by the mapping.
+    // Linux perf simply always uses the .text section as the reference contribution.
+    let ref_contribution = if let Some(contribution) = contributions.iter().find(|contribution| {
+        contribution.encompasses_file_range(mapping_file_offset, mapping_size)
+            || contribution.is_encompassed_by_file_range(mapping_file_offset, mapping_size)
+    }) {
+        contribution
+    } else {
+        println!(
+            "Could not find segment or section overlapping the file offset range 0x{:x}..0x{:x}",
+            mapping_file_offset,
+            mapping_file_offset + mapping_size,
+        );
+        return None;
+    };
+
+    // Compute the AVMA at which the reference contribution is located in process memory.
+    let ref_avma = if ref_contribution.file_offset > mapping_file_offset {
+        mapping_avma + (ref_contribution.file_offset - mapping_file_offset)
+    } else {
+        mapping_avma - (mapping_file_offset - ref_contribution.file_offset)
+    };
+
+    // We have everything we need now.
+    let bias = ref_avma.wrapping_sub(ref_contribution.svma);
+    Some(bias)
+}
+
+#[test]
+fn test_compute_base_avma_impl() {
+    // From a local build of the Spidermonkey shell ("js")
+    let js_segments = &[
+        SvmaFileRange {
+            svma: 0x0,
+            file_offset: 0x0,
+            size: 0x14bd0bc,
+        },
+        SvmaFileRange {
+            svma: 0x14be0c0,
+            file_offset: 0x14bd0c0,
+            size: 0xf5bf60,
+        },
+        SvmaFileRange {
+            svma: 0x241b020,
+            file_offset: 0x2419020,
+            size: 0x08e920,
+        },
+        SvmaFileRange {
+            svma: 0x24aa940,
+            file_offset: 0x24a7940,
+            size: 0x002d48,
+        },
+    ];
+    assert_eq!(
+        compute_vma_bias_impl(js_segments, 0x14bd0c0, 0x100014be0c0, 0xf5bf60),
+        Some(0x10000000000)
+    );
+    assert_eq!(
+        compute_vma_bias_impl(js_segments, 0x14bd000, 0x55d605384000, 0xf5d000),
+        Some(0x55d603ec6000)
+    );
+
+    // From a local build of the V8 shell ("d8")
+    let d8_segments = &[
+        SvmaFileRange {
+            svma: 0x0,
+            file_offset: 0x0,
+            size: 0x3c8ed8,
+        },
+        SvmaFileRange {
+            svma: 0x03ca000,
+            file_offset: 0x3c9000,
+            size: 0xfec770,
+        },
+        SvmaFileRange {
+            svma: 0x13b7770,
+            file_offset: 0x13b5770,
+            size: 0x0528d0,
+        },
+        SvmaFileRange {
+            svma: 0x140c000,
+            file_offset: 0x1409000,
+            size: 0x0118f0,
+        },
+    ];
+    assert_eq!(
+        compute_vma_bias_impl(d8_segments, 0x1056000, 0x55d15fe80000, 0x180000),
+        Some(0x55d15ee29000)
+    );
+}
+
+fn get_pe_mapping_size(path_slice: &[u8], open_cache: &FileOpenCache) -> Option<u64> {
+    fn inner<T: ImageNtHeaders>(data: &[u8]) -> Option<u64> {
+        let file = PeFile::<T>::parse(data).ok()?;
+        let size = file.nt_headers().optional_header().size_of_image();
+        Some(size as u64)
+    }
+
+    let path = Path::new(std::str::from_utf8(path_slice).ok()?);
+    if is_device_path(path) {
+        return None;
+    }
+    let file = open_cache.open(path).ok()?;
+    let mmap = unsafe { Mmap::map(&file).ok()? };
+
+    let Ok(kind) = FileKind::parse(&mmap[..]) else {
+        open_cache.record_parse_failure(path);
+        return None;
+    };
+    match kind {
+        FileKind::Pe32 => inner::<ImageNtHeaders32>(&mmap),
+        FileKind::Pe64 => inner::<ImageNtHeaders64>(&mmap),
+        _ => None,
+    }
+}
+
+fn build_id_for_file(
+    path: &Path,
+    extra_binary_artifact_dir: Option<&Path>,
+    path_map: Option<&PathMap>,
+    open_cache: &FileOpenCache,
+) -> Option<Vec<u8>> {
+    let (file, opened_path) = open_cache
+        .open_with_fallback(path, extra_binary_artifact_dir, path_map)
+        .ok()?;
+    let mmap = unsafe { memmap2::MmapOptions::new().map(&file) }.ok()?;
+    let Ok(obj) = object::File::parse(&mmap[..]) else {
+        open_cache.record_parse_failure(&opened_path);
+        return None;
+    };
+    match obj.build_id() {
+        Ok(Some(build_id)) => Some(build_id.to_owned()),
+        _ => None,
+    }
+}
+
+/// Correct unusable .so files from certain versions of perf which create ELF program
+/// headers but fail to adjust addresses by the program header size.
+///
+/// For these bad files, we create a new, fixed, file, so that the mapping correctly
+/// refers to the location of its .text section.
+///
+/// Background:
+///
+/// If you use `perf record` on applications which output JITDUMP information, such as
+/// the V8 shell, you usually run `perf inject --jit` on your `perf.data` file afterwards.
+/// This command interprets the JITDUMP file, and creates files of the form `jitted-12345-12.so`
+/// which contain the JIT code from the JITDUMP file. There is one file per function.
+/// These files are ELF files which look just enough like regular ELF files so that the
+/// regular perf functionality works with them - unwinding, symbols, line numbers, assembly.
+///
+/// Before September 2022, these files did not contain any ELF program headers ("segments"),
+/// they only contained sections. The files have 0x40 bytes of ELF header, followed by a
+/// .text section at offset and address 0x40, followed by a few other sections, followed by
+/// the section table.
+///
+/// This was changed by commit <https://github.com/torvalds/linux/commit/babd04386b1df8c364cdaa39ac0e54349502e1e5>,
+/// "perf jit: Include program header in ELF files", in September 2022.
+/// There was a bug in this commit, which was fixed by commit <https://github.com/torvalds/linux/commit/89b15d00527b7>,
+/// "perf inject: Fix GEN_ELF_TEXT_OFFSET for jit", in October 2022.
+///
+/// Unfortunately, the first commit made it into a number of perf releases:
+/// 4.19.215+, 5.4.215+, 5.10.145+, 5.15.71+, 5.19.12+, probably 6.0.16, and 6.1.2
+///
+/// The bug in the first commit means that, if you load the jit-ified perf.data file using
+/// `samply load` and use the `jitted-12345-12.so` files as-is, the opened profile will
+/// contain no useful information about JIT functions.
+///
+/// The broken files have a PT_LOAD command with file offset 0 and address 0, and a
+/// .text section with file offset 0x80 and address 0x40. Furthermore, the synthesized
+/// mmap record points at offset 0x40. The function name symbol is at address 0x40.
+///
+/// This means:
+///  - The mapping does not encompass the entire .text section.
+///  - We cannot calculate the image's base address in process memory because the .text
+///    section has a different file-offset-to-address translation (-0x40) than the
+///    PT_LOAD command (0x0). Neither of the translation amounts would give us a
+///    base address that works correctly with the rest of the system: Following the
+///    section might give us correct symbols but bad assembly, and the other way round.
+///
+/// We load these .so files twice: First, during profile conversion, and then later again
+/// during symbolication and also when assembly code is looked up. We need to have a
+/// consistent file on the file system which works with all these consumers.
+///
+/// So this function creates a fixed file and adjusts all fall paths to point to the fixed
+/// file. The fixed file has its program header removed, so that the original symbol address
+/// and mmap record are correct for the file offset and address 0x40.
+/// We could also choose to keep the program header, but then we would need to adjust a
+/// lot more: the mmap record, the symbol addresses, and the debug info.
+fn correct_bad_perf_jit_so_file(
+    file: &std::fs::File,
+    path: &str,
+) -> Option<(std::fs::File, String)> {
+    if !path.contains("/jitted-") || !path.ends_with(".so") {
+        return None;
+    }
+
+    let mmap = unsafe { memmap2::MmapOptions::new().map(file) }.ok()?;
+    let obj = object::read::File::parse(&mmap[..]).ok()?;
+    if obj.format() != object::BinaryFormat::Elf {
+        return None;
+    }
 
-            fn svma_range<'a>(section: &impl ObjectSection<'a>) -> Range<u64> {
-                section.address()..section.address() + section.size()
-            }
+    // The bad files have exactly one segment, with offset 0x0 and address 0x0.
+    let segment = obj.segments().next()?;
+    if segment.address() != 0 || segment.file_range().0 != 0 {
+        return None;
+    }
 
-            let module = Module::new(
-                path.to_string(),
-                avma_range.clone(),
-                base_avma,
-                ModuleSvmaInfo {
-                    base_svma,
-                    text: text.as_ref().map(svma_range),
-                    text_env: text_env.as_ref().map(svma_range),
-                    stubs: None,
-                    stub_helper: None,
-                    eh_frame: eh_frame.as_ref().map(svma_range),
-                    eh_frame_hdr: eh_frame_hdr.as_ref().map(svma_range),
-                    got: got.as_ref().map(svma_range),
-                },
-                unwind_data,
-                text_data,
-            );
-            process.unwinder.add_module(module);
+    // The bad files have a .text section with offset 0x80 and address 0x40 (on x86_64).
+    let text_section = obj.section_by_name(".text")?;
+    if text_section.file_range()?.0 == text_section.address() {
+        return None;
+    }
 
-            let debug_id = if let Some(debug_id) = debug_id_for_object(&file) {
-                debug_id
-            } else {
-                return;
-            };
-            let code_id = file
-                .build_id()
-                .ok()
-                .flatten()
-                .map(|build_id| CodeId::from_binary(build_id).to_string());
-            let lib_handle = self.profile.add_lib(LibraryInfo {
-                debug_id,
-                code_id,
-                path: path.clone(),
-                debug_path: path,
-                debug_name: name.clone(),
-                name: name.clone(),
-                arch: None,
-                symbol_table: None,
-            });
+    // All right, we have one of the broken files!
 
-            let relative_address_at_start = (avma_range.start - base_avma) as u32;
+    // Let's make it right.
+    let fixed_data = if obj.is_64() {
+        object_rewriter::drop_phdr::<object::elf::FileHeader64<object::Endianness>>(&mmap[..])
+            .ok()?
+    } else {
+        object_rewriter::drop_phdr::<object::elf::FileHeader32<object::Endianness>>(&mmap[..])
+            .ok()?
+    };
+    let mut fixed_path = path.strip_suffix(".so").unwrap().to_string();
+    fixed_path.push_str("-fixed.so");
 
-            if name.starts_with("jitted-") && name.ends_with(".so") {
-                let symbol_name = jit_function_name(&file);
-                process.add_lib_mapping_for_injected_jit_lib(
-                    timestamp,
-                    self.timestamp_converter.convert_time(timestamp),
-                    symbol_name,
-                    mapping_start_avma,
-                    mapping_end_avma,
-                    relative_address_at_start,
-                    lib_handle,
-                    &mut self.jit_category_manager,
-                    &mut self.profile,
-                );
-            } else {
-                process.add_regular_lib_mapping(
-                    timestamp,
-                    mapping_start_avma,
-                    mapping_end_avma,
-                    relative_address_at_start,
-                    lib_handle,
-                );
-            }
-        } else {
-            // Without access to the binary file, make some guesses. We can't really
-            // know what the right base address is because we don't have the section
-            // information which lets us map between addresses and file offsets, but
-            // often svmas and file offsets are the same, so this is a reasonable guess.
-            let base_avma = mapping_start_avma - mapping_start_file_offset;
-            let relative_address_at_start = (mapping_start_avma - base_avma) as u32;
+    std::fs::write(&fixed_path, fixed_data).ok()?;
 
-            // If we have a build ID, convert it to a debug_id and a code_id.
-            let debug_id = build_id
-                .map(|id| DebugId::from_identifier(id, true)) // TODO: endian
-                .unwrap_or_default();
-            let code_id = build_id.map(|build_id| CodeId::from_binary(build_id).to_string());
+    // Open the fixed file for reading, and return it.
+    let fixed_file = std::fs::File::open(&fixed_path).ok()?;
 
-            let lib_handle = self.profile.add_lib(LibraryInfo {
-                debug_id,
-                code_id,
-                path: path.clone(),
-                debug_path: path,
-                debug_name: name.clone(),
-                name,
-                arch: None,
-                symbol_table: None,
-            });
-            process.add_regular_lib_mapping(
-                timestamp,
-                mapping_start_avma,
-                mapping_end_avma,
-                relative_address_at_start,
-                lib_handle,
-            );
-        }
-    }
+    Some((fixed_file, fixed_path))
 }
 
-fn jit_function_name<'data>(obj: &object::File<'data>) -> Option<&'data str> {
-    let mut text_symbols = obj.symbols().filter(|s| s.kind() == SymbolKind::Text);
-    let symbol = text_symbols.next()?;
-    symbol.name().ok()
+fn get_path_if_jitdump(path: &[u8]) -> Option<&Path> {
+    let path = Path::new(std::str::from_utf8(path).ok()?);
+    let filename = path.file_name()?.to_str()?;
+    if filename.starts_with("jit-") && filename.ends_with(".dump") {
+        Some(path)
+    } else {
+        None
+    }
 }
 
-// #[test]
-// fn test_my_jit() {
-//     let data = std::fs::read("/Users/mstange/Downloads/jitted-123175-0-fixed.so").unwrap();
-//     let file = object::File::parse(&data[..]).unwrap();
-//     dbg!(jit_function_name(&file));
-// }
+#[cfg(test)]
+mod test {
+    use framehop::aarch64::{CacheAarch64, UnwinderAarch64};
+    use framehop::x86_64::{CacheX86_64, UnwinderX86_64};
+    use fxprof_processed_profile::ClampedTimestampCounts;
+    use linux_perf_event_reader::constants::{PERF_CONTEXT_KERNEL, PERF_CONTEXT_USER};
+    use linux_perf_event_reader::{CpuMode, RawData, TaskWasPreempted};
 
-fn process_off_cpu_sample_group(
-    off_cpu_sample: OffCpuSampleGroup,
-    thread_handle: ThreadHandle,
-    cpu_delta_ns: u64,
-    timestamp_converter: &TimestampConverter,
-    off_cpu_weight_per_sample: i32,
-    off_cpu_stack: UnresolvedStackHandle,
-    samples: &mut UnresolvedSamples,
-) {
-    let OffCpuSampleGroup {
-        begin_timestamp,
-        end_timestamp,
-        sample_count,
-    } = off_cpu_sample;
+    use std::cell::Cell;
+    use std::rc::Rc;
 
-    // Add a sample at the beginning of the paused range.
-    // This "first sample" will carry any leftover accumulated running time ("cpu delta").
-    let cpu_delta = CpuDelta::from_nanos(cpu_delta_ns);
-    let weight = off_cpu_weight_per_sample;
-    let stack = off_cpu_stack;
-    let profile_timestamp = timestamp_converter.convert_time(begin_timestamp);
-    samples.add_sample(
-        thread_handle,
-        profile_timestamp,
-        begin_timestamp,
-        stack,
-        cpu_delta,
-        weight,
-    );
+    use super::*;
+    use crate::shared::lib_mappings::MappingKind;
+    use crate::shared::marker_addresses::MarkerValue;
+    use crate::shared::unresolved_samples::{SampleData, SampleOrMarker};
 
-    if sample_count > 1 {
-        // Emit a "rest sample" with a CPU delta of zero covering the rest of the paused range.
-        let cpu_delta = CpuDelta::from_nanos(0);
-        let weight = i32::try_from(sample_count - 1).unwrap_or(0) * off_cpu_weight_per_sample;
-        let profile_timestamp = timestamp_converter.convert_time(end_timestamp);
-        samples.add_sample(
-            thread_handle,
-            profile_timestamp,
-            begin_timestamp,
-            stack,
-            cpu_delta,
-            weight,
-        );
-    }
-}
+    type TestConverter = Converter<UnwinderX86_64<ModuleData>>;
 
-struct Processes<U>
-where
-    U: Unwinder<Module = Module<Vec<u8>>> + Default,
-{
-    processes_by_pid: HashMap<i32, Process<U>>,
-    ended_processes_for_reuse_by_name: HashMap<String, VecDeque<Process<U>>>,
+    const MS: u64 = 1_000_000;
 
-    /// The sample data for all removed processes.
-    process_sample_datas: Vec<ProcessSampleData>,
+    fn make_converter(merge_threads: bool) -> TestConverter {
+        make_converter_with_reference(merge_threads, 0)
+    }
 
-    allow_reuse: bool,
-}
+    fn make_converter_with_reference(merge_threads: bool, reference_time: u64) -> TestConverter {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: true,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["cpu-clock".to_string(), "sched:sched_switch".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        Converter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            reference_time,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                merge_threads,
+                ..Default::default()
+            },
+        )
+    }
 
-impl<U> Processes<U>
-where
-    U: Unwinder<Module = Module<Vec<u8>>> + Default,
-{
-    pub fn new(allow_reuse: bool) -> Self {
-        Self {
-            processes_by_pid: HashMap::new(),
-            ended_processes_for_reuse_by_name: HashMap::new(),
-            process_sample_datas: Vec::new(),
-            allow_reuse,
+    fn sample(pid: i32, tid: i32, timestamp: u64, ip: u64) -> SampleRecord<'static> {
+        SampleRecord {
+            id: None,
+            addr: None,
+            stream_id: None,
+            raw: None,
+            ip: Some(ip),
+            timestamp: Some(timestamp),
+            pid: Some(pid),
+            tid: Some(tid),
+            cpu: None,
+            period: None,
+            user_regs: None,
+            user_stack: None,
+            callchain: None,
+            phys_addr: None,
+            data_page_size: None,
+            code_page_size: None,
+            intr_regs: None,
+            cpu_mode: CpuMode::User,
         }
     }
 
-    pub fn attempt_reuse(&mut self, pid: i32, name: &str) -> Option<&mut Process<U>> {
-        if let Entry::Vacant(entry) = self.processes_by_pid.entry(pid) {
-            if let Some(processes_of_same_name) =
-                self.ended_processes_for_reuse_by_name.get_mut(name)
-            {
-                let mut process = processes_of_same_name
-                    .pop_front()
-                    .expect("We only have non-empty VecDeques in this HashMap");
-                if processes_of_same_name.is_empty() {
-                    self.ended_processes_for_reuse_by_name.remove(name);
-                }
-                process.reset_for_reuse(pid);
-                return Some(entry.insert(process));
-            }
+    fn common(pid: i32, tid: i32, timestamp: u64) -> CommonData {
+        CommonData {
+            pid: Some(pid),
+            tid: Some(tid),
+            timestamp: Some(timestamp),
+            id: None,
+            stream_id: None,
+            cpu: None,
         }
-        None
     }
 
-    pub fn get_by_pid(&mut self, pid: i32, profile: &mut Profile) -> &mut Process<U> {
-        self.processes_by_pid.entry(pid).or_insert_with(|| {
-            let name = format!("<{pid}>");
-            let handle = profile.add_process(
-                &name,
-                pid as u32,
-                Timestamp::from_millis_since_reference(0.0),
-            );
-            let profile_thread = profile.add_thread(
-                handle,
-                pid as u32,
-                Timestamp::from_millis_since_reference(0.0),
-                true,
-            );
-            let main_thread = Thread {
-                profile_thread,
-                context_switch_data: Default::default(),
-                last_sample_timestamp: None,
-                off_cpu_stack: None,
-                name: None,
-            };
-            let jit_function_recycler = if self.allow_reuse {
-                Some(JitFunctionRecycler::default())
-            } else {
-                None
-            };
-            Process {
-                profile_process: handle,
-                unwinder: U::default(),
-                jitdump_manager: JitDumpManager::new_for_process(profile_thread),
-                lib_mapping_ops: Default::default(),
-                name: None,
-                pid,
-                threads: ProcessThreads {
-                    pid,
-                    profile_process: handle,
-                    main_thread,
-                    threads_by_tid: HashMap::new(),
-                    ended_threads_for_reuse_by_name: HashMap::new(),
-                },
-                jit_function_recycler,
-                unresolved_samples: Default::default(),
-                prev_mm_filepages_size: 0,
-                prev_mm_anonpages_size: 0,
-                prev_mm_swapents_size: 0,
-                prev_mm_shmempages_size: 0,
-                mem_counter: None,
-            }
-        })
+    fn fork(converter: &mut TestConverter, pid: i32, tid: i32, timestamp: u64) {
+        converter.handle_thread_start(ForkOrExitRecord {
+            pid,
+            ppid: pid,
+            tid,
+            ptid: pid,
+            timestamp,
+        });
     }
 
-    pub fn remove(
-        &mut self,
-        pid: i32,
-        time: Timestamp,
-        profile: &mut Profile,
-        jit_category_manager: &mut JitCategoryManager,
-        timestamp_converter: &TimestampConverter,
-    ) {
-        let Some(mut process) = self.processes_by_pid.remove(&pid) else { return };
-        profile.set_process_end_time(process.profile_process, time);
+    fn exit(converter: &mut TestConverter, pid: i32, tid: i32, timestamp: u64) {
+        converter.handle_thread_end(ForkOrExitRecord {
+            pid,
+            ppid: pid,
+            tid,
+            ptid: pid,
+            timestamp,
+        });
+    }
 
-        let process_sample_data = process.on_remove(
-            self.allow_reuse,
-            profile,
-            jit_category_manager,
-            timestamp_converter,
+    fn comm(converter: &mut TestConverter, pid: i32, tid: i32, name: &[u8], timestamp: u64) {
+        converter.handle_thread_name_update(
+            CommOrExecRecord {
+                pid,
+                tid,
+                name: RawData::Single(name),
+                is_execve: false,
+            },
+            Some(timestamp),
+        );
+    }
+
+    fn block(converter: &mut TestConverter, pid: i32, tid: i32, timestamp: u64) {
+        let sched_switch = sample(pid, tid, timestamp, 0x1234);
+        converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&sched_switch, 1);
+        converter.handle_context_switch(
+            ContextSwitchRecord::Out {
+                next_pid: None,
+                next_tid: None,
+                preempted: TaskWasPreempted::No,
+            },
+            common(pid, tid, timestamp),
         );
-        if !process_sample_data.is_empty() {
-            self.process_sample_datas.push(process_sample_data);
-        }
+    }
 
-        if self.allow_reuse {
-            if let Some(name) = process.name.as_deref() {
-                self.ended_processes_for_reuse_by_name
-                    .entry(name.to_string())
-                    .or_default()
-                    .push_back(process);
-            }
-        }
+    fn unblock(converter: &mut TestConverter, pid: i32, tid: i32, timestamp: u64) {
+        converter.handle_context_switch(
+            ContextSwitchRecord::In {
+                prev_pid: None,
+                prev_tid: None,
+            },
+            common(pid, tid, timestamp),
+        );
     }
 
-    pub fn finish(
-        mut self,
-        profile: &mut Profile,
-        unresolved_stacks: &UnresolvedStacks,
-        event_names: &[String],
-        jit_category_manager: &mut JitCategoryManager,
-        timestamp_converter: &TimestampConverter,
-    ) {
-        // Gather the ProcessSampleData from any processes which are still alive at the end of profiling.
-        for mut process in self.processes_by_pid.into_values() {
-            let process_sample_data = process.on_remove(
-                self.allow_reuse,
-                profile,
-                jit_category_manager,
-                timestamp_converter,
-            );
-            if !process_sample_data.is_empty() {
-                self.process_sample_datas.push(process_sample_data);
-            }
-        }
+    /// Returns (timestamp_mono, weight) for all samples on the thread with this tid.
+    fn thread_samples(converter: &TestConverter, pid: i32, tid: i32) -> Vec<(u64, i32)> {
+        let process = &converter.processes.processes_by_pid[&pid];
+        let thread_handle = process.threads.threads_by_tid[&tid].profile_thread;
+        process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter(|s| s.thread_handle == thread_handle)
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::Sample(SampleData { weight, .. }) => {
+                    Some((s.timestamp_mono, weight))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 
-        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
-        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
-        let mut stack_frame_scratch_buf = Vec::new();
-        for process_sample_data in self.process_sample_datas {
-            process_sample_data.flush_samples_to_profile(
-                profile,
-                user_category,
-                kernel_category,
-                &mut stack_frame_scratch_buf,
-                unresolved_stacks,
-                event_names,
-            );
-        }
+    /// A "worker" thread blocks, exits, and its name is picked up by a new thread
+    /// 1ms later. The new thread runs again at 10ms. With merge_threads, the whole
+    /// 9ms blocked interval should be accounted for in one off-CPU sample group.
+    fn run_blocked_thread_reuse(merge_threads: bool, reuse_time: u64) -> Vec<(u64, i32)> {
+        let mut converter = make_converter(merge_threads);
+        fork(&mut converter, 100, 101, 0);
+        comm(&mut converter, 100, 101, b"worker", 0);
+        block(&mut converter, 100, 101, MS);
+        exit(&mut converter, 100, 101, 2 * MS);
+        fork(&mut converter, 100, 102, reuse_time);
+        comm(&mut converter, 100, 102, b"worker", reuse_time);
+        unblock(&mut converter, 100, 102, reuse_time + 7 * MS);
+        thread_samples(&converter, 100, 102)
     }
-}
 
-#[derive(Debug)]
-struct Thread {
-    profile_thread: ThreadHandle,
-    context_switch_data: ThreadContextSwitchData,
-    last_sample_timestamp: Option<u64>,
+    #[test]
+    fn blocked_thread_state_carries_over_on_reuse() {
+        let samples = run_blocked_thread_reuse(true, 3 * MS);
+        assert_eq!(samples, vec![(2 * MS, 1), (2 * MS, 8)]);
+    }
 
-    /// Some() between sched_switch and the next context switch IN
-    ///
-    /// Refers to a stack in the containing Process's UnresolvedSamples stack table.
-    off_cpu_stack: Option<UnresolvedStackHandle>,
-    name: Option<String>,
-}
+    #[test]
+    fn blocked_thread_state_not_carried_over_without_merge_threads() {
+        let samples = run_blocked_thread_reuse(false, 3 * MS);
+        assert_eq!(samples, vec![]);
+    }
 
-impl Thread {
-    pub fn on_remove(&mut self) {
-        self.context_switch_data = Default::default();
-        self.last_sample_timestamp = None;
-        self.off_cpu_stack = None;
+    #[test]
+    fn blocked_thread_state_not_carried_over_after_window() {
+        let samples = run_blocked_thread_reuse(true, 20 * MS);
+        assert_eq!(samples, vec![]);
     }
 
-    pub fn reset_for_reuse(&mut self, _tid: i32) {}
-}
+    /// The "worker" thread of process 100 blocks, and the process exits. The
+    /// pid and the tid are reused for a new incarnation, which picks up the
+    /// ended worker. The worker runs again 7ms after the reuse.
+    fn run_blocked_thread_process_reuse(reuse_time: u64) -> Vec<(u64, i32)> {
+        let mut converter = make_converter(true);
+        comm(&mut converter, 1, 1, b"launcher", 0);
+        let start_process = |converter: &mut TestConverter, timestamp: u64| {
+            converter.handle_thread_start(ForkOrExitRecord {
+                pid: 100,
+                ppid: 1,
+                tid: 100,
+                ptid: 1,
+                timestamp,
+            });
+        };
+        start_process(&mut converter, 0);
+        fork(&mut converter, 100, 101, 0);
+        comm(&mut converter, 100, 101, b"worker", 0);
+        block(&mut converter, 100, 101, MS);
+        let worker = converter.processes.existing_thread_handle(100, 101);
+        exit(&mut converter, 100, 100, 2 * MS);
+        start_process(&mut converter, reuse_time);
+        fork(&mut converter, 100, 101, reuse_time);
+        comm(&mut converter, 100, 101, b"worker", reuse_time);
+        assert_eq!(converter.processes.existing_thread_handle(100, 101), worker);
+        unblock(&mut converter, 100, 101, reuse_time + 7 * MS);
+        thread_samples(&converter, 100, 101)
+    }
 
-struct Process<U>
-where
-    U: Unwinder<Module = Module<Vec<u8>>> + Default,
-{
-    pub profile_process: ProcessHandle,
-    pub unwinder: U,
-    pub jitdump_manager: JitDumpManager,
-    pub lib_mapping_ops: LibMappingOpQueue,
-    pub name: Option<String>,
-    pub threads: ProcessThreads,
-    pid: i32,
-    pub unresolved_samples: UnresolvedSamples,
-    jit_function_recycler: Option<JitFunctionRecycler>,
-    prev_mm_filepages_size: i64,
-    prev_mm_anonpages_size: i64,
-    prev_mm_swapents_size: i64,
-    prev_mm_shmempages_size: i64,
-    mem_counter: Option<CounterHandle>,
-}
+    #[test]
+    fn blocked_thread_state_carries_over_into_reused_process() {
+        let samples = run_blocked_thread_process_reuse(3 * MS);
+        assert_eq!(samples, vec![(2 * MS, 1), (2 * MS, 8)]);
+    }
 
-impl<U> Process<U>
-where
-    U: Unwinder<Module = Module<Vec<u8>>> + Default,
-{
-    pub fn check_jitdump(
-        &mut self,
-        jit_category_manager: &mut JitCategoryManager,
-        profile: &mut Profile,
-        timestamp_converter: &TimestampConverter,
-    ) {
-        self.jitdump_manager.process_pending_records(
-            jit_category_manager,
-            profile,
-            self.jit_function_recycler.as_mut(),
-            timestamp_converter,
-        );
+    #[test]
+    fn blocked_thread_state_not_carried_over_into_process_reused_after_window() {
+        let samples = run_blocked_thread_process_reuse(20 * MS);
+        assert_eq!(samples, vec![]);
     }
 
-    pub fn reset_for_reuse(&mut self, new_pid: i32) {
-        self.pid = new_pid;
-        self.threads.pid = new_pid;
+    #[test]
+    fn renames_with_merge_threads_keep_the_thread() {
+        let mut converter = make_converter(true);
+        fork(&mut converter, 100, 101, 0);
+        comm(&mut converter, 100, 101, b"worker", 0);
+        let threads = &converter.processes.processes_by_pid[&100].threads;
+        let thread_handle = threads.threads_by_tid[&101].profile_thread;
+        let reusable_thread_count = threads.ended_threads_for_reuse_by_name.len();
+
+        for i in 0..100_000 {
+            let name = format!("request-{i}");
+            comm(&mut converter, 100, 101, name.as_bytes(), MS + i);
+        }
+
+        let threads = &converter.processes.processes_by_pid[&100].threads;
+        let thread = &threads.threads_by_tid[&101];
+        assert_eq!(thread.profile_thread, thread_handle);
+        assert_eq!(thread.name.as_deref(), Some("request-99999"));
+        assert_eq!(thread.rename_count, 100_000);
+        assert_eq!(thread.previous_names.len(), THREAD_NAME_HISTORY_LEN);
+        assert_eq!(
+            threads.ended_threads_for_reuse_by_name.len(),
+            reusable_thread_count
+        );
     }
 
-    pub fn on_remove(
-        &mut self,
-        allow_thread_reuse: bool,
-        profile: &mut Profile,
-        jit_category_manager: &mut JitCategoryManager,
-        timestamp_converter: &TimestampConverter,
-    ) -> ProcessSampleData {
-        self.unwinder = U::default();
+    /// Returns the CPU deltas of all samples on the thread with this tid.
+    fn thread_cpu_deltas(converter: &TestConverter, pid: i32, tid: i32) -> Vec<CpuDelta> {
+        let process = &converter.processes.processes_by_pid[&pid];
+        let thread_handle = process.threads.threads_by_tid[&tid].profile_thread;
+        process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter(|s| s.thread_handle == thread_handle)
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::Sample(SampleData { cpu_delta, .. }) => Some(cpu_delta),
+                _ => None,
+            })
+            .collect()
+    }
 
-        if allow_thread_reuse {
-            self.threads.prepare_for_reuse();
+    /// `perf record -e instructions -c 1000000`: every sample stands for a
+    /// million instructions, and without context switches there's no CPU time.
+    #[test]
+    fn instruction_samples_are_weighted_by_their_period() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "instructions".to_string(),
+            sampling_is_time_based: None,
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["instructions".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions::default(),
+        );
+        fork(&mut converter, 100, 101, 0);
+        for i in 1..=3 {
+            let e = SampleRecord {
+                period: Some(1_000_000),
+                ..sample(100, 101, i * MS, 0x1234)
+            };
+            converter.handle_sample::<ConvertRegsX86_64>(&e);
         }
 
-        let perf_map_mappings = if !self.unresolved_samples.is_empty() {
-            try_load_perf_map(
-                self.pid as u32,
-                profile,
-                jit_category_manager,
-                self.jit_function_recycler.as_mut(),
-            )
-        } else {
-            None
-        };
+        let process = &converter.processes.processes_by_pid[&100];
+        let weights: Vec<i32> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::Sample(SampleData { weight, .. }) => Some(weight),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(weights, vec![1_000_000; 3]);
+        assert_eq!(
+            thread_cpu_deltas(&converter, 100, 101),
+            vec![CpuDelta::ZERO; 3]
+        );
 
-        if let Some(recycler) = self.jit_function_recycler.as_mut() {
-            recycler.finish_round();
-        }
+        let profile = serde_json::to_value(&converter.profile).unwrap();
+        assert_eq!(profile["meta"]["sampleUnits"]["weight"], "instructions");
+        assert_eq!(profile["meta"]["sampleUnits"]["time"], "ms");
+    }
 
-        let jitdump_manager = std::mem::replace(
-            &mut self.jitdump_manager,
-            JitDumpManager::new_for_process(self.threads.main_thread.profile_thread),
+    /// A thread is sampled in the scheduler right after it blocks, and then sleeps
+    /// for 8ms. The sleep must not show up as CPU time on the samples after it.
+    #[test]
+    fn sample_after_switch_out_does_not_count_sleep_as_cpu_time() {
+        let mut converter = make_converter(false);
+        fork(&mut converter, 100, 101, 0);
+        unblock(&mut converter, 100, 101, 0);
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, MS, 0x1234));
+        block(&mut converter, 100, 101, 2 * MS);
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, 2 * MS + 1000, 0x1234));
+        unblock(&mut converter, 100, 101, 10 * MS);
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, 11 * MS, 0x1234));
+        assert_eq!(
+            thread_cpu_deltas(&converter, 100, 101),
+            vec![
+                CpuDelta::from_nanos(MS),
+                CpuDelta::from_nanos(MS),
+                // The off-cpu sample group at the switch-in.
+                CpuDelta::ZERO,
+                CpuDelta::ZERO,
+                CpuDelta::from_nanos(MS),
+            ]
         );
-        let jitdump_ops = jitdump_manager.finish(
-            jit_category_manager,
-            profile,
-            self.jit_function_recycler.as_mut(),
-            timestamp_converter,
+    }
+
+    fn synthesized_samples(converter: &TestConverter, pid: i32) -> Vec<u64> {
+        let process = &converter.processes.processes_by_pid[&pid];
+        process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter(|s| matches!(s.sample_or_marker, SampleOrMarker::SynthesizedSample))
+            .map(|s| s.timestamp_mono)
+            .collect()
+    }
+
+    #[test]
+    fn short_thread_gets_synthesized_sample() {
+        let mut converter = make_converter(false);
+        converter.processes.synthesize_samples_for_short_threads = true;
+        fork(&mut converter, 100, 101, 0);
+        block(&mut converter, 100, 101, MS);
+        exit(&mut converter, 100, 101, 2 * MS);
+        assert_eq!(synthesized_samples(&converter, 100), vec![2 * MS]);
+    }
+
+    #[test]
+    fn unblocked_short_thread_gets_the_parent_stack_at_fork() {
+        let mut converter = make_converter(false);
+        converter.processes.synthesize_samples_for_short_threads = true;
+        // The main thread forks 101 between two samples with different stacks.
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, MS / 2, 0x1234));
+        fork(&mut converter, 100, 101, MS);
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 3 * MS / 2, 0x5678));
+        exit(&mut converter, 100, 101, 2 * MS);
+
+        let process = &converter.processes.processes_by_pid[&100];
+        let samples = process.unresolved_samples.clone().into_inner();
+        let parent_stack_at_fork = samples[0].stack;
+        let synthesized: Vec<_> = samples
+            .iter()
+            .filter(|s| matches!(s.sample_or_marker, SampleOrMarker::SynthesizedSample))
+            .map(|s| (s.timestamp_mono, s.stack))
+            .collect();
+        assert_eq!(synthesized, vec![(2 * MS, parent_stack_at_fork)]);
+    }
+
+    /// The stack table fills up after the first two samples. Later samples
+    /// with new frames are cut off, and samples whose stacks are in the table
+    /// already still get their full stack.
+    #[test]
+    fn samples_after_the_stack_node_limit_are_cut_off() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["cpu-clock".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                max_stack_nodes: Some(2),
+                ..Default::default()
+            },
         );
+        fork(&mut converter, 100, 101, 0);
+        for (i, ip) in [0x1000, 0x2000, 0x3000, 0x1000, 0x4000, 0x3000]
+            .into_iter()
+            .enumerate()
+        {
+            let timestamp = (i as u64 + 1) * MS;
+            converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, timestamp, ip));
+        }
 
-        ProcessSampleData::new(
-            std::mem::take(&mut self.unresolved_samples),
-            std::mem::take(&mut self.lib_mapping_ops),
-            jitdump_ops,
-            perf_map_mappings,
-        )
+        let process = &converter.processes.processes_by_pid[&100];
+        let truncated: Vec<bool> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter(|s| matches!(s.sample_or_marker, SampleOrMarker::Sample(_)))
+            .map(|s| converter.unresolved_stacks.is_truncated(s.stack))
+            .collect();
+        assert_eq!(truncated, vec![false, false, true, false, true, true]);
+        assert_eq!(converter.truncated_sample_count, 3);
+        let stats = converter.unresolved_stacks.stats();
+        assert_eq!(stats.node_count, 3);
+        assert_eq!(stats.truncated_stack_count, 3);
+
+        let profile = serde_json::to_value(converter.finish()).unwrap();
+        assert_eq!(profile["threads"][1]["samples"]["length"], 6);
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn add_regular_lib_mapping(
-        &mut self,
-        timestamp: u64,
-        start_address: u64,
-        end_address: u64,
-        relative_address_at_start: u32,
-        lib_handle: LibraryHandle,
-    ) {
-        self.lib_mapping_ops.push(
-            timestamp,
-            LibMappingOp::Add(LibMappingAdd {
-                start_avma: start_address,
-                end_avma: end_address,
-                relative_address_at_start,
-                info: LibMappingInfo::new_lib(lib_handle),
-            }),
+    #[test]
+    fn sampled_thread_gets_no_synthesized_sample() {
+        let mut converter = make_converter(false);
+        converter.processes.synthesize_samples_for_short_threads = true;
+        fork(&mut converter, 100, 101, 0);
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, MS / 2, 0x1234));
+        block(&mut converter, 100, 101, MS);
+        exit(&mut converter, 100, 101, 2 * MS);
+        assert!(synthesized_samples(&converter, 100).is_empty());
+    }
+
+    /// The records of a thread and of the next thread with the same tid
+    /// arrive before the first thread's last samples.
+    #[test]
+    fn late_samples_go_to_the_incarnation_alive_at_their_time() {
+        let mut converter = make_converter(false);
+        fork(&mut converter, 100, 101, 0);
+        let first = converter.processes.existing_thread_handle(100, 101);
+        exit(&mut converter, 100, 101, 2 * MS);
+        fork(&mut converter, 100, 101, 3 * MS);
+        let second = converter.processes.existing_thread_handle(100, 101);
+        assert_ne!(first, second);
+
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, MS, 0x1234));
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, 2 * MS + 1000, 0x1234));
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, 4 * MS, 0x1234));
+
+        let process = &converter.processes.processes_by_pid[&100];
+        let late_sample_thread = process.threads.late_sample_thread;
+        assert!(late_sample_thread.is_some());
+        let sample_threads: Vec<_> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter(|s| matches!(s.sample_or_marker, SampleOrMarker::Sample(_)))
+            .map(|s| (s.timestamp_mono, Some(s.thread_handle)))
+            .collect();
+        assert_eq!(
+            sample_threads,
+            vec![
+                (MS, first),
+                (2 * MS + 1000, late_sample_thread),
+                (4 * MS, second),
+            ]
         );
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn add_lib_mapping_for_injected_jit_lib(
-        &mut self,
-        timestamp: u64,
-        profile_timestamp: Timestamp,
-        symbol_name: Option<&str>,
-        start_address: u64,
-        end_address: u64,
-        mut relative_address_at_start: u32,
-        mut lib_handle: LibraryHandle,
-        jit_category_manager: &mut JitCategoryManager,
-        profile: &mut Profile,
-    ) {
-        let main_thread = self.threads.main_thread.profile_thread;
-        let timing = MarkerTiming::Instant(profile_timestamp);
-        profile.add_marker(
-            main_thread,
-            "JitFunctionAdd",
-            JitFunctionAddMarker(symbol_name.unwrap_or("<unknown>").to_owned()),
-            timing,
+    fn kernel_mmap(converter: &mut TestConverter, path: &[u8], address: u64, length: u64) {
+        converter.handle_mmap(
+            MmapRecord {
+                pid: -1,
+                tid: 0,
+                address,
+                length,
+                page_offset: 0,
+                is_executable: true,
+                cpu_mode: CpuMode::Kernel,
+                path: RawData::Single(path),
+            },
+            0,
         );
+    }
 
-        if let (Some(name), Some(recycler)) = (symbol_name, self.jit_function_recycler.as_mut()) {
-            (lib_handle, relative_address_at_start) = recycler.recycle(
-                start_address,
-                end_address,
-                relative_address_at_start,
-                name,
-                lib_handle,
+    #[test]
+    fn repeated_kernel_mmaps_are_merged() {
+        let mut converter = make_converter(false);
+        for _cpu in 0..4 {
+            kernel_mmap(
+                &mut converter,
+                b"[kernel.kallsyms]_text",
+                0xffff_ffff_8100_0000,
+                0x100_0000,
             );
         }
-
-        let (category, js_frame) =
-            jit_category_manager.classify_jit_symbol(symbol_name.unwrap_or(""), profile);
-        self.lib_mapping_ops.push(
-            timestamp,
-            LibMappingOp::Add(LibMappingAdd {
-                start_avma: start_address,
-                end_avma: end_address,
-                relative_address_at_start,
-                info: LibMappingInfo::new_jit_function(lib_handle, category, js_frame),
-            }),
+        kernel_mmap(&mut converter, b"[snd_seq]", 0xffff_ffff_c000_0000, 0x1000);
+        kernel_mmap(&mut converter, b"[snd_seq]", 0xffff_ffff_c000_0000, 0x1000);
+        kernel_mmap(&mut converter, b"[psmouse]", 0xffff_ffff_c001_0000, 0x2000);
+        // A kernel mapping which overlaps the existing one with a different size
+        // replaces it.
+        kernel_mmap(
+            &mut converter,
+            b"[kernel.kallsyms]_text",
+            0xffff_ffff_8100_0000,
+            0x200_0000,
         );
-    }
 
-    pub fn get_or_make_mem_counter(&mut self, profile: &mut Profile) -> CounterHandle {
-        *self.mem_counter.get_or_insert_with(|| {
-            profile.add_counter(
-                self.profile_process,
-                "malloc",
-                "Memory",
-                "Amount of allocated memory",
-            )
-        })
+        let kernel_mappings: Vec<_> = converter
+            .kernel_mappings
+            .iter()
+            .map(|(start, mapping)| (*start, mapping.end, mapping.dso_key.name().to_string()))
+            .collect();
+        assert_eq!(
+            kernel_mappings,
+            vec![
+                (
+                    0xffff_ffff_8100_0000,
+                    0xffff_ffff_8300_0000,
+                    "[kernel.kallsyms]".to_string()
+                ),
+                (
+                    0xffff_ffff_c000_0000,
+                    0xffff_ffff_c000_1000,
+                    "[snd_seq]".to_string()
+                ),
+                (
+                    0xffff_ffff_c001_0000,
+                    0xffff_ffff_c001_2000,
+                    "[psmouse]".to_string()
+                ),
+            ]
+        );
     }
-}
 
-struct ProcessThreads {
-    pid: i32,
-    profile_process: ProcessHandle,
-    main_thread: Thread,
-    threads_by_tid: HashMap<i32, Thread>,
-    ended_threads_for_reuse_by_name: HashMap<String, VecDeque<Thread>>,
-}
+    #[test]
+    fn custom_tracepoint_handler_receives_its_samples() {
+        struct CountingHandler(Rc<Cell<usize>>);
 
-impl ProcessThreads {
-    pub fn prepare_for_reuse(&mut self) {
-        for (_tid, mut thread) in self.threads_by_tid.drain() {
-            thread.on_remove();
+        impl TracepointHandler for CountingHandler {
+            fn wants(&self, attr_name: &str) -> bool {
+                attr_name == "syscalls:sys_enter_read"
+            }
 
-            if let Some(name) = thread.name.as_deref() {
-                self.ended_threads_for_reuse_by_name
-                    .entry(name.to_owned())
-                    .or_default()
-                    .push_back(thread);
+            fn handle(&mut self, _ctx: &mut ConvertCtx, _e: &SampleRecord) {
+                self.0.set(self.0.get() + 1);
             }
         }
+
+        let count = Rc::new(Cell::new(0));
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec![
+                "cpu-clock".to_string(),
+                "syscalls:sys_enter_read".to_string(),
+                "syscalls:sys_enter_write".to_string(),
+            ],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                tracepoint_handlers: vec![Box::new(CountingHandler(count.clone()))],
+                ..Default::default()
+            },
+        );
+
+        fork(&mut converter, 1, 1, 0);
+        assert!(
+            converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&sample(1, 1, MS, 0x1234), 1)
+        );
+        assert!(converter
+            .handle_tracepoint_sample::<ConvertRegsX86_64>(&sample(1, 1, 2 * MS, 0x1234), 1));
+        assert!(!converter
+            .handle_tracepoint_sample::<ConvertRegsX86_64>(&sample(1, 1, 3 * MS, 0x1234), 2));
+        assert_eq!(count.get(), 2);
+    }
+
+    fn syscall_sample(
+        converter: &mut TestConverter,
+        attr_index: usize,
+        timestamp: u64,
+        args: &[u64],
+    ) {
+        let mut raw = vec![0; 16];
+        for arg in args {
+            raw.extend_from_slice(&arg.to_le_bytes());
+        }
+        let e = SampleRecord {
+            raw: Some(RawData::Single(&raw)),
+            ..sample(100, 101, timestamp, 0x1234)
+        };
+        converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&e, attr_index);
+    }
+
+    #[test]
+    fn large_mmaps_get_markers() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec![
+                "cpu-clock".to_string(),
+                "syscalls:sys_enter_mmap".to_string(),
+                "syscalls:sys_exit_mmap".to_string(),
+            ],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions::default(),
+        );
+        fork(&mut converter, 100, 101, 0);
+
+        const MB: u64 = 1024 * 1024;
+        // Small mmap: counted, but no marker.
+        syscall_sample(&mut converter, 1, MS, &[0, MB]);
+        syscall_sample(&mut converter, 2, MS, &[0x7f0000000000]);
+        // Large mmap which fails with ENOMEM: no marker.
+        syscall_sample(&mut converter, 1, 2 * MS, &[0, 100 * MB]);
+        syscall_sample(&mut converter, 2, 2 * MS, &[-12i64 as u64]);
+        // Large mmap which succeeds.
+        syscall_sample(&mut converter, 1, 3 * MS, &[0, 100 * MB - 1]);
+        syscall_sample(&mut converter, 2, 3 * MS, &[0x7f0010000000]);
+
+        let process = &converter.processes.processes_by_pid[&100];
+        let markers: Vec<_> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::LargeMmapMarker(data) => Some((s.timestamp_mono, data.length)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(markers, vec![(3 * MS, 100 * MB)]);
+    }
+
+    #[test]
+    fn futex_waits_and_wakes_get_markers() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec![
+                "cpu-clock".to_string(),
+                "syscalls:sys_enter_futex".to_string(),
+                "syscalls:sys_exit_futex".to_string(),
+            ],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions::default(),
+        );
+        fork(&mut converter, 100, 101, 0);
+
+        const FUTEX_WAIT_PRIVATE: u64 = 128;
+        const FUTEX_WAKE_PRIVATE: u64 = 129;
+        syscall_sample(&mut converter, 1, MS, &[0x1000, FUTEX_WAIT_PRIVATE]);
+        syscall_sample(&mut converter, 2, 3 * MS, &[0]);
+        syscall_sample(&mut converter, 1, 4 * MS, &[0x1000, FUTEX_WAKE_PRIVATE]);
+        syscall_sample(&mut converter, 2, 4 * MS, &[1]);
+        // A wait which returns EAGAIN right away: no marker.
+        syscall_sample(&mut converter, 1, 5 * MS, &[0x2000, FUTEX_WAIT_PRIVATE]);
+        syscall_sample(&mut converter, 2, 5 * MS, &[-11i64 as u64]);
+
+        let process = &converter.processes.processes_by_pid[&100];
+        let markers: Vec<_> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::FutexWaitMarker(data) => {
+                    Some(("wait", s.timestamp_mono, data.address, data.duration_ns))
+                }
+                SampleOrMarker::FutexWakeMarker(data) => {
+                    Some(("wake", s.timestamp_mono, data.address, data.woken_count))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            markers,
+            vec![("wait", MS, 0x1000, 2 * MS), ("wake", 4 * MS, 0x1000, 1)]
+        );
+    }
+
+    #[test]
+    fn delivered_signals_get_markers() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["cpu-clock".to_string(), "signal:signal_deliver".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                ignored_signals: vec![17, 11],
+                ..Default::default()
+            },
+        );
+        fork(&mut converter, 100, 101, 0);
+
+        const SIGSEGV: i32 = 11;
+        const SIGCHLD: i32 = 17;
+        const SIGUSR1: i32 = 10;
+        let mut signal_deliver = |timestamp: u64, signal: i32, code: i32, sa_handler: u64| {
+            let mut raw = vec![0; 8];
+            raw.extend_from_slice(&signal.to_le_bytes());
+            raw.extend_from_slice(&0i32.to_le_bytes());
+            raw.extend_from_slice(&code.to_le_bytes());
+            raw.extend_from_slice(&[0; 4]);
+            raw.extend_from_slice(&sa_handler.to_le_bytes());
+            raw.extend_from_slice(&0u64.to_le_bytes());
+            let e = SampleRecord {
+                raw: Some(RawData::Single(&raw)),
+                ..sample(100, 101, timestamp, 0x1234)
+            };
+            converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&e, 1);
+        };
+        // Ignored.
+        signal_deliver(MS, SIGCHLD, 1, 0);
+        // Handled by the process, so not fatal.
+        signal_deliver(2 * MS, SIGUSR1, 0, 0x5000);
+        // Fatal, so it isn't ignored.
+        signal_deliver(3 * MS, SIGSEGV, 1, 0);
+
+        let process = &converter.processes.processes_by_pid[&100];
+        let markers: Vec<_> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::SignalMarker(data) => {
+                    Some((s.timestamp_mono, data.signal_name, data.fatal))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            markers,
+            vec![
+                (2 * MS, "SIGUSR1".to_string(), false),
+                (3 * MS, "SIGSEGV".to_string(), true)
+            ]
+        );
     }
 
-    pub fn attempt_thread_reuse(&mut self, tid: i32, name: &str) -> Option<&mut Thread> {
-        if let Entry::Vacant(entry) = self.threads_by_tid.entry(tid) {
-            if let Some(threads_of_same_name) = self.ended_threads_for_reuse_by_name.get_mut(name) {
-                let mut thread = threads_of_same_name
-                    .pop_front()
-                    .expect("We only have non-empty VecDeques in this HashMap");
-                if threads_of_same_name.is_empty() {
-                    self.ended_threads_for_reuse_by_name.remove(name);
+    #[test]
+    fn probe_hits_get_named_markers() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec![
+                "cpu-clock".to_string(),
+                "probe:my_function".to_string(),
+                "probe_libc:malloc".to_string(),
+            ],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut tracepoint_formats = TracepointFormats::default();
+        let format = tracepoint_format::EventFormat::parse(
+            "name: my_function\n\
+             format:\n\
+             \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;\n\
+             \tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;\n\
+             \tfield:unsigned long __probe_ip;\toffset:8;\tsize:8;\tsigned:0;\n\
+             \tfield:s32 arg1;\toffset:16;\tsize:4;\tsigned:1;\n\
+             print fmt: \"(%lx) arg1=%d\", REC->__probe_ip, REC->arg1\n",
+        )
+        .unwrap();
+        tracepoint_formats.insert("probe:my_function", format);
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                tracepoint_formats,
+                ..Default::default()
+            },
+        );
+        fork(&mut converter, 100, 101, 0);
+
+        let mut raw = vec![0; 8];
+        raw.extend_from_slice(&0x401234u64.to_le_bytes());
+        raw.extend_from_slice(&42i32.to_le_bytes());
+        let e = SampleRecord {
+            raw: Some(RawData::Single(&raw)),
+            ..sample(100, 101, MS, 0x1234)
+        };
+        converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&e, 1);
+        // No format, so the marker only has the event name.
+        let e = SampleRecord {
+            raw: Some(RawData::Single(&raw)),
+            ..sample(100, 101, 2 * MS, 0x1234)
+        };
+        converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&e, 2);
+
+        let process = &converter.processes.processes_by_pid[&100];
+        let markers: Vec<_> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::ProbeMarker(data) => {
+                    Some((s.timestamp_mono, data.event_name, data.args))
                 }
-                thread.reset_for_reuse(tid);
-                return Some(entry.insert(thread));
-            }
-        }
-        None
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            markers,
+            vec![
+                (
+                    MS,
+                    "probe:my_function".to_string(),
+                    vec![("arg1".to_string(), MarkerValue::Text("42".to_string()))]
+                ),
+                (2 * MS, "probe_libc:malloc".to_string(), vec![]),
+            ]
+        );
     }
 
-    pub fn get_main_thread(&mut self) -> &mut Thread {
-        &mut self.main_thread
+    /// Nested begin/end pairs on one thread nest, and a begin which is still
+    /// open at the end of the profile becomes an interval without an end.
+    #[test]
+    fn marker_pairs_become_nested_interval_markers() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec![
+                "cpu-clock".to_string(),
+                "sdt_app:phase_begin".to_string(),
+                "sdt_app:phase_end".to_string(),
+            ],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let spec = parse_marker_pair("begin=sdt_app:phase_begin,end=sdt_app:phase_end").unwrap();
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                tracepoint_handlers: vec![Box::new(MarkerPairHandler::new(vec![spec]))],
+                ..Default::default()
+            },
+        );
+        fork(&mut converter, 100, 100, 0);
+        for (timestamp, attr_index) in [(1, 1), (2, 1), (3, 2), (4, 2), (5, 2), (6, 1)] {
+            let e = sample(100, 100, timestamp * MS, 0x1234);
+            converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&e, attr_index);
+        }
+
+        let profile = serde_json::to_value(converter.finish()).unwrap();
+        let markers = &profile["threads"][0]["markers"];
+        let intervals: Vec<_> = (0..markers["length"].as_u64().unwrap() as usize)
+            .filter(|&i| markers["data"][i]["type"] == "MarkerPair")
+            .map(|i| {
+                (
+                    markers["startTime"][i].as_f64().unwrap(),
+                    markers["endTime"][i].as_f64().unwrap(),
+                    markers["phase"][i].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // Phase 1 is an interval, phase 2 an interval start without an end.
+        assert_eq!(intervals, vec![(2.0, 3.0, 1), (1.0, 4.0, 1), (6.0, 0.0, 2)]);
     }
 
-    pub fn get_thread_by_tid(&mut self, tid: i32, profile: &mut Profile) -> &mut Thread {
-        if tid == self.pid {
-            return &mut self.main_thread;
+    /// The throttles of a cgroup on several CPUs nest, every process which hit
+    /// the throttle gets the cgroup's throttled periods, and a throttle which
+    /// is still in effect at the end becomes an interval without an end.
+    #[test]
+    fn cfs_throttles_become_markers_on_every_throttled_process() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec![
+                "cpu-clock".to_string(),
+                "sched:sched_cfs_throttle".to_string(),
+                "sched:sched_cfs_unthrottle".to_string(),
+            ],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions::default(),
+        );
+        fork(&mut converter, 100, 100, 0);
+        fork(&mut converter, 200, 200, 0);
+        // The unthrottle at 3ms only undoes one of the two throttles, and the
+        // one at 6ms has no throttle. Unthrottles come from a timer, in any
+        // process.
+        for (pid, timestamp, attr_index) in [
+            (100, 1, 1),
+            (200, 2, 1),
+            (100, 3, 2),
+            (100, 5, 2),
+            (100, 6, 2),
+            (200, 8, 1),
+        ] {
+            let e = sample(pid, pid, timestamp * MS, 0x1234);
+            converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&e, attr_index);
+        }
+
+        let profile = serde_json::to_value(converter.finish()).unwrap();
+        for thread in profile["threads"].as_array().unwrap() {
+            let markers = &thread["markers"];
+            let intervals: Vec<_> = (0..markers["length"].as_u64().unwrap() as usize)
+                .filter(|&i| markers["data"][i]["type"] == "CfsThrottled")
+                .map(|i| {
+                    (
+                        markers["startTime"][i].as_f64().unwrap(),
+                        markers["endTime"][i].as_f64().unwrap(),
+                        markers["phase"][i].as_u64().unwrap(),
+                    )
+                })
+                .collect();
+            assert_eq!(intervals, vec![(1.0, 5.0, 1), (8.0, 0.0, 2)]);
         }
-        self.threads_by_tid.entry(tid).or_insert_with(|| {
-            let profile_thread = profile.add_thread(
-                self.profile_process,
-                tid as u32,
-                Timestamp::from_millis_since_reference(0.0),
-                false,
-            );
-            Thread {
-                profile_thread,
-                context_switch_data: Default::default(),
-                last_sample_timestamp: None,
-                off_cpu_stack: None,
-                name: None,
-            }
-        })
     }
 
-    pub fn remove_non_main_thread(
-        &mut self,
-        tid: i32,
-        time: Timestamp,
-        allow_reuse: bool,
-        profile: &mut Profile,
-    ) {
-        let Some(mut thread) = self.threads_by_tid.remove(&tid) else { return };
-        profile.set_thread_end_time(thread.profile_thread, time);
+    /// The samples of a JVM GC thread get the GC category, and with
+    /// --detect-gc, their run becomes a pause marker on the main thread.
+    #[test]
+    fn gc_thread_samples_get_the_gc_category_and_a_pause_marker() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["cpu-clock".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                detect_gc_pauses: true,
+                ..Default::default()
+            },
+        );
+        fork(&mut converter, 100, 100, 0);
+        fork(&mut converter, 100, 101, 0);
+        converter.set_thread_name(100, 101, "GC Thread#0", false);
+        for timestamp in 1..=5 {
+            converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 101, timestamp * MS, 0x1234));
+        }
 
-        thread.on_remove();
+        let profile = serde_json::to_value(converter.finish()).unwrap();
+        let gc_category = profile["meta"]["categories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .position(|category| category["name"] == "GC")
+            .unwrap();
+        let threads = profile["threads"].as_array().unwrap();
+        let gc_thread = threads
+            .iter()
+            .find(|thread| thread["name"] == "GC Thread#0")
+            .unwrap();
+        let categories = gc_thread["stackTable"]["category"].as_array().unwrap();
+        assert!(!categories.is_empty());
+        assert!(categories.iter().all(|category| *category == gc_category));
 
-        if allow_reuse {
-            if let Some(name) = thread.name.as_deref() {
-                self.ended_threads_for_reuse_by_name
-                    .entry(name.to_owned())
-                    .or_default()
-                    .push_back(thread);
-            }
-        }
+        let main_thread = threads
+            .iter()
+            .find(|thread| thread["isMainThread"] == true)
+            .unwrap();
+        let marker_names: Vec<_> = main_thread["markers"]["name"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|name| &main_thread["stringArray"][name.as_u64().unwrap() as usize])
+            .collect();
+        assert_eq!(marker_names, vec!["GC pause (~4ms)"]);
     }
-}
 
-// A file range in an object file, such as a segment or a section,
-// for which we know the corresponding Stated Virtual Memory Address (SVMA).
-#[derive(Clone)]
-struct SvmaFileRange {
-    svma: u64,
-    file_offset: u64,
-    size: u64,
-}
+    /// A process execs a set-uid binary and we never get its mmap records.
+    /// Kernel samples during the exec don't count; the first user sample
+    /// requests a /proc snapshot, and only once.
+    #[test]
+    fn user_samples_without_mappings_after_exec_request_a_snapshot() {
+        let mut converter = make_converter(false);
+        converter.take_mapping_snapshots = true;
+        converter.handle_thread_name_update(
+            CommOrExecRecord {
+                pid: 100,
+                tid: 100,
+                name: RawData::Single(b"sudo"),
+                is_execve: true,
+            },
+            Some(MS),
+        );
 
-impl SvmaFileRange {
-    pub fn from_segment<'data, S: ObjectSegment<'data>>(segment: S) -> Self {
-        let svma = segment.address();
-        let (file_offset, size) = segment.file_range();
-        SvmaFileRange {
-            svma,
-            file_offset,
-            size,
-        }
+        let mut kernel_sample = sample(100, 100, 2 * MS, 0xffffffff81000000);
+        kernel_sample.cpu_mode = CpuMode::Kernel;
+        converter.handle_sample::<ConvertRegsX86_64>(&kernel_sample);
+        assert!(converter
+            .take_processes_needing_mapping_snapshot()
+            .is_empty());
+
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 3 * MS, 0x1234));
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 4 * MS, 0x1234));
+        assert_eq!(
+            converter.take_processes_needing_mapping_snapshot(),
+            vec![(100, MS)]
+        );
+        converter.handle_mapping_snapshot(100, 3);
+        assert_eq!(
+            converter.processes_with_missing_mappings[0].snapshot_mapping_count,
+            Some(3)
+        );
+        assert!(converter
+            .take_processes_needing_mapping_snapshot()
+            .is_empty());
     }
 
-    pub fn from_section<'data, S: ObjectSection<'data>>(section: S) -> Option<Self> {
-        let svma = section.address();
-        let (file_offset, size) = section.file_range()?;
-        Some(SvmaFileRange {
-            svma,
-            file_offset,
-            size,
-        })
+    /// With --synthesize-from-proc, a process without COMM or MMAP records
+    /// gets its mappings and name from /proc. Here that's the test process
+    /// itself; the other pid doesn't exist.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn processes_without_records_are_synthesized_from_proc() {
+        let mut converter = make_converter(false);
+        converter.proc_synthesis = Some(ProcSynthesis::new(0));
+        let pid = std::process::id() as i32;
+        let missing_pid = 0x3fff_fff0;
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(pid, pid, MS, 0x1234));
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(missing_pid, missing_pid, MS, 0x1234));
+
+        let comm = std::fs::read_to_string("/proc/self/comm").unwrap();
+        let process = converter.processes.get_if_alive(pid).unwrap();
+        assert!(process.executable_mapping_count > 0);
+        assert_eq!(process.name.as_deref(), Some(comm.trim_end()));
+        let missing_process = converter.processes.get_if_alive(missing_pid).unwrap();
+        assert_eq!(missing_process.executable_mapping_count, 0);
+        assert_eq!(missing_process.name, None);
     }
 
-    pub fn encompasses_file_range(&self, other_file_offset: u64, other_file_size: u64) -> bool {
-        let self_file_range_end = self.file_offset + self.size;
-        let other_file_range_end = other_file_offset + other_file_size;
-        self.file_offset <= other_file_offset && other_file_range_end <= self_file_range_end
+    #[test]
+    fn address_space_timeline_has_the_sampled_mappings() {
+        let mut converter = make_converter(false);
+        converter.handle_mmap(
+            MmapRecord {
+                pid: 100,
+                tid: 100,
+                address: 0x10000,
+                length: 0x1000,
+                page_offset: 0,
+                is_executable: true,
+                cpu_mode: CpuMode::User,
+                path: RawData::Single(b"/nonexistent/libfoo.so"),
+            },
+            MS,
+        );
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 2 * MS, 0x10100));
+
+        let (profile, timeline) = converter.finish_with_address_space_timeline();
+        let view = timeline.lookup(100, 0x10100, 2 * MS).unwrap();
+        assert_eq!(profile.lib_info(view.lib).name, "libfoo.so");
+        assert_eq!(view.relative_address, 0x100);
+        assert_eq!(view.kind, MappingKind::Regular);
+        assert_eq!(timeline.lookup(100, 0x10100, 0), None);
     }
 
-    pub fn is_encompassed_by_file_range(
-        &self,
-        other_file_offset: u64,
-        other_file_size: u64,
-    ) -> bool {
-        let self_file_range_end = self.file_offset + self.size;
-        let other_file_range_end = other_file_offset + other_file_size;
-        other_file_offset <= self.file_offset && self_file_range_end <= other_file_range_end
+    /// With 52-bit virtual addresses on arm64, user addresses go up to
+    /// 0x000f_ffff_ffff_ffff. They must only be looked up in the process's
+    /// mappings, even if a kernel mapping covers them, e.g. perf's
+    /// synthesized kernel mapping with kptr_restrict, which starts at zero.
+    #[test]
+    fn high_user_addresses_stay_user_frames() {
+        const LIB_START: u64 = 0x000f_ffff_f000_0000;
+        const UNMAPPED: u64 = 0x000f_ffff_ff00_0000;
+        let mut converter = make_converter(false);
+        kernel_mmap(&mut converter, b"[kernel.kallsyms]_text", 0, u64::MAX);
+        converter.handle_mmap(
+            MmapRecord {
+                pid: 100,
+                tid: 100,
+                address: LIB_START,
+                length: 0x1000,
+                page_offset: 0,
+                is_executable: true,
+                cpu_mode: CpuMode::User,
+                path: RawData::Single(b"/nonexistent/libfoo.so"),
+            },
+            0,
+        );
+
+        let callchain: Vec<u8> = [
+            PERF_CONTEXT_KERNEL,
+            0xffff_8000_0810_0000,
+            PERF_CONTEXT_USER,
+            LIB_START + 0x100,
+            UNMAPPED,
+        ]
+        .iter()
+        .flat_map(|address| address.to_le_bytes())
+        .collect();
+        let mut e = sample(100, 100, MS, 0xffff_8000_0810_0000);
+        e.cpu_mode = CpuMode::Kernel;
+        e.callchain = Some(RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(
+            &callchain,
+        )));
+        let unwinder = UnwinderX86_64::default();
+        let mut cache = CacheX86_64::new();
+        let mut stack = Vec::new();
+        TestConverter::get_sample_stack::<ConvertRegsX86_64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut UnwindValidator::new(UnwindValidation::Off),
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            false,
+            None,
+            None,
+        );
+        assert_eq!(
+            stack,
+            vec![
+                StackFrame::InstructionPointer(0xffff_8000_0810_0000, StackMode::Kernel),
+                StackFrame::ReturnAddress(LIB_START + 0x100, StackMode::User),
+                StackFrame::ReturnAddress(UNMAPPED, StackMode::User),
+            ]
+        );
+
+        converter.handle_sample::<ConvertRegsX86_64>(&e);
+        let profile = serde_json::to_value(converter.finish()).unwrap();
+        let thread = &profile["threads"][0];
+        let frame_libs: Vec<_> = thread["frameTable"]["func"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|func| {
+                let resource = &thread["funcTable"]["resource"][func.as_u64().unwrap() as usize];
+                let lib = resource
+                    .as_u64()
+                    .map(|resource| &thread["resourceTable"]["lib"][resource as usize]);
+                lib.map(|lib| profile["libs"][lib.as_u64().unwrap() as usize]["name"].clone())
+            })
+            .collect();
+        assert_eq!(frame_libs.len(), 3);
+        assert!(frame_libs.contains(&Some("[kernel.kallsyms]".into())));
+        assert!(frame_libs.contains(&Some("libfoo.so".into())));
+        // The unmapped user address isn't attributed to the kernel.
+        assert!(frame_libs.contains(&None));
     }
-}
 
-impl Debug for SvmaFileRange {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("SvmaFileRange")
-            .field("svma", &format!("{:#x}", &self.svma))
-            .field("file_offset", &format!("{:#x}", &self.file_offset))
-            .field("size", &format!("{:#x}", &self.size))
-            .finish()
+    /// A host sample of a vCPU thread whose callchain continues into the
+    /// guest kernel, in a recording without any guest samples. The guest
+    /// frame becomes a placeholder instead of being dropped.
+    #[test]
+    fn guest_frames_of_host_samples_become_placeholders() {
+        use linux_perf_event_reader::constants::PERF_CONTEXT_GUEST_KERNEL;
+
+        let mut converter = make_converter(false);
+        let callchain: Vec<u8> = [
+            PERF_CONTEXT_KERNEL,
+            0xffff_ffff_8100_0000,
+            PERF_CONTEXT_GUEST_KERNEL,
+            0xffff_ffff_8200_0000,
+            PERF_CONTEXT_USER,
+            0x1234,
+        ]
+        .iter()
+        .flat_map(|address| address.to_le_bytes())
+        .collect();
+        let mut e = sample(100, 100, MS, 0xffff_ffff_8100_0000);
+        e.cpu_mode = CpuMode::Kernel;
+        e.callchain = Some(RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(
+            &callchain,
+        )));
+        converter.handle_sample::<ConvertRegsX86_64>(&e);
+        assert!(!converter.have_guest_samples);
+
+        let profile = serde_json::to_value(converter.finish()).unwrap();
+        let thread = &profile["threads"][0];
+        let string = |index: &serde_json::Value| {
+            thread["stringArray"][index.as_u64().unwrap() as usize].clone()
+        };
+        let mut frame_names = Vec::new();
+        let mut stack = thread["samples"]["stack"][0].as_u64();
+        while let Some(stack_index) = stack {
+            let stack_index = stack_index as usize;
+            let frame = thread["stackTable"]["frame"][stack_index].as_u64().unwrap() as usize;
+            let func = thread["frameTable"]["func"][frame].as_u64().unwrap() as usize;
+            frame_names.push(string(&thread["funcTable"]["name"][func]));
+            stack = thread["stackTable"]["prefix"][stack_index].as_u64();
+        }
+        // Unresolved return addresses are shown as the address of the call.
+        assert_eq!(
+            frame_names,
+            vec!["0xffffffff81000000", "[guest kernel code]", "0x1233"]
+        );
     }
-}
 
-/// Compute the bias from the stated virtual memory address (SVMA), the VMA defined in the file's
-/// section table, to the actual virtual memory address (AVMA), the VMA the file is actually mapped
-/// at.
-///
-/// We have the section + segment information of the mapped object file, and we know the file offset
-/// and size of the mapping, as well as the AVMA at the mapping start.
-///
-/// An image is mapped into memory using ELF load commands ("segments"). Usually there are multiple
-/// ELF load commands, resulting in multiple mappings. Some of these mappings will be read-only,
-/// and some will be executable.
-///
-/// Commonly, the executable mapping is the second of four mappings.
-///
-/// If we know about all mappings of an image, then the base AVMA is the start AVMA of the first mapping.
-/// But sometimes we only have one mapping of an image, for example only the second mapping. This
-/// happens if the `perf.data` file only contains mmap information of the executable mappings. In that
-/// case, we have to look at the "page offset" of that mapping and find out which part of the file
-/// was mapped in that range.
-///
-/// In that case it's tempting to say "The AVMA is the address at which the file would start, if
-/// the entire file was mapped contiguously into memory around our mapping." While this works in
-/// many cases, it doesn't work if there are "SVMA gaps" between the segments which have been elided
-/// in the file, i.e. it doesn't work for files where the file offset <-> SVMA translation
-/// is different for each segment.
-///
-/// Easy case A:
-/// ```plain
-/// File offset:  0x0 |----------------| |-------------|
-/// SVMA:         0x0 |----------------| |-------------|
-/// AVMA:   0x1750000 |----------------| |-------------|
-/// ```
-///
-/// Easy case B:
-/// ```plain
-/// File offset:  0x0 |----------------| |-------------|
-/// SVMA:     0x40000 |----------------| |-------------|
-/// AVMA:   0x1750000 |----------------| |-------------|
-/// ```
-///
-/// Hard case:
-/// ```plain
-/// File offset:  0x0 |----------------| |-------------|
-/// SVMA:         0x0 |----------------|         |-------------|
-/// AVMA:   0x1750000 |----------------|         |-------------|
-/// ```
-///
-/// One example of the hard case has been observed in `libxul.so`: The `.text` section
-/// was in the second segment. In the first segment, SVMAs were equal to their
-/// corresponding file offsets. In the second segment, SMAs were 0x1000 bytes higher
-/// than their corresponding file offsets. In other words, there was a 0x1000-wide gap
-/// between the segments in virtual address space, but this gap was omitted in the file.
-/// The SVMA gap exists in the AVMAs too - the "bias" between SVMAs and AVMAs is the same
-/// for all segments of an image. So we have to find the SVMA for the mapping by finding
-/// a segment or section which overlaps the mapping in file offset space, and then use
-/// the matching segment's / section's SVMA to find the SVMA-to-AVMA "bias" for the
-/// mapped bytes.
-///
-/// Another interesting edge case we observed was a case where a mapping was seemingly
-/// not initiated by an ELF LOAD command: Part of the d8 binary (the V8 shell) was mapped
-/// into memory with a mapping that covered only a small part of the `.text` section.
-/// Usually, you'd expect a section to be mapped in its entirety, but this was not the
-/// case here. So the segment finding code below checks for containment both ways: Whether
-/// the mapping is contained in the segment, or whether the segment is contained in the
-/// mapping. We also tried a solution where we just check for overlap between the segment
-/// and the mapping, but this sometimes got the wrong segment, because the mapping is
-/// larger than the segment due to alignment, and can extend into other segments.
-fn compute_vma_bias<'data, 'file, O>(
-    file: &'file O,
-    mapping_start_file_offset: u64,
-    mapping_start_avma: u64,
-    mapping_size: u64,
-) -> Option<u64>
-where
-    'data: 'file,
-    O: Object<'data, 'file>,
-{
-    let mut contributions: Vec<SvmaFileRange> =
-        file.segments().map(SvmaFileRange::from_segment).collect();
+    /// The COMM record of a process which was already running when perf
+    /// started is earlier than the first sample.
+    #[test]
+    fn records_before_the_first_sample_stay_in_range() {
+        let reference_time = reference_timestamp(10 * MS, Some(5 * MS));
+        assert_eq!(reference_time, 5 * MS);
+        let mut converter = make_converter_with_reference(false, reference_time);
+
+        converter.observe_record_timestamp(RecordType::COMM, 5 * MS);
+        comm(&mut converter, 100, 100, b"app", 5 * MS);
+        for timestamp in [10 * MS, 11 * MS, 12 * MS] {
+            converter.observe_record_timestamp(RecordType::SAMPLE, timestamp);
+            converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, timestamp, 0x1234));
+        }
+        assert_eq!(converter.record_timestamps.early_record_count(), 0);
+
+        let timestamp_converter = TimestampConverter::with_reference_timestamp(reference_time);
+        let mut profile = converter.finish();
+        assert_eq!(
+            profile.timestamp_bounds(),
+            Some((
+                timestamp_converter.convert_time(10 * MS),
+                timestamp_converter.convert_time(12 * MS)
+            ))
+        );
+        let range = (
+            timestamp_converter.convert_time(5 * MS),
+            timestamp_converter.convert_time(12 * MS),
+        );
+        assert_eq!(
+            profile.clamp_timestamps(range.0, range.1),
+            ClampedTimestampCounts::default()
+        );
+    }
 
-    if contributions.is_empty() {
-        // If no segment is found, fall back to using section information.
-        // This fallback only exists for the synthetic .so files created by `perf inject --jit`
-        // - those don't have LOAD commands.
-        contributions = file
-            .sections()
-            .filter(|s| s.kind() == SectionKind::Text)
-            .filter_map(SvmaFileRange::from_section)
+    /// With perf record --delay, the records of the running processes are
+    /// synthesized long before the first sample.
+    #[test]
+    fn recording_delays_are_trimmed_and_get_a_marker() {
+        let delay = RecordingDelay::detect(2005 * MS, Some(5 * MS)).unwrap();
+        let mut converter = make_converter_with_reference(false, delay.trimmed_start());
+        converter.set_recording_delay(delay, false);
+
+        converter.observe_record_timestamp(RecordType::COMM, 5 * MS);
+        comm(&mut converter, 100, 100, b"app", 5 * MS);
+        for timestamp in [2005 * MS, 2006 * MS] {
+            converter.observe_record_timestamp(RecordType::SAMPLE, timestamp);
+            converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, timestamp, 0x1234));
+        }
+        assert_eq!(converter.record_timestamps.early_record_count(), 0);
+
+        let profile = converter.finish();
+        assert_eq!(
+            profile.timestamp_bounds(),
+            Some((
+                Timestamp::from_millis_since_reference(1.0),
+                Timestamp::from_millis_since_reference(2.0)
+            ))
+        );
+        let profile = serde_json::to_value(&profile).unwrap();
+        let thread = &profile["threads"][0];
+        let marker_names: Vec<_> = thread["markers"]["name"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|name| &thread["stringArray"][name.as_u64().unwrap() as usize])
             .collect();
+        assert_eq!(marker_names, vec!["Recording started (after 2000ms delay)"]);
     }
 
-    compute_vma_bias_impl(
-        &contributions,
-        mapping_start_file_offset,
-        mapping_start_avma,
-        mapping_size,
-    )
-}
+    #[test]
+    fn leaf_only_keeps_only_the_sampled_address() {
+        let callchain: Vec<u8> = [0x1234u64, 0x5678, 0x9abc]
+            .iter()
+            .flat_map(|address| address.to_le_bytes())
+            .collect();
+        let mut e = sample(100, 100, MS, 0x1234);
+        e.callchain = Some(RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(
+            &callchain,
+        )));
 
-fn compute_vma_bias_impl(
-    contributions: &[SvmaFileRange],
-    mapping_file_offset: u64,
-    mapping_avma: u64,
-    mapping_size: u64,
-) -> Option<u64> {
-    // Find a contribution which either fully contains the mapping, or which is fully contained by the mapping.
-    // Linux perf simply always uses the .text section as the reference contribution.
-    let ref_contribution = if let Some(contribution) = contributions.iter().find(|contribution| {
-        contribution.encompasses_file_range(mapping_file_offset, mapping_size)
-            || contribution.is_encompassed_by_file_range(mapping_file_offset, mapping_size)
-    }) {
-        contribution
-    } else {
-        println!(
-            "Could not find segment or section overlapping the file offset range 0x{:x}..0x{:x}",
-            mapping_file_offset,
-            mapping_file_offset + mapping_size,
+        let unwinder = UnwinderX86_64::default();
+        let mut cache = CacheX86_64::new();
+        let mut stack = Vec::new();
+        TestConverter::get_sample_stack::<ConvertRegsX86_64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut UnwindValidator::new(UnwindValidation::Off),
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            false,
+            None,
+            None,
         );
-        return None;
-    };
+        assert_eq!(stack.len(), 3);
+        TestConverter::get_sample_stack::<ConvertRegsX86_64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut UnwindValidator::new(UnwindValidation::Off),
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            true,
+            None,
+            None,
+        );
+        assert_eq!(
+            stack,
+            vec![StackFrame::InstructionPointer(0x1234, StackMode::User)]
+        );
+    }
 
-    // Compute the AVMA at which the reference contribution is located in process memory.
-    let ref_avma = if ref_contribution.file_offset > mapping_file_offset {
-        mapping_avma + (ref_contribution.file_offset - mapping_file_offset)
-    } else {
-        mapping_avma - (mapping_file_offset - ref_contribution.file_offset)
-    };
+    #[test]
+    fn max_stack_depth_keeps_the_callee_most_frames() {
+        let callchain: Vec<u8> = [0x1234u64, 0x5678, 0x9abc, 0xdef0]
+            .iter()
+            .flat_map(|address| address.to_le_bytes())
+            .collect();
+        let mut e = sample(100, 100, MS, 0x1234);
+        e.callchain = Some(RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(
+            &callchain,
+        )));
 
-    // We have everything we need now.
-    let bias = ref_avma.wrapping_sub(ref_contribution.svma);
-    Some(bias)
-}
+        let unwinder = UnwinderX86_64::default();
+        let mut cache = CacheX86_64::new();
+        let mut stack = Vec::new();
+        TestConverter::get_sample_stack::<ConvertRegsX86_64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut UnwindValidator::new(UnwindValidation::Off),
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            false,
+            Some(2),
+            None,
+        );
+        assert_eq!(
+            stack,
+            vec![
+                StackFrame::InstructionPointer(0x1234, StackMode::User),
+                StackFrame::ReturnAddress(0x5678, StackMode::User),
+                StackFrame::TruncatedStackMarker,
+            ]
+        );
 
-#[test]
-fn test_compute_base_avma_impl() {
-    // From a local build of the Spidermonkey shell ("js")
-    let js_segments = &[
-        SvmaFileRange {
-            svma: 0x0,
-            file_offset: 0x0,
-            size: 0x14bd0bc,
-        },
-        SvmaFileRange {
-            svma: 0x14be0c0,
-            file_offset: 0x14bd0c0,
-            size: 0xf5bf60,
-        },
-        SvmaFileRange {
-            svma: 0x241b020,
-            file_offset: 0x2419020,
-            size: 0x08e920,
-        },
-        SvmaFileRange {
-            svma: 0x24aa940,
-            file_offset: 0x24a7940,
-            size: 0x002d48,
-        },
-    ];
-    assert_eq!(
-        compute_vma_bias_impl(js_segments, 0x14bd0c0, 0x100014be0c0, 0xf5bf60),
-        Some(0x10000000000)
-    );
-    assert_eq!(
-        compute_vma_bias_impl(js_segments, 0x14bd000, 0x55d605384000, 0xf5d000),
-        Some(0x55d603ec6000)
-    );
+        // A stack which fits isn't marked as truncated.
+        TestConverter::get_sample_stack::<ConvertRegsX86_64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut UnwindValidator::new(UnwindValidation::Off),
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            false,
+            Some(4),
+            None,
+        );
+        assert_eq!(stack.len(), 4);
+    }
 
-    // From a local build of the V8 shell ("d8")
-    let d8_segments = &[
-        SvmaFileRange {
-            svma: 0x0,
-            file_offset: 0x0,
-            size: 0x3c8ed8,
-        },
-        SvmaFileRange {
-            svma: 0x03ca000,
-            file_offset: 0x3c9000,
-            size: 0xfec770,
-        },
-        SvmaFileRange {
-            svma: 0x13b7770,
-            file_offset: 0x13b5770,
-            size: 0x0528d0,
-        },
-        SvmaFileRange {
-            svma: 0x140c000,
-            file_offset: 0x1409000,
-            size: 0x0118f0,
-        },
-    ];
-    assert_eq!(
-        compute_vma_bias_impl(d8_segments, 0x1056000, 0x55d15fe80000, 0x180000),
-        Some(0x55d15ee29000)
-    );
-}
+    /// The raw register values as the kernel delivers them: only the
+    /// registers in the mask, in the order of their register numbers.
+    fn raw_regs(values_by_register: &[(u64, u64)]) -> (u64, Vec<u8>) {
+        let mut values_by_register = values_by_register.to_vec();
+        values_by_register.sort();
+        let mask = values_by_register
+            .iter()
+            .fold(0, |mask, (register, _)| mask | 1u64 << register);
+        let bytes = values_by_register
+            .iter()
+            .flat_map(|(_, value)| value.to_le_bytes())
+            .collect();
+        (mask, bytes)
+    }
 
-fn get_pe_mapping_size(path_slice: &[u8]) -> Option<u64> {
-    fn inner<T: ImageNtHeaders>(data: &[u8]) -> Option<u64> {
-        let file = PeFile::<T>::parse(data).ok()?;
-        let size = file.nt_headers().optional_header().size_of_image();
-        Some(size as u64)
+    #[test]
+    fn x86_64_regs_are_found_with_partial_masks() {
+        const PERF_REG_X86_AX: u64 = 0;
+        let (mask, bytes) = raw_regs(&[
+            (PERF_REG_X86_IP, 0x1000),
+            (PERF_REG_X86_SP, 0x7ff0),
+            (PERF_REG_X86_BP, 0x8000),
+            (PERF_REG_X86_AX, 0x42),
+        ]);
+        let regs = Regs::new(
+            mask,
+            RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(&bytes)),
+        );
+        let (ip, sp, unwind_regs) = ConvertRegsX86_64::convert_regs(&regs).unwrap();
+        assert_eq!((ip, sp, unwind_regs.bp()), (0x1000, 0x7ff0, 0x8000));
+
+        // No BP.
+        let (mask, bytes) = raw_regs(&[(PERF_REG_X86_IP, 0x1000), (PERF_REG_X86_SP, 0x7ff0)]);
+        let regs = Regs::new(
+            mask,
+            RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(&bytes)),
+        );
+        assert_eq!(ConvertRegsX86_64::convert_regs(&regs).err(), Some("bp"));
     }
 
-    let path = Path::new(std::str::from_utf8(path_slice).ok()?);
-    let file = std::fs::File::open(path).ok()?;
-    let mmap = unsafe { Mmap::map(&file).ok()? };
+    #[test]
+    fn aarch64_regs_are_found_with_partial_masks() {
+        const PERF_REG_ARM64_X0: u64 = 0;
+        let (mask, bytes) = raw_regs(&[
+            (PERF_REG_ARM64_X0, 0x42),
+            (PERF_REG_ARM64_X29, 0x8000),
+            (PERF_REG_ARM64_LR, 0x2000),
+            (PERF_REG_ARM64_SP, 0x7ff0),
+            (PERF_REG_ARM64_PC, 0x1000),
+        ]);
+        let regs = Regs::new(
+            mask,
+            RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(&bytes)),
+        );
+        let (ip, sp, unwind_regs) = ConvertRegsAarch64::convert_regs(&regs).unwrap();
+        assert_eq!(
+            (ip, sp, unwind_regs.lr(), unwind_regs.fp()),
+            (0x1000, 0x7ff0, 0x2000, 0x8000)
+        );
 
-    match FileKind::parse(&mmap[..]).ok()? {
-        FileKind::Pe32 => inner::<ImageNtHeaders32>(&mmap),
-        FileKind::Pe64 => inner::<ImageNtHeaders64>(&mmap),
-        _ => None,
+        // No LR.
+        let (mask, bytes) = raw_regs(&[
+            (PERF_REG_ARM64_X29, 0x8000),
+            (PERF_REG_ARM64_SP, 0x7ff0),
+            (PERF_REG_ARM64_PC, 0x1000),
+        ]);
+        let regs = Regs::new(
+            mask,
+            RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(&bytes)),
+        );
+        assert_eq!(ConvertRegsAarch64::convert_regs(&regs).err(), Some("lr"));
     }
-}
 
-fn kernel_module_build_id(
-    path: &Path,
-    extra_binary_artifact_dir: Option<&Path>,
-) -> Option<Vec<u8>> {
-    let file = open_file_with_fallback(path, extra_binary_artifact_dir)
-        .ok()?
-        .0;
-    let mmap = unsafe { memmap2::MmapOptions::new().map(&file) }.ok()?;
-    let obj = object::File::parse(&mmap[..]).ok()?;
-    match obj.build_id() {
-        Ok(Some(build_id)) => Some(build_id.to_owned()),
-        _ => None,
+    /// A sample with the given callchain, user registers and user stack.
+    fn sample_with_user_regs<'a>(
+        callchain: &'a [u8],
+        regs_bytes: &'a [u8],
+        mask: u64,
+        user_stack: &'a [u8],
+    ) -> SampleRecord<'a> {
+        let mut e = sample(100, 100, MS, 0x1234);
+        e.callchain = Some(RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(
+            callchain,
+        )));
+        e.user_regs = Some(Regs::new(
+            mask,
+            RawDataU64::from_raw_data::<LittleEndian>(RawData::Single(regs_bytes)),
+        ));
+        e.user_stack = Some((RawData::Single(user_stack), user_stack.len() as u64));
+        e
     }
-}
 
-/// Correct unusable .so files from certain versions of perf which create ELF program
-/// headers but fail to adjust addresses by the program header size.
-///
-/// For these bad files, we create a new, fixed, file, so that the mapping correctly
-/// refers to the location of its .text section.
-///
-/// Background:
-///
-/// If you use `perf record` on applications which output JITDUMP information, such as
-/// the V8 shell, you usually run `perf inject --jit` on your `perf.data` file afterwards.
-/// This command interprets the JITDUMP file, and creates files of the form `jitted-12345-12.so`
-/// which contain the JIT code from the JITDUMP file. There is one file per function.
-/// These files are ELF files which look just enough like regular ELF files so that the
-/// regular perf functionality works with them - unwinding, symbols, line numbers, assembly.
-///
-/// Before September 2022, these files did not contain any ELF program headers ("segments"),
-/// they only contained sections. The files have 0x40 bytes of ELF header, followed by a
-/// .text section at offset and address 0x40, followed by a few other sections, followed by
-/// the section table.
-///
-/// This was changed by commit <https://github.com/torvalds/linux/commit/babd04386b1df8c364cdaa39ac0e54349502e1e5>,
-/// "perf jit: Include program header in ELF files", in September 2022.
-/// There was a bug in this commit, which was fixed by commit <https://github.com/torvalds/linux/commit/89b15d00527b7>,
-/// "perf inject: Fix GEN_ELF_TEXT_OFFSET for jit", in October 2022.
-///
-/// Unfortunately, the first commit made it into a number of perf releases:
-/// 4.19.215+, 5.4.215+, 5.10.145+, 5.15.71+, 5.19.12+, probably 6.0.16, and 6.1.2
-///
-/// The bug in the first commit means that, if you load the jit-ified perf.data file using
-/// `samply load` and use the `jitted-12345-12.so` files as-is, the opened profile will
-/// contain no useful information about JIT functions.
-///
-/// The broken files have a PT_LOAD command with file offset 0 and address 0, and a
-/// .text section with file offset 0x80 and address 0x40. Furthermore, the synthesized
-/// mmap record points at offset 0x40. The function name symbol is at address 0x40.
-///
-/// This means:
-///  - The mapping does not encompass the entire .text section.
-///  - We cannot calculate the image's base address in process memory because the .text
-///    section has a different file-offset-to-address translation (-0x40) than the
-///    PT_LOAD command (0x0). Neither of the translation amounts would give us a
-///    base address that works correctly with the rest of the system: Following the
-///    section might give us correct symbols but bad assembly, and the other way round.
-///
-/// We load these .so files twice: First, during profile conversion, and then later again
-/// during symbolication and also when assembly code is looked up. We need to have a
-/// consistent file on the file system which works with all these consumers.
-///
-/// So this function creates a fixed file and adjusts all fall paths to point to the fixed
-/// file. The fixed file has its program header removed, so that the original symbol address
-/// and mmap record are correct for the file offset and address 0x40.
-/// We could also choose to keep the program header, but then we would need to adjust a
-/// lot more: the mmap record, the symbol addresses, and the debug info.
-fn correct_bad_perf_jit_so_file(
-    file: &std::fs::File,
-    path: &str,
-) -> Option<(std::fs::File, String)> {
-    if !path.contains("/jitted-") || !path.ends_with(".so") {
-        return None;
+    fn callchain_bytes(addresses: &[u64]) -> Vec<u8> {
+        addresses
+            .iter()
+            .flat_map(|address| address.to_le_bytes())
+            .collect()
     }
 
-    let mmap = unsafe { memmap2::MmapOptions::new().map(file) }.ok()?;
-    let obj = object::read::File::parse(&mmap[..]).ok()?;
-    if obj.format() != object::BinaryFormat::Elf {
-        return None;
+    #[test]
+    fn samples_without_unwind_regs_keep_their_callchain() {
+        let callchain = callchain_bytes(&[0x1234, 0x5678]);
+        // SP, but no BP, as for some samples taken during a syscall.
+        let (mask, regs_bytes) = raw_regs(&[(PERF_REG_X86_IP, 0x1234), (PERF_REG_X86_SP, 0x7ff0)]);
+        let user_stack = [0u8; 64];
+        let e = sample_with_user_regs(&callchain, &regs_bytes, mask, &user_stack);
+
+        let unwinder = UnwinderX86_64::default();
+        let mut cache = CacheX86_64::new();
+        let mut validator = UnwindValidator::new(UnwindValidation::Off);
+        let mut stack = Vec::new();
+        TestConverter::get_sample_stack::<ConvertRegsX86_64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut validator,
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            false,
+            None,
+            None,
+        );
+        assert_eq!(
+            stack,
+            vec![
+                StackFrame::InstructionPointer(0x1234, StackMode::User),
+                StackFrame::ReturnAddress(0x5678, StackMode::User),
+            ]
+        );
+        assert_eq!(
+            validator.skipped_unwind_counts,
+            BTreeMap::from([(("x86_64", SkippedUnwindReason::MissingRegister("bp")), 1)])
+        );
     }
 
-    // The bad files have exactly one segment, with offset 0x0 and address 0x0.
-    let segment = obj.segments().next()?;
-    if segment.address() != 0 || segment.file_range().0 != 0 {
-        return None;
+    #[test]
+    fn aarch64_samples_without_unwind_regs_keep_their_callchain() {
+        let callchain = callchain_bytes(&[0x1234, 0x5678]);
+        let (mask, regs_bytes) = raw_regs(&[
+            (PERF_REG_ARM64_LR, 0x5678),
+            (PERF_REG_ARM64_SP, 0x7ff0),
+            (PERF_REG_ARM64_PC, 0x1234),
+        ]);
+        let user_stack = [0u8; 64];
+        let e = sample_with_user_regs(&callchain, &regs_bytes, mask, &user_stack);
+
+        let unwinder = UnwinderAarch64::default();
+        let mut cache = CacheAarch64::new();
+        let mut validator = UnwindValidator::new(UnwindValidation::Off);
+        let mut stack = Vec::new();
+        Converter::<UnwinderAarch64<ModuleData>>::get_sample_stack::<ConvertRegsAarch64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut validator,
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            false,
+            None,
+            None,
+        );
+        assert_eq!(
+            stack,
+            vec![
+                StackFrame::InstructionPointer(0x1234, StackMode::User),
+                StackFrame::ReturnAddress(0x5678, StackMode::User),
+            ]
+        );
+        assert_eq!(
+            validator.skipped_unwind_counts,
+            BTreeMap::from([(("aarch64", SkippedUnwindReason::MissingRegister("x29")), 1)])
+        );
     }
 
-    // The bad files have a .text section with offset 0x80 and address 0x40 (on x86_64).
-    let text_section = obj.section_by_name(".text")?;
-    if text_section.file_range()?.0 == text_section.address() {
-        return None;
+    #[test]
+    fn samples_with_an_empty_user_stack_are_not_unwound() {
+        let callchain = callchain_bytes(&[0x1234, 0x5678]);
+        let (mask, regs_bytes) = raw_regs(&[
+            (PERF_REG_X86_IP, 0x1234),
+            (PERF_REG_X86_SP, 0x7ff0),
+            (PERF_REG_X86_BP, 0x8000),
+        ]);
+        let e = sample_with_user_regs(&callchain, &regs_bytes, mask, &[]);
+
+        let unwinder = UnwinderX86_64::default();
+        let mut cache = CacheX86_64::new();
+        let mut validator = UnwindValidator::new(UnwindValidation::Off);
+        let mut stack = Vec::new();
+        TestConverter::get_sample_stack::<ConvertRegsX86_64>(
+            &e,
+            &unwinder,
+            &CodeRanges::default(),
+            &mut validator,
+            &mut cache,
+            &mut stack,
+            &[],
+            false,
+            false,
+            None,
+            None,
+        );
+        assert_eq!(stack.len(), 2);
+        assert_eq!(
+            validator.skipped_unwind_counts,
+            BTreeMap::from([(("x86_64", SkippedUnwindReason::EmptyUserStack), 1)])
+        );
     }
 
-    // All right, we have one of the broken files!
-
-    // Let's make it right.
-    let fixed_data = if obj.is_64() {
-        object_rewriter::drop_phdr::<object::elf::FileHeader64<object::Endianness>>(&mmap[..])
-            .ok()?
-    } else {
-        object_rewriter::drop_phdr::<object::elf::FileHeader32<object::Endianness>>(&mmap[..])
-            .ok()?
-    };
-    let mut fixed_path = path.strip_suffix(".so").unwrap().to_string();
-    fixed_path.push_str("-fixed.so");
+    /// CPU 1 was sampled at a tenth of the rate of CPU 0, so with
+    /// --normalize-cpu-rates, its samples weigh ten times as much.
+    #[test]
+    fn normalized_cpu_rates_weight_samples_by_their_cpu_interval() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["cpu-clock".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                normalize_cpu_rates: true,
+                ..Default::default()
+            },
+        );
+        fork(&mut converter, 100, 101, 0);
+        fork(&mut converter, 100, 102, 0);
+        for i in 1..=30 {
+            let mut e = sample(100, 101, i * MS, 0x1234);
+            e.cpu = Some(0);
+            converter.handle_sample::<ConvertRegsX86_64>(&e);
+            if i % 10 == 0 {
+                let mut e = sample(100, 102, i * MS, 0x1234);
+                e.cpu = Some(1);
+                converter.handle_sample::<ConvertRegsX86_64>(&e);
+            }
+        }
 
-    std::fs::write(&fixed_path, fixed_data).ok()?;
+        let weights = |tid| -> Vec<i32> {
+            thread_samples(&converter, 100, tid)
+                .into_iter()
+                .map(|(_, weight)| weight)
+                .collect()
+        };
+        assert_eq!(weights(101), vec![1; 30]);
+        assert_eq!(weights(102), vec![1, 10, 10]);
+    }
 
-    // Open the fixed file for reading, and return it.
-    let fixed_file = std::fs::File::open(&fixed_path).ok()?;
+    /// Records whose sample format lacks the pid, tid or timestamp are
+    /// counted and skipped, rather than aborting the conversion.
+    #[test]
+    fn records_without_pids_are_skipped() {
+        let mut converter = make_converter(false);
+        let without_pid = SampleRecord {
+            pid: None,
+            ..sample(100, 100, MS, 0x1234)
+        };
+        let without_tid = SampleRecord {
+            tid: None,
+            ..sample(100, 100, MS, 0x1234)
+        };
+        converter.handle_sample::<ConvertRegsX86_64>(&without_pid);
+        converter.handle_sample::<ConvertRegsX86_64>(&without_tid);
+        assert!(converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&without_tid, 1));
+        converter.handle_other_event_sample::<ConvertRegsX86_64>(&without_pid, 1);
+        converter.handle_context_switch(
+            ContextSwitchRecord::In {
+                prev_pid: None,
+                prev_tid: None,
+            },
+            CommonData {
+                tid: None,
+                ..common(100, 100, 2 * MS)
+            },
+        );
+        converter.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 3 * MS, 0x1234));
 
-    Some((fixed_file, fixed_path))
-}
+        assert_eq!(
+            converter.skipped_sample_counts,
+            BTreeMap::from([(0, 2), (1, 2)])
+        );
+        assert_eq!(converter.skipped_context_switch_count, 1);
+        let process = &converter.processes.processes_by_pid[&100];
+        assert_eq!(process.unresolved_samples.clone().into_inner().len(), 1);
+        converter.finish();
+    }
 
-/// Resident file mapping pages
-#[allow(unused)]
-const MM_FILEPAGES: i32 = 0;
+    /// In a recording of only tracepoints, the off-CPU samples are added
+    /// right away, with a weight per off-CPU interval, because there are no
+    /// samples which could bring the resumed stack.
+    #[test]
+    fn marker_only_recordings_get_off_cpu_samples() {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "sched:sched_switch".to_string(),
+            sampling_is_time_based: None,
+            have_context_switches: true,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["sched:sched_switch".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: true,
+            event_track_attr_indexes: Vec::new(),
+        };
+        let mut converter = Converter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions::default(),
+        );
+        fork(&mut converter, 100, 101, 0);
+        unblock(&mut converter, 100, 101, 0);
+        let sched_switch = sample(100, 101, MS, 0x1234);
+        assert!(converter.handle_tracepoint_sample::<ConvertRegsX86_64>(&sched_switch, 0));
+        converter.handle_context_switch(
+            ContextSwitchRecord::Out {
+                next_pid: None,
+                next_tid: None,
+                preempted: TaskWasPreempted::No,
+            },
+            common(100, 101, MS),
+        );
+        unblock(&mut converter, 100, 101, 5 * MS);
 
-/// Resident anonymous pages
-#[allow(unused)]
-const MM_ANONPAGES: i32 = 1;
+        assert_eq!(converter.deferred_off_cpu_group_count, 0);
+        let samples = thread_samples(&converter, 100, 101);
+        let total_weight: i32 = samples.iter().map(|(_, weight)| weight).sum();
+        assert_eq!(total_weight, 4);
+        converter.finish();
+    }
 
-/// Anonymous swap entries
-#[allow(unused)]
-const MM_SWAPENTS: i32 = 2;
+    /// In a recording of cycles and instructions, the instructions samples of
+    /// all threads go on one track of their process, weighted by their
+    /// period, and the main thread's samples are unchanged.
+    #[test]
+    fn additional_sampled_events_get_their_own_track() {
+        let mut converter = make_converter(false);
+        converter.event_names.push("instructions".to_string());
+        fork(&mut converter, 100, 101, 0);
+        let mut instructions = sample(100, 101, MS, 0x1234);
+        instructions.period = Some(5000);
+        converter.handle_event_track_sample::<ConvertRegsX86_64>(&instructions, 2);
+        let mut instructions = sample(100, 100, 2 * MS, 0x1234);
+        instructions.period = Some(3000);
+        converter.handle_event_track_sample::<ConvertRegsX86_64>(&instructions, 2);
+
+        let process = &converter.processes.processes_by_pid[&100];
+        let track = process.threads.event_track_threads[&2];
+        let weights: Vec<i32> = process
+            .unresolved_samples
+            .clone()
+            .into_inner()
+            .into_iter()
+            .filter(|s| s.thread_handle == track)
+            .filter_map(|s| match s.sample_or_marker {
+                SampleOrMarker::Sample(SampleData { weight, .. }) => Some(weight),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(weights, vec![5000, 3000]);
+        assert!(thread_samples(&converter, 100, 101).is_empty());
+        converter.finish();
+    }
 
-/// Resident shared memory pages
-#[allow(unused)]
-const MM_SHMEMPAGES: i32 = 3;
+    /// The events of a later input get their own attribute indexes, and its
+    /// timestamps are converted with its own reference.
+    #[test]
+    fn later_inputs_get_their_own_attr_indexes() {
+        let mut converter = make_converter(false);
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 1,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: false,
+            frequency_event_attr_indexes: Some((2, 3)),
+            event_names: vec![
+                "sched:sched_switch".to_string(),
+                "cpu-clock".to_string(),
+                "cycles".to_string(),
+                "ref-cycles".to_string(),
+            ],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: vec![2, 3],
+        };
+        let offset = converter.start_next_input(
+            HashMap::new(),
+            Endianness::LittleEndian,
+            &interpretation,
+            TracepointFormats::default(),
+            TimestampConverter::with_reference_timestamp(10 * MS),
+        );
+        assert_eq!(offset, 2);
+        assert_eq!(converter.main_event_attr_index, 3);
+        assert!(!converter.have_context_switches);
+        assert_eq!(converter.event_names[2], "sched:sched_switch");
+        let sched_switch_handlers = &converter.tracepoint_handler_indexes_by_attr_index;
+        assert_eq!(sched_switch_handlers[2], sched_switch_handlers[1]);
+        assert!(sched_switch_handlers[3].is_empty());
+        assert_eq!(
+            converter.timestamp_converter.convert_time(11 * MS),
+            Timestamp::from_millis_since_reference(1.0)
+        );
 
-/// ```
-/// # cat /sys/kernel/debug/tracing/events/kmem/rss_stat/format
-/// name: rss_stat
-/// ID: 537
-/// format:
-///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
-///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
-///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
-///         field:int common_pid;   offset:4;       size:4; signed:1;
-///
-///         field:unsigned int mm_id;       offset:8;       size:4; signed:0;
-///         field:unsigned int curr;        offset:12;      size:4; signed:0;
-///         field:int member;       offset:16;      size:4; signed:1;
-///         field:long size;        offset:24;      size:8; signed:1;
-///
-/// print fmt: "mm_id=%u curr=%d type=%s size=%ldB", REC->mm_id, REC->curr, __print_symbolic(REC->member, { 0, "MM_FILEPAGES" }, { 1, "MM_ANONPAGES" }, { 2, "MM_SWAPENTS" }, { 3, "MM_SHMEMPAGES" }), REC->size
-/// ```
-#[repr(C)]
-#[derive(Debug)]
-struct RssStat {
-    common_type: u16,
-    common_flags: u8,
-    common_preempt_count: u8,
-    common_pid: i32,
-    mm_id: u32,
-    curr: u32,
-    member: i32,
-    size: i64,
-}
+        let shifted = interpretation.with_attr_index_offset(offset);
+        assert_eq!(shifted.main_event_attr_index, 3);
+        assert_eq!(shifted.frequency_event_attr_indexes, Some((4, 5)));
+        assert_eq!(shifted.event_track_attr_indexes, vec![4, 5]);
+    }
 
-impl RssStat {
-    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
-        match endian {
-            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
-            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
-        }
-    }
-
-    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
-        let common_type = data.read_u16::<O>()?;
-        let common_flags = data.read_u8()?;
-        let common_preempt_count = data.read_u8()?;
-        let common_pid = data.read_i32::<O>()?;
-        let mm_id = data.read_u32::<O>()?;
-        let curr = data.read_u32::<O>()?;
-        let member = data.read_i32::<O>()?;
-        let _padding = data.read_u32::<O>()?;
-        let size = data.read_u64::<O>()? as i64;
-        Ok(RssStat {
-            common_type,
-            common_flags,
-            common_preempt_count,
-            common_pid,
-            mm_id,
-            curr,
-            member,
-            size,
-        })
+    fn make_rotating_converter() -> TestConverter {
+        let interpretation = EventInterpretation {
+            main_event_attr_index: 0,
+            main_event_name: "cpu-clock".to_string(),
+            sampling_is_time_based: Some(MS),
+            have_context_switches: true,
+            frequency_event_attr_indexes: None,
+            event_names: vec!["cpu-clock".to_string(), "sched:sched_switch".to_string()],
+            clock: TimestampClock::Monotonic,
+            marker_only: false,
+            event_track_attr_indexes: Vec::new(),
+        };
+        TestConverter::new(
+            "test",
+            None,
+            HashMap::new(),
+            None,
+            0,
+            Endianness::LittleEndian,
+            CacheX86_64::new(),
+            None,
+            interpretation,
+            ConversionOptions {
+                track_mappings_for_checkpoints: true,
+                ..Default::default()
+            },
+        )
     }
-}
 
-fn get_path_if_jitdump(path: &[u8]) -> Option<&Path> {
-    let path = Path::new(std::str::from_utf8(path).ok()?);
-    let filename = path.file_name()?.to_str()?;
-    if filename.starts_with("jit-") && filename.ends_with(".dump") {
-        Some(path)
-    } else {
-        None
+    /// A rotating recording is written as three files, and the library is
+    /// only mapped during the first one. The third file still resolves its
+    /// addresses, and the blocked thread stays off-CPU across the files.
+    #[test]
+    fn checkpoints_carry_mappings_and_blocked_threads_into_later_files() {
+        let mut first = make_rotating_converter();
+        fork(&mut first, 100, 100, 0);
+        comm(&mut first, 100, 100, b"app", 0);
+        fork(&mut first, 100, 101, 0);
+        comm(&mut first, 100, 101, b"worker", 0);
+        first.handle_mmap(
+            MmapRecord {
+                pid: 100,
+                tid: 100,
+                address: 0x10000,
+                length: 0x1000,
+                page_offset: 0,
+                is_executable: true,
+                cpu_mode: CpuMode::User,
+                path: RawData::Single(b"/nonexistent/libfoo.so"),
+            },
+            MS,
+        );
+        first.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 2 * MS, 0x10100));
+        block(&mut first, 100, 101, 3 * MS);
+        let first_checkpoint = first.checkpoint();
+        first.cut_at_checkpoint(10 * MS);
+        first.finish();
+
+        let mut second = make_rotating_converter();
+        second.restore_checkpoint(&first_checkpoint, 10 * MS);
+        second.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 12 * MS, 0x10200));
+        let second_checkpoint = second.checkpoint();
+        second.cut_at_checkpoint(20 * MS);
+        second.finish();
+
+        let worker_checkpoint = |checkpoint: &ConverterCheckpoint| {
+            let process = &checkpoint.processes[0];
+            assert_eq!(process.name.as_deref(), Some("app"));
+            let worker = process.threads.iter().find(|t| t.tid == 101).unwrap();
+            assert_eq!(worker.name.as_deref(), Some("worker"));
+            worker.off_cpu_stack.clone()
+        };
+        assert!(worker_checkpoint(&first_checkpoint).is_some());
+        assert_eq!(
+            worker_checkpoint(&second_checkpoint),
+            worker_checkpoint(&first_checkpoint)
+        );
+        assert_eq!(second_checkpoint.processes[0].mappings.len(), 1);
+
+        let mut third = make_rotating_converter();
+        third.restore_checkpoint(&second_checkpoint, 20 * MS);
+        third.handle_sample::<ConvertRegsX86_64>(&sample(100, 100, 21 * MS, 0x10100));
+        let (profile, timeline) = third.finish_with_address_space_timeline();
+        let view = timeline.lookup(100, 0x10100, 21 * MS).unwrap();
+        assert_eq!(profile.lib_info(view.lib).name, "libfoo.so");
+        assert_eq!(view.relative_address, 0x100);
     }
 }
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use object::{Object, ObjectSection, ObjectSegment};
+use wholesym::samply_symbols::object;
+
+/// The type of the byte buffers of unwinder modules. Every process which maps
+/// the same binary gets its own `framehop::Module`, but they all share one
+/// copy of the binary's unwind data and text bytes.
+pub type ModuleData = Arc<[u8]>;
+
+/// The bytes of a binary which the unwinder needs.
+#[derive(Debug, Clone, Default)]
+pub struct ModuleSectionData {
+    pub eh_frame: Option<ModuleData>,
+    pub eh_frame_hdr: Option<ModuleData>,
+    /// The bytes of the text segment or section, with the file offset at
+    /// which they start.
+    pub text: Option<(ModuleData, u64)>,
+}
+
+impl ModuleSectionData {
+    pub fn from_object<'data: 'file, 'file>(file: &'file impl Object<'data, 'file>) -> Self {
+        fn section_data<'a>(section: &impl ObjectSection<'a>) -> Option<ModuleData> {
+            section.uncompressed_data().ok().map(|data| data.into())
+        }
+
+        let eh_frame = file.section_by_name(".eh_frame");
+        let eh_frame_hdr = file.section_by_name(".eh_frame_hdr");
+        let text = if let Some(text_segment) = file
+            .segments()
+            .find(|segment| segment.name_bytes() == Ok(Some(b"__TEXT")))
+        {
+            let (start, _size) = text_segment.file_range();
+            text_segment.data().ok().map(|data| (data.into(), start))
+        } else if let Some(text_section) = file.section_by_name(".text") {
+            match text_section.file_range() {
+                Some((start, _size)) => text_section.data().ok().map(|data| (data.into(), start)),
+                None => None,
+            }
+        } else {
+            None
+        };
+        Self {
+            eh_frame: eh_frame.as_ref().and_then(section_data),
+            eh_frame_hdr: eh_frame_hdr.as_ref().and_then(section_data),
+            text,
+        }
+    }
+}
+
+/// Caches the `ModuleSectionData` of each binary by path and build ID, so that
+/// a binary which is mapped into many processes, like libc in a system-wide
+/// profile, is only copied once.
+///
+/// Binaries without a build ID aren't cached: the file at a path can be
+/// replaced during a recording, and without a build ID we couldn't tell that
+/// the cached data belongs to the old file.
+#[derive(Debug, Default)]
+pub struct ModuleDataCache {
+    entries: HashMap<(String, Vec<u8>), Arc<ModuleSectionData>>,
+}
+
+impl ModuleDataCache {
+    pub fn get_or_insert_with(
+        &mut self,
+        path: &str,
+        build_id: Option<&[u8]>,
+        f: impl FnOnce() -> ModuleSectionData,
+    ) -> Arc<ModuleSectionData> {
+        let Some(build_id) = build_id else {
+            return Arc::new(f());
+        };
+        self.entries
+            .entry((path.to_owned(), build_id.to_owned()))
+            .or_insert_with(|| Arc::new(f()))
+            .clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shares_data_between_mappings_of_the_same_binary() {
+        let mut cache = ModuleDataCache::default();
+        let load = || ModuleSectionData {
+            eh_frame: Some(vec![1, 2, 3].into()),
+            eh_frame_hdr: None,
+            text: Some((vec![0x90; 16].into(), 0x1000)),
+        };
+        let first = cache.get_or_insert_with("/usr/lib/libc.so.6", Some(&[0xab]), load);
+        let second = cache.get_or_insert_with("/usr/lib/libc.so.6", Some(&[0xab]), || {
+            panic!("should have been cached")
+        });
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(
+            first.eh_frame.as_ref().unwrap(),
+            second.eh_frame.as_ref().unwrap()
+        ));
+
+        // A different build of the same path is loaded separately.
+        let rebuilt = cache.get_or_insert_with("/usr/lib/libc.so.6", Some(&[0xcd]), load);
+        assert!(!Arc::ptr_eq(&first, &rebuilt));
+    }
+
+    #[test]
+    fn binaries_without_build_id_are_not_shared() {
+        let mut cache = ModuleDataCache::default();
+        let load = || ModuleSectionData {
+            eh_frame: Some(vec![1, 2, 3].into()),
+            ..Default::default()
+        };
+        let first = cache.get_or_insert_with("/opt/app/bin/server", None, load);
+        let second = cache.get_or_insert_with("/opt/app/bin/server", None, load);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use fxprof_processed_profile::Profile;
+use linux_perf_data::Endianness;
+
+use crate::shared::address_space_timeline::AddressSpaceTimeline;
+use crate::shared::timestamp_converter::TimestampConverter;
+
+/// The bits of the `mem_op` field of `perf_mem_data_src`.
+const PERF_MEM_OP_LOAD: u64 = 0x02;
+const PERF_MEM_OP_STORE: u64 = 0x04;
+
+/// The bits of the `mem_lvl` field, which starts at bit 5.
+const PERF_MEM_LVL_SHIFT: u32 = 5;
+const PERF_MEM_LVL_L1: u64 = 0x08;
+const PERF_MEM_LVL_LFB: u64 = 0x10;
+const PERF_MEM_LVL_L2: u64 = 0x20;
+const PERF_MEM_LVL_L3: u64 = 0x40;
+const PERF_MEM_LVL_LOC_RAM: u64 = 0x80;
+const PERF_MEM_LVL_REM_RAM1: u64 = 0x100;
+const PERF_MEM_LVL_REM_RAM2: u64 = 0x200;
+const PERF_MEM_LVL_REM_CCE1: u64 = 0x400;
+const PERF_MEM_LVL_REM_CCE2: u64 = 0x800;
+
+/// The `mem_lvl_num` field, which newer kernels set instead of `mem_lvl`,
+/// and the `mem_remote` bit which goes with it.
+const PERF_MEM_LVLNUM_SHIFT: u32 = 33;
+const PERF_MEM_LVLNUM_NA: u64 = 0x0f;
+const PERF_MEM_REMOTE_SHIFT: u32 = 37;
+
+/// The number of memory accesses listed per process and per code location in
+/// the report.
+const REPORT_TOP_COUNT: usize = 10;
+
+/// The NUMA node of each CPU, from the HEADER_NUMA_TOPOLOGY feature section
+/// of a perf.data file.
+#[derive(Debug, Clone, Default)]
+pub struct NumaTopology {
+    node_by_cpu: HashMap<u32, u32>,
+}
+
+impl NumaTopology {
+    /// The section has the number of nodes, and for each node its ID, its
+    /// total and free memory, and the list of its CPUs, e.g. "0-3,8-11".
+    pub fn parse(data: &[u8], endian: Endianness) -> Option<Self> {
+        let mut reader = HeaderReader { data, endian };
+        let mut node_by_cpu = HashMap::new();
+        let node_count = reader.u32()?;
+        for _ in 0..node_count {
+            let node = reader.u32()?;
+            let _mem_total = reader.u64()?;
+            let _mem_free = reader.u64()?;
+            for cpu in parse_cpu_list(&reader.string()?)? {
+                node_by_cpu.insert(cpu, node);
+            }
+        }
+        Some(Self { node_by_cpu })
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.node_by_cpu.values().collect::<BTreeSet<_>>().len()
+    }
+
+    pub fn node_of_cpu(&self, cpu: u32) -> Option<u32> {
+        self.node_by_cpu.get(&cpu).copied()
+    }
+}
+
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    endian: Endianness,
+}
+
+impl HeaderReader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.data.get(..N)?.try_into().unwrap();
+        self.data = &self.data[N..];
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes()?;
+        Some(match self.endian {
+            Endianness::LittleEndian => u32::from_le_bytes(bytes),
+            Endianness::BigEndian => u32::from_be_bytes(bytes),
+        })
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let bytes = self.bytes()?;
+        Some(match self.endian {
+            Endianness::LittleEndian => u64::from_le_bytes(bytes),
+            Endianness::BigEndian => u64::from_be_bytes(bytes),
+        })
+    }
+
+    /// A string is its padded length followed by the nul-padded bytes.
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.data.get(..len)?;
+        self.data = &self.data[len..];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+/// Parses a CPU list in the format of /sys/devices/system/node/node0/cpulist,
+/// e.g. "0-3,8-11".
+fn parse_cpu_list(list: &str) -> Option<Vec<u32>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => cpus.extend(first.parse::<u32>().ok()?..=last.parse().ok()?),
+            None => cpus.push(range.parse::<u32>().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+/// Whether a memory access was served by the accessing CPU's own node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAccessLocality {
+    Local,
+    Remote,
+}
+
+/// Classifies a load or store by the `perf_mem_data_src` of its sample, which
+/// PEBS and similar hardware record for memory access events such as
+/// "mem-loads". Returns None if the sample isn't a load or store, or if the
+/// hardware didn't record where the data came from.
+pub fn mem_access_locality(data_src: u64) -> Option<MemAccessLocality> {
+    if data_src & (PERF_MEM_OP_LOAD | PERF_MEM_OP_STORE) == 0 {
+        return None;
+    }
+    let lvl = (data_src >> PERF_MEM_LVL_SHIFT) & 0x3fff;
+    let lvl_num = (data_src >> PERF_MEM_LVLNUM_SHIFT) & 0xf;
+    let remote = (data_src >> PERF_MEM_REMOTE_SHIFT) & 1 != 0;
+    let remote_lvls = PERF_MEM_LVL_REM_RAM1
+        | PERF_MEM_LVL_REM_RAM2
+        | PERF_MEM_LVL_REM_CCE1
+        | PERF_MEM_LVL_REM_CCE2;
+    let local_lvls = PERF_MEM_LVL_L1
+        | PERF_MEM_LVL_LFB
+        | PERF_MEM_LVL_L2
+        | PERF_MEM_LVL_L3
+        | PERF_MEM_LVL_LOC_RAM;
+    if remote || lvl & remote_lvls != 0 {
+        Some(MemAccessLocality::Remote)
+    } else if lvl & local_lvls != 0 || (lvl_num != 0 && lvl_num != PERF_MEM_LVLNUM_NA) {
+        Some(MemAccessLocality::Local)
+    } else {
+        None
+    }
+}
+
+/// Counts the samples which ran on each NUMA node in fixed-size time buckets,
+/// for one counter track per node.
+#[derive(Debug, Clone)]
+pub struct NodeSampleCounts {
+    topology: NumaTopology,
+    bucket_duration_ns: u64,
+    counts: BTreeMap<(u32, u64), u64>,
+}
+
+impl NodeSampleCounts {
+    pub fn new(topology: NumaTopology, bucket_duration_ns: u64) -> Self {
+        Self {
+            topology,
+            bucket_duration_ns,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, cpu: u32, timestamp: u64) {
+        if let Some(node) = self.topology.node_of_cpu(cpu) {
+            let bucket_index = timestamp / self.bucket_duration_ns;
+            *self.counts.entry((node, bucket_index)).or_default() += 1;
+        }
+    }
+
+    /// Returns, for each node, the sample count of every bucket from the
+    /// first to the last bucket with samples on any node, with the bucket
+    /// start timestamp.
+    pub fn counts_by_node(&self) -> BTreeMap<u32, Vec<(u64, u64)>> {
+        let mut counts_by_node = BTreeMap::new();
+        let buckets: BTreeSet<u64> = self.counts.keys().map(|&(_, bucket)| bucket).collect();
+        let (Some(&first), Some(&last)) = (buckets.iter().next(), buckets.iter().next_back())
+        else {
+            return counts_by_node;
+        };
+        let nodes: BTreeSet<u32> = self.counts.keys().map(|&(node, _)| node).collect();
+        for node in nodes {
+            let counts = (first..=last)
+                .map(|bucket| {
+                    let count = self.counts.get(&(node, bucket)).copied().unwrap_or(0);
+                    (bucket * self.bucket_duration_ns, count)
+                })
+                .collect();
+            counts_by_node.insert(node, counts);
+        }
+        counts_by_node
+    }
+
+    /// Adds one "samples" counter per NUMA node, attached to a separate
+    /// "NUMA nodes" process.
+    pub fn add_counters(&self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        let counts_by_node = self.counts_by_node();
+        let Some(first_timestamp) = counts_by_node
+            .values()
+            .filter_map(|counts| counts.first())
+            .map(|(t, _)| *t)
+            .min()
+        else {
+            return;
+        };
+        let start_time = timestamp_converter.convert_time(first_timestamp);
+        let process = profile.add_process("NUMA nodes", 0, start_time);
+        let thread = profile.add_thread(process, 0, start_time, true);
+        profile.set_thread_name(thread, "NUMA nodes");
+        for (node, counts) in counts_by_node {
+            let counter = profile.add_counter(
+                process,
+                &format!("NUMA node {node} samples"),
+                "NUMA",
+                &format!("The number of samples on the CPUs of NUMA node {node}"),
+            );
+            for (timestamp, count) in counts {
+                let timestamp = timestamp_converter.convert_time(timestamp);
+                profile.add_counter_sample(counter, timestamp, count as f64, count as u32);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct AccessCounts {
+    local: u64,
+    remote: u64,
+}
+
+impl AccessCounts {
+    fn add(&mut self, locality: MemAccessLocality) {
+        match locality {
+            MemAccessLocality::Local => self.local += 1,
+            MemAccessLocality::Remote => self.remote += 1,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.local + self.remote
+    }
+
+    fn remote_percentage(&self) -> f64 {
+        self.remote as f64 * 100.0 / self.total().max(1) as f64
+    }
+}
+
+/// Local and remote memory access counts per process and per code location,
+/// from the samples of memory access events, for the conversion report.
+///
+/// The code locations are the sampled instruction addresses. They're
+/// attributed to a library and relative address at the end of the
+/// conversion; symbols aren't known at that point.
+#[derive(Debug, Clone, Default)]
+pub struct MemAccessStats {
+    by_pid: HashMap<i32, (Option<String>, AccessCounts)>,
+    /// By pid and instruction address, with the timestamp of the first access.
+    by_location: HashMap<(i32, u64), (u64, AccessCounts)>,
+    unknown_count: u64,
+}
+
+impl MemAccessStats {
+    pub fn add_sample(
+        &mut self,
+        pid: i32,
+        process_name: Option<&str>,
+        ip: Option<u64>,
+        timestamp: u64,
+        data_src: u64,
+    ) {
+        let Some(locality) = mem_access_locality(data_src) else {
+            self.unknown_count += 1;
+            return;
+        };
+        let (name, counts) = self.by_pid.entry(pid).or_default();
+        if name.is_none() {
+            *name = process_name.map(ToOwned::to_owned);
+        }
+        counts.add(locality);
+        if let Some(ip) = ip {
+            let (_, counts) = self
+                .by_location
+                .entry((pid, ip))
+                .or_insert((timestamp, AccessCounts::default()));
+            counts.add(locality);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_pid.is_empty() && self.unknown_count == 0
+    }
+
+    /// Prints the totals, and the processes and code locations with the most
+    /// remote accesses. `timeline` is used to find the library of each code
+    /// location.
+    pub fn report(&self, timeline: Option<&AddressSpaceTimeline>, profile: &Profile) {
+        if self.is_empty() {
+            return;
+        }
+        let mut total = AccessCounts::default();
+        for (_, counts) in self.by_pid.values() {
+            total.local += counts.local;
+            total.remote += counts.remote;
+        }
+        eprintln!(
+            "Memory accesses: {} local, {} remote ({:.1}%), {} with an unknown data source.",
+            total.local,
+            total.remote,
+            total.remote_percentage(),
+            self.unknown_count
+        );
+        if total.remote == 0 {
+            return;
+        }
+
+        let mut processes: Vec<_> = self.by_pid.iter().collect();
+        processes.sort_by_key(|(pid, (_, counts))| (std::cmp::Reverse(counts.remote), **pid));
+        eprintln!("Processes with the most remote memory accesses:");
+        for (pid, (name, counts)) in processes.into_iter().take(REPORT_TOP_COUNT) {
+            eprintln!(
+                "  {} (pid {pid}): {} of {} remote ({:.1}%)",
+                name.as_deref().unwrap_or("<unknown>"),
+                counts.remote,
+                counts.total(),
+                counts.remote_percentage()
+            );
+        }
+
+        let mut locations: Vec<_> = self
+            .by_location
+            .iter()
+            .filter(|(_, (_, counts))| counts.remote != 0)
+            .collect();
+        locations
+            .sort_by_key(|((pid, ip), (_, counts))| (std::cmp::Reverse(counts.remote), *pid, *ip));
+        eprintln!("Code locations with the most remote memory accesses:");
+        for (&(pid, ip), &(timestamp, counts)) in locations.into_iter().take(REPORT_TOP_COUNT) {
+            let location = match timeline.and_then(|timeline| timeline.lookup(pid, ip, timestamp)) {
+                Some(view) => format!(
+                    "{} + 0x{:x}",
+                    profile.lib_info(view.lib).name,
+                    view.relative_address
+                ),
+                None => format!("0x{ip:x}"),
+            };
+            eprintln!(
+                "  {location} (pid {pid}): {} of {} remote ({:.1}%)",
+                counts.remote,
+                counts.total(),
+                counts.remote_percentage()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn topology_section(nodes: &[(u32, &str)]) -> Vec<u8> {
+        let mut data = (nodes.len() as u32).to_le_bytes().to_vec();
+        for &(node, cpus) in nodes {
+            data.extend_from_slice(&node.to_le_bytes());
+            data.extend_from_slice(&(64u64 << 30).to_le_bytes());
+            data.extend_from_slice(&(32u64 << 30).to_le_bytes());
+            let mut string = cpus.as_bytes().to_vec();
+            string.resize((cpus.len() + 8) / 8 * 8, 0);
+            data.extend_from_slice(&(string.len() as u32).to_le_bytes());
+            data.extend_from_slice(&string);
+        }
+        data
+    }
+
+    #[test]
+    fn parses_numa_topology() {
+        let data = topology_section(&[(0, "0-3,8-11"), (1, "4-7,12")]);
+        let topology = NumaTopology::parse(&data, Endianness::LittleEndian).unwrap();
+        assert_eq!(topology.node_of_cpu(2), Some(0));
+        assert_eq!(topology.node_of_cpu(11), Some(0));
+        assert_eq!(topology.node_of_cpu(5), Some(1));
+        assert_eq!(topology.node_of_cpu(12), Some(1));
+        assert_eq!(topology.node_of_cpu(13), None);
+        assert_eq!(topology.node_count(), 2);
+
+        assert!(NumaTopology::parse(&data[..data.len() - 4], Endianness::LittleEndian).is_none());
+        assert_eq!(parse_cpu_list("\n"), Some(vec![]));
+        assert_eq!(parse_cpu_list("1-x"), None);
+    }
+
+    #[test]
+    fn classifies_data_sources() {
+        let lvl = |lvl: u64| PERF_MEM_OP_LOAD | (lvl << PERF_MEM_LVL_SHIFT);
+        let lvl_num = |num: u64, remote: bool| {
+            PERF_MEM_OP_LOAD
+                | (num << PERF_MEM_LVLNUM_SHIFT)
+                | (u64::from(remote) << PERF_MEM_REMOTE_SHIFT)
+        };
+        assert_eq!(
+            mem_access_locality(lvl(PERF_MEM_LVL_L1)),
+            Some(MemAccessLocality::Local)
+        );
+        assert_eq!(
+            mem_access_locality(lvl(PERF_MEM_LVL_LOC_RAM)),
+            Some(MemAccessLocality::Local)
+        );
+        assert_eq!(
+            mem_access_locality(lvl(PERF_MEM_LVL_REM_RAM1)),
+            Some(MemAccessLocality::Remote)
+        );
+        assert_eq!(
+            mem_access_locality(lvl(PERF_MEM_LVL_REM_CCE2)),
+            Some(MemAccessLocality::Remote)
+        );
+        // A RAM access (0x0d) on another node.
+        assert_eq!(
+            mem_access_locality(lvl_num(0x0d, false)),
+            Some(MemAccessLocality::Local)
+        );
+        assert_eq!(
+            mem_access_locality(lvl_num(0x0d, true)),
+            Some(MemAccessLocality::Remote)
+        );
+        assert_eq!(
+            mem_access_locality(lvl_num(PERF_MEM_LVLNUM_NA, false)),
+            None
+        );
+        // Not a load or store.
+        assert_eq!(
+            mem_access_locality(PERF_MEM_LVL_L1 << PERF_MEM_LVL_SHIFT),
+            None
+        );
+    }
+
+    #[test]
+    fn counts_samples_per_node() {
+        let data = topology_section(&[(0, "0-1"), (1, "2-3")]);
+        let topology = NumaTopology::parse(&data, Endianness::LittleEndian).unwrap();
+        let mut counts = NodeSampleCounts::new(topology, 10);
+        counts.add_sample(0, 5);
+        counts.add_sample(1, 7);
+        counts.add_sample(3, 25);
+        // CPUs which aren't in the topology are ignored.
+        counts.add_sample(9, 25);
+        let counts_by_node = counts.counts_by_node();
+        assert_eq!(counts_by_node[&0], vec![(0, 2), (10, 0), (20, 0)]);
+        assert_eq!(counts_by_node[&1], vec![(0, 0), (10, 0), (20, 1)]);
+    }
+}
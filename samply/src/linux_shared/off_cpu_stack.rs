@@ -0,0 +1,139 @@
+use super::context_switch::{ContextSwitchHandler, OffCpuSampleGroup};
+use crate::shared::unresolved_samples::UnresolvedStackHandle;
+
+/// Which stack the off-CPU samples of a thread get.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OffCpuStack {
+    /// The stack at which the thread blocked, from the sched_switch event.
+    #[default]
+    Blocked,
+    /// The stack of the thread's first sample after it was switched back in.
+    Resumed,
+    /// The first half of the samples get the blocked stack and the second
+    /// half get the resumed stack.
+    Both,
+}
+
+/// Parses an `--off-cpu-stack` argument.
+pub fn parse_off_cpu_stack(s: &str) -> Result<OffCpuStack, String> {
+    match s {
+        "blocked" => Ok(OffCpuStack::Blocked),
+        "resumed" => Ok(OffCpuStack::Resumed),
+        "both" => Ok(OffCpuStack::Both),
+        _ => Err(format!("expected blocked, resumed or both, got {s:?}")),
+    }
+}
+
+/// The number of off-CPU sample groups which can wait for a resumed stack at
+/// the same time. Beyond that, groups get the blocked stack right away.
+pub const MAX_DEFERRED_OFF_CPU_GROUPS: usize = 10_000;
+
+/// How long after the switch-in the thread's first sample can arrive for its
+/// stack to count as the resumed stack. A later sample is likely to be
+/// somewhere else entirely, so the group gets the blocked stack instead.
+const RESUMED_STACK_MAX_DELAY_NS: u64 = 10_000_000; // 10ms
+
+/// An off-CPU sample group which waits for the thread's first sample after
+/// the switch-in, for [`OffCpuStack::Resumed`] and [`OffCpuStack::Both`].
+#[derive(Debug)]
+pub struct DeferredOffCpuGroup {
+    pub group: OffCpuSampleGroup,
+    /// The running time before the thread blocked, which is carried by the
+    /// group's first sample.
+    pub cpu_delta_ns: u64,
+    pub blocked_stack: UnresolvedStackHandle,
+    pub switch_in_timestamp: u64,
+}
+
+impl DeferredOffCpuGroup {
+    /// Returns `stack` if a sample at `timestamp` is close enough to the
+    /// switch-in for its stack to be the resumed stack.
+    pub fn resumed_stack(
+        &self,
+        timestamp: u64,
+        stack: UnresolvedStackHandle,
+    ) -> Option<UnresolvedStackHandle> {
+        let delay = timestamp.saturating_sub(self.switch_in_timestamp);
+        (delay <= RESUMED_STACK_MAX_DELAY_NS).then(|| stack)
+    }
+
+    /// Returns the parts of the group with their CPU delta and stack. Without
+    /// a resumed stack, the whole group gets the blocked stack.
+    pub fn into_parts(
+        self,
+        mode: OffCpuStack,
+        resumed_stack: Option<UnresolvedStackHandle>,
+        context_switch_handler: &ContextSwitchHandler,
+    ) -> Vec<(OffCpuSampleGroup, u64, UnresolvedStackHandle)> {
+        let DeferredOffCpuGroup {
+            group,
+            cpu_delta_ns,
+            blocked_stack,
+            ..
+        } = self;
+        match (mode, resumed_stack) {
+            (OffCpuStack::Resumed, Some(resumed_stack)) => {
+                vec![(group, cpu_delta_ns, resumed_stack)]
+            }
+            (OffCpuStack::Both, Some(resumed_stack)) => {
+                let (first, second) = context_switch_handler.split_group(group);
+                let mut parts = vec![(first, cpu_delta_ns, blocked_stack)];
+                if let Some(second) = second {
+                    parts.push((second, 0, resumed_stack));
+                }
+                parts
+            }
+            _ => vec![(group, cpu_delta_ns, blocked_stack)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shared::types::{StackFrame, StackMode};
+    use crate::shared::unresolved_samples::UnresolvedStacks;
+
+    #[test]
+    fn chooses_stacks_by_mode() {
+        let mut stacks = UnresolvedStacks::default();
+        let blocked_stack = UnresolvedStackHandle::EMPTY;
+        let resumed_stack = stacks.convert(std::iter::once(StackFrame::InstructionPointer(
+            0x1000,
+            StackMode::User,
+        )));
+        let handler = ContextSwitchHandler::new(10);
+        let deferred = || DeferredOffCpuGroup {
+            group: OffCpuSampleGroup {
+                begin_timestamp: 10,
+                end_timestamp: 40,
+                sample_count: 4,
+            },
+            cpu_delta_ns: 7,
+            blocked_stack,
+            switch_in_timestamp: 45,
+        };
+
+        let resumed = deferred().resumed_stack(50, resumed_stack);
+        assert_eq!(resumed, Some(resumed_stack));
+        let too_late = deferred().resumed_stack(45 + RESUMED_STACK_MAX_DELAY_NS + 1, resumed_stack);
+        assert_eq!(too_late, None);
+
+        let parts = deferred().into_parts(OffCpuStack::Blocked, resumed, &handler);
+        assert_eq!(parts, vec![(deferred().group, 7, blocked_stack)]);
+        let parts = deferred().into_parts(OffCpuStack::Resumed, resumed, &handler);
+        assert_eq!(parts, vec![(deferred().group, 7, resumed_stack)]);
+        let parts = deferred().into_parts(OffCpuStack::Resumed, None, &handler);
+        assert_eq!(parts, vec![(deferred().group, 7, blocked_stack)]);
+
+        let parts = deferred().into_parts(OffCpuStack::Both, resumed, &handler);
+        let (first, second) = handler.split_group(deferred().group);
+        assert_eq!(
+            parts,
+            vec![
+                (first, 7, blocked_stack),
+                (second.unwrap(), 0, resumed_stack)
+            ]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeSet;
+
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+use tracing::warn;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use crate::shared::unresolved_samples::UnresolvedStacks;
+
+/// Whether the event is a dynamic probe, i.e. a uprobe or kprobe which was
+/// added with `perf probe` ("probe:my_function", "probe_libc:malloc") or
+/// through the tracefs interface ("uprobes:p_my_function").
+fn is_probe_event(attr_name: &str) -> bool {
+    let Some((system, _)) = attr_name.split_once(':') else {
+        return false;
+    };
+    system == "probe"
+        || system.starts_with("probe_")
+        || matches!(system, "kprobe" | "kprobes" | "uprobe" | "uprobes")
+}
+
+/// Handles the samples of uprobe and kprobe events and emits a marker named
+/// after the probe, with the probe's arguments, on the thread which hit the
+/// probe, e.g. "probe:my_function arg1=42".
+///
+/// The arguments are decoded with the event's format from the tracing data
+/// in the perf.data header. If the format is missing, e.g. because the
+/// recording was made without it, the marker only has the event name.
+#[derive(Debug, Default)]
+pub struct ProbeHandler {
+    events_without_format: BTreeSet<String>,
+}
+
+impl TracepointHandler for ProbeHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        is_probe_event(attr_name)
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(tid), Some(timestamp_mono)) = (e.tid, e.timestamp) else {
+            return;
+        };
+        let args = match (ctx.tracepoint_formats.get(ctx.attr_name), e.raw) {
+            (Some(format), Some(raw)) => format.decode_fields(&raw.as_slice(), ctx.endian),
+            _ => {
+                if !self.events_without_format.contains(ctx.attr_name) {
+                    self.events_without_format.insert(ctx.attr_name.to_string());
+                }
+                Vec::new()
+            }
+        };
+
+        let thread = ctx.thread_handle(tid);
+        let stack = ctx.stack_or_last_sample_stack(thread);
+        let timestamp = ctx.timestamp_converter.convert_time(timestamp_mono);
+        ctx.unresolved_samples.add_probe_marker(
+            thread,
+            timestamp,
+            timestamp_mono,
+            stack,
+            ctx.attr_name.to_string(),
+            args,
+        );
+    }
+
+    fn only_uses_stack_for_markers(&self) -> bool {
+        true
+    }
+
+    fn finish(&mut self, _unresolved_stacks: &UnresolvedStacks) {
+        for event in &self.events_without_format {
+            warn!(
+                event = event.as_str(),
+                "The format of the probe event {event} wasn't found in the tracing data, so its \
+                 markers don't have arguments."
+            );
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+
+use tracing::warn;
+
+/// For `--synthesize-from-proc`: Reads the state of processes for which a
+/// perf.data file has samples but no COMM or MMAP records from /proc, at
+/// import time. perf doesn't synthesize these records for processes which
+/// were running before the recording started if it was run with
+/// `--no-synth`, or if it crashed before it was done.
+///
+/// Each process is looked up once, at its first user-space sample without
+/// any executable mappings. Its mappings are passed to `handle_mmap2` as if
+/// they had been mmapped at the start of the profile, and its thread names
+/// to `set_thread_name`, so that they're handled like the records which perf
+/// would have synthesized. This only gives the right result if the process
+/// is still running and hasn't mapped different libraries since the
+/// recording.
+#[derive(Debug)]
+pub struct ProcSynthesis {
+    /// The timestamp which the synthesized mmap records get.
+    timestamp: u64,
+    /// The pids which were looked up in /proc, whether they were found or not.
+    looked_up_pids: HashSet<i32>,
+    /// The pids which were found in /proc.
+    found_pids: HashSet<i32>,
+    /// The threads of the found processes whose names were looked up.
+    named_threads: HashSet<(i32, i32)>,
+    synthesized_processes: Vec<SynthesizedProcess>,
+    /// The pids which were gone from /proc, in the order of their first
+    /// sample.
+    missing_pids: Vec<i32>,
+}
+
+/// A process whose state was read from /proc.
+#[derive(Debug, Clone)]
+struct SynthesizedProcess {
+    pid: i32,
+    exe: Option<String>,
+    executable_mapping_count: usize,
+}
+
+/// What /proc/<pid> says about a process.
+#[derive(Debug, Clone)]
+pub struct ProcProcessState {
+    /// The contents of /proc/<pid>/maps.
+    pub maps: String,
+    /// The name from /proc/<pid>/comm.
+    pub name: Option<String>,
+    /// The target of the /proc/<pid>/exe link.
+    pub exe: Option<String>,
+}
+
+impl ProcSynthesis {
+    /// `timestamp` is the start of the profile.
+    pub fn new(timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            looked_up_pids: HashSet::new(),
+            found_pids: HashSet::new(),
+            named_threads: HashSet::new(),
+            synthesized_processes: Vec::new(),
+            missing_pids: Vec::new(),
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Returns true the first time it's called for a pid.
+    pub fn should_look_up_process(&mut self, pid: i32) -> bool {
+        pid > 0 && self.looked_up_pids.insert(pid)
+    }
+
+    /// Returns true the first time it's called for a thread of a process
+    /// which was found in /proc.
+    pub fn should_look_up_thread_name(&mut self, pid: i32, tid: i32) -> bool {
+        self.found_pids.contains(&pid) && self.named_threads.insert((pid, tid))
+    }
+
+    pub fn on_process_found(&mut self, pid: i32, exe: Option<String>, count: usize) {
+        self.found_pids.insert(pid);
+        self.named_threads.insert((pid, pid));
+        self.synthesized_processes.push(SynthesizedProcess {
+            pid,
+            exe,
+            executable_mapping_count: count,
+        });
+    }
+
+    pub fn on_process_missing(&mut self, pid: i32) {
+        self.missing_pids.push(pid);
+    }
+
+    /// Logs which processes were synthesized from /proc, and which
+    /// processes couldn't be symbolicated because they were gone.
+    pub fn report(&self) {
+        for process in &self.synthesized_processes {
+            let exe = process.exe.as_deref().unwrap_or("<unknown executable>");
+            warn!(
+                pid = process.pid,
+                exe,
+                executable_mapping_count = process.executable_mapping_count,
+                "Process {} ({}) had no COMM or MMAP records. Used {} executable mappings from \
+                 /proc/{}/maps instead; they are treated as if they had been mapped at the start \
+                 of the profile.",
+                process.pid,
+                exe,
+                process.executable_mapping_count,
+                process.pid
+            );
+        }
+        if !self.missing_pids.is_empty() {
+            let missing_process_count = self.missing_pids.len();
+            let pids: Vec<String> = self.missing_pids.iter().map(i32::to_string).collect();
+            let pids = pids.join(", ");
+            warn!(
+                missing_process_count,
+                pids = pids.as_str(),
+                "{missing_process_count} processes had samples but no COMM or MMAP records, and \
+                 are no longer in /proc, so their samples can't be symbolicated. Their pids are: \
+                 {pids}"
+            );
+        }
+    }
+}
+
+/// Reads the mappings, name and executable of a process from /proc. Fails
+/// if the process doesn't exist anymore.
+pub fn read_proc_process_state(pid: i32) -> std::io::Result<ProcProcessState> {
+    let maps = std::fs::read(format!("/proc/{pid}/maps"))?;
+    Ok(ProcProcessState {
+        maps: String::from_utf8_lossy(&maps).into_owned(),
+        name: read_comm(&format!("/proc/{pid}/comm")),
+        exe: std::fs::read_link(format!("/proc/{pid}/exe"))
+            .ok()
+            .map(|path| path.to_string_lossy().into_owned()),
+    })
+}
+
+/// Reads the name of a thread from /proc.
+pub fn read_proc_thread_name(pid: i32, tid: i32) -> Option<String> {
+    read_comm(&format!("/proc/{pid}/task/{tid}/comm"))
+}
+
+fn read_comm(path: &str) -> Option<String> {
+    let buffer = std::fs::read(path).ok()?;
+    let length = memchr::memchr(b'\0', &buffer).unwrap_or(buffer.len());
+    let name = String::from_utf8_lossy(&buffer[..length]);
+    Some(name.trim_end().to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn each_process_and_thread_is_looked_up_once() {
+        let mut synthesis = ProcSynthesis::new(1000);
+        assert!(synthesis.should_look_up_process(10));
+        assert!(!synthesis.should_look_up_process(10));
+        assert!(!synthesis.should_look_up_process(0));
+        assert!(synthesis.should_look_up_process(20));
+
+        synthesis.on_process_found(10, Some("/usr/bin/app".to_string()), 3);
+        synthesis.on_process_missing(20);
+        // The main thread was named with the process.
+        assert!(!synthesis.should_look_up_thread_name(10, 10));
+        assert!(synthesis.should_look_up_thread_name(10, 11));
+        assert!(!synthesis.should_look_up_thread_name(10, 11));
+        // Threads of missing processes aren't looked up.
+        assert!(!synthesis.should_look_up_thread_name(20, 21));
+        assert_eq!(synthesis.missing_pids, vec![20]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerTiming, Profile, ProfilerMarker, ThreadHandle,
+};
+use linux_perf_data::linux_perf_event_reader::{RawData, SampleRecord};
+use linux_perf_data::Endianness;
+use serde_json::json;
+
+use super::signals::signal_name;
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use crate::shared::timestamp_converter::TimestampConverter;
+
+const SYS_ENTER_EXIT_GROUP: &str = "syscalls:sys_enter_exit_group";
+
+/// Why a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The process exited with this exit code.
+    Code(i32),
+    /// The process was killed by this signal.
+    Signal(i32),
+}
+
+impl ExitReason {
+    fn is_failure(self) -> bool {
+        self != ExitReason::Code(0)
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ExitReason::Code(code) => write!(f, "exited with code {code}"),
+            ExitReason::Signal(signal) => write!(f, "killed by {}", signal_name(signal)),
+        }
+    }
+}
+
+/// Collects why the processes ended, and adds it to the profile at the end
+/// of each process: as an instant marker on its main thread, and as process
+/// information in the profile's info panel. Processes which didn't exit
+/// with code 0 are printed at the end of the conversion.
+///
+/// The reasons come from the exit_group syscall, from fatal signal_deliver
+/// tracepoints, and, for the command which `samply record` launched, from
+/// its wait status. The wait status can arrive after the process's EXIT
+/// record, so it's matched up with an ended process too. It takes precedence
+/// over the tracepoints, because it's what the parent saw.
+#[derive(Debug, Default)]
+pub struct ProcessExits {
+    /// The reasons of the processes which haven't ended yet, by pid.
+    pending_reasons: HashMap<i32, ExitReason>,
+    exits: Vec<ProcessExit>,
+}
+
+#[derive(Debug)]
+struct ProcessExit {
+    pid: i32,
+    name: Option<String>,
+    timestamp: u64,
+    main_thread: ThreadHandle,
+    reason: Option<ExitReason>,
+}
+
+impl ProcessExits {
+    /// Called for a tracepoint which tells how a live process is ending.
+    pub fn set_reason(&mut self, pid: i32, reason: ExitReason) {
+        self.pending_reasons.insert(pid, reason);
+    }
+
+    /// Called with the wait status of a child process, which may already
+    /// have ended.
+    pub fn set_wait_status(&mut self, pid: i32, reason: ExitReason) {
+        match self.exits.iter_mut().rfind(|exit| exit.pid == pid) {
+            Some(exit) => exit.reason = Some(reason),
+            None => self.set_reason(pid, reason),
+        }
+    }
+
+    /// Called when the main thread of a process exits.
+    pub fn on_process_end(
+        &mut self,
+        pid: i32,
+        name: Option<String>,
+        timestamp: u64,
+        main_thread: ThreadHandle,
+    ) {
+        self.exits.push(ProcessExit {
+            pid,
+            name,
+            timestamp,
+            main_thread,
+            reason: self.pending_reasons.remove(&pid),
+        });
+    }
+
+    pub fn add_to_profile(&self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        for exit in &self.exits {
+            let Some(reason) = exit.reason else {
+                continue;
+            };
+            let reason = reason.to_string();
+            profile.add_marker(
+                exit.main_thread,
+                "Process exit",
+                ProcessExitMarker {
+                    reason: reason.clone(),
+                },
+                MarkerTiming::Instant(timestamp_converter.convert_time(exit.timestamp)),
+            );
+            profile.add_extra_info("Process exits", &exit.label(), &reason);
+        }
+    }
+
+    /// Prints the processes which didn't exit with code 0.
+    pub fn report(&self) {
+        let failures: Vec<_> = self
+            .exits
+            .iter()
+            .filter_map(|exit| Some((exit, exit.reason.filter(|r| r.is_failure())?)))
+            .collect();
+        if failures.is_empty() {
+            return;
+        }
+        eprintln!("Processes which didn't exit successfully:");
+        for (exit, reason) in failures {
+            eprintln!("  {} {reason}", exit.label());
+        }
+    }
+}
+
+impl ProcessExit {
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("Process {} ({name})", self.pid),
+            None => format!("Process {}", self.pid),
+        }
+    }
+}
+
+/// Handles the syscalls:sys_enter_exit_group tracepoint, which has the exit
+/// code of a process which exits normally.
+///
+/// ```
+/// # cat /sys/kernel/debug/tracing/events/syscalls/sys_enter_exit_group/format
+/// name: sys_enter_exit_group
+/// ID: 627
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:int __syscall_nr; offset:8;       size:4; signed:1;
+///         field:int error_code;   offset:16;      size:8; signed:0;
+/// ```
+#[derive(Debug, Default)]
+pub struct ExitGroupHandler;
+
+impl ExitGroupHandler {
+    fn exit_code(data: RawData, endian: Endianness) -> Result<i32, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::exit_code_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::exit_code_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    fn exit_code_impl<O: ByteOrder>(mut data: RawData) -> Result<i32, std::io::Error> {
+        let _common = data.read_u64::<O>()?;
+        let _syscall_nr = data.read_i32::<O>()?;
+        let _padding = data.read_u32::<O>()?;
+        let error_code = data.read_u64::<O>()?;
+        // The parent only sees the low byte of the status.
+        Ok((error_code & 0xff) as i32)
+    }
+}
+
+impl TracepointHandler for ExitGroupHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        attr_name == SYS_ENTER_EXIT_GROUP
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(raw), Some(pid)) = (e.raw, e.pid) else {
+            return;
+        };
+        if let Ok(code) = Self::exit_code(raw, ctx.endian) {
+            ctx.set_exit_reason(pid, ExitReason::Code(code));
+        }
+    }
+
+    fn only_uses_stack_for_markers(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessExitMarker {
+    reason: String,
+}
+
+impl ProfilerMarker for ProcessExitMarker {
+    const MARKER_TYPE_NAME: &'static str = "ProcessExit";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "reason": self.reason,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.reason}"),
+            tooltip_label: Some("Process {marker.data.reason}"),
+            table_label: Some("Process {marker.data.reason}"),
+            fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                key: "reason",
+                label: "Reason",
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            })],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{ReferenceTimestamp, SamplingInterval, Timestamp};
+
+    use super::*;
+
+    #[test]
+    fn wait_statuses_override_the_tracepoints() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let start_time = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("app", 100, start_time);
+        let thread = profile.add_thread(process, 100, start_time, true);
+
+        let mut exits = ProcessExits::default();
+        exits.set_reason(100, ExitReason::Code(0));
+        exits.set_reason(200, ExitReason::Signal(11));
+        exits.on_process_end(100, Some("app".into()), 10, thread);
+        exits.on_process_end(200, None, 20, thread);
+        exits.on_process_end(300, None, 30, thread);
+        // The wait status arrives after the EXIT record.
+        exits.set_wait_status(100, ExitReason::Signal(9));
+
+        let reasons: Vec<_> = exits
+            .exits
+            .iter()
+            .map(|exit| (exit.label(), exit.reason.map(|r| r.to_string())))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                ("Process 100 (app)".into(), Some("killed by SIGKILL".into())),
+                ("Process 200".into(), Some("killed by SIGSEGV".into())),
+                ("Process 300".into(), None),
+            ]
+        );
+        assert!(!ExitReason::Code(0).is_failure());
+        assert_eq!(ExitReason::Code(137).to_string(), "exited with code 137");
+    }
+}
@@ -0,0 +1,196 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+/// A command which the profiled application sends to samply, one per line,
+/// while it's being recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Stop emitting samples, e.g. at the end of a benchmark's measured
+    /// section.
+    Pause,
+    /// Start emitting samples again.
+    Resume,
+    /// Add an instant marker with the given name.
+    Marker(String),
+}
+
+impl ControlCommand {
+    /// Parses "pause", "resume" or "marker <name>".
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        match (command, argument) {
+            ("pause", "") => Some(ControlCommand::Pause),
+            ("resume", "") => Some(ControlCommand::Resume),
+            ("marker", name) if !name.is_empty() => Some(ControlCommand::Marker(name.to_owned())),
+            _ => None,
+        }
+    }
+}
+
+/// The time ranges during which sampling was paused.
+#[derive(Debug, Clone, Default)]
+pub struct PauseState {
+    /// Sorted, non-overlapping. Only the last range can be open-ended.
+    ranges: Vec<(u64, Option<u64>)>,
+}
+
+impl PauseState {
+    pub fn pause(&mut self, timestamp: u64) {
+        if !self.is_paused() {
+            self.ranges.push((timestamp, None));
+        }
+    }
+
+    /// Returns the start of the paused range which was ended, if sampling
+    /// was paused.
+    pub fn resume(&mut self, timestamp: u64) -> Option<u64> {
+        match self.ranges.last_mut() {
+            Some((start, end @ None)) => {
+                *end = Some(timestamp.max(*start));
+                Some(*start)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the start of the current paused range, if sampling is paused.
+    pub fn paused_since(&self) -> Option<u64> {
+        match self.ranges.last() {
+            Some((start, None)) => Some(*start),
+            _ => None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_since().is_some()
+    }
+
+    /// Whether the timestamp falls into a paused range. Samples can arrive a
+    /// little after the command which paused sampling, so this checks all
+    /// ranges and not just the current state.
+    pub fn is_paused_at(&self, timestamp: u64) -> bool {
+        self.overlaps(timestamp, timestamp)
+    }
+
+    /// Whether any part of `start..=end` falls into a paused range.
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.ranges.iter().rev().any(|&(range_start, range_end)| {
+            range_start <= end && range_end.map_or(true, |range_end| start < range_end)
+        })
+    }
+}
+
+/// Covers the time during which sampling was paused by the profiled
+/// application.
+#[derive(Debug, Clone)]
+pub struct ProfilingPausedMarker;
+
+impl ProfilerMarker for ProfilingPausedMarker {
+    const MARKER_TYPE_NAME: &'static str = "ProfilingPaused";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({ "type": Self::MARKER_TYPE_NAME })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("Profiling paused"),
+            tooltip_label: Some("Profiling paused"),
+            table_label: Some("Profiling paused"),
+            fields: vec![MarkerSchemaField::Static(MarkerStaticField {
+                label: "Description",
+                value: "The profiled application paused sampling through the control pipe. \
+                        There are no samples in this range, including off-CPU samples.",
+            })],
+        }
+    }
+}
+
+/// An instant marker which the profiled application added through the
+/// control pipe.
+#[derive(Debug, Clone)]
+pub struct ControlMarker {
+    pub name: String,
+}
+
+impl ProfilerMarker for ControlMarker {
+    const MARKER_TYPE_NAME: &'static str = "ControlMarker";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "name": self.name,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.name}"),
+            tooltip_label: Some("{marker.data.name}"),
+            table_label: Some("{marker.data.name}"),
+            fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                key: "name",
+                label: "Name",
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            })],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            ControlCommand::parse("pause\n"),
+            Some(ControlCommand::Pause)
+        );
+        assert_eq!(
+            ControlCommand::parse("resume"),
+            Some(ControlCommand::Resume)
+        );
+        assert_eq!(
+            ControlCommand::parse("marker warmup done\n"),
+            Some(ControlCommand::Marker("warmup done".to_string()))
+        );
+        assert_eq!(ControlCommand::parse("marker"), None);
+        assert_eq!(ControlCommand::parse("pause now"), None);
+        assert_eq!(ControlCommand::parse("stop"), None);
+    }
+
+    #[test]
+    fn tracks_paused_ranges() {
+        let mut state = PauseState::default();
+        assert_eq!(state.resume(5), None);
+        state.pause(10);
+        state.pause(15);
+        assert_eq!(state.paused_since(), Some(10));
+        assert!(state.is_paused_at(100));
+        assert_eq!(state.resume(20), Some(10));
+        assert!(!state.is_paused());
+        assert!(!state.is_paused_at(9));
+        assert!(state.is_paused_at(10));
+        assert!(state.is_paused_at(19));
+        assert!(!state.is_paused_at(20));
+        assert!(state.overlaps(0, 10));
+        assert!(!state.overlaps(20, 30));
+    }
+}
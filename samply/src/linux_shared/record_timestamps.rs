@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use fxprof_processed_profile::{ClampedTimestampCounts, Profile, Timestamp};
+use linux_perf_data::linux_perf_event_reader::RecordType;
+use tracing::{info, warn};
+
+use crate::shared::timestamp_converter::TimestampConverter;
+
+/// The timestamp which is converted to zero in the profile: the earlier of
+/// the first sample and the first record. Zero stands for "unknown".
+pub fn reference_timestamp(first_sample_time: u64, first_record_time: Option<u64>) -> u64 {
+    match (first_sample_time, first_record_time) {
+        (0, Some(record_time)) => record_time,
+        (sample_time, Some(record_time)) if record_time != 0 => sample_time.min(record_time),
+        (sample_time, _) => sample_time,
+    }
+}
+
+/// Tracks the range of the record timestamps in a conversion, so that the
+/// profile's timestamps can be checked against it at the end.
+///
+/// The timestamp converter is anchored at the earliest record timestamp which
+/// is known before the conversion starts, but perf.data files are only sorted
+/// by round, and jitdump files and tracepoint handlers bring their own
+/// timestamps. Anything which ends up outside the range of the records is
+/// clamped into it, so that the front-end doesn't show a profile which starts
+/// at zero with a large gap, or which extends far beyond the last sample.
+#[derive(Debug, Default)]
+pub struct RecordTimestamps {
+    /// The earliest and the latest record timestamp.
+    range: Option<(u64, u64)>,
+    /// The number of records which are earlier than the timestamp converter's
+    /// reference timestamp, by record type.
+    early_records_by_type: BTreeMap<u32, usize>,
+    /// Records from this timestamp on which are earlier than the reference
+    /// timestamp are expected, because the start of the profile was moved
+    /// past them, e.g. past a `perf record --delay`.
+    expected_early_records_from: Option<u64>,
+}
+
+impl RecordTimestamps {
+    /// A timestamp of zero means that the record has no timestamp.
+    pub fn observe(
+        &mut self,
+        record_type: RecordType,
+        timestamp: u64,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        if timestamp == 0 {
+            return;
+        }
+        self.range = Some(match self.range {
+            Some((min, max)) => (min.min(timestamp), max.max(timestamp)),
+            None => (timestamp, timestamp),
+        });
+        let is_expected =
+            matches!(self.expected_early_records_from, Some(start) if start <= timestamp);
+        if timestamp < timestamp_converter.reference_timestamp() && !is_expected {
+            *self.early_records_by_type.entry(record_type.0).or_default() += 1;
+        }
+    }
+
+    pub fn expect_early_records_from(&mut self, timestamp: u64) {
+        self.expected_early_records_from = Some(timestamp);
+    }
+
+    #[allow(unused)]
+    pub fn early_record_count(&self) -> usize {
+        self.early_records_by_type.values().sum()
+    }
+
+    /// The range of the record timestamps, in profile time.
+    fn profile_range(
+        &self,
+        timestamp_converter: &TimestampConverter,
+    ) -> Option<(Timestamp, Timestamp)> {
+        let (min, max) = self.range?;
+        Some((
+            timestamp_converter.convert_time(min),
+            timestamp_converter.convert_time(max),
+        ))
+    }
+
+    /// Clamps the sample, marker and counter timestamps in the profile to the
+    /// range of the record timestamps. In debug builds, this also checks that
+    /// nothing is left outside of the range.
+    pub fn clamp_profile(
+        &self,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+    ) -> ClampedTimestampCounts {
+        let Some((start, end)) = self.profile_range(timestamp_converter) else {
+            return ClampedTimestampCounts::default();
+        };
+        let counts = profile.clamp_timestamps(start, end);
+        debug_assert!(
+            profile
+                .timestamp_bounds()
+                .map_or(true, |(min, max)| start <= min && max <= end),
+            "profile timestamps outside of the record range after clamping"
+        );
+        counts
+    }
+
+    pub fn report(&self, clamped: &ClampedTimestampCounts) {
+        for (record_type, count) in &self.early_records_by_type {
+            warn!(
+                record_type = ?RecordType(*record_type),
+                count,
+                "{count} {:?} records were earlier than the start of the profile.",
+                RecordType(*record_type)
+            );
+        }
+        if clamped.total() != 0 {
+            info!(
+                samples = clamped.samples,
+                markers = clamped.markers,
+                counter_samples = clamped.counter_samples,
+                "Moved {} samples, {} markers and {} counter samples which were outside of \
+                 the time range of the perf records to the start or end of the profile.",
+                clamped.samples,
+                clamped.markers,
+                clamped.counter_samples
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+/// Gaps between the first record and the first sample which are shorter than
+/// this are normal startup noise, not a `perf record --delay`.
+const MIN_RECORDING_DELAY_NS: u64 = 100_000_000;
+
+/// How much of the delay is kept before the first sample when the profile's
+/// start is trimmed, so that the first sample isn't exactly at zero.
+const TRIMMED_DELAY_MARGIN_NS: u64 = 1_000_000;
+
+/// The initial skip of `perf record --delay`: perf synthesizes the COMM and
+/// MMAP records of the running processes when it starts, but only enables
+/// the events after the delay, so the first sample is much later than the
+/// first record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordingDelay {
+    pub first_record_time: u64,
+    pub first_sample_time: u64,
+}
+
+impl RecordingDelay {
+    /// Returns None if the gap is too short to be a delay. Zero stands for
+    /// "unknown".
+    pub fn detect(first_sample_time: u64, first_record_time: Option<u64>) -> Option<Self> {
+        let first_record_time = first_record_time.filter(|time| *time != 0)?;
+        if first_sample_time == 0
+            || first_sample_time.saturating_sub(first_record_time) < MIN_RECORDING_DELAY_NS
+        {
+            return None;
+        }
+        Some(Self {
+            first_record_time,
+            first_sample_time,
+        })
+    }
+
+    pub fn duration_ns(&self) -> u64 {
+        self.first_sample_time - self.first_record_time
+    }
+
+    /// The start of the profile if the delay is trimmed: just before the
+    /// first sample. Anything earlier is converted to this time.
+    pub fn trimmed_start(&self) -> u64 {
+        self.first_sample_time
+            .saturating_sub(TRIMMED_DELAY_MARGIN_NS)
+            .max(self.first_record_time)
+    }
+
+    pub fn marker_name(&self) -> String {
+        format!(
+            "Recording started (after {}ms delay)",
+            self.duration_ns() / 1_000_000
+        )
+    }
+}
+
+/// An instant marker at the first sample of a recording with a
+/// [`RecordingDelay`].
+#[derive(Debug, Clone)]
+pub struct RecordingStartedMarker {
+    pub delay_ms: f64,
+}
+
+impl ProfilerMarker for RecordingStartedMarker {
+    const MARKER_TYPE_NAME: &'static str = "RecordingStarted";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "delay": self.delay_ms,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("Recording started"),
+            tooltip_label: Some("Recording started after {marker.data.delay}"),
+            table_label: Some("Recording started after {marker.data.delay}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "delay",
+                    label: "Delay",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "perf enabled its events after this delay (perf record --delay). \
+                            The records from before the delay describe the processes which \
+                            were already running.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn detects_long_gaps_before_the_first_sample() {
+        assert_eq!(RecordingDelay::detect(10 * MS, Some(5 * MS)), None);
+        assert_eq!(RecordingDelay::detect(2000 * MS, None), None);
+        assert_eq!(RecordingDelay::detect(0, Some(5 * MS)), None);
+
+        let delay = RecordingDelay::detect(2005 * MS, Some(5 * MS)).unwrap();
+        assert_eq!(delay.duration_ns(), 2000 * MS);
+        assert_eq!(delay.trimmed_start(), 2004 * MS);
+        assert_eq!(
+            delay.marker_name(),
+            "Recording started (after 2000ms delay)"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// The most ended processes which are kept around for reuse.
+pub const MAX_REUSABLE_PROCESSES: usize = 1024;
+
+/// The most ended threads of one process which are kept around for reuse.
+pub const MAX_REUSABLE_THREADS: usize = 1024;
+
+/// Ended processes or threads, by name, which a new process or thread of the
+/// same name can take over with `--merge-threads`.
+///
+/// Holds at most `capacity` items. When it's full, the oldest item of the
+/// least recently used name is dropped, so that workloads with many distinct
+/// names can't grow it without bound.
+#[derive(Debug)]
+pub struct ReusePool<T> {
+    capacity: usize,
+    len: usize,
+    next_generation: u64,
+    /// The items of each name, oldest first, and the generation in which the
+    /// name was last used.
+    items_by_name: HashMap<String, (u64, VecDeque<T>)>,
+    /// The names by the generation in which they were last used.
+    names_by_generation: BTreeMap<u64, String>,
+}
+
+impl<T> ReusePool<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            len: 0,
+            next_generation: 0,
+            items_by_name: HashMap::new(),
+            names_by_generation: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn push(&mut self, name: &str, item: T) {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        match self.items_by_name.get_mut(name) {
+            Some((last_used, items)) => {
+                self.names_by_generation.remove(last_used);
+                *last_used = generation;
+                items.push_back(item);
+            }
+            None => {
+                self.items_by_name
+                    .insert(name.to_owned(), (generation, VecDeque::from([item])));
+            }
+        }
+        self.names_by_generation.insert(generation, name.to_owned());
+        self.len += 1;
+        while self.len > self.capacity {
+            self.evict_least_recently_used();
+        }
+    }
+
+    /// Takes the oldest item of this name.
+    pub fn take(&mut self, name: &str) -> Option<T> {
+        let (last_used, items) = self.items_by_name.get_mut(name)?;
+        let item = items
+            .pop_front()
+            .expect("We only have non-empty VecDeques in this HashMap");
+        if items.is_empty() {
+            self.names_by_generation.remove(last_used);
+            self.items_by_name.remove(name);
+        }
+        self.len -= 1;
+        Some(item)
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let Some(name) = self.names_by_generation.values().next().cloned() else { return };
+        self.take(&name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_names_are_evicted() {
+        let mut pool = ReusePool::new(3);
+        pool.push("a", 1);
+        pool.push("b", 2);
+        pool.push("a", 3);
+        pool.push("c", 4);
+        // "b" was used least recently.
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.take("b"), None);
+        assert_eq!(pool.take("a"), Some(1));
+        assert_eq!(pool.take("a"), Some(3));
+        assert_eq!(pool.take("a"), None);
+        assert_eq!(pool.take("c"), Some(4));
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn many_distinct_names_stay_within_the_capacity() {
+        let mut pool = ReusePool::new(100);
+        for i in 0..100_000 {
+            pool.push(&format!("request-{i}"), i);
+        }
+        assert_eq!(pool.len(), 100);
+        assert_eq!(pool.take("request-0"), None);
+        assert_eq!(pool.take("request-99999"), Some(99_999));
+    }
+}
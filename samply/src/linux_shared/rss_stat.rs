@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{ProcessHandle, Profile};
+use linux_perf_data::linux_perf_event_reader::{RawData, SampleRecord};
+use linux_perf_data::Endianness;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use crate::shared::counter_buckets::{BucketedCounter, CounterBuckets, LARGE_MEMORY_DELTA_BYTES};
+use crate::shared::process_sample_data::RssStatMember;
+use crate::shared::timestamp_converter::TimestampConverter;
+
+/// Handles "kmem:rss_stat" samples, which are emitted when the resident memory
+/// of a process changes. Each sample becomes a marker with a stack, and changes
+/// to the anonymous pages are tracked in a per-process memory counter, whose
+/// samples are aggregated into buckets of `counter_bucket_duration_ns`.
+#[derive(Debug)]
+pub struct RssStatHandler {
+    state_by_process: HashMap<ProcessHandle, ProcessRssState>,
+    counter_bucket_duration_ns: u64,
+}
+
+impl RssStatHandler {
+    pub fn new(counter_bucket_duration_ns: u64) -> Self {
+        Self {
+            state_by_process: HashMap::new(),
+            counter_bucket_duration_ns,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProcessRssState {
+    prev_mm_filepages_size: i64,
+    prev_mm_anonpages_size: i64,
+    prev_mm_swapents_size: i64,
+    prev_mm_shmempages_size: i64,
+    mem_counter: Option<BucketedCounter>,
+}
+
+impl TracepointHandler for RssStatHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        attr_name == "kmem:rss_stat"
+    }
+
+    fn only_uses_stack_for_markers(&self) -> bool {
+        true
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let Some(raw) = e.raw else { return };
+        let Ok(rss_stat) = RssStat::parse(raw, ctx.endian) else { return };
+
+        let Some(timestamp_mono) = e.timestamp else {
+            return;
+        };
+        let timestamp = ctx.timestamp_converter.convert_time(timestamp_mono);
+
+        let counter_bucket_duration_ns = self.counter_bucket_duration_ns;
+        let state = self.state_by_process.entry(ctx.process).or_default();
+        let (prev_size_of_this_member, member) = match rss_stat.member {
+            MM_FILEPAGES => (
+                &mut state.prev_mm_filepages_size,
+                RssStatMember::ResidentFileMappingPages,
+            ),
+            MM_ANONPAGES => (
+                &mut state.prev_mm_anonpages_size,
+                RssStatMember::ResidentAnonymousPages,
+            ),
+            MM_SHMEMPAGES => (
+                &mut state.prev_mm_shmempages_size,
+                RssStatMember::ResidentSharedMemoryPages,
+            ),
+            MM_SWAPENTS => (
+                &mut state.prev_mm_swapents_size,
+                RssStatMember::AnonymousSwapEntries,
+            ),
+            _ => return,
+        };
+
+        let delta = rss_stat.size - *prev_size_of_this_member;
+        *prev_size_of_this_member = rss_stat.size;
+
+        if rss_stat.member == MM_ANONPAGES {
+            let counter = state.mem_counter.get_or_insert_with(|| {
+                let counter = ctx.profile.add_counter(
+                    ctx.process,
+                    "malloc",
+                    "Memory",
+                    "Amount of allocated memory",
+                );
+                let buckets =
+                    CounterBuckets::new(counter_bucket_duration_ns, LARGE_MEMORY_DELTA_BYTES);
+                BucketedCounter::new(counter, buckets)
+            });
+            counter.add_sample(
+                ctx.profile,
+                ctx.timestamp_converter,
+                timestamp_mono,
+                delta as f64,
+            );
+        }
+
+        let unresolved_stack = ctx
+            .unresolved_stacks
+            .convert(ctx.stack.iter().rev().cloned());
+        ctx.unresolved_samples.add_rss_stat_marker(
+            ctx.main_thread,
+            timestamp,
+            timestamp_mono,
+            unresolved_stack,
+            member,
+            rss_stat.size,
+            delta,
+        );
+    }
+
+    fn flush(&mut self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        for state in self.state_by_process.values_mut() {
+            if let Some(counter) = &mut state.mem_counter {
+                counter.flush(profile, timestamp_converter);
+            }
+        }
+    }
+}
+
+/// Resident file mapping pages
+#[allow(unused)]
+const MM_FILEPAGES: i32 = 0;
+
+/// Resident anonymous pages
+#[allow(unused)]
+const MM_ANONPAGES: i32 = 1;
+
+/// Anonymous swap entries
+#[allow(unused)]
+const MM_SWAPENTS: i32 = 2;
+
+/// Resident shared memory pages
+#[allow(unused)]
+const MM_SHMEMPAGES: i32 = 3;
+
+/// ```
+/// # cat /sys/kernel/debug/tracing/events/kmem/rss_stat/format
+/// name: rss_stat
+/// ID: 537
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:unsigned int mm_id;       offset:8;       size:4; signed:0;
+///         field:unsigned int curr;        offset:12;      size:4; signed:0;
+///         field:int member;       offset:16;      size:4; signed:1;
+///         field:long size;        offset:24;      size:8; signed:1;
+///
+/// print fmt: "mm_id=%u curr=%d type=%s size=%ldB", REC->mm_id, REC->curr, __print_symbolic(REC->member, { 0, "MM_FILEPAGES" }, { 1, "MM_ANONPAGES" }, { 2, "MM_SWAPENTS" }, { 3, "MM_SHMEMPAGES" }), REC->size
+/// ```
+#[repr(C)]
+#[derive(Debug)]
+struct RssStat {
+    common_type: u16,
+    common_flags: u8,
+    common_preempt_count: u8,
+    common_pid: i32,
+    mm_id: u32,
+    curr: u32,
+    member: i32,
+    size: i64,
+}
+
+impl RssStat {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        let common_type = data.read_u16::<O>()?;
+        let common_flags = data.read_u8()?;
+        let common_preempt_count = data.read_u8()?;
+        let common_pid = data.read_i32::<O>()?;
+        let mm_id = data.read_u32::<O>()?;
+        let curr = data.read_u32::<O>()?;
+        let member = data.read_i32::<O>()?;
+        let _padding = data.read_u32::<O>()?;
+        let size = data.read_u64::<O>()? as i64;
+        Ok(RssStat {
+            common_type,
+            common_flags,
+            common_preempt_count,
+            common_pid,
+            mm_id,
+            curr,
+            member,
+            size,
+        })
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, SymbolTable, ThreadHandle, Timestamp,
+};
+use serde_json::json;
+use tracing::warn;
+
+use crate::shared::types::{StackFrame, StackMode};
+
+/// The kernel functions which re-enable interrupts, e.g. when releasing a
+/// spinlock which was taken with interrupts disabled. A timer interrupt which
+/// became pending while interrupts were disabled fires right after they're
+/// re-enabled, so the samples for the whole interrupt-disabled region land in
+/// these functions.
+const IRQ_ENABLING_KERNEL_FUNCTIONS: &[&str] = &[
+    "_raw_spin_unlock_irqrestore",
+    "_raw_spin_unlock_irq",
+    "_raw_read_unlock_irqrestore",
+    "_raw_read_unlock_irq",
+    "_raw_write_unlock_irqrestore",
+    "_raw_write_unlock_irq",
+    "finish_task_switch",
+    "arch_local_irq_restore",
+    "native_irq_enable",
+];
+
+/// A function is reported once it has at least this many samples right after
+/// interrupts were re-enabled...
+const MIN_SAMPLES_AFTER_IRQ_ENABLE: u64 = 10;
+
+/// ... and these make up at least this share of its samples.
+const MIN_SHARE_AFTER_IRQ_ENABLE: f64 = 0.5;
+
+/// Detects kernel functions whose samples mostly land right after interrupts
+/// are re-enabled, which means that most of their time was spent with
+/// interrupts disabled and couldn't be sampled where it was spent.
+///
+/// We don't have the kernel's text bytes, so instead of decoding the
+/// instruction before the sampled one, we look for samples in the kernel's
+/// interrupt-enabling functions and blame their caller, which is the function
+/// which ran with interrupts disabled.
+#[derive(Debug, Clone)]
+pub struct SamplingBiasDetector {
+    kernel_base_avma: u64,
+    kernel_symbol_table: Arc<SymbolTable>,
+    stats_by_function: HashMap<String, FunctionStats>,
+}
+
+#[derive(Debug, Clone)]
+struct FunctionStats {
+    /// Samples with this function as the leaf.
+    leaf_samples: u64,
+    /// Samples in an interrupt-enabling function called by this function.
+    samples_after_irq_enable: u64,
+    /// The thread and time range of the samples after an interrupt enable,
+    /// for the marker. The thread is the one of the first such sample.
+    marker_range: Option<(ThreadHandle, Timestamp, Timestamp)>,
+}
+
+impl SamplingBiasDetector {
+    pub fn new(kernel_base_avma: u64, kernel_symbol_table: Arc<SymbolTable>) -> Self {
+        Self {
+            kernel_base_avma,
+            kernel_symbol_table,
+            stats_by_function: HashMap::new(),
+        }
+    }
+
+    /// `stack` is ordered from callee-most to caller-most.
+    pub fn add_sample(&mut self, stack: &[StackFrame], thread: ThreadHandle, time: Timestamp) {
+        let kernel_base_avma = self.kernel_base_avma;
+        let symbol_table = self.kernel_symbol_table.clone();
+        let function_name = |address: u64| {
+            let relative_address = address.checked_sub(kernel_base_avma)?;
+            let relative_address = u32::try_from(relative_address).ok()?;
+            let symbol = symbol_table.lookup(relative_address)?;
+            Some(symbol.name.as_str())
+        };
+        let mut kernel_functions = stack.iter().filter_map(|frame| match *frame {
+            StackFrame::InstructionPointer(address, StackMode::Kernel) => function_name(address),
+            StackFrame::ReturnAddress(address, StackMode::Kernel) => {
+                function_name(address.saturating_sub(1))
+            }
+            _ => None,
+        });
+        let Some(leaf) = kernel_functions.next() else {
+            return;
+        };
+        if !is_irq_enabling_function(leaf) {
+            self.stats(leaf).leaf_samples += 1;
+            return;
+        }
+        let Some(caller) = kernel_functions.find(|name| !is_irq_enabling_function(name)) else {
+            return;
+        };
+        let stats = self.stats(caller);
+        stats.samples_after_irq_enable += 1;
+        stats.marker_range = Some(match stats.marker_range {
+            Some((thread, start, _)) => (thread, start, time),
+            None => (thread, time, time),
+        });
+    }
+
+    /// Logs the suspected functions and adds a marker for each of them.
+    pub fn finish(&self, profile: &mut Profile) {
+        let mut suspects: Vec<(&str, &FunctionStats, f64)> = self
+            .stats_by_function
+            .iter()
+            .filter_map(|(name, stats)| {
+                let share = stats.share_after_irq_enable();
+                let is_suspect = stats.samples_after_irq_enable >= MIN_SAMPLES_AFTER_IRQ_ENABLE
+                    && share >= MIN_SHARE_AFTER_IRQ_ENABLE;
+                is_suspect.then(|| (name.as_str(), stats, share))
+            })
+            .collect();
+        if suspects.is_empty() {
+            return;
+        }
+        suspects.sort_by(|(_, a, _), (_, b, _)| {
+            b.samples_after_irq_enable.cmp(&a.samples_after_irq_enable)
+        });
+
+        warn!(
+            "Sampling bias suspected: these kernel functions run with interrupts disabled, so \
+             their samples land where interrupts are re-enabled instead:"
+        );
+        for (name, stats, share) in suspects {
+            let sample_count = stats.samples_after_irq_enable + stats.leaf_samples;
+            warn!(
+                function = name,
+                samples_after_irq_enable = stats.samples_after_irq_enable,
+                sample_count,
+                share,
+                "  {name}: {} of {sample_count} samples ({:.0}%) right after interrupts were \
+                 re-enabled",
+                stats.samples_after_irq_enable,
+                share * 100.0
+            );
+            if let Some((thread, start, end)) = stats.marker_range {
+                profile.add_marker(
+                    thread,
+                    &format!("Sampling bias suspected in {name}"),
+                    SamplingBiasMarker {
+                        function: name.to_owned(),
+                        share,
+                        sample_count: stats.samples_after_irq_enable,
+                    },
+                    MarkerTiming::Interval(start, end),
+                );
+            }
+        }
+    }
+
+    fn stats(&mut self, name: &str) -> &mut FunctionStats {
+        self.stats_by_function
+            .entry(name.to_owned())
+            .or_insert(FunctionStats {
+                leaf_samples: 0,
+                samples_after_irq_enable: 0,
+                marker_range: None,
+            })
+    }
+}
+
+impl FunctionStats {
+    fn share_after_irq_enable(&self) -> f64 {
+        let total = self.samples_after_irq_enable + self.leaf_samples;
+        self.samples_after_irq_enable as f64 / total as f64
+    }
+}
+
+/// Compiler-generated suffixes like "finish_task_switch.isra.0" are ignored.
+fn is_irq_enabling_function(name: &str) -> bool {
+    let name = name.split('.').next().unwrap_or(name);
+    IRQ_ENABLING_KERNEL_FUNCTIONS.contains(&name)
+}
+
+/// Put on the thread with the first biased sample of a suspected function,
+/// spanning its biased samples.
+#[derive(Debug, Clone)]
+pub struct SamplingBiasMarker {
+    pub function: String,
+    /// The share of the function's samples which landed right after
+    /// interrupts were re-enabled, between 0 and 1.
+    pub share: f64,
+    pub sample_count: u64,
+}
+
+impl ProfilerMarker for SamplingBiasMarker {
+    const MARKER_TYPE_NAME: &'static str = "SamplingBias";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "function": self.function,
+            "share": self.share,
+            "sampleCount": self.sample_count,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("Sampling bias suspected in {marker.data.function}"),
+            tooltip_label: Some("Sampling bias suspected in {marker.data.function}"),
+            table_label: Some("Sampling bias suspected in {marker.data.function}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "function",
+                    label: "Function",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "share",
+                    label: "Share of samples after interrupt enable",
+                    format: MarkerFieldFormat::Percentage,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "sampleCount",
+                    label: "Samples after interrupt enable",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "This function runs with interrupts disabled, so timer-based \
+                            samples can't be taken while it does. They're taken right after \
+                            interrupts are re-enabled instead, which makes the interrupt-enabling \
+                            function look expensive and this function look cheap.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::{ReferenceTimestamp, SamplingInterval, Symbol};
+
+    use super::*;
+
+    const KERNEL_BASE: u64 = 0xffffffff81000000;
+
+    #[test]
+    fn blames_callers_of_irq_enabling_functions() {
+        let symbol = |address, name: &str| Symbol {
+            address,
+            size: None,
+            name: name.to_string(),
+        };
+        let symbol_table = SymbolTable::new(vec![
+            symbol(0x1000, "do_work_with_lock"),
+            symbol(0x2000, "_raw_spin_unlock_irqrestore"),
+            symbol(0x3000, "finish_task_switch.isra.0"),
+            symbol(0x4000, "schedule"),
+        ]);
+        let mut detector = SamplingBiasDetector::new(KERNEL_BASE, Arc::new(symbol_table));
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let process = profile.add_process("p", 1, Timestamp::from_millis_since_reference(0.0));
+        let thread = profile.add_thread(
+            process,
+            1,
+            Timestamp::from_millis_since_reference(0.0),
+            true,
+        );
+        let time = Timestamp::from_millis_since_reference(0.0);
+
+        let unlock_in_work = [
+            StackFrame::InstructionPointer(KERNEL_BASE + 0x2010, StackMode::Kernel),
+            StackFrame::ReturnAddress(KERNEL_BASE + 0x1080, StackMode::Kernel),
+        ];
+        let in_work = [StackFrame::InstructionPointer(
+            KERNEL_BASE + 0x1040,
+            StackMode::Kernel,
+        )];
+        for _ in 0..12 {
+            detector.add_sample(&unlock_in_work, thread, time);
+        }
+        for _ in 0..3 {
+            detector.add_sample(&in_work, thread, time);
+        }
+        let stats = &detector.stats_by_function["do_work_with_lock"];
+        assert_eq!(stats.samples_after_irq_enable, 12);
+        assert_eq!(stats.leaf_samples, 3);
+        assert!(stats.share_after_irq_enable() > 0.79);
+
+        assert!(is_irq_enabling_function("finish_task_switch.isra.0"));
+        assert!(!is_irq_enabling_function("schedule"));
+        detector.finish(&mut profile);
+    }
+}
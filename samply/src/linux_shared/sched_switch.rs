@@ -0,0 +1,24 @@
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+
+/// Handles "sched:sched_switch" samples, which are emitted when a thread goes
+/// to sleep. The sample's stack is remembered as the thread's off-CPU stack.
+#[derive(Debug, Default)]
+pub struct SchedSwitchHandler;
+
+impl TracepointHandler for SchedSwitchHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        attr_name == "sched:sched_switch"
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let Some(tid) = e.tid else {
+            return;
+        };
+        let stack_index = ctx
+            .unresolved_stacks
+            .convert_no_kernel(ctx.stack.iter().rev().cloned());
+        ctx.set_off_cpu_stack(tid, stack_index);
+    }
+}
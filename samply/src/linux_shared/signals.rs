@@ -0,0 +1,247 @@
+use byteorder::ByteOrder;
+use linux_perf_data::linux_perf_event_reader::{RawData, SampleRecord};
+use linux_perf_data::Endianness;
+use tracing::warn;
+
+use super::process_exits::ExitReason;
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use crate::shared::unresolved_samples::UnresolvedStacks;
+
+const SIGNAL_DELIVER: &str = "signal:signal_deliver";
+
+/// The handler address of signals with the default action, SIG_DFL.
+const SIG_DFL: u64 = 0;
+
+/// The first real-time signal. Real-time signals terminate the process by
+/// default.
+const SIGRTMIN: i32 = 32;
+
+/// Names for the Linux signal numbers, from asm-generic/signal.h, and whether
+/// the default action of the signal terminates the process.
+const SIGNALS: &[(i32, &str, bool)] = &[
+    (1, "SIGHUP", true),
+    (2, "SIGINT", true),
+    (3, "SIGQUIT", true),
+    (4, "SIGILL", true),
+    (5, "SIGTRAP", true),
+    (6, "SIGABRT", true),
+    (7, "SIGBUS", true),
+    (8, "SIGFPE", true),
+    (9, "SIGKILL", true),
+    (10, "SIGUSR1", true),
+    (11, "SIGSEGV", true),
+    (12, "SIGUSR2", true),
+    (13, "SIGPIPE", true),
+    (14, "SIGALRM", true),
+    (15, "SIGTERM", true),
+    (16, "SIGSTKFLT", true),
+    (17, "SIGCHLD", false),
+    (18, "SIGCONT", false),
+    (19, "SIGSTOP", false),
+    (20, "SIGTSTP", false),
+    (21, "SIGTTIN", false),
+    (22, "SIGTTOU", false),
+    (23, "SIGURG", false),
+    (24, "SIGXCPU", true),
+    (25, "SIGXFSZ", true),
+    (26, "SIGVTALRM", true),
+    (27, "SIGPROF", true),
+    (28, "SIGWINCH", false),
+    (29, "SIGIO", true),
+    (30, "SIGPWR", true),
+    (31, "SIGSYS", true),
+];
+
+/// Parses a signal given by name ("SIGCHLD" or "CHLD") or by number ("17").
+pub fn parse_signal(s: &str) -> Option<i32> {
+    if let Ok(signal) = s.parse::<i32>() {
+        return Some(signal);
+    }
+    let name = s
+        .strip_prefix("SIG")
+        .or_else(|| s.strip_prefix("sig"))
+        .unwrap_or(s);
+    SIGNALS
+        .iter()
+        .find(|(_, n, _)| n[3..].eq_ignore_ascii_case(name))
+        .map(|(signal, _, _)| *signal)
+}
+
+pub(super) fn signal_name(signal: i32) -> String {
+    match SIGNALS.iter().find(|(s, _, _)| *s == signal) {
+        Some((_, name, _)) => name.to_string(),
+        None if signal >= SIGRTMIN => format!("SIGRTMIN+{}", signal - SIGRTMIN),
+        None => format!("signal {signal}"),
+    }
+}
+
+fn default_action_terminates(signal: i32) -> bool {
+    match SIGNALS.iter().find(|(s, _, _)| *s == signal) {
+        Some((_, _, terminates)) => *terminates,
+        None => signal >= SIGRTMIN,
+    }
+}
+
+/// Handles the signal:signal_deliver tracepoint and emits a "Received SIGSEGV"
+/// marker on the thread which the signal was delivered to. The marker gets the
+/// stack of the thread's most recent sample, unless the tracepoint sample has
+/// a stack of its own, so that a crash shows up close to where it happened.
+///
+/// A signal is fatal if it's delivered with the default action and the
+/// default action terminates the process. Fatal signals are listed in the
+/// profile's info panel and printed at the end of the conversion. Markers for
+/// the other signals can be turned off per signal number, e.g. for SIGCHLD.
+#[derive(Debug, Default)]
+pub struct SignalHandler {
+    ignored_signals: Vec<i32>,
+    crashes: Vec<Crash>,
+}
+
+#[derive(Debug)]
+struct Crash {
+    pid: i32,
+    tid: i32,
+    signal: i32,
+}
+
+impl SignalHandler {
+    pub fn new(ignored_signals: Vec<i32>) -> Self {
+        Self {
+            ignored_signals,
+            crashes: Vec::new(),
+        }
+    }
+}
+
+impl TracepointHandler for SignalHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        attr_name == SIGNAL_DELIVER
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(raw), Some(pid), Some(tid), Some(timestamp_mono)) =
+            (e.raw, e.pid, e.tid, e.timestamp)
+        else {
+            return;
+        };
+        let Ok(deliver) = SignalDeliver::parse(raw, ctx.endian) else {
+            return;
+        };
+        let fatal = deliver.sa_handler == SIG_DFL && default_action_terminates(deliver.signal);
+        if !fatal && self.ignored_signals.contains(&deliver.signal) {
+            return;
+        }
+
+        let thread = ctx.thread_handle(tid);
+        let stack = ctx.stack_or_last_sample_stack(thread);
+        let timestamp = ctx.timestamp_converter.convert_time(timestamp_mono);
+        let name = signal_name(deliver.signal);
+        ctx.unresolved_samples.add_signal_marker(
+            thread,
+            timestamp,
+            timestamp_mono,
+            stack,
+            name.clone(),
+            deliver.code,
+            fatal,
+        );
+
+        if fatal {
+            ctx.set_exit_reason(pid, ExitReason::Signal(deliver.signal));
+            ctx.profile.add_extra_info(
+                "Crashes",
+                &format!("Process {pid}"),
+                &format!("Terminated by {name} on thread {tid}"),
+            );
+            self.crashes.push(Crash {
+                pid,
+                tid,
+                signal: deliver.signal,
+            });
+        }
+    }
+
+    fn only_uses_stack_for_markers(&self) -> bool {
+        true
+    }
+
+    fn finish(&mut self, _unresolved_stacks: &UnresolvedStacks) {
+        for Crash { pid, tid, signal } in &self.crashes {
+            let name = signal_name(*signal);
+            warn!(
+                pid,
+                tid,
+                signal = name,
+                "Process {pid} was terminated by {name} on thread {tid}. The \"Received {name}\" \
+                 marker on that thread has the thread's last sampled stack."
+            );
+        }
+    }
+}
+
+/// The fields we need from the signal_deliver tracepoint.
+///
+/// ```
+/// # cat /sys/kernel/debug/tracing/events/signal/signal_deliver/format
+/// name: signal_deliver
+/// ID: 186
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:int sig;  offset:8;       size:4; signed:1;
+///         field:int errno;        offset:12;      size:4; signed:1;
+///         field:int code; offset:16;      size:4; signed:1;
+///         field:unsigned long sa_handler; offset:24;      size:8; signed:0;
+///         field:unsigned long sa_flags;   offset:32;      size:8; signed:0;
+/// ```
+#[derive(Debug)]
+struct SignalDeliver {
+    signal: i32,
+    code: i32,
+    sa_handler: u64,
+}
+
+impl SignalDeliver {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        let _common = data.read_u64::<O>()?;
+        let signal = data.read_i32::<O>()?;
+        let _errno = data.read_i32::<O>()?;
+        let code = data.read_i32::<O>()?;
+        let _padding = data.read_u32::<O>()?;
+        let sa_handler = data.read_u64::<O>()?;
+        Ok(SignalDeliver {
+            signal,
+            code,
+            sa_handler,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{default_action_terminates, parse_signal, signal_name};
+
+    #[test]
+    fn parses_signals() {
+        assert_eq!(parse_signal("SIGCHLD"), Some(17));
+        assert_eq!(parse_signal("chld"), Some(17));
+        assert_eq!(parse_signal("sigsegv"), Some(11));
+        assert_eq!(parse_signal("14"), Some(14));
+        assert_eq!(parse_signal("SIGWHATEVER"), None);
+        assert_eq!(signal_name(6), "SIGABRT");
+        assert_eq!(signal_name(34), "SIGRTMIN+2");
+        assert!(default_action_terminates(11));
+        assert!(!default_action_terminates(17));
+        assert!(default_action_terminates(40));
+    }
+}
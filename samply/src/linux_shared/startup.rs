@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, ProfilerMarker,
+};
+use serde_json::json;
+
+/// The startup of a process which was launched during the recording, from its
+/// fork or exec to its first sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessStartup {
+    pub pid: i32,
+    pub name: Option<String>,
+    /// The timestamp of the fork record, if the process was forked during the
+    /// recording.
+    pub fork_timestamp: Option<u64>,
+    /// The timestamp of the exec record, if the process exec'd during the
+    /// recording. Library loading is only counted after the exec.
+    pub exec_timestamp: Option<u64>,
+    /// The timestamps of the first and the last executable mmap record before
+    /// the first sample.
+    pub mmap_range: Option<(u64, u64)>,
+    pub mmap_count: u64,
+    pub first_sample_timestamp: Option<u64>,
+}
+
+impl ProcessStartup {
+    /// The exec timestamp, or the fork timestamp for processes which didn't exec.
+    pub fn start_timestamp(&self) -> u64 {
+        self.exec_timestamp.or(self.fork_timestamp).unwrap_or(0)
+    }
+
+    pub fn time_to_first_sample_ns(&self) -> Option<u64> {
+        let first_sample_timestamp = self.first_sample_timestamp?;
+        Some(first_sample_timestamp.saturating_sub(self.start_timestamp()))
+    }
+
+    /// The time from the first to the last mmap record before the first sample.
+    pub fn library_loading_ns(&self) -> Option<u64> {
+        let (first, last) = self.mmap_range?;
+        Some(last - first)
+    }
+
+    /// The name of the startup marker, e.g. "Startup: exec→first sample".
+    pub fn marker_name(&self) -> &'static str {
+        if self.exec_timestamp.is_some() {
+            "Startup: exec→first sample"
+        } else {
+            "Startup: fork→first sample"
+        }
+    }
+}
+
+/// Tracks the startup of the processes which were forked or exec'd during the
+/// recording. Processes which already existed when the recording started have
+/// no fork or exec record, so they're never tracked.
+#[derive(Debug, Clone, Default)]
+pub struct StartupTracker {
+    startups: Vec<ProcessStartup>,
+    /// The index into `startups` for each process which hasn't had its first
+    /// sample yet.
+    pending_by_pid: HashMap<i32, usize>,
+}
+
+impl StartupTracker {
+    pub fn on_fork(&mut self, pid: i32, timestamp: u64) {
+        self.start(pid, Some(timestamp), None);
+    }
+
+    pub fn on_exec(&mut self, pid: i32, timestamp: u64) {
+        if let Some(startup) = self.pending(pid) {
+            // Forked and exec'd without a sample in between. The libraries
+            // which were mapped before the exec belonged to the old image.
+            startup.exec_timestamp = Some(timestamp);
+            startup.mmap_range = None;
+            startup.mmap_count = 0;
+            return;
+        }
+        self.start(pid, None, Some(timestamp));
+    }
+
+    pub fn on_mmap(&mut self, pid: i32, timestamp: u64) {
+        let Some(startup) = self.pending(pid) else {
+            return;
+        };
+        if timestamp < startup.start_timestamp() {
+            return;
+        }
+        startup.mmap_range = Some(match startup.mmap_range {
+            Some((first, _)) => (first, timestamp),
+            None => (timestamp, timestamp),
+        });
+        startup.mmap_count += 1;
+    }
+
+    /// Returns the finished startup if this is the first sample of a tracked process.
+    pub fn on_sample(
+        &mut self,
+        pid: i32,
+        timestamp: u64,
+        name: Option<&str>,
+    ) -> Option<&ProcessStartup> {
+        let index = self.pending_by_pid.remove(&pid)?;
+        let startup = &mut self.startups[index];
+        startup.first_sample_timestamp = Some(timestamp);
+        startup.name = name.map(ToOwned::to_owned);
+        Some(startup)
+    }
+
+    /// Prints the startup table, slowest first. Processes which never had a
+    /// sample are left out.
+    pub fn report(&self) {
+        let mut startups: Vec<(&ProcessStartup, u64)> = self
+            .startups
+            .iter()
+            .filter_map(|startup| Some((startup, startup.time_to_first_sample_ns()?)))
+            .collect();
+        if startups.is_empty() {
+            return;
+        }
+        startups.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        eprintln!("Process startup, by time to first sample:");
+        eprintln!(
+            "  {:>8}  {:>14}  {:>16}  {:>6}  name",
+            "pid", "first sample", "library loading", "mmaps"
+        );
+        for (startup, time_to_first_sample_ns) in startups {
+            let library_loading = match startup.library_loading_ns() {
+                Some(ns) => format!("{:.1}ms", ns as f64 / 1_000_000.0),
+                None => "-".to_string(),
+            };
+            eprintln!(
+                "  {:>8}  {:>12.1}ms  {library_loading:>16}  {:>6}  {}",
+                startup.pid,
+                time_to_first_sample_ns as f64 / 1_000_000.0,
+                startup.mmap_count,
+                startup.name.as_deref().unwrap_or("<unknown>"),
+            );
+        }
+    }
+
+    fn start(&mut self, pid: i32, fork_timestamp: Option<u64>, exec_timestamp: Option<u64>) {
+        self.pending_by_pid.insert(pid, self.startups.len());
+        self.startups.push(ProcessStartup {
+            pid,
+            name: None,
+            fork_timestamp,
+            exec_timestamp,
+            mmap_range: None,
+            mmap_count: 0,
+            first_sample_timestamp: None,
+        });
+    }
+
+    fn pending(&mut self, pid: i32) -> Option<&mut ProcessStartup> {
+        let index = *self.pending_by_pid.get(&pid)?;
+        Some(&mut self.startups[index])
+    }
+}
+
+/// Put on the main thread of a process which was launched during the
+/// recording, from its fork or exec to its first sample.
+#[derive(Debug, Clone)]
+pub struct StartupMarker {
+    pub library_loading_ms: f64,
+    pub mmap_count: u64,
+}
+
+impl ProfilerMarker for StartupMarker {
+    const MARKER_TYPE_NAME: &'static str = "ProcessStartup";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "libraryLoading": self.library_loading_ms,
+            "mmapCount": self.mmap_count,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.name}"),
+            tooltip_label: Some("{marker.name}"),
+            table_label: Some("{marker.name}, {marker.data.mmapCount} mmaps"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "libraryLoading",
+                    label: "Library loading",
+                    format: MarkerFieldFormat::Milliseconds,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "mmapCount",
+                    label: "Executable mmaps",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The time from the process's exec, or from its fork if it didn't \
+                            exec, to its first sample. Library loading is the span of the \
+                            executable mmap records in that time.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tracks_startup_until_first_sample() {
+        let mut tracker = StartupTracker::default();
+        // Already running when the recording started.
+        tracker.on_mmap(1, 5);
+        assert_eq!(tracker.on_sample(1, 6, Some("bash")), None);
+
+        tracker.on_fork(2, 10);
+        tracker.on_mmap(2, 12);
+        tracker.on_exec(2, 20);
+        tracker.on_mmap(2, 25);
+        tracker.on_mmap(2, 40);
+        let startup = tracker.on_sample(2, 50, Some("app")).unwrap();
+        assert_eq!(startup.marker_name(), "Startup: exec→first sample");
+        assert_eq!(startup.time_to_first_sample_ns(), Some(30));
+        assert_eq!(startup.library_loading_ns(), Some(15));
+        assert_eq!(startup.mmap_count, 2);
+        // Only the first sample ends the startup.
+        tracker.on_mmap(2, 60);
+        assert_eq!(tracker.on_sample(2, 70, Some("app")), None);
+
+        tracker.on_fork(3, 100);
+        let startup = tracker.on_sample(3, 104, None).unwrap();
+        assert_eq!(startup.marker_name(), "Startup: fork→first sample");
+        assert_eq!(startup.time_to_first_sample_ns(), Some(4));
+        assert_eq!(startup.library_loading_ns(), None);
+
+        // An exec after the first sample starts a new startup.
+        tracker.on_exec(3, 200);
+        let startup = tracker.on_sample(3, 201, Some("child")).unwrap();
+        assert_eq!(startup.fork_timestamp, None);
+        assert_eq!(startup.time_to_first_sample_ns(), Some(1));
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerStaticField, MarkerTiming, ProfilerMarker,
+};
+use linux_perf_data::linux_perf_event_reader::{RawData, SampleRecord};
+use linux_perf_data::Endianness;
+use serde_json::json;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+
+/// The errnos which are reported by default. These are the failures which
+/// usually indicate that a resource limit has been hit.
+pub const DEFAULT_SYSCALL_FAILURE_ERRNOS: &[i64] = &[EMFILE, ENFILE, ENOMEM, EAGAIN];
+
+/// Failures of the same syscall with the same errno on the same thread are
+/// folded into one marker if they happen within this interval.
+const MIN_MARKER_INTERVAL_NS: u64 = 100_000_000;
+
+const EAGAIN: i64 = 11;
+const ENOMEM: i64 = 12;
+const ENFILE: i64 = 23;
+const EMFILE: i64 = 24;
+
+/// Names for the Linux errno values, from asm-generic/errno-base.h and
+/// asm-generic/errno.h. Only the commonly seen ones are listed.
+const ERRNO_NAMES: &[(i64, &str)] = &[
+    (1, "EPERM"),
+    (2, "ENOENT"),
+    (3, "ESRCH"),
+    (4, "EINTR"),
+    (5, "EIO"),
+    (6, "ENXIO"),
+    (7, "E2BIG"),
+    (8, "ENOEXEC"),
+    (9, "EBADF"),
+    (10, "ECHILD"),
+    (EAGAIN, "EAGAIN"),
+    (ENOMEM, "ENOMEM"),
+    (13, "EACCES"),
+    (14, "EFAULT"),
+    (16, "EBUSY"),
+    (17, "EEXIST"),
+    (18, "EXDEV"),
+    (19, "ENODEV"),
+    (20, "ENOTDIR"),
+    (21, "EISDIR"),
+    (22, "EINVAL"),
+    (ENFILE, "ENFILE"),
+    (EMFILE, "EMFILE"),
+    (25, "ENOTTY"),
+    (26, "ETXTBSY"),
+    (27, "EFBIG"),
+    (28, "ENOSPC"),
+    (29, "ESPIPE"),
+    (30, "EROFS"),
+    (31, "EMLINK"),
+    (32, "EPIPE"),
+    (36, "ENAMETOOLONG"),
+    (38, "ENOSYS"),
+    (75, "EOVERFLOW"),
+    (95, "EOPNOTSUPP"),
+    (98, "EADDRINUSE"),
+    (99, "EADDRNOTAVAIL"),
+    (104, "ECONNRESET"),
+    (105, "ENOBUFS"),
+    (110, "ETIMEDOUT"),
+    (111, "ECONNREFUSED"),
+    (122, "EDQUOT"),
+];
+
+/// Parses an errno given by name ("EMFILE") or by number ("24").
+pub fn parse_errno(s: &str) -> Option<i64> {
+    if let Ok(errno) = s.parse::<i64>() {
+        return Some(errno);
+    }
+    ERRNO_NAMES
+        .iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(s))
+        .map(|(errno, _)| *errno)
+}
+
+fn errno_name(errno: i64) -> String {
+    match ERRNO_NAMES.iter().find(|(e, _)| *e == errno) {
+        Some((_, name)) => name.to_string(),
+        None => format!("errno {errno}"),
+    }
+}
+
+/// Handles "syscalls:sys_exit_*" and "raw_syscalls:sys_exit" samples, and
+/// emits a marker on the calling thread whenever a syscall fails with one of
+/// the configured errnos.
+///
+/// Repeated failures are rate-limited per (thread, syscall, errno): after a
+/// marker is emitted, further failures within `MIN_MARKER_INTERVAL_NS` are
+/// only counted, and the count is attached to the next marker.
+#[derive(Debug)]
+pub struct SyscallFailureHandler {
+    errnos: Vec<i64>,
+    rate_limit_state: HashMap<(i32, String, i64), RateLimitState>,
+}
+
+#[derive(Debug)]
+struct RateLimitState {
+    last_marker_timestamp: u64,
+    suppressed_count: u64,
+}
+
+impl SyscallFailureHandler {
+    pub fn new(errnos: Vec<i64>) -> Self {
+        Self {
+            errnos,
+            rate_limit_state: HashMap::new(),
+        }
+    }
+}
+
+impl Default for SyscallFailureHandler {
+    fn default() -> Self {
+        Self::new(DEFAULT_SYSCALL_FAILURE_ERRNOS.to_vec())
+    }
+}
+
+impl TracepointHandler for SyscallFailureHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        attr_name.starts_with("syscalls:sys_exit_") || attr_name == "raw_syscalls:sys_exit"
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(raw), Some(tid), Some(timestamp_mono)) = (e.raw, e.tid, e.timestamp) else {
+            return;
+        };
+        let Ok(sys_exit) = SysExit::parse(raw, ctx.endian) else { return };
+        if sys_exit.ret >= 0 {
+            return;
+        }
+        let errno = -sys_exit.ret;
+        if !self.errnos.contains(&errno) {
+            return;
+        }
+
+        let syscall = match ctx.attr_name.strip_prefix("syscalls:sys_exit_") {
+            Some(name) => name.to_string(),
+            None => format!("syscall {}", sys_exit.syscall_nr),
+        };
+
+        let count = match self.rate_limit_state.entry((tid, syscall.clone(), errno)) {
+            Entry::Occupied(mut entry) => {
+                let state = entry.get_mut();
+                if timestamp_mono < state.last_marker_timestamp + MIN_MARKER_INTERVAL_NS {
+                    state.suppressed_count += 1;
+                    return;
+                }
+                let count = state.suppressed_count + 1;
+                state.last_marker_timestamp = timestamp_mono;
+                state.suppressed_count = 0;
+                count
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(RateLimitState {
+                    last_marker_timestamp: timestamp_mono,
+                    suppressed_count: 0,
+                });
+                1
+            }
+        };
+
+        let thread = ctx.thread_handle(tid);
+        let timestamp = ctx.timestamp_converter.convert_time(timestamp_mono);
+        let errno_name = errno_name(errno);
+        ctx.profile.add_marker(
+            thread,
+            &format!("{syscall} failed: {errno_name}"),
+            SyscallFailureMarker {
+                syscall,
+                errno_name,
+                count,
+            },
+            MarkerTiming::Instant(timestamp),
+        );
+    }
+}
+
+/// The fields we need from a sys_exit tracepoint.
+///
+/// ```
+/// # cat /sys/kernel/debug/tracing/events/syscalls/sys_exit_openat/format
+/// name: sys_exit_openat
+/// ID: 631
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:int __syscall_nr; offset:8;       size:4; signed:1;
+///         field:long ret; offset:16;      size:8; signed:1;
+/// ```
+///
+/// raw_syscalls:sys_exit has `long id` at offset 8 instead, but the low 32
+/// bits of it are at the same place on little-endian machines, and `ret` is at
+/// offset 16 in both.
+#[derive(Debug)]
+struct SysExit {
+    syscall_nr: i32,
+    ret: i64,
+}
+
+impl SysExit {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        let _common = data.read_u64::<O>()?;
+        let syscall_nr = data.read_i32::<O>()?;
+        let _padding = data.read_u32::<O>()?;
+        let ret = data.read_u64::<O>()? as i64;
+        Ok(SysExit { syscall_nr, ret })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyscallFailureMarker {
+    syscall: String,
+    errno_name: String,
+    count: u64,
+}
+
+impl ProfilerMarker for SyscallFailureMarker {
+    const MARKER_TYPE_NAME: &'static str = "SyscallFailure";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "syscall": self.syscall,
+            "errno": self.errno_name,
+            "count": self.count,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.syscall}: {marker.data.errno}"),
+            tooltip_label: Some("{marker.data.syscall} failed with {marker.data.errno}"),
+            table_label: Some(
+                "{marker.data.syscall} failed with {marker.data.errno} ({marker.data.count}x)",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "syscall",
+                    label: "Syscall",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "errno",
+                    label: "Error",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "count",
+                    label: "Failures",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted when a syscall fails with an errno that indicates resource exhaustion.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{errno_name, parse_errno, SysExit};
+    use linux_perf_data::linux_perf_event_reader::RawData;
+    use linux_perf_data::Endianness;
+
+    #[test]
+    fn parses_errnos() {
+        assert_eq!(parse_errno("EMFILE"), Some(24));
+        assert_eq!(parse_errno("enomem"), Some(12));
+        assert_eq!(parse_errno("105"), Some(105));
+        assert_eq!(parse_errno("EWHATEVER"), None);
+        assert_eq!(errno_name(23), "ENFILE");
+        assert_eq!(errno_name(1000), "errno 1000");
+    }
+
+    #[test]
+    fn parses_sys_exit() {
+        let mut data = vec![0u8; 8];
+        data.extend_from_slice(&257i32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(-24i64).to_le_bytes());
+        let sys_exit = SysExit::parse(RawData::Single(&data), Endianness::LittleEndian).unwrap();
+        assert_eq!(sys_exit.syscall_nr, 257);
+        assert_eq!(sys_exit.ret, -24);
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use linux_perf_data::Endianness;
+
+use crate::shared::marker_addresses::MarkerValue;
+
+/// The magic bytes at the start of perf's tracing data.
+const TRACING_DATA_MAGIC: &[u8] = b"\x17\x08\x44tracing";
+
+/// The format descriptions of the tracepoint events in a recording, keyed by
+/// event name, e.g. "probe:my_function".
+///
+/// perf stores them in the tracing data, which is the HEADER_TRACING_DATA
+/// feature section of a perf.data file, or a PERF_RECORD_HEADER_TRACING_DATA
+/// record in a stream. It's a copy of the format files in
+/// /sys/kernel/tracing/events/, for the recorded events.
+#[derive(Debug, Clone, Default)]
+pub struct TracepointFormats {
+    formats_by_name: HashMap<String, Arc<EventFormat>>,
+}
+
+impl TracepointFormats {
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        let mut reader = TracingDataReader {
+            data,
+            big_endian: false,
+        };
+        if reader.bytes(TRACING_DATA_MAGIC.len())? != TRACING_DATA_MAGIC {
+            return Err("bad magic");
+        }
+        let _version = reader.c_string()?;
+        reader.big_endian = reader.bytes(1)?[0] != 0;
+        let _long_size = reader.bytes(1)?;
+        let _page_size = reader.u32()?;
+
+        for header_name in ["header_page", "header_event"] {
+            if reader.c_string()? != header_name {
+                return Err("missing header file");
+            }
+            let size = reader.u64()?;
+            reader.bytes(size as usize)?;
+        }
+        let ftrace_format_count = reader.u32()?;
+        for _ in 0..ftrace_format_count {
+            let size = reader.u64()?;
+            reader.bytes(size as usize)?;
+        }
+
+        let mut formats_by_name = HashMap::new();
+        let system_count = reader.u32()?;
+        for _ in 0..system_count {
+            let system = reader.c_string()?;
+            let event_count = reader.u32()?;
+            for _ in 0..event_count {
+                let size = reader.u64()?;
+                let text = reader.bytes(size as usize)?;
+                if let Some(format) = EventFormat::parse(&String::from_utf8_lossy(text)) {
+                    let name = format!("{system}:{}", format.name);
+                    formats_by_name.insert(name, Arc::new(format));
+                }
+            }
+        }
+        // The kallsyms, printk formats and saved cmdlines which follow aren't needed.
+        Ok(Self { formats_by_name })
+    }
+
+    /// `event_name` is the name of the event attribute, e.g. "probe:my_function".
+    pub fn get(&self, event_name: &str) -> Option<&Arc<EventFormat>> {
+        self.formats_by_name.get(event_name)
+    }
+
+    #[cfg(test)]
+    pub fn insert(&mut self, event_name: &str, format: EventFormat) {
+        self.formats_by_name
+            .insert(event_name.to_string(), Arc::new(format));
+    }
+}
+
+struct TracingDataReader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> TracingDataReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.data.len() < len {
+            return Err("unexpected end of the tracing data");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn c_string(&mut self) -> Result<&'a str, &'static str> {
+        let len = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("unterminated string in the tracing data")?;
+        let s = std::str::from_utf8(self.bytes(len)?).map_err(|_| "bad string")?;
+        self.bytes(1)?;
+        Ok(s)
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        let bytes = self.bytes(4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        let bytes = self.bytes(8)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u64::from_be_bytes(bytes),
+            false => u64::from_le_bytes(bytes),
+        })
+    }
+}
+
+/// The format of one tracepoint event, e.g.
+///
+/// ```text
+/// name: my_function
+/// ID: 1532
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:unsigned long __probe_ip; offset:8;       size:8; signed:0;
+///         field:s32 arg1; offset:16;      size:4; signed:1;
+///
+/// print fmt: "(%lx) arg1=%d", REC->__probe_ip, REC->arg1
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventFormat {
+    pub name: String,
+    pub fields: Vec<FieldFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldFormat {
+    pub name: String,
+    /// The C type, e.g. "unsigned long" or "__data_loc char[]".
+    pub type_name: String,
+    pub offset: usize,
+    pub size: usize,
+    pub signed: bool,
+    /// Whether the print format shows the field in hex, like the "x64"
+    /// arguments of `perf probe`.
+    pub hex: bool,
+}
+
+impl EventFormat {
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut name = None;
+        let mut fields = Vec::new();
+        let mut print_fmt = "";
+        for line in text.lines() {
+            let line = line.trim();
+            if let Some(n) = line.strip_prefix("name:") {
+                name = Some(n.trim().to_string());
+            } else if let Some(field) = line.strip_prefix("field:") {
+                fields.extend(FieldFormat::parse(field));
+            } else if let Some(fmt) = line.strip_prefix("print fmt:") {
+                print_fmt = fmt;
+            }
+        }
+        for field in &mut fields {
+            field.hex = print_fmt.contains(&format!("{}=0x%", field.name));
+        }
+        Some(Self {
+            name: name?,
+            fields,
+        })
+    }
+
+    /// Decodes the event-specific fields of a sample's raw data, as names
+    /// and values. The common fields and the probe address fields which
+    /// `perf probe` adds are skipped, and so are fields whose type isn't
+    /// supported. Addresses are tagged, see [`FieldFormat::is_address`].
+    pub fn decode_fields(&self, raw: &[u8], endian: Endianness) -> Vec<(String, MarkerValue)> {
+        self.fields
+            .iter()
+            .filter(|field| {
+                !field.name.starts_with("common_") && !field.name.starts_with("__probe")
+            })
+            .filter_map(|field| Some((field.name.clone(), field.decode_value(raw, endian)?)))
+            .collect()
+    }
+
+    /// Decodes a single field of a sample's raw data, as a string. Addresses
+    /// are formatted as hex numbers.
+    pub fn decode_field(&self, raw: &[u8], endian: Endianness, name: &str) -> Option<String> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)?
+            .decode(raw, endian)
+    }
+}
+
+impl FieldFormat {
+    /// Parses "unsigned long __probe_ip;\toffset:8;\tsize:8;\tsigned:0;".
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(';').map(str::trim);
+        let declaration = parts.next()?;
+        let (mut offset, mut size, mut signed) = (None, None, false);
+        for part in parts {
+            match part.split_once(':') {
+                Some(("offset", v)) => offset = v.parse().ok(),
+                Some(("size", v)) => size = v.parse().ok(),
+                Some(("signed", v)) => signed = v == "1",
+                _ => {}
+            }
+        }
+        let (type_name, name) = declaration.rsplit_once(' ')?;
+        // Strip the array length from "char comm[16]".
+        let (name, type_name) = match name.split_once('[') {
+            Some((name, array)) => (name, format!("{type_name}[{array}")),
+            None => (name, type_name.to_string()),
+        };
+        Some(Self {
+            name: name.to_string(),
+            type_name,
+            offset: offset?,
+            size: size?,
+            signed,
+            hex: false,
+        })
+    }
+
+    /// Whether the field holds an address: a C pointer, or a 64 bit value
+    /// which the print format shows in hex. `perf probe` gives pointer
+    /// arguments the "x64" type, which is printed in hex.
+    fn is_address(&self) -> bool {
+        self.type_name.contains('*') || (self.hex && self.size == 8)
+    }
+
+    fn decode_value(&self, raw: &[u8], endian: Endianness) -> Option<MarkerValue> {
+        if self.is_address() && matches!(self.size, 4 | 8) {
+            let bytes = raw.get(self.offset..self.offset + self.size)?;
+            return Some(MarkerValue::Address(read_uint(bytes, endian)));
+        }
+        self.decode(raw, endian).map(MarkerValue::Text)
+    }
+
+    fn decode(&self, raw: &[u8], endian: Endianness) -> Option<String> {
+        let bytes = raw.get(self.offset..self.offset + self.size)?;
+        if self.type_name.starts_with("__data_loc") {
+            // The value is the offset of the data in the record, with its
+            // length in the upper 16 bits.
+            let loc = read_uint(bytes.get(..4)?, endian);
+            let (offset, len) = ((loc & 0xffff) as usize, (loc >> 16) as usize);
+            if !self.type_name.contains("char") {
+                return None;
+            }
+            return Some(c_string_value(raw.get(offset..offset + len)?));
+        }
+        if self.type_name.contains("char") && self.type_name.ends_with(']') {
+            return Some(c_string_value(bytes));
+        }
+        if !matches!(self.size, 1 | 2 | 4 | 8) {
+            return None;
+        }
+        let value = read_uint(bytes, endian);
+        Some(match (self.hex, self.signed) {
+            (true, _) => format!("0x{value:x}"),
+            (false, true) => {
+                let shift = 64 - 8 * self.size as u32;
+                (((value << shift) as i64) >> shift).to_string()
+            }
+            (false, false) => value.to_string(),
+        })
+    }
+}
+
+fn read_uint(bytes: &[u8], endian: Endianness) -> u64 {
+    let mut buf = [0; 8];
+    match endian {
+        Endianness::LittleEndian => {
+            buf[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(buf)
+        }
+        Endianness::BigEndian => {
+            buf[8 - bytes.len()..].copy_from_slice(bytes);
+            u64::from_be_bytes(buf)
+        }
+    }
+}
+
+/// Quotes a string field like perf script does.
+fn c_string_value(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    format!("\"{}\"", String::from_utf8_lossy(&bytes[..len]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROBE_FORMAT: &str = "name: my_function
+ID: 1532
+format:
+\tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;
+\tfield:unsigned char common_flags;\toffset:2;\tsize:1;\tsigned:0;
+\tfield:unsigned char common_preempt_count;\toffset:3;\tsize:1;\tsigned:0;
+\tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;
+
+\tfield:unsigned long __probe_ip;\toffset:8;\tsize:8;\tsigned:0;
+\tfield:s32 count;\toffset:16;\tsize:4;\tsigned:1;
+\tfield:u64 flags;\toffset:24;\tsize:8;\tsigned:0;
+\tfield:__data_loc char[] path;\toffset:32;\tsize:4;\tsigned:1;
+
+print fmt: \"(%lx) count=%d flags=0x%Lx path=\\\"%s\\\"\", REC->__probe_ip, REC->count, REC->flags, __get_str(path)
+";
+
+    fn tracing_data(system: &str, formats: &[&str]) -> Vec<u8> {
+        let mut data = TRACING_DATA_MAGIC.to_vec();
+        data.extend_from_slice(b"0.6\0");
+        data.extend_from_slice(&[0, 8]);
+        data.extend_from_slice(&4096u32.to_le_bytes());
+        for header in ["header_page", "header_event"] {
+            data.extend_from_slice(header.as_bytes());
+            data.push(0);
+            data.extend_from_slice(&3u64.to_le_bytes());
+            data.extend_from_slice(b"...");
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(system.as_bytes());
+        data.push(0);
+        data.extend_from_slice(&(formats.len() as u32).to_le_bytes());
+        for format in formats {
+            data.extend_from_slice(&(format.len() as u64).to_le_bytes());
+            data.extend_from_slice(format.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn decodes_probe_arguments() {
+        let formats = TracepointFormats::parse(&tracing_data("probe", &[PROBE_FORMAT])).unwrap();
+        assert!(formats.get("probe:other_function").is_none());
+        let format = formats.get("probe:my_function").unwrap();
+        assert_eq!(format.fields.len(), 8);
+
+        let mut raw = vec![0; 8];
+        raw.extend_from_slice(&0x401234u64.to_le_bytes());
+        raw.extend_from_slice(&(-42i32).to_le_bytes());
+        raw.extend_from_slice(&[0; 4]);
+        raw.extend_from_slice(&0x80u64.to_le_bytes());
+        raw.extend_from_slice(&((6u32 << 16) | 36).to_le_bytes());
+        raw.extend_from_slice(b"/tmp\0\0");
+        let text = |value: &str| MarkerValue::Text(value.to_string());
+        // The hex 64 bit field is tagged as an address.
+        assert_eq!(
+            format.decode_fields(&raw, Endianness::LittleEndian),
+            vec![
+                ("count".to_string(), text("-42")),
+                ("flags".to_string(), MarkerValue::Address(0x80)),
+                ("path".to_string(), text("\"/tmp\"")),
+            ]
+        );
+        // Fields beyond the end of the data are skipped.
+        assert_eq!(
+            format.decode_fields(&raw[..20], Endianness::LittleEndian),
+            vec![("count".to_string(), text("-42"))]
+        );
+    }
+}
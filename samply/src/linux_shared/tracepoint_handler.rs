@@ -0,0 +1,120 @@
+use fxprof_processed_profile::{ProcessHandle, Profile, ThreadHandle};
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+use linux_perf_data::Endianness;
+
+use super::process_exits::{ExitReason, ProcessExits};
+use super::tracepoint_format::TracepointFormats;
+use super::ProcessThreads;
+use crate::shared::timestamp_converter::TimestampConverter;
+use crate::shared::types::StackFrame;
+use crate::shared::unresolved_samples::{
+    UnresolvedSamples, UnresolvedStackHandle, UnresolvedStacks,
+};
+
+/// Converts the samples of a tracepoint event (or any other non-main event)
+/// into profile data.
+///
+/// Handlers are registered via
+/// [`ConversionOptions::tracepoint_handlers`](super::ConversionOptions::tracepoint_handlers).
+/// When the conversion starts, each event attribute is assigned to all
+/// handlers which want it, and all samples of that event are then passed to
+/// those handlers, in order, instead of being turned into generic event
+/// markers.
+///
+/// Registration is internal to the crate: samply only has a binary target, so
+/// the handlers are the converter's own modules, and some of the state in
+/// [`ConvertCtx`] is only visible to the `linux_shared` module.
+pub trait TracepointHandler {
+    /// Whether this handler wants to handle the samples of the event with
+    /// this name, e.g. "sched:sched_switch".
+    fn wants(&self, attr_name: &str) -> bool;
+
+    /// Handle a sample of one of the wanted events.
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord);
+
+    /// Whether the handler only uses the sample's stack for markers. If all
+    /// handlers of an event do, `--marker-stacks` decides whether the stack
+    /// is unwound, and `ConvertCtx::stack` is empty if it isn't.
+    fn only_uses_stack_for_markers(&self) -> bool {
+        false
+    }
+
+    /// Called once all samples have been handled, before `finish`, to add
+    /// the data which the handler has held back, e.g. the last bucket of a
+    /// [`BucketedCounter`](crate::shared::counter_buckets::BucketedCounter).
+    fn flush(&mut self, _profile: &mut Profile, _timestamp_converter: &TimestampConverter) {}
+
+    /// Called once all samples have been handled, e.g. to print a summary.
+    fn finish(&mut self, _unresolved_stacks: &UnresolvedStacks) {}
+}
+
+/// The parts of the converter state which a [`TracepointHandler`] can access
+/// while it handles a sample.
+///
+/// By the time the handler is called, the sample's process exists and its
+/// jitdump files have been checked, and the sample's stack has been unwound.
+pub struct ConvertCtx<'a> {
+    pub profile: &'a mut Profile,
+    pub timestamp_converter: &'a TimestampConverter,
+    pub unresolved_stacks: &'a mut UnresolvedStacks,
+    pub endian: Endianness,
+
+    /// The formats of the recorded tracepoint events, for decoding their
+    /// fields.
+    pub tracepoint_formats: &'a TracepointFormats,
+
+    /// The profile process for the sample's pid.
+    pub process: ProcessHandle,
+
+    /// The main thread of the sample's process.
+    pub main_thread: ThreadHandle,
+
+    /// The samples and markers of the sample's process. Any stacks in here
+    /// refer to `unresolved_stacks`.
+    pub unresolved_samples: &'a mut UnresolvedSamples,
+
+    /// The stack of the sample, ordered from callee-most to caller-most.
+    pub stack: &'a [StackFrame],
+
+    /// The name of the sample's event, e.g. "syscalls:sys_exit_openat".
+    pub attr_name: &'a str,
+
+    pub(super) threads: &'a mut ProcessThreads,
+
+    pub(super) process_exits: &'a mut ProcessExits,
+}
+
+impl<'a> ConvertCtx<'a> {
+    /// The profile thread for this tid in the sample's process.
+    pub fn thread_handle(&mut self, tid: i32) -> ThreadHandle {
+        self.threads
+            .get_thread_by_tid(tid, self.profile)
+            .profile_thread
+    }
+
+    /// The stack of the sample if it has one, otherwise the stack of the
+    /// thread's most recent sample, for markers.
+    pub fn stack_or_last_sample_stack(&mut self, thread: ThreadHandle) -> UnresolvedStackHandle {
+        if self.stack.is_empty() {
+            self.unresolved_samples
+                .last_sample_stack(thread)
+                .unwrap_or(UnresolvedStackHandle::EMPTY)
+        } else {
+            self.unresolved_stacks
+                .convert(self.stack.iter().rev().cloned())
+        }
+    }
+
+    /// Remember the stack at which the thread is about to go to sleep. It is
+    /// used for the off-CPU samples which are emitted when the thread runs
+    /// again.
+    pub fn set_off_cpu_stack(&mut self, tid: i32, stack: UnresolvedStackHandle) {
+        let thread = self.threads.get_thread_by_tid(tid, self.profile);
+        thread.off_cpu_stack = Some(stack);
+    }
+
+    /// Remember why the process is ending, for its "Process exit" marker.
+    pub fn set_exit_reason(&mut self, pid: i32, reason: ExitReason) {
+        self.process_exits.set_reason(pid, reason);
+    }
+}
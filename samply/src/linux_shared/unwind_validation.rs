@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+use tracing::{info, warn};
+
+use super::module_data_cache::ModuleData;
+use super::ConvertRegs;
+
+/// How strictly the return addresses which DWARF unwinding finds on the
+/// stack are checked. The first return address which fails the check ends
+/// the stack with a truncation marker, so that a stale CFI or a corrupted
+/// stack doesn't produce a long chain of made-up callers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnwindValidation {
+    /// Keep every frame the unwinder finds.
+    Off,
+    /// Each return address must be in an executable mapping of the process.
+    #[default]
+    Mappings,
+    /// Like `Mappings`, and the instruction before each return address must
+    /// be a call, if we have the code bytes of its mapping.
+    Calls,
+}
+
+/// Parses an `--unwind-validation` argument.
+pub fn parse_unwind_validation(s: &str) -> Result<UnwindValidation, String> {
+    match s {
+        "off" => Ok(UnwindValidation::Off),
+        "mappings" => Ok(UnwindValidation::Mappings),
+        "calls" => Ok(UnwindValidation::Calls),
+        _ => Err(format!("expected off, mappings or calls, got {s:?}")),
+    }
+}
+
+/// The executable mappings of a process, and the code bytes of the ones
+/// whose binary we have.
+#[derive(Debug, Default)]
+pub struct CodeRanges {
+    /// The end address of each mapping, by its start address.
+    mapping_ends: BTreeMap<u64, u64>,
+    /// The code bytes, by the address at which they start.
+    texts: BTreeMap<u64, ModuleData>,
+}
+
+impl CodeRanges {
+    /// Adds an executable mapping, which replaces the mappings that start
+    /// inside of it.
+    pub fn add_mapping(&mut self, range: Range<u64>) {
+        let replaced: Vec<u64> = self
+            .mapping_ends
+            .range(range.clone())
+            .map(|(&s, _)| s)
+            .collect();
+        for start in replaced {
+            self.mapping_ends.remove(&start);
+            self.texts.remove(&start);
+        }
+        self.mapping_ends.insert(range.start, range.end);
+    }
+
+    pub fn add_text(&mut self, start_address: u64, text: ModuleData) {
+        self.texts.insert(start_address, text);
+    }
+
+    /// Forgets all mappings, e.g. because the process has called exec.
+    pub fn clear(&mut self) {
+        self.mapping_ends.clear();
+        self.texts.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mapping_ends.is_empty()
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        match self.mapping_ends.range(..=address).next_back() {
+            Some((_, &end)) => address < end,
+            None => false,
+        }
+    }
+
+    /// Returns up to `len` code bytes which end right before `address`, or
+    /// None if we don't have the code at `address`.
+    pub fn bytes_before(&self, address: u64, len: usize) -> Option<&[u8]> {
+        let (&start, text) = self.texts.range(..address).next_back()?;
+        let end_offset = usize::try_from(address - start).ok()?;
+        if end_offset > text.len() {
+            return None;
+        }
+        Some(&text[end_offset.saturating_sub(len)..end_offset])
+    }
+}
+
+/// Why a sample with user registers and a user stack wasn't DWARF-unwound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkippedUnwindReason {
+    /// A register which the unwinder needs isn't among the sampled ones.
+    MissingRegister(&'static str),
+    /// The kernel didn't copy any stack bytes.
+    EmptyUserStack,
+}
+
+impl fmt::Display for SkippedUnwindReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingRegister(register) => write!(f, "the {register} register is missing"),
+            Self::EmptyUserStack => write!(f, "the user stack is empty"),
+        }
+    }
+}
+
+/// Checks the return addresses found by DWARF unwinding, and counts the
+/// ones it rejects, as well as the samples which couldn't be unwound.
+#[derive(Debug, Clone)]
+pub struct UnwindValidator {
+    validation: UnwindValidation,
+    rejected_frame_count: u64,
+    /// The number of samples which weren't unwound, by architecture and
+    /// reason.
+    pub(super) skipped_unwind_counts: BTreeMap<(&'static str, SkippedUnwindReason), u64>,
+}
+
+impl UnwindValidator {
+    pub fn new(validation: UnwindValidation) -> Self {
+        Self {
+            validation,
+            rejected_frame_count: 0,
+            skipped_unwind_counts: BTreeMap::new(),
+        }
+    }
+
+    /// Counts a sample whose stack only has its callchain, because it
+    /// couldn't be unwound.
+    pub fn count_skipped_unwind<C: ConvertRegs>(&mut self, reason: SkippedUnwindReason) {
+        *self
+            .skipped_unwind_counts
+            .entry((C::ARCH, reason))
+            .or_default() += 1;
+    }
+
+    /// Returns whether the return address is plausible. Processes for which
+    /// we don't know any mappings, e.g. because their mmap records are
+    /// missing, can't be checked, so all of their frames are accepted.
+    pub fn accepts<C: ConvertRegs>(&mut self, code_ranges: &CodeRanges, address: u64) -> bool {
+        if self.validation == UnwindValidation::Off || code_ranges.is_empty() {
+            return true;
+        }
+        let plausible = code_ranges.contains(address)
+            && match (
+                self.validation,
+                code_ranges.bytes_before(address, C::MAX_CALL_LEN),
+            ) {
+                (UnwindValidation::Calls, Some(code)) => C::follows_call(code),
+                _ => true,
+            };
+        if !plausible {
+            self.rejected_frame_count += 1;
+        }
+        plausible
+    }
+
+    pub fn report(&self) {
+        if self.rejected_frame_count != 0 {
+            warn!(
+                rejected_frame_count = self.rejected_frame_count,
+                "Stopped DWARF unwinding at {} implausible return addresses. Use \
+                 --unwind-validation off to keep these frames.",
+                self.rejected_frame_count
+            );
+        }
+        for (&(arch, reason), count) in &self.skipped_unwind_counts {
+            info!(
+                arch,
+                reason = %reason,
+                count,
+                "Skipped DWARF unwinding of {count} {arch} samples because {reason}; their \
+                 stacks only have the callchain."
+            );
+        }
+    }
+}
+
+/// Returns whether the x86_64 instruction right before the end of `code` is
+/// a call: either a direct call (E8 rel32) or an indirect one (FF /2 or
+/// FF /3, with two to seven bytes including the ModRM byte and operands).
+pub fn follows_call_x86_64(code: &[u8]) -> bool {
+    let len = code.len();
+    if len >= 5 && code[len - 5] == 0xe8 {
+        return true;
+    }
+    (2..=len.min(7)).any(|instruction_len| {
+        let opcode_index = len - instruction_len;
+        let reg = (code[opcode_index + 1] >> 3) & 0b111;
+        code[opcode_index] == 0xff && (reg == 2 || reg == 3)
+    })
+}
+
+/// Returns whether the aarch64 instruction right before the end of `code` is
+/// a call: BL, BLR, or one of the BLRA* variants of BLR with pointer
+/// authentication.
+pub fn follows_call_aarch64(code: &[u8]) -> bool {
+    let Some(bytes) = code.len().checked_sub(4).map(|start| &code[start..]) else {
+        return false;
+    };
+    let instruction = u32::from_le_bytes(bytes.try_into().unwrap());
+    instruction & 0xfc00_0000 == 0x9400_0000 // BL
+        || instruction & 0xffff_fc1f == 0xd63f_0000 // BLR
+        || instruction & 0xfeff_f800 == 0xd63f_0800 // BLRAA, BLRAAZ, BLRAB, BLRABZ
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::linux_shared::{ConvertRegsAarch64, ConvertRegsX86_64};
+
+    fn code_ranges() -> CodeRanges {
+        let mut ranges = CodeRanges::default();
+        ranges.add_mapping(0x1000..0x2000);
+        // call rel32; nop; mov eax, 0; call rax.
+        let text: Vec<u8> = [
+            &[0xe8, 0, 0, 0, 0, 0x90][..],
+            &[0xb8, 0, 0, 0, 0, 0xff, 0xd0],
+        ]
+        .concat();
+        ranges.add_text(0x1000, Arc::from(text));
+        ranges
+    }
+
+    #[test]
+    fn mappings_validation_rejects_addresses_outside_of_mappings() {
+        let ranges = code_ranges();
+        let mut validator = UnwindValidator::new(UnwindValidation::Mappings);
+        assert!(validator.accepts::<ConvertRegsX86_64>(&ranges, 0x1006));
+        assert!(validator.accepts::<ConvertRegsX86_64>(&ranges, 0x1fff));
+        assert!(!validator.accepts::<ConvertRegsX86_64>(&ranges, 0x2000));
+        assert!(!validator.accepts::<ConvertRegsX86_64>(&ranges, 0x10));
+        assert_eq!(validator.rejected_frame_count, 2);
+    }
+
+    #[test]
+    fn calls_validation_rejects_addresses_not_after_a_call() {
+        let ranges = code_ranges();
+        let mut validator = UnwindValidator::new(UnwindValidation::Calls);
+        // Right after the direct and the indirect call.
+        assert!(validator.accepts::<ConvertRegsX86_64>(&ranges, 0x1005));
+        assert!(validator.accepts::<ConvertRegsX86_64>(&ranges, 0x100d));
+        // After the nop and the mov.
+        assert!(!validator.accepts::<ConvertRegsX86_64>(&ranges, 0x1006));
+        assert!(!validator.accepts::<ConvertRegsX86_64>(&ranges, 0x100b));
+        // Beyond the text, where we don't know the code.
+        assert!(validator.accepts::<ConvertRegsX86_64>(&ranges, 0x1800));
+    }
+
+    #[test]
+    fn no_validation_without_known_mappings() {
+        let mut validator = UnwindValidator::new(UnwindValidation::Calls);
+        assert!(validator.accepts::<ConvertRegsX86_64>(&CodeRanges::default(), 0x10));
+    }
+
+    #[test]
+    fn later_mappings_replace_earlier_ones() {
+        let mut ranges = code_ranges();
+        ranges.add_mapping(0x1000..0x1800);
+        assert!(ranges.contains(0x17ff));
+        assert!(!ranges.contains(0x1800));
+        assert_eq!(ranges.bytes_before(0x1005, 7), None);
+    }
+
+    #[test]
+    fn aarch64_calls() {
+        let bl = 0x9400_0010u32.to_le_bytes();
+        let blr_x8 = 0xd63f_0100u32.to_le_bytes();
+        let ret = 0xd65f_03c0u32.to_le_bytes();
+        assert!(ConvertRegsAarch64::follows_call(&bl));
+        assert!(ConvertRegsAarch64::follows_call(&blr_x8));
+        assert!(!ConvertRegsAarch64::follows_call(&ret));
+        assert!(!ConvertRegsAarch64::follows_call(&bl[1..]));
+    }
+}
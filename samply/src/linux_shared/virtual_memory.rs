@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use byteorder::ByteOrder;
+use fxprof_processed_profile::{ProcessHandle, Profile};
+use linux_perf_data::linux_perf_event_reader::{RawData, SampleRecord};
+use linux_perf_data::Endianness;
+
+use super::tracepoint_handler::{ConvertCtx, TracepointHandler};
+use crate::shared::counter_buckets::{BucketedCounter, CounterBuckets, LARGE_MEMORY_DELTA_BYTES};
+use crate::shared::timestamp_converter::TimestampConverter;
+
+/// Successful mmaps of at least this many bytes get a marker with their stack.
+const LARGE_MMAP_MARKER_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The largest errno value; syscalls which return an address signal an error
+/// by returning a value in `-MAX_ERRNO..0`.
+const MAX_ERRNO: i64 = 4095;
+
+const SYS_ENTER_MMAP: &str = "syscalls:sys_enter_mmap";
+const SYS_EXIT_MMAP: &str = "syscalls:sys_exit_mmap";
+const SYS_ENTER_MUNMAP: &str = "syscalls:sys_enter_munmap";
+const SYS_EXIT_MUNMAP: &str = "syscalls:sys_exit_munmap";
+const SYS_EXIT_BRK: &str = "syscalls:sys_exit_brk";
+
+/// Handles the mmap, munmap and brk syscall tracepoints, and tracks the
+/// virtual address space which each process has mapped in a "Virtual memory"
+/// counter. Unlike the RSS counter from kmem:rss_stat, this also shows memory
+/// which has been reserved but never touched.
+///
+/// The mapping length is only known from the sys_enter_* tracepoint and the
+/// outcome only from the sys_exit_* tracepoint, so the two are paired up per
+/// thread. Calls which fail are not counted.
+///
+/// The counter is an upper bound: if munmap calls are missing from the
+/// recording, or memory is unmapped by other means (e.g. mremap, or a MAP_FIXED
+/// mmap over an existing mapping), the unmapped memory is still counted.
+#[derive(Debug)]
+pub struct VirtualMemoryHandler {
+    state_by_process: HashMap<ProcessHandle, ProcessVirtualMemoryState>,
+
+    /// The length argument of the mmap or munmap call which each thread is
+    /// currently in, by tid.
+    pending_length_by_tid: HashMap<i32, u64>,
+
+    /// The bucket size of the counter samples, see [`CounterBuckets`].
+    counter_bucket_duration_ns: u64,
+}
+
+impl VirtualMemoryHandler {
+    pub fn new(counter_bucket_duration_ns: u64) -> Self {
+        Self {
+            state_by_process: HashMap::new(),
+            pending_length_by_tid: HashMap::new(),
+            counter_bucket_duration_ns,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProcessVirtualMemoryState {
+    /// The current program break, once we've seen a brk call.
+    current_brk: Option<u64>,
+    counter: Option<BucketedCounter>,
+}
+
+impl TracepointHandler for VirtualMemoryHandler {
+    fn wants(&self, attr_name: &str) -> bool {
+        matches!(
+            attr_name,
+            SYS_ENTER_MMAP | SYS_EXIT_MMAP | SYS_ENTER_MUNMAP | SYS_EXIT_MUNMAP | SYS_EXIT_BRK
+        )
+    }
+
+    fn handle(&mut self, ctx: &mut ConvertCtx, e: &SampleRecord) {
+        let (Some(raw), Some(tid), Some(timestamp_mono)) = (e.raw, e.tid, e.timestamp) else {
+            return;
+        };
+        let Ok(args) = SyscallArgs::parse(raw, ctx.endian) else { return };
+
+        let delta = match ctx.attr_name {
+            SYS_ENTER_MMAP | SYS_ENTER_MUNMAP => {
+                // The second argument is the length for both.
+                self.pending_length_by_tid.insert(tid, args.args[1]);
+                return;
+            }
+            SYS_EXIT_MMAP => {
+                let Some(length) = self.pending_length_by_tid.remove(&tid) else { return };
+                let ret = args.args[0] as i64;
+                if (-MAX_ERRNO..0).contains(&ret) {
+                    return;
+                }
+                let length = round_up_to_page_size(length);
+                if length >= LARGE_MMAP_MARKER_THRESHOLD {
+                    let thread = ctx.thread_handle(tid);
+                    let timestamp = ctx.timestamp_converter.convert_time(timestamp_mono);
+                    let stack = ctx
+                        .unresolved_stacks
+                        .convert(ctx.stack.iter().rev().cloned());
+                    ctx.unresolved_samples.add_large_mmap_marker(
+                        thread,
+                        timestamp,
+                        timestamp_mono,
+                        stack,
+                        length,
+                    );
+                }
+                length as i64
+            }
+            SYS_EXIT_MUNMAP => {
+                let Some(length) = self.pending_length_by_tid.remove(&tid) else { return };
+                if args.args[0] as i64 != 0 {
+                    return;
+                }
+                -(round_up_to_page_size(length) as i64)
+            }
+            SYS_EXIT_BRK => {
+                // brk returns the new program break, or the old one if it
+                // failed. The first call, usually brk(0), gives us the initial
+                // break.
+                let new_brk = args.args[0];
+                let state = self.state_by_process.entry(ctx.process).or_default();
+                match state.current_brk.replace(new_brk) {
+                    Some(old_brk) => new_brk.wrapping_sub(old_brk) as i64,
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+
+        if delta == 0 {
+            return;
+        }
+
+        let counter_bucket_duration_ns = self.counter_bucket_duration_ns;
+        let state = self.state_by_process.entry(ctx.process).or_default();
+        let counter = state.counter.get_or_insert_with(|| {
+            let counter = ctx.profile.add_counter(
+                ctx.process,
+                "Virtual memory",
+                "Memory",
+                "Amount of mapped virtual memory (upper bound)",
+            );
+            let buckets = CounterBuckets::new(counter_bucket_duration_ns, LARGE_MEMORY_DELTA_BYTES);
+            BucketedCounter::new(counter, buckets)
+        });
+        counter.add_sample(
+            ctx.profile,
+            ctx.timestamp_converter,
+            timestamp_mono,
+            delta as f64,
+        );
+    }
+
+    fn flush(&mut self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        for state in self.state_by_process.values_mut() {
+            if let Some(counter) = &mut state.counter {
+                counter.flush(profile, timestamp_converter);
+            }
+        }
+    }
+}
+
+fn round_up_to_page_size(length: u64) -> u64 {
+    length.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// The first two 8-byte fields after the syscall number of a syscalls:sys_enter_*
+/// or syscalls:sys_exit_* tracepoint. For sys_exit, `args[0]` is the return value.
+///
+/// ```
+/// # cat /sys/kernel/debug/tracing/events/syscalls/sys_enter_munmap/format
+/// name: sys_enter_munmap
+/// ID: 707
+/// format:
+///         field:unsigned short common_type;       offset:0;       size:2; signed:0;
+///         field:unsigned char common_flags;       offset:2;       size:1; signed:0;
+///         field:unsigned char common_preempt_count;       offset:3;       size:1; signed:0;
+///         field:int common_pid;   offset:4;       size:4; signed:1;
+///
+///         field:int __syscall_nr; offset:8;       size:4; signed:1;
+///         field:unsigned long addr;       offset:16;      size:8; signed:0;
+///         field:size_t len;       offset:24;      size:8; signed:0;
+/// ```
+#[derive(Debug)]
+pub(super) struct SyscallArgs {
+    pub args: [u64; 2],
+}
+
+impl SyscallArgs {
+    pub fn parse(data: RawData, endian: Endianness) -> Result<Self, std::io::Error> {
+        match endian {
+            Endianness::LittleEndian => Self::parse_impl::<byteorder::LittleEndian>(data),
+            Endianness::BigEndian => Self::parse_impl::<byteorder::BigEndian>(data),
+        }
+    }
+
+    pub fn parse_impl<O: ByteOrder>(mut data: RawData) -> Result<Self, std::io::Error> {
+        let _common = data.read_u64::<O>()?;
+        let _syscall_nr = data.read_u64::<O>()?;
+        let arg0 = data.read_u64::<O>()?;
+        // sys_exit tracepoints only have one field.
+        let arg1 = data.read_u64::<O>().unwrap_or(0);
+        Ok(SyscallArgs { args: [arg0, arg1] })
+    }
+}
@@ -0,0 +1,196 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use linux_perf_data::linux_perf_event_reader::RecordType;
+
+use super::conversion_metrics::ConversionMetrics;
+use crate::cli_error::{CliError, ErrorKind};
+
+/// A new metrics snapshot is taken after every this many records.
+const RECORDS_PER_SNAPSHOT: u64 = 10_000;
+
+/// How often the watchdog thread checks for progress, at most.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Stands for "no record yet" in `Progress::last_record_type`.
+const NO_RECORD_TYPE: u32 = u32::MAX;
+
+/// See [`ConversionOptions::watchdog`](super::ConversionOptions::watchdog).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// How long the conversion may go without progress before the state is
+    /// dumped.
+    pub timeout: Duration,
+    /// Whether to exit with [`ErrorKind::Stalled`] after the dump.
+    pub abort: bool,
+}
+
+/// Watches the record loop of a conversion from a separate thread, and dumps
+/// the converter's state to stderr if no record has been processed for the
+/// configured time.
+///
+/// The converter can't be inspected from another thread, so the state comes
+/// from the most recent snapshot which the record loop published with
+/// [`Watchdog::publish_snapshot`]. The watchdog thread stops when the
+/// `Watchdog` is dropped.
+#[derive(Debug)]
+pub struct Watchdog {
+    progress: Arc<Progress>,
+}
+
+#[derive(Debug)]
+struct Progress {
+    records_processed: AtomicU64,
+    stage_changes: AtomicU64,
+    last_record_type: AtomicU32,
+    /// What the conversion is busy with, e.g. "reading records".
+    stage: Mutex<&'static str>,
+    /// The most recent metrics, with the number of records which had been
+    /// processed when they were taken.
+    snapshot: Mutex<Option<(u64, ConversionMetrics)>>,
+}
+
+impl Watchdog {
+    pub fn start(config: WatchdogConfig) -> Self {
+        let progress = Arc::new(Progress {
+            records_processed: AtomicU64::new(0),
+            stage_changes: AtomicU64::new(0),
+            last_record_type: AtomicU32::new(NO_RECORD_TYPE),
+            stage: Mutex::new("reading records"),
+            snapshot: Mutex::new(None),
+        });
+        let weak_progress = Arc::downgrade(&progress);
+        std::thread::Builder::new()
+            .name("samply watchdog".into())
+            .spawn(move || watch(weak_progress, config))
+            .expect("couldn't spawn the watchdog thread");
+        Self { progress }
+    }
+
+    /// Called by the record loop before a record is handled, so that a
+    /// stall is attributed to the record which caused it.
+    pub fn record_started(&self, record_type: RecordType) {
+        self.progress
+            .last_record_type
+            .store(record_type.0, Ordering::Relaxed);
+        self.progress
+            .records_processed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether the record loop should call [`Watchdog::publish_snapshot`]
+    /// after the current record.
+    pub fn wants_snapshot(&self) -> bool {
+        self.progress.records_processed.load(Ordering::Relaxed) % RECORDS_PER_SNAPSHOT == 1
+    }
+
+    pub fn publish_snapshot(&self, metrics: ConversionMetrics) {
+        let records_processed = self.progress.records_processed.load(Ordering::Relaxed);
+        *self.progress.snapshot.lock().unwrap() = Some((records_processed, metrics));
+    }
+
+    /// Entering a new stage counts as progress.
+    pub fn set_stage(&self, stage: &'static str) {
+        *self.progress.stage.lock().unwrap() = stage;
+        self.progress.stage_changes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn watch(progress: Weak<Progress>, config: WatchdogConfig) {
+    let poll_interval = config.timeout.min(MAX_POLL_INTERVAL);
+    let mut last_count = None;
+    let mut last_progress_time = Instant::now();
+    let mut dumped_this_stall = false;
+    loop {
+        std::thread::sleep(poll_interval);
+        let Some(progress) = progress.upgrade() else {
+            return;
+        };
+        let count = progress.progress_count();
+        if last_count != Some(count) {
+            last_count = Some(count);
+            last_progress_time = Instant::now();
+            dumped_this_stall = false;
+            continue;
+        }
+        let stalled_for = last_progress_time.elapsed();
+        if stalled_for < config.timeout || dumped_this_stall {
+            continue;
+        }
+        dumped_this_stall = true;
+        eprintln!("{}", progress.dump(stalled_for));
+        if config.abort {
+            CliError::new(
+                ErrorKind::Stalled,
+                "Aborting the conversion because of --watchdog-abort.",
+            )
+            .exit();
+        }
+    }
+}
+
+impl Progress {
+    fn progress_count(&self) -> u64 {
+        self.records_processed.load(Ordering::Relaxed) + self.stage_changes.load(Ordering::Relaxed)
+    }
+
+    fn dump(&self, stalled_for: Duration) -> String {
+        let records_processed = self.records_processed.load(Ordering::Relaxed);
+        let last_record_type = match self.last_record_type.load(Ordering::Relaxed) {
+            NO_RECORD_TYPE => "none".to_string(),
+            record_type => format!("{:?}", RecordType(record_type)),
+        };
+        let stage = *self.stage.lock().unwrap();
+        let mut dump = format!(
+            "Watchdog: the conversion has made no progress for {:.1}s while {stage}.\n\
+             Record number: {records_processed}, last record type: {last_record_type}\n",
+            stalled_for.as_secs_f64()
+        );
+        match &*self.snapshot.lock().unwrap() {
+            Some((snapshot_records, metrics)) => {
+                dump += &format!("State as of record {snapshot_records}:\n{metrics}");
+            }
+            None => dump += "No state snapshot has been taken yet.\n",
+        }
+        dump
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::linux_shared::ProcessMetrics;
+
+    #[test]
+    fn dump_includes_the_last_record_and_snapshot() {
+        let watchdog = Watchdog::start(WatchdogConfig {
+            timeout: Duration::from_secs(3600),
+            abort: false,
+        });
+        let dump = watchdog.progress.dump(Duration::from_secs(5));
+        assert!(dump.contains("Record number: 0, last record type: none"));
+        assert!(dump.contains("No state snapshot"));
+
+        watchdog.record_started(RecordType::SAMPLE);
+        assert!(watchdog.wants_snapshot());
+        watchdog.publish_snapshot(ConversionMetrics {
+            processes: vec![ProcessMetrics {
+                pid: 1234,
+                name: Some("node".into()),
+                thread_count: 1,
+                sample_count: 5,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        watchdog.record_started(RecordType::MMAP2);
+        assert!(!watchdog.wants_snapshot());
+
+        let dump = watchdog.progress.dump(Duration::from_secs(5));
+        assert!(dump.contains("no progress for 5.0s while reading records"));
+        assert!(dump.contains("Record number: 2"));
+        assert!(dump.contains("State as of record 1:"));
+        assert!(dump.contains("pid 1234 (node): 1 threads, 5 samples and markers"));
+    }
+}
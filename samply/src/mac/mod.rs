@@ -7,6 +7,7 @@ mod mach_ipc;
 mod proc_maps;
 mod process_launcher;
 pub mod profiler;
+mod rosetta;
 mod sampler;
 mod task_profiler;
 pub mod thread_act;
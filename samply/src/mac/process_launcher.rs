@@ -9,6 +9,7 @@ use std::time::Duration;
 
 pub use super::mach_ipc::{mach_port_t, MachError, OsIpcSender};
 use super::mach_ipc::{BlockingMode, OsIpcMultiShotServer, MACH_PORT_NULL};
+use crate::cli_error::CliError;
 use flate2::write::GzDecoder;
 use tempfile::tempdir;
 
@@ -21,15 +22,17 @@ static PRELOAD_LIB_CONTENTS: &[u8] =
     include_bytes!("../../resources/libsamply_mac_preload.dylib.gz");
 
 impl TaskAccepter {
-    pub fn create_and_launch_root_task<I, S>(
-        program: S,
-        args: I,
-    ) -> Result<(Self, Child), MachError>
+    /// Launches the root process of the recording. Fails with a user input
+    /// error if there's no executable with this name, and with an environment
+    /// error if the process couldn't be launched for another reason.
+    pub fn create_and_launch_root_task<I, S>(program: S, args: I) -> Result<(Self, Child), CliError>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
-        let (server, server_name) = OsIpcMultiShotServer::new()?;
+        let (server, server_name) = OsIpcMultiShotServer::new().map_err(|err| {
+            CliError::environment(format!("Could not create the task server: {err:?}"))
+        })?;
 
         // Launch the child with DYLD_INSERT_LIBRARIES set to libsamply_mac_preload.dylib.
 
@@ -67,15 +70,15 @@ impl TaskAccepter {
         {
             Ok(child) => child,
             Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
-                eprintln!(
-                    "Error: Could not find an executable with the name {}.",
+                return Err(CliError::user_input(format!(
+                    "Could not find an executable with the name {}.",
                     program.as_ref().to_string_lossy()
-                );
-                std::process::exit(1)
+                )));
             }
             Err(err) => {
-                eprintln!("Error: Could not launch child process: {err}");
-                std::process::exit(1)
+                return Err(CliError::environment(format!(
+                    "Could not launch child process: {err}"
+                )));
             }
         };
 
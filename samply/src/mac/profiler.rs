@@ -1,4 +1,7 @@
 use crossbeam_channel::unbounded;
+use fxprof_processed_profile::Profile;
+use mach::mach_init::mach_thread_self;
+use mach::traps::mach_task_self;
 use serde_json::to_writer;
 
 use std::collections::hash_map::Entry;
@@ -8,7 +11,7 @@ use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
 use std::process::ExitStatus;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -16,19 +19,29 @@ use std::time::Duration;
 use super::error::SamplingError;
 use super::process_launcher::{MachError, ReceivedStuff, TaskAccepter};
 use super::sampler::{Sampler, TaskInit};
+use super::thread_profiler::get_thread_id;
 use super::time::get_monotonic_timestamp;
+use crate::cli_error::CliError;
 use crate::server::{start_server_main, ServerProps};
+use crate::shared::rotation::RotationOptions;
+use crate::shared::self_profile::{PhaseRecorder, SelfProfiler};
 
 pub fn start_profiling_pid(
     _output_file: &Path,
     _pid: u32,
     _time_limit: Option<Duration>,
     _interval: Duration,
+    _idle_thread_rate_divisor: Option<u32>,
+    _leaf_only: bool,
+    _auto_tune: bool,
+    _rotation: Option<RotationOptions>,
     _server_props: Option<ServerProps>,
 ) {
-    eprintln!("Profiling existing processes is currently not supported on macOS.");
-    eprintln!("You can only profile processes which you launch via samply.");
-    std::process::exit(1)
+    CliError::user_input(
+        "Profiling existing processes is currently not supported on macOS.\n\
+         You can only profile processes which you launch via samply.",
+    )
+    .exit()
 }
 
 pub fn start_recording(
@@ -37,12 +50,37 @@ pub fn start_recording(
     command_args: &[OsString],
     time_limit: Option<Duration>,
     interval: Duration,
+    idle_thread_rate_divisor: Option<u32>,
+    leaf_only: bool,
+    auto_tune: bool,
+    rotation: Option<RotationOptions>,
     server_props: Option<ServerProps>,
-) -> Result<ExitStatus, MachError> {
+) -> Result<ExitStatus, CliError> {
+    if leaf_only {
+        CliError::user_input("--leaf-only is currently not supported on macOS.").exit()
+    }
+    if auto_tune {
+        CliError::user_input("--auto-tune is only supported on Linux.").exit()
+    }
+    if rotation.is_some() {
+        CliError::user_input("--rotate is only supported on Linux.").exit()
+    }
+
     let (task_sender, task_receiver) = unbounded();
     let command_name_copy = command_name.to_string_lossy().to_string();
     let sampler_thread = thread::spawn(move || {
-        let sampler = Sampler::new(command_name_copy, task_receiver, interval, time_limit);
+        // Create a stop flag which always stays false. The sampler runs until all
+        // tasks have terminated or until the time limit has elapsed.
+        let stop = Arc::new(AtomicBool::new(false));
+        let sampler = Sampler::new(
+            command_name_copy,
+            task_receiver,
+            interval,
+            idle_thread_rate_divisor.unwrap_or(1),
+            time_limit,
+            stop,
+            None,
+        );
         sampler.run()
     });
 
@@ -119,7 +157,7 @@ pub fn start_recording(
     let exit_status = root_child.wait().expect("couldn't wait for child");
 
     // The subprocess is done. From now on, we want to terminate if the user presses Ctrl+C.
-    should_terminate_on_ctrl_c.store(true, std::sync::atomic::Ordering::SeqCst);
+    should_terminate_on_ctrl_c.store(true, Ordering::SeqCst);
 
     accepter_sender
         .send(())
@@ -140,17 +178,12 @@ pub fn start_recording(
             eprintln!("On macOS, samply cannot profile system commands, such as the sleep command or system python. This is because system executables are signed in such a way that they block the DYLD_INSERT_LIBRARIES environment variable, which subverts samply's attempt to siphon out the mach task port of the process.");
             eprintln!();
             eprintln!("Suggested remedy: You can profile any binaries that you've compiled yourself, or which are unsigned or locally-signed, such as anything installed by cargo install or by Homebrew.");
-            std::process::exit(1)
-        }
-        Err(e) => {
-            eprintln!("An error occurred during profiling: {e}");
-            std::process::exit(1)
+            CliError::user_input("The launched command cannot be profiled").exit()
         }
+        Err(e) => CliError::environment(format!("An error occurred during profiling: {e}")).exit(),
     };
 
-    let file = File::create(output_file).unwrap();
-    let writer = BufWriter::new(file);
-    to_writer(writer, &profile).expect("Couldn't write JSON");
+    save_profile_to_file(&profile, output_file);
 
     if let Some(server_props) = server_props {
         start_server_main(output_file, server_props);
@@ -158,3 +191,60 @@ pub fn start_recording(
 
     Ok(exit_status)
 }
+
+/// Starts sampling samply's own task for `--self-profile`. The profile is
+/// saved to `output_file` when [`SelfProfiler::finish`] is called.
+pub fn start_self_profiling(output_file: &Path) -> SelfProfiler {
+    // The phases are recorded by the calling thread.
+    let (tid, _is_libdispatch_thread) = get_thread_id(unsafe { mach_thread_self() })
+        .unwrap_or_else(|err| {
+            CliError::environment(format!("Could not get the current thread id: {err:?}")).exit()
+        });
+    let phases = PhaseRecorder::new(tid, get_monotonic_timestamp);
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Nothing sends jitdump paths for our own task.
+    let (_jitdump_path_sender, jitdump_path_receiver) = unbounded();
+    let (task_sender, task_receiver) = unbounded();
+    task_sender
+        .send(TaskInit {
+            start_time: get_monotonic_timestamp(),
+            task: unsafe { mach_task_self() },
+            pid: std::process::id(),
+            jitdump_path_receiver,
+        })
+        .unwrap();
+
+    let output_file_copy = output_file.to_owned();
+    let sampler_thread = thread::spawn({
+        let stop = stop.clone();
+        let phases = phases.clone();
+        move || {
+            let sampler = Sampler::new(
+                "samply".to_string(),
+                task_receiver,
+                Duration::from_millis(1),
+                1,
+                None,
+                stop,
+                Some(phases),
+            );
+            match sampler.run() {
+                Ok(profile) => save_profile_to_file(&profile, &output_file_copy),
+                Err(err) => eprintln!("Self-profiling failed: {err}"),
+            }
+        }
+    });
+
+    SelfProfiler::new(output_file.to_owned(), phases, stop, sampler_thread)
+}
+
+fn save_profile_to_file(profile: &Profile, output_file: &Path) {
+    let file = File::create(output_file).unwrap_or_else(|err| {
+        CliError::io(format!("Could not create {output_file:?}"), &err).exit()
+    });
+    let writer = BufWriter::new(file);
+    if let Err(err) = to_writer(writer, profile) {
+        CliError::environment(format!("Could not write {output_file:?}: {err}")).exit()
+    }
+}
@@ -0,0 +1,190 @@
+//! Support for x86_64 processes which run under Rosetta 2 on arm64 machines.
+//!
+//! In a translated process, dyld reports the original x86_64 images, but the
+//! code which actually executes lives in "ahead-of-time" translated images,
+//! which Rosetta keeps in /var/db/oah and maps into the process next to the
+//! original images. The sampled (arm64) instruction addresses fall into those
+//! AOT images, so without knowing about them we'd get unsymbolicated frames
+//! which aren't attributed to any library.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use mach::kern_return::KERN_SUCCESS;
+use mach::message::mach_msg_type_number_t;
+use mach::port::mach_port_t;
+use mach::vm::mach_vm_region;
+use mach::vm_prot::VM_PROT_EXECUTE;
+use mach::vm_region::{vm_region_basic_info_data_64_t, vm_region_info_t, VM_REGION_BASIC_INFO_64};
+use mach::vm_types::{mach_vm_address_t, mach_vm_size_t};
+
+/// The directory in which Rosetta stores its AOT translations.
+const AOT_DIRECTORY: &str = "/var/db/oah/";
+
+/// `P_TRANSLATED` from sys/proc.h.
+const P_TRANSLATED: i32 = 0x0002_0000;
+
+/// The offset of `kp_proc.p_flag` in `struct kinfo_proc` on 64 bit:
+/// `p_un` (two pointers), `p_vmspace` and `p_sigacts` come before it.
+const KINFO_PROC_P_FLAG_OFFSET: usize = 32;
+
+/// Returns whether the process with the given pid is an x86_64 process
+/// which is being translated by Rosetta.
+pub fn is_translated_process(pid: u32) -> bool {
+    let mut mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_PID,
+        pid as libc::c_int,
+    ];
+    // struct kinfo_proc is 648 bytes; the kernel fails with ENOMEM if the
+    // buffer is smaller than that.
+    let mut buffer = [0u8; 1024];
+    let mut size = buffer.len();
+    let ret = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as libc::c_uint,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 || size < KINFO_PROC_P_FLAG_OFFSET + 4 {
+        return false;
+    }
+    let p_flag_bytes = &buffer[KINFO_PROC_P_FLAG_OFFSET..][..4];
+    let p_flag = i32::from_ne_bytes(p_flag_bytes.try_into().unwrap());
+    p_flag & P_TRANSLATED != 0
+}
+
+/// An executable mapping of a Rosetta AOT image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AotImage {
+    pub start_avma: u64,
+    pub end_avma: u64,
+    /// The path of the .aot file, e.g.
+    /// /var/db/oah/<hash>/<hash>/libfoo.dylib.aot
+    pub aot_path: String,
+}
+
+impl AotImage {
+    /// The file name of the original x86_64 binary which this image is the
+    /// translation of, e.g. "libfoo.dylib".
+    pub fn original_file_name(&self) -> Option<&str> {
+        original_file_name_for_aot_path(&self.aot_path)
+    }
+}
+
+fn original_file_name_for_aot_path(aot_path: &str) -> Option<&str> {
+    if !aot_path.starts_with(AOT_DIRECTORY) {
+        return None;
+    }
+    Path::new(aot_path)
+        .file_name()?
+        .to_str()?
+        .strip_suffix(".aot")
+}
+
+/// Keeps track of the AOT images in a translated process, and of the original
+/// binaries they were translated from.
+#[derive(Debug, Default)]
+pub struct RosettaImages {
+    /// The path of each original x86_64 binary, keyed by file name.
+    original_paths_by_file_name: HashMap<String, String>,
+    /// The AOT images which have been reported so far, keyed by start address.
+    known_aot_images: HashMap<u64, AotImage>,
+}
+
+impl RosettaImages {
+    /// Called for every image that dyld reports in the translated process.
+    pub fn add_original_binary(&mut self, path: &str) {
+        if let Some(file_name) = Path::new(path).file_name().and_then(|f| f.to_str()) {
+            self.original_paths_by_file_name
+                .insert(file_name.to_owned(), path.to_owned());
+        }
+    }
+
+    /// Returns the path of the original x86_64 binary for an AOT image.
+    pub fn original_path(&self, aot_image: &AotImage) -> Option<&str> {
+        let file_name = aot_image.original_file_name()?;
+        self.original_paths_by_file_name
+            .get(file_name)
+            .map(String::as_str)
+    }
+
+    /// Enumerates the AOT images which are currently mapped into the task,
+    /// and returns the ones which haven't been seen before.
+    pub fn check_for_new_aot_images(&mut self, task: mach_port_t, pid: u32) -> Vec<AotImage> {
+        let mut new_images = Vec::new();
+        for image in enumerate_aot_images(task, pid) {
+            if self.known_aot_images.contains_key(&image.start_avma) {
+                continue;
+            }
+            self.known_aot_images
+                .insert(image.start_avma, image.clone());
+            new_images.push(image);
+        }
+        new_images
+    }
+}
+
+/// Walks the task's address space and returns the executable regions which
+/// are mapped from files in the AOT directory.
+fn enumerate_aot_images(task: mach_port_t, pid: u32) -> Vec<AotImage> {
+    let mut images = Vec::new();
+    let mut address: mach_vm_address_t = 0;
+    loop {
+        let mut size: mach_vm_size_t = 0;
+        let mut info: vm_region_basic_info_data_64_t = Default::default();
+        let mut count = vm_region_basic_info_data_64_t::count() as mach_msg_type_number_t;
+        let mut object_name: mach_port_t = 0;
+        let kr = unsafe {
+            mach_vm_region(
+                task,
+                &mut address,
+                &mut size,
+                VM_REGION_BASIC_INFO_64,
+                &mut info as *mut _ as vm_region_info_t,
+                &mut count,
+                &mut object_name,
+            )
+        };
+        if kr != KERN_SUCCESS {
+            // KERN_INVALID_ADDRESS: we've reached the end of the address space.
+            break;
+        }
+        if info.protection & VM_PROT_EXECUTE != 0 {
+            if let Some(path) = region_file_name(pid, address) {
+                if original_file_name_for_aot_path(&path).is_some() {
+                    images.push(AotImage {
+                        start_avma: address,
+                        end_avma: address + size,
+                        aot_path: path,
+                    });
+                }
+            }
+        }
+        address += size;
+    }
+    images
+}
+
+fn region_file_name(pid: u32, address: u64) -> Option<String> {
+    let mut buffer = [0u8; libc::PATH_MAX as usize];
+    let len = unsafe {
+        libc::proc_regionfilename(
+            pid as libc::c_int,
+            address,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len() as u32,
+        )
+    };
+    if len <= 0 {
+        return None;
+    }
+    std::str::from_utf8(&buffer[..len as usize])
+        .ok()
+        .map(ToOwned::to_owned)
+}
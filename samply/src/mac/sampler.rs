@@ -4,15 +4,20 @@ use mach::port::mach_port_t;
 
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use std::time::SystemTime;
 
+use crate::shared::marker_addresses::MarkerAddresses;
+use crate::shared::self_profile::{add_phase_markers, PhaseRecorder};
 use crate::shared::timestamp_converter::TimestampConverter;
 use crate::shared::unresolved_samples::UnresolvedStacks;
 
 use super::error::SamplingError;
 use super::task_profiler::TaskProfiler;
+use super::thread_profiler::SamplingTiers;
 use super::time::get_monotonic_timestamp;
 
 #[derive(Debug, Clone)]
@@ -27,7 +32,15 @@ pub struct Sampler {
     command_name: String,
     task_receiver: Receiver<TaskInit>,
     interval: Duration,
+    /// Threads which have been idle for a while are only sampled every this
+    /// many intervals, see [`SamplingTiers`].
+    idle_thread_rate_divisor: u32,
     time_limit: Option<Duration>,
+    /// Sampling stops once this is set, even if tasks are still alive.
+    stop: Arc<AtomicBool>,
+    /// The phases which are added as markers at the end, when samply
+    /// profiles itself.
+    phases: Option<PhaseRecorder>,
 }
 
 impl Sampler {
@@ -35,7 +48,10 @@ impl Sampler {
         command: String,
         task_receiver: Receiver<TaskInit>,
         interval: Duration,
+        idle_thread_rate_divisor: u32,
         time_limit: Option<Duration>,
+        stop: Arc<AtomicBool>,
+        phases: Option<PhaseRecorder>,
     ) -> Self {
         let command_name = Path::new(&command)
             .components()
@@ -49,7 +65,10 @@ impl Sampler {
             command_name,
             task_receiver,
             interval,
+            idle_thread_rate_divisor: idle_thread_rate_divisor.max(1),
             time_limit,
+            stop,
+            phases,
         }
     }
 
@@ -95,6 +114,10 @@ impl Sampler {
         let mut unwinder_cache = Default::default();
         let mut unresolved_stacks = UnresolvedStacks::default();
         let mut last_sleep_overshoot = 0;
+        let tiers = SamplingTiers {
+            interval_ns: self.interval.as_nanos() as u64,
+            idle_divisor: self.idle_thread_rate_divisor,
+        };
 
         loop {
             // Poll to see if there are any new tasks we should add. If no new tasks are available,
@@ -125,6 +148,9 @@ impl Sampler {
                     break;
                 }
             }
+            if self.stop.load(Ordering::SeqCst) {
+                break;
+            }
 
             let sample_timestamp = timestamp_converter.convert_time(sample_mono);
 
@@ -139,6 +165,7 @@ impl Sampler {
                 let still_alive = task.sample(
                     sample_timestamp,
                     sample_mono,
+                    tiers,
                     &mut unwinder_cache,
                     &mut profile,
                     &mut stack_scratch_buffer,
@@ -180,7 +207,15 @@ impl Sampler {
                 }
             }
 
-            let intended_wakeup_time = sample_mono + self.interval.as_nanos() as u64;
+            let mut intended_wakeup_time = sample_mono + tiers.interval_ns;
+            if tiers.is_tiered() {
+                // Each thread has its own due time, so sleep until the first
+                // one is due. This skips the ticks in which only demoted
+                // threads would have been looked at.
+                if let Some(next_due) = live_tasks.iter().filter_map(|t| t.next_due_mono()).min() {
+                    intended_wakeup_time = intended_wakeup_time.max(next_due);
+                }
+            }
             let before_sleep = get_monotonic_timestamp();
             let indended_wait_time = intended_wakeup_time.saturating_sub(before_sleep);
             let sleep_time = indended_wait_time.saturating_sub(last_sleep_overshoot);
@@ -192,7 +227,11 @@ impl Sampler {
         // Gather the sample data from the remaining live tasks.
         // `live_tasks` can be non-empty if we stopped profiling before all tasks ended,
         // for example because the time limit was reached,
+        let mut phase_thread = None;
         for task in live_tasks.into_iter() {
+            if let Some(phases) = &self.phases {
+                phase_thread = phase_thread.or(task.thread_handle_for_tid(phases.tid()));
+            }
             process_sample_datas.push(task.finish(
                 &mut jit_category_manager,
                 &mut profile,
@@ -206,12 +245,35 @@ impl Sampler {
                 &mut profile,
                 default_category,
                 default_category,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                &mut MarkerAddresses::default(),
                 &mut stack_frame_scratch_buf,
                 &unresolved_stacks,
                 &[],
             );
         }
 
+        if let (Some(phases), Some(thread)) = (&self.phases, phase_thread) {
+            add_phase_markers(
+                &mut profile,
+                thread,
+                &phases.take_phases(),
+                &timestamp_converter,
+            );
+        }
+
         Ok(profile)
     }
 }
@@ -1,10 +1,11 @@
 use crossbeam_channel::Receiver;
 use framehop::{
-    CacheNative, FrameAddress, MayAllocateDuringUnwind, Module, ModuleUnwindData, TextByteData,
-    Unwinder, UnwinderNative,
+    CacheNative, FrameAddress, MayAllocateDuringUnwind, Module, ModuleSvmaInfo, ModuleUnwindData,
+    TextByteData, Unwinder, UnwinderNative,
 };
 use fxprof_processed_profile::debugid::DebugId;
-use fxprof_processed_profile::{LibraryInfo, ProcessHandle, Profile, Timestamp};
+use fxprof_processed_profile::{LibraryInfo, ProcessHandle, Profile, ThreadHandle, Timestamp};
+use mach::mach_init::mach_thread_self;
 use mach::mach_types::thread_act_port_array_t;
 use mach::mach_types::thread_act_t;
 use mach::message::mach_msg_type_number_t;
@@ -14,7 +15,7 @@ use mach::traps::mach_task_self;
 use mach::vm::mach_vm_deallocate;
 use mach::vm_types::{mach_vm_address_t, mach_vm_size_t};
 use object::{CompressedFileRange, CompressionFormat, Object, ObjectSection};
-use samply_symbols::{object, DebugIdExt};
+use samply_symbols::{debug_id_for_object, object, DebugIdExt};
 use wholesym::samply_symbols;
 
 use std::collections::hash_map::Entry;
@@ -24,11 +25,11 @@ use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
 use crate::shared::jit_category_manager::JitCategoryManager;
-use crate::shared::jitdump_manager::JitDumpManager;
+use crate::shared::jitdump_manager::{JitDumpManager, TimestampClock};
 use crate::shared::lib_mappings::{
     LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue, LibMappingRemove,
 };
-use crate::shared::perf_map::try_load_perf_map;
+use crate::shared::perf_map::{try_load_perf_map, PerfMapLimits};
 use crate::shared::process_sample_data::ProcessSampleData;
 use crate::shared::timestamp_converter::TimestampConverter;
 use crate::shared::unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
@@ -36,7 +37,8 @@ use crate::shared::unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
 use super::error::SamplingError;
 use super::kernel_error::{IntoResult, KernelError};
 use super::proc_maps::{DyldInfo, DyldInfoManager, Modification, StackwalkerRef, VmSubData};
-use super::thread_profiler::{get_thread_id, ThreadProfiler};
+use super::rosetta::{is_translated_process, AotImage, RosettaImages};
+use super::thread_profiler::{get_thread_id, SamplingTiers, ThreadProfiler};
 
 pub enum UnwindSectionBytes {
     Remapped(VmSubData),
@@ -98,6 +100,11 @@ pub struct TaskProfiler {
     jitdump_manager: JitDumpManager,
     unresolved_samples: UnresolvedSamples,
     lib_mapping_ops: LibMappingOpQueue,
+    /// Only present if this is an x86_64 process running under Rosetta.
+    rosetta_images: Option<RosettaImages>,
+    /// The sampler's own thread, if the task is samply's own task. A thread
+    /// can't suspend itself to walk its stack, so this thread isn't sampled.
+    sampler_thread: Option<thread_act_t>,
 }
 
 impl TaskProfiler {
@@ -117,12 +124,17 @@ impl TaskProfiler {
             ));
         }
 
+        let sampler_thread =
+            (task == unsafe { mach_task_self() }).then(|| unsafe { mach_thread_self() });
         let profile_process = profile.add_process(command_name, pid, start_time);
         let mut live_threads = HashMap::new();
         let mut main_thread_handle = None;
         for (i, thread_act) in thread_acts.into_iter().enumerate() {
             // Assume that the first thread is the main thread. This seems to hold true in practice.
             let is_main = i == 0;
+            if Some(thread_act) == sampler_thread {
+                continue;
+            }
             if let Ok((tid, _is_libdispatch_thread)) = get_thread_id(thread_act) {
                 let profile_thread = profile.add_thread(profile_process, tid, start_time, is_main);
                 if is_main {
@@ -144,16 +156,42 @@ impl TaskProfiler {
             ignored_errors: Vec::new(),
             unwinder: UnwinderNative::new(),
             jitdump_path_receiver,
-            jitdump_manager: JitDumpManager::new_for_process(main_thread_handle.unwrap()),
+            // We assume that jitdump files on macOS use the same clock as our samples.
+            jitdump_manager: JitDumpManager::new_for_process(
+                main_thread_handle.unwrap(),
+                TimestampClock::Monotonic,
+            ),
             lib_mapping_ops: Default::default(),
             unresolved_samples: Default::default(),
+            rosetta_images: is_translated_process(pid).then(RosettaImages::default),
+            sampler_thread,
         })
     }
 
+    /// The earliest time at which one of the live threads is due for its
+    /// next sample.
+    pub fn next_due_mono(&self) -> Option<u64> {
+        self.live_threads
+            .values()
+            .map(ThreadProfiler::next_due_mono)
+            .min()
+    }
+
+    /// Returns the profile thread of the thread with the given tid, if that
+    /// thread has been seen.
+    pub fn thread_handle_for_tid(&self, tid: u32) -> Option<ThreadHandle> {
+        self.live_threads
+            .values()
+            .chain(self.dead_threads.iter())
+            .find(|thread| thread.tid() == tid)
+            .map(ThreadProfiler::profile_thread)
+    }
+
     pub fn sample(
         &mut self,
         now: Timestamp,
         now_mono: u64,
+        tiers: SamplingTiers,
         unwinder_cache: &mut UnwinderCache,
         profile: &mut Profile,
         stack_scratch_buffer: &mut Vec<FrameAddress>,
@@ -162,6 +200,7 @@ impl TaskProfiler {
         let result = self.sample_impl(
             now,
             now_mono,
+            tiers,
             unwinder_cache,
             profile,
             stack_scratch_buffer,
@@ -192,6 +231,7 @@ impl TaskProfiler {
         &mut self,
         now: Timestamp,
         now_mono: u64,
+        tiers: SamplingTiers,
         unwinder_cache: &mut UnwinderCache,
         profile: &mut Profile,
         stack_scratch_buffer: &mut Vec<FrameAddress>,
@@ -202,10 +242,14 @@ impl TaskProfiler {
             .lib_info_manager
             .check_for_changes()
             .unwrap_or_else(|_| Vec::new());
+        let had_lib_changes = !changes.is_empty();
         for change in changes {
             match change {
                 Modification::Added(mut lib) => {
                     self.add_lib_to_unwinder_and_ensure_debug_id(&mut lib);
+                    if let Some(rosetta_images) = &mut self.rosetta_images {
+                        rosetta_images.add_original_binary(&lib.file);
+                    }
                     let path = Path::new(&lib.file);
                     if self.executable_lib.is_none() && lib.is_executable {
                         self.executable_lib = Some(lib.clone());
@@ -255,11 +299,20 @@ impl TaskProfiler {
             }
         }
 
+        // Rosetta maps the AOT translations of the images it runs, so look
+        // for new ones whenever the set of images changes.
+        if had_lib_changes {
+            self.check_for_new_aot_images(now_mono, profile);
+        }
+
         // Enumerate threads.
         let thread_acts = get_thread_list(self.task)?;
         let previously_live_threads: HashSet<_> = self.live_threads.keys().cloned().collect();
         let mut now_live_threads = HashSet::new();
         for thread_act in thread_acts {
+            if Some(thread_act) == self.sampler_thread {
+                continue;
+            }
             let mut entry = self.live_threads.entry(thread_act);
             let thread = match entry {
                 Entry::Occupied(ref mut entry) => entry.get_mut(),
@@ -275,12 +328,17 @@ impl TaskProfiler {
                     }
                 }
             };
+            if !thread.is_due(now_mono, tiers) {
+                now_live_threads.insert(thread_act);
+                continue;
+            }
             // Grab a sample from the thread.
             let stackwalker = StackwalkerRef::new(&self.unwinder, unwinder_cache);
             let still_alive = thread.sample(
                 stackwalker,
                 now,
                 now_mono,
+                tiers,
                 profile,
                 stack_scratch_buffer,
                 unresolved_stacks,
@@ -371,6 +429,79 @@ impl TaskProfiler {
         self.unwinder.add_module(module);
     }
 
+    fn check_for_new_aot_images(&mut self, now_mono: u64, profile: &mut Profile) {
+        let Some(rosetta_images) = &mut self.rosetta_images else { return };
+        let new_aot_images = rosetta_images.check_for_new_aot_images(self.task, self.pid);
+        let new_aot_images: Vec<(AotImage, String)> = new_aot_images
+            .into_iter()
+            .filter_map(|image| {
+                let original_path = rosetta_images.original_path(&image)?.to_owned();
+                Some((image, original_path))
+            })
+            .collect();
+        for (image, original_path) in new_aot_images {
+            self.add_aot_image(image, &original_path, now_mono, profile);
+        }
+    }
+
+    /// Adds an AOT image to the unwinder and to the profile. The library is
+    /// presented under the name and path of the original x86_64 binary, so
+    /// that time spent in translated code is attributed to that binary.
+    /// The sampled addresses are addresses in the AOT image though, so the
+    /// AOT file is used for symbolication. This gives function names if the
+    /// translation kept the symbols of the original binary.
+    fn add_aot_image(
+        &mut self,
+        image: AotImage,
+        original_path: &str,
+        now_mono: u64,
+        profile: &mut Profile,
+    ) {
+        // The translated code is arm64 code with frame pointers, and there is
+        // no unwind info for it.
+        let module = Module::new(
+            image.aot_path.clone(),
+            image.start_avma..image.end_avma,
+            image.start_avma,
+            ModuleSvmaInfo {
+                base_svma: 0,
+                text: None,
+                text_env: None,
+                stubs: None,
+                stub_helper: None,
+                eh_frame: None,
+                eh_frame_hdr: None,
+                got: None,
+            },
+            ModuleUnwindData::None,
+            None,
+        );
+        self.unwinder.add_module(module);
+
+        let name = Path::new(original_path)
+            .file_name()
+            .map_or_else(|| original_path.into(), |name| name.to_string_lossy());
+        let lib_handle = profile.add_lib(LibraryInfo {
+            name: name.to_string(),
+            debug_name: name.to_string(),
+            path: original_path.to_string(),
+            debug_path: image.aot_path.clone(),
+            debug_id: debug_id_for_file(&image.aot_path).unwrap_or_else(DebugId::nil),
+            code_id: None,
+            arch: Some("arm64".to_string()),
+            symbol_table: None,
+        });
+        self.lib_mapping_ops.push(
+            now_mono,
+            LibMappingOp::Add(LibMappingAdd {
+                start_avma: image.start_avma,
+                end_avma: image.end_avma,
+                relative_address_at_start: 0,
+                info: LibMappingInfo::new_lib(lib_handle),
+            }),
+        );
+    }
+
     pub fn check_jitdump(
         &mut self,
         profile: &mut Profile,
@@ -378,7 +509,8 @@ impl TaskProfiler {
         timestamp_converter: &TimestampConverter,
     ) {
         while let Ok(jitdump_path) = self.jitdump_path_receiver.try_recv() {
-            self.jitdump_manager.add_jitdump_path(jitdump_path, None);
+            self.jitdump_manager
+                .add_jitdump_path(jitdump_path, None, None, None);
         }
 
         self.jitdump_manager.process_pending_records(
@@ -405,7 +537,16 @@ impl TaskProfiler {
         timestamp_converter: &TimestampConverter,
     ) -> ProcessSampleData {
         let perf_map_mappings = if !self.unresolved_samples.is_empty() {
-            try_load_perf_map(self.pid, profile, jit_category_manager, None)
+            try_load_perf_map(
+                self.pid,
+                None,
+                None,
+                &PerfMapLimits::default(),
+                &[],
+                profile,
+                jit_category_manager,
+                None,
+            )
         } else {
             None
         };
@@ -419,6 +560,15 @@ impl TaskProfiler {
     }
 }
 
+/// The AOT files in /var/db/oah are only readable by root, so this often
+/// returns None.
+fn debug_id_for_file(file_path: &str) -> Option<DebugId> {
+    let file = std::fs::File::open(file_path).ok()?;
+    let mmap = unsafe { memmap2::MmapOptions::new().map(&file).ok()? };
+    let obj = object::read::File::parse(&mmap[..]).ok()?;
+    debug_id_for_object(&obj)
+}
+
 fn get_debug_frame(file_path: &str) -> Option<UnwindSectionBytes> {
     let file = std::fs::File::open(file_path).ok()?;
     let mmap = unsafe { memmap2::MmapOptions::new().map(&file).ok()? };
@@ -6,7 +6,7 @@ use mach::port::mach_port_t;
 use std::mem;
 
 use crate::shared::types::{StackFrame, StackMode};
-use crate::shared::unresolved_samples::{UnresolvedSamples, UnresolvedStacks};
+use crate::shared::unresolved_samples::{LastStackCache, UnresolvedSamples, UnresolvedStacks};
 
 use super::error::SamplingError;
 use super::kernel_error::{self, IntoResult, KernelError};
@@ -19,6 +19,30 @@ use super::thread_info::{
     THREAD_EXTENDED_INFO_COUNT, THREAD_IDENTIFIER_INFO, THREAD_IDENTIFIER_INFO_COUNT,
 };
 
+/// How many samples in a row without CPU time have to pass before a thread
+/// is demoted to the idle sampling rate.
+const IDLE_SAMPLES_BEFORE_DEMOTION: u32 = 100;
+
+/// The sampling rates for `--idle-thread-rate-divisor`. Threads whose CPU
+/// time didn't advance for [`IDLE_SAMPLES_BEFORE_DEMOTION`] samples in a row
+/// are only sampled every `idle_divisor` intervals, and go back to the full
+/// rate as soon as a sample sees their CPU time advance. The samples of
+/// demoted threads weigh as many intervals as have passed since the thread's
+/// previous sample, so that the sample weights still add up to the wall-clock
+/// time.
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingTiers {
+    pub interval_ns: u64,
+    /// 1 samples all threads at the full rate.
+    pub idle_divisor: u32,
+}
+
+impl SamplingTiers {
+    pub fn is_tiered(&self) -> bool {
+        self.idle_divisor > 1
+    }
+}
+
 pub struct ThreadProfiler {
     thread_act: thread_act_t,
     name: Option<String>,
@@ -28,6 +52,12 @@ pub struct ThreadProfiler {
     stack_memory: ForeignMemory,
     previous_sample_cpu_time_us: u64,
     ignored_errors: Vec<SamplingError>,
+    stack_cache: LastStackCache,
+    /// The number of samples in a row in which the CPU time didn't advance.
+    idle_sample_count: u32,
+    last_sample_mono: u64,
+    /// When this thread should be sampled next, see [`SamplingTiers`].
+    next_due_mono: u64,
 }
 
 impl ThreadProfiler {
@@ -46,15 +76,43 @@ impl ThreadProfiler {
             stack_memory: ForeignMemory::new(task),
             previous_sample_cpu_time_us: 0,
             ignored_errors: Vec::new(),
+            stack_cache: LastStackCache::default(),
+            idle_sample_count: 0,
+            last_sample_mono: 0,
+            next_due_mono: 0,
         }
     }
 
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    pub fn profile_thread(&self) -> ThreadHandle {
+        self.profile_thread
+    }
+
+    pub fn next_due_mono(&self) -> u64 {
+        self.next_due_mono
+    }
+
+    /// Whether the thread should be sampled in the tick at `now_mono`. The
+    /// ticks can come a little early, so anything within half an interval of
+    /// the due time counts.
+    pub fn is_due(&self, now_mono: u64, tiers: SamplingTiers) -> bool {
+        now_mono + tiers.interval_ns / 2 >= self.next_due_mono
+    }
+
+    fn is_demoted(&self, tiers: SamplingTiers) -> bool {
+        tiers.is_tiered() && self.idle_sample_count >= IDLE_SAMPLES_BEFORE_DEMOTION
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn sample(
         &mut self,
         stackwalker: StackwalkerRef,
         now: Timestamp,
         now_mono: u64,
+        tiers: SamplingTiers,
         profile: &mut Profile,
         stack_scratch_buffer: &mut Vec<FrameAddress>,
         unresolved_stacks: &mut UnresolvedStacks,
@@ -64,6 +122,7 @@ impl ThreadProfiler {
             stackwalker,
             now,
             now_mono,
+            tiers,
             profile,
             stack_scratch_buffer,
             unresolved_stacks,
@@ -97,6 +156,7 @@ impl ThreadProfiler {
         stackwalker: StackwalkerRef,
         now: Timestamp,
         now_mono: u64,
+        tiers: SamplingTiers,
         profile: &mut Profile,
         stack_scratch_buffer: &mut Vec<FrameAddress>,
         unresolved_stacks: &mut UnresolvedStacks,
@@ -116,6 +176,16 @@ impl ThreadProfiler {
         let cpu_delta_us = cpu_time_us - self.previous_sample_cpu_time_us;
         let cpu_delta = CpuDelta::from_micros(cpu_delta_us);
 
+        // A demoted thread's sample stands for all the intervals since its
+        // previous sample, including the sample which promotes it again.
+        let weight = if self.is_demoted(tiers) {
+            let elapsed_ns = now_mono.saturating_sub(self.last_sample_mono);
+            let intervals = (elapsed_ns + tiers.interval_ns / 2) / tiers.interval_ns.max(1);
+            i32::try_from(intervals.max(1)).unwrap_or(i32::MAX)
+        } else {
+            1
+        };
+
         if !cpu_delta.is_zero() || self.tick_count == 0 {
             stack_scratch_buffer.clear();
             get_backtrace(
@@ -133,8 +203,15 @@ impl ThreadProfiler {
                     StackFrame::ReturnAddress((*address).into(), StackMode::User)
                 }
             });
-            let stack = unresolved_stacks.convert(frames);
-            unresolved_samples.add_sample(self.profile_thread, now, now_mono, stack, cpu_delta, 1);
+            let stack = unresolved_stacks.convert_with_cache(frames, &mut self.stack_cache);
+            unresolved_samples.add_sample(
+                self.profile_thread,
+                now,
+                now_mono,
+                stack,
+                cpu_delta,
+                weight,
+            );
         } else {
             // No CPU time elapsed since just before the last time we grabbed a stack.
             // Assume that the thread has done literally zero work and could not have changed
@@ -155,11 +232,23 @@ impl ThreadProfiler {
                 self.profile_thread,
                 now,
                 now_mono,
-                1,
+                weight,
             );
         }
 
         self.previous_sample_cpu_time_us = cpu_time_us;
+        if cpu_delta.is_zero() {
+            self.idle_sample_count = self.idle_sample_count.saturating_add(1);
+        } else {
+            self.idle_sample_count = 0;
+        }
+        let interval_ns = if self.is_demoted(tiers) {
+            tiers.interval_ns * u64::from(tiers.idle_divisor)
+        } else {
+            tiers.interval_ns
+        };
+        self.last_sample_mono = now_mono;
+        self.next_due_mono = now_mono + interval_ns;
 
         Ok(())
     }
@@ -4,16 +4,26 @@ mod mac;
 #[cfg(target_os = "linux")]
 mod linux;
 
+mod aggregate;
+mod cli_error;
 mod import;
 mod linux_shared;
+mod merge;
+mod profile_filter;
+mod profile_json;
 mod server;
 mod shared;
+mod source_archive;
+mod trace_event;
+mod validate;
 
 use clap::{Args, Parser, Subcommand};
-use tempfile::NamedTempFile;
+use regex::Regex;
+use tempfile::TempDir;
 
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
 
 // To avoid warnings about unused declarations
@@ -25,7 +35,37 @@ use linux::profiler;
 #[cfg(target_os = "macos")]
 use mac::profiler;
 
-use server::{start_server_main, PortSelection, ServerProps};
+use aggregate::{parse_inline_mode, write_aggregate_main, AggregateOptions, InlineMode};
+use cli_error::CliError;
+use import::heap_profile::HeapProfile;
+use import::intermediate::IntermediateWriter;
+use import::perf_dir::PerfDir;
+use linux_shared::{
+    explain_log_main, parse_errno, parse_marker_pair, parse_marker_stacks, parse_off_cpu_stack,
+    parse_signal, parse_unwind_validation, BuildIdCaches, ConversionLog, ConversionOptions,
+    ConversionTimings, GuestOptions, MarkerPairHandler, MarkerPairSpec, MarkerStacks, OffCpuStack,
+    SyscallFailureHandler, TracepointHandler, UnwindValidation, WatchdogConfig,
+    DEFAULT_SYSCALL_FAILURE_ERRNOS,
+};
+use merge::{merge_main, parse_merge_layout, MergeLayout, MergeOptions};
+use profile_filter::ProfileFilter;
+use server::{serve_profiles_main, PortSelection, ServerProps};
+use shared::address_space_timeline::{parse_address_query, AddressQuery};
+use shared::frame_filter::{HideRule, LibraryGlob};
+use shared::jit_code_embedding::DEFAULT_EMBEDDED_JIT_CODE_BYTES;
+use shared::marker_addresses::MarkerAddressMode;
+use shared::path_map::{parse_path_map_rule, PathMap};
+use shared::perf_map::{PerfMapLimits, DEFAULT_PERF_MAP_MAX_BYTES, DEFAULT_PERF_MAP_MAX_ENTRIES};
+use shared::profile_split::{write_profile_parts, ConvertedProfile};
+use shared::request_spans::RequestSpans;
+use shared::self_profile::{PhaseRecorder, SelfProfiler};
+use shared::symbol_map::{parse_symbol_map_arg, SymbolMap, SymbolMaps};
+use shared::thread_groups::{builtin_thread_groups, parse_thread_group};
+use source_archive::{
+    write_source_archive_main, SourceArchiveOptions, DEFAULT_SOURCE_ARCHIVE_BUDGET,
+};
+use trace_event::{parse_output_format, write_trace_event_main, OutputFormat, TraceEventOptions};
+use validate::validate_main;
 
 #[derive(Debug, Parser)]
 #[command(
@@ -50,14 +90,24 @@ EXAMPLES:
 
     # Import perf.data files from Linux perf:
     samply load perf.data
+
+    # Serve previously saved profiles, finding symbols in a directory of binaries:
+    samply serve prof1.json prof2.json --binaries ./build/bin
 "#
 )]
 struct Opt {
+    /// Also write the log events, e.g. the converter's warnings, to this file
+    /// as JSON lines, with their fields and spans. RUST_LOG selects the events
+    /// for both stderr and the file, e.g. RUST_LOG=samply::linux_shared=trace.
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
     #[command(subcommand)]
     action: Action,
 }
 
 #[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Action {
     /// Load a profile from a file and display it.
     Load(LoadArgs),
@@ -65,16 +115,201 @@ enum Action {
     #[cfg(any(target_os = "macos", target_os = "linux"))]
     /// Record a profile and display it.
     Record(RecordArgs),
+
+    #[cfg(target_os = "linux")]
+    /// Show the hottest functions while recording, like `perf top`.
+    Top(TopArgs),
+
+    /// Serve existing profiles and answer symbolication requests for them.
+    Serve(ServeArgs),
+
+    /// Merge profiles into one, e.g. to compare two runs.
+    Merge(MergeArgs),
+
+    /// Print a log written by --record-conversion-log and check it for
+    /// inconsistencies, e.g. overlapping mappings.
+    ExplainLog(ExplainLogArgs),
+
+    /// Check a profile for references to missing table rows, timestamps out
+    /// of order and other inconsistencies, and print each one.
+    Validate(ValidateArgs),
 }
 
 #[derive(Debug, Args)]
 struct LoadArgs {
-    /// Path to the file that should be loaded.
+    /// Path to the file that should be loaded, or to a directory with
+    /// perf.data files. For a directory, the newest perf.data file is loaded.
+    /// Use - to read a perf.data stream from stdin, e.g. from
+    /// `perf record -o - ... | samply load -`.
     file: PathBuf,
 
+    /// When loading a directory, load all perf.data files in it into one
+    /// profile, in timestamp order, instead of only the newest one.
+    #[arg(long)]
+    all: bool,
+
+    /// Convert this perf.data file into the same profile as the loaded
+    /// perf.data file, e.g. a recording of the processes of another container
+    /// during the same time. Can be given several times. The files are lined
+    /// up through the wall clock if they were recorded with
+    /// `perf record -k CLOCK_MONOTONIC`; otherwise their timestamps are
+    /// assumed to be from the same clock.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["all", "emit_intermediate"]
+    )]
+    from_perf_data: Vec<PathBuf>,
+
     #[command(flatten)]
     conversion_args: ConversionArgs,
 
+    #[command(flatten)]
+    aggregate_args: AggregateArgs,
+
+    #[command(flatten)]
+    export_args: ExportArgs,
+
+    #[command(flatten)]
+    source_archive_args: SourceArchiveArgs,
+
+    #[command(flatten)]
+    server_args: ServerArgs,
+}
+
+#[derive(Debug, Args)]
+struct AggregateArgs {
+    /// Also write the self and total weight of each symbol to this file,
+    /// per process and library and sorted by self weight. Library addresses
+    /// are symbolicated for this, like in the profiler UI.
+    #[arg(long, value_name = "PATH")]
+    aggregate_output: Option<PathBuf>,
+
+    /// Write the --aggregate-output as CSV instead of JSON.
+    #[arg(long, requires = "aggregate_output")]
+    csv: bool,
+
+    /// Split up the self weight of each symbol in the --aggregate-output by
+    /// up to this many of its nearest callers. Defaults to 2; 0 writes a flat
+    /// list.
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        value_parser = clap::value_parser!(u8).range(0..=3),
+        requires = "aggregate_output"
+    )]
+    aggregate_callers: Option<u8>,
+
+    /// How functions which the debug info lists as inlined are aggregated in
+    /// the --aggregate-output: collapse folds them into the function they
+    /// were inlined into, like perf report, and expand counts them as
+    /// separate functions, with the self weight going to the innermost
+    /// inlined function. Defaults to collapse.
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_parser = parse_inline_mode,
+        requires = "aggregate_output"
+    )]
+    inline: Option<InlineMode>,
+
+    /// Only write the --aggregate-output, and don't open the profile.
+    #[arg(long, requires = "aggregate_output")]
+    aggregate_only: bool,
+}
+
+#[derive(Debug, Args)]
+struct ExportArgs {
+    /// The format of the profile: firefox opens it in the Firefox profiler,
+    /// trace-event writes the markers and counters to --export-output as
+    /// Trace Event Format JSON, for chrome://tracing and Perfetto, instead.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "firefox",
+        value_parser = parse_output_format
+    )]
+    output_format: OutputFormat,
+
+    /// The file to write for --output-format trace-event.
+    #[arg(
+        long,
+        value_name = "PATH",
+        required_if_eq("output_format", "trace-event")
+    )]
+    export_output: Option<PathBuf>,
+
+    /// Also write each sample for --output-format trace-event, as a duration
+    /// event of one sampling interval named by the leaf symbol.
+    #[arg(long)]
+    trace_event_samples: bool,
+}
+
+#[derive(Debug, Args)]
+struct SourceArchiveArgs {
+    /// Also copy the source files of the sampled functions into this
+    /// zstd-compressed tar archive, e.g. sources.tar.zst, so that the source
+    /// view still works once the build tree is gone: pass it to
+    /// --source-archive when serving the profile. Library addresses are
+    /// symbolicated for this, like in the profiler UI.
+    #[arg(long, value_name = "PATH")]
+    archive_sources: Option<PathBuf>,
+
+    /// The maximum total size of the source files in the --archive-sources
+    /// archive, in bytes before compression. The files of the functions with
+    /// the most sample weight are archived first. Defaults to 64 MiB.
+    #[arg(long, value_name = "BYTES", requires = "archive_sources")]
+    archive_sources_budget: Option<u64>,
+}
+
+#[derive(Debug, Args)]
+struct MergeArgs {
+    /// Paths to the profile JSON files that should be merged.
+    #[arg(required = true, num_args = 2..)]
+    files: Vec<PathBuf>,
+
+    /// Where to write the merged profile.
+    #[arg(short, long, default_value = "merged.json")]
+    output: PathBuf,
+
+    /// How the threads are arranged: concatenate lists the processes of all
+    /// profiles at their real times. interleave-by-thread aligns the profiles
+    /// at their start and puts each thread of the first profile directly
+    /// above its counterparts in the other profiles, matched by process name
+    /// and thread name without numeric suffixes, with "A: ", "B: " and so on
+    /// as prefixes.
+    #[arg(
+        long,
+        value_name = "LAYOUT",
+        default_value = "concatenate",
+        value_parser = parse_merge_layout
+    )]
+    merge_layout: MergeLayout,
+}
+
+#[derive(Debug, Args)]
+struct ExplainLogArgs {
+    /// Path to the conversion log.
+    log: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ValidateArgs {
+    /// Path to the profile JSON file, which can be gzipped.
+    file: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Paths to the profile JSON files that should be served.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Directories in which to look for binaries and debug files, for
+    /// symbolication and for the assembly view.
+    #[arg(long, value_name = "DIR", num_args = 1..)]
+    binaries: Vec<PathBuf>,
+
     #[command(flatten)]
     server_args: ServerArgs,
 }
@@ -98,6 +333,53 @@ struct RecordArgs {
     #[arg(short, long, default_value = "profile.json")]
     output: PathBuf,
 
+    /// Don't record stacks, only the sampled instruction address. This
+    /// greatly reduces the recording overhead at high sampling rates
+    /// (Linux only).
+    #[arg(long)]
+    leaf_only: bool,
+
+    /// If perf_event_paranoid, kptr_restrict or perf_event_mlock_kb keep the
+    /// recording from working fully, change them for the duration of the
+    /// recording and restore them afterwards. Requires running as root
+    /// (Linux only).
+    #[arg(long)]
+    auto_tune: bool,
+
+    /// Sample threads which haven't used any CPU time for 100 samples in a
+    /// row only every N intervals, until they use CPU time again. Their
+    /// samples weigh N intervals, so the sampled time stays the same. This
+    /// reduces the sampling overhead for processes with many idle threads
+    /// (macOS only).
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    idle_thread_rate_divisor: Option<u32>,
+
+    /// Every this often, save what was recorded since the last time to its
+    /// own profile file, which is named after its time range, e.g. "10m" for
+    /// profile-20261017T101500Z-20261017T102500Z.json and so on. Takes
+    /// seconds, or a number with an s, m or h suffix. Implies --save-only
+    /// (Linux only).
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_rotation_interval,
+        conflicts_with = "archive_sources"
+    )]
+    rotate: Option<std::time::Duration>,
+
+    /// With --rotate, delete the oldest files so that only the N most recent
+    /// ones are kept.
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "rotate",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    keep: Option<u32>,
+
+    #[command(flatten)]
+    source_archive_args: SourceArchiveArgs,
+
     #[command(flatten)]
     server_args: ServerArgs,
 
@@ -115,6 +397,36 @@ struct RecordArgs {
     pid: Option<u32>,
 }
 
+#[cfg(target_os = "linux")]
+#[derive(Debug, Args)]
+struct TopArgs {
+    /// Sampling rate, in Hz
+    #[arg(short, long, default_value = "1000")]
+    rate: f64,
+
+    /// The number of functions to show.
+    #[arg(short = 'n', long, default_value = "30")]
+    count: usize,
+
+    /// When quitting, save everything which was recorded to this file as a
+    /// profile.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Show the functions of this command.
+    #[arg(
+        required_unless_present = "pid",
+        conflicts_with = "pid",
+        allow_hyphen_values = true,
+        trailing_var_arg = true
+    )]
+    command: Vec<std::ffi::OsString>,
+
+    /// Process ID of existing process to attach to.
+    #[arg(short, long)]
+    pid: Option<u32>,
+}
+
 #[derive(Debug, Args)]
 struct ServerArgs {
     /// Do not open the profiler UI.
@@ -128,6 +440,12 @@ struct ServerArgs {
     /// Print debugging output.
     #[arg(short, long)]
     verbose: bool,
+
+    /// Answer the source view's requests for files which don't exist
+    /// anymore from this archive, which --archive-sources wrote. Can be given
+    /// several times.
+    #[arg(long, value_name = "PATH")]
+    source_archive: Vec<PathBuf>,
 }
 
 #[derive(Debug, Args)]
@@ -139,45 +457,552 @@ pub struct ConversionArgs {
     /// Fold repeated frames at the base of the stack.
     #[arg(long)]
     fold_recursive_prefix: bool,
+
+    /// For threads which exit without ever being sampled, add a single
+    /// zero-weight sample at exit with the thread's last known stack: the
+    /// stack it blocked with, or else its parent's stack when it was created.
+    #[arg(long)]
+    synthesize_samples_for_short_threads: bool,
+
+    /// Merge frames in PLT stubs into the frame of the function they call,
+    /// if both are on the stack, so that the callee is charged for the time.
+    #[arg(long)]
+    fold_plt: bool,
+
+    /// For Wine processes, replace the Unix-side frames of a Windows system
+    /// call or callback, from __wine_syscall_dispatcher up to the next frame
+    /// in Windows code, with a single "Windows syscall" frame. Wine's
+    /// transition functions get the "Wine" category either way.
+    #[arg(long)]
+    fold_wine_syscalls: bool,
+
+    /// Remove the kernel frames of perf's sampling interrupt from the
+    /// stacks, so that the interrupted function becomes the leaf. The
+    /// interrupted function is categorized as "Profiler overhead (kernel)",
+    /// so the overhead still shows up in the category breakdown.
+    #[arg(long)]
+    strip_profiler_frames: bool,
+
+    /// Remove the frames in libraries whose file name matches the glob from
+    /// sample and marker stacks, e.g. --hide-library 'libclang_rt.*'. Each
+    /// run of removed frames is replaced by a single "(hidden: GLOB)" frame.
+    /// Removed frames at the leaf are dropped, so that their caller becomes
+    /// the leaf. The glob is matched against the library's path if it
+    /// contains a slash. Can be repeated.
+    #[arg(long, value_name = "GLOB", value_parser = LibraryGlob::new)]
+    hide_library: Vec<LibraryGlob>,
+
+    /// Like --hide-library, but for the functions whose symbol name starts
+    /// with the prefix, e.g. --hide-symbol-prefix __tsan_. Can be repeated.
+    #[arg(long, value_name = "PREFIX")]
+    hide_symbol_prefix: Vec<String>,
+
+    /// Attribute each sample to the outermost function on its stack whose
+    /// demangled name matches the regex, e.g. --attribute-by-frame
+    /// 'myserver::handlers::(\w+)'. The label is the first capture group,
+    /// or the function name if the regex has none, and samples get a
+    /// "[request: LABEL]" frame with a subcategory of the "Request"
+    /// category. Samples without a matching frame are "(unattributed)". A
+    /// summary of the weight per label is printed at the end. Can be
+    /// repeated.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    attribute_by_frame: Vec<Regex>,
+
+    /// Label the samples with the request IDs of the spans they were taken
+    /// in, from a file which the application wrote while it was recorded.
+    /// Each line is "REQUEST_ID START_NS END_NS [TID]", with CLOCK_MONOTONIC
+    /// timestamps, like the ones of `perf record -k CLOCK_MONOTONIC`. Spans
+    /// without a tid apply to all threads. If spans overlap, the innermost
+    /// one wins. Samples get a "[REQUEST_ID]" frame with the "Request span"
+    /// category, and the request IDs with the most sample weight are printed
+    /// at the end.
+    #[arg(long, value_name = "FILE")]
+    request_spans: Option<PathBuf>,
+
+    /// Add "GC pause (~Nms)" and "GC phase (~Nms)" markers to the main
+    /// thread of a process for the times at which most of its samples are
+    /// in the garbage collector of the JVM, Go or .NET. GC samples are put
+    /// into the "GC" category either way.
+    #[arg(long)]
+    detect_gc: bool,
+
+    /// Only keep the sampled instruction address of each sample and ignore
+    /// its callers, even if the recording contains stacks.
+    #[arg(long)]
+    leaf_only: bool,
+
+    /// Keep at most this many frames of each stack, the callee-most ones,
+    /// and mark the stacks which were cut off. Deep stacks are then neither
+    /// fully unwound nor kept in memory, which makes the conversion of
+    /// recordings with very deep stacks faster.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "leaf_only"
+    )]
+    max_stack_depth: Option<u32>,
+
+    /// Stop growing the stack table once it has this many nodes. Stacks
+    /// with new frames after that end at their deepest known prefix, with a
+    /// marker. This bounds the memory use of recordings with a huge number
+    /// of distinct stacks, e.g. from deep recursion of varying depth.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_stack_nodes: Option<u32>,
+
+    /// How the return addresses found by DWARF unwinding are checked: off,
+    /// mappings (each one must be in an executable mapping of the process)
+    /// or calls (additionally, the instruction before it must be a call).
+    /// Unwinding stops at the first return address which fails the check,
+    /// and the stack is marked as truncated.
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        default_value = "mappings",
+        value_parser = parse_unwind_validation
+    )]
+    unwind_validation: UnwindValidation,
+
+    /// Weight the samples of each CPU by the sampling interval which was
+    /// observed on that CPU, so that each CPU-second has the same weight even
+    /// if some CPUs were sampled at a lower rate than requested. Only for
+    /// time-based sampling.
+    #[arg(long)]
+    normalize_cpu_rates: bool,
+
+    /// Embed the machine code of the hottest JIT functions from the jitdump
+    /// files into the profile, so that the assembly view works for them
+    /// without the jitdump files.
+    #[arg(long)]
+    embed_jit_code: bool,
+
+    /// The most code bytes which --embed-jit-code embeds. Defaults to 4 MiB.
+    #[arg(long, value_name = "BYTES", requires = "embed_jit_code")]
+    embed_jit_code_max_bytes: Option<u64>,
+
+    /// In marker payloads, e.g. futex addresses and pointer arguments of
+    /// probes, write addresses which are in a mapped file as
+    /// "libname+0xoffset (symbol)".
+    #[arg(long, conflicts_with = "redact_marker_addresses")]
+    resolve_marker_addresses: bool,
+
+    /// In marker payloads, replace addresses with opaque tokens like "ptr#1",
+    /// so that the profile can be shared without revealing the address space
+    /// layout. Equal addresses get equal tokens.
+    #[arg(long)]
+    redact_marker_addresses: bool,
+
+    /// For processes which have samples but no COMM or MMAP records, e.g.
+    /// because perf was run with --no-synth, read their mappings, name and
+    /// executable from /proc at import time. This only works on the recording
+    /// machine, while the processes are still running.
+    #[arg(long)]
+    synthesize_from_proc: bool,
+
+    /// Refuse perf map files (/tmp/perf-<pid>.map) which are larger than
+    /// this. Defaults to 512 MiB.
+    #[arg(long, value_name = "BYTES")]
+    perf_map_max_bytes: Option<u64>,
+
+    /// Refuse perf map files with more entries than this. Defaults to 4
+    /// million.
+    #[arg(long, value_name = "N")]
+    perf_map_max_entries: Option<usize>,
+
+    /// Put the samples on one thread per CPU instead of on the sampled
+    /// threads, with a marker for each period in which a thread ran on the
+    /// CPU. Needs samples with the CPU, e.g. from `perf record -a`.
+    #[arg(long)]
+    per_cpu_threads: bool,
+
+    /// Which stack the off-CPU samples get: blocked (where the thread was
+    /// switched out), resumed (the thread's first sample after it was
+    /// switched back in) or both (the first half of each off-CPU period with
+    /// the blocked stack, the second half with the resumed stack). Periods
+    /// without a sample shortly after the switch-in keep the blocked stack.
+    #[arg(
+        long,
+        value_name = "STACK",
+        default_value = "blocked",
+        value_parser = parse_off_cpu_stack
+    )]
+    off_cpu_stack: OffCpuStack,
+
+    /// Aggregate the samples of the memory counters into buckets of this many
+    /// milliseconds, one point per bucket, so that the counter tracks don't
+    /// alias. Large single changes keep their exact time. Defaults to the
+    /// sampling interval; 0 keeps every sample.
+    #[arg(long, value_name = "MS")]
+    counter_bucket_ms: Option<f64>,
+
+    /// Exclude samples which were taken while a KVM guest was running.
+    #[arg(long)]
+    drop_guest_samples: bool,
+
+    /// A copy of the guest's /proc/kallsyms, used to symbolicate guest kernel frames.
+    #[arg(long, value_name = "FILE")]
+    guest_kallsyms: Option<PathBuf>,
+
+    /// A copy of the guest's /proc/modules, or a directory containing it as
+    /// "modules". Requires --guest-kallsyms.
+    #[arg(long, value_name = "DIR", requires = "guest_kallsyms")]
+    guest_modules: Option<PathBuf>,
+
+    /// Add markers for syscalls which fail with resource exhaustion errors,
+    /// if the profile contains syscalls:sys_exit_* tracepoints.
+    #[arg(long)]
+    syscall_failure_markers: bool,
+
+    /// The errnos which --syscall-failure-markers reports, by name or number.
+    /// Defaults to EMFILE, ENFILE, ENOMEM and EAGAIN.
+    #[arg(
+        long,
+        value_name = "ERRNOS",
+        value_delimiter = ',',
+        value_parser = parse_errno_arg,
+        requires = "syscall_failure_markers"
+    )]
+    syscall_failure_errnos: Option<Vec<i64>>,
+
+    /// Add interval markers between the samples of two tracepoint events,
+    /// e.g. USDT probes which mark the phases of an application:
+    /// begin=<event-name>,end=<event-name>[,name-field=<field>]. The markers
+    /// are named after the begin event, or after the value of its name
+    /// field, which also pairs up begins and ends across the threads of a
+    /// process. Can be given multiple times.
+    #[arg(long, value_name = "PAIR", value_parser = parse_marker_pair)]
+    marker_pair: Vec<MarkerPairSpec>,
+
+    /// Signals which don't get markers if the profile contains
+    /// signal:signal_deliver tracepoints, by name or number, e.g.
+    /// --ignore-signals SIGCHLD,SIGALRM. Signals which terminate the process
+    /// always get a marker.
+    #[arg(
+        long,
+        value_name = "SIGNALS",
+        value_delimiter = ',',
+        value_parser = parse_signal_arg
+    )]
+    ignore_signals: Vec<i32>,
+
+    /// Put threads whose name fully matches the regex into a named thread
+    /// group, e.g. --thread-group 'Workers=worker-\d+'. Can be repeated.
+    /// Threads of the same group are listed next to each other.
+    #[arg(long, value_name = "NAME=REGEX", value_parser = parse_thread_group)]
+    thread_group: Vec<(String, Regex)>,
+
+    /// Don't use the built-in thread groups (GC, Render, IO).
+    #[arg(long)]
+    no_builtin_thread_groups: bool,
+
+    /// Only keep the processes whose name matches the regex in the written
+    /// profile. Their libraries are kept either way.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    filter_process: Option<Regex>,
+
+    /// Drop the processes whose name matches the regex from the written
+    /// profile.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    exclude_process: Option<Regex>,
+
+    /// Only keep the threads whose name matches the regex, among the threads
+    /// of the processes which the process filters keep.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    filter_thread: Option<Regex>,
+
+    /// Drop the threads whose name matches the regex.
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    exclude_thread: Option<Regex>,
+
+    /// Keep the main thread of each kept process, even if the thread filters
+    /// would drop it.
+    #[arg(long)]
+    keep_main_threads: bool,
+
+    /// A jemalloc or tcmalloc heap profile of a recorded process, which is
+    /// added as a "Heap" track with live bytes per allocation stack. Can be
+    /// repeated.
+    #[arg(long, value_name = "FILE")]
+    heap_profile: Vec<PathBuf>,
+
+    /// The pid of the process which the --heap-profile files belong to. By
+    /// default, this is taken from the name of jemalloc's dump files, or found
+    /// by matching the executable.
+    #[arg(long, value_name = "PID", requires = "heap_profile")]
+    heap_profile_pid: Option<i32>,
+
+    /// Profile samply itself while converting, and save that profile to this
+    /// file. The profile has markers for the stages of the conversion (Linux
+    /// and macOS only).
+    #[arg(long, value_name = "PATH")]
+    self_profile: Option<PathBuf>,
+
+    /// Open binaries, jitdump files and perf map files whose path starts with
+    /// FROM at the same path under TO instead, e.g. --path-map /app=./rootfs/app
+    /// for binaries from a container. Can be repeated; if several prefixes
+    /// match, the longest one wins.
+    #[arg(long, value_name = "FROM=TO", value_parser = parse_path_map_rule)]
+    path_map: Vec<(PathBuf, PathBuf)>,
+
+    /// Use the symbols from FILE for the libraries which match LIB_GLOB, e.g.
+    /// --symbol-map 'libblob*.so=blob.map', for libraries without symbols.
+    /// FILE is a GNU ld or LLD map file, or a .csv file with hex_offset,name
+    /// lines whose offsets are relative addresses. The glob is matched
+    /// against the library's file name, its path if the glob contains a
+    /// slash, or its build ID. Can be repeated; the first match wins.
+    #[arg(long, value_name = "LIB_GLOB=FILE", value_parser = parse_symbol_map_arg)]
+    symbol_map: Vec<(LibraryGlob, PathBuf)>,
+
+    /// Which rss_stat and other-event markers keep their stack: all, sampled
+    /// (at most one per thread and event per sampling interval) or none.
+    /// Markers without a stack are still added.
+    #[arg(
+        long,
+        value_name = "MODE",
+        default_value = "all",
+        value_parser = parse_marker_stacks
+    )]
+    marker_stacks: MarkerStacks,
+
+    /// A perf build ID cache directory, like the default ~/.debug. Can be
+    /// repeated; the directories are searched in the order given, instead of
+    /// ~/.debug. A binary is taken from the cache if the mapped file (after
+    /// --path-map) and the file of the same name in the binaries directory are
+    /// missing or have a different build ID. The symbols are later looked up
+    /// in the file that was found, before any symbol server or debuginfod.
+    #[arg(long, value_name = "DIR")]
+    buildid_cache: Vec<PathBuf>,
+
+    /// Skip a binary if opening it takes longer than this many seconds, e.g.
+    /// on an unresponsive network file system. 0 waits for every open.
+    /// Binaries which couldn't be opened are only tried once per conversion.
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    file_open_timeout: u64,
+
+    /// Print which library an address belonged to in a process at a time,
+    /// and whether the mapping came from an mmap record, a jitdump file or a
+    /// perf map. The address is in hex and the time is in seconds, as printed
+    /// by perf script, e.g. --query 1234,7f3a2c001234,5361.123456. Can be
+    /// repeated.
+    #[arg(long, value_name = "PID,ADDR,TIME", value_parser = parse_address_query)]
+    query: Vec<AddressQuery>,
+
+    /// If the conversion processes no records for this many seconds, print
+    /// the current record number and type and the sizes of the converter's
+    /// state, e.g. the samples, mappings and JIT functions of each process.
+    #[arg(long, value_name = "SECS")]
+    watchdog: Option<u64>,
+
+    /// Exit with code 5 after the --watchdog output, instead of continuing
+    /// the conversion.
+    #[arg(long, requires = "watchdog")]
+    watchdog_abort: bool,
+
+    /// Print how many records of each type were converted and how long their
+    /// handling took, and how long stack unwinding, stack interning and
+    /// jitdump processing took. The numbers are also in the --log-file.
+    #[arg(long)]
+    timings: bool,
+
+    /// Write the decisions of the conversion which affect how addresses are
+    /// attributed to libraries to this file: the base address of each
+    /// mapping and how it was found, the skipped mappings, and the number of
+    /// records per type. The log has no sample contents, and hashes instead
+    /// of paths, so it can be shared in bug reports. Print it with samply
+    /// explain-log.
+    #[arg(long, value_name = "PATH", conflicts_with = "all")]
+    record_conversion_log: Option<PathBuf>,
+
+    /// Write the records of the conversion to this file in the samply
+    /// intermediate format, with the stacks already unwound. samply load
+    /// converts the file without the perf.data file and the binaries which
+    /// were needed for unwinding, e.g. on another machine.
+    #[arg(long, value_name = "PATH", conflicts_with = "all")]
+    emit_intermediate: Option<PathBuf>,
+
+    /// If the converted profile is estimated to be larger than this many
+    /// bytes, split it by time into parts which each are a complete profile,
+    /// and write an index.json which lists the parts and their time ranges.
+    /// The parts are served together.
+    #[arg(long, value_name = "BYTES")]
+    max_output_size: Option<u64>,
+
+    /// Keep the time before the first sample of a recording made with perf
+    /// record --delay in the profile. By default, the profile starts just
+    /// before the first sample, and a "Recording started" marker shows the
+    /// length of the delay.
+    #[arg(long)]
+    keep_recording_delay: bool,
+}
+
+fn parse_errno_arg(s: &str) -> Result<i64, String> {
+    parse_errno(s).ok_or_else(|| format!("unknown errno {s}"))
+}
+
+fn parse_signal_arg(s: &str) -> Result<i32, String> {
+    parse_signal(s).ok_or_else(|| format!("unknown signal {s}"))
+}
+
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn parse_rotation_interval(s: &str) -> Result<std::time::Duration, String> {
+    let (number, seconds_per_unit) = match s.as_bytes().last() {
+        Some(b's') => (&s[..s.len() - 1], 1.0),
+        Some(b'm') => (&s[..s.len() - 1], 60.0),
+        Some(b'h') => (&s[..s.len() - 1], 3600.0),
+        _ => (s, 1.0),
+    };
+    let seconds = number
+        .parse::<f64>()
+        .map(|number| number * seconds_per_unit)
+        .map_err(|_| format!("invalid duration {s}, expected e.g. 90, 30s, 10m or 1h"))?;
+    if !seconds.is_finite() || seconds < 1.0 {
+        return Err(format!(
+            "the rotation interval {s} is shorter than a second"
+        ));
+    }
+    Ok(std::time::Duration::from_secs_f64(seconds))
 }
 
 fn main() {
-    let opt = Opt::parse();
+    let opt = match Opt::try_parse() {
+        Ok(opt) => opt,
+        // --help and --version
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => CliError::user_input(err.to_string().trim_end()).exit(),
+    };
+    if let Err(err) = shared::logging::init(opt.log_file.as_deref()) {
+        err.exit();
+    }
+    match std::panic::catch_unwind(AssertUnwindSafe(|| run(opt))) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => err.exit(),
+        Err(_) => cli_error::exit_after_panic(),
+    }
+}
+
+fn run(opt: Opt) -> Result<(), CliError> {
     match opt.action {
+        Action::Load(load_args) if !load_args.from_perf_data.is_empty() => {
+            load_perf_files(&load_args)?;
+        }
+
+        Action::Load(load_args) if load_args.file.is_dir() => {
+            load_perf_dir(&load_args)?;
+        }
+
+        Action::Load(load_args) if load_args.file == Path::new("-") => {
+            load_perf_stream(&load_args)?;
+        }
+
         Action::Load(load_args) => {
-            let input_file = match File::open(&load_args.file) {
-                Ok(file) => file,
-                Err(err) => {
-                    eprintln!("Could not open file {:?}: {}", load_args.file, err);
-                    std::process::exit(1)
-                }
+            let input_file = File::open(&load_args.file).map_err(|err| {
+                CliError::io(format!("Could not open file {:?}", load_args.file), &err)
+            })?;
+            let self_profiler = load_args.conversion_args.start_self_profiler()?;
+            let converted_files = attempt_conversion(
+                &load_args.file,
+                &input_file,
+                &load_args.conversion_args,
+                self_profiler.as_ref(),
+            )?;
+            let paths = match &converted_files {
+                Some(files) => files.paths.clone(),
+                None => vec![load_args.file.clone()],
             };
-            let converted_temp_file =
-                attempt_conversion(&load_args.file, &input_file, &load_args.conversion_args);
-            let filename = match &converted_temp_file {
-                Some(temp_file) => temp_file.path(),
-                None => &load_args.file,
+            write_exports(
+                &load_args,
+                &paths,
+                &[],
+                self_profiler.as_ref().map(SelfProfiler::phases),
+            )?;
+            if let Some(self_profiler) = self_profiler {
+                self_profiler.finish();
+            }
+            if load_args.opens_profile() {
+                serve_profiles_main(&paths, &[], load_args.server_props()?);
+            }
+        }
+
+        Action::Serve(serve_args) => {
+            for file in &serve_args.files {
+                if let Err(err) = File::open(file) {
+                    return Err(CliError::io(format!("Could not open file {file:?}"), &err));
+                }
+            }
+            serve_profiles_main(
+                &serve_args.files,
+                &serve_args.binaries,
+                serve_args.server_args.server_props()?,
+            );
+        }
+
+        Action::Merge(merge_args) => {
+            merge_main(&merge_args.files, &merge_args.merge_options())?;
+        }
+
+        Action::ExplainLog(explain_log_args) => {
+            explain_log_main(&explain_log_args.log)?;
+        }
+
+        Action::Validate(validate_args) => {
+            validate_main(&validate_args.file)?;
+        }
+
+        #[cfg(target_os = "linux")]
+        Action::Top(top_args) => {
+            use std::time::Duration;
+
+            if top_args.rate <= 0.0 {
+                return Err(CliError::user_input(format!(
+                    "Error: sampling rate must be greater than zero, got {}",
+                    top_args.rate
+                )));
+            }
+            let interval = Duration::from_secs_f64(1.0 / top_args.rate);
+            let target = match top_args.pid {
+                Some(pid) => profiler::TopTarget::Pid(pid),
+                None => profiler::TopTarget::Command(
+                    top_args.command[0].clone(),
+                    top_args.command[1..].to_vec(),
+                ),
             };
-            start_server_main(filename, load_args.server_args.server_props());
+            profiler::start_top(target, interval, top_args.count, top_args.output.as_deref());
         }
 
         #[cfg(any(target_os = "macos", target_os = "linux"))]
         Action::Record(record_args) => {
+            use shared::rotation::RotationOptions;
             use std::time::Duration;
 
-            let server_props = if record_args.save_only {
+            // Rotating recordings are saved to many files, so there's no
+            // single profile to serve.
+            let server_props = if record_args.save_only || record_args.rotate.is_some() {
                 None
             } else {
-                Some(record_args.server_args.server_props())
+                Some(
+                    record_args
+                        .source_archive_args
+                        .server_props(&record_args.server_args)?,
+                )
+            };
+            // With --archive-sources, the profile is only served once the
+            // archive is written.
+            let source_archive = record_args.source_archive_args.source_archive_options();
+            let (server_props, server_props_after_archiving) = match source_archive {
+                Some(_) => (None, server_props),
+                None => (server_props, None),
             };
+            let rotation = record_args.rotate.map(|interval| RotationOptions {
+                interval,
+                keep: record_args.keep.map(|keep| keep as usize),
+            });
 
             let time_limit = record_args.duration.map(Duration::from_secs_f64);
             if record_args.rate <= 0.0 {
-                eprintln!(
+                return Err(CliError::user_input(format!(
                     "Error: sampling rate must be greater than zero, got {}",
                     record_args.rate
-                );
-                std::process::exit(1);
+                )));
             }
             let interval = Duration::from_secs_f64(1.0 / record_args.rate);
 
@@ -187,70 +1012,662 @@ fn main() {
                     pid,
                     time_limit,
                     interval,
+                    record_args.idle_thread_rate_divisor,
+                    record_args.leaf_only,
+                    record_args.auto_tune,
+                    rotation,
                     server_props,
                 );
+                archive_recorded_sources(
+                    &record_args.output,
+                    source_archive.as_ref(),
+                    server_props_after_archiving,
+                    record_args.server_args.verbose,
+                )?;
             } else {
-                let exit_status = match profiler::start_recording(
+                let exit_status = profiler::start_recording(
                     &record_args.output,
                     record_args.command[0].clone(),
                     &record_args.command[1..],
                     time_limit,
                     interval,
+                    record_args.idle_thread_rate_divisor,
+                    record_args.leaf_only,
+                    record_args.auto_tune,
+                    rotation,
                     server_props,
-                ) {
-                    Ok(exit_status) => exit_status,
-                    Err(err) => {
-                        eprintln!("Encountered an error during profiling: {err:?}");
-                        std::process::exit(1);
-                    }
-                };
+                )?;
+                archive_recorded_sources(
+                    &record_args.output,
+                    source_archive.as_ref(),
+                    server_props_after_archiving,
+                    record_args.server_args.verbose,
+                )?;
                 std::process::exit(exit_status.code().unwrap_or(0));
             }
         }
     }
+    Ok(())
+}
+
+/// Writes the --archive-sources archive of a recorded profile, and then
+/// serves the profile, which the profiler doesn't do itself in that case.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+fn archive_recorded_sources(
+    profile_path: &Path,
+    options: Option<&SourceArchiveOptions>,
+    server_props: Option<ServerProps>,
+    verbose: bool,
+) -> Result<(), CliError> {
+    let Some(options) = options else {
+        return Ok(());
+    };
+    write_source_archive_main(&[profile_path.to_owned()], &[], options, verbose)?;
+    if let Some(server_props) = server_props {
+        server::start_server_main(profile_path, server_props);
+    }
+    Ok(())
 }
 
 impl ServerArgs {
-    pub fn server_props(&self) -> ServerProps {
+    pub fn server_props(&self) -> Result<ServerProps, CliError> {
         let open_in_browser = !self.no_open;
-        let port_selection = match PortSelection::try_from_str(&self.port) {
-            Ok(p) => p,
-            Err(e) => {
-                eprintln!(
-                    "Could not parse port as <u16> or <u16>+, got port {}, error: {}",
-                    self.port, e
-                );
-                std::process::exit(1)
-            }
-        };
-        ServerProps {
+        let port_selection = PortSelection::try_from_str(&self.port).map_err(|e| {
+            CliError::user_input(format!(
+                "Could not parse port as <u16> or <u16>+, got port {}, error: {}",
+                self.port, e
+            ))
+        })?;
+        Ok(ServerProps {
             port_selection,
             verbose: self.verbose,
             open_in_browser,
+            source_archives: self.source_archive.clone(),
+        })
+    }
+}
+
+impl SourceArchiveArgs {
+    fn source_archive_options(&self) -> Option<SourceArchiveOptions> {
+        Some(SourceArchiveOptions {
+            output: self.archive_sources.clone()?,
+            budget: self
+                .archive_sources_budget
+                .unwrap_or(DEFAULT_SOURCE_ARCHIVE_BUDGET),
+        })
+    }
+
+    /// The server props, with the archive which --archive-sources wrote.
+    fn server_props(&self, server_args: &ServerArgs) -> Result<ServerProps, CliError> {
+        let mut props = server_args.server_props()?;
+        props.source_archives.extend(self.archive_sources.clone());
+        Ok(props)
+    }
+}
+
+impl LoadArgs {
+    /// Whether the profile is opened in the Firefox profiler after the
+    /// exports, rather than only exported.
+    fn opens_profile(&self) -> bool {
+        !self.aggregate_args.aggregate_only
+            && self.export_args.output_format == OutputFormat::Firefox
+    }
+
+    fn server_props(&self) -> Result<ServerProps, CliError> {
+        self.source_archive_args.server_props(&self.server_args)
+    }
+}
+
+impl AggregateArgs {
+    fn aggregate_options(&self) -> Option<AggregateOptions> {
+        Some(AggregateOptions {
+            output: self.aggregate_output.clone()?,
+            csv: self.csv,
+            caller_depth: self.aggregate_callers.unwrap_or(2).into(),
+            inline_mode: self.inline.unwrap_or_default(),
+        })
+    }
+}
+
+impl MergeArgs {
+    fn merge_options(&self) -> MergeOptions {
+        MergeOptions {
+            output: self.output.clone(),
+            layout: self.merge_layout,
         }
     }
 }
 
+impl ExportArgs {
+    fn trace_event_options(&self) -> Option<TraceEventOptions> {
+        if self.output_format != OutputFormat::TraceEvent {
+            return None;
+        }
+        Some(TraceEventOptions {
+            output: self.export_output.clone()?,
+            samples: self.trace_event_samples,
+        })
+    }
+}
+
+impl ConversionArgs {
+    pub fn conversion_options(&self) -> Result<ConversionOptions, CliError> {
+        Ok(ConversionOptions {
+            merge_threads: self.merge_threads,
+            fold_recursive_prefix: self.fold_recursive_prefix,
+            synthesize_samples_for_short_threads: self.synthesize_samples_for_short_threads,
+            fold_plt: self.fold_plt,
+            fold_wine_syscalls: self.fold_wine_syscalls,
+            strip_profiler_frames: self.strip_profiler_frames,
+            hidden_frame_rules: self.hidden_frame_rules(),
+            request_attribution_regexes: self.attribute_by_frame.clone(),
+            request_spans: self.request_spans()?,
+            detect_gc_pauses: self.detect_gc,
+            leaf_only: self.leaf_only,
+            max_stack_depth: self.max_stack_depth.map(|depth| depth as usize),
+            max_stack_nodes: self.max_stack_nodes.map(|count| count as usize),
+            unwind_validation: self.unwind_validation,
+            normalize_cpu_rates: self.normalize_cpu_rates,
+            embed_jit_code: self.embed_jit_code.then(|| {
+                self.embed_jit_code_max_bytes
+                    .unwrap_or(DEFAULT_EMBEDDED_JIT_CODE_BYTES)
+            }),
+            marker_address_mode: self.marker_address_mode(),
+            off_cpu_stack: self.off_cpu_stack,
+            counter_bucket_duration_ns: self.counter_bucket_ms.map(|ms| (ms * 1_000_000.0) as u64),
+            guest: GuestOptions {
+                drop_samples: self.drop_guest_samples,
+                kallsyms: self.guest_kallsyms.clone(),
+                modules: self.guest_modules.clone(),
+            },
+            tracepoint_handlers: self.tracepoint_handlers(),
+            ignored_signals: self.ignore_signals.clone(),
+            // Read from the recording by the importer.
+            tracepoint_formats: Default::default(),
+            numa_topology: None,
+            thread_groups: self.thread_groups(),
+            jitdump_paths_by_pid: Default::default(),
+            // There's no /proc for the recorded processes at import time.
+            take_mapping_snapshots: false,
+            synthesize_from_proc: self.synthesize_from_proc,
+            perf_map_limits: PerfMapLimits {
+                max_bytes: self
+                    .perf_map_max_bytes
+                    .unwrap_or(DEFAULT_PERF_MAP_MAX_BYTES),
+                max_entries: self
+                    .perf_map_max_entries
+                    .unwrap_or(DEFAULT_PERF_MAP_MAX_ENTRIES),
+            },
+            per_cpu_threads: self.per_cpu_threads,
+            // Imports are never rotated.
+            track_mappings_for_checkpoints: false,
+            heap_profiles: self.heap_profiles()?,
+            phases: None,
+            path_map: PathMap::new(self.path_map.clone()),
+            marker_stacks: self.marker_stacks,
+            build_id_caches: BuildIdCaches::new(if self.buildid_cache.is_empty() {
+                BuildIdCaches::default_dirs()
+            } else {
+                self.buildid_cache.clone()
+            }),
+            file_open_timeout: (self.file_open_timeout != 0)
+                .then(|| std::time::Duration::from_secs(self.file_open_timeout)),
+            symbol_maps: self.symbol_maps()?,
+            timings: self.timings.then(ConversionTimings::default),
+            address_queries: self.query.clone(),
+            conversion_log: self.conversion_log()?,
+            intermediate: self.intermediate_writer()?,
+            watchdog: self.watchdog.map(|secs| WatchdogConfig {
+                timeout: std::time::Duration::from_secs(secs),
+                abort: self.watchdog_abort,
+            }),
+            max_output_size: self.max_output_size,
+            keep_recording_delay: self.keep_recording_delay,
+            // Only used while recording.
+            container_binaries: None,
+            live_samples: None,
+        })
+    }
+
+    /// Starts recording samply itself if `--self-profile` was passed. The
+    /// caller passes the profiler's phase recorder to the conversion and
+    /// finishes the self-profile once the conversion is done.
+    fn start_self_profiler(&self) -> Result<Option<SelfProfiler>, CliError> {
+        let Some(path) = &self.self_profile else {
+            return Ok(None);
+        };
+        #[cfg(any(target_os = "macos", target_os = "linux"))]
+        {
+            Ok(Some(profiler::start_self_profiling(path)))
+        }
+        #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+        {
+            let _ = path;
+            Err(CliError::user_input(
+                "--self-profile is currently only supported on Linux and macOS.",
+            ))
+        }
+    }
+
+    fn heap_profiles(&self) -> Result<Vec<HeapProfile>, CliError> {
+        self.heap_profile
+            .iter()
+            .map(|path| {
+                let mut heap_profile = HeapProfile::from_file(path).map_err(|err| match &err {
+                    import::heap_profile::Error::Io(io_err) => {
+                        CliError::io(format!("Could not read heap profile {path:?}"), io_err)
+                    }
+                    _ => CliError::user_input(format!(
+                        "Could not parse heap profile {path:?}: {err}"
+                    )),
+                })?;
+                if let Some(pid) = self.heap_profile_pid {
+                    heap_profile.pid = Some(pid);
+                }
+                Ok(heap_profile)
+            })
+            .collect()
+    }
+
+    fn request_spans(&self) -> Result<Option<RequestSpans>, CliError> {
+        let Some(path) = &self.request_spans else {
+            return Ok(None);
+        };
+        let spans = RequestSpans::from_file(path).map_err(|err| match &err {
+            shared::request_spans::Error::Io(io_err) => {
+                CliError::io(format!("Could not read request spans {path:?}"), io_err)
+            }
+            _ => CliError::user_input(format!("Could not parse request spans {path:?}: {err}")),
+        })?;
+        Ok(Some(spans))
+    }
+
+    fn symbol_maps(&self) -> Result<SymbolMaps, CliError> {
+        let maps = self
+            .symbol_map
+            .iter()
+            .map(|(glob, path)| {
+                SymbolMap::from_file(glob.clone(), path).map_err(|err| {
+                    CliError::io(format!("Could not read symbol map {path:?}"), &err)
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(SymbolMaps::new(maps))
+    }
+
+    fn conversion_log(&self) -> Result<Option<ConversionLog>, CliError> {
+        let Some(path) = &self.record_conversion_log else {
+            return Ok(None);
+        };
+        let log = ConversionLog::create(path)
+            .map_err(|err| CliError::io(format!("Could not create {path:?}"), &err))?;
+        Ok(Some(log))
+    }
+
+    fn intermediate_writer(&self) -> Result<Option<IntermediateWriter>, CliError> {
+        let Some(path) = &self.emit_intermediate else {
+            return Ok(None);
+        };
+        let writer = IntermediateWriter::create(path)
+            .map_err(|err| CliError::io(format!("Could not create {path:?}"), &err))?;
+        Ok(Some(writer))
+    }
+
+    fn hidden_frame_rules(&self) -> Vec<HideRule> {
+        let libraries = self.hide_library.iter().cloned().map(HideRule::Library);
+        let prefixes = self
+            .hide_symbol_prefix
+            .iter()
+            .cloned()
+            .map(HideRule::SymbolPrefix);
+        libraries.chain(prefixes).collect()
+    }
+
+    /// The filters for the written profile, or None if there are none.
+    fn profile_filter(&self) -> Option<ProfileFilter> {
+        let filter = ProfileFilter {
+            processes: self.filter_process.clone(),
+            exclude_processes: self.exclude_process.clone(),
+            threads: self.filter_thread.clone(),
+            exclude_threads: self.exclude_thread.clone(),
+            keep_main_threads: self.keep_main_threads,
+        };
+        (!filter.is_empty()).then(|| filter)
+    }
+
+    fn thread_groups(&self) -> Vec<(String, Regex)> {
+        let mut groups = self.thread_group.clone();
+        if !self.no_builtin_thread_groups {
+            groups.extend(builtin_thread_groups());
+        }
+        groups
+    }
+
+    fn marker_address_mode(&self) -> MarkerAddressMode {
+        if self.resolve_marker_addresses {
+            MarkerAddressMode::Resolve
+        } else if self.redact_marker_addresses {
+            MarkerAddressMode::Redact
+        } else {
+            MarkerAddressMode::Raw
+        }
+    }
+
+    fn tracepoint_handlers(&self) -> Vec<Box<dyn TracepointHandler>> {
+        let mut handlers: Vec<Box<dyn TracepointHandler>> = Vec::new();
+        if self.syscall_failure_markers {
+            let errnos = match &self.syscall_failure_errnos {
+                Some(errnos) => errnos.clone(),
+                None => DEFAULT_SYSCALL_FAILURE_ERRNOS.to_vec(),
+            };
+            handlers.push(Box::new(SyscallFailureHandler::new(errnos)));
+        }
+        if !self.marker_pair.is_empty() {
+            handlers.push(Box::new(MarkerPairHandler::new(self.marker_pair.clone())));
+        }
+        handlers
+    }
+}
+
+fn load_perf_dir(load_args: &LoadArgs) -> Result<(), CliError> {
+    let perf_dir = PerfDir::scan(&load_args.file).map_err(|err| match &err {
+        import::perf_dir::Error::Io(_, io_err) => CliError::io(&err, io_err),
+        _ => CliError::user_input(err.to_string()),
+    })?;
+    let files: Vec<&Path> = if load_args.all {
+        perf_dir
+            .perf_data_files
+            .iter()
+            .map(PathBuf::as_path)
+            .collect()
+    } else {
+        vec![perf_dir.newest_perf_data_file()]
+    };
+
+    let self_profiler = load_args.conversion_args.start_self_profiler()?;
+    let mut options = load_args.conversion_args.conversion_options()?;
+    options.jitdump_paths_by_pid = perf_dir.jitdump_paths_by_pid.clone();
+    options.phases = self_profiler.as_ref().map(|p| p.phases().clone());
+    let extra_dir = Some(perf_dir.extra_binary_artifact_dir.as_path());
+    let filter = load_args.conversion_args.profile_filter();
+    let input_files = files
+        .iter()
+        .map(|file| {
+            eprintln!("Converting {file:?}");
+            File::open(file)
+                .map_err(|err| CliError::io(format!("Could not open file {file:?}"), &err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // With --all, the files are parts of one recording, so they go into one
+    // profile, and processes which span several files keep their state.
+    let converted_files = match &input_files[..] {
+        [input_file] => convert_perf_file(input_file, extra_dir, options, filter.as_ref())?,
+        _ => {
+            let phases = options.phases.clone();
+            let max_output_size = options.max_output_size;
+            let converted = import::perf::convert_multiple(&input_files, extra_dir, options)?;
+            write_converted_profile(
+                &converted,
+                max_output_size,
+                filter.as_ref(),
+                phases.as_ref(),
+            )?
+        }
+    };
+
+    let converted_paths = &converted_files.paths;
+    write_exports(
+        load_args,
+        converted_paths,
+        std::slice::from_ref(&perf_dir.extra_binary_artifact_dir),
+        self_profiler.as_ref().map(SelfProfiler::phases),
+    )?;
+    if let Some(self_profiler) = self_profiler {
+        self_profiler.finish();
+    }
+    if !load_args.opens_profile() {
+        return Ok(());
+    }
+
+    serve_profiles_main(
+        converted_paths,
+        std::slice::from_ref(&perf_dir.extra_binary_artifact_dir),
+        load_args.server_props()?,
+    );
+    Ok(())
+}
+
+/// Converts a perf.data stream from stdin, as it arrives.
+fn load_perf_stream(load_args: &LoadArgs) -> Result<(), CliError> {
+    let self_profiler = load_args.conversion_args.start_self_profiler()?;
+    let mut options = load_args.conversion_args.conversion_options()?;
+    options.phases = self_profiler.as_ref().map(|p| p.phases().clone());
+    let phases = options.phases.clone();
+    let reader = BufReader::new(std::io::stdin().lock());
+    let max_output_size = options.max_output_size;
+    let converted = import::perf::convert_pipe(reader, options)?;
+    let filter = load_args.conversion_args.profile_filter();
+    let files = write_converted_profile(
+        &converted,
+        max_output_size,
+        filter.as_ref(),
+        phases.as_ref(),
+    )?;
+    write_exports(load_args, &files.paths, &[], phases.as_ref())?;
+    if let Some(self_profiler) = self_profiler {
+        self_profiler.finish();
+    }
+    if load_args.opens_profile() {
+        serve_profiles_main(&files.paths, &[], load_args.server_props()?);
+    }
+    Ok(())
+}
+
+/// Converts the loaded perf.data file and the --from-perf-data files into one
+/// profile.
+fn load_perf_files(load_args: &LoadArgs) -> Result<(), CliError> {
+    let paths = std::iter::once(&load_args.file).chain(&load_args.from_perf_data);
+    let input_files = paths
+        .map(|path| {
+            File::open(path)
+                .map_err(|err| CliError::io(format!("Could not open file {path:?}"), &err))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let path = load_args.file.canonicalize().map_err(|err| {
+        CliError::io(format!("Could not resolve path {:?}", load_args.file), &err)
+    })?;
+
+    let self_profiler = load_args.conversion_args.start_self_profiler()?;
+    let mut options = load_args.conversion_args.conversion_options()?;
+    options.phases = self_profiler.as_ref().map(|p| p.phases().clone());
+    let phases = options.phases.clone();
+    let max_output_size = options.max_output_size;
+    let converted = import::perf::convert_multiple(&input_files, path.parent(), options)?;
+    let filter = load_args.conversion_args.profile_filter();
+    let files = write_converted_profile(
+        &converted,
+        max_output_size,
+        filter.as_ref(),
+        phases.as_ref(),
+    )?;
+    write_exports(load_args, &files.paths, &[], phases.as_ref())?;
+    if let Some(self_profiler) = self_profiler {
+        self_profiler.finish();
+    }
+    if load_args.opens_profile() {
+        serve_profiles_main(&files.paths, &[], load_args.server_props()?);
+    }
+    Ok(())
+}
+
+/// Writes the --aggregate-output, the --export-output and the
+/// --archive-sources archive for the loaded profiles, if requested.
+fn write_exports(
+    load_args: &LoadArgs,
+    profile_paths: &[PathBuf],
+    binaries_dirs: &[PathBuf],
+    phases: Option<&PhaseRecorder>,
+) -> Result<(), CliError> {
+    let verbose = load_args.server_args.verbose;
+    if let Some(options) = load_args.aggregate_args.aggregate_options() {
+        let _aggregate_phase = phases.map(|phases| phases.interval("Aggregate symbols"));
+        write_aggregate_main(profile_paths, binaries_dirs, &options, verbose)?;
+    }
+    if let Some(options) = load_args.export_args.trace_event_options() {
+        let _export_phase = phases.map(|phases| phases.interval("Export trace events"));
+        write_trace_event_main(profile_paths, binaries_dirs, &options, verbose)?;
+    }
+    if let Some(options) = load_args.source_archive_args.source_archive_options() {
+        let _archive_phase = phases.map(|phases| phases.interval("Archive sources"));
+        write_source_archive_main(profile_paths, binaries_dirs, &options, verbose)?;
+    }
+    Ok(())
+}
+
+/// Converts the file if it's a perf.data file or an intermediate file.
+/// Returns Ok(None) for other files, e.g. profiles which are already in the
+/// Firefox profiler format.
 fn attempt_conversion(
     filename: &Path,
     input_file: &File,
     settings: &ConversionArgs,
-) -> Option<NamedTempFile> {
+    self_profiler: Option<&SelfProfiler>,
+) -> Result<Option<ConvertedFiles>, CliError> {
     let path = Path::new(filename)
         .canonicalize()
-        .expect("Couldn't form absolute path");
+        .map_err(|err| CliError::io(format!("Could not resolve path {filename:?}"), &err))?;
+    let mut options = settings.conversion_options()?;
+    options.phases = self_profiler.map(|p| p.phases().clone());
+    let filter = settings.profile_filter();
+    if import::intermediate::is_intermediate_file(input_file) {
+        return Ok(Some(convert_intermediate_file(
+            input_file,
+            options,
+            filter.as_ref(),
+        )?));
+    }
+    match convert_perf_file(input_file, path.parent(), options, filter.as_ref()) {
+        Ok(files) => Ok(Some(files)),
+        Err(ConvertPerfFileError::NotAPerfFile) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+enum ConvertPerfFileError {
+    NotAPerfFile,
+    Other(CliError),
+}
+
+impl From<ConvertPerfFileError> for CliError {
+    fn from(err: ConvertPerfFileError) -> Self {
+        match err {
+            ConvertPerfFileError::NotAPerfFile => {
+                CliError::user_input("The file is not a perf.data file")
+            }
+            ConvertPerfFileError::Other(err) => err,
+        }
+    }
+}
+
+fn convert_perf_file(
+    input_file: &File,
+    extra_dir: Option<&Path>,
+    options: ConversionOptions,
+    filter: Option<&ProfileFilter>,
+) -> Result<ConvertedFiles, ConvertPerfFileError> {
+    let phases = options.phases.clone();
+    let max_output_size = options.max_output_size;
     let reader = BufReader::new(input_file);
-    let output_file = tempfile::NamedTempFile::new().ok()?;
-    let profile = import::perf::convert(
-        reader,
-        path.parent(),
-        settings.merge_threads,
-        settings.fold_recursive_prefix,
-    )
-    .ok()?;
-    let writer = BufWriter::new(output_file.as_file());
-    serde_json::to_writer(writer, &profile).ok()?;
-    Some(output_file)
+    let converted = match import::perf::convert(reader, extra_dir, Some(input_file), options) {
+        Ok(converted) => converted,
+        Err(import::perf::Error::LinuxPerf(linux_perf_data::Error::UnrecognizedMagicValue(_))) => {
+            return Err(ConvertPerfFileError::NotAPerfFile)
+        }
+        Err(err) => return Err(ConvertPerfFileError::Other(err.into())),
+    };
+    write_converted_profile(&converted, max_output_size, filter, phases.as_ref())
+}
+
+fn convert_intermediate_file(
+    input_file: &File,
+    options: ConversionOptions,
+    filter: Option<&ProfileFilter>,
+) -> Result<ConvertedFiles, ConvertPerfFileError> {
+    let phases = options.phases.clone();
+    let max_output_size = options.max_output_size;
+    let converted = import::intermediate::convert(BufReader::new(input_file), options)
+        .map_err(|err| ConvertPerfFileError::Other(err.into()))?;
+    write_converted_profile(&converted, max_output_size, filter, phases.as_ref())
+}
+
+/// The JSON files of a converted profile, in a temporary directory which is
+/// deleted when this is dropped.
+struct ConvertedFiles {
+    _dir: TempDir,
+    /// The profile, or its parts if it was split for --max-output-size.
+    paths: Vec<PathBuf>,
+}
+
+/// Writes the converted profile as JSON into a temporary directory, either as
+/// a whole or in parts, without the threads which `filter` drops.
+fn write_converted_profile(
+    converted: &ConvertedProfile,
+    max_output_size: Option<u64>,
+    filter: Option<&ProfileFilter>,
+    phases: Option<&PhaseRecorder>,
+) -> Result<ConvertedFiles, ConvertPerfFileError> {
+    let dir = tempfile::tempdir().map_err(|err| {
+        ConvertPerfFileError::Other(CliError::io("Could not create a temporary directory", &err))
+    })?;
+    let _write_phase = phases.map(|phases| phases.interval("Write JSON"));
+    if let Some(max_output_size) = max_output_size.filter(|_| !converted.parts.is_empty()) {
+        let (paths, filter_summary) = write_profile_parts(
+            &converted.profile,
+            &converted.parts,
+            max_output_size,
+            dir.path(),
+            filter,
+        )
+        .map_err(|err| {
+            ConvertPerfFileError::Other(CliError::io(
+                "Could not write the parts of the converted profile",
+                &err,
+            ))
+        })?;
+        println!(
+            "Wrote {} parts and their index to {:?}.",
+            paths.len(),
+            dir.path().join("index.json")
+        );
+        if filter.is_some() {
+            filter_summary.report();
+        }
+        return Ok(ConvertedFiles { _dir: dir, paths });
+    }
+    let path = dir.path().join("profile.json");
+    let output_file = File::create(&path).map_err(|err| {
+        ConvertPerfFileError::Other(CliError::io(format!("Could not create {path:?}"), &err))
+    })?;
+    let writer = BufWriter::new(output_file);
+    let result = match filter {
+        Some(filter) => serde_json::to_value(&converted.profile).and_then(|mut profile| {
+            filter.apply(&mut profile).report();
+            serde_json::to_writer(writer, &profile)
+        }),
+        None => serde_json::to_writer(writer, &converted.profile),
+    };
+    result.map_err(|err| {
+        let message = format!("Could not write the converted profile: {err}");
+        ConvertPerfFileError::Other(if err.is_io() {
+            CliError::environment(message)
+        } else {
+            CliError::internal(message)
+        })
+    })?;
+    Ok(ConvertedFiles {
+        _dir: dir,
+        paths: vec![path],
+    })
 }
 
 #[cfg(test)]
@@ -286,5 +1703,272 @@ mod test {
         // Make sure you can't pass both a pid and a command name at the same time.
         let opt_res = Opt::try_parse_from(["samply", "record", "-p", "1234", "rustup"]);
         assert!(opt_res.is_err());
+
+        // --keep only makes sense with --rotate.
+        let opt_res = Opt::try_parse_from(["samply", "record", "--keep", "3", "rustup"]);
+        assert!(opt_res.is_err());
+
+        let opt = Opt::parse_from(["samply", "record", "--log-file", "log.json", "rustup"]);
+        assert_eq!(opt.log_file.as_deref(), Some(Path::new("log.json")));
+        assert!(
+            matches!(opt.action, Action::Record(record_args) if record_args.command == ["rustup"])
+        );
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    #[test]
+    fn parses_rotation_intervals() {
+        use std::time::Duration;
+        assert_eq!(parse_rotation_interval("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_rotation_interval("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_rotation_interval("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_rotation_interval("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_rotation_interval("0.5s").is_err());
+        assert!(parse_rotation_interval("10d").is_err());
+    }
+
+    #[test]
+    fn verify_cli_serve() {
+        let opt = Opt::parse_from([
+            "samply",
+            "serve",
+            "a.json",
+            "b.json",
+            "--binaries",
+            "bin1",
+            "bin2",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Serve(serve_args) if serve_args.files == [PathBuf::from("a.json"), PathBuf::from("b.json")] && serve_args.binaries == [PathBuf::from("bin1"), PathBuf::from("bin2")])
+        );
+
+        let opt_res = Opt::try_parse_from(["samply", "serve"]);
+        assert!(opt_res.is_err());
+    }
+
+    #[test]
+    fn verify_cli_aggregate() {
+        let opt = Opt::parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--aggregate-output",
+            "symbols.csv",
+            "--csv",
+            "--aggregate-callers",
+            "3",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Load(load_args) if load_args.aggregate_args.aggregate_options().map(|o| (o.csv, o.caller_depth, o.inline_mode)) == Some((true, 3, InlineMode::Collapse)))
+        );
+        let opt = Opt::parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--aggregate-output",
+            "symbols.json",
+            "--inline",
+            "expand",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Load(load_args) if load_args.aggregate_args.aggregate_options().map(|o| o.inline_mode) == Some(InlineMode::Expand))
+        );
+        let opt_res = Opt::try_parse_from(["samply", "load", "perf.data", "--inline", "expand"]);
+        assert!(opt_res.is_err());
+
+        let opt_res = Opt::try_parse_from(["samply", "load", "perf.data", "--csv"]);
+        assert!(opt_res.is_err());
+        let opt_res = Opt::try_parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--aggregate-output",
+            "symbols.json",
+            "--aggregate-callers",
+            "4",
+        ]);
+        assert!(opt_res.is_err());
+    }
+
+    #[test]
+    fn verify_cli_trace_event() {
+        let opt = Opt::parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--output-format",
+            "trace-event",
+            "--export-output",
+            "trace.json",
+            "--trace-event-samples",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Load(load_args) if !load_args.opens_profile() && load_args.export_args.trace_event_options().map(|o| (o.output, o.samples)) == Some((PathBuf::from("trace.json"), true)))
+        );
+
+        let opt = Opt::parse_from(["samply", "load", "perf.data"]);
+        assert!(
+            matches!(opt.action, Action::Load(load_args) if load_args.opens_profile() && load_args.export_args.trace_event_options().is_none())
+        );
+
+        let opt_res = Opt::try_parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--output-format",
+            "trace-event",
+        ]);
+        assert!(opt_res.is_err());
+        let opt_res =
+            Opt::try_parse_from(["samply", "load", "perf.data", "--output-format", "pprof"]);
+        assert!(opt_res.is_err());
+    }
+
+    #[test]
+    fn verify_cli_merge() {
+        let opt = Opt::parse_from([
+            "samply",
+            "merge",
+            "a.json",
+            "b.json",
+            "--merge-layout",
+            "interleave-by-thread",
+            "-o",
+            "ab.json",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Merge(merge_args) if merge_args.files.len() == 2 && merge_args.merge_options().output == Path::new("ab.json") && merge_args.merge_layout == MergeLayout::InterleaveByThread)
+        );
+
+        let opt = Opt::parse_from(["samply", "merge", "a.json", "b.json"]);
+        assert!(
+            matches!(opt.action, Action::Merge(merge_args) if merge_args.output == Path::new("merged.json") && merge_args.merge_layout == MergeLayout::Concatenate)
+        );
+
+        let opt_res = Opt::try_parse_from(["samply", "merge", "a.json"]);
+        assert!(opt_res.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn verify_cli_top() {
+        let opt = Opt::parse_from(["samply", "top", "-n", "10", "-o", "top.json", "myapp", "-x"]);
+        assert!(
+            matches!(opt.action, Action::Top(top_args) if top_args.command == ["myapp", "-x"] && top_args.count == 10 && top_args.output.as_deref() == Some(Path::new("top.json")))
+        );
+
+        let opt = Opt::parse_from(["samply", "top", "--pid", "1234"]);
+        assert!(
+            matches!(opt.action, Action::Top(top_args) if top_args.pid == Some(1234) && top_args.output.is_none())
+        );
+
+        let opt_res = Opt::try_parse_from(["samply", "top", "-p", "1234", "myapp"]);
+        assert!(opt_res.is_err());
+    }
+
+    #[test]
+    fn verify_cli_conversion_log() {
+        let opt = Opt::parse_from(["samply", "explain-log", "conversion.log"]);
+        assert!(
+            matches!(opt.action, Action::ExplainLog(args) if args.log == Path::new("conversion.log"))
+        );
+
+        let opt = Opt::parse_from(["samply", "validate", "profile.json.gz"]);
+        assert!(
+            matches!(opt.action, Action::Validate(args) if args.file == Path::new("profile.json.gz"))
+        );
+
+        let opt = Opt::parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--record-conversion-log",
+            "conversion.log",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Load(load_args) if load_args.conversion_args.record_conversion_log == Some(PathBuf::from("conversion.log")))
+        );
+
+        // Each file of a directory would overwrite the log.
+        let opt_res = Opt::try_parse_from([
+            "samply",
+            "load",
+            "perf-dir",
+            "--all",
+            "--record-conversion-log",
+            "conversion.log",
+        ]);
+        assert!(opt_res.is_err());
+    }
+
+    #[test]
+    fn verify_cli_from_perf_data() {
+        let opt = Opt::parse_from([
+            "samply",
+            "load",
+            "a.data",
+            "--from-perf-data",
+            "b.data",
+            "--from-perf-data",
+            "c.data",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Load(load_args) if load_args.from_perf_data == [PathBuf::from("b.data"), PathBuf::from("c.data")])
+        );
+
+        let opt_res = Opt::try_parse_from([
+            "samply",
+            "load",
+            "a.data",
+            "--from-perf-data",
+            "b.data",
+            "--emit-intermediate",
+            "a.intermediate",
+        ]);
+        assert!(opt_res.is_err());
+    }
+    #[test]
+    fn verify_cli_archive_sources() {
+        let opt = Opt::parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--archive-sources",
+            "sources.tar.zst",
+            "--archive-sources-budget",
+            "1000",
+        ]);
+        let Action::Load(load_args) = opt.action else {
+            panic!("expected load");
+        };
+        let options = load_args
+            .source_archive_args
+            .source_archive_options()
+            .unwrap();
+        assert_eq!(options.output, Path::new("sources.tar.zst"));
+        assert_eq!(options.budget, 1000);
+        // The profile is served with the archive which was just written.
+        let props = load_args.server_props().unwrap();
+        assert_eq!(props.source_archives, [PathBuf::from("sources.tar.zst")]);
+
+        let opt = Opt::parse_from([
+            "samply",
+            "serve",
+            "profile.json",
+            "--source-archive",
+            "sources.tar.zst",
+        ]);
+        assert!(
+            matches!(opt.action, Action::Serve(serve_args) if serve_args.server_args.source_archive == [PathBuf::from("sources.tar.zst")])
+        );
+
+        let opt_res = Opt::try_parse_from([
+            "samply",
+            "load",
+            "perf.data",
+            "--archive-sources-budget",
+            "1",
+        ]);
+        assert!(opt_res.is_err());
     }
 }
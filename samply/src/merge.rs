@@ -0,0 +1,628 @@
+use serde_json::{json, Value};
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::cli_error::CliError;
+use crate::profile_json::read_profile_value;
+
+/// How `samply merge` arranges the threads of the merged profiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeLayout {
+    /// The processes of all profiles, one after the other, at their real
+    /// times.
+    #[default]
+    Concatenate,
+    /// Each thread of the first profile directly above its counterparts in
+    /// the other profiles, matched by process name and normalized thread
+    /// name. The profiles are aligned at their start times.
+    InterleaveByThread,
+}
+
+/// Parses a `--merge-layout` argument.
+pub fn parse_merge_layout(s: &str) -> Result<MergeLayout, String> {
+    match s {
+        "concatenate" => Ok(MergeLayout::Concatenate),
+        "interleave-by-thread" => Ok(MergeLayout::InterleaveByThread),
+        _ => Err(format!(
+            "expected concatenate or interleave-by-thread, got {s:?}"
+        )),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergeOptions {
+    pub output: PathBuf,
+    pub layout: MergeLayout,
+}
+
+/// Merges the processed profiles into one and writes it to `options.output`.
+pub fn merge_main(profile_paths: &[PathBuf], options: &MergeOptions) -> Result<(), CliError> {
+    let profiles = profile_paths
+        .iter()
+        .map(|path| read_profile_value(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let merged = merge_profiles(profiles, options.layout).map_err(CliError::user_input)?;
+
+    let file = File::create(&options.output)
+        .map_err(|err| CliError::io(format!("Could not create {:?}", options.output), &err))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &merged)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writer.flush())
+        .map_err(|err| CliError::io(format!("Could not write {:?}", options.output), &err))?;
+    eprintln!(
+        "Wrote the merged profile to {:?}. Open it with samply load.",
+        options.output
+    );
+    Ok(())
+}
+
+/// A thread of one of the merged profiles, with its libraries, categories
+/// and times already rewritten for the merged profile.
+struct InputThread {
+    input: usize,
+    /// The index in the input profile's threads.
+    index: usize,
+    thread: Value,
+}
+
+/// Merges processed profiles. The libraries, categories and marker schemas
+/// are combined, and the threads and counters are rewritten to refer to the
+/// combined lists.
+pub fn merge_profiles(profiles: Vec<Value>, layout: MergeLayout) -> Result<Value, String> {
+    if profiles.is_empty() {
+        return Err("No profiles to merge.".into());
+    }
+    let start_times = profiles
+        .iter()
+        .map(|profile| profile["meta"]["startTime"].as_f64())
+        .collect::<Option<Vec<f64>>>()
+        .ok_or("A profile doesn't have a start time.")?;
+    let merged_start_time = match layout {
+        MergeLayout::Concatenate => start_times.iter().copied().fold(f64::INFINITY, f64::min),
+        MergeLayout::InterleaveByThread => start_times[0],
+    };
+
+    // The first profile without its arrays, which are replaced below.
+    let mut merged = Value::Null;
+    let mut libs = Vec::new();
+    let mut categories = CategoryList::default();
+    let mut marker_schemas: Vec<Value> = Vec::new();
+    let mut threads = Vec::new();
+    let mut counters = Vec::new();
+    for (input, mut profile) in profiles.into_iter().enumerate() {
+        let time_offset = match layout {
+            MergeLayout::Concatenate => start_times[input] - merged_start_time,
+            MergeLayout::InterleaveByThread => 0.0,
+        };
+        let lib_offset = libs.len() as u64;
+        libs.extend(take_array(&mut profile, "/libs"));
+        let category_map = categories.add_profile_categories(&profile["meta"]["categories"]);
+        for schema in take_array(&mut profile, "/meta/markerSchema") {
+            if !marker_schemas.iter().any(|s| s["name"] == schema["name"]) {
+                marker_schemas.push(schema);
+            }
+        }
+        for (index, mut thread) in take_array(&mut profile, "/threads").into_iter().enumerate() {
+            rewrite_thread(&mut thread, lib_offset, &category_map, time_offset);
+            threads.push(InputThread {
+                input,
+                index,
+                thread,
+            });
+        }
+        for mut counter in take_array(&mut profile, "/counters") {
+            for group in column_mut(&mut counter, "/sampleGroups") {
+                shift_times(group, "/samples/time", time_offset);
+            }
+            counters.push((input, counter));
+        }
+        if input == 0 {
+            merged = profile;
+        }
+    }
+
+    let (threads, keep_thread_order) = match layout {
+        MergeLayout::Concatenate => (concatenate(threads), false),
+        MergeLayout::InterleaveByThread => (interleave_by_thread(threads), true),
+    };
+
+    // Point the counters at the new index and pid of their main thread.
+    let new_thread_indexes: HashMap<(usize, u64), usize> = threads
+        .iter()
+        .enumerate()
+        .map(|(new_index, t)| ((t.input, t.index as u64), new_index))
+        .collect();
+    let counters: Vec<Value> = counters
+        .into_iter()
+        .filter_map(|(input, mut counter)| {
+            let old_index = counter["mainThreadIndex"].as_u64()?;
+            let new_index = *new_thread_indexes.get(&(input, old_index))?;
+            counter["mainThreadIndex"] = new_index.into();
+            counter["pid"] = threads[new_index].thread["pid"].clone();
+            if layout == MergeLayout::InterleaveByThread {
+                let name = counter["name"].as_str().unwrap_or_default();
+                counter["name"] = format!("{}: {name}", input_label(input)).into();
+            }
+            Some(counter)
+        })
+        .collect();
+
+    merged["meta"]["startTime"] = merged_start_time.into();
+    merged["meta"]["categories"] = categories.into_value();
+    merged["meta"]["markerSchema"] = marker_schemas.into();
+    if keep_thread_order {
+        merged["meta"]["keepProfileThreadOrder"] = true.into();
+    }
+    merged["libs"] = libs.into();
+    merged["threads"] = threads.into_iter().map(|t| t.thread).collect();
+    merged["counters"] = counters.into();
+    Ok(merged)
+}
+
+/// Keeps the threads in input order. Processes of later profiles whose pid
+/// was already used by an earlier profile get a `.<profile number>` suffix,
+/// so that they stay separate processes.
+fn concatenate(mut threads: Vec<InputThread>) -> Vec<InputThread> {
+    let mut pids_of_earlier_inputs = HashSet::new();
+    let mut pids_of_current_input = HashSet::new();
+    let mut current_input = 0;
+    for t in &mut threads {
+        if t.input != current_input {
+            pids_of_earlier_inputs.extend(pids_of_current_input.drain());
+            current_input = t.input;
+        }
+        let pid = pid(&t.thread);
+        let new_pid = match pids_of_earlier_inputs.contains(&pid) {
+            true => format!("{pid}.{}", t.input + 1),
+            false => pid.clone(),
+        };
+        pids_of_current_input.insert(pid);
+        t.thread["pid"] = new_pid.into();
+    }
+    threads
+}
+
+/// Orders the threads by process name, and within a process puts the
+/// threads of the different profiles which have the same normalized name
+/// next to each other, prefixed with "A: ", "B: " and so on. If a profile
+/// has several threads with the same normalized name, they're paired by
+/// their rank in sample count. Threads without a counterpart come last in
+/// their process.
+fn interleave_by_thread(threads: Vec<InputThread>) -> Vec<InputThread> {
+    // The processes, by name, in the order in which they first appear.
+    let mut processes: Vec<(String, Vec<InputThread>)> = Vec::new();
+    for t in threads {
+        let process_name = t.thread["processName"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match processes.iter_mut().find(|(name, _)| *name == process_name) {
+            Some((_, process_threads)) => process_threads.push(t),
+            None => processes.push((process_name, vec![t])),
+        }
+    }
+
+    let mut used_pids = HashSet::new();
+    let mut ordered = Vec::new();
+    for (_, process_threads) in processes {
+        // Use the pid of the first profile's process, unless a process with
+        // a different name already has it.
+        let mut process_pid = pid(&process_threads[0].thread);
+        let mut suffix = 1;
+        while used_pids.contains(&process_pid) {
+            suffix += 1;
+            process_pid = format!("{}.{suffix}", pid(&process_threads[0].thread));
+        }
+        used_pids.insert(process_pid.clone());
+
+        let first_ordered = ordered.len();
+        for mut t in interleave_process(process_threads) {
+            let name = t.thread["name"].as_str().unwrap_or_default().to_string();
+            t.thread["name"] = format!("{}: {name}", input_label(t.input)).into();
+            t.thread["pid"] = process_pid.clone().into();
+            ordered.push(t);
+        }
+        // The process track shows the first main thread. The other main
+        // threads become local tracks below it.
+        let mut have_main_thread = false;
+        for t in &mut ordered[first_ordered..] {
+            if t.thread["isMainThread"] == true {
+                if have_main_thread {
+                    t.thread["isMainThread"] = false.into();
+                }
+                have_main_thread = true;
+            }
+        }
+    }
+    ordered
+}
+
+/// Orders the threads of one process: first the matched groups, by the
+/// order of their names in the earliest profile, starting with the main
+/// threads, and then the unmatched threads.
+fn interleave_process(threads: Vec<InputThread>) -> Vec<InputThread> {
+    let mut keys: Vec<(bool, String)> = Vec::new();
+    // The threads of each (key, input), by descending sample count.
+    let mut buckets: HashMap<(String, usize), Vec<InputThread>> = HashMap::new();
+    for t in threads {
+        let is_main = t.thread["isMainThread"] == true;
+        let key = normalize_thread_name(t.thread["name"].as_str().unwrap_or_default());
+        if !keys.iter().any(|(_, k)| *k == key) {
+            keys.push((is_main, key.clone()));
+        }
+        buckets.entry((key, t.input)).or_default().push(t);
+    }
+    // Stable, so the original order is kept for equal sample counts.
+    for bucket in buckets.values_mut() {
+        bucket.sort_by(|a, b| {
+            let (a, b) = (sample_weight(&a.thread), sample_weight(&b.thread));
+            b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal)
+        });
+    }
+    // Main threads first; the sort is stable too.
+    keys.sort_by_key(|(is_main, _)| !is_main);
+    let mut inputs: Vec<usize> = buckets.keys().map(|(_, input)| *input).collect();
+    inputs.sort_unstable();
+    inputs.dedup();
+
+    let mut matched = Vec::new();
+    let mut unmatched = Vec::new();
+    for (_, key) in keys {
+        let mut rank_buckets: Vec<std::vec::IntoIter<InputThread>> = inputs
+            .iter()
+            .filter_map(|&input| buckets.remove(&(key.clone(), input)))
+            .map(Vec::into_iter)
+            .collect();
+        loop {
+            let group: Vec<InputThread> =
+                rank_buckets.iter_mut().filter_map(Iterator::next).collect();
+            match group.len() {
+                0 => break,
+                1 => unmatched.extend(group),
+                _ => matched.extend(group),
+            }
+        }
+    }
+    unmatched.sort_by_key(|t| (t.input, t.index));
+    matched.extend(unmatched);
+    matched
+}
+
+/// Removes the parts of a thread name which usually differ between runs,
+/// e.g. "worker-12" and "Thread <4321>" become "worker" and "Thread".
+fn normalize_thread_name(name: &str) -> String {
+    let normalized = name
+        .trim()
+        .trim_end_matches(|c: char| c.is_ascii_digit() || "<>-_#:. ".contains(c));
+    match normalized.is_empty() {
+        true => name.trim().to_string(),
+        false => normalized.to_string(),
+    }
+}
+
+/// The label of a profile in the interleaved layout: A for the first, B for
+/// the second, and so on.
+fn input_label(input: usize) -> String {
+    match u8::try_from(input).ok().filter(|&i| i < 26) {
+        Some(i) => char::from(b'A' + i).to_string(),
+        None => format!("#{}", input + 1),
+    }
+}
+
+fn pid(thread: &Value) -> String {
+    match &thread["pid"] {
+        Value::String(pid) => pid.clone(),
+        pid => pid.to_string(),
+    }
+}
+
+/// The sum of the sample weights of a thread, or the sample count if the
+/// samples have no weights.
+pub fn sample_weight(thread: &Value) -> f64 {
+    let samples = &thread["samples"];
+    match samples["weight"].as_array() {
+        Some(weights) => weights.iter().filter_map(Value::as_f64).sum(),
+        None => samples["stack"].as_array().map_or(0, Vec::len) as f64,
+    }
+}
+
+/// Rewrites the library indexes, category indexes and times of a thread.
+fn rewrite_thread(
+    thread: &mut Value,
+    lib_offset: u64,
+    category_map: &CategoryMap,
+    time_offset: f64,
+) {
+    for column in ["/resourceTable/lib", "/nativeSymbols/libIndex"] {
+        for lib in column_mut(thread, column) {
+            if let Some(index) = lib.as_u64() {
+                *lib = (index + lib_offset).into();
+            }
+        }
+    }
+
+    for table in ["/frameTable", "/stackTable"] {
+        let categories: Vec<Option<u64>> = column_mut(thread, &format!("{table}/category"))
+            .map(|c| c.as_u64())
+            .collect();
+        let subcategories = column_mut(thread, &format!("{table}/subcategory"));
+        for (subcategory, category) in subcategories.zip(&categories) {
+            if let (Some(category), Some(index)) = (category, subcategory.as_u64()) {
+                *subcategory = category_map.subcategory(*category, index).into();
+            }
+        }
+        for category in column_mut(thread, &format!("{table}/category")) {
+            if let Some(index) = category.as_u64() {
+                *category = category_map.category(index).into();
+            }
+        }
+    }
+    for category in column_mut(thread, "/markers/category") {
+        if let Some(index) = category.as_u64() {
+            *category = category_map.category(index).into();
+        }
+    }
+
+    for column in ["/samples/time", "/markers/startTime", "/markers/endTime"] {
+        shift_times(thread, column, time_offset);
+    }
+    for property in [
+        "/registerTime",
+        "/unregisterTime",
+        "/processStartupTime",
+        "/processShutdownTime",
+    ] {
+        if let Some(time) = thread.pointer_mut(property) {
+            shift_time(time, time_offset);
+        }
+    }
+}
+
+/// The categories of the merged profile. Categories and subcategories with
+/// the same name are shared by the profiles.
+#[derive(Default)]
+struct CategoryList {
+    categories: Vec<Value>,
+}
+
+/// Maps the category and subcategory indexes of a profile to the merged
+/// [`CategoryList`].
+struct CategoryMap {
+    categories: Vec<u64>,
+    subcategories: Vec<Vec<u64>>,
+}
+
+impl CategoryList {
+    fn add_profile_categories(&mut self, categories: &Value) -> CategoryMap {
+        let mut map = CategoryMap {
+            categories: Vec::new(),
+            subcategories: Vec::new(),
+        };
+        for category in categories.as_array().into_iter().flatten() {
+            let index = match self
+                .categories
+                .iter()
+                .position(|c| c["name"] == category["name"])
+            {
+                Some(index) => index,
+                None => {
+                    let mut new_category = category.clone();
+                    new_category["subcategories"] = json!([]);
+                    self.categories.push(new_category);
+                    self.categories.len() - 1
+                }
+            };
+            let merged_subcategories = self.categories[index]["subcategories"]
+                .as_array_mut()
+                .unwrap();
+            let mut subcategory_map = Vec::new();
+            for subcategory in category["subcategories"].as_array().into_iter().flatten() {
+                let subcategory_index =
+                    match merged_subcategories.iter().position(|s| s == subcategory) {
+                        Some(subcategory_index) => subcategory_index,
+                        None => {
+                            merged_subcategories.push(subcategory.clone());
+                            merged_subcategories.len() - 1
+                        }
+                    };
+                subcategory_map.push(subcategory_index as u64);
+            }
+            map.categories.push(index as u64);
+            map.subcategories.push(subcategory_map);
+        }
+        map
+    }
+
+    fn into_value(self) -> Value {
+        self.categories.into()
+    }
+}
+
+impl CategoryMap {
+    fn category(&self, index: u64) -> u64 {
+        self.categories
+            .get(index as usize)
+            .copied()
+            .unwrap_or(index)
+    }
+
+    fn subcategory(&self, category: u64, index: u64) -> u64 {
+        self.subcategories
+            .get(category as usize)
+            .and_then(|subcategories| subcategories.get(index as usize))
+            .copied()
+            .unwrap_or(index)
+    }
+}
+
+/// Takes the array at the JSON pointer, e.g. "/meta/markerSchema", out of
+/// `value`. Missing properties are empty arrays.
+fn take_array(value: &mut Value, pointer: &str) -> Vec<Value> {
+    match value.pointer_mut(pointer).map(std::mem::take) {
+        Some(Value::Array(array)) => array,
+        _ => Vec::new(),
+    }
+}
+
+/// The elements of the array at the JSON pointer, e.g. "/samples/time".
+/// Missing properties are empty, and aren't added to `value`.
+fn column_mut<'a>(value: &'a mut Value, pointer: &str) -> impl Iterator<Item = &'a mut Value> {
+    value
+        .pointer_mut(pointer)
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+}
+
+fn shift_times(value: &mut Value, pointer: &str, offset: f64) {
+    for time in column_mut(value, pointer) {
+        shift_time(time, offset);
+    }
+}
+
+fn shift_time(time: &mut Value, offset: f64) {
+    if let Some(t) = time.as_f64() {
+        *time = (t + offset).into();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A profile with one process "app" and the given threads, as (name,
+    /// is main thread, sample count).
+    fn profile(start_time: f64, pid: &str, threads: &[(&str, bool, usize)]) -> Value {
+        let threads: Vec<Value> = threads
+            .iter()
+            .map(|&(name, is_main, sample_count)| {
+                json!({
+                    "name": name,
+                    "isMainThread": is_main,
+                    "pid": pid,
+                    "processName": "app",
+                    "registerTime": 1.0,
+                    "samples": { "stack": vec![0; sample_count], "time": vec![2.0; sample_count] },
+                    "frameTable": { "category": [1], "subcategory": [1] },
+                    "stackTable": { "category": [1], "subcategory": [1] },
+                    "markers": { "category": [], "startTime": [], "endTime": [] },
+                    "resourceTable": { "lib": [0] },
+                })
+            })
+            .collect();
+        json!({
+            "meta": {
+                "startTime": start_time,
+                "categories": [
+                    { "name": "Other", "subcategories": ["Other"] },
+                    { "name": "User", "subcategories": ["Other", "Hot"] },
+                ],
+                "markerSchema": [],
+            },
+            "libs": [{ "name": format!("lib{pid}") }],
+            "threads": threads,
+            "counters": [{
+                "name": "Memory",
+                "pid": pid,
+                "mainThreadIndex": 0,
+                "sampleGroups": [{ "samples": { "time": [3.0], "count": [1] } }],
+            }],
+        })
+    }
+
+    fn names(merged: &Value) -> Vec<&str> {
+        merged["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn interleaves_matching_threads() {
+        let a = profile(
+            1000.0,
+            "10",
+            &[("app", true, 5), ("render", false, 5), ("io-1", false, 1)],
+        );
+        let b = profile(
+            5000.0,
+            "20",
+            &[
+                ("gc", false, 2),
+                ("io-7", false, 3),
+                ("app", true, 4),
+                ("render", false, 1),
+            ],
+        );
+        let merged = merge_profiles(vec![a, b], MergeLayout::InterleaveByThread).unwrap();
+        assert_eq!(
+            names(&merged),
+            vec![
+                "A: app",
+                "B: app",
+                "A: render",
+                "B: render",
+                "A: io-1",
+                "B: io-7",
+                "B: gc"
+            ]
+        );
+        assert_eq!(merged["meta"]["keepProfileThreadOrder"], true);
+        let threads = merged["threads"].as_array().unwrap();
+        assert!(threads.iter().all(|t| t["pid"] == "10"));
+        let main_threads: Vec<bool> = threads.iter().map(|t| t["isMainThread"] == true).collect();
+        assert_eq!(
+            main_threads,
+            vec![true, false, false, false, false, false, false]
+        );
+        // The runs are aligned at their start, and B's libraries come after A's.
+        assert_eq!(threads[1]["registerTime"], 1.0);
+        assert_eq!(threads[1]["resourceTable"]["lib"], json!([1]));
+        // B's counter belongs to B's first thread, "gc".
+        assert_eq!(merged["counters"][1]["mainThreadIndex"], 6);
+        assert_eq!(merged["counters"][1]["name"], "B: Memory");
+    }
+
+    #[test]
+    fn pairs_same_named_threads_by_sample_count() {
+        let a = profile(0.0, "1", &[("worker-1", false, 1), ("worker-2", false, 9)]);
+        let b = profile(0.0, "2", &[("worker-3", false, 8), ("worker-4", false, 2)]);
+        let merged = merge_profiles(vec![a, b], MergeLayout::InterleaveByThread).unwrap();
+        assert_eq!(
+            names(&merged),
+            vec!["A: worker-2", "B: worker-3", "A: worker-1", "B: worker-4"]
+        );
+    }
+
+    #[test]
+    fn concatenates_with_shared_categories_and_separate_pids() {
+        let a = profile(1000.0, "10", &[("app", true, 1)]);
+        let mut b = profile(1500.0, "10", &[("app", true, 1)]);
+        // B has an extra category before the shared ones.
+        b["meta"]["categories"]
+            .as_array_mut()
+            .unwrap()
+            .insert(0, json!({ "name": "Kernel", "subcategories": ["Other"] }));
+        b["threads"][0]["frameTable"] = json!({ "category": [2], "subcategory": [1] });
+        let merged = merge_profiles(vec![a, b], MergeLayout::Concatenate).unwrap();
+        assert_eq!(names(&merged), vec!["app", "app"]);
+        assert_eq!(merged["meta"]["startTime"], 1000.0);
+        assert_eq!(merged["meta"]["categories"].as_array().unwrap().len(), 3);
+        let b_thread = &merged["threads"][1];
+        assert_eq!(b_thread["pid"], "10.2");
+        assert_eq!(b_thread["registerTime"], 501.0);
+        assert_eq!(b_thread["frameTable"]["category"], json!([1]));
+        assert_eq!(b_thread["frameTable"]["subcategory"], json!([1]));
+        assert_eq!(merged["counters"][1]["pid"], "10.2");
+        assert_eq!(
+            merged["counters"][1]["sampleGroups"][0]["samples"]["time"],
+            json!([503.0])
+        );
+    }
+}
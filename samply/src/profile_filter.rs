@@ -0,0 +1,244 @@
+use regex::Regex;
+use serde_json::Value;
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::merge::sample_weight;
+
+/// Which processes and threads are kept in the written profile, for
+/// `--filter-process`, `--exclude-process`, `--filter-thread` and
+/// `--exclude-thread`. The process filters are applied first, and the thread
+/// filters only choose among the threads of the kept processes. The
+/// libraries stay as they are, so that the library indexes of the kept
+/// threads stay valid.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileFilter {
+    /// Only processes whose name matches are kept.
+    pub processes: Option<Regex>,
+    /// Processes whose name matches are dropped.
+    pub exclude_processes: Option<Regex>,
+    /// Only threads whose name matches are kept.
+    pub threads: Option<Regex>,
+    /// Threads whose name matches are dropped.
+    pub exclude_threads: Option<Regex>,
+    /// Whether the main thread of a kept process is kept even if the thread
+    /// filters would drop it.
+    pub keep_main_threads: bool,
+}
+
+/// How much [`ProfileFilter::apply`] dropped, summed over the parts of a
+/// profile.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterSummary {
+    pub thread_count: usize,
+    pub dropped_thread_count: usize,
+    pub dropped_process_count: usize,
+    pub sample_weight: f64,
+    pub dropped_sample_weight: f64,
+}
+
+impl ProfileFilter {
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_none()
+            && self.exclude_processes.is_none()
+            && self.threads.is_none()
+            && self.exclude_threads.is_none()
+    }
+
+    fn keeps_process(&self, name: &str) -> bool {
+        self.processes.as_ref().map_or(true, |re| re.is_match(name))
+            && !self
+                .exclude_processes
+                .as_ref()
+                .map_or(false, |re| re.is_match(name))
+    }
+
+    fn keeps_thread(&self, name: &str, is_main_thread: bool) -> bool {
+        (self.keep_main_threads && is_main_thread)
+            || (self.threads.as_ref().map_or(true, |re| re.is_match(name))
+                && !self
+                    .exclude_threads
+                    .as_ref()
+                    .map_or(false, |re| re.is_match(name)))
+    }
+
+    /// Removes the threads which the filters drop from the processed
+    /// profile, with their samples and markers, and the counters of the
+    /// removed processes. The counters and thread selections which refer to
+    /// kept threads are pointed at their new indexes.
+    pub fn apply(&self, profile: &mut Value) -> FilterSummary {
+        let threads = match profile.get_mut("threads").map(Value::take) {
+            Some(Value::Array(threads)) => threads,
+            _ => Vec::new(),
+        };
+        let mut summary = FilterSummary {
+            thread_count: threads.len(),
+            ..Default::default()
+        };
+        let mut dropped_processes = BTreeSet::new();
+        let mut new_thread_indexes = HashMap::new();
+        let mut kept_threads = Vec::new();
+        for (index, thread) in threads.into_iter().enumerate() {
+            let weight = sample_weight(&thread);
+            summary.sample_weight += weight;
+            let process_name = thread["processName"].as_str().unwrap_or_default();
+            let name = thread["name"].as_str().unwrap_or_default();
+            let is_main_thread = thread["isMainThread"] == true;
+            if !self.keeps_process(process_name) {
+                dropped_processes.insert(thread["pid"].to_string());
+            } else if self.keeps_thread(name, is_main_thread) {
+                new_thread_indexes.insert(index as u64, kept_threads.len());
+                kept_threads.push(thread);
+                continue;
+            }
+            summary.dropped_thread_count += 1;
+            summary.dropped_sample_weight += weight;
+        }
+        summary.dropped_process_count = dropped_processes.len();
+        profile["threads"] = kept_threads.into();
+
+        if let Some(counters) = profile.get_mut("counters").and_then(Value::as_array_mut) {
+            counters.retain_mut(|counter| {
+                let old_index = counter["mainThreadIndex"].as_u64();
+                match old_index.and_then(|index| new_thread_indexes.get(&index)) {
+                    Some(&new_index) => {
+                        counter["mainThreadIndex"] = new_index.into();
+                        true
+                    }
+                    None => false,
+                }
+            });
+        }
+        for selection in ["initialVisibleThreads", "initialSelectedThreads"] {
+            if let Some(indexes) = profile["meta"]
+                .get_mut(selection)
+                .and_then(Value::as_array_mut)
+            {
+                *indexes = indexes
+                    .iter()
+                    .filter_map(|index| new_thread_indexes.get(&index.as_u64()?))
+                    .map(|&index| index.into())
+                    .collect();
+            }
+        }
+        summary
+    }
+}
+
+impl FilterSummary {
+    pub fn add(&mut self, other: &FilterSummary) {
+        self.thread_count += other.thread_count;
+        self.dropped_thread_count += other.dropped_thread_count;
+        self.dropped_process_count += other.dropped_process_count;
+        self.sample_weight += other.sample_weight;
+        self.dropped_sample_weight += other.dropped_sample_weight;
+    }
+
+    pub fn report(&self) {
+        let share = match self.sample_weight {
+            weight if weight > 0.0 => self.dropped_sample_weight / weight * 100.0,
+            _ => 0.0,
+        };
+        eprintln!(
+            "The filters dropped {} of {} threads, including all threads of {} processes, with \
+             {share:.1}% of the sample weight ({} of {}).",
+            self.dropped_thread_count,
+            self.thread_count,
+            self.dropped_process_count,
+            self.dropped_sample_weight,
+            self.sample_weight
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A profile with the given threads, as (process name, pid, thread
+    /// name, is main thread, sample count), and a counter for each process.
+    fn profile(threads: &[(&str, &str, &str, bool, usize)]) -> Value {
+        let threads: Vec<Value> = threads
+            .iter()
+            .map(|&(process_name, pid, name, is_main, sample_count)| {
+                json!({
+                    "name": name,
+                    "isMainThread": is_main,
+                    "pid": pid,
+                    "processName": process_name,
+                    "samples": { "stack": vec![0; sample_count] },
+                })
+            })
+            .collect();
+        json!({
+            "meta": { "initialVisibleThreads": [0, 2, 3] },
+            "libs": [{ "name": "libc.so.6" }],
+            "threads": threads,
+            "counters": [
+                { "name": "Memory", "pid": "1", "mainThreadIndex": 0 },
+                { "name": "Memory", "pid": "2", "mainThreadIndex": 2 },
+            ],
+        })
+    }
+
+    fn thread_names(profile: &Value) -> Vec<&str> {
+        profile["threads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|thread| thread["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn thread_filters_apply_within_the_kept_processes() {
+        let mut profile = profile(&[
+            ("app", "1", "app", true, 10),
+            ("app", "1", "worker-1", false, 20),
+            ("daemon", "2", "daemon", true, 30),
+            ("daemon", "2", "worker-2", false, 40),
+        ]);
+        let filter = ProfileFilter {
+            exclude_processes: Some(Regex::new("^daemon$").unwrap()),
+            threads: Some(Regex::new("worker").unwrap()),
+            keep_main_threads: true,
+            ..Default::default()
+        };
+        let summary = filter.apply(&mut profile);
+
+        assert_eq!(thread_names(&profile), vec!["app", "worker-1"]);
+        assert_eq!(profile["libs"].as_array().unwrap().len(), 1);
+        assert_eq!(profile["counters"].as_array().unwrap().len(), 1);
+        assert_eq!(profile["meta"]["initialVisibleThreads"], json!([0]));
+        assert_eq!(
+            summary,
+            FilterSummary {
+                thread_count: 4,
+                dropped_thread_count: 2,
+                dropped_process_count: 1,
+                sample_weight: 100.0,
+                dropped_sample_weight: 70.0,
+            }
+        );
+    }
+
+    #[test]
+    fn main_threads_are_only_kept_on_request() {
+        let mut profile = profile(&[
+            ("app", "1", "app", true, 10),
+            ("app", "1", "worker-1", false, 20),
+            ("daemon", "2", "daemon", true, 30),
+        ]);
+        let filter = ProfileFilter {
+            threads: Some(Regex::new("worker").unwrap()),
+            ..Default::default()
+        };
+        filter.apply(&mut profile);
+
+        assert_eq!(thread_names(&profile), vec!["worker-1"]);
+        // The counters belonged to the dropped main threads.
+        assert_eq!(profile["counters"], json!([]));
+    }
+}
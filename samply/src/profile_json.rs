@@ -0,0 +1,540 @@
+use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use wholesym::{FramesLookupResult, SymbolManager};
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::cli_error::CliError;
+use crate::server::{libinfo_map_entry_for_lib, symbol_manager_config, ProfileJsonLib};
+
+/// Symbol names of library frames, by (lib index, relative address).
+pub type SymbolNames = HashMap<(usize, u32), String>;
+
+/// The functions which were inlined at the address of a library frame, by
+/// (lib index, relative address). Innermost first; the outer function is the
+/// frame's symbol name and isn't included.
+pub type InlineFrames = HashMap<(usize, u32), Vec<String>>;
+
+/// The processed profile format version which samply writes, i.e.
+/// `meta.preprocessedProfileVersion`.
+pub const CURRENT_PROFILE_VERSION: u64 = 46;
+
+/// The oldest format version which can be read back. Older samply releases
+/// wrote version 41, which has numeric pids and tids and 0 instead of null
+/// for the end times of threads and processes which hadn't ended.
+pub const OLDEST_PROFILE_VERSION: u64 = 41;
+
+/// Reads a processed profile, which can be gzipped, for the exporters.
+pub fn read_profile(path: &Path) -> Result<ProfileJson, CliError> {
+    read_profile_as(path)
+}
+
+/// Reads a processed profile with all of its properties, e.g. for merging.
+pub fn read_profile_value(path: &Path) -> Result<Value, CliError> {
+    read_profile_as(path)
+}
+
+/// Profiles of older format versions are upgraded to the current version
+/// before they're deserialized, see [`upgrade_profile`].
+fn read_profile_as<T: DeserializeOwned>(path: &Path) -> Result<T, CliError> {
+    let file = File::open(path)
+        .map_err(|err| CliError::io(format!("Could not open file {path:?}"), &err))?;
+    let reader = BufReader::new(file);
+    let profile: Result<Value, _> = if path.extension() == Some(OsStr::new("gz")) {
+        serde_json::from_reader(BufReader::new(GzDecoder::new(reader)))
+    } else {
+        serde_json::from_reader(reader)
+    };
+    let mut profile = profile.map_err(|err| {
+        CliError::user_input(format!("Could not parse {path:?} as a profile: {err}"))
+    })?;
+    upgrade_profile(&mut profile)
+        .map_err(|err| CliError::user_input(format!("Could not read {path:?}: {err}")))?;
+    serde_json::from_value(profile).map_err(|err| {
+        CliError::user_input(format!("Could not parse {path:?} as a profile: {err}"))
+    })
+}
+
+/// Checks that a profile has a format version which samply can read, and
+/// rewrites the parts which differ in older versions to the current format.
+pub fn upgrade_profile(profile: &mut Value) -> Result<(), String> {
+    let Some(version) = profile
+        .pointer("/meta/preprocessedProfileVersion")
+        .and_then(Value::as_u64)
+    else {
+        return Err(
+            "it has no meta.preprocessedProfileVersion, so it isn't a processed \
+                    profile which samply wrote"
+                .to_string(),
+        );
+    };
+    if version > CURRENT_PROFILE_VERSION {
+        return Err(format!(
+            "it has format version {version}, but this version of samply only reads versions \
+             {OLDEST_PROFILE_VERSION} to {CURRENT_PROFILE_VERSION}; it was probably written by \
+             a newer version of samply"
+        ));
+    }
+    if version < OLDEST_PROFILE_VERSION {
+        return Err(format!(
+            "it has format version {version}, but samply only reads versions \
+             {OLDEST_PROFILE_VERSION} to {CURRENT_PROFILE_VERSION}; it was written by a \
+             version of samply which is too old"
+        ));
+    }
+    if version == CURRENT_PROFILE_VERSION {
+        return Ok(());
+    }
+
+    if let Some(threads) = profile["threads"].as_array_mut() {
+        for thread in threads {
+            for id in ["/pid", "/tid"] {
+                if let Some(id) = thread.pointer_mut(id) {
+                    number_to_string(id);
+                }
+            }
+            for end_time in ["/unregisterTime", "/processShutdownTime"] {
+                if let Some(end_time) = thread.pointer_mut(end_time) {
+                    if end_time.as_f64() == Some(0.0) {
+                        *end_time = Value::Null;
+                    }
+                }
+            }
+        }
+    }
+    if let Some(counters) = profile["counters"].as_array_mut() {
+        for counter in counters {
+            if let Some(pid) = counter.pointer_mut("/pid") {
+                number_to_string(pid);
+            }
+        }
+    }
+    profile["meta"]["preprocessedProfileVersion"] = CURRENT_PROFILE_VERSION.into();
+    Ok(())
+}
+
+fn number_to_string(value: &mut Value) {
+    if let Value::Number(number) = value {
+        *value = number.to_string().into();
+    }
+}
+
+/// Looks up the symbols for the library frames which only have an address
+/// as their function name, with the same symbol manager setup as the local
+/// server.
+pub async fn symbolicate(
+    profile: &ProfileJson,
+    binaries_dirs: &[PathBuf],
+    verbose: bool,
+) -> SymbolNames {
+    symbolicate_with_inline_frames(profile, binaries_dirs, verbose)
+        .await
+        .0
+}
+
+/// Like [`symbolicate`], but also returns the functions which the debug info
+/// lists as inlined at each address.
+pub async fn symbolicate_with_inline_frames(
+    profile: &ProfileJson,
+    binaries_dirs: &[PathBuf],
+    verbose: bool,
+) -> (SymbolNames, InlineFrames) {
+    let mut addresses_by_lib: HashMap<usize, Vec<u32>> = HashMap::new();
+    for thread in &profile.threads {
+        for (frame, &address) in thread.frame_table.address.iter().enumerate() {
+            let Ok(address) = u32::try_from(address) else {
+                continue;
+            };
+            let func = thread.frame_table.func[frame];
+            let name = &thread.string_array[thread.func_table.name[func]];
+            if !name.starts_with("0x") {
+                continue;
+            }
+            if let Some(lib) = thread.func_lib(func) {
+                addresses_by_lib.entry(lib).or_default().push(address);
+            }
+        }
+    }
+
+    let mut symbol_manager =
+        SymbolManager::with_config(symbol_manager_config(binaries_dirs, verbose));
+    let mut lib_infos = HashMap::new();
+    for &lib in addresses_by_lib.keys() {
+        if let Some(lib_info) = profile.libs.get(lib).and_then(libinfo_map_entry_for_lib) {
+            symbol_manager.add_known_library(lib_info.clone());
+            lib_infos.insert(lib, lib_info);
+        }
+    }
+
+    let mut symbol_names = HashMap::new();
+    let mut inline_frames = HashMap::new();
+    for (lib, mut addresses) in addresses_by_lib {
+        let Some(lib_info) = lib_infos.get(&lib) else {
+            continue;
+        };
+        // libinfo_map_entry_for_lib only returns libraries with both.
+        let debug_name = lib_info.debug_name.as_deref().unwrap();
+        let debug_id = lib_info.debug_id.unwrap();
+        let symbol_map = match symbol_manager.load_symbol_map(debug_name, debug_id).await {
+            Ok(symbol_map) => symbol_map,
+            Err(err) => {
+                if verbose {
+                    eprintln!("Could not load symbols for {debug_name}: {err}");
+                }
+                continue;
+            }
+        };
+        addresses.sort_unstable();
+        addresses.dedup();
+        for address in addresses {
+            let Some(info) = symbol_map.lookup_relative_address(address) else {
+                continue;
+            };
+            if let FramesLookupResult::Available(frames) = &info.frames {
+                // The last frame is the outer function.
+                if let Some((_, inlined)) = frames.split_last() {
+                    let inlined: Vec<String> = inlined
+                        .iter()
+                        .filter_map(|frame| frame.function.clone())
+                        .collect();
+                    if !inlined.is_empty() {
+                        inline_frames.insert((lib, address), inlined);
+                    }
+                }
+            }
+            symbol_names.insert((lib, address), info.symbol.name);
+        }
+    }
+    (symbol_names, inline_frames)
+}
+
+/// The parts of a processed profile which the exporters need.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileJson {
+    #[serde(default)]
+    pub meta: MetaJson,
+    #[serde(default)]
+    pub libs: Vec<ProfileJsonLib>,
+    #[serde(default)]
+    pub threads: Vec<ThreadJson>,
+    #[serde(default)]
+    pub counters: Vec<CounterJson>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetaJson {
+    /// The sampling interval, in milliseconds.
+    #[serde(default)]
+    pub interval: f64,
+    #[serde(default)]
+    pub categories: Vec<CategoryJson>,
+}
+
+impl MetaJson {
+    pub fn category_name(&self, category: usize) -> Option<&str> {
+        self.categories
+            .get(category)
+            .map(|category| category.name.as_str())
+    }
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CategoryJson {
+    pub name: String,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadJson {
+    pub name: Option<String>,
+    pub pid: Option<String>,
+    pub tid: Option<String>,
+    pub process_name: Option<String>,
+    /// In milliseconds since the profile's start time.
+    #[serde(default)]
+    pub register_time: f64,
+    /// In milliseconds since the profile's start time, if the thread ended.
+    pub unregister_time: Option<f64>,
+    /// In milliseconds since the profile's start time.
+    #[serde(default)]
+    pub process_startup_time: f64,
+    /// In milliseconds since the profile's start time, if the process ended.
+    pub process_shutdown_time: Option<f64>,
+    pub samples: SamplesJson,
+    #[serde(default)]
+    pub markers: MarkersJson,
+    pub stack_table: StackTableJson,
+    pub frame_table: FrameTableJson,
+    pub func_table: FuncTableJson,
+    pub resource_table: ResourceTableJson,
+    pub string_array: Vec<String>,
+}
+
+impl ThreadJson {
+    pub fn func_lib(&self, func: usize) -> Option<usize> {
+        let resource = usize::try_from(self.func_table.resource[func]).ok()?;
+        self.resource_table.lib[resource]
+    }
+
+    /// Returns the symbolicated name of the frame's function if there is
+    /// one, and its name in the profile otherwise.
+    pub fn frame_name<'a>(&'a self, frame: usize, symbol_names: &'a SymbolNames) -> &'a str {
+        let func = self.frame_table.func[frame];
+        let address = u32::try_from(self.frame_table.address[frame]).ok();
+        let symbol = match (self.func_lib(func), address) {
+            (Some(lib), Some(address)) => symbol_names.get(&(lib, address)),
+            _ => None,
+        };
+        match symbol {
+            Some(symbol) => symbol,
+            None => &self.string_array[self.func_table.name[func]],
+        }
+    }
+
+    /// Returns the functions which were inlined at the frame's address,
+    /// innermost first.
+    pub fn inline_frame_names<'a>(
+        &self,
+        frame: usize,
+        inline_frames: &'a InlineFrames,
+    ) -> &'a [String] {
+        let func = self.frame_table.func[frame];
+        let address = u32::try_from(self.frame_table.address[frame]).ok();
+        let inlined = match (self.func_lib(func), address) {
+            (Some(lib), Some(address)) => inline_frames.get(&(lib, address)),
+            _ => None,
+        };
+        inlined.map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the frames of a stack, starting at the leaf.
+    pub fn stack_frames(&self, stack: Option<usize>) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(stack, |&stack_index| self.stack_table.prefix[stack_index])
+            .map(|stack_index| self.stack_table.frame[stack_index])
+    }
+
+    /// Returns the names of the frames of a stack, starting at the leaf, see
+    /// [`ThreadJson::frame_name`].
+    pub fn stack_frame_names<'a>(
+        &'a self,
+        stack: Option<usize>,
+        symbol_names: &'a SymbolNames,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.stack_frames(stack)
+            .map(move |frame| self.frame_name(frame, symbol_names))
+    }
+
+    /// Returns the samples with their weights, which are 1 if the samples
+    /// table has no weights.
+    pub fn samples(&self) -> impl Iterator<Item = Sample> + '_ {
+        let samples = &self.samples;
+        samples
+            .stack
+            .iter()
+            .enumerate()
+            .map(move |(index, &stack)| Sample {
+                stack,
+                time: samples.time.get(index).copied(),
+                weight: match &samples.weight {
+                    Some(weights) => weights.get(index).copied().unwrap_or(1),
+                    None => 1,
+                },
+            })
+    }
+
+    /// Returns the markers with their names. Markers with an unknown phase
+    /// are skipped.
+    pub fn markers(&self) -> impl Iterator<Item = Marker<'_>> + '_ {
+        let markers = &self.markers;
+        markers
+            .name
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, &name)| {
+                let phase = match markers.phase.get(index) {
+                    Some(0) => MarkerPhase::Instant,
+                    Some(1) => MarkerPhase::Interval,
+                    Some(2) => MarkerPhase::IntervalStart,
+                    Some(3) => MarkerPhase::IntervalEnd,
+                    _ => return None,
+                };
+                Some(Marker {
+                    name: self.string_array.get(name).map_or("", String::as_str),
+                    category: markers.category.get(index).copied(),
+                    phase,
+                    start_time: markers.start_time.get(index).copied().flatten(),
+                    end_time: markers.end_time.get(index).copied().flatten(),
+                    data: markers.data.get(index).and_then(Option::as_ref),
+                })
+            })
+    }
+}
+
+/// A sample of a thread, see [`ThreadJson::samples`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// The index in the thread's stack table, `None` for empty stacks.
+    pub stack: Option<usize>,
+    /// In milliseconds since the profile's start time.
+    pub time: Option<f64>,
+    pub weight: i64,
+}
+
+/// A marker of a thread, see [`ThreadJson::markers`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Marker<'a> {
+    pub name: &'a str,
+    /// The index in the profile's categories.
+    pub category: Option<usize>,
+    pub phase: MarkerPhase,
+    /// In milliseconds since the profile's start time.
+    pub start_time: Option<f64>,
+    /// In milliseconds since the profile's start time.
+    pub end_time: Option<f64>,
+    pub data: Option<&'a Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerPhase {
+    Instant,
+    Interval,
+    IntervalStart,
+    IntervalEnd,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplesJson {
+    pub stack: Vec<Option<usize>>,
+    /// In milliseconds since the profile's start time.
+    #[serde(default)]
+    pub time: Vec<f64>,
+    /// Each sample has a weight of 1 if this is missing.
+    pub weight: Option<Vec<i64>>,
+    pub weight_type: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkersJson {
+    #[serde(default)]
+    pub category: Vec<usize>,
+    #[serde(default)]
+    pub data: Vec<Option<Value>>,
+    /// Indexes into the thread's string array.
+    #[serde(default)]
+    pub name: Vec<usize>,
+    /// 0 for instants, 1 for intervals, 2 for interval starts and 3 for
+    /// interval ends.
+    #[serde(default)]
+    pub phase: Vec<u8>,
+    /// In milliseconds since the profile's start time, 0 if the phase has
+    /// no start.
+    #[serde(default)]
+    pub start_time: Vec<Option<f64>>,
+    /// In milliseconds since the profile's start time, 0 if the phase has
+    /// no end.
+    #[serde(default)]
+    pub end_time: Vec<Option<f64>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StackTableJson {
+    pub prefix: Vec<Option<usize>>,
+    pub frame: Vec<usize>,
+    #[serde(default)]
+    pub category: Vec<usize>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameTableJson {
+    /// -1 for frames without an address.
+    pub address: Vec<i64>,
+    pub category: Vec<Option<usize>>,
+    pub func: Vec<usize>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FuncTableJson {
+    pub name: Vec<usize>,
+    /// -1 for functions without a library.
+    pub resource: Vec<i64>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceTableJson {
+    pub lib: Vec<Option<usize>>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CounterJson {
+    pub name: String,
+    pub category: String,
+    pub pid: String,
+    pub main_thread_index: Option<usize>,
+    #[serde(default)]
+    pub sample_groups: Vec<CounterSampleGroupJson>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CounterSampleGroupJson {
+    pub samples: CounterSamplesJson,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CounterSamplesJson {
+    /// In milliseconds since the profile's start time.
+    pub time: Vec<f64>,
+    /// The change of the counter value since the previous sample.
+    pub count: Vec<f64>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profiles_of_an_older_samply_are_upgraded() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures/other/ls-linux/ls-profile.json");
+        let profile = read_profile(&path).unwrap();
+        let thread = &profile.threads[1];
+        assert_eq!(thread.pid.as_deref(), Some("18693"));
+        assert_eq!(thread.tid.as_deref(), Some("18693"));
+        assert_eq!(thread.unregister_time, None);
+        assert_eq!(thread.process_shutdown_time, None);
+        assert_eq!(thread.samples().count(), 5130);
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        let upgrade = |mut profile: Value| upgrade_profile(&mut profile);
+        assert!(upgrade(serde_json::json!({ "meta": {}, "threads": [] }))
+            .unwrap_err()
+            .contains("no meta.preprocessedProfileVersion"));
+        let newer = serde_json::json!({ "meta": { "preprocessedProfileVersion": 47 } });
+        assert!(upgrade(newer)
+            .unwrap_err()
+            .contains("newer version of samply"));
+        let older = serde_json::json!({ "meta": { "preprocessedProfileVersion": 40 } });
+        assert!(upgrade(older).unwrap_err().contains("too old"));
+        let current = serde_json::json!({ "meta": { "preprocessedProfileVersion": 46 } });
+        assert_eq!(upgrade(current), Ok(()));
+    }
+}
@@ -10,7 +10,9 @@ use rand::RngCore;
 use serde_derive::Deserialize;
 use tokio::io::AsyncReadExt;
 use wholesym::debugid::DebugId;
-use wholesym::{CodeId, LibraryInfo, SymbolManager, SymbolManagerConfig};
+use wholesym::{
+    query_asm_api_json_for_code, CodeId, LibraryInfo, SymbolManager, SymbolManagerConfig,
+};
 
 use std::collections::HashMap;
 use std::convert::Infallible;
@@ -22,17 +24,40 @@ use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
+use crate::cli_error::CliError;
+use crate::source_archive::{query_source_api_json_from_archives, SourceArchive};
+
 #[derive(Clone, Debug)]
 pub struct ServerProps {
     pub port_selection: PortSelection,
     pub verbose: bool,
     pub open_in_browser: bool,
+    /// Source archives which `--archive-sources` wrote, for the source view
+    /// of files which don't exist anymore.
+    pub source_archives: Vec<PathBuf>,
 }
 
 #[tokio::main]
 pub async fn start_server_main(file: &Path, props: ServerProps) {
     start_server(
-        Some(file),
+        &[file.to_owned()],
+        &[],
+        &props.source_archives,
+        props.port_selection,
+        props.verbose,
+        props.open_in_browser,
+    )
+    .await;
+}
+
+/// Serve one or more existing profiles, and answer symbolication requests for
+/// them with the help of the binaries / debug files in `binaries_dirs`.
+#[tokio::main]
+pub async fn serve_profiles_main(files: &[PathBuf], binaries_dirs: &[PathBuf], props: ServerProps) {
+    start_server(
+        files,
+        binaries_dirs,
+        &props.source_archives,
         props.port_selection,
         props.verbose,
         props.open_in_browser,
@@ -69,30 +94,81 @@ impl PortSelection {
     }
 }
 
+/// A profile file which is served by the local server.
+#[derive(Clone, Debug)]
+struct ServedProfile {
+    /// The path of the file on disk.
+    file_path: PathBuf,
+    /// The URL path at which the file is served, relative to the secret path prefix.
+    url_path: String,
+}
+
+impl ServedProfile {
+    /// If there's only one profile, it's served at `/profile.json`. Multiple profiles
+    /// are served at `/profile-1.json`, `/profile-2.json` etc.
+    fn for_files(files: &[PathBuf]) -> Vec<Self> {
+        files
+            .iter()
+            .enumerate()
+            .map(|(index, file_path)| {
+                let url_path = match files.len() {
+                    1 => "/profile.json".to_string(),
+                    _ => format!("/profile-{}.json", index + 1),
+                };
+                ServedProfile {
+                    file_path: file_path.clone(),
+                    url_path,
+                }
+            })
+            .collect()
+    }
+}
+
 async fn start_server(
-    profile_filename: Option<&Path>,
+    profile_filenames: &[PathBuf],
+    binaries_dirs: &[PathBuf],
+    source_archive_paths: &[PathBuf],
     port_selection: PortSelection,
     verbose: bool,
     open_in_browser: bool,
 ) {
-    let libinfo_map = if let Some(profile_filename) = profile_filename {
+    let mut libinfo_map = HashMap::new();
+    let mut embedded_code_map = HashMap::new();
+    for profile_filename in profile_filenames {
         // Read the profile.json file and parse it as JSON.
         // Build a map (debugName, breakpadID) -> debugPath from the information
         // in profile(\.processes\[\d+\])*(\.threads\[\d+\])?\.libs.
-        let file = std::fs::File::open(profile_filename).expect("couldn't read file");
+        let file = std::fs::File::open(profile_filename).unwrap_or_else(|err| {
+            CliError::io(format!("Could not open file {profile_filename:?}"), &err).exit()
+        });
         let reader = BufReader::new(file);
 
         // Handle .gz profiles
-        if profile_filename.extension() == Some(&OsString::from("gz")) {
+        let profile_maps = if profile_filename.extension() == Some(&OsString::from("gz")) {
             let decoder = GzDecoder::new(reader);
             let reader = BufReader::new(decoder);
-            parse_libinfo_map_from_profile(reader).expect("couldn't parse json")
+            parse_libinfo_map_from_profile(reader)
         } else {
-            parse_libinfo_map_from_profile(reader).expect("couldn't parse json")
-        }
-    } else {
-        HashMap::new()
-    };
+            parse_libinfo_map_from_profile(reader)
+        };
+        let (profile_libinfo_map, profile_embedded_code_map) = profile_maps.unwrap_or_else(|err| {
+            CliError::user_input(format!(
+                "Could not parse {profile_filename:?} as a profile: {err}"
+            ))
+            .exit()
+        });
+        libinfo_map.extend(profile_libinfo_map);
+        embedded_code_map.extend(profile_embedded_code_map);
+    }
+    let served_profiles = ServedProfile::for_files(profile_filenames);
+    let source_archives: Vec<SourceArchive> = source_archive_paths
+        .iter()
+        .map(|path| {
+            SourceArchive::open(path).unwrap_or_else(|err| {
+                CliError::io(format!("Could not read the source archive {path:?}"), &err).exit()
+            })
+        })
+        .collect();
 
     let (builder, addr) = make_builder_at_port(port_selection);
 
@@ -104,49 +180,50 @@ async fn start_server(
     template_values.insert("SERVER_URL", server_origin.clone());
     template_values.insert("PATH_PREFIX", path_prefix.clone());
 
-    let profiler_url = if profile_filename.is_some() {
-        let profile_url = format!("{symbol_server_url}/profile.json");
-
-        let env_profiler_override = std::env::var("PROFILER_URL").ok();
-        let profiler_origin = match &env_profiler_override {
-            Some(s) => s.trim_end_matches('/'),
-            None => "https://profiler.firefox.com",
-        };
+    let env_profiler_override = std::env::var("PROFILER_URL").ok();
+    let profiler_origin = match &env_profiler_override {
+        Some(s) => s.trim_end_matches('/'),
+        None => "https://profiler.firefox.com",
+    };
+    let encoded_symbol_server_url = utf8_percent_encode(&symbol_server_url, BAD_CHARS).to_string();
 
+    let mut profiler_urls = Vec::new();
+    let mut profile_links = String::new();
+    for served_profile in &served_profiles {
+        let profile_url = format!("{symbol_server_url}{}", served_profile.url_path);
         let encoded_profile_url = utf8_percent_encode(&profile_url, BAD_CHARS).to_string();
-        let encoded_symbol_server_url =
-            utf8_percent_encode(&symbol_server_url, BAD_CHARS).to_string();
         let profiler_url = format!(
             "{profiler_origin}/from-url/{encoded_profile_url}/?symbolServer={encoded_symbol_server_url}"
         );
-        template_values.insert("PROFILER_URL", profiler_url.clone());
-        template_values.insert("PROFILE_URL", profile_url);
-        Some(profiler_url)
-    } else {
-        None
-    };
+        let display_name = escape_html(&served_profile.file_path.to_string_lossy());
+        let profiler_href = escape_html(&profiler_url);
+        let profile_href = escape_html(&profile_url);
+        profile_links.push_str(&format!(
+            "    <li><a href=\"{profiler_href}\">Open {display_name} in the profiler UI</a></li>\n"
+        ));
+        profile_links.push_str(&format!(
+            "    <li><a download href=\"{profile_href}\">Download the raw profile JSON of {display_name}</a></li>\n"
+        ));
+        profiler_urls.push(profiler_url);
+    }
+    template_values.insert("PROFILE_LINKS", profile_links);
 
     let template_values = Arc::new(template_values);
 
-    let mut config = SymbolManagerConfig::new()
-        .verbose(verbose)
-        .respect_nt_symbol_path(true)
-        .default_nt_symbol_path("srv**https://msdl.microsoft.com/download/symbols")
-        .use_debuginfod(std::env::var("SAMPLY_USE_DEBUGINFOD").is_ok())
-        .use_spotlight(true);
-    if let Some(home_dir) = dirs::home_dir() {
-        config = config.debuginfod_cache_dir_if_not_installed(home_dir.join("sym"));
-    }
-    // TODO: Read breakpad symbol server config from some kind of config file, and call breakpad_symbols_server
-
-    let mut symbol_manager = SymbolManager::with_config(config);
+    let mut symbol_manager =
+        SymbolManager::with_config(symbol_manager_config(binaries_dirs, verbose));
     for lib_info in libinfo_map.into_values() {
         symbol_manager.add_known_library(lib_info);
     }
     let symbol_manager = Arc::new(symbol_manager);
+    let embedded_code_map = Arc::new(embedded_code_map);
+    let source_archives = Arc::new(source_archives);
+    let served_profiles = Arc::new(served_profiles);
     let new_service = make_service_fn(move |_conn| {
         let symbol_manager = symbol_manager.clone();
-        let profile_filename = profile_filename.map(PathBuf::from);
+        let embedded_code_map = embedded_code_map.clone();
+        let source_archives = source_archives.clone();
+        let served_profiles = served_profiles.clone();
         let template_values = template_values.clone();
         let path_prefix = path_prefix.clone();
         async {
@@ -155,7 +232,9 @@ async fn start_server(
                     req,
                     template_values.clone(),
                     symbol_manager.clone(),
-                    profile_filename.clone(),
+                    embedded_code_map.clone(),
+                    source_archives.clone(),
+                    served_profiles.clone(),
                     path_prefix.clone(),
                 )
             }))
@@ -166,14 +245,14 @@ async fn start_server(
 
     eprintln!("Local server listening at {server_origin}");
     if !open_in_browser {
-        if let Some(profiler_url) = &profiler_url {
+        for profiler_url in &profiler_urls {
             eprintln!("  Open the profiler at {profiler_url}");
         }
     }
     eprintln!("Press Ctrl+C to stop.");
 
     if open_in_browser {
-        if let Some(profiler_url) = &profiler_url {
+        for profiler_url in &profiler_urls {
             let _ = webbrowser::open(profiler_url);
         }
     }
@@ -184,13 +263,43 @@ async fn start_server(
     }
 }
 
+/// The symbol manager configuration for the local server, also used for
+/// symbolicating profiles in samply itself.
+pub fn symbol_manager_config(binaries_dirs: &[PathBuf], verbose: bool) -> SymbolManagerConfig {
+    let mut config = SymbolManagerConfig::new()
+        .verbose(verbose)
+        .respect_nt_symbol_path(true)
+        .default_nt_symbol_path("srv**https://msdl.microsoft.com/download/symbols")
+        .use_debuginfod(std::env::var("SAMPLY_USE_DEBUGINFOD").is_ok())
+        .use_spotlight(true);
+    if let Some(home_dir) = dirs::home_dir() {
+        config = config.debuginfod_cache_dir_if_not_installed(home_dir.join("sym"));
+    }
+    for dir in binaries_dirs {
+        config = config.binaries_dir(dir);
+    }
+    // TODO: Read breakpad symbol server config from some kind of config file, and call breakpad_symbols_server
+    config
+}
+
+/// The library infos of a profile, by debug name and debug ID.
+type LibinfoMap = HashMap<(String, DebugId), LibraryInfo>;
+
+/// The start and the decoded bytes of an embedded code range.
+type CodeRange = (u32, Vec<u8>);
+
+/// The code which was embedded into the profile for a library, by its debug
+/// name and debug ID, as its architecture and its code ranges.
+type EmbeddedCodeMap = HashMap<(String, DebugId), (String, Vec<CodeRange>)>;
+
 fn parse_libinfo_map_from_profile(
     reader: impl std::io::Read,
-) -> Result<HashMap<(String, DebugId), LibraryInfo>, std::io::Error> {
+) -> Result<(LibinfoMap, EmbeddedCodeMap), std::io::Error> {
     let profile: ProfileJsonProcess = serde_json::from_reader(reader)?;
     let mut libinfo_map = HashMap::new();
-    add_to_libinfo_map_recursive(&profile, &mut libinfo_map);
-    Ok(libinfo_map)
+    let mut embedded_code_map = HashMap::new();
+    add_to_libinfo_map_recursive(&profile, &mut libinfo_map, &mut embedded_code_map);
+    Ok((libinfo_map, embedded_code_map))
 }
 
 #[derive(Deserialize, Default, Clone, Debug, PartialEq, Eq)]
@@ -213,7 +322,7 @@ struct ProfileJsonThread {
 
 #[derive(Deserialize, Default, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
-struct ProfileJsonLib {
+pub struct ProfileJsonLib {
     pub debug_name: Option<String>,
     pub debug_path: Option<String>,
     pub name: Option<String>,
@@ -221,6 +330,23 @@ struct ProfileJsonLib {
     pub breakpad_id: Option<String>,
     pub code_id: Option<String>,
     pub arch: Option<String>,
+    pub embedded_code: Option<ProfileJsonEmbeddedCode>,
+}
+
+/// The code of hot JIT functions which `--embed-jit-code` embedded into the
+/// profile, for the assembly view.
+#[derive(Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct ProfileJsonEmbeddedCode {
+    pub arch: String,
+    #[serde(default)]
+    pub ranges: Vec<ProfileJsonCodeRange>,
+}
+
+#[derive(Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct ProfileJsonCodeRange {
+    pub start: u32,
+    /// The code bytes, hex encoded.
+    pub bytes: String,
 }
 
 // Returns a base32 string for 24 random bytes.
@@ -237,8 +363,7 @@ fn make_builder_at_port(port_selection: PortSelection) -> (Builder<AddrIncoming>
             match Server::try_bind(&addr) {
                 Ok(builder) => (builder, addr),
                 Err(e) => {
-                    eprintln!("Could not bind to port {port}: {e}");
-                    std::process::exit(1)
+                    CliError::environment(format!("Could not bind to port {port}: {e}")).exit()
                 }
             }
         }
@@ -254,14 +379,15 @@ fn make_builder_at_port(port_selection: PortSelection) -> (Builder<AddrIncoming>
                 }
             }
             match error {
-                Some(error) => {
-                    eprintln!("Could not bind to any port in the range {range:?}: {error}",);
-                }
+                Some(error) => CliError::environment(format!(
+                    "Could not bind to any port in the range {range:?}: {error}",
+                ))
+                .exit(),
                 None => {
-                    eprintln!("Binding failed, port range empty? {range:?}");
+                    CliError::user_input(format!("Binding failed, port range empty? {range:?}"))
+                        .exit()
                 }
             }
-            std::process::exit(1)
         }
     }
 }
@@ -275,9 +401,7 @@ const TEMPLATE_WITH_PROFILE: &str = r#"
 
 <p>This is the profiler symbol server, running at <code>SERVER_URL</code>. You can:</p>
 <ul>
-    <li><a href="PROFILER_URL">Open the profile in the profiler UI</a></li>
-    <li><a download href="PROFILE_URL">Download the raw profile JSON</a></li>
-    <li>Obtain symbols by POSTing to <code>PATH_PREFIX/symbolicate/v5</code>, with the format specified by the <a href="https://tecken.readthedocs.io/en/latest/symbolication.html">Mozilla symbolication API documentation</a>.</li>
+PROFILE_LINKS    <li>Obtain symbols by POSTing to <code>PATH_PREFIX/symbolicate/v5</code>, with the format specified by the <a href="https://tecken.readthedocs.io/en/latest/symbolication.html">Mozilla symbolication API documentation</a>.</li>
     <li>Obtain source code by POSTing to <code>PATH_PREFIX/source/v1</code>, with the format specified in this <a href="https://github.com/mstange/profiler-get-symbols/issues/24#issuecomment-989985588">github comment</a>.</li>
 </ul>
 "#;
@@ -300,10 +424,12 @@ async fn symbolication_service(
     req: Request<Body>,
     template_values: Arc<HashMap<&'static str, String>>,
     symbol_manager: Arc<SymbolManager>,
-    profile_filename: Option<PathBuf>,
+    embedded_code_map: Arc<EmbeddedCodeMap>,
+    source_archives: Arc<Vec<SourceArchive>>,
+    served_profiles: Arc<Vec<ServedProfile>>,
     path_prefix: String,
 ) -> Result<Response<Body>, hyper::Error> {
-    let has_profile = profile_filename.is_some();
+    let has_profile = !served_profiles.is_empty();
     let method = req.method();
     let path = req.uri().path();
     let mut response = Response::new(Body::empty());
@@ -342,6 +468,11 @@ async fn symbolication_service(
         header::HeaderValue::from_static("*"),
     );
 
+    let profile_filename = served_profiles
+        .iter()
+        .find(|served_profile| served_profile.url_path == path_without_prefix)
+        .map(|served_profile| served_profile.file_path.clone());
+
     match (method, path_without_prefix, profile_filename) {
         (&Method::OPTIONS, _, _) => {
             // https://developer.mozilla.org/en-US/docs/Web/HTTP/Methods/OPTIONS
@@ -375,7 +506,7 @@ async fn symbolication_service(
                 );
             }
         }
-        (&Method::GET, "/profile.json", Some(profile_filename)) => {
+        (&Method::GET, _, Some(profile_filename)) => {
             if profile_filename.extension() == Some(OsStr::new("gz")) {
                 response.headers_mut().insert(
                     header::CONTENT_ENCODING,
@@ -422,7 +553,26 @@ async fn symbolication_service(
             // Await the full body to be concatenated into a single `Bytes`...
             let full_body = hyper::body::to_bytes(req.into_body()).await?;
             let full_body = String::from_utf8(full_body.to_vec()).expect("invalid utf-8");
-            let response_json = symbol_manager.query_json_api(&path, &full_body).await;
+            // Assembly requests for embedded JIT code don't need the binary.
+            let embedded_response_json = match path.as_str() {
+                "/asm/v1" => query_asm_api_json_for_code(&full_body, |debug_name, debug_id| {
+                    embedded_code_for_library(&embedded_code_map, debug_name, debug_id)
+                }),
+                _ => None,
+            };
+            let response_json = match embedded_response_json {
+                Some(response_json) => response_json,
+                None => symbol_manager.query_json_api(&path, &full_body).await,
+            };
+            // Source files which don't exist anymore, e.g. of a deleted build
+            // tree, can come from the source archives.
+            let response_json = match path.as_str() {
+                "/source/v1" if is_error_response(&response_json) => {
+                    query_source_api_json_from_archives(&full_body, &source_archives)
+                        .unwrap_or(response_json)
+                }
+                _ => response_json,
+            };
 
             *response.body_mut() = response_json.into();
         }
@@ -434,6 +584,11 @@ async fn symbolication_service(
     Ok(response)
 }
 
+fn is_error_response(response_json: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(response_json)
+        .map_or(true, |response| response.get("error").is_some())
+}
+
 fn substitute_template(template: &str, template_values: &HashMap<&'static str, String>) -> String {
     let mut s = template.to_string();
     for (key, value) in template_values {
@@ -442,21 +597,67 @@ fn substitute_template(template: &str, template_values: &HashMap<&'static str, S
     s
 }
 
+/// Escapes text for use in HTML element content and in quoted attribute
+/// values.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 fn add_libs_to_libinfo_map(
     libs: &[ProfileJsonLib],
-    libinfo_map: &mut HashMap<(String, DebugId), LibraryInfo>,
+    libinfo_map: &mut LibinfoMap,
+    embedded_code_map: &mut EmbeddedCodeMap,
 ) {
     for lib in libs {
         if let Some(lib_info) = libinfo_map_entry_for_lib(lib) {
             // If libinfo_map_entry_for_lib returns Some(), debug_name and debug_id are guaranteed to be Some().
             let debug_name = lib_info.debug_name.clone().unwrap();
             let debug_id = lib_info.debug_id.unwrap();
+            if let Some(code) = &lib.embedded_code {
+                let ranges = code
+                    .ranges
+                    .iter()
+                    .filter_map(|range| Some((range.start, decode_hex(&range.bytes)?)))
+                    .collect();
+                embedded_code_map
+                    .insert((debug_name.clone(), debug_id), (code.arch.clone(), ranges));
+            }
             libinfo_map.insert((debug_name, debug_id), lib_info);
         }
     }
 }
 
-fn libinfo_map_entry_for_lib(lib: &ProfileJsonLib) -> Option<LibraryInfo> {
+fn embedded_code_for_library<'a>(
+    embedded_code_map: &'a EmbeddedCodeMap,
+    debug_name: &str,
+    debug_id: DebugId,
+) -> Option<(&'a str, &'a [CodeRange])> {
+    let (arch, ranges) = embedded_code_map.get(&(debug_name.to_string(), debug_id))?;
+    Some((arch.as_str(), ranges.as_slice()))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn libinfo_map_entry_for_lib(lib: &ProfileJsonLib) -> Option<LibraryInfo> {
     let debug_name = lib.debug_name.clone()?;
     let breakpad_id = lib.breakpad_id.as_ref()?;
     let debug_path = lib.debug_path.clone();
@@ -482,14 +683,15 @@ fn libinfo_map_entry_for_lib(lib: &ProfileJsonLib) -> Option<LibraryInfo> {
 
 fn add_to_libinfo_map_recursive(
     profile: &ProfileJsonProcess,
-    libinfo_map: &mut HashMap<(String, DebugId), LibraryInfo>,
+    libinfo_map: &mut LibinfoMap,
+    embedded_code_map: &mut EmbeddedCodeMap,
 ) {
-    add_libs_to_libinfo_map(&profile.libs, libinfo_map);
+    add_libs_to_libinfo_map(&profile.libs, libinfo_map, embedded_code_map);
     for thread in &profile.threads {
-        add_libs_to_libinfo_map(&thread.libs, libinfo_map);
+        add_libs_to_libinfo_map(&thread.libs, libinfo_map, embedded_code_map);
     }
     for process in &profile.processes {
-        add_to_libinfo_map_recursive(process, libinfo_map);
+        add_to_libinfo_map_recursive(process, libinfo_map, embedded_code_map);
     }
 }
 
@@ -517,4 +719,180 @@ mod test {
         assert_eq!(p.threads[0].libs[0], ProfileJsonLib::default());
         assert!(p.processes.is_empty());
     }
+
+    #[test]
+    fn profile_names_are_escaped_in_html() {
+        assert_eq!(
+            escape_html(r#"<img src=x onerror="alert('&')">.json"#),
+            "&lt;img src=x onerror=&quot;alert(&#39;&amp;&#39;)&quot;&gt;.json"
+        );
+        assert_eq!(escape_html("/tmp/profile.json"), "/tmp/profile.json");
+    }
+
+    #[test]
+    fn asm_requests_are_answered_from_embedded_code() {
+        let profile = r#"{
+            "libs": [{
+                "debugName": "jit-1234.dump",
+                "breakpadId": "000000000000000000000000000000000",
+                "embeddedCode": {
+                    "arch": "x86_64",
+                    "ranges": [{ "start": 16, "bytes": "90c3" }]
+                }
+            }]
+        }"#;
+        let (_, embedded_code_map) = parse_libinfo_map_from_profile(profile.as_bytes()).unwrap();
+        let request = r#"{
+            "debugName": "jit-1234.dump",
+            "debugId": "000000000000000000000000000000000",
+            "startAddress": "0x10",
+            "size": "0x2"
+        }"#;
+        let response = query_asm_api_json_for_code(request, |debug_name, debug_id| {
+            embedded_code_for_library(&embedded_code_map, debug_name, debug_id)
+        })
+        .unwrap();
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["instructions"].as_array().unwrap().len(), 2);
+
+        // Addresses outside of the embedded code fall back to the binary.
+        let request = request.replace("0x10", "0x20");
+        let response = query_asm_api_json_for_code(&request, |debug_name, debug_id| {
+            embedded_code_for_library(&embedded_code_map, debug_name, debug_id)
+        });
+        assert_eq!(response, None);
+    }
+
+    fn fixture_path(dir: &str, name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures")
+            .join(dir)
+            .join(name)
+    }
+
+    fn profile_with_lib(debug_name: &str, breakpad_id: &str, path: &Path) -> String {
+        serde_json::json!({
+            "libs": [{
+                "debugName": debug_name,
+                "breakpadId": breakpad_id,
+                "debugPath": path,
+                "path": path,
+            }]
+        })
+        .to_string()
+    }
+
+    async fn response_body(response: Response<Body>) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn each_served_profile_has_its_own_url_and_libraries() {
+        let dir = tempfile::tempdir().unwrap();
+        let example_linux = fixture_path("other", "example-linux");
+        let firefox = fixture_path("linux64-ci", "firefox");
+        let profiles = [
+            profile_with_lib(
+                "example-linux",
+                "BE4E976C325246EE9D6B7847A670B2A90",
+                &example_linux,
+            ),
+            profile_with_lib("firefox", "83CA53B0E8272691CEFCD79178D33D5C0", &firefox),
+        ];
+        let profile_paths: Vec<PathBuf> = profiles
+            .iter()
+            .enumerate()
+            .map(|(i, profile)| {
+                let path = dir.path().join(format!("profile{i}.json"));
+                std::fs::write(&path, profile).unwrap();
+                path
+            })
+            .collect();
+
+        // Set up the service state the way start_server does, without
+        // binaries dirs, so that libraries are only found via the profiles.
+        let mut libinfo_map = HashMap::new();
+        for path in &profile_paths {
+            let file = std::fs::File::open(path).unwrap();
+            let (profile_libinfo_map, _) = parse_libinfo_map_from_profile(file).unwrap();
+            libinfo_map.extend(profile_libinfo_map);
+        }
+        let mut symbol_manager = SymbolManager::with_config(SymbolManagerConfig::new());
+        for lib_info in libinfo_map.into_values() {
+            symbol_manager.add_known_library(lib_info);
+        }
+        let symbol_manager = Arc::new(symbol_manager);
+        let served_profiles = Arc::new(ServedProfile::for_files(&profile_paths));
+        let path_prefix = format!("/{}", generate_token());
+        let request = |method: Method, path: &str, body: String| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::from(body))
+                .unwrap();
+            symbolication_service(
+                req,
+                Arc::new(HashMap::new()),
+                symbol_manager.clone(),
+                Arc::new(HashMap::new()),
+                Arc::new(Vec::new()),
+                served_profiles.clone(),
+                path_prefix.clone(),
+            )
+        };
+
+        for (i, profile) in profiles.iter().enumerate() {
+            let url_path = format!("/profile-{}.json", i + 1);
+            let response = request(
+                Method::GET,
+                &format!("{path_prefix}{url_path}"),
+                String::new(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(&response_body(response).await, profile);
+
+            // Without the token, the profile isn't served.
+            let response = request(Method::GET, &url_path, String::new())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        let symbolicate = |debug_name: &str, breakpad_id: &str, address: u32| {
+            let body = serde_json::json!({
+                "memoryMap": [[debug_name, breakpad_id]],
+                "stacks": [[[0, address]]],
+            });
+            let response = request(
+                Method::POST,
+                &format!("{path_prefix}/symbolicate/v5"),
+                body.to_string(),
+            );
+            async {
+                let response = response.await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let response: serde_json::Value =
+                    serde_json::from_str(&response_body(response).await).unwrap();
+                response["results"][0].clone()
+            }
+        };
+
+        let result =
+            symbolicate("example-linux", "BE4E976C325246EE9D6B7847A670B2A90", 0x1156).await;
+        assert_eq!(
+            result["found_modules"]["example-linux/BE4E976C325246EE9D6B7847A670B2A90"],
+            true
+        );
+        assert_eq!(result["stacks"][0][0]["function"], "main");
+
+        let result = symbolicate("firefox", "83CA53B0E8272691CEFCD79178D33D5C0", 0x18a0).await;
+        assert_eq!(
+            result["found_modules"]["firefox/83CA53B0E8272691CEFCD79178D33D5C0"],
+            true
+        );
+        assert_eq!(result["stacks"][0][0]["function"], "start");
+    }
 }
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+
+use fxprof_processed_profile::{LibMappings, LibraryHandle, Profile};
+
+use super::lib_mappings::{LibMappingInfo, LibMappingOpQueue, LibMappingsHierarchy, MappingKind};
+
+/// The mapping history of one process: the same mapping operations which are
+/// replayed when its samples are added to the profile.
+#[derive(Debug, Clone)]
+pub struct ProcessAddressSpace {
+    regular_lib_mapping_op_queue: LibMappingOpQueue,
+    jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
+    perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
+}
+
+impl ProcessAddressSpace {
+    pub fn new(
+        regular_lib_mapping_op_queue: LibMappingOpQueue,
+        jitdump_lib_mapping_op_queues: Vec<LibMappingOpQueue>,
+        perf_map_mappings: Option<LibMappings<LibMappingInfo>>,
+    ) -> Self {
+        Self {
+            regular_lib_mapping_op_queue,
+            jitdump_lib_mapping_op_queues,
+            perf_map_mappings,
+        }
+    }
+
+    fn lookup(&self, avma: u64, timestamp: u64) -> Option<MappingView> {
+        let mut hierarchy = LibMappingsHierarchy::new(self.regular_lib_mapping_op_queue.clone());
+        for ops in &self.jitdump_lib_mapping_op_queues {
+            hierarchy.add_jitdump_lib_mappings_ops(ops.clone());
+        }
+        if let Some(perf_map_mappings) = &self.perf_map_mappings {
+            hierarchy.add_perf_map_mappings(perf_map_mappings.clone());
+        }
+        hierarchy.process_ops(timestamp);
+        let (relative_address, info, kind) = hierarchy.convert_address_with_kind(avma)?;
+        Some(MappingView {
+            lib: info.lib_handle,
+            relative_address,
+            kind,
+        })
+    }
+}
+
+/// The library which an address belonged to at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingView {
+    pub lib: LibraryHandle,
+    pub relative_address: u32,
+    pub kind: MappingKind,
+}
+
+/// The mapping history of all processes in a conversion, for finding out
+/// which library an address was attributed to at a given time.
+///
+/// Each lookup replays the process's mapping operations up to the timestamp,
+/// in the same way as the sample conversion does, so it's only meant for
+/// debugging and tests.
+#[derive(Debug, Clone, Default)]
+pub struct AddressSpaceTimeline {
+    /// The incarnations of each pid, in the order in which they ended.
+    address_spaces_by_pid: HashMap<i32, Vec<ProcessAddressSpace>>,
+}
+
+impl AddressSpaceTimeline {
+    pub fn add_process(&mut self, pid: i32, address_space: ProcessAddressSpace) {
+        self.address_spaces_by_pid
+            .entry(pid)
+            .or_default()
+            .push(address_space);
+    }
+
+    /// `timestamp` is in the clock of the perf.data samples. If the pid was
+    /// reused, the latest process which had started by `timestamp` is used.
+    pub fn lookup(&self, pid: i32, avma: u64, timestamp: u64) -> Option<MappingView> {
+        let address_spaces = self.address_spaces_by_pid.get(&pid)?;
+        let has_started = |address_space: &&ProcessAddressSpace| {
+            let start = address_space.regular_lib_mapping_op_queue.start_timestamp();
+            start.map_or(true, |start| start <= timestamp)
+        };
+        let address_space = address_spaces.iter().rev().find(has_started)?;
+        address_space.lookup(avma, timestamp)
+    }
+}
+
+/// A `--query` lookup: which library `address` belonged to in process `pid`
+/// at `timestamp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressQuery {
+    pub pid: i32,
+    pub address: u64,
+    /// In nanoseconds, in the clock of the perf.data samples.
+    pub timestamp: u64,
+}
+
+impl AddressQuery {
+    /// Prints the answer to the query, e.g.
+    /// "pid 1234, address 0x7f3a2c001234 at 5.000000: libxul.so + 0x1234 (mmap record)".
+    pub fn print_answer(&self, timeline: &AddressSpaceTimeline, profile: &Profile) {
+        let AddressQuery {
+            pid,
+            address,
+            timestamp,
+        } = *self;
+        let query = format!(
+            "pid {pid}, address 0x{address:x} at {}.{:06}",
+            timestamp / 1_000_000_000,
+            timestamp % 1_000_000_000 / 1000
+        );
+        match timeline.lookup(pid, address, timestamp) {
+            Some(view) => println!(
+                "{query}: {} + 0x{:x} ({})",
+                profile.lib_info(view.lib).name,
+                view.relative_address,
+                view.kind
+            ),
+            None => println!("{query}: not in any mapping"),
+        }
+    }
+}
+
+/// Parses "PID,ADDRESS,TIME", with the address in hex (with or without 0x)
+/// and the time in seconds, as printed by `perf script`, e.g.
+/// "1234,7f3a2c001234,5361.123456".
+pub fn parse_address_query(s: &str) -> Result<AddressQuery, String> {
+    let mut parts = s.split(',');
+    let (Some(pid), Some(address), Some(time), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(format!("expected PID,ADDRESS,TIME, got {s:?}"));
+    };
+    let pid = pid
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid pid {pid:?}"))?;
+    let address = address.trim();
+    let address = address.strip_prefix("0x").unwrap_or(address);
+    let address =
+        u64::from_str_radix(address, 16).map_err(|_| format!("invalid address {address:?}"))?;
+    let timestamp =
+        parse_seconds_as_nanos(time.trim()).ok_or_else(|| format!("invalid time {time:?}"))?;
+    Ok(AddressQuery {
+        pid,
+        address,
+        timestamp,
+    })
+}
+
+/// Parses "5361.123456" as 5361123456000, without going through f64.
+fn parse_seconds_as_nanos(s: &str) -> Option<u64> {
+    let (seconds, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let seconds: u64 = seconds.parse().ok()?;
+    let fraction_nanos = format!("{fraction:0<9}").parse::<u64>().ok()?;
+    seconds
+        .checked_mul(1_000_000_000)?
+        .checked_add(fraction_nanos)
+}
+
+#[cfg(test)]
+mod test {
+    use debugid::DebugId;
+    use fxprof_processed_profile::{LibraryInfo, ReferenceTimestamp, SamplingInterval};
+
+    use super::*;
+    use crate::shared::lib_mappings::{LibMappingAdd, LibMappingOp};
+
+    fn add_lib(profile: &mut Profile, name: &str) -> LibraryHandle {
+        profile.add_lib(LibraryInfo {
+            name: name.to_string(),
+            debug_name: name.to_string(),
+            path: format!("/usr/lib/{name}"),
+            debug_path: format!("/usr/lib/{name}"),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        })
+    }
+
+    fn add_op(lib: LibraryHandle, start_avma: u64, end_avma: u64) -> LibMappingOp {
+        LibMappingOp::Add(LibMappingAdd {
+            start_avma,
+            end_avma,
+            relative_address_at_start: 0,
+            info: LibMappingInfo::new_lib(lib),
+        })
+    }
+
+    #[test]
+    fn looks_up_mappings_at_their_time() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let (old_lib, new_lib, jit_lib) = (
+            add_lib(&mut profile, "old.so"),
+            add_lib(&mut profile, "new.so"),
+            add_lib(&mut profile, "jitted-123.so"),
+        );
+
+        // The same range is first used by old.so, and after an exec by new.so.
+        let mut regular = LibMappingOpQueue::default();
+        regular.push(100, add_op(old_lib, 0x1000, 0x2000));
+        regular.push(200, LibMappingOp::Clear);
+        regular.push(300, add_op(new_lib, 0x1000, 0x2000));
+        let mut jitdump = LibMappingOpQueue::default();
+        jitdump.push(150, add_op(jit_lib, 0x5000, 0x5100));
+
+        let mut timeline = AddressSpaceTimeline::default();
+        timeline.add_process(10, ProcessAddressSpace::new(regular, vec![jitdump], None));
+
+        let view = |lib, relative_address, kind| {
+            Some(MappingView {
+                lib,
+                relative_address,
+                kind,
+            })
+        };
+        assert_eq!(
+            timeline.lookup(10, 0x1234, 150),
+            view(old_lib, 0x234, MappingKind::Regular)
+        );
+        assert_eq!(timeline.lookup(10, 0x1234, 250), None);
+        assert_eq!(
+            timeline.lookup(10, 0x1234, 300),
+            view(new_lib, 0x234, MappingKind::Regular)
+        );
+        assert_eq!(timeline.lookup(10, 0x5010, 100), None);
+        assert_eq!(
+            timeline.lookup(10, 0x5010, 400),
+            view(jit_lib, 0x10, MappingKind::JitDump)
+        );
+        assert_eq!(timeline.lookup(11, 0x1234, 150), None);
+    }
+
+    #[test]
+    fn parses_queries() {
+        assert_eq!(
+            parse_address_query("1234,0x7f3a2c001234,5361.123456"),
+            Ok(AddressQuery {
+                pid: 1234,
+                address: 0x7f3a2c001234,
+                timestamp: 5_361_123_456_000,
+            })
+        );
+        assert_eq!(
+            parse_address_query("1,ff,2").map(|q| (q.address, q.timestamp)),
+            Ok((0xff, 2_000_000_000))
+        );
+        assert!(parse_address_query("1,ff").is_err());
+        assert!(parse_address_query("1,zz,2").is_err());
+        assert!(parse_address_query("1,ff,2.1234567891").is_err());
+    }
+}
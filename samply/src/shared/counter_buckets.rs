@@ -0,0 +1,212 @@
+use fxprof_processed_profile::{CounterHandle, Profile};
+
+use super::timestamp_converter::TimestampConverter;
+
+/// A counter sample, as a delta from the previous sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CounterPoint {
+    pub timestamp: u64,
+    pub delta: f64,
+    pub operation_count: u32,
+}
+
+/// Deltas of memory counters, in bytes, above which a sample is emitted as
+/// it is rather than aggregated into its bucket.
+pub const LARGE_MEMORY_DELTA_BYTES: f64 = 1024.0 * 1024.0;
+
+/// The samples of the current bucket which haven't been emitted yet.
+#[derive(Debug, Clone, Copy)]
+struct PendingBucket {
+    index: u64,
+    last_timestamp: u64,
+    delta: f64,
+    operation_count: u32,
+}
+
+/// Aggregates the samples of a counter into fixed time buckets, so that
+/// counters which change thousands of times per second, e.g. the memory
+/// counter from kmem:rss_stat, don't alias when the viewer downsamples them.
+///
+/// Each bucket becomes one point at the timestamp of its last sample, with
+/// the summed delta, so the counter value at the end of each bucket is exact.
+/// Samples whose delta exceeds the threshold are emitted as they are, so that
+/// large steps, e.g. big allocations, stay at their exact time.
+#[derive(Debug, Clone)]
+pub struct CounterBuckets {
+    /// 0 means that samples aren't aggregated.
+    bucket_duration_ns: u64,
+    large_delta_threshold: f64,
+    pending: Option<PendingBucket>,
+}
+
+impl CounterBuckets {
+    pub fn new(bucket_duration_ns: u64, large_delta_threshold: f64) -> Self {
+        Self {
+            bucket_duration_ns,
+            large_delta_threshold,
+            pending: None,
+        }
+    }
+
+    /// Adds a sample and returns the points which are complete. Timestamps
+    /// must not decrease.
+    pub fn add(&mut self, timestamp: u64, delta: f64) -> Vec<CounterPoint> {
+        let mut points = Vec::new();
+        if self.bucket_duration_ns == 0 || delta.abs() > self.large_delta_threshold {
+            points.extend(self.flush());
+            points.push(CounterPoint {
+                timestamp,
+                delta,
+                operation_count: 1,
+            });
+            return points;
+        }
+        let index = timestamp / self.bucket_duration_ns;
+        if let Some(pending) = &mut self.pending {
+            if pending.index == index {
+                pending.last_timestamp = timestamp;
+                pending.delta += delta;
+                pending.operation_count += 1;
+                return points;
+            }
+        }
+        points.extend(self.flush());
+        self.pending = Some(PendingBucket {
+            index,
+            last_timestamp: timestamp,
+            delta,
+            operation_count: 1,
+        });
+        points
+    }
+
+    /// Returns the point of the current bucket, if it has any samples.
+    pub fn flush(&mut self) -> Option<CounterPoint> {
+        let pending = self.pending.take()?;
+        Some(CounterPoint {
+            timestamp: pending.last_timestamp,
+            delta: pending.delta,
+            operation_count: pending.operation_count,
+        })
+    }
+}
+
+/// A profile counter whose samples go through [`CounterBuckets`].
+#[derive(Debug, Clone)]
+pub struct BucketedCounter {
+    counter: CounterHandle,
+    buckets: CounterBuckets,
+}
+
+impl BucketedCounter {
+    pub fn new(counter: CounterHandle, buckets: CounterBuckets) -> Self {
+        Self { counter, buckets }
+    }
+
+    pub fn add_sample(
+        &mut self,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+        timestamp: u64,
+        delta: f64,
+    ) {
+        for point in self.buckets.add(timestamp, delta) {
+            self.add_point(profile, timestamp_converter, point);
+        }
+    }
+
+    /// Adds the samples of the current bucket. Called at the end of the
+    /// conversion.
+    pub fn flush(&mut self, profile: &mut Profile, timestamp_converter: &TimestampConverter) {
+        if let Some(point) = self.buckets.flush() {
+            self.add_point(profile, timestamp_converter, point);
+        }
+    }
+
+    fn add_point(
+        &self,
+        profile: &mut Profile,
+        timestamp_converter: &TimestampConverter,
+        point: CounterPoint,
+    ) {
+        profile.add_counter_sample(
+            self.counter,
+            timestamp_converter.convert_time(point.timestamp),
+            point.delta,
+            point.operation_count,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bucket_stream(buckets: &mut CounterBuckets, samples: &[(u64, f64)]) -> Vec<CounterPoint> {
+        let mut points = Vec::new();
+        for &(timestamp, delta) in samples {
+            points.extend(buckets.add(timestamp, delta));
+        }
+        points.extend(buckets.flush());
+        points
+    }
+
+    #[test]
+    fn bucketed_points_sum_to_input() {
+        // A sawtooth of small allocations and frees every 7ns, with one
+        // large allocation at 511, in the middle of the bucket from 500 to 600.
+        let samples: Vec<(u64, f64)> = (0..200u64)
+            .map(|i| match i {
+                73 => (i * 7, 1000.0),
+                _ if i % 3 == 0 => (i * 7, -8.0),
+                _ => (i * 7, 4.0),
+            })
+            .collect();
+
+        let mut buckets = CounterBuckets::new(100, 100.0);
+        let points = bucket_stream(&mut buckets, &samples);
+
+        let input_sum: f64 = samples.iter().map(|&(_, d)| d).sum();
+        let output_sum: f64 = points.iter().map(|p| p.delta).sum();
+        assert_eq!(input_sum, output_sum);
+        let operation_count: u32 = points.iter().map(|p| p.operation_count).sum();
+        assert_eq!(operation_count as usize, samples.len());
+
+        // One point per 100ns bucket from 0 to 1393, plus the large delta,
+        // which splits its bucket in two.
+        assert_eq!(points.len(), 14 + 2);
+        assert!(points.contains(&CounterPoint {
+            timestamp: 511,
+            delta: 1000.0,
+            operation_count: 1,
+        }));
+
+        // The running value at each point matches the exact value at that time.
+        let mut value = 0.0;
+        for point in &points {
+            value += point.delta;
+            let exact: f64 = samples
+                .iter()
+                .filter(|&&(t, _)| t <= point.timestamp)
+                .map(|&(_, d)| d)
+                .sum();
+            assert_eq!(value, exact, "at {}", point.timestamp);
+        }
+    }
+
+    #[test]
+    fn zero_duration_keeps_every_sample() {
+        let samples = [(1, 2.0), (2, -1.0), (3, 5.0)];
+        let mut buckets = CounterBuckets::new(0, f64::INFINITY);
+        let points = bucket_stream(&mut buckets, &samples);
+        let expected: Vec<CounterPoint> = samples
+            .iter()
+            .map(|&(timestamp, delta)| CounterPoint {
+                timestamp,
+                delta,
+                operation_count: 1,
+            })
+            .collect();
+        assert_eq!(points, expected);
+    }
+}
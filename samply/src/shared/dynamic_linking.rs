@@ -0,0 +1,312 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use fxprof_processed_profile::{
+    CategoryPairHandle, MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema,
+    MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile, ProfilerMarker, ThreadHandle,
+    Timestamp,
+};
+use object::{Object, ObjectSection, ObjectSymbol};
+use serde_json::json;
+use wholesym::samply_symbols::object;
+
+use super::lib_mappings::LibMappingsHierarchy;
+use super::types::{StackFrame, StackMode};
+
+/// The sections which contain PLT stubs.
+const PLT_SECTION_NAMES: &[&str] = &[".plt", ".plt.got", ".plt.sec"];
+
+/// The functions in ld.so which resolve lazily-bound symbols on the first
+/// call through a PLT stub.
+const RESOLVER_FUNCTION_PREFIXES: &[&str] = &[
+    "_dl_runtime_resolve",
+    "_dl_runtime_profile",
+    "_dl_fixup",
+    "_dl_profile_fixup",
+];
+
+/// What kind of dynamic linking code an address is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicLinkingFrameKind {
+    PltStub,
+    Resolver,
+    /// Any other code in the dynamic linker, e.g. relocation processing and
+    /// symbol lookup during startup or dlopen.
+    Loader,
+}
+
+/// The address ranges of a module which contain dynamic linking code, as
+/// relative addresses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DynamicLinkingRanges {
+    plt_stubs: Vec<Range<u32>>,
+    resolver: Vec<Range<u32>>,
+    /// Whether the module is the dynamic linker itself.
+    is_dynamic_linker: bool,
+}
+
+impl DynamicLinkingRanges {
+    /// Finds the PLT sections of the module, and the lazy-binding resolver if
+    /// the module is the dynamic linker. Returns None if the module has no
+    /// PLT sections and isn't the dynamic linker.
+    pub fn from_object<'data: 'file, 'file>(
+        file: &'file impl Object<'data, 'file>,
+        base_svma: u64,
+        is_dynamic_linker: bool,
+    ) -> Option<Arc<Self>> {
+        let relative_range = |address: u64, size: u64| {
+            let start = address.checked_sub(base_svma)?;
+            Some(start as u32..(start + size) as u32)
+        };
+        let plt_stubs = PLT_SECTION_NAMES
+            .iter()
+            .filter_map(|name| file.section_by_name(name))
+            .filter_map(|section| relative_range(section.address(), section.size()))
+            .collect();
+        let resolver = if is_dynamic_linker {
+            file.symbols()
+                .chain(file.dynamic_symbols())
+                .filter(|symbol| symbol.size() != 0)
+                .filter(|symbol| {
+                    symbol.name().map_or(false, |name| {
+                        RESOLVER_FUNCTION_PREFIXES
+                            .iter()
+                            .any(|prefix| name.starts_with(prefix))
+                    })
+                })
+                .filter_map(|symbol| relative_range(symbol.address(), symbol.size()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let ranges = Self {
+            plt_stubs,
+            resolver,
+            is_dynamic_linker,
+        };
+        if ranges.plt_stubs.is_empty() && !is_dynamic_linker {
+            return None;
+        }
+        Some(Arc::new(ranges))
+    }
+
+    pub fn classify(&self, relative_address: u32) -> Option<DynamicLinkingFrameKind> {
+        let contains = |ranges: &[Range<u32>]| ranges.iter().any(|r| r.contains(&relative_address));
+        if contains(&self.plt_stubs) {
+            Some(DynamicLinkingFrameKind::PltStub)
+        } else if contains(&self.resolver) {
+            Some(DynamicLinkingFrameKind::Resolver)
+        } else if self.is_dynamic_linker {
+            Some(DynamicLinkingFrameKind::Loader)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_dynamic_linker(&self) -> bool {
+        self.is_dynamic_linker
+    }
+}
+
+/// Whether a file name is the name of the dynamic linker, e.g.
+/// "ld-linux-x86-64.so.2", "ld-musl-x86_64.so.1" or "ld-2.31.so".
+pub fn is_dynamic_linker_name(name: &str) -> bool {
+    (name.starts_with("ld-") || name.starts_with("ld.so")) && name.contains(".so")
+}
+
+/// How frames in dynamic linking code are converted.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicLinkingFrameConversion {
+    /// The category of PLT stubs and the resolver.
+    pub category: CategoryPairHandle,
+    /// The category of the rest of the dynamic linker.
+    pub loader_category: CategoryPairHandle,
+    /// Whether a PLT stub frame should be dropped if its child frame is the
+    /// real callee, so that the callee is charged for the time.
+    pub fold_plt: bool,
+}
+
+/// Whether the innermost user frame of a stack, leaf first, is in the
+/// dynamic linker. Returns None if the stack has no user frames.
+pub fn user_leaf_is_in_dynamic_linker(
+    stack: &[StackFrame],
+    lib_mappings: &LibMappingsHierarchy,
+) -> Option<bool> {
+    let lookup_address = stack.iter().find_map(|frame| match *frame {
+        StackFrame::InstructionPointer(addr, StackMode::User) => Some(addr),
+        StackFrame::ReturnAddress(addr, StackMode::User) => Some(addr.saturating_sub(1)),
+        _ => None,
+    })?;
+    let is_dynamic_linker = lib_mappings
+        .convert_address(lookup_address)
+        .and_then(|(_, info)| info.dynamic_linking_ranges.as_ref())
+        .map_or(false, |ranges| ranges.is_dynamic_linker());
+    Some(is_dynamic_linker)
+}
+
+/// Finds the dynamic loading phase at the start of a process: the samples
+/// whose innermost user frame is in the dynamic linker, up to the first
+/// sample which is elsewhere, usually in the main binary. Later samples in
+/// the dynamic linker, e.g. from dlopen, don't extend the phase.
+#[derive(Debug, Default)]
+pub struct DynamicLoadingPhaseDetector {
+    /// A thread of the process.
+    thread: Option<ThreadHandle>,
+    /// (timestamp_mono, timestamp, whether the sample is in the dynamic
+    /// linker) for the samples with user frames.
+    samples: Vec<(u64, Timestamp, bool)>,
+}
+
+impl DynamicLoadingPhaseDetector {
+    pub fn add_sample(
+        &mut self,
+        timestamp_mono: u64,
+        timestamp: Timestamp,
+        thread: ThreadHandle,
+        in_dynamic_linker: bool,
+    ) {
+        self.thread.get_or_insert(thread);
+        self.samples
+            .push((timestamp_mono, timestamp, in_dynamic_linker));
+    }
+
+    /// Adds the phase as a marker on the process's main thread, if the
+    /// process's first samples are in the dynamic linker.
+    pub fn add_marker(mut self, profile: &mut Profile) {
+        let Some(main_thread) = self
+            .thread
+            .and_then(|thread| profile.main_thread_of_process(thread))
+        else {
+            return;
+        };
+        self.samples
+            .sort_by_key(|(timestamp_mono, ..)| *timestamp_mono);
+        let Some(((start_mono, start), (end_mono, end), sample_count)) =
+            leading_dynamic_loading_run(&self.samples)
+        else {
+            return;
+        };
+        let duration_ms = (end_mono - start_mono) as f64 / 1_000_000.0;
+        profile.add_marker(
+            main_thread,
+            &format!("Dynamic loading phase (~{duration_ms:.0}ms)"),
+            DynamicLoadingPhaseMarker { sample_count },
+            MarkerTiming::Interval(start, end),
+        );
+    }
+}
+
+/// Returns the first and last sample of the leading run of samples in the
+/// dynamic linker, and the run's sample count. The samples are sorted by
+/// time.
+#[allow(clippy::type_complexity)]
+fn leading_dynamic_loading_run(
+    samples: &[(u64, Timestamp, bool)],
+) -> Option<((u64, Timestamp), (u64, Timestamp), usize)> {
+    let sample_count = samples
+        .iter()
+        .take_while(|(.., in_dynamic_linker)| *in_dynamic_linker)
+        .count();
+    let (first, last) = (samples.first()?, samples[..sample_count].last()?);
+    Some(((first.0, first.1), (last.0, last.1), sample_count))
+}
+
+#[derive(Debug, Clone)]
+pub struct DynamicLoadingPhaseMarker {
+    sample_count: usize,
+}
+
+impl ProfilerMarker for DynamicLoadingPhaseMarker {
+    const MARKER_TYPE_NAME: &'static str = "DynamicLoadingPhase";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "samples": self.sample_count,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.name}"),
+            tooltip_label: Some("{marker.name}"),
+            table_label: Some("{marker.name}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "samples",
+                    label: "Samples",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "The time at the start of the process in which the dynamic linker \
+                            loaded and relocated the libraries, before the main binary's code \
+                            ran.",
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classifies_addresses() {
+        use DynamicLinkingFrameKind::{Loader, PltStub, Resolver};
+        let mut ranges = DynamicLinkingRanges {
+            plt_stubs: vec![0x1000..0x1100, 0x1100..0x1180],
+            resolver: vec![0x5000..0x5040, 0x6000..0x6100],
+            is_dynamic_linker: false,
+        };
+        assert_eq!(ranges.classify(0x1010), Some(PltStub));
+        assert_eq!(ranges.classify(0x1170), Some(PltStub));
+        assert_eq!(ranges.classify(0x5000), Some(Resolver));
+        assert_eq!(ranges.classify(0x6080), Some(Resolver));
+        assert_eq!(ranges.classify(0x5040), None);
+        assert_eq!(ranges.classify(0x2000), None);
+        ranges.is_dynamic_linker = true;
+        assert_eq!(ranges.classify(0x5000), Some(Resolver));
+        assert_eq!(ranges.classify(0x2000), Some(Loader));
+        assert!(is_dynamic_linker_name("ld-linux-x86-64.so.2"));
+        assert!(is_dynamic_linker_name("ld-2.31.so"));
+        assert!(!is_dynamic_linker_name("libld-helper.so"));
+        assert!(!is_dynamic_linker_name("ld-config.txt"));
+        assert!(is_dynamic_linker_name("ld-musl-x86_64.so.1"));
+    }
+
+    #[test]
+    fn finds_the_leading_dynamic_loading_run() {
+        const MS: u64 = 1_000_000;
+        let sample = |ms: u64, in_dynamic_linker: bool| {
+            (
+                ms * MS,
+                Timestamp::from_nanos_since_reference(ms * MS),
+                in_dynamic_linker,
+            )
+        };
+        let run = |samples: &[(u64, Timestamp, bool)]| {
+            leading_dynamic_loading_run(samples)
+                .map(|(start, end, count)| (start.0 / MS, end.0 / MS, count))
+        };
+
+        // Relocations from 1 to 9ms, then main, then a dlopen at 30ms which
+        // doesn't extend the phase.
+        let mut samples: Vec<_> = (1..10).map(|ms| sample(ms, true)).collect();
+        samples.extend((10..30).map(|ms| sample(ms, false)));
+        samples.extend((30..40).map(|ms| sample(ms, true)));
+        assert_eq!(run(&samples), Some((1, 9, 9)));
+
+        // A process which was already running when the profile started.
+        assert_eq!(run(&samples[9..]), None);
+        assert_eq!(run(&[]), None);
+    }
+}
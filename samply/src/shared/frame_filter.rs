@@ -0,0 +1,249 @@
+use std::cell::Cell;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+use fxprof_processed_profile::StringHandle;
+use object::{Object, ObjectSymbol};
+use regex::Regex;
+use tracing::info;
+use wholesym::samply_symbols::object;
+
+/// A `--hide-library` or `--hide-symbol-prefix` rule.
+#[derive(Debug, Clone)]
+pub enum HideRule {
+    /// Hides all frames in the matching libraries.
+    Library(LibraryGlob),
+    /// Hides the frames in functions whose symbol name starts with the
+    /// prefix, e.g. "__asan_". Only symbols with a size are used.
+    SymbolPrefix(String),
+}
+
+impl fmt::Display for HideRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HideRule::Library(glob) => write!(f, "{}", glob.glob),
+            HideRule::SymbolPrefix(prefix) => write!(f, "{prefix}*"),
+        }
+    }
+}
+
+/// A glob with `*` and `?` wildcards, e.g. "libclang_rt.*". It's matched
+/// against the library's file name, or against its path if it contains a
+/// slash.
+#[derive(Debug, Clone)]
+pub struct LibraryGlob {
+    glob: String,
+    regex: Regex,
+}
+
+impl LibraryGlob {
+    pub fn new(glob: &str) -> Result<Self, String> {
+        if glob.is_empty() {
+            return Err("the library glob is empty".to_string());
+        }
+        let pattern = glob
+            .split('*')
+            .map(|part| {
+                part.split('?')
+                    .map(regex::escape)
+                    .collect::<Vec<_>>()
+                    .join(".")
+            })
+            .collect::<Vec<_>>()
+            .join(".*");
+        let regex = Regex::new(&format!("^{pattern}$")).map_err(|err| err.to_string())?;
+        Ok(Self {
+            glob: glob.to_string(),
+            regex,
+        })
+    }
+
+    pub fn matches(&self, name: &str, path: &str) -> bool {
+        match self.glob.contains('/') {
+            true => self.regex.is_match(path),
+            false => self.regex.is_match(name),
+        }
+    }
+}
+
+/// Removes frames from sample and marker stacks, e.g. the interceptors of
+/// ASan and TSan builds, which would otherwise bury the real code.
+///
+/// Runs of hidden frames are replaced by a single placeholder frame, so that
+/// the stack still shows that something was removed. Hidden frames at the
+/// leaf are dropped without a placeholder, so that the closest visible frame
+/// becomes the leaf.
+#[derive(Debug)]
+pub struct FrameFilter {
+    rules: Vec<HideRule>,
+    /// The number of hidden frames, by rule.
+    hidden_frame_counts: Vec<Cell<usize>>,
+}
+
+impl FrameFilter {
+    /// Returns None if there are no rules.
+    pub fn new(rules: Vec<HideRule>) -> Option<Self> {
+        if rules.is_empty() {
+            return None;
+        }
+        let hidden_frame_counts = rules.iter().map(|_| Cell::new(0)).collect();
+        Some(Self {
+            rules,
+            hidden_frame_counts,
+        })
+    }
+
+    pub fn rules(&self) -> &[HideRule] {
+        &self.rules
+    }
+
+    /// For libraries whose file couldn't be opened, only the library rules
+    /// can be applied.
+    pub fn hidden_ranges_for_library(
+        &self,
+        name: &str,
+        path: &str,
+    ) -> Option<Arc<HiddenFrameRanges>> {
+        let whole_library_rule = self.library_rule(name, path)?;
+        Some(Arc::new(HiddenFrameRanges::new(
+            Some(whole_library_rule),
+            Vec::new(),
+        )))
+    }
+
+    pub fn hidden_ranges_for_object<'data: 'file, 'file>(
+        &self,
+        file: &'file impl Object<'data, 'file>,
+        base_svma: u64,
+        name: &str,
+        path: &str,
+    ) -> Option<Arc<HiddenFrameRanges>> {
+        if let Some(ranges) = self.hidden_ranges_for_library(name, path) {
+            return Some(ranges);
+        }
+        let symbol_ranges: Vec<_> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.size() != 0)
+            .filter_map(|symbol| {
+                let rule = self.symbol_rule(symbol.name().ok()?)?;
+                let start = symbol.address().checked_sub(base_svma)?;
+                Some((start as u32..(start + symbol.size()) as u32, rule))
+            })
+            .collect();
+        if symbol_ranges.is_empty() {
+            return None;
+        }
+        Some(Arc::new(HiddenFrameRanges::new(None, symbol_ranges)))
+    }
+
+    fn library_rule(&self, name: &str, path: &str) -> Option<usize> {
+        self.rules.iter().position(|rule| match rule {
+            HideRule::Library(glob) => glob.matches(name, path),
+            HideRule::SymbolPrefix(_) => false,
+        })
+    }
+
+    fn symbol_rule(&self, symbol_name: &str) -> Option<usize> {
+        self.rules.iter().position(|rule| match rule {
+            HideRule::Library(_) => false,
+            HideRule::SymbolPrefix(prefix) => symbol_name.starts_with(prefix.as_str()),
+        })
+    }
+
+    pub fn record_hidden_frame(&self, rule: usize) {
+        let count = &self.hidden_frame_counts[rule];
+        count.set(count.get() + 1);
+    }
+
+    pub fn hidden_frame_count(&self, rule: usize) -> usize {
+        self.hidden_frame_counts[rule].get()
+    }
+
+    pub fn report(&self) {
+        for (index, rule) in self.rules.iter().enumerate() {
+            let option = match rule {
+                HideRule::Library(_) => "--hide-library",
+                HideRule::SymbolPrefix(_) => "--hide-symbol-prefix",
+            };
+            let count = self.hidden_frame_count(index);
+            info!(
+                option,
+                rule = %rule,
+                count,
+                "Hid {count} frames with {option} {rule}."
+            );
+        }
+    }
+}
+
+/// The parts of a library whose frames are hidden, with the index of the
+/// rule which hides them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HiddenFrameRanges {
+    whole_library_rule: Option<usize>,
+    /// Relative address ranges of the matching symbols.
+    symbol_ranges: Vec<(Range<u32>, usize)>,
+}
+
+impl HiddenFrameRanges {
+    pub fn new(whole_library_rule: Option<usize>, symbol_ranges: Vec<(Range<u32>, usize)>) -> Self {
+        Self {
+            whole_library_rule,
+            symbol_ranges,
+        }
+    }
+
+    pub fn rule_for(&self, relative_address: u32) -> Option<usize> {
+        self.whole_library_rule.or_else(|| {
+            self.symbol_ranges
+                .iter()
+                .find(|(range, _)| range.contains(&relative_address))
+                .map(|(_, rule)| *rule)
+        })
+    }
+}
+
+/// How hidden frames are converted.
+#[derive(Debug, Clone, Copy)]
+pub struct HiddenFrameConversion<'a> {
+    pub filter: &'a FrameFilter,
+    /// The placeholder label for each rule, e.g. "(hidden: libclang_rt.*)".
+    pub labels: &'a [StringHandle],
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_rules() {
+        let glob = LibraryGlob::new("libclang_rt.?san*").unwrap();
+        assert!(glob.matches(
+            "libclang_rt.asan-x86_64.so",
+            "/usr/lib/libclang_rt.asan-x86_64.so"
+        ));
+        assert!(!glob.matches("libclang_rt.ubsan.so", "/usr/lib/libclang_rt.ubsan.so"));
+        assert!(!glob.matches("libfoo.so", "/usr/lib/libclang_rt.asan/libfoo.so"));
+        let path_glob = LibraryGlob::new("/opt/sanitizers/*").unwrap();
+        assert!(path_glob.matches("libtsan.so", "/opt/sanitizers/libtsan.so"));
+
+        let filter = FrameFilter::new(vec![
+            HideRule::SymbolPrefix("__asan_".to_string()),
+            HideRule::Library(glob),
+        ])
+        .unwrap();
+        assert_eq!(filter.symbol_rule("__asan_memcpy"), Some(0));
+        assert_eq!(filter.symbol_rule("memcpy"), None);
+        assert_eq!(
+            filter.library_rule("libclang_rt.tsan.so", "/libclang_rt.tsan.so"),
+            Some(1)
+        );
+        assert_eq!(filter.rules()[0].to_string(), "__asan_*");
+
+        let ranges = HiddenFrameRanges::new(None, vec![(0x100..0x200, 0)]);
+        assert_eq!(ranges.rule_for(0x150), Some(0));
+        assert_eq!(ranges.rule_for(0x200), None);
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use fxprof_processed_profile::{
+    CategoryColor, CategoryHandle, CategoryPairHandle, Frame, FrameInfo, LibraryHandle,
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerTiming, Profile, ProfilerMarker, ThreadHandle, Timestamp,
+};
+use object::{Object, ObjectSymbol};
+use regex::Regex;
+use serde_json::json;
+use wholesym::samply_symbols::{demangle_any, object};
+
+/// How the garbage collector of a runtime can be recognized: by the names of
+/// its threads, and by the functions it runs on any thread.
+#[derive(Debug, Clone, Copy)]
+pub struct GcRuntime {
+    pub name: &'static str,
+    /// Matched against the whole thread name. Thread names are truncated to
+    /// 15 bytes on Linux.
+    pub thread_names: &'static str,
+    /// Prefixes of the demangled names of the GC's entry functions.
+    pub symbol_prefixes: &'static [&'static str],
+}
+
+/// The GC conventions of common runtimes.
+pub const GC_RUNTIMES: &[GcRuntime] = &[
+    GcRuntime {
+        name: "JVM",
+        thread_names: r"GC Thread#\d+|G1 .*|GC task thread.*|ParGC Thread.*|Shenandoah.*|ZWorker.*|ZDriver.*",
+        symbol_prefixes: &[
+            "G1ConcurrentMark",
+            "G1CollectedHeap::",
+            "G1ParScanThreadState::",
+            "G1YoungCollector::",
+            "G1FullCollector::",
+            "PSScavenge::",
+            "PSParallelCompact::",
+            "GenCollectedHeap::",
+            "ZCollectedHeap::",
+            "ShenandoahHeap::",
+        ],
+    },
+    GcRuntime {
+        name: "Go",
+        thread_names: r"gc bg worker.*",
+        symbol_prefixes: &[
+            "runtime.gcBgMarkWorker",
+            "runtime.gcDrain",
+            "runtime.gcStart",
+            "runtime.gcMarkDone",
+            "runtime.gcMarkTermination",
+            "runtime.markroot",
+            "runtime.scanobject",
+            "runtime.bgsweep",
+        ],
+    },
+    GcRuntime {
+        name: ".NET",
+        thread_names: r"\.NET (?:BGC|Server GC|GC).*",
+        symbol_prefixes: &[
+            "WKS::gc_heap::",
+            "SVR::gc_heap::",
+            "WKS::GCHeap::",
+            "SVR::GCHeap::",
+        ],
+    },
+];
+
+/// Samples are grouped into buckets of this length to find the times at
+/// which most of a process's samples are in the GC.
+const GC_PAUSE_BUCKET_NS: u64 = 10_000_000;
+
+/// Runs with fewer GC samples than this don't get a marker.
+const MIN_GC_RUN_SAMPLE_COUNT: usize = 2;
+
+/// Recognizes the samples of garbage collectors with the [`GC_RUNTIMES`]
+/// table, so that they can be put into a "GC" category when the samples are
+/// flushed to the profile.
+///
+/// A sample is in the GC if its thread is a GC thread, or if one of its
+/// frames is in a GC function. Like for
+/// [`RequestAttribution`](super::request_attribution::RequestAttribution),
+/// the GC functions of each library are found when it's loaded, so that
+/// stacks only need address range lookups.
+#[derive(Debug)]
+pub struct GcDetection {
+    thread_name_regexes: Vec<Regex>,
+    /// The longest identifier of each symbol prefix. Mangled names contain
+    /// it too, so symbols without any of them don't need to be demangled.
+    symbol_keywords: Vec<&'static str>,
+    ranges_by_lib: HashMap<LibraryHandle, Vec<(Range<u32>, usize)>>,
+    /// Whether to add interval markers for runs of GC samples, see
+    /// [`GcPauseDetector`].
+    detect_pauses: bool,
+}
+
+impl GcDetection {
+    pub fn new(detect_pauses: bool) -> Self {
+        Self {
+            thread_name_regexes: GC_RUNTIMES
+                .iter()
+                .map(|runtime| Regex::new(&format!("^(?:{})$", runtime.thread_names)).unwrap())
+                .collect(),
+            symbol_keywords: GC_RUNTIMES
+                .iter()
+                .flat_map(|runtime| runtime.symbol_prefixes)
+                .filter_map(|prefix| {
+                    prefix
+                        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .max_by_key(|identifier| identifier.len())
+                })
+                .collect(),
+            ranges_by_lib: HashMap::new(),
+            detect_pauses,
+        }
+    }
+
+    pub fn detects_pauses(&self) -> bool {
+        self.detect_pauses
+    }
+
+    /// Finds the GC functions of the library.
+    pub fn add_object<'data: 'file, 'file>(
+        &mut self,
+        lib_handle: LibraryHandle,
+        file: &'file impl Object<'data, 'file>,
+        base_svma: u64,
+    ) {
+        let mut symbol_ranges = Vec::new();
+        for symbol in file.symbols().chain(file.dynamic_symbols()) {
+            let Ok(name) = symbol.name() else { continue };
+            if symbol.size() == 0 || !self.symbol_keywords.iter().any(|k| name.contains(k)) {
+                continue;
+            }
+            let Some(start) = symbol.address().checked_sub(base_svma) else {
+                continue;
+            };
+            if let Some(runtime) = runtime_for_symbol(&demangle_any(name)) {
+                symbol_ranges.push((start as u32..(start + symbol.size()) as u32, runtime));
+            }
+        }
+        if !symbol_ranges.is_empty() {
+            self.ranges_by_lib.insert(lib_handle, symbol_ranges);
+        }
+    }
+
+    /// Returns the index of the runtime in [`GC_RUNTIMES`] whose GC threads
+    /// have names like this.
+    pub fn runtime_for_thread_name(&self, thread_name: &str) -> Option<usize> {
+        self.thread_name_regexes
+            .iter()
+            .position(|regex| regex.is_match(thread_name))
+    }
+
+    /// Returns the runtime of the outermost GC function on the stack.
+    pub fn runtime_for_stack(&self, mut frames: impl Iterator<Item = FrameInfo>) -> Option<usize> {
+        if self.ranges_by_lib.is_empty() {
+            return None;
+        }
+        frames.find_map(|frame| {
+            let (lib_handle, address) = match frame.frame {
+                Frame::RelativeAddressFromInstructionPointer(lib_handle, address) => {
+                    (lib_handle, address)
+                }
+                // Return addresses point after the call instruction.
+                Frame::RelativeAddressFromReturnAddress(lib_handle, address) => {
+                    (lib_handle, address.checked_sub(1)?)
+                }
+                _ => return None,
+            };
+            self.ranges_by_lib
+                .get(&lib_handle)?
+                .iter()
+                .find(|(range, _)| range.contains(&address))
+                .map(|(_, runtime)| *runtime)
+        })
+    }
+}
+
+fn runtime_for_symbol(symbol_name: &str) -> Option<usize> {
+    GC_RUNTIMES.iter().position(|runtime| {
+        runtime
+            .symbol_prefixes
+            .iter()
+            .any(|prefix| symbol_name.starts_with(prefix))
+    })
+}
+
+/// How GC samples are categorized: all of their frames get the subcategory
+/// of their runtime in the "GC" category, which is only added to the profile
+/// once the first GC sample is found.
+#[derive(Debug)]
+pub struct GcConversion<'a> {
+    pub detection: &'a GcDetection,
+    category_pairs: Option<Vec<CategoryPairHandle>>,
+}
+
+impl<'a> GcConversion<'a> {
+    pub fn new(detection: &'a GcDetection) -> Self {
+        Self {
+            detection,
+            category_pairs: None,
+        }
+    }
+
+    pub fn category_pair(&mut self, runtime: usize, profile: &mut Profile) -> CategoryPairHandle {
+        let category_pairs = self.category_pairs.get_or_insert_with(|| {
+            let category: CategoryHandle = profile.add_category("GC", CategoryColor::Orange);
+            GC_RUNTIMES
+                .iter()
+                .map(|runtime| profile.add_subcategory(category, runtime.name))
+                .collect()
+        });
+        category_pairs[runtime]
+    }
+}
+
+/// Finds the times at which a process is mostly collecting garbage, from
+/// the samples of all of its threads, and adds an interval marker for each
+/// of them to the process's main thread.
+///
+/// The samples are grouped into buckets of [`GC_PAUSE_BUCKET_NS`], and runs
+/// of consecutive buckets in which more than half of the samples are in the
+/// GC become a marker from the first to the last GC sample of the run. The
+/// marker is a "GC pause" if all of the run's samples are in the GC, i.e. the
+/// mutator threads didn't run, and a "GC phase" otherwise.
+#[derive(Debug, Default)]
+pub struct GcPauseDetector {
+    /// A thread of the process.
+    thread: Option<ThreadHandle>,
+    /// (timestamp_mono, timestamp, runtime if the sample is in the GC)
+    samples: Vec<(u64, Timestamp, Option<usize>)>,
+}
+
+impl GcPauseDetector {
+    pub fn add_sample(
+        &mut self,
+        timestamp_mono: u64,
+        timestamp: Timestamp,
+        thread: ThreadHandle,
+        runtime: Option<usize>,
+    ) {
+        self.thread.get_or_insert(thread);
+        self.samples.push((timestamp_mono, timestamp, runtime));
+    }
+
+    pub fn add_markers(mut self, profile: &mut Profile) {
+        let Some(main_thread) = self
+            .thread
+            .and_then(|thread| profile.main_thread_of_process(thread))
+        else {
+            return;
+        };
+        self.samples
+            .sort_by_key(|(timestamp_mono, ..)| *timestamp_mono);
+        for run in gc_runs(&self.samples) {
+            run.add_marker(profile, main_thread);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct GcRun {
+    start: Option<(u64, Timestamp)>,
+    end: Option<(u64, Timestamp)>,
+    gc_sample_count: usize,
+    other_sample_count: usize,
+    sample_counts_by_runtime: HashMap<usize, usize>,
+}
+
+impl GcRun {
+    fn add_marker(&self, profile: &mut Profile, main_thread: ThreadHandle) {
+        let (Some((start_mono, start)), Some((end_mono, end))) = (self.start, self.end) else {
+            return;
+        };
+        if self.gc_sample_count < MIN_GC_RUN_SAMPLE_COUNT {
+            return;
+        }
+        let runtime = self
+            .sample_counts_by_runtime
+            .iter()
+            .max_by_key(|(runtime, count)| (**count, std::cmp::Reverse(**runtime)))
+            .map_or(0, |(runtime, _)| *runtime);
+        let kind = match self.other_sample_count {
+            0 => "pause",
+            _ => "phase",
+        };
+        let duration_ms = (end_mono - start_mono) as f64 / 1_000_000.0;
+        profile.add_marker(
+            main_thread,
+            &format!("GC {kind} (~{duration_ms:.0}ms)"),
+            GcPauseMarker {
+                runtime: GC_RUNTIMES[runtime].name,
+                gc_sample_count: self.gc_sample_count,
+                other_sample_count: self.other_sample_count,
+            },
+            MarkerTiming::Interval(start, end),
+        );
+    }
+}
+
+/// Groups the samples, sorted by time, into runs of GC-dominated buckets.
+fn gc_runs(samples: &[(u64, Timestamp, Option<usize>)]) -> Vec<GcRun> {
+    let mut runs = Vec::new();
+    let mut current_run: Option<GcRun> = None;
+    let mut previous_bucket = None;
+    let mut rest = samples;
+    while let Some(&(first_timestamp_mono, ..)) = rest.first() {
+        let bucket_index = first_timestamp_mono / GC_PAUSE_BUCKET_NS;
+        let bucket_len = rest
+            .iter()
+            .take_while(|(timestamp_mono, ..)| timestamp_mono / GC_PAUSE_BUCKET_NS == bucket_index)
+            .count();
+        let (bucket, remaining) = rest.split_at(bucket_len);
+        rest = remaining;
+        let gc_sample_count = bucket
+            .iter()
+            .filter(|(.., runtime)| runtime.is_some())
+            .count();
+        let is_gc_dominated = gc_sample_count * 2 > bucket.len();
+        let continues_run = previous_bucket == Some(bucket_index.wrapping_sub(1));
+        previous_bucket = Some(bucket_index);
+        if !is_gc_dominated || !continues_run {
+            runs.extend(current_run.take());
+        }
+        if !is_gc_dominated {
+            continue;
+        }
+        let run = current_run.get_or_insert_with(GcRun::default);
+        for &(timestamp_mono, timestamp, runtime) in bucket {
+            let Some(runtime) = runtime else {
+                run.other_sample_count += 1;
+                continue;
+            };
+            run.start.get_or_insert((timestamp_mono, timestamp));
+            run.end = Some((timestamp_mono, timestamp));
+            run.gc_sample_count += 1;
+            *run.sample_counts_by_runtime.entry(runtime).or_default() += 1;
+        }
+    }
+    runs.extend(current_run);
+    runs
+}
+
+#[derive(Debug, Clone)]
+pub struct GcPauseMarker {
+    runtime: &'static str,
+    gc_sample_count: usize,
+    other_sample_count: usize,
+}
+
+impl ProfilerMarker for GcPauseMarker {
+    const MARKER_TYPE_NAME: &'static str = "GcPause";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "runtime": self.runtime,
+            "gcSamples": self.gc_sample_count,
+            "otherSamples": self.other_sample_count,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.name}"),
+            tooltip_label: Some("{marker.name} ({marker.data.runtime})"),
+            table_label: Some("{marker.name} ({marker.data.runtime})"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "runtime",
+                    label: "Runtime",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "gcSamples",
+                    label: "GC samples",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "otherSamples",
+                    label: "Other samples",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recognizes_gc_threads_and_functions() {
+        let detection = GcDetection::new(false);
+        let runtime_name = |runtime: Option<usize>| runtime.map(|i| GC_RUNTIMES[i].name);
+        assert_eq!(
+            runtime_name(detection.runtime_for_thread_name("GC Thread#3")),
+            Some("JVM")
+        );
+        assert_eq!(
+            runtime_name(detection.runtime_for_thread_name("G1 Conc#0")),
+            Some("JVM")
+        );
+        assert_eq!(
+            runtime_name(detection.runtime_for_thread_name(".NET BGC")),
+            Some(".NET")
+        );
+        assert_eq!(detection.runtime_for_thread_name("java"), None);
+        assert_eq!(detection.runtime_for_thread_name("My GC Thread#3"), None);
+
+        assert_eq!(
+            runtime_name(runtime_for_symbol("runtime.gcBgMarkWorker")),
+            Some("Go")
+        );
+        assert_eq!(
+            runtime_name(runtime_for_symbol("G1ConcurrentMark::mark_from_roots()")),
+            Some("JVM")
+        );
+        assert_eq!(
+            runtime_name(runtime_for_symbol("WKS::gc_heap::mark_phase(int)")),
+            Some(".NET")
+        );
+        assert_eq!(runtime_for_symbol("runtime.mallocgc"), None);
+        assert!(detection.symbol_keywords.contains(&"gcBgMarkWorker"));
+        assert!(detection.symbol_keywords.contains(&"gc_heap"));
+    }
+
+    #[test]
+    fn finds_gc_dominated_runs() {
+        const MS: u64 = 1_000_000;
+        let sample = |ms: u64, runtime: Option<usize>| {
+            (
+                ms * MS,
+                Timestamp::from_nanos_since_reference(ms * MS),
+                runtime,
+            )
+        };
+        // A stop-the-world pause from 20 to 38ms, and a concurrent phase from
+        // 60 to 68ms, in which a mutator thread keeps running. The GC sample
+        // at 95ms is alone in its bucket, which is too short for a run.
+        let mut samples = vec![sample(1, None)];
+        samples.extend((20..40).step_by(2).map(|ms| sample(ms, Some(0))));
+        samples.extend((60..70).step_by(2).map(|ms| sample(ms, Some(0))));
+        samples.push(sample(65, None));
+        samples.push(sample(85, None));
+        samples.push(sample(95, Some(1)));
+
+        let runs: Vec<_> = gc_runs(&samples)
+            .into_iter()
+            .filter(|run| run.gc_sample_count >= MIN_GC_RUN_SAMPLE_COUNT)
+            .map(|run| {
+                (
+                    run.start.unwrap().0 / MS,
+                    run.end.unwrap().0 / MS,
+                    run.other_sample_count,
+                )
+            })
+            .collect();
+        assert_eq!(runs, vec![(20, 38, 0), (60, 68, 1)]);
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use fxprof_processed_profile::{EmbeddedCode, Frame, FrameInfo, LibraryHandle, Profile};
+use linux_perf_data::jitdump::{JitDumpReader, JitDumpRecord, JitDumpRecordType};
+use tracing::info;
+
+use super::types::FastHashMap;
+
+/// The default budget for `--embed-jit-code`, in code bytes.
+pub const DEFAULT_EMBEDDED_JIT_CODE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// JIT functions with less than this share of the total sample weight aren't
+/// embedded.
+const MIN_WEIGHT_SHARE: f64 = 0.001;
+
+/// The ELF machine numbers of the architectures which the assembly view can
+/// decode, with the arch names which the `/asm/v1` API uses.
+const ARCH_NAMES_BY_ELF_MACHINE: &[(u32, &str)] =
+    &[(3, "x86"), (40, "arm"), (62, "x86_64"), (183, "arm64")];
+
+/// For `--embed-jit-code`: Finds the hottest JIT functions, and embeds their
+/// code bytes into the profile, so that the assembly view works for them
+/// after the jitdump files are gone.
+///
+/// Each sample's weight goes to the JIT function of its innermost frame in a
+/// library with a symbol table, i.e. in a jitdump or perf map library. The
+/// code bytes are read from the jitdump files again at the end of the
+/// conversion, hottest function first, until the budget is used up. perf map
+/// libraries don't have code bytes and are skipped.
+#[derive(Debug)]
+pub struct JitCodeEmbedding {
+    max_bytes: u64,
+    weight_by_address: FastHashMap<(LibraryHandle, u32), i64>,
+    total_weight: i64,
+}
+
+/// A JIT function which was selected for embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct HotFunction {
+    relative_address: u32,
+    size: u32,
+}
+
+impl JitCodeEmbedding {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            weight_by_address: FastHashMap::default(),
+            total_weight: 0,
+        }
+    }
+
+    /// Called for each sample, with its frames from the root to the leaf.
+    pub fn add_sample(
+        &mut self,
+        frames: impl Iterator<Item = FrameInfo>,
+        weight: i32,
+        profile: &Profile,
+    ) {
+        self.total_weight += i64::from(weight);
+        let innermost_jit_frame = frames
+            .filter_map(|frame| match frame.frame {
+                Frame::RelativeAddressFromInstructionPointer(lib, address) => Some((lib, address)),
+                Frame::RelativeAddressFromReturnAddress(lib, address) => {
+                    Some((lib, address.saturating_sub(1)))
+                }
+                _ => None,
+            })
+            .filter(|&(lib, _)| profile.lib_info(lib).symbol_table.is_some())
+            .last();
+        if let Some(lib_and_address) = innermost_jit_frame {
+            *self.weight_by_address.entry(lib_and_address).or_default() += i64::from(weight);
+        }
+    }
+
+    /// The functions to embed, hottest first, within the budget.
+    fn hot_functions(&self, profile: &Profile) -> Vec<(LibraryHandle, HotFunction)> {
+        let mut weight_by_function: FastHashMap<(LibraryHandle, HotFunction), i64> =
+            FastHashMap::default();
+        for (&(lib, address), &weight) in &self.weight_by_address {
+            let Some(symbol_table) = profile.lib_info(lib).symbol_table.as_deref() else { continue };
+            let Some(symbol) = symbol_table.lookup(address) else { continue };
+            let Some(size) = symbol.size else { continue };
+            let function = HotFunction {
+                relative_address: symbol.address,
+                size,
+            };
+            *weight_by_function.entry((lib, function)).or_default() += weight;
+        }
+        let min_weight = (self.total_weight as f64 * MIN_WEIGHT_SHARE).max(1.0);
+        let mut functions: Vec<_> = weight_by_function
+            .into_iter()
+            .filter(|&(_, weight)| weight as f64 >= min_weight)
+            .collect();
+        functions.sort_by(|(a, a_weight), (b, b_weight)| b_weight.cmp(a_weight).then(a.cmp(b)));
+
+        let mut remaining_bytes = self.max_bytes;
+        functions
+            .into_iter()
+            .map(|(function, _)| function)
+            .filter(|(_, function)| {
+                let fits = u64::from(function.size) <= remaining_bytes;
+                if fits {
+                    remaining_bytes -= u64::from(function.size);
+                }
+                fits
+            })
+            .collect()
+    }
+
+    /// Reads the code of the hot functions from their jitdump files and adds
+    /// it to the profile.
+    pub fn finish(self, profile: &mut Profile) {
+        let mut functions_by_lib: BTreeMap<LibraryHandle, BTreeSet<HotFunction>> = BTreeMap::new();
+        for (lib, function) in self.hot_functions(profile) {
+            functions_by_lib.entry(lib).or_default().insert(function);
+        }
+        let mut function_count = 0;
+        let mut byte_count = 0;
+        for (lib, functions) in functions_by_lib {
+            let path = profile.lib_info(lib).path.clone();
+            let Some(code) = read_jitdump_code(Path::new(&path), &functions) else { continue };
+            function_count += code.ranges.len();
+            byte_count += code.byte_count();
+            profile.set_lib_code(lib, Arc::new(code));
+        }
+        if function_count != 0 {
+            info!(
+                function_count,
+                byte_count,
+                "Embedded the code of {function_count} hot JIT functions ({byte_count} bytes) into the profile."
+            );
+        }
+    }
+}
+
+/// Reads the code bytes of `functions` from the jitdump file at `path`. The
+/// relative addresses are the ones which
+/// [`JitDumpManager`](super::jitdump_manager::JitDumpManager) assigns, i.e.
+/// the sum of the code sizes of all earlier functions in the file.
+fn read_jitdump_code(path: &Path, functions: &BTreeSet<HotFunction>) -> Option<EmbeddedCode> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut reader = JitDumpReader::new(file).ok()?;
+    let elf_machine = reader.header().elf_machine_arch;
+    let arch = ARCH_NAMES_BY_ELF_MACHINE
+        .iter()
+        .find(|(machine, _)| *machine == elf_machine)?
+        .1;
+    let wanted: BTreeMap<u32, u32> = functions
+        .iter()
+        .map(|function| (function.relative_address, function.size))
+        .collect();
+    let mut ranges = Vec::new();
+    let mut cumulative_address = 0u32;
+    while let Ok(Some(header)) = reader.next_record_header() {
+        if header.record_type != JitDumpRecordType::JIT_CODE_LOAD {
+            if let Ok(true) = reader.skip_next_record() {
+                continue;
+            }
+            break;
+        }
+        let Ok(Some(raw_record)) = reader.next_record() else { break };
+        let Ok(JitDumpRecord::CodeLoad(record)) = raw_record.parse() else { continue };
+        let relative_address = cumulative_address;
+        let code_size = record.code_bytes.len() as u32;
+        cumulative_address += code_size;
+        if wanted.get(&relative_address) == Some(&code_size) {
+            ranges.push((relative_address, record.code_bytes.as_slice().into_owned()));
+        }
+    }
+    (!ranges.is_empty()).then(|| EmbeddedCode {
+        arch: arch.to_string(),
+        ranges,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use debugid::DebugId;
+    use fxprof_processed_profile::{
+        CategoryColor, FrameFlags, LibraryInfo, ReferenceTimestamp, SamplingInterval, Symbol,
+        SymbolTable,
+    };
+
+    use super::*;
+
+    fn frame(profile: &mut Profile, frame: Frame) -> FrameInfo {
+        FrameInfo {
+            frame,
+            category_pair: profile.add_category("JIT", CategoryColor::Green).into(),
+            flags: FrameFlags::empty(),
+        }
+    }
+
+    #[test]
+    fn hottest_functions_are_embedded_first() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_system_time(SystemTime::now()),
+            SamplingInterval::from_millis(1),
+        );
+        let symbols: Vec<Symbol> = [(0, 0x40, "cold"), (0x40, 0x30, "warm"), (0x70, 0x20, "hot")]
+            .iter()
+            .map(|&(address, size, name)| Symbol {
+                address,
+                size: Some(size),
+                name: name.to_string(),
+            })
+            .collect();
+        let jit_lib = profile.add_lib(LibraryInfo {
+            name: "jit-1234.dump".to_string(),
+            debug_name: "jit-1234.dump".to_string(),
+            path: "/tmp/jit-1234.dump".to_string(),
+            debug_path: "/tmp/jit-1234.dump".to_string(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: Some(Arc::new(SymbolTable::new(symbols))),
+        });
+        let native_lib = LibraryInfo {
+            symbol_table: None,
+            name: "libc.so.6".to_string(),
+            ..profile.lib_info(jit_lib).clone()
+        };
+        let native_lib = profile.add_lib(native_lib);
+
+        let mut embedding = JitCodeEmbedding::new(0x60);
+        let caller = frame(
+            &mut profile,
+            Frame::RelativeAddressFromReturnAddress(jit_lib, 0x48),
+        );
+        for (address, weight) in [(0x10, 1), (0x78, 5), (0x44, 3)] {
+            let leaf = frame(
+                &mut profile,
+                Frame::RelativeAddressFromInstructionPointer(jit_lib, address),
+            );
+            embedding.add_sample([caller.clone(), leaf].into_iter(), weight, &profile);
+        }
+        // The weight of samples in native code goes to their JIT caller.
+        let native_leaf = frame(
+            &mut profile,
+            Frame::RelativeAddressFromInstructionPointer(native_lib, 0x1000),
+        );
+        embedding.add_sample([caller, native_leaf].into_iter(), 2, &profile);
+
+        // "hot" (weight 5) and "warm" (weight 5) fit into the budget, "cold"
+        // doesn't.
+        let functions: Vec<_> = embedding
+            .hot_functions(&profile)
+            .into_iter()
+            .map(|(_, function)| function.relative_address)
+            .collect();
+        assert_eq!(functions, vec![0x40, 0x70]);
+    }
+}
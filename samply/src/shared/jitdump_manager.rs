@@ -1,8 +1,11 @@
 use fxprof_processed_profile::{
     LibraryHandle, MarkerTiming, Profile, Symbol, SymbolTable, ThreadHandle,
 };
-use linux_perf_data::jitdump::{JitDumpReader, JitDumpRecord, JitDumpRecordType};
+use linux_perf_data::jitdump::{JitDumpHeader, JitDumpReader, JitDumpRecord, JitDumpRecordType};
+use tracing::{info, warn};
 
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -12,27 +15,137 @@ use super::jit_function_recycler::JitFunctionRecycler;
 use super::lib_mappings::{
     LibMappingAdd, LibMappingInfo, LibMappingMove, LibMappingOp, LibMappingOpQueue,
 };
+use super::path_map::PathMap;
 use super::timestamp_converter::TimestampConverter;
 use super::utils::open_file_with_fallback;
 
+/// The jitdump flag which says that the record timestamps come from the
+/// architecture's timestamp counter (e.g. the TSC on x86_64) rather than from
+/// CLOCK_MONOTONIC.
+const JITDUMP_FLAGS_ARCH_TIMESTAMP: u64 = 1;
+
+/// [`JitDumpManager::poll`] reads the pending jitdump records on every this
+/// many calls. The records only need to be read before the process's samples
+/// are resolved, because their mapping ops are ordered by timestamp, so
+/// reading them on every sample would only cost time.
+const POLL_INTERVAL: u32 = 256;
+
+/// The clock which a set of timestamps was taken with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampClock {
+    /// CLOCK_MONOTONIC, which is what jitdump files use by default.
+    Monotonic,
+    /// The architecture's timestamp counter.
+    ArchTimestamp,
+    /// Any other clock, e.g. perf's default clock or CLOCK_MONOTONIC_RAW.
+    Other,
+}
+
+impl TimestampClock {
+    fn for_jitdump_header(header: &JitDumpHeader) -> Self {
+        if header.flags & JITDUMP_FLAGS_ARCH_TIMESTAMP != 0 {
+            TimestampClock::ArchTimestamp
+        } else {
+            TimestampClock::Monotonic
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingJitDump {
+    path: PathBuf,
+    fallback_dir: Option<PathBuf>,
+    path_map: Option<Arc<PathMap>>,
+    /// The sample timestamp at which the process mapped the jitdump file, if known.
+    mapping_timestamp: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct JitDumpManager {
-    pending_jitdump_paths: Vec<(PathBuf, Option<PathBuf>)>,
+    pending_jitdump_paths: Vec<PendingJitDump>,
+    /// The file names of all added jitdump paths, so that we don't process the
+    /// same jitdump file twice if we know about it from several sources.
+    added_jitdump_file_names: HashSet<OsString>,
     processors: Vec<SingleJitDumpProcessor>,
     main_thread_handle: ThreadHandle,
+    /// The clock of the sample timestamps.
+    sample_clock: TimestampClock,
+    /// The timestamp of the process's first sample, for estimating the clock
+    /// offset of jitdump files whose mapping timestamp isn't known.
+    first_sample_timestamp: Option<u64>,
+    /// Whether any jitdump path was added. Most processes don't have one, and
+    /// this is all they check per sample.
+    has_jitdumps: bool,
+    /// The number of [`JitDumpManager::poll`] calls until the next read.
+    polls_until_read: u32,
 }
 
 impl JitDumpManager {
-    pub fn new_for_process(main_thread_handle: ThreadHandle) -> Self {
+    pub fn new_for_process(main_thread_handle: ThreadHandle, sample_clock: TimestampClock) -> Self {
         JitDumpManager {
             pending_jitdump_paths: Vec::new(),
+            added_jitdump_file_names: HashSet::new(),
             processors: Vec::new(),
             main_thread_handle,
+            sample_clock,
+            first_sample_timestamp: None,
+            has_jitdumps: false,
+            polls_until_read: 0,
+        }
+    }
+
+    pub fn has_jitdumps(&self) -> bool {
+        self.has_jitdumps
+    }
+
+    /// `mapping_timestamp` is the sample timestamp at which the process mapped
+    /// the jitdump file, if known. It's used to line up the jitdump timestamps
+    /// with the sample timestamps if the two were taken with different clocks.
+    pub fn add_jitdump_path(
+        &mut self,
+        path: impl Into<PathBuf>,
+        fallback_dir: Option<PathBuf>,
+        path_map: Option<Arc<PathMap>>,
+        mapping_timestamp: Option<u64>,
+    ) {
+        let path = path.into();
+        if let Some(file_name) = path.file_name() {
+            if !self.added_jitdump_file_names.insert(file_name.to_owned()) {
+                return;
+            }
         }
+        self.pending_jitdump_paths.push(PendingJitDump {
+            path,
+            fallback_dir,
+            path_map,
+            mapping_timestamp,
+        });
+        self.has_jitdumps = true;
+        // Open the new file at the next poll.
+        self.polls_until_read = 0;
     }
 
-    pub fn add_jitdump_path(&mut self, path: impl Into<PathBuf>, fallback_dir: Option<PathBuf>) {
-        self.pending_jitdump_paths.push((path.into(), fallback_dir));
+    /// Called for each sample or other record of the process, with the
+    /// sample timestamp if there is one. Reads the pending records on every
+    /// [`POLL_INTERVAL`]th call, and at the first call after a new jitdump
+    /// path was added. [`JitDumpManager::finish`] reads the rest.
+    pub fn poll(
+        &mut self,
+        timestamp: Option<u64>,
+        jit_category_manager: &mut JitCategoryManager,
+        profile: &mut Profile,
+        recycler: Option<&mut JitFunctionRecycler>,
+        timestamp_converter: &TimestampConverter,
+    ) {
+        if let Some(timestamp) = timestamp {
+            self.first_sample_timestamp.get_or_insert(timestamp);
+        }
+        if self.polls_until_read != 0 {
+            self.polls_until_read -= 1;
+            return;
+        }
+        self.polls_until_read = POLL_INTERVAL - 1;
+        self.process_pending_records(jit_category_manager, profile, recycler, timestamp_converter);
     }
 
     pub fn process_pending_records(
@@ -42,17 +155,27 @@ impl JitDumpManager {
         mut recycler: Option<&mut JitFunctionRecycler>,
         timestamp_converter: &TimestampConverter,
     ) {
-        self.pending_jitdump_paths.retain_mut(|(path, fallback_dir)| {
-            fn jitdump_reader_for_path(path: &Path, fallback_dir: Option<&Path>) -> Option<(JitDumpReader<std::fs::File>, PathBuf)> {
-                let (file, path) = open_file_with_fallback(path, fallback_dir).ok()?;
+        self.pending_jitdump_paths.retain_mut(|pending| {
+            fn jitdump_reader_for_path(path: &Path, fallback_dir: Option<&Path>, path_map: Option<&PathMap>) -> Option<(JitDumpReader<std::fs::File>, PathBuf)> {
+                let (file, path) = open_file_with_fallback(path, fallback_dir, path_map).ok()?;
                 let reader = JitDumpReader::new(file).ok()?;
                 Some((reader, path))
             }
-            let Some((reader, actual_path)) = jitdump_reader_for_path(path, fallback_dir.as_deref()) else { return true };
+            let Some((reader, actual_path)) = jitdump_reader_for_path(&pending.path, pending.fallback_dir.as_deref(), pending.path_map.as_deref()) else { return true };
             let lib_handle =
                 crate::shared::utils::lib_handle_for_jitdump(&actual_path, reader.header(), profile);
-            self.processors
-                .push(SingleJitDumpProcessor::new(reader, lib_handle, self.main_thread_handle));
+            let clock_offset = ClockOffset::estimate(
+                &actual_path,
+                reader.header(),
+                self.sample_clock,
+                pending.mapping_timestamp.or(self.first_sample_timestamp),
+            );
+            self.processors.push(SingleJitDumpProcessor::new(
+                reader,
+                lib_handle,
+                self.main_thread_handle,
+                clock_offset,
+            ));
             false // "Do not retain", i.e. remove from pending_jitdump_paths
         });
 
@@ -66,6 +189,31 @@ impl JitDumpManager {
         }
     }
 
+    /// The function name of the JIT_CODE_LOAD record with this code index, in
+    /// the records which have been processed so far. This is for the
+    /// `jitted-<pid>-<code_index>.so` files of `perf inject --jit`, which
+    /// don't always have the name.
+    pub fn function_name(&self, code_index: u64) -> Option<&str> {
+        self.processors
+            .iter()
+            .find_map(|processor| processor.function_names_by_code_index.get(&code_index))
+            .map(String::as_str)
+    }
+
+    /// The sizes of the manager's state, for the `--watchdog` diagnostics.
+    pub fn metrics(&self) -> JitDumpMetrics {
+        JitDumpMetrics {
+            file_count: self.processors.len(),
+            pending_file_count: self.pending_jitdump_paths.len(),
+            function_count: self.processors.iter().map(|p| p.symbols.len()).sum(),
+            mapping_op_count: self
+                .processors
+                .iter()
+                .map(|p| p.lib_mapping_ops.len())
+                .sum(),
+        }
+    }
+
     pub fn finish(
         mut self,
         jit_category_manager: &mut JitCategoryManager,
@@ -81,6 +229,77 @@ impl JitDumpManager {
     }
 }
 
+/// See [`JitDumpManager::metrics`].
+#[derive(Debug, Clone, Default)]
+pub struct JitDumpMetrics {
+    /// The jitdump files which are being read.
+    pub file_count: usize,
+    /// The jitdump files which couldn't be opened yet.
+    pub pending_file_count: usize,
+    pub function_count: usize,
+    pub mapping_op_count: usize,
+}
+
+/// The offset which is added to the timestamps of a jitdump file to get sample
+/// timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ClockOffset(i64);
+
+impl ClockOffset {
+    /// Jitdump files are usually written with CLOCK_MONOTONIC, but the samples
+    /// can have been recorded with a different clock, e.g. with perf's default
+    /// clock or with `perf record --clockid`. In that case the timestamps of the
+    /// JIT functions are shifted against the samples, and samples shortly after
+    /// a function was compiled don't find it.
+    ///
+    /// jitdump files don't contain a correlation between the two clocks, so we
+    /// estimate the offset from a pair of timestamps which were taken at roughly
+    /// the same time: The header timestamp, which the runtime takes when it
+    /// creates the file, and `reference_timestamp`, which is the sample timestamp
+    /// at which the file was mapped, or, if that's not known, the timestamp of the
+    /// process's first sample.
+    fn estimate(
+        path: &Path,
+        header: &JitDumpHeader,
+        sample_clock: TimestampClock,
+        reference_timestamp: Option<u64>,
+    ) -> Self {
+        let jitdump_clock = TimestampClock::for_jitdump_header(header);
+        if jitdump_clock == sample_clock {
+            return ClockOffset(0);
+        }
+        let Some(reference_timestamp) = reference_timestamp else {
+            warn!(
+                path = %path.display(),
+                ?jitdump_clock,
+                ?sample_clock,
+                "The jitdump file {} uses a different clock ({jitdump_clock:?}) than the samples ({sample_clock:?}), and its timestamps could not be corrected.",
+                path.display()
+            );
+            return ClockOffset(0);
+        };
+        let offset = reference_timestamp as i64 - header.timestamp as i64;
+        info!(
+            path = %path.display(),
+            ?jitdump_clock,
+            ?sample_clock,
+            offset_ns = offset,
+            "The jitdump file {} uses a different clock ({jitdump_clock:?}) than the samples ({sample_clock:?}). Shifting its timestamps by {:.3}ms.",
+            path.display(),
+            offset as f64 / 1_000_000.0
+        );
+        ClockOffset(offset)
+    }
+
+    fn apply(self, jitdump_timestamp: u64) -> u64 {
+        if self.0 >= 0 {
+            jitdump_timestamp.saturating_add(self.0 as u64)
+        } else {
+            jitdump_timestamp.saturating_sub(self.0.unsigned_abs())
+        }
+    }
+}
+
 #[derive(Debug)]
 struct SingleJitDumpProcessor {
     /// Some() until a JIT_CODE_CLOSE record is encountered.
@@ -88,6 +307,8 @@ struct SingleJitDumpProcessor {
     lib_handle: LibraryHandle,
     lib_mapping_ops: LibMappingOpQueue,
     symbols: Vec<Symbol>,
+    /// The function names of the JIT_CODE_LOAD records, by code index.
+    function_names_by_code_index: HashMap<u64, String>,
     main_thread_handle: ThreadHandle,
 
     /// The relative_address of the next JIT function.
@@ -98,6 +319,9 @@ struct SingleJitDumpProcessor {
     /// relative address is the sum of the `code_size`s of all the `JIT_CODE_LOAD`
     /// entries that came before it in the file.
     cumulative_address: u32,
+
+    /// Converts the timestamps in the file into sample timestamps.
+    clock_offset: ClockOffset,
 }
 
 impl SingleJitDumpProcessor {
@@ -105,14 +329,17 @@ impl SingleJitDumpProcessor {
         reader: JitDumpReader<std::fs::File>,
         lib_handle: LibraryHandle,
         main_thread_handle: ThreadHandle,
+        clock_offset: ClockOffset,
     ) -> Self {
         Self {
             reader: Some(reader),
             lib_handle,
             lib_mapping_ops: Default::default(),
             symbols: Default::default(),
+            function_names_by_code_index: Default::default(),
             main_thread_handle,
             cumulative_address: 0,
+            clock_offset,
         }
     }
 
@@ -144,6 +371,7 @@ impl SingleJitDumpProcessor {
                 }
             }
             let Ok(Some(raw_jitdump_record)) = reader.next_record() else { break };
+            let timestamp_mono = self.clock_offset.apply(raw_jitdump_record.timestamp);
             match raw_jitdump_record.parse() {
                 Ok(JitDumpRecord::CodeLoad(record)) => {
                     let start_avma = record.code_addr;
@@ -159,9 +387,11 @@ impl SingleJitDumpProcessor {
                         size: Some(record.code_bytes.len() as u32),
                         name: symbol_name.to_owned(),
                     });
+                    self.function_names_by_code_index
+                        .insert(record.code_index, symbol_name.to_owned());
 
                     let main_thread = self.main_thread_handle;
-                    let timestamp = timestamp_converter.convert_time(raw_jitdump_record.timestamp);
+                    let timestamp = timestamp_converter.convert_time(timestamp_mono);
                     let timing = MarkerTiming::Instant(timestamp);
                     profile.add_marker(
                         main_thread,
@@ -186,7 +416,7 @@ impl SingleJitDumpProcessor {
                     let (category, js_frame) =
                         jit_category_manager.classify_jit_symbol(symbol_name, profile);
                     self.lib_mapping_ops.push(
-                        raw_jitdump_record.timestamp,
+                        timestamp_mono,
                         LibMappingOp::Add(LibMappingAdd {
                             start_avma,
                             end_avma,
@@ -198,7 +428,7 @@ impl SingleJitDumpProcessor {
                 }
                 Ok(JitDumpRecord::CodeMove(record)) => {
                     self.lib_mapping_ops.push(
-                        raw_jitdump_record.timestamp,
+                        timestamp_mono,
                         LibMappingOp::Move(LibMappingMove {
                             old_start_avma: record.old_code_addr,
                             new_start_avma: record.new_code_addr,
@@ -212,7 +442,7 @@ impl SingleJitDumpProcessor {
                 }
                 Ok(JitDumpRecord::CodeClose) => {
                     self.lib_mapping_ops
-                        .push(raw_jitdump_record.timestamp, LibMappingOp::Clear);
+                        .push(timestamp_mono, LibMappingOp::Clear);
                     self.close_and_commit_symbol_table(profile);
                     return;
                 }
@@ -237,3 +467,172 @@ impl SingleJitDumpProcessor {
         self.lib_mapping_ops
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use std::io::Write;
+    use std::time::SystemTime;
+
+    use fxprof_processed_profile::{ReferenceTimestamp, SamplingInterval, Timestamp};
+
+    use super::*;
+    use crate::shared::lib_mappings::LibMappingsHierarchy;
+
+    pub const CODE_ADDR: u64 = 0x7f00_0000_1000;
+
+    /// The jitdump file header.
+    fn jitdump_header(header_timestamp: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        // Header: magic, version, total_size, elf_mach, pad1, pid, timestamp, flags.
+        for value in [0x4A695444u32, 1, 40, 62, 0, 1234] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&header_timestamp.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data
+    }
+
+    /// A JIT_CODE_LOAD record for a 16 byte function at `code_addr`.
+    fn code_load_record(load_timestamp: u64, code_addr: u64, code_index: u64) -> Vec<u8> {
+        let name = b"jitted_function\0";
+        let code = [0xc3u8; 16];
+        let mut data = Vec::new();
+        // JIT_CODE_LOAD record header: id, total_size, timestamp.
+        let record_size = 16 + 40 + name.len() + code.len();
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(record_size as u32).to_le_bytes());
+        data.extend_from_slice(&load_timestamp.to_le_bytes());
+        // pid, tid, vma, code_addr, code_size, code_index, name, code.
+        data.extend_from_slice(&1234u32.to_le_bytes());
+        data.extend_from_slice(&1234u32.to_le_bytes());
+        for value in [code_addr, code_addr, code.len() as u64, code_index] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(name);
+        data.extend_from_slice(&code);
+        data
+    }
+
+    /// Writes a jitdump file with a single JIT_CODE_LOAD record for a 16 byte
+    /// function at CODE_ADDR.
+    pub fn write_jitdump(path: &Path, header_timestamp: u64, load_timestamp: u64) {
+        let mut data = jitdump_header(header_timestamp);
+        data.extend(code_load_record(load_timestamp, CODE_ADDR, 0));
+        std::fs::File::create(path)
+            .unwrap()
+            .write_all(&data)
+            .unwrap();
+    }
+
+    fn test_profile() -> (Profile, ThreadHandle) {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_system_time(SystemTime::now()),
+            SamplingInterval::from_millis(1),
+        );
+        let start_time = Timestamp::from_millis_since_reference(0.0);
+        let process = profile.add_process("test", 1234, start_time);
+        let thread = profile.add_thread(process, 1234, start_time, true);
+        (profile, thread)
+    }
+
+    /// Returns whether CODE_ADDR resolves to the JIT function at `timestamp`.
+    fn resolves_at(
+        sample_clock: TimestampClock,
+        path: &Path,
+        mapping_timestamp: u64,
+        timestamp: u64,
+    ) -> bool {
+        let (mut profile, thread) = test_profile();
+        let mut manager = JitDumpManager::new_for_process(thread, sample_clock);
+        manager.add_jitdump_path(path, None, None, Some(mapping_timestamp));
+        let mut ops = manager.finish(
+            &mut JitCategoryManager::new(),
+            &mut profile,
+            None,
+            &TimestampConverter::with_reference_timestamp(0),
+        );
+        let mut mappings = LibMappingsHierarchy::new(LibMappingOpQueue::default());
+        mappings.add_jitdump_lib_mappings_ops(ops.pop().unwrap());
+        mappings.process_ops(timestamp);
+        mappings.convert_address(CODE_ADDR + 4).is_some()
+    }
+
+    #[test]
+    fn jitdump_timestamps_are_shifted_to_the_sample_clock() {
+        const MS: u64 = 1_000_000;
+        const SKEW: u64 = 5_000 * MS;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jit-1234.dump");
+        // The jitdump clock is 5 seconds ahead of the sample clock. The file is
+        // mapped at 10ms and the function is loaded at 20ms, in sample time.
+        write_jitdump(&path, SKEW + 10 * MS, SKEW + 20 * MS);
+
+        let resolves = |clock, timestamp| resolves_at(clock, &path, 10 * MS, timestamp);
+
+        assert!(!resolves(TimestampClock::Other, 20 * MS - 1));
+        assert!(resolves(TimestampClock::Other, 20 * MS + 1));
+
+        // If the clocks are the same, the timestamps are used as they are.
+        assert!(!resolves(TimestampClock::Monotonic, 20 * MS + 1));
+        assert!(resolves(TimestampClock::Monotonic, SKEW + 20 * MS));
+    }
+
+    /// A live recording, where the runtime compiles a function between two
+    /// adjacent samples and appends it to the jitdump file after the manager
+    /// has read the file up to its end. The polls between the samples don't
+    /// read the new record, but the function still resolves for the second
+    /// sample and not for the first one, because the mapping ops are ordered
+    /// by timestamp and `finish` reads the rest of the file.
+    #[test]
+    fn functions_compiled_between_adjacent_samples_resolve_for_the_later_sample() {
+        const MS: u64 = 1_000_000;
+        const SECOND_FUNCTION_ADDR: u64 = CODE_ADDR + 0x100;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jit-1234.dump");
+        write_jitdump(&path, 0, 10 * MS);
+
+        let (mut profile, thread) = test_profile();
+        let mut jit_category_manager = JitCategoryManager::new();
+        let timestamp_converter = TimestampConverter::with_reference_timestamp(0);
+        let mut manager = JitDumpManager::new_for_process(thread, TimestampClock::Monotonic);
+        manager.add_jitdump_path(&path, None, None, Some(0));
+        let mut poll = |manager: &mut JitDumpManager, profile: &mut Profile, timestamp| {
+            manager.poll(
+                Some(timestamp),
+                &mut jit_category_manager,
+                profile,
+                None,
+                &timestamp_converter,
+            )
+        };
+        // The first poll after adding the path reads the file.
+        poll(&mut manager, &mut profile, 20 * MS);
+        assert_eq!(manager.metrics().function_count, 1);
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&code_load_record(20 * MS + 1, SECOND_FUNCTION_ADDR, 1))
+            .unwrap();
+        poll(&mut manager, &mut profile, 20 * MS + 2);
+        assert_eq!(manager.metrics().function_count, 1);
+
+        let ops = manager.finish(
+            &mut JitCategoryManager::new(),
+            &mut profile,
+            None,
+            &timestamp_converter,
+        );
+        assert_eq!(ops[0].len(), 2);
+        let resolves = |timestamp| {
+            let mut mappings = LibMappingsHierarchy::new(LibMappingOpQueue::default());
+            mappings.add_jitdump_lib_mappings_ops(ops[0].clone());
+            mappings.process_ops(timestamp);
+            mappings.convert_address(SECOND_FUNCTION_ADDR + 4).is_some()
+        };
+        assert!(!resolves(20 * MS));
+        assert!(resolves(20 * MS + 2));
+    }
+}
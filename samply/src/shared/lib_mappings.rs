@@ -1,14 +1,24 @@
 use std::iter::Peekable;
+use std::sync::Arc;
 
 use fxprof_processed_profile::{CategoryPairHandle, LibMappings, LibraryHandle};
 
+use super::dynamic_linking::DynamicLinkingRanges;
+use super::frame_filter::HiddenFrameRanges;
 use super::jit_category_manager::JsFrame;
+use super::wine::WineModuleInfo;
 
 #[derive(Debug, Clone)]
 pub struct LibMappingInfo {
     pub lib_handle: LibraryHandle,
     pub category: Option<CategoryPairHandle>,
     pub js_frame: Option<JsFrame>,
+    /// The PLT and resolver ranges of the library, if it has any.
+    pub dynamic_linking_ranges: Option<Arc<DynamicLinkingRanges>>,
+    /// Set for PE images and for Wine modules with transition functions.
+    pub wine_module: Option<Arc<WineModuleInfo>>,
+    /// Set if some or all of the library's frames are hidden.
+    pub hidden_frames: Option<Arc<HiddenFrameRanges>>,
 }
 
 impl LibMappingInfo {
@@ -17,6 +27,19 @@ impl LibMappingInfo {
             lib_handle,
             category: None,
             js_frame: None,
+            dynamic_linking_ranges: None,
+            wine_module: None,
+            hidden_frames: None,
+        }
+    }
+
+    pub fn new_lib_with_dynamic_linking_ranges(
+        lib_handle: LibraryHandle,
+        dynamic_linking_ranges: Option<Arc<DynamicLinkingRanges>>,
+    ) -> Self {
+        Self {
+            dynamic_linking_ranges,
+            ..Self::new_lib(lib_handle)
         }
     }
 
@@ -29,6 +52,9 @@ impl LibMappingInfo {
             lib_handle,
             category: Some(category),
             js_frame,
+            dynamic_linking_ranges: None,
+            wine_module: None,
+            hidden_frames: None,
         }
     }
 }
@@ -69,29 +95,77 @@ impl LibMappingsHierarchy {
     }
 
     pub fn convert_address(&self, address: u64) -> Option<(u32, &LibMappingInfo)> {
-        if let Some(x) = self.regular_libs.0.convert_address(address) {
-            return Some(x);
+        let (relative_address, info, _kind) = self.convert_address_with_kind(address)?;
+        Some((relative_address, info))
+    }
+
+    /// Like `convert_address`, but also returns where the mapping came from.
+    pub fn convert_address_with_kind(
+        &self,
+        address: u64,
+    ) -> Option<(u32, &LibMappingInfo, MappingKind)> {
+        if let Some((relative_address, info)) = self.regular_libs.0.convert_address(address) {
+            return Some((relative_address, info, MappingKind::Regular));
         }
         for (mappings, _ops) in &self.jitdumps {
-            if let Some(x) = mappings.convert_address(address) {
-                return Some(x);
+            if let Some((relative_address, info)) = mappings.convert_address(address) {
+                return Some((relative_address, info, MappingKind::JitDump));
             }
         }
         if let Some(perf_map) = &self.perf_map {
-            if let Some(x) = perf_map.convert_address(address) {
-                return Some(x);
+            if let Some((relative_address, info)) = perf_map.convert_address(address) {
+                return Some((relative_address, info, MappingKind::PerfMap));
             }
         }
         None
     }
 }
 
+/// Where a mapping in a [`LibMappingsHierarchy`] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingKind {
+    /// A mapping of a file, from an mmap record or a /proc/<pid>/maps snapshot.
+    Regular,
+    /// A JIT function from a jitdump file.
+    JitDump,
+    /// A JIT function from a /tmp/perf-<pid>.map file.
+    PerfMap,
+}
+
+impl std::fmt::Display for MappingKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MappingKind::Regular => f.write_str("mmap record"),
+            MappingKind::JitDump => f.write_str("jitdump"),
+            MappingKind::PerfMap => f.write_str("perf map"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct LibMappingOpQueue(Vec<(u64, LibMappingOp)>);
 
 impl LibMappingOpQueue {
+    /// Adds an operation after the operations with the same or an earlier
+    /// timestamp. Operations usually arrive in order, but an mmap record
+    /// which arrived late still applies to the samples after its timestamp.
     pub fn push(&mut self, timestamp: u64, op: LibMappingOp) {
-        self.0.push((timestamp, op));
+        let index = self.0.partition_point(|(t, _op)| *t <= timestamp);
+        self.0.insert(index, (timestamp, op));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The operations with their timestamps, in timestamp order.
+    pub fn ops(&self) -> &[(u64, LibMappingOp)] {
+        &self.0
+    }
+
+    /// The timestamp of the first operation.
+    pub fn start_timestamp(&self) -> Option<u64> {
+        self.0.first().map(|(timestamp, _op)| *timestamp)
     }
 
     pub fn into_iter(self) -> LibMappingOpQueueIter {
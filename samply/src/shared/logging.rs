@@ -0,0 +1,88 @@
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::cli_error::CliError;
+
+/// The events which are printed to stderr if RUST_LOG isn't set. The
+/// converter's diagnostics are warnings, so users see the same messages as
+/// before they were events.
+const DEFAULT_STDERR_FILTER: &str = "warn";
+
+/// The events which are written to the `--log-file` if RUST_LOG isn't set.
+const DEFAULT_LOG_FILE_FILTER: &str = "samply=debug,warn";
+
+/// Sets up the `tracing` subscriber: events go to stderr as plain messages,
+/// and, with `--log-file`, to the file as JSON lines with their fields and
+/// spans. RUST_LOG overrides the levels of both, e.g.
+/// `RUST_LOG=samply::linux_shared=trace`.
+pub fn init(log_file: Option<&Path>) -> Result<(), CliError> {
+    let stderr_layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .event_format(MessageOnly)
+        .with_filter(env_filter(DEFAULT_STDERR_FILTER));
+    let file_layer = match log_file {
+        Some(path) => {
+            let file = File::create(path).map_err(|err| {
+                CliError::io(format!("Could not create the log file {path:?}"), &err)
+            })?;
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(Mutex::new(file))
+                .with_filter(env_filter(DEFAULT_LOG_FILE_FILTER));
+            Some(layer)
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(stderr_layer)
+        .with(file_layer)
+        .try_init()
+        .map_err(|err| CliError::internal(format!("Could not set up logging: {err}")))
+}
+
+fn env_filter(default: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default))
+}
+
+/// Formats an event as its message, without the level, target, fields and
+/// spans, which are only in the log file.
+struct MessageOnly;
+
+impl<S, N> FormatEvent<S, N> for MessageOnly
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut visitor = MessageVisitor(None);
+        event.record(&mut visitor);
+        writeln!(writer, "{}", visitor.0.unwrap_or_default())
+    }
+}
+
+struct MessageVisitor(Option<String>);
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
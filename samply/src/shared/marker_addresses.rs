@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use fxprof_processed_profile::Profile;
+
+use super::lib_mappings::LibMappingsHierarchy;
+
+/// How the addresses in marker payloads are written, for
+/// `--resolve-marker-addresses` and `--redact-marker-addresses`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarkerAddressMode {
+    /// As hex numbers, e.g. "0x7f32a1c04010".
+    #[default]
+    Raw,
+    /// Addresses in a mapped file as "libname+0xoffset (symbol)", other
+    /// addresses as hex numbers.
+    Resolve,
+    /// As opaque tokens, e.g. "ptr#1". Equal addresses get equal tokens
+    /// within the profile.
+    Redact,
+}
+
+/// A value in a marker payload. The code which creates a marker tags the
+/// values which are addresses, so that [`MarkerAddresses`] can rewrite them
+/// when the markers are added to the profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarkerValue {
+    Text(String),
+    Address(u64),
+}
+
+/// Formats the addresses in marker payloads according to a
+/// [`MarkerAddressMode`]. There's one of these per profile, so that the
+/// redaction tokens are the same in all processes.
+#[derive(Debug, Default)]
+pub struct MarkerAddresses {
+    mode: MarkerAddressMode,
+    /// The redaction token numbers, by address.
+    tokens: HashMap<u64, usize>,
+}
+
+impl MarkerAddresses {
+    pub fn new(mode: MarkerAddressMode) -> Self {
+        Self {
+            mode,
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Formats an address from a marker of a process whose mappings at the
+    /// time of the marker are `lib_mappings`.
+    pub fn format(
+        &mut self,
+        address: u64,
+        lib_mappings: &LibMappingsHierarchy,
+        profile: &Profile,
+    ) -> String {
+        match self.mode {
+            MarkerAddressMode::Raw => format!("0x{address:x}"),
+            MarkerAddressMode::Resolve => match lib_mappings.convert_address(address) {
+                Some((relative_address, info)) => {
+                    let lib = profile.lib_info(info.lib_handle);
+                    let symbol = lib
+                        .symbol_table
+                        .as_deref()
+                        .and_then(|symbol_table| symbol_table.lookup(relative_address));
+                    match symbol {
+                        Some(symbol) => {
+                            format!("{}+0x{relative_address:x} ({})", lib.name, symbol.name)
+                        }
+                        None => format!("{}+0x{relative_address:x}", lib.name),
+                    }
+                }
+                None => format!("0x{address:x}"),
+            },
+            MarkerAddressMode::Redact => {
+                let next_token = self.tokens.len() + 1;
+                let token = *self.tokens.entry(address).or_insert(next_token);
+                format!("ptr#{token}")
+            }
+        }
+    }
+
+    /// Formats a tagged value, see [`MarkerAddresses::format`].
+    pub fn format_value(
+        &mut self,
+        value: &MarkerValue,
+        lib_mappings: &LibMappingsHierarchy,
+        profile: &Profile,
+    ) -> String {
+        match value {
+            MarkerValue::Text(text) => text.clone(),
+            MarkerValue::Address(address) => self.format(*address, lib_mappings, profile),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use debugid::DebugId;
+    use fxprof_processed_profile::{
+        LibraryInfo, ReferenceTimestamp, SamplingInterval, Symbol, SymbolTable,
+    };
+
+    use super::*;
+    use crate::shared::lib_mappings::{
+        LibMappingAdd, LibMappingInfo, LibMappingOp, LibMappingOpQueue,
+    };
+
+    fn profile_and_mappings() -> (Profile, LibMappingsHierarchy) {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_system_time(SystemTime::now()),
+            SamplingInterval::from_millis(1),
+        );
+        let lib = profile.add_lib(LibraryInfo {
+            name: "libfoo.so".to_string(),
+            debug_name: "libfoo.so".to_string(),
+            path: "/usr/lib/libfoo.so".to_string(),
+            debug_path: "/usr/lib/libfoo.so".to_string(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: Some(Arc::new(SymbolTable::new(vec![Symbol {
+                address: 0x100,
+                size: Some(0x100),
+                name: "global_lock".to_string(),
+            }]))),
+        });
+        let mut ops = LibMappingOpQueue::default();
+        ops.push(
+            0,
+            LibMappingOp::Add(LibMappingAdd {
+                start_avma: 0x10000,
+                end_avma: 0x11000,
+                relative_address_at_start: 0,
+                info: LibMappingInfo::new_lib(lib),
+            }),
+        );
+        let mut mappings = LibMappingsHierarchy::new(ops);
+        mappings.process_ops(0);
+        (profile, mappings)
+    }
+
+    #[test]
+    fn addresses_are_resolved_to_the_mapped_file() {
+        let (profile, mappings) = profile_and_mappings();
+        let mut addresses = MarkerAddresses::new(MarkerAddressMode::Resolve);
+        let mut format = |address| addresses.format(address, &mappings, &profile);
+        assert_eq!(format(0x10140), "libfoo.so+0x140 (global_lock)");
+        assert_eq!(format(0x10800), "libfoo.so+0x800");
+        assert_eq!(format(0x7f0010), "0x7f0010");
+    }
+
+    #[test]
+    fn redacted_addresses_keep_their_equality() {
+        let (profile, mappings) = profile_and_mappings();
+        let mut addresses = MarkerAddresses::new(MarkerAddressMode::Redact);
+        let mut format = |address| addresses.format(address, &mappings, &profile);
+        assert_eq!(format(0x7f0010), "ptr#1");
+        assert_eq!(format(0x10140), "ptr#2");
+        assert_eq!(format(0x7f0010), "ptr#1");
+    }
+}
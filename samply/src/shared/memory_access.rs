@@ -0,0 +1,62 @@
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, Frame, FrameFlags, FrameInfo, Profile,
+};
+
+/// The slowest level of the memory hierarchy which a sampled memory access
+/// missed in, e.g. from the events of an ARM SPE record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccessKind {
+    /// The access needed a page table walk.
+    TlbMiss,
+    /// The access missed the last level cache.
+    LlcMiss,
+    /// The access missed the L1 data cache.
+    L1dMiss,
+    Hit,
+}
+
+impl MemoryAccessKind {
+    pub const ALL: [MemoryAccessKind; 4] = [
+        MemoryAccessKind::TlbMiss,
+        MemoryAccessKind::LlcMiss,
+        MemoryAccessKind::L1dMiss,
+        MemoryAccessKind::Hit,
+    ];
+
+    fn category(self) -> (&'static str, CategoryColor) {
+        match self {
+            MemoryAccessKind::TlbMiss => ("TLB miss", CategoryColor::Red),
+            MemoryAccessKind::LlcMiss => ("LLC miss", CategoryColor::Purple),
+            MemoryAccessKind::L1dMiss => ("L1D miss", CategoryColor::Blue),
+            MemoryAccessKind::Hit => ("Cache hit", CategoryColor::Green),
+        }
+    }
+}
+
+/// The categories of memory access samples. Each sample gets a label frame
+/// for its kind at the leaf, so that the kinds can be told apart in the call
+/// tree and the activity graph.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryAccessCategories {
+    categories: [CategoryPairHandle; MemoryAccessKind::ALL.len()],
+}
+
+impl MemoryAccessCategories {
+    pub fn new(profile: &mut Profile) -> Self {
+        Self {
+            categories: MemoryAccessKind::ALL.map(|kind| {
+                let (name, color) = kind.category();
+                profile.add_category(name, color).into()
+            }),
+        }
+    }
+
+    /// The label frames, in the order of [`MemoryAccessKind::ALL`].
+    pub fn labels(&self, profile: &mut Profile) -> [FrameInfo; MemoryAccessKind::ALL.len()] {
+        MemoryAccessKind::ALL.map(|kind| FrameInfo {
+            frame: Frame::Label(profile.intern_string(&format!("[{}]", kind.category().0))),
+            category_pair: self.categories[kind as usize],
+            flags: FrameFlags::empty(),
+        })
+    }
+}
@@ -1,13 +1,32 @@
+pub mod address_space_timeline;
+pub mod counter_buckets;
+pub mod dynamic_linking;
+pub mod frame_filter;
+pub mod gc_detection;
 pub mod jit_category_manager;
+pub mod jit_code_embedding;
 pub mod jit_function_add_marker;
 pub mod jit_function_recycler;
 pub mod jitdump_manager;
 pub mod lib_mappings;
+pub mod logging;
+pub mod marker_addresses;
+pub mod memory_access;
+pub mod path_map;
 pub mod perf_map;
 pub mod process_sample_data;
+pub mod profile_split;
+pub mod profiler_overhead;
+pub mod request_attribution;
+pub mod request_spans;
+pub mod rotation;
+pub mod self_profile;
 pub mod stack_converter;
 pub mod stack_depth_limiting_frame_iter;
+pub mod symbol_map;
+pub mod thread_groups;
 pub mod timestamp_converter;
 pub mod types;
 pub mod unresolved_samples;
 pub mod utils;
+pub mod wine;
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing::warn;
+
+/// Translates path prefixes for `--path-map`, e.g. the paths of binaries
+/// inside a container to the host directory they were extracted to.
+#[derive(Debug, Default)]
+pub struct PathMap {
+    /// The (from, to) prefix pairs, with the most specific `from` first.
+    rules: Vec<(PathBuf, PathBuf)>,
+    /// The translated paths of the files which were opened, for the report
+    /// at the end of the conversion.
+    applied: Mutex<BTreeMap<PathBuf, PathBuf>>,
+}
+
+impl PathMap {
+    /// If several rules match a path, the one with the longest `from` prefix
+    /// wins. Among rules with the same `from`, the first one wins.
+    pub fn new(mut rules: Vec<(PathBuf, PathBuf)>) -> Self {
+        rules.sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+        Self {
+            rules,
+            applied: Default::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the translated path if a rule matches. Prefixes only match
+    /// whole path components, so "/app" doesn't match "/application".
+    pub fn translate(&self, path: &Path) -> Option<PathBuf> {
+        self.rules.iter().find_map(|(from, to)| {
+            let rest = path.strip_prefix(from).ok()?;
+            Some(to.join(rest))
+        })
+    }
+
+    /// Called once a translated path has been opened.
+    pub fn record_applied(&self, path: &Path, translated_path: &Path) {
+        self.applied
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), translated_path.to_owned());
+    }
+
+    /// Lists the translations which were used for opening files.
+    pub fn report(&self) {
+        let applied = self.applied.lock().unwrap();
+        if applied.is_empty() {
+            if !self.is_empty() {
+                warn!("None of the --path-map rules matched a file which could be opened.");
+            }
+            return;
+        }
+        eprintln!("Applied --path-map translations:");
+        for (path, translated_path) in applied.iter() {
+            eprintln!("  {} -> {}", path.display(), translated_path.display());
+        }
+    }
+}
+
+/// Parses a `--path-map` argument of the form FROM=TO.
+pub fn parse_path_map_rule(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FROM=TO, got {s:?}"))?;
+    if from.is_empty() || to.is_empty() {
+        return Err(format!("expected FROM=TO, got {s:?}"));
+    }
+    Ok((PathBuf::from(from), PathBuf::from(to)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let path_map = PathMap::new(vec![
+            parse_path_map_rule("/app=/host/app").unwrap(),
+            parse_path_map_rule("/app/lib/plugins=/host/plugins").unwrap(),
+            parse_path_map_rule("/app=/elsewhere").unwrap(),
+        ]);
+        let translate = |path: &str| path_map.translate(Path::new(path));
+
+        assert_eq!(
+            translate("/app/bin/server"),
+            Some(PathBuf::from("/host/app/bin/server"))
+        );
+        assert_eq!(
+            translate("/app/lib/plugins/libfoo.so"),
+            Some(PathBuf::from("/host/plugins/libfoo.so"))
+        );
+        assert_eq!(translate("/application/bin/server"), None);
+        assert_eq!(translate("/usr/lib/libc.so.6"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        assert!(parse_path_map_rule("/app").is_err());
+        assert!(parse_path_map_rule("=/host/app").is_err());
+        assert!(parse_path_map_rule("/app=").is_err());
+        assert_eq!(
+            parse_path_map_rule("/usr/local/lib=/tmp/image/usr/local/lib"),
+            Ok((
+                PathBuf::from("/usr/local/lib"),
+                PathBuf::from("/tmp/image/usr/local/lib")
+            ))
+        );
+    }
+}
@@ -1,13 +1,192 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
 use std::sync::Arc;
 
 use debugid::DebugId;
 use fxprof_processed_profile::{LibMappings, LibraryInfo, Profile, Symbol, SymbolTable};
+use tracing::warn;
 
 use super::{
     jit_category_manager::JitCategoryManager, jit_function_recycler::JitFunctionRecycler,
-    lib_mappings::LibMappingInfo,
+    lib_mappings::LibMappingInfo, path_map::PathMap, utils::open_file_with_fallback_using,
 };
 
+/// The largest perf map file which is read, by default.
+pub const DEFAULT_PERF_MAP_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// The most entries a perf map file can have, by default.
+pub const DEFAULT_PERF_MAP_MAX_ENTRIES: usize = 4_000_000;
+
+/// Limits for perf map files. Any user can create /tmp/perf-<pid>.map, so a
+/// huge file mustn't be able to stall the conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfMapLimits {
+    /// Larger files are refused.
+    pub max_bytes: u64,
+    /// Files with more entries are refused.
+    pub max_entries: usize,
+}
+
+impl Default for PerfMapLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: DEFAULT_PERF_MAP_MAX_BYTES,
+            max_entries: DEFAULT_PERF_MAP_MAX_ENTRIES,
+        }
+    }
+}
+
+/// Why a perf map file was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PerfMapRejection {
+    Symlink,
+    NotARegularFile,
+    WrongOwner { owner: u32, expected_owner: u32 },
+    TooLarge { size: u64, max_bytes: u64 },
+    TooManyEntries { max_entries: usize },
+    Unreadable(String),
+}
+
+impl fmt::Display for PerfMapRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Symlink => write!(f, "it is a symlink"),
+            Self::NotARegularFile => write!(f, "it is not a regular file"),
+            Self::WrongOwner {
+                owner,
+                expected_owner,
+            } => write!(
+                f,
+                "it is owned by uid {owner}, but only files owned by uid {expected_owner} are \
+                 trusted"
+            ),
+            Self::TooLarge { size, max_bytes } => write!(
+                f,
+                "it has {size} bytes, more than the limit of {max_bytes} bytes \
+                 (--perf-map-max-bytes)"
+            ),
+            Self::TooManyEntries { max_entries } => write!(
+                f,
+                "it has more than {max_entries} entries (--perf-map-max-entries)"
+            ),
+            Self::Unreadable(err) => write!(f, "it could not be read: {err}"),
+        }
+    }
+}
+
+/// Returns the uid of a running process, from /proc. This is the owner which
+/// its perf map file must have if it's known at record time.
+pub fn process_owner_uid(pid: i32) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(format!("/proc/{pid}"))
+            .ok()
+            .map(|metadata| metadata.uid())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+/// Opens a file without following a symlink at the path's last component.
+/// The file is opened as non-blocking so that a FIFO doesn't block the open.
+fn open_no_follow(path: &Path) -> std::io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    }
+    options.open(path)
+}
+
+/// Checks the opened perf map file and reads it. The file must be a regular
+/// file owned by `expected_owner`, or by the user running samply if that's
+/// None, and it can't be larger than the limit.
+fn read_perf_map_file(
+    file: File,
+    expected_owner: Option<u32>,
+    limits: &PerfMapLimits,
+) -> Result<String, PerfMapRejection> {
+    let metadata = file
+        .metadata()
+        .map_err(|err| PerfMapRejection::Unreadable(err.to_string()))?;
+    if !metadata.is_file() {
+        return Err(PerfMapRejection::NotARegularFile);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let expected_owner = expected_owner.unwrap_or_else(|| unsafe { libc::geteuid() });
+        if metadata.uid() != expected_owner {
+            return Err(PerfMapRejection::WrongOwner {
+                owner: metadata.uid(),
+                expected_owner,
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = expected_owner;
+    if metadata.len() > limits.max_bytes {
+        return Err(PerfMapRejection::TooLarge {
+            size: metadata.len(),
+            max_bytes: limits.max_bytes,
+        });
+    }
+    // The file can still grow while it's read.
+    let mut content = Vec::new();
+    file.take(limits.max_bytes.saturating_add(1))
+        .read_to_end(&mut content)
+        .map_err(|err| PerfMapRejection::Unreadable(err.to_string()))?;
+    if content.len() as u64 > limits.max_bytes {
+        return Err(PerfMapRejection::TooLarge {
+            size: content.len() as u64,
+            max_bytes: limits.max_bytes,
+        });
+    }
+    String::from_utf8(content).map_err(|err| PerfMapRejection::Unreadable(err.to_string()))
+}
+
+/// Parses the entries of a perf map file. Fails if there are more than
+/// `limits.max_entries`.
+fn parse_perf_map<'a>(
+    content: &'a str,
+    limits: &PerfMapLimits,
+) -> Result<Vec<(u64, u64, &'a str)>, PerfMapRejection> {
+    let mut entries = Vec::new();
+    for entry in content.lines().filter_map(process_perf_map_line) {
+        if entries.len() == limits.max_entries {
+            return Err(PerfMapRejection::TooManyEntries {
+                max_entries: limits.max_entries,
+            });
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Whether the code of an entry lies within one of the anonymous executable
+/// mappings. Without any known mappings, all entries are accepted.
+fn is_in_anonymous_executable_mapping(
+    (addr, len, _): (u64, u64, &str),
+    anonymous_executable_ranges: &[Range<u64>],
+) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    anonymous_executable_ranges.is_empty()
+        || anonymous_executable_ranges
+            .iter()
+            .any(|range| range.start <= addr && end <= range.end)
+}
+
 fn process_perf_map_line(line: &str) -> Option<(u64, u64, &str)> {
     let mut split = line.splitn(3, ' ');
     let addr = split.next()?;
@@ -23,15 +202,83 @@ fn process_perf_map_line(line: &str) -> Option<(u64, u64, &str)> {
 
 /// Tries to load a perf mapping file that could have been generated by the process during
 /// execution.
+///
+/// The file is refused if it's a symlink, if it isn't owned by
+/// `expected_owner` (the process's uid if it was known at record time, the
+/// user running samply otherwise), or if it exceeds the limits. Entries
+/// outside of the process's `anonymous_executable_ranges` are dropped, if
+/// any are known. Refused files and dropped entries are reported.
+#[allow(clippy::too_many_arguments)]
 pub fn try_load_perf_map(
     pid: u32,
+    path_map: Option<&PathMap>,
+    expected_owner: Option<u32>,
+    limits: &PerfMapLimits,
+    anonymous_executable_ranges: &[Range<u64>],
     profile: &mut Profile,
     jit_category_manager: &mut JitCategoryManager,
     mut recycler: Option<&mut JitFunctionRecycler>,
 ) -> Option<LibMappings<LibMappingInfo>> {
     let name = format!("perf-{}.map", pid);
-    let path = format!("/tmp/{name}");
-    let Ok(content) = std::fs::read_to_string(&path) else { return None };
+    let opened = open_file_with_fallback_using(
+        Path::new(&format!("/tmp/{name}")),
+        None,
+        path_map,
+        open_no_follow,
+    );
+    let (file, path) = match opened {
+        Ok(opened) => opened,
+        #[cfg(unix)]
+        Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+            warn!(
+                path = format!("/tmp/{name}"),
+                reason = %PerfMapRejection::Symlink,
+                "Ignoring the perf map file /tmp/{name}: {}",
+                PerfMapRejection::Symlink
+            );
+            return None;
+        }
+        Err(_) => return None,
+    };
+    let path = path.to_string_lossy().into_owned();
+    let content = match read_perf_map_file(file, expected_owner, limits) {
+        Ok(content) => content,
+        Err(rejection) => {
+            warn!(
+                path = path.as_str(),
+                reason = %rejection,
+                "Ignoring the perf map file {path}: {rejection}"
+            );
+            return None;
+        }
+    };
+    let entries = match parse_perf_map(&content, limits) {
+        Ok(entries) => entries,
+        Err(rejection) => {
+            warn!(
+                path = path.as_str(),
+                reason = %rejection,
+                "Ignoring the perf map file {path}: {rejection}"
+            );
+            return None;
+        }
+    };
+    let entry_count = entries.len();
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|&entry| is_in_anonymous_executable_mapping(entry, anonymous_executable_ranges))
+        .collect();
+    if entries.len() < entry_count {
+        warn!(
+            path = path.as_str(),
+            pid,
+            ignored_entry_count = entry_count - entries.len(),
+            entry_count,
+            "Ignoring {} of the {entry_count} entries of the perf map file {path}: their \
+             addresses aren't in an anonymous executable mapping of process {pid}.",
+            entry_count - entries.len()
+        );
+    }
 
     // Read the map file and set everything up so that absolute addresses
     // in JIT code get symbolicated to the right function name.
@@ -68,7 +315,7 @@ pub fn try_load_perf_map(
     let mut mappings = LibMappings::new();
     let mut cumulative_address = 0;
 
-    for (addr, len, symbol_name) in content.lines().filter_map(process_perf_map_line) {
+    for (addr, len, symbol_name) in entries {
         let start_address = addr;
         let end_address = addr + len;
 
@@ -112,6 +359,9 @@ pub fn try_load_perf_map(
                 lib_handle,
                 category: Some(category),
                 js_frame,
+                dynamic_linking_ranges: None,
+                wine_module: None,
+                hidden_frames: None,
             },
         );
     }
@@ -120,3 +370,117 @@ pub fn try_load_perf_map(
 
     Some(mappings)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_file(dir: &Path, name: &str, content: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn read(
+        path: &Path,
+        expected_owner: Option<u32>,
+        limits: &PerfMapLimits,
+    ) -> Result<String, PerfMapRejection> {
+        let file =
+            open_no_follow(path).map_err(|err| PerfMapRejection::Unreadable(err.to_string()))?;
+        read_perf_map_file(file, expected_owner, limits)
+    }
+
+    #[test]
+    fn accepts_a_file_of_the_current_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(dir.path(), "perf-1.map", "1000 10 foo\n");
+        let content = read(&path, None, &PerfMapLimits::default()).unwrap();
+        let entries = parse_perf_map(&content, &PerfMapLimits::default()).unwrap();
+        assert_eq!(entries, vec![(0x1000, 0x10, "foo")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = write_file(dir.path(), "target.map", "1000 10 foo\n");
+        let link = dir.path().join("perf-1.map");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let err = open_no_follow(&link).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_files_of_other_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(dir.path(), "perf-1.map", "1000 10 foo\n");
+        let other_uid = unsafe { libc::geteuid() }.wrapping_add(1);
+        let owner = unsafe { libc::geteuid() };
+        assert_eq!(
+            read(&path, Some(other_uid), &PerfMapLimits::default()),
+            Err(PerfMapRejection::WrongOwner {
+                owner,
+                expected_owner: other_uid
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_fifos() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("perf-1.map");
+        let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        assert_eq!(
+            read(&fifo, None, &PerfMapLimits::default()),
+            Err(PerfMapRejection::NotARegularFile)
+        );
+    }
+
+    #[test]
+    fn refuses_files_over_the_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(dir.path(), "perf-1.map", "1000 10 foo\n2000 10 bar\n");
+        let limits = PerfMapLimits {
+            max_bytes: 16,
+            max_entries: 1,
+        };
+        assert_eq!(
+            read(&path, None, &limits),
+            Err(PerfMapRejection::TooLarge {
+                size: 24,
+                max_bytes: 16
+            })
+        );
+        let limits = PerfMapLimits {
+            max_bytes: 1024,
+            max_entries: 1,
+        };
+        let content = read(&path, None, &limits).unwrap();
+        assert_eq!(
+            parse_perf_map(&content, &limits),
+            Err(PerfMapRejection::TooManyEntries { max_entries: 1 })
+        );
+    }
+
+    #[test]
+    fn drops_entries_outside_of_anonymous_executable_mappings() {
+        let ranges = [0x1000..0x2000, 0x4000..0x5000];
+        let is_in = |entry| is_in_anonymous_executable_mapping(entry, &ranges);
+        assert!(is_in((0x1000, 0x10, "foo")));
+        assert!(is_in((0x1ff0, 0x10, "foo")));
+        assert!(is_in((0x4800, 0x10, "bar")));
+        assert!(!is_in((0x1ff0, 0x20, "straddles")));
+        assert!(!is_in((0x3000, 0x10, "between")));
+        assert!(!is_in((0x7f00_0000, 0x10, "elsewhere")));
+        assert!(!is_in((u64::MAX, 0x10, "overflows")));
+        // Without known mappings, the entries can't be checked.
+        assert!(is_in_anonymous_executable_mapping(
+            (0x7f00_0000, 0x10, "foo"),
+            &[]
+        ));
+    }
+}
@@ -1,18 +1,35 @@
+use std::collections::HashMap;
+
 use fxprof_processed_profile::{
-    CategoryPairHandle, LibMappings, MarkerDynamicField, MarkerFieldFormat, MarkerLocation,
-    MarkerSchema, MarkerSchemaField, MarkerStaticField, MarkerTiming, Profile, ProfilerMarker,
+    CategoryPairHandle, CpuDelta, Frame, FrameFlags, FrameInfo, LibMappings, MarkerDynamicField,
+    MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField, MarkerStaticField,
+    MarkerTiming, Profile, ProfilerMarker,
 };
 use serde_json::json;
 
 use super::{
+    address_space_timeline::ProcessAddressSpace,
+    dynamic_linking::{
+        user_leaf_is_in_dynamic_linker, DynamicLinkingFrameConversion, DynamicLoadingPhaseDetector,
+    },
+    frame_filter::HiddenFrameConversion,
+    gc_detection::{GcConversion, GcPauseDetector},
+    jit_code_embedding::JitCodeEmbedding,
     lib_mappings::{LibMappingInfo, LibMappingOpQueue, LibMappingsHierarchy},
-    stack_converter::StackConverter,
+    marker_addresses::MarkerAddresses,
+    memory_access::MemoryAccessCategories,
+    profiler_overhead::ProfilerOverheadFrameConversion,
+    request_attribution::RequestAttributionConversion,
+    request_spans::RequestSpanConversion,
+    stack_converter::{GuestFrameConversion, StackConverter},
     stack_depth_limiting_frame_iter::StackDepthLimitingFrameIter,
     types::StackFrame,
     unresolved_samples::{
-        OtherEventMarkerData, RssStatMarkerData, SampleData, SampleOrMarker,
+        FutexWaitMarkerData, FutexWakeMarkerData, LargeMmapMarkerData, OtherEventMarkerData,
+        ProbeMarkerData, RssStatMarkerData, SampleData, SampleOrMarker, SignalMarkerData,
         UnresolvedSampleOrMarker, UnresolvedSamples, UnresolvedStacks,
     },
+    wine::WineFrameConversion,
 };
 
 #[derive(Debug, Clone)]
@@ -50,11 +67,36 @@ impl ProcessSampleData {
         self.unresolved_samples.is_empty()
     }
 
+    /// A copy of the process's mapping history, for an
+    /// [`AddressSpaceTimeline`](super::address_space_timeline::AddressSpaceTimeline).
+    pub fn address_space(&self) -> ProcessAddressSpace {
+        ProcessAddressSpace::new(
+            self.regular_lib_mapping_op_queue.clone(),
+            self.jitdump_lib_mapping_op_queues.clone(),
+            self.perf_map_mappings.clone(),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn flush_samples_to_profile(
         self,
         profile: &mut Profile,
         user_category: CategoryPairHandle,
         kernel_category: CategoryPairHandle,
+        kernel_lib_mappings: Option<&LibMappings<LibMappingInfo>>,
+        guest: Option<GuestFrameConversion>,
+        dynamic_linking: Option<DynamicLinkingFrameConversion>,
+        wine: Option<WineFrameConversion>,
+        profiler_overhead: Option<ProfilerOverheadFrameConversion>,
+        hidden_frames: Option<HiddenFrameConversion>,
+        synthesized_category: Option<CategoryPairHandle>,
+        cow_fault_category: Option<CategoryPairHandle>,
+        memory_access_categories: Option<MemoryAccessCategories>,
+        request_attribution: Option<&RequestAttributionConversion>,
+        mut request_spans: Option<&mut RequestSpanConversion>,
+        mut gc: Option<&mut GcConversion>,
+        mut jit_code_embedding: Option<&mut JitCodeEmbedding>,
+        marker_addresses: &mut MarkerAddresses,
         stack_frame_scratch_buf: &mut Vec<StackFrame>,
         stacks: &UnresolvedStacks,
         event_names: &[String],
@@ -72,26 +114,170 @@ impl ProcessSampleData {
         if let Some(perf_map_mappings) = perf_map_mappings {
             lib_mappings_hierarchy.add_perf_map_mappings(perf_map_mappings);
         }
-        let stack_converter = StackConverter::new(user_category, kernel_category);
+        let stack_converter = StackConverter::new(
+            user_category,
+            kernel_category,
+            kernel_lib_mappings,
+            guest,
+            dynamic_linking,
+            wine,
+            profiler_overhead,
+            hidden_frames,
+        );
+        let synthesized_label = synthesized_category.map(|category_pair| FrameInfo {
+            frame: Frame::Label(profile.intern_string("[synthesized at thread exit]")),
+            category_pair,
+            flags: FrameFlags::empty(),
+        });
+        let cow_fault_label = cow_fault_category.map(|category_pair| FrameInfo {
+            frame: Frame::Label(profile.intern_string("[copy-on-write fault]")),
+            category_pair,
+            flags: FrameFlags::empty(),
+        });
+        let memory_access_labels =
+            memory_access_categories.map(|categories| categories.labels(profile));
+        // The request label of each unique stack. The stacks' frames are
+        // looked up once per stack, not once per sample.
+        let mut request_labels_by_stack = HashMap::new();
+        // The GC runtime of each unique (thread, stack), if it's in the GC.
+        let mut gc_runtimes_by_thread_and_stack = HashMap::new();
+        let mut gc_pause_detector = gc
+            .as_ref()
+            .filter(|gc| gc.detection.detects_pauses())
+            .map(|_| GcPauseDetector::default());
+        let mut dynamic_loading_phase_detector =
+            dynamic_linking.map(|_| DynamicLoadingPhaseDetector::default());
         let samples = unresolved_samples.into_inner();
         for sample in samples {
             lib_mappings_hierarchy.process_ops(sample.timestamp_mono);
             let UnresolvedSampleOrMarker {
                 thread_handle,
                 timestamp,
+                timestamp_mono,
                 stack,
                 sample_or_marker,
-                ..
             } = sample;
             stack_frame_scratch_buf.clear();
             stacks.convert_back(stack, stack_frame_scratch_buf);
             let frames =
                 stack_converter.convert_stack(stack_frame_scratch_buf, &lib_mappings_hierarchy);
+            // Synthesized samples get a label frame at the leaf, so that they're
+            // categorized separately from measured samples. The same goes for
+            // samples in copy-on-write faults, and memory access samples of
+            // each kind.
+            let leaf_label = match sample_or_marker {
+                SampleOrMarker::SynthesizedSample => synthesized_label.clone(),
+                SampleOrMarker::Sample(SampleData {
+                    is_cow_fault: true, ..
+                }) => cow_fault_label.clone(),
+                SampleOrMarker::Sample(SampleData {
+                    memory_access: Some(kind),
+                    ..
+                }) => memory_access_labels
+                    .as_ref()
+                    .map(|labels| labels[kind as usize].clone()),
+                _ => None,
+            };
+            // Samples get the label of the request they're attributed to
+            // above their frames, and below any leaf label.
+            let request_label = match (request_attribution, &sample_or_marker) {
+                (Some(conversion), SampleOrMarker::Sample(SampleData { weight, .. })) => {
+                    let label = *request_labels_by_stack.entry(stack).or_insert_with(|| {
+                        conversion.attribution.label_for_stack(
+                            stack_converter
+                                .convert_stack(stack_frame_scratch_buf, &lib_mappings_hierarchy),
+                        )
+                    });
+                    conversion.attribution.record_sample(label, *weight);
+                    Some(conversion.label_frame(label))
+                }
+                _ => None,
+            };
+            // Samples in a --request-spans span get its request ID next to
+            // the label of --attribute-by-frame.
+            let request_span_label = match (&mut request_spans, &sample_or_marker) {
+                (
+                    Some(conversion),
+                    SampleOrMarker::Sample(SampleData {
+                        weight,
+                        request_span,
+                        ..
+                    }),
+                ) => {
+                    conversion.spans.record_sample(*request_span, *weight);
+                    request_span.map(|request| conversion.label_frame(request, profile))
+                }
+                _ => None,
+            };
+            // Samples in the GC get the GC category on all of their frames.
+            let gc_category_pair = match (&mut gc, &sample_or_marker) {
+                (Some(gc), SampleOrMarker::Sample(_)) => {
+                    let detection = gc.detection;
+                    let runtime = *gc_runtimes_by_thread_and_stack
+                        .entry((thread_handle, stack))
+                        .or_insert_with(|| {
+                            profile
+                                .thread_name(thread_handle)
+                                .and_then(|name| detection.runtime_for_thread_name(name))
+                                .or_else(|| {
+                                    detection.runtime_for_stack(stack_converter.convert_stack(
+                                        stack_frame_scratch_buf,
+                                        &lib_mappings_hierarchy,
+                                    ))
+                                })
+                        });
+                    if let Some(detector) = &mut gc_pause_detector {
+                        detector.add_sample(timestamp_mono, timestamp, thread_handle, runtime);
+                    }
+                    runtime.map(|runtime| gc.category_pair(runtime, profile))
+                }
+                _ => None,
+            };
+            if let (Some(detector), SampleOrMarker::Sample(_)) =
+                (&mut dynamic_loading_phase_detector, &sample_or_marker)
+            {
+                if let Some(in_dynamic_linker) =
+                    user_leaf_is_in_dynamic_linker(stack_frame_scratch_buf, &lib_mappings_hierarchy)
+                {
+                    detector.add_sample(
+                        timestamp_mono,
+                        timestamp,
+                        thread_handle,
+                        in_dynamic_linker,
+                    );
+                }
+            }
+            if let (Some(embedding), SampleOrMarker::Sample(SampleData { weight, .. })) =
+                (&mut jit_code_embedding, &sample_or_marker)
+            {
+                embedding.add_sample(
+                    stack_converter.convert_stack(stack_frame_scratch_buf, &lib_mappings_hierarchy),
+                    *weight,
+                    profile,
+                );
+            }
+            let frames = frames
+                .map(|frame| match gc_category_pair {
+                    Some(category_pair) => FrameInfo {
+                        category_pair,
+                        ..frame
+                    },
+                    None => frame,
+                })
+                .chain(request_label)
+                .chain(request_span_label)
+                .chain(leaf_label);
             let frames = StackDepthLimitingFrameIter::new(profile, frames, user_category);
             match sample_or_marker {
-                SampleOrMarker::Sample(SampleData { cpu_delta, weight }) => {
+                SampleOrMarker::Sample(SampleData {
+                    cpu_delta, weight, ..
+                }) => {
                     profile.add_sample(thread_handle, timestamp, frames, cpu_delta, weight);
                 }
+                SampleOrMarker::SynthesizedSample => {
+                    // The sample wasn't measured, so it must not add to any totals.
+                    profile.add_sample(thread_handle, timestamp, frames, CpuDelta::ZERO, 0);
+                }
                 SampleOrMarker::RssStatMarker(RssStatMarkerData {
                     size,
                     delta,
@@ -112,6 +298,87 @@ impl ProcessSampleData {
                         frames,
                     );
                 }
+                SampleOrMarker::LargeMmapMarker(LargeMmapMarkerData { length }) => {
+                    profile.add_marker_with_stack(
+                        thread_handle,
+                        "Large mmap",
+                        LargeMmapMarker(length),
+                        MarkerTiming::Instant(timestamp),
+                        frames,
+                    );
+                }
+                SampleOrMarker::FutexWaitMarker(FutexWaitMarkerData {
+                    end_timestamp,
+                    duration_ns,
+                    address,
+                }) => {
+                    let duration_ms = duration_ns as f64 / 1_000_000.0;
+                    let address =
+                        marker_addresses.format(address, &lib_mappings_hierarchy, profile);
+                    profile.add_marker_with_stack(
+                        thread_handle,
+                        &format!("futex wait on {address} ({duration_ms:.1}ms)"),
+                        FutexWaitMarker(address),
+                        MarkerTiming::Interval(timestamp, end_timestamp),
+                        frames,
+                    );
+                }
+                SampleOrMarker::FutexWakeMarker(FutexWakeMarkerData {
+                    address,
+                    woken_count,
+                }) => {
+                    let address =
+                        marker_addresses.format(address, &lib_mappings_hierarchy, profile);
+                    profile.add_marker_with_stack(
+                        thread_handle,
+                        &format!("futex wake on {address}"),
+                        FutexWakeMarker(address, woken_count),
+                        MarkerTiming::Instant(timestamp),
+                        frames,
+                    );
+                }
+                SampleOrMarker::SignalMarker(SignalMarkerData {
+                    signal_name,
+                    code,
+                    fatal,
+                }) => {
+                    profile.add_marker_with_stack(
+                        thread_handle,
+                        &format!("Received {signal_name}"),
+                        SignalMarker {
+                            signal_name,
+                            code,
+                            fatal,
+                        },
+                        MarkerTiming::Instant(timestamp),
+                        frames,
+                    );
+                }
+                SampleOrMarker::ProbeMarker(ProbeMarkerData { event_name, args }) => {
+                    let args: Vec<String> = args
+                        .iter()
+                        .map(|(name, value)| {
+                            let value = marker_addresses.format_value(
+                                value,
+                                &lib_mappings_hierarchy,
+                                profile,
+                            );
+                            format!("{name}={value}")
+                        })
+                        .collect();
+                    let args = args.join(" ");
+                    let name = match args.is_empty() {
+                        true => event_name.clone(),
+                        false => format!("{event_name} {args}"),
+                    };
+                    profile.add_marker_with_stack(
+                        thread_handle,
+                        &name,
+                        ProbeMarker { event_name, args },
+                        MarkerTiming::Instant(timestamp),
+                        frames,
+                    );
+                }
                 SampleOrMarker::OtherEventMarker(OtherEventMarkerData { attr_index }) => {
                     if let Some(name) = event_names.get(attr_index) {
                         let timing = MarkerTiming::Instant(timestamp);
@@ -126,6 +393,12 @@ impl ProcessSampleData {
                 }
             }
         }
+        if let Some(detector) = gc_pause_detector {
+            detector.add_markers(profile);
+        }
+        if let Some(detector) = dynamic_loading_phase_detector {
+            detector.add_marker(profile);
+        }
     }
 }
 
@@ -172,6 +445,232 @@ impl ProfilerMarker for RssStatMarker {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct LargeMmapMarker(pub u64);
+
+impl ProfilerMarker for LargeMmapMarker {
+    const MARKER_TYPE_NAME: &'static str = "Large mmap";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "length": self.0,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.length}"),
+            tooltip_label: Some("mmap of {marker.data.length}"),
+            table_label: Some("mmap of {marker.data.length}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "length",
+                    label: "Length",
+                    format: MarkerFieldFormat::Bytes,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for successful mmap calls which map a large amount of memory.",
+                }),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FutexWaitMarker(pub String);
+
+impl ProfilerMarker for FutexWaitMarker {
+    const MARKER_TYPE_NAME: &'static str = "FutexWait";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "address": self.0,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.address}"),
+            tooltip_label: Some("futex wait on {marker.data.address}"),
+            table_label: Some("futex wait on {marker.data.address}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "address",
+                    label: "Futex address",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for futex calls which wait for the futex, from syscall entry to exit.",
+                }),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FutexWakeMarker(pub String, pub u64);
+
+impl ProfilerMarker for FutexWakeMarker {
+    const MARKER_TYPE_NAME: &'static str = "FutexWake";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "address": self.0,
+            "wokenCount": self.1,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.address}"),
+            tooltip_label: Some(
+                "futex wake on {marker.data.address}, woke {marker.data.wokenCount} waiters",
+            ),
+            table_label: Some(
+                "futex wake on {marker.data.address}, woke {marker.data.wokenCount} waiters",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "address",
+                    label: "Futex address",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "wokenCount",
+                    label: "Woken waiters",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted for futex calls which wake waiters of the futex.",
+                }),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SignalMarker {
+    pub signal_name: String,
+    pub code: i32,
+    pub fatal: bool,
+}
+
+impl ProfilerMarker for SignalMarker {
+    const MARKER_TYPE_NAME: &'static str = "Signal";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "signal": self.signal_name,
+            "code": self.code,
+            "outcome": if self.fatal { "Terminated the process" } else { "Delivered" },
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.signal}"),
+            tooltip_label: Some("Received {marker.data.signal}: {marker.data.outcome}"),
+            table_label: Some(
+                "Received {marker.data.signal} (code {marker.data.code}): {marker.data.outcome}",
+            ),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "signal",
+                    label: "Signal",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "code",
+                    label: "Signal code",
+                    format: MarkerFieldFormat::Integer,
+                    searchable: false,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "outcome",
+                    label: "Outcome",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted when a signal is delivered to the thread. The stack is the thread's most recent sampled stack.",
+                }),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeMarker {
+    pub event_name: String,
+    /// The decoded arguments, e.g. "arg1=42 arg2=0x10".
+    pub args: String,
+}
+
+impl ProfilerMarker for ProbeMarker {
+    const MARKER_TYPE_NAME: &'static str = "Probe";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "event": self.event_name,
+            "args": self.args,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![MarkerLocation::MarkerChart, MarkerLocation::MarkerTable],
+            chart_label: Some("{marker.data.event}"),
+            tooltip_label: Some("{marker.data.event} {marker.data.args}"),
+            table_label: Some("{marker.data.event} {marker.data.args}"),
+            fields: vec![
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "event",
+                    label: "Event",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Dynamic(MarkerDynamicField {
+                    key: "args",
+                    label: "Arguments",
+                    format: MarkerFieldFormat::String,
+                    searchable: true,
+                }),
+                MarkerSchemaField::Static(MarkerStaticField {
+                    label: "Description",
+                    value: "Emitted when a uprobe or kprobe is hit, with the probe's arguments as decoded from the event's tracepoint format.",
+                }),
+            ],
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct OtherEventMarker;
 
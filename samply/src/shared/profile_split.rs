@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use fxprof_processed_profile::{Profile, ProfileSizeEstimate, Timestamp};
+use serde_json::json;
+
+use crate::profile_filter::{FilterSummary, ProfileFilter};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A converted profile, with the time ranges of the parts which it's written
+/// in if it's larger than `--max-output-size`.
+pub struct ConvertedProfile {
+    pub profile: Profile,
+    /// The `start..end` ranges of the parts, see [`split_ranges`]. Empty if
+    /// the profile is written as a whole.
+    pub parts: Vec<(Timestamp, Timestamp)>,
+}
+
+impl ConvertedProfile {
+    pub fn whole(profile: Profile) -> Self {
+        Self {
+            profile,
+            parts: Vec::new(),
+        }
+    }
+}
+
+/// Splits the timeline into consecutive `start..end` ranges whose estimated
+/// JSON size is at most `max_size`, for profiles which are too large for the
+/// Firefox Profiler to load. Returns no ranges if the profile fits, or if it
+/// can't be split.
+///
+/// The ranges start and end at whole seconds. They never start within one of
+/// the `unsplittable` ranges, whose end is inclusive, e.g. between the first
+/// and the last sample of an off-CPU sample group. Every part repeats the
+/// estimate's fixed bytes. A part can exceed the limit if a single second, or
+/// an unsplittable range, is larger than the limit on its own.
+pub fn split_ranges(
+    estimate: &ProfileSizeEstimate,
+    max_size: u64,
+    unsplittable: &[(Timestamp, Timestamp)],
+) -> Vec<(Timestamp, Timestamp)> {
+    let (Some(&first_second), Some(&last_second)) = (
+        estimate.bytes_by_second.keys().next(),
+        estimate.bytes_by_second.keys().next_back(),
+    ) else {
+        return Vec::new();
+    };
+    if estimate.total() <= max_size {
+        return Vec::new();
+    }
+    let budget = max_size.saturating_sub(estimate.fixed_bytes);
+
+    let mut part_starts = vec![first_second];
+    let mut part_bytes = 0;
+    for (&second, &bytes) in &estimate.bytes_by_second {
+        if part_bytes != 0
+            && part_bytes + bytes > budget
+            && can_split_at(seconds(second), unsplittable)
+        {
+            part_starts.push(second);
+            part_bytes = 0;
+        }
+        part_bytes += bytes;
+    }
+    if part_starts.len() == 1 {
+        return Vec::new();
+    }
+    let part_ends = part_starts.iter().skip(1).copied().chain([last_second + 1]);
+    part_starts
+        .iter()
+        .zip(part_ends)
+        .map(|(&start, end)| (seconds(start), seconds(end)))
+        .collect()
+}
+
+fn seconds(seconds: u64) -> Timestamp {
+    Timestamp::from_nanos_since_reference(seconds * NANOS_PER_SECOND)
+}
+
+/// Whether a part can start at `time`, i.e. whether no unsplittable range
+/// has a timestamp before `time` and one at or after it.
+fn can_split_at(time: Timestamp, unsplittable: &[(Timestamp, Timestamp)]) -> bool {
+    !unsplittable
+        .iter()
+        .any(|&(start, end)| start < time && time <= end)
+}
+
+/// Writes each part of the profile into `dir` as a self-contained profile,
+/// "part-1.json", "part-2.json" and so on, and writes an "index.json" which
+/// lists the parts and their time ranges. The threads which `filter` drops are
+/// left out of each part. Returns the paths of the parts, and what the filter
+/// dropped from all parts together.
+pub fn write_profile_parts(
+    profile: &Profile,
+    parts: &[(Timestamp, Timestamp)],
+    max_size: u64,
+    dir: &Path,
+    filter: Option<&ProfileFilter>,
+) -> io::Result<(Vec<PathBuf>, FilterSummary)> {
+    let mut paths = Vec::new();
+    let mut filter_summary = FilterSummary::default();
+    let mut index_parts = Vec::new();
+    for (i, &(start, end)) in parts.iter().enumerate() {
+        let file_name = format!("part-{}.json", i + 1);
+        let path = dir.join(&file_name);
+        let part = profile.time_slice(start, end);
+        let writer = BufWriter::new(File::create(&path)?);
+        match filter {
+            Some(filter) => {
+                let mut part = serde_json::to_value(&part)?;
+                filter_summary.add(&filter.apply(&mut part));
+                serde_json::to_writer(writer, &part)?;
+            }
+            None => serde_json::to_writer(writer, &part)?,
+        }
+        index_parts.push(json!({
+            "file": file_name,
+            "startTime": start,
+            "endTime": end,
+        }));
+        paths.push(path);
+    }
+    let index = json!({
+        "maxOutputSize": max_size,
+        "parts": index_parts,
+    });
+    let writer = BufWriter::new(File::create(dir.join("index.json"))?);
+    serde_json::to_writer_pretty(writer, &index)?;
+    Ok((paths, filter_summary))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn estimate(fixed_bytes: u64, bytes_by_second: &[(u64, u64)]) -> ProfileSizeEstimate {
+        ProfileSizeEstimate {
+            fixed_bytes,
+            bytes_by_second: bytes_by_second.iter().copied().collect(),
+        }
+    }
+
+    fn ms(millis: f64) -> Timestamp {
+        Timestamp::from_millis_since_reference(millis)
+    }
+
+    #[test]
+    fn splits_at_whole_seconds_outside_of_off_cpu_groups() {
+        let estimate = estimate(100, &[(0, 100), (1, 100), (2, 100), (3, 100), (5, 100)]);
+        assert!(split_ranges(&estimate, 600, &[]).is_empty());
+        assert_eq!(
+            split_ranges(&estimate, 350, &[]),
+            vec![
+                (seconds(0), seconds(2)),
+                (seconds(2), seconds(5)),
+                (seconds(5), seconds(6))
+            ]
+        );
+
+        // An off-CPU group from 1.5s to 2s keeps the split at 2s from
+        // happening, but one which ends at 1.9s doesn't.
+        let off_cpu = [(ms(1500.0), ms(2000.0))];
+        assert_eq!(
+            split_ranges(&estimate, 350, &off_cpu),
+            vec![(seconds(0), seconds(3)), (seconds(3), seconds(6))]
+        );
+        let off_cpu = [(ms(1500.0), ms(1900.0))];
+        assert_eq!(split_ranges(&estimate, 350, &off_cpu).len(), 3);
+
+        // A group which spans the whole profile keeps it in one piece.
+        let off_cpu = [(ms(0.0), ms(5500.0))];
+        assert!(split_ranges(&estimate, 350, &off_cpu).is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use fxprof_processed_profile::{CategoryPairHandle, SymbolTable};
+
+/// The kernel functions in perf's sampling interrupt path. When a sample
+/// hits one of these, the CPU was busy with the profiler itself rather than
+/// with the interrupted code.
+const PROFILER_OVERHEAD_KERNEL_FUNCTIONS: &[&str] = &[
+    // x86 NMI entry and PMU interrupt handlers
+    "asm_exc_nmi",
+    "exc_nmi",
+    "default_do_nmi",
+    "nmi_handle",
+    "perf_event_nmi_handler",
+    "x86_pmu_handle_irq",
+    "intel_pmu_handle_irq",
+    "handle_pmi_common",
+    "amd_pmu_handle_irq",
+    "amd_pmu_v2_handle_irq",
+    "perf_ibs_nmi_handler",
+    // arm64 PMU interrupt handlers
+    "armpmu_dispatch_irq",
+    "armv8pmu_handle_irq",
+    "arm_spe_pmu_irq_handler",
+    // Generic perf_event overflow handling and sample output
+    "perf_event_overflow",
+    "__perf_event_overflow",
+    "perf_swevent_hrtimer",
+    "perf_swevent_overflow",
+    "perf_prepare_sample",
+    "perf_output_sample",
+    "perf_event_output_forward",
+    "perf_callchain",
+    "get_perf_callchain",
+    "perf_callchain_kernel",
+    "perf_callchain_user",
+    "perf_output_sample_regs",
+    "perf_output_sample_ustack",
+];
+
+/// Recognizes kernel frames in perf's own interrupt path, by the name of the
+/// kernel function.
+#[derive(Debug, Clone)]
+pub struct KernelFrameClassifier {
+    kernel_base_avma: u64,
+    kernel_symbol_table: Arc<SymbolTable>,
+}
+
+impl KernelFrameClassifier {
+    pub fn new(kernel_base_avma: u64, kernel_symbol_table: Arc<SymbolTable>) -> Self {
+        Self {
+            kernel_base_avma,
+            kernel_symbol_table,
+        }
+    }
+
+    /// `lookup_address` is the frame's address, minus one for return addresses.
+    pub fn is_profiler_overhead(&self, lookup_address: u64) -> bool {
+        let Some(relative_address) = lookup_address.checked_sub(self.kernel_base_avma) else {
+            return false;
+        };
+        let Ok(relative_address) = u32::try_from(relative_address) else {
+            return false;
+        };
+        match self.kernel_symbol_table.lookup(relative_address) {
+            Some(symbol) => is_profiler_overhead_function(&symbol.name),
+            None => false,
+        }
+    }
+}
+
+/// Compiler-generated suffixes like "nmi_handle.constprop.0" are ignored.
+fn is_profiler_overhead_function(name: &str) -> bool {
+    let name = name.split('.').next().unwrap_or(name);
+    PROFILER_OVERHEAD_KERNEL_FUNCTIONS.contains(&name)
+}
+
+/// How kernel frames in perf's interrupt path are converted.
+#[derive(Debug, Clone, Copy)]
+pub struct ProfilerOverheadFrameConversion<'a> {
+    pub category: CategoryPairHandle,
+    /// Whether the profiler frames should be removed from the stack, so that
+    /// the interrupted frame becomes the leaf. The interrupted frame gets
+    /// `category`, so that the overhead still shows up in the category
+    /// breakdown.
+    pub strip: bool,
+    pub classifier: &'a KernelFrameClassifier,
+}
+
+#[cfg(test)]
+mod test {
+    use fxprof_processed_profile::Symbol;
+
+    use super::*;
+
+    #[test]
+    fn recognizes_perf_interrupt_frames() {
+        let symbol = |address, name: &str| Symbol {
+            address,
+            size: None,
+            name: name.to_string(),
+        };
+        let symbol_table = SymbolTable::new(vec![
+            symbol(0x1000, "do_syscall_64"),
+            symbol(0x2000, "nmi_handle.constprop.0"),
+            symbol(0x3000, "__perf_event_overflow"),
+            symbol(0x4000, "perf_event_output"),
+        ]);
+        let base = 0xffffffff81000000;
+        let classifier = KernelFrameClassifier::new(base, Arc::new(symbol_table));
+        assert!(!classifier.is_profiler_overhead(base + 0x1010));
+        assert!(classifier.is_profiler_overhead(base + 0x2010));
+        assert!(classifier.is_profiler_overhead(base + 0x3010));
+        assert!(!classifier.is_profiler_overhead(base + 0x4010));
+        assert!(!classifier.is_profiler_overhead(base - 0x10));
+    }
+}
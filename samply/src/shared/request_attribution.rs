@@ -0,0 +1,229 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use fxprof_processed_profile::{
+    CategoryColor, Frame, FrameFlags, FrameInfo, LibraryHandle, Profile,
+};
+use object::{Object, ObjectSymbol};
+use regex::Regex;
+use wholesym::samply_symbols::{demangle_any, object};
+
+/// The label of samples without a matching frame on their stack.
+pub const UNATTRIBUTED_LABEL: &str = "(unattributed)";
+
+/// Attributes samples to the outermost frame on their stack whose function
+/// matches one of the `--attribute-by-frame` regexes, e.g. to the request
+/// handler which the sampled code runs for.
+///
+/// Like the symbol rules of the
+/// [`FrameFilter`](super::frame_filter::FrameFilter), the regexes are
+/// matched against the demangled symbol names of each library when it's
+/// loaded, so a sample's label only needs address range lookups. The label
+/// is the text of the regex's first capture group if it has one, and the
+/// symbol name otherwise.
+#[derive(Debug)]
+pub struct RequestAttribution {
+    regexes: Vec<Regex>,
+    /// The label strings. Index 0 is [`UNATTRIBUTED_LABEL`].
+    labels: Vec<String>,
+    label_indexes: HashMap<String, usize>,
+    ranges_by_lib: HashMap<LibraryHandle, AttributedFrameRanges>,
+    /// The sample count and total weight of each label.
+    totals: Vec<(Cell<u64>, Cell<i64>)>,
+}
+
+impl RequestAttribution {
+    /// Returns None if there are no regexes.
+    pub fn new(regexes: Vec<Regex>) -> Option<Self> {
+        if regexes.is_empty() {
+            return None;
+        }
+        let mut attribution = Self {
+            regexes,
+            labels: Vec::new(),
+            label_indexes: HashMap::new(),
+            ranges_by_lib: HashMap::new(),
+            totals: Vec::new(),
+        };
+        attribution.intern_label(UNATTRIBUTED_LABEL);
+        Some(attribution)
+    }
+
+    /// Finds the functions of the library whose names match a regex.
+    pub fn add_object<'data: 'file, 'file>(
+        &mut self,
+        lib_handle: LibraryHandle,
+        file: &'file impl Object<'data, 'file>,
+        base_svma: u64,
+    ) {
+        let symbols: Vec<_> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.size() != 0)
+            .filter_map(|symbol| {
+                let start = symbol.address().checked_sub(base_svma)?;
+                Some((start, symbol.size(), symbol.name().ok()?))
+            })
+            .collect();
+        let mut symbol_ranges = Vec::new();
+        for (start, size, name) in symbols {
+            if let Some(label) = self.label_for_symbol(&demangle_any(name)) {
+                let label = self.intern_label(&label);
+                symbol_ranges.push((start as u32..(start + size) as u32, label));
+            }
+        }
+        if !symbol_ranges.is_empty() {
+            self.ranges_by_lib
+                .insert(lib_handle, AttributedFrameRanges { symbol_ranges });
+        }
+    }
+
+    fn label_for_symbol(&self, symbol_name: &str) -> Option<String> {
+        self.regexes.iter().find_map(|regex| {
+            let captures = regex.captures(symbol_name)?;
+            let label = captures.get(1).map_or(symbol_name, |group| group.as_str());
+            Some(label.to_string())
+        })
+    }
+
+    fn intern_label(&mut self, label: &str) -> usize {
+        if let Some(&index) = self.label_indexes.get(label) {
+            return index;
+        }
+        let index = self.labels.len();
+        self.labels.push(label.to_string());
+        self.label_indexes.insert(label.to_string(), index);
+        self.totals.push(Default::default());
+        index
+    }
+
+    /// Returns the label index of the outermost matching frame, or the index
+    /// of [`UNATTRIBUTED_LABEL`]. The frames are ordered from the root.
+    pub fn label_for_stack(&self, mut frames: impl Iterator<Item = FrameInfo>) -> usize {
+        frames
+            .find_map(|frame| {
+                let (lib_handle, address) = match frame.frame {
+                    Frame::RelativeAddressFromInstructionPointer(lib_handle, address) => {
+                        (lib_handle, address)
+                    }
+                    // Return addresses point after the call instruction.
+                    Frame::RelativeAddressFromReturnAddress(lib_handle, address) => {
+                        (lib_handle, address.checked_sub(1)?)
+                    }
+                    _ => return None,
+                };
+                self.ranges_by_lib.get(&lib_handle)?.label_for(address)
+            })
+            .unwrap_or(0)
+    }
+
+    pub fn record_sample(&self, label: usize, weight: i32) {
+        let (count, total_weight) = &self.totals[label];
+        count.set(count.get() + 1);
+        total_weight.set(total_weight.get() + i64::from(weight));
+    }
+
+    /// The labels with their sample count and total weight, heaviest first.
+    pub fn label_totals(&self) -> Vec<(&str, u64, i64)> {
+        let mut totals: Vec<_> = self
+            .labels
+            .iter()
+            .zip(&self.totals)
+            .map(|(label, (count, weight))| (label.as_str(), count.get(), weight.get()))
+            .filter(|(_, count, _)| *count != 0)
+            .collect();
+        totals.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)));
+        totals
+    }
+
+    pub fn report(&self) {
+        let totals = self.label_totals();
+        let total_weight: i64 = totals.iter().map(|(_, _, weight)| weight).sum();
+        if total_weight == 0 {
+            return;
+        }
+        eprintln!("Sample weight by request (--attribute-by-frame):");
+        for (label, count, weight) in totals {
+            let percentage = weight as f64 * 100.0 / total_weight as f64;
+            eprintln!("{percentage:>6.1}% {weight:>12} {count:>10} samples  {label}");
+        }
+    }
+}
+
+/// The functions of a library which samples are attributed to, with the
+/// index of their label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AttributedFrameRanges {
+    /// Relative address ranges of the matching symbols.
+    symbol_ranges: Vec<(Range<u32>, usize)>,
+}
+
+impl AttributedFrameRanges {
+    fn label_for(&self, relative_address: u32) -> Option<usize> {
+        self.symbol_ranges
+            .iter()
+            .find(|(range, _)| range.contains(&relative_address))
+            .map(|(_, label)| *label)
+    }
+}
+
+/// How samples get their request label: as a label frame with a subcategory
+/// of the "Request" category per label, right above the sampled frames.
+#[derive(Debug)]
+pub struct RequestAttributionConversion<'a> {
+    pub attribution: &'a RequestAttribution,
+    label_frames: Vec<FrameInfo>,
+}
+
+impl<'a> RequestAttributionConversion<'a> {
+    pub fn new(attribution: &'a RequestAttribution, profile: &mut Profile) -> Self {
+        let category = profile.add_category("Request", CategoryColor::LightBlue);
+        let label_frames = attribution
+            .labels
+            .iter()
+            .map(|label| FrameInfo {
+                frame: Frame::Label(profile.intern_string(&format!("[request: {label}]"))),
+                category_pair: profile.add_subcategory(category, label),
+                flags: FrameFlags::empty(),
+            })
+            .collect();
+        Self {
+            attribution,
+            label_frames,
+        }
+    }
+
+    pub fn label_frame(&self, label: usize) -> FrameInfo {
+        self.label_frames[label].clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labels_symbols_and_stacks() {
+        let attribution = RequestAttribution::new(vec![
+            Regex::new(r"^myserver::handlers::(\w+)").unwrap(),
+            Regex::new(r"^myserver::jobs::").unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            attribution.label_for_symbol("myserver::handlers::get_user::{{closure}}"),
+            Some("get_user".to_string())
+        );
+        assert_eq!(
+            attribution.label_for_symbol("myserver::jobs::cleanup"),
+            Some("myserver::jobs::cleanup".to_string())
+        );
+        assert_eq!(attribution.label_for_symbol("tokio::runtime::run"), None);
+
+        let ranges = AttributedFrameRanges {
+            symbol_ranges: vec![(0x100..0x200, 1)],
+        };
+        assert_eq!(ranges.label_for(0x1ff), Some(1));
+        assert_eq!(ranges.label_for(0x200), None);
+    }
+}
@@ -0,0 +1,313 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+use fxprof_processed_profile::{
+    CategoryColor, CategoryPairHandle, Frame, FrameFlags, FrameInfo, Profile,
+};
+
+/// The number of request IDs which [`RequestSpans::report`] prints.
+const REPORTED_REQUEST_COUNT: usize = 20;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Malformed line {0}: {1:?}")]
+    MalformedLine(usize, String),
+}
+
+/// The request spans of `--request-spans`: the times at which an application
+/// worked on a request, from a sidecar file which it wrote while it was
+/// being recorded. Samples which were taken during a span get its request ID
+/// as a label.
+///
+/// Each line of the file is a span, `REQUEST_ID START_NS END_NS [TID]`,
+/// separated by whitespace or commas. The timestamps are CLOCK_MONOTONIC,
+/// like the timestamps of the perf records. A span with a tid only applies
+/// to the samples of that thread, and a span without one applies to the
+/// samples of all threads. Empty lines and lines starting with '#' are
+/// skipped.
+///
+/// If spans overlap, a sample gets the innermost one, i.e. the one which
+/// started last, and a span of its own thread takes precedence over a span
+/// without a tid.
+#[derive(Debug, Default)]
+pub struct RequestSpans {
+    request_ids: Vec<String>,
+    segments_by_tid: HashMap<i32, Vec<SpanSegment>>,
+    process_wide_segments: Vec<SpanSegment>,
+    /// The sample count and total weight of each request ID.
+    totals: Vec<(Cell<u64>, Cell<i64>)>,
+    /// The sample count and total weight of the samples outside any span.
+    unlabeled_totals: (Cell<u64>, Cell<i64>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    start: u64,
+    end: u64,
+    request: u32,
+}
+
+/// A time range in which the innermost span is the same. The segments of a
+/// thread are sorted and don't overlap, so that a sample's span is found
+/// with a binary search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SpanSegment {
+    start: u64,
+    end: u64,
+    request: u32,
+}
+
+impl RequestSpans {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let file = std::fs::File::open(path)?;
+        Self::parse(std::io::BufReader::new(file))
+    }
+
+    pub fn parse(reader: impl BufRead) -> Result<Self, Error> {
+        let mut request_ids = Vec::new();
+        let mut request_indexes = HashMap::new();
+        let mut spans_by_tid: HashMap<i32, Vec<Span>> = HashMap::new();
+        let mut process_wide_spans = Vec::new();
+        for (line_index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let Some((request_id, start, end, tid)) = parse_line(trimmed) else {
+                return Err(Error::MalformedLine(line_index + 1, line));
+            };
+            let request = *request_indexes
+                .entry(request_id.to_string())
+                .or_insert_with(|| {
+                    request_ids.push(request_id.to_string());
+                    request_ids.len() as u32 - 1
+                });
+            let span = Span {
+                start,
+                end,
+                request,
+            };
+            match tid {
+                Some(tid) => spans_by_tid.entry(tid).or_default().push(span),
+                None => process_wide_spans.push(span),
+            }
+        }
+        Ok(Self {
+            totals: request_ids.iter().map(|_| Default::default()).collect(),
+            request_ids,
+            segments_by_tid: spans_by_tid
+                .into_iter()
+                .map(|(tid, spans)| (tid, innermost_segments(spans)))
+                .collect(),
+            process_wide_segments: innermost_segments(process_wide_spans),
+            unlabeled_totals: Default::default(),
+        })
+    }
+
+    /// Returns the request of the innermost span which contains the sample.
+    pub fn request_for_sample(&self, tid: i32, timestamp: u64) -> Option<u32> {
+        self.segments_by_tid
+            .get(&tid)
+            .and_then(|segments| request_at(segments, timestamp))
+            .or_else(|| request_at(&self.process_wide_segments, timestamp))
+    }
+
+    pub fn record_sample(&self, request: Option<u32>, weight: i32) {
+        let (count, total_weight) = match request {
+            Some(request) => &self.totals[request as usize],
+            None => &self.unlabeled_totals,
+        };
+        count.set(count.get() + 1);
+        total_weight.set(total_weight.get() + i64::from(weight));
+    }
+
+    /// Prints the request IDs with the most sample weight.
+    pub fn report(&self) {
+        let mut totals: Vec<_> = self
+            .request_ids
+            .iter()
+            .zip(&self.totals)
+            .map(|(request_id, (count, weight))| (request_id.as_str(), count.get(), weight.get()))
+            .filter(|(_, count, _)| *count != 0)
+            .collect();
+        let (unlabeled_count, unlabeled_weight) =
+            (self.unlabeled_totals.0.get(), self.unlabeled_totals.1.get());
+        let total_weight: i64 =
+            totals.iter().map(|(_, _, weight)| weight).sum::<i64>() + unlabeled_weight;
+        if total_weight == 0 {
+            return;
+        }
+        totals.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)));
+        let print = |label: &str, count: u64, weight: i64| {
+            let percentage = weight as f64 * 100.0 / total_weight as f64;
+            eprintln!("{percentage:>6.1}% {weight:>12} {count:>10} samples  {label}");
+        };
+        eprintln!("Sample weight by request ID (--request-spans):");
+        for &(request_id, count, weight) in totals.iter().take(REPORTED_REQUEST_COUNT) {
+            print(request_id, count, weight);
+        }
+        if totals.len() > REPORTED_REQUEST_COUNT {
+            let rest = &totals[REPORTED_REQUEST_COUNT..];
+            print(
+                &format!("({} more request IDs)", rest.len()),
+                rest.iter().map(|(_, count, _)| count).sum(),
+                rest.iter().map(|(_, _, weight)| weight).sum(),
+            );
+        }
+        if unlabeled_count != 0 {
+            print("(outside any span)", unlabeled_count, unlabeled_weight);
+        }
+    }
+}
+
+/// Parses `REQUEST_ID START_NS END_NS [TID]`.
+fn parse_line(line: &str) -> Option<(&str, u64, u64, Option<i32>)> {
+    let mut fields = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|field| !field.is_empty());
+    let request_id = fields.next()?;
+    let start = fields.next()?.parse().ok()?;
+    let end = fields.next()?.parse().ok()?;
+    let tid = fields.next().map(str::parse).transpose().ok()?;
+    if end < start || fields.next().is_some() {
+        return None;
+    }
+    Some((request_id, start, end, tid))
+}
+
+/// Splits the spans into non-overlapping segments with the innermost span at
+/// each time, by sweeping over the times at which spans start or end.
+fn innermost_segments(mut spans: Vec<Span>) -> Vec<SpanSegment> {
+    // Of the spans which start at the same time, the longest comes first,
+    // so that the innermost open span is always the last one.
+    spans.sort_unstable_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+    let mut boundaries: Vec<u64> = spans
+        .iter()
+        .flat_map(|span| [span.start, span.end])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut segments: Vec<SpanSegment> = Vec::new();
+    let mut open_spans: Vec<Span> = Vec::new();
+    let mut next_span = 0;
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        open_spans.retain(|span| span.end > start);
+        while let Some(span) = spans.get(next_span).filter(|span| span.start == start) {
+            if span.end > start {
+                open_spans.push(*span);
+            }
+            next_span += 1;
+        }
+        let Some(innermost) = open_spans.last() else {
+            continue;
+        };
+        match segments.last_mut() {
+            Some(last) if last.end == start && last.request == innermost.request => {
+                last.end = end;
+            }
+            _ => segments.push(SpanSegment {
+                start,
+                end,
+                request: innermost.request,
+            }),
+        }
+    }
+    segments
+}
+
+fn request_at(segments: &[SpanSegment], timestamp: u64) -> Option<u32> {
+    let index = segments.partition_point(|segment| segment.end <= timestamp);
+    let segment = segments.get(index)?;
+    (segment.start <= timestamp).then(|| segment.request)
+}
+
+/// How samples get their request ID: as a "[request ID]" label frame with
+/// the "Request span" category, right above the sampled frames. The frames
+/// are only created for the request IDs which have samples, because a span
+/// file can have millions of them.
+#[derive(Debug)]
+pub struct RequestSpanConversion<'a> {
+    pub spans: &'a RequestSpans,
+    category_pair: Option<CategoryPairHandle>,
+    label_frames: HashMap<u32, FrameInfo>,
+}
+
+impl<'a> RequestSpanConversion<'a> {
+    pub fn new(spans: &'a RequestSpans) -> Self {
+        Self {
+            spans,
+            category_pair: None,
+            label_frames: HashMap::new(),
+        }
+    }
+
+    pub fn label_frame(&mut self, request: u32, profile: &mut Profile) -> FrameInfo {
+        let category_pair = *self.category_pair.get_or_insert_with(|| {
+            profile
+                .add_category("Request span", CategoryColor::Blue)
+                .into()
+        });
+        let request_id = &self.spans.request_ids[request as usize];
+        self.label_frames
+            .entry(request)
+            .or_insert_with(|| FrameInfo {
+                frame: Frame::Label(profile.intern_string(&format!("[{request_id}]"))),
+                category_pair,
+                flags: FrameFlags::empty(),
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_the_innermost_span_of_a_sample() {
+        let spans = RequestSpans::parse(
+            "# request_id start_ns end_ns tid\n\
+             outer 100 1000 7\n\
+             inner 300 400 7\n\
+             \n\
+             sibling,500,600,7\n\
+             other-thread 100 1000 8\n\
+             everywhere 0 2000\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let request_id = |tid, timestamp| {
+            spans
+                .request_for_sample(tid, timestamp)
+                .map(|request| spans.request_ids[request as usize].as_str())
+        };
+        assert_eq!(request_id(7, 100), Some("outer"));
+        assert_eq!(request_id(7, 350), Some("inner"));
+        assert_eq!(request_id(7, 400), Some("outer"));
+        assert_eq!(request_id(7, 599), Some("sibling"));
+        assert_eq!(request_id(8, 350), Some("other-thread"));
+        // Outside of the thread's own spans, and on other threads, the
+        // spans without a tid apply.
+        assert_eq!(request_id(7, 1500), Some("everywhere"));
+        assert_eq!(request_id(9, 350), Some("everywhere"));
+        assert_eq!(request_id(9, 2000), None);
+        assert_eq!(spans.segments_by_tid[&7].len(), 5);
+
+        assert!(matches!(
+            RequestSpans::parse("req 200 100\n".as_bytes()),
+            Err(Error::MalformedLine(1, _))
+        ));
+        assert!(matches!(
+            RequestSpans::parse("req 100 200\nreq 100\n".as_bytes()),
+            Err(Error::MalformedLine(2, _))
+        ));
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::warn;
+
+/// For `samply record --rotate`: every `interval`, the current profile is
+/// written to a file which is named with its time range, and a new profile
+/// is started.
+#[derive(Debug, Clone, Copy)]
+pub struct RotationOptions {
+    pub interval: Duration,
+    /// If set, only the most recent this many files are kept.
+    pub keep: Option<usize>,
+}
+
+/// Decides when a rotating recording starts a new file, names the files and
+/// deletes the old ones.
+///
+/// Timestamps are in the clock of the records. `reference` maps them to
+/// wall-clock time for the file names.
+#[derive(Debug)]
+pub struct Rotation {
+    options: RotationOptions,
+    output_file: PathBuf,
+    reference: (u64, SystemTime),
+    /// The timestamp at which the current file starts, once it has a record.
+    file_start: Option<u64>,
+    written_files: VecDeque<PathBuf>,
+}
+
+impl Rotation {
+    pub fn new(
+        options: RotationOptions,
+        output_file: &Path,
+        reference_timestamp: u64,
+        reference_time: SystemTime,
+    ) -> Self {
+        Self {
+            options,
+            output_file: output_file.to_owned(),
+            reference: (reference_timestamp, reference_time),
+            file_start: None,
+            written_files: VecDeque::new(),
+        }
+    }
+
+    /// Returns the end of the current file if the record at `timestamp`
+    /// belongs in the next one. The end is a multiple of the interval after
+    /// the start of the current file, and the next file starts there, so
+    /// that every record is in exactly one file.
+    pub fn boundary_before(&mut self, timestamp: u64) -> Option<u64> {
+        let start = *self.file_start.get_or_insert(timestamp);
+        let interval = (self.options.interval.as_nanos() as u64).max(1);
+        if timestamp < start.saturating_add(interval) {
+            return None;
+        }
+        Some(start + (timestamp - start) / interval * interval)
+    }
+
+    /// The path of the current file, if it ends at `end`, e.g.
+    /// "profile-20261017T101500Z-20261017T102500Z.json" for "profile.json".
+    pub fn file_path(&self, end: u64) -> PathBuf {
+        let start = self.file_start.unwrap_or(end);
+        let range = format!(
+            "{}-{}",
+            format_utc(self.wall_clock_time(start)),
+            format_utc(self.wall_clock_time(end))
+        );
+        let stem = self
+            .output_file
+            .file_stem()
+            .map_or_else(|| "profile".into(), |stem| stem.to_string_lossy());
+        let file_name = match self.output_file.extension() {
+            Some(extension) => format!("{stem}-{range}.{}", extension.to_string_lossy()),
+            None => format!("{stem}-{range}"),
+        };
+        self.output_file.with_file_name(file_name)
+    }
+
+    /// Starts the next file at `end`, after the current file has been written
+    /// to `path`, and deletes the oldest files beyond the ones to keep.
+    pub fn file_written(&mut self, path: PathBuf, end: u64) {
+        eprintln!("Saved {path:?}.");
+        self.file_start = Some(end);
+        self.written_files.push_back(path);
+        let Some(keep) = self.options.keep else {
+            return;
+        };
+        while self.written_files.len() > keep {
+            let oldest = self.written_files.pop_front().unwrap();
+            if let Err(err) = std::fs::remove_file(&oldest) {
+                warn!(
+                    path = ?oldest,
+                    error = %err,
+                    "Could not delete the old profile {oldest:?}: {err}"
+                );
+            }
+        }
+    }
+
+    fn wall_clock_time(&self, timestamp: u64) -> SystemTime {
+        let (reference_timestamp, reference_time) = self.reference;
+        if timestamp >= reference_timestamp {
+            reference_time + Duration::from_nanos(timestamp - reference_timestamp)
+        } else {
+            reference_time - Duration::from_nanos(reference_timestamp - timestamp)
+        }
+    }
+}
+
+/// Formats a time as a UTC timestamp which can be used in file names, e.g.
+/// "20261017T101500Z".
+fn format_utc(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) date of the
+/// proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // From Howard Hinnant's chrono-compatible date algorithms.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn formats_utc_times() {
+        let time = |seconds| UNIX_EPOCH + Duration::from_secs(seconds);
+        assert_eq!(format_utc(time(0)), "19700101T000000Z");
+        assert_eq!(format_utc(time(951_782_400)), "20000229T000000Z");
+        assert_eq!(format_utc(time(1_792_232_100)), "20261017T101500Z");
+    }
+
+    #[test]
+    fn rotates_at_multiples_of_the_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = RotationOptions {
+            interval: Duration::from_secs(600),
+            keep: Some(2),
+        };
+        let reference_time = UNIX_EPOCH + Duration::from_secs(1_792_232_100);
+        let mut rotation = Rotation::new(
+            options,
+            &dir.path().join("profile.json"),
+            1000 * SECOND,
+            reference_time,
+        );
+
+        assert_eq!(rotation.boundary_before(1000 * SECOND), None);
+        assert_eq!(rotation.boundary_before(1599 * SECOND), None);
+        assert_eq!(rotation.boundary_before(1600 * SECOND), Some(1600 * SECOND));
+        let first = rotation.file_path(1600 * SECOND);
+        assert_eq!(
+            first.file_name().unwrap(),
+            "profile-20261017T101500Z-20261017T102500Z.json"
+        );
+
+        let mut written = Vec::new();
+        for end in [1600, 2200, 2800] {
+            let path = rotation.file_path(end * SECOND);
+            std::fs::write(&path, "{}").unwrap();
+            rotation.file_written(path.clone(), end * SECOND);
+            written.push(path);
+        }
+        // Only the two most recent files are kept.
+        assert!(!written[0].exists());
+        assert!(written[1].exists() && written[2].exists());
+
+        // A gap without records skips the empty intervals.
+        assert_eq!(rotation.boundary_before(4500 * SECOND), Some(4000 * SECOND));
+    }
+}
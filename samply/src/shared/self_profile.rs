@@ -0,0 +1,238 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use fxprof_processed_profile::{
+    MarkerDynamicField, MarkerFieldFormat, MarkerLocation, MarkerSchema, MarkerSchemaField,
+    MarkerTiming, Profile, ProfilerMarker, ThreadHandle,
+};
+use serde_json::json;
+
+use super::timestamp_converter::TimestampConverter;
+
+/// A stage of samply's own work, e.g. parsing the perf.data header or
+/// loading a module, which is shown as a marker in the self-profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phase {
+    pub name: String,
+    pub start: u64,
+    /// `None` for instant phases, e.g. progress updates.
+    pub end: Option<u64>,
+}
+
+/// Collects the phases of a conversion for `--self-profile`.
+///
+/// Cloning the recorder is cheap, and all clones record into the same list.
+/// Timestamps come from the clock of the self-profiler's samples, so that the
+/// phases line up with them.
+#[derive(Debug, Clone)]
+pub struct PhaseRecorder {
+    /// The thread which records the phases. The markers are put on this
+    /// thread in the self-profile.
+    tid: u32,
+    clock: fn() -> u64,
+    phases: Arc<Mutex<Vec<Phase>>>,
+}
+
+impl PhaseRecorder {
+    pub fn new(tid: u32, clock: fn() -> u64) -> Self {
+        Self {
+            tid,
+            clock,
+            phases: Default::default(),
+        }
+    }
+
+    pub fn tid(&self) -> u32 {
+        self.tid
+    }
+
+    /// Starts an interval phase, which ends when the returned guard is dropped.
+    pub fn interval(&self, name: impl Into<String>) -> PhaseGuard {
+        PhaseGuard {
+            recorder: self.clone(),
+            name: name.into(),
+            start: (self.clock)(),
+        }
+    }
+
+    pub fn instant(&self, name: impl Into<String>) {
+        let now = (self.clock)();
+        self.push(Phase {
+            name: name.into(),
+            start: now,
+            end: None,
+        });
+    }
+
+    /// Returns the phases which have been recorded so far, in the order in
+    /// which they ended.
+    pub fn take_phases(&self) -> Vec<Phase> {
+        std::mem::take(&mut *self.phases.lock().unwrap())
+    }
+
+    fn push(&self, phase: Phase) {
+        self.phases.lock().unwrap().push(phase);
+    }
+}
+
+/// Returned by [`PhaseRecorder::interval`].
+#[derive(Debug)]
+pub struct PhaseGuard {
+    recorder: PhaseRecorder,
+    name: String,
+    start: u64,
+}
+
+impl Drop for PhaseGuard {
+    fn drop(&mut self) {
+        let end = (self.recorder.clock)();
+        self.recorder.push(Phase {
+            name: std::mem::take(&mut self.name),
+            start: self.start,
+            end: Some(end),
+        });
+    }
+}
+
+/// Adds the phases as markers to `thread`.
+pub fn add_phase_markers(
+    profile: &mut Profile,
+    thread: ThreadHandle,
+    phases: &[Phase],
+    timestamp_converter: &TimestampConverter,
+) {
+    for phase in phases {
+        let start = timestamp_converter.convert_time(phase.start);
+        let timing = match phase.end {
+            Some(end) => MarkerTiming::Interval(start, timestamp_converter.convert_time(end)),
+            None => MarkerTiming::Instant(start),
+        };
+        profile.add_marker(
+            thread,
+            &phase.name,
+            PhaseMarker {
+                name: phase.name.clone(),
+            },
+            timing,
+        );
+    }
+}
+
+/// A marker for a [`Phase`] in the self-profile.
+#[derive(Debug, Clone)]
+pub struct PhaseMarker {
+    pub name: String,
+}
+
+impl ProfilerMarker for PhaseMarker {
+    const MARKER_TYPE_NAME: &'static str = "SamplyPhase";
+
+    fn json_marker_data(&self) -> serde_json::Value {
+        json!({
+            "type": Self::MARKER_TYPE_NAME,
+            "name": self.name,
+        })
+    }
+
+    fn schema() -> MarkerSchema {
+        MarkerSchema {
+            type_name: Self::MARKER_TYPE_NAME,
+            locations: vec![
+                MarkerLocation::MarkerChart,
+                MarkerLocation::MarkerTable,
+                MarkerLocation::TimelineOverview,
+            ],
+            chart_label: Some("{marker.data.name}"),
+            tooltip_label: Some("{marker.data.name}"),
+            table_label: Some("{marker.data.name}"),
+            fields: vec![MarkerSchemaField::Dynamic(MarkerDynamicField {
+                key: "name",
+                label: "Phase",
+                format: MarkerFieldFormat::String,
+                searchable: true,
+            })],
+        }
+    }
+}
+
+/// A running `--self-profile` recording of samply's own process.
+///
+/// The platform's profiler module starts the recording, see
+/// `profiler::start_self_profiling`. The recording thread saves the profile,
+/// including the recorded phases, once [`SelfProfiler::finish`] is called.
+pub struct SelfProfiler {
+    output_file: PathBuf,
+    phases: PhaseRecorder,
+    stop: Arc<AtomicBool>,
+    recording_thread: JoinHandle<()>,
+}
+
+impl SelfProfiler {
+    pub fn new(
+        output_file: PathBuf,
+        phases: PhaseRecorder,
+        stop: Arc<AtomicBool>,
+        recording_thread: JoinHandle<()>,
+    ) -> Self {
+        Self {
+            output_file,
+            phases,
+            stop,
+            recording_thread,
+        }
+    }
+
+    /// The recorder which the conversion should report its phases to.
+    pub fn phases(&self) -> &PhaseRecorder {
+        &self.phases
+    }
+
+    /// Stops the recording and waits until the self-profile has been saved.
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.recording_thread
+            .join()
+            .expect("couldn't join self-profiling thread");
+        eprintln!("Saved the self-profile to {:?}.", self.output_file);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicU64;
+
+    use super::*;
+
+    static NOW: AtomicU64 = AtomicU64::new(0);
+
+    fn test_clock() -> u64 {
+        NOW.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn records_intervals_and_instants() {
+        let phases = PhaseRecorder::new(1, test_clock);
+        NOW.store(10, Ordering::SeqCst);
+        let parse = phases.interval("Parse header");
+        NOW.store(20, Ordering::SeqCst);
+        phases.clone().instant("100000 records");
+        NOW.store(30, Ordering::SeqCst);
+        drop(parse);
+
+        let phase = |name: &str, start, end| Phase {
+            name: name.to_string(),
+            start,
+            end,
+        };
+        assert_eq!(
+            phases.take_phases(),
+            vec![
+                phase("100000 records", 20, None),
+                phase("Parse header", 10, Some(30)),
+            ]
+        );
+        assert_eq!(phases.take_phases(), vec![]);
+    }
+}
@@ -1,13 +1,39 @@
-use fxprof_processed_profile::{CategoryPairHandle, Frame, FrameFlags, FrameInfo};
+use fxprof_processed_profile::{
+    CategoryPairHandle, Frame, FrameFlags, FrameInfo, LibMappings, StringHandle,
+};
+use tracing::debug;
 
+use super::dynamic_linking::{DynamicLinkingFrameConversion, DynamicLinkingFrameKind};
+use super::frame_filter::HiddenFrameConversion;
 use super::jit_category_manager::{JsFrame, JsName};
-use super::lib_mappings::LibMappingsHierarchy;
+use super::lib_mappings::{LibMappingInfo, LibMappingsHierarchy};
+use super::profiler_overhead::{KernelFrameClassifier, ProfilerOverheadFrameConversion};
 use super::types::{StackFrame, StackMode};
+use super::wine::WineFrameConversion;
 
 #[derive(Debug, Clone, Copy)]
-pub struct StackConverter {
+pub struct StackConverter<'a> {
     user_category: CategoryPairHandle,
     kernel_category: CategoryPairHandle,
+    kernel_lib_mappings: Option<&'a LibMappings<LibMappingInfo>>,
+    guest: Option<GuestFrameConversion<'a>>,
+    dynamic_linking: Option<DynamicLinkingFrameConversion>,
+    wine: Option<WineFrameConversion>,
+    profiler_overhead: Option<ProfilerOverheadFrameConversion<'a>>,
+    hidden_frames: Option<HiddenFrameConversion<'a>>,
+}
+
+/// How frames from KVM guest code are converted.
+///
+/// Guest addresses must not be looked up in the host's mappings, so guest
+/// frames which can't be resolved become label frames.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestFrameConversion<'a> {
+    pub category: CategoryPairHandle,
+    pub user_label: StringHandle,
+    pub kernel_label: StringHandle,
+    /// The guest kernel and guest kernel module mappings, if known.
+    pub kernel_lib_mappings: Option<&'a LibMappings<LibMappingInfo>>,
 }
 
 pub struct ConvertedStackIter<'a> {
@@ -15,8 +41,23 @@ pub struct ConvertedStackIter<'a> {
     lib_mappings: &'a LibMappingsHierarchy,
     user_category: CategoryPairHandle,
     kernel_category: CategoryPairHandle,
+    kernel_lib_mappings: Option<&'a LibMappings<LibMappingInfo>>,
+    guest: Option<GuestFrameConversion<'a>>,
+    dynamic_linking: Option<DynamicLinkingFrameConversion>,
+    wine: Option<WineFrameConversion>,
+    profiler_overhead: Option<ProfilerOverheadFrameConversion<'a>>,
+    hidden_frames: Option<HiddenFrameConversion<'a>>,
     pending_frame_after_js: Option<FrameInfo>,
     js_name_for_baseline_interpreter: Option<JsName>,
+    /// Set once the profiler frames have been stripped from the stack.
+    stripped_profiler_frames: bool,
+    /// Whether the previous frame, towards the root, was in a PE image.
+    previous_frame_was_pe: bool,
+    /// Whether a frame has been returned yet.
+    returned_frame: bool,
+    /// The number of guest frames which were dropped because there's no
+    /// `guest` conversion.
+    dropped_guest_frame_count: usize,
 }
 
 impl<'a> Iterator for ConvertedStackIter<'a> {
@@ -32,10 +73,29 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
     }
 
     fn next(&mut self) -> Option<Self::Item> {
+        let frame_info = self.next_frame_info();
+        self.returned_frame |= frame_info.is_some();
+        if frame_info.is_none() && self.dropped_guest_frame_count != 0 {
+            debug!(
+                dropped_guest_frame_count = self.dropped_guest_frame_count,
+                "Dropped {} guest frames of a stack without a guest frame conversion",
+                self.dropped_guest_frame_count
+            );
+            self.dropped_guest_frame_count = 0;
+        }
+        frame_info
+    }
+}
+
+impl<'a> ConvertedStackIter<'a> {
+    fn next_frame_info(&mut self) -> Option<FrameInfo> {
         loop {
             if let Some(pending_frame_after_js) = self.pending_frame_after_js.take() {
                 return Some(pending_frame_after_js);
             }
+            if self.stripped_profiler_frames {
+                return None;
+            }
             let frame = self.inner.next()?;
             let (mode, addr, lookup_address, from_ip) = match *frame {
                 StackFrame::InstructionPointer(addr, mode) => (mode, addr, addr, true),
@@ -44,9 +104,51 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                 }
                 StackFrame::TruncatedStackMarker => continue,
             };
-            let (location, category, js_frame) = match mode {
+            if let Some(hidden_frames) = self.hidden_frames {
+                if let Some(rule) = self.hidden_frame_rule(frame) {
+                    return self.skip_hidden_frames(rule, hidden_frames);
+                }
+            }
+            let previous_frame_was_pe = std::mem::take(&mut self.previous_frame_was_pe);
+            let (location, mut category, js_frame) = match mode {
                 StackMode::User => match self.lib_mappings.convert_address(lookup_address) {
                     Some((relative_address, info)) => {
+                        let dynamic_linking_kind = info
+                            .dynamic_linking_ranges
+                            .as_ref()
+                            .and_then(|ranges| ranges.classify(relative_address));
+                        let mut category = info.category.unwrap_or(self.user_category);
+                        if let (Some(kind), Some(dynamic_linking)) =
+                            (dynamic_linking_kind, self.dynamic_linking)
+                        {
+                            if kind == DynamicLinkingFrameKind::PltStub
+                                && dynamic_linking.fold_plt
+                                && self.next_frame_is_outside_dynamic_linking_code()
+                            {
+                                // Charge the callee instead of the PLT stub.
+                                continue;
+                            }
+                            category = match kind {
+                                DynamicLinkingFrameKind::Loader => dynamic_linking.loader_category,
+                                _ => dynamic_linking.category,
+                            };
+                        }
+                        if let (Some(wine_module), Some(wine)) = (&info.wine_module, self.wine) {
+                            if wine_module.is_transition(relative_address) {
+                                if wine.fold_syscalls
+                                    && previous_frame_was_pe
+                                    && self.skip_unix_frames_before_pe_frame()
+                                {
+                                    return Some(FrameInfo {
+                                        frame: Frame::Label(wine.syscall_label),
+                                        category_pair: wine.category,
+                                        flags: FrameFlags::empty(),
+                                    });
+                                }
+                                category = wine.category;
+                            }
+                            self.previous_frame_was_pe = wine_module.is_pe();
+                        }
                         let location = match from_ip {
                             true => Frame::RelativeAddressFromInstructionPointer(
                                 info.lib_handle,
@@ -57,11 +159,7 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                                 relative_address,
                             ),
                         };
-                        (
-                            location,
-                            info.category.unwrap_or(self.user_category),
-                            info.js_frame,
-                        )
+                        (location, category, info.js_frame)
                     }
                     None => {
                         let location = match from_ip {
@@ -71,14 +169,77 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
                         (location, self.user_category, None)
                     }
                 },
+                // Kernel addresses are only looked up in the kernel mappings,
+                // and user addresses only in the process's mappings, so that
+                // the address ranges of the two can't be confused, e.g. with
+                // 52-bit user addresses on arm64.
                 StackMode::Kernel => {
-                    let location = match from_ip {
-                        true => Frame::InstructionPointer(addr),
-                        false => Frame::ReturnAddress(addr),
+                    let resolved = self
+                        .kernel_lib_mappings
+                        .and_then(|mappings| mappings.convert_address(lookup_address));
+                    let location = match (resolved, from_ip) {
+                        (Some((relative_address, info)), true) => {
+                            Frame::RelativeAddressFromInstructionPointer(
+                                info.lib_handle,
+                                relative_address,
+                            )
+                        }
+                        (Some((relative_address, info)), false) => {
+                            Frame::RelativeAddressFromReturnAddress(
+                                info.lib_handle,
+                                relative_address,
+                            )
+                        }
+                        (None, true) => Frame::InstructionPointer(addr),
+                        (None, false) => Frame::ReturnAddress(addr),
                     };
                     (location, self.kernel_category, None)
                 }
+                StackMode::GuestUser | StackMode::GuestKernel => {
+                    let Some(guest) = self.guest else {
+                        self.dropped_guest_frame_count += 1;
+                        continue;
+                    };
+                    let resolved = match (mode, guest.kernel_lib_mappings) {
+                        (StackMode::GuestKernel, Some(mappings)) => {
+                            mappings.convert_address(lookup_address)
+                        }
+                        _ => None,
+                    };
+                    let location = match (resolved, from_ip) {
+                        (Some((relative_address, info)), true) => {
+                            Frame::RelativeAddressFromInstructionPointer(
+                                info.lib_handle,
+                                relative_address,
+                            )
+                        }
+                        (Some((relative_address, info)), false) => {
+                            Frame::RelativeAddressFromReturnAddress(
+                                info.lib_handle,
+                                relative_address,
+                            )
+                        }
+                        (None, _) if mode == StackMode::GuestKernel => {
+                            Frame::Label(guest.kernel_label)
+                        }
+                        (None, _) => Frame::Label(guest.user_label),
+                    };
+                    (location, guest.category, None)
+                }
             };
+            if let Some(profiler_overhead) = self.profiler_overhead {
+                let classifier = profiler_overhead.classifier;
+                if mode == StackMode::Kernel && classifier.is_profiler_overhead(lookup_address) {
+                    category = profiler_overhead.category;
+                } else if profiler_overhead.strip
+                    && self.next_frame_is_profiler_overhead(classifier)
+                {
+                    // This is the interrupted frame. Drop the profiler frames
+                    // on top of it, and charge the overhead to this frame.
+                    category = profiler_overhead.category;
+                    self.stripped_profiler_frames = true;
+                }
+            }
             let frame_info = FrameInfo {
                 frame: location,
                 category_pair: category,
@@ -126,13 +287,148 @@ impl<'a> Iterator for ConvertedStackIter<'a> {
             return Some(frame_info);
         }
     }
+
+    /// Whether the next frame towards the leaf is a kernel frame in perf's
+    /// interrupt path.
+    fn next_frame_is_profiler_overhead(&self, classifier: &KernelFrameClassifier) -> bool {
+        match self
+            .inner
+            .clone()
+            .find(|frame| !matches!(frame, StackFrame::TruncatedStackMarker))
+        {
+            Some(StackFrame::InstructionPointer(addr, StackMode::Kernel)) => {
+                classifier.is_profiler_overhead(*addr)
+            }
+            Some(StackFrame::ReturnAddress(addr, StackMode::Kernel)) => {
+                classifier.is_profiler_overhead(addr.saturating_sub(1))
+            }
+            _ => false,
+        }
+    }
+
+    /// The index of the rule which hides this frame, if any.
+    fn hidden_frame_rule(&self, frame: &StackFrame) -> Option<usize> {
+        let lookup_address = match *frame {
+            StackFrame::InstructionPointer(addr, StackMode::User) => addr,
+            StackFrame::ReturnAddress(addr, StackMode::User) => addr.saturating_sub(1),
+            _ => return None,
+        };
+        let (relative_address, info) = self.lib_mappings.convert_address(lookup_address)?;
+        info.hidden_frames.as_ref()?.rule_for(relative_address)
+    }
+
+    /// Called for a hidden frame. Skips the hidden frames which follow it
+    /// towards the leaf, and returns a placeholder for all of them, labeled
+    /// with the rule of the first one. If the hidden frames reach the leaf,
+    /// no placeholder is returned, so that the caller of the hidden frames
+    /// becomes the leaf, unless there are no other frames.
+    fn skip_hidden_frames(
+        &mut self,
+        rule: usize,
+        hidden_frames: HiddenFrameConversion,
+    ) -> Option<FrameInfo> {
+        hidden_frames.filter.record_hidden_frame(rule);
+        loop {
+            let mut remaining = self.inner.clone();
+            let next_frame =
+                remaining.find(|frame| !matches!(frame, StackFrame::TruncatedStackMarker));
+            let Some(next_rule) = next_frame.and_then(|frame| self.hidden_frame_rule(frame)) else {
+                break;
+            };
+            hidden_frames.filter.record_hidden_frame(next_rule);
+            self.inner = remaining;
+        }
+        let reached_leaf = self
+            .inner
+            .clone()
+            .all(|frame| matches!(frame, StackFrame::TruncatedStackMarker));
+        if reached_leaf && self.returned_frame {
+            return None;
+        }
+        Some(FrameInfo {
+            frame: Frame::Label(hidden_frames.labels[rule]),
+            category_pair: self.user_category,
+            flags: FrameFlags::empty(),
+        })
+    }
+
+    /// If the frames towards the leaf are Unix-side user frames followed by a
+    /// frame in a PE image, skips the Unix-side frames and returns true. The
+    /// PE frame is the next frame after that.
+    fn skip_unix_frames_before_pe_frame(&mut self) -> bool {
+        let mut unix_frame_count = 0;
+        for frame in self.inner.clone() {
+            let lookup_address = match *frame {
+                StackFrame::InstructionPointer(addr, StackMode::User) => addr,
+                StackFrame::ReturnAddress(addr, StackMode::User) => addr.saturating_sub(1),
+                StackFrame::TruncatedStackMarker => {
+                    unix_frame_count += 1;
+                    continue;
+                }
+                _ => return false,
+            };
+            let is_pe = match self.lib_mappings.convert_address(lookup_address) {
+                Some((_, info)) => info.wine_module.as_ref().map_or(false, |m| m.is_pe()),
+                None => false,
+            };
+            if is_pe {
+                if unix_frame_count > 0 {
+                    self.inner.nth(unix_frame_count - 1);
+                }
+                return true;
+            }
+            unix_frame_count += 1;
+        }
+        false
+    }
+
+    /// Whether the next frame towards the leaf is a user frame in a known
+    /// library, outside of PLT stubs and the resolver.
+    fn next_frame_is_outside_dynamic_linking_code(&self) -> bool {
+        let lookup_address = match self
+            .inner
+            .clone()
+            .find(|frame| !matches!(frame, StackFrame::TruncatedStackMarker))
+        {
+            Some(StackFrame::InstructionPointer(addr, StackMode::User)) => *addr,
+            Some(StackFrame::ReturnAddress(addr, StackMode::User)) => addr.saturating_sub(1),
+            _ => return false,
+        };
+        match self.lib_mappings.convert_address(lookup_address) {
+            Some((relative_address, info)) => !matches!(
+                info.dynamic_linking_ranges
+                    .as_ref()
+                    .and_then(|ranges| ranges.classify(relative_address)),
+                Some(DynamicLinkingFrameKind::PltStub | DynamicLinkingFrameKind::Resolver)
+            ),
+            None => false,
+        }
+    }
 }
 
-impl StackConverter {
-    pub fn new(user_category: CategoryPairHandle, kernel_category: CategoryPairHandle) -> Self {
+impl<'g> StackConverter<'g> {
+    /// `kernel_lib_mappings` are the only mappings which kernel frames are
+    /// looked up in.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        user_category: CategoryPairHandle,
+        kernel_category: CategoryPairHandle,
+        kernel_lib_mappings: Option<&'g LibMappings<LibMappingInfo>>,
+        guest: Option<GuestFrameConversion<'g>>,
+        dynamic_linking: Option<DynamicLinkingFrameConversion>,
+        wine: Option<WineFrameConversion>,
+        profiler_overhead: Option<ProfilerOverheadFrameConversion<'g>>,
+        hidden_frames: Option<HiddenFrameConversion<'g>>,
+    ) -> Self {
         Self {
             user_category,
             kernel_category,
+            kernel_lib_mappings,
+            guest,
+            dynamic_linking,
+            wine,
+            profiler_overhead,
+            hidden_frames,
         }
     }
 
@@ -140,14 +436,285 @@ impl StackConverter {
         &self,
         stack: &'a [StackFrame],
         lib_mappings: &'a LibMappingsHierarchy,
-    ) -> impl Iterator<Item = FrameInfo> + 'a {
+    ) -> impl Iterator<Item = FrameInfo> + 'a
+    where
+        'g: 'a,
+    {
         ConvertedStackIter {
             inner: stack.iter().rev(),
             lib_mappings,
             user_category: self.user_category,
             kernel_category: self.kernel_category,
+            kernel_lib_mappings: self.kernel_lib_mappings,
+            guest: self.guest,
+            dynamic_linking: self.dynamic_linking,
+            wine: self.wine,
+            profiler_overhead: self.profiler_overhead,
+            hidden_frames: self.hidden_frames,
             pending_frame_after_js: None,
             js_name_for_baseline_interpreter: None,
+            stripped_profiler_frames: false,
+            previous_frame_was_pe: false,
+            returned_frame: false,
+            dropped_guest_frame_count: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use debugid::DebugId;
+    use fxprof_processed_profile::{
+        CategoryColor, LibraryHandle, LibraryInfo, Profile, ReferenceTimestamp, SamplingInterval,
+    };
+
+    use super::*;
+    use crate::shared::frame_filter::{FrameFilter, HiddenFrameRanges, HideRule, LibraryGlob};
+    use crate::shared::lib_mappings::{LibMappingAdd, LibMappingOp, LibMappingOpQueue};
+    use crate::shared::wine::WineModuleInfo;
+
+    fn add_lib(profile: &mut Profile, name: &str) -> LibraryHandle {
+        profile.add_lib(LibraryInfo {
+            name: name.to_string(),
+            debug_name: name.to_string(),
+            path: name.to_string(),
+            debug_path: name.to_string(),
+            debug_id: DebugId::nil(),
+            code_id: None,
+            arch: None,
+            symbol_table: None,
+        })
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn folds_wine_syscalls_between_pe_frames() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let wine_category = profile.add_category("Wine", CategoryColor::Magenta).into();
+        let syscall_label = profile.intern_string("Windows syscall");
+
+        // Each library is mapped at (index + 1) * 0x10000.
+        let pe = Arc::new(WineModuleInfo::new(true, Vec::new()));
+        let ntdll_so = Arc::new(WineModuleInfo::new(false, vec![0x100..0x200]));
+        let libs = [
+            ("game.exe", Some(pe.clone())),
+            ("ntdll.dll", Some(pe.clone())),
+            ("ntdll.so", Some(ntdll_so)),
+            ("win32u.so", None),
+            ("user32.dll", Some(pe)),
+        ];
+        let mut ops = LibMappingOpQueue::default();
+        let mut lib_handles = Vec::new();
+        for (i, (name, wine_module)) in libs.into_iter().enumerate() {
+            let lib_handle = add_lib(&mut profile, name);
+            let start_avma = (i as u64 + 1) * 0x10000;
+            ops.push(
+                0,
+                LibMappingOp::Add(LibMappingAdd {
+                    start_avma,
+                    end_avma: start_avma + 0x10000,
+                    relative_address_at_start: 0,
+                    info: LibMappingInfo {
+                        wine_module,
+                        ..LibMappingInfo::new_lib(lib_handle)
+                    },
+                }),
+            );
+            lib_handles.push(lib_handle);
         }
+        let mut lib_mappings = LibMappingsHierarchy::new(ops);
+        lib_mappings.process_ops(0);
+        let [game_exe, ntdll_dll, ntdll_so, win32u_so, user32_dll] = lib_handles[..] else {
+            unreachable!()
+        };
+
+        // A window procedure which is called back from a system call, leaf first.
+        let user = |addr| StackFrame::ReturnAddress(addr, StackMode::User);
+        let callback_stack = [
+            StackFrame::InstructionPointer(0x10500, StackMode::User), // game.exe WndProc
+            user(0x50300),                                            // user32.dll
+            user(0x30180), // ntdll.so call_user_mode_callback
+            user(0x40200), // win32u.so NtUserDispatchMessage
+            user(0x30150), // ntdll.so __wine_syscall_dispatcher
+            user(0x20100), // ntdll.dll NtUserDispatchMessage stub
+            user(0x10400), // game.exe main
+        ];
+        // A system call which is sampled on the Unix side.
+        let syscall_stack = [
+            StackFrame::InstructionPointer(0x40200, StackMode::User), // win32u.so
+            user(0x30150), // ntdll.so __wine_syscall_dispatcher
+            user(0x20100), // ntdll.dll
+        ];
+
+        let convert = |stack: &[StackFrame], fold_syscalls| {
+            let wine = WineFrameConversion {
+                category: wine_category,
+                fold_syscalls,
+                syscall_label,
+            };
+            StackConverter::new(
+                user_category,
+                kernel_category,
+                None,
+                None,
+                None,
+                Some(wine),
+                None,
+                None,
+            )
+            .convert_stack(stack, &lib_mappings)
+            .map(|frame| (frame.frame, frame.category_pair))
+            .collect::<Vec<_>>()
+        };
+        // Return addresses are looked up one byte earlier.
+        let from_ra = |lib, relative_address| {
+            (
+                Frame::RelativeAddressFromReturnAddress(lib, relative_address),
+                user_category,
+            )
+        };
+        let wine_frame = |lib, relative_address| {
+            (
+                Frame::RelativeAddressFromReturnAddress(lib, relative_address),
+                wine_category,
+            )
+        };
+
+        assert_eq!(
+            convert(&callback_stack, true),
+            vec![
+                from_ra(game_exe, 0x3ff),
+                from_ra(ntdll_dll, 0xff),
+                (Frame::Label(syscall_label), wine_category),
+                from_ra(user32_dll, 0x2ff),
+                (
+                    Frame::RelativeAddressFromInstructionPointer(game_exe, 0x500),
+                    user_category
+                ),
+            ]
+        );
+        // Without folding, only the transition frames are recategorized.
+        let unfolded = convert(&callback_stack, false);
+        assert_eq!(unfolded.len(), callback_stack.len());
+        assert_eq!(unfolded[2], wine_frame(ntdll_so, 0x14f));
+        assert_eq!(unfolded[3], from_ra(win32u_so, 0x1ff));
+        assert_eq!(unfolded[4], wine_frame(ntdll_so, 0x17f));
+        // Frames which don't lead back into PE code are kept.
+        assert_eq!(
+            convert(&syscall_stack, true),
+            vec![
+                from_ra(ntdll_dll, 0xff),
+                wine_frame(ntdll_so, 0x14f),
+                (
+                    Frame::RelativeAddressFromInstructionPointer(win32u_so, 0x200),
+                    user_category
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn hides_frames_and_promotes_the_caller_to_leaf() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_millis(1),
+        );
+        let user_category = profile.add_category("User", CategoryColor::Yellow).into();
+        let kernel_category = profile.add_category("Kernel", CategoryColor::Orange).into();
+        let filter = FrameFilter::new(vec![
+            HideRule::Library(LibraryGlob::new("libclang_rt.*").unwrap()),
+            HideRule::SymbolPrefix("__interceptor_".to_string()),
+        ])
+        .unwrap();
+        let labels = [
+            profile.intern_string("(hidden: libclang_rt.*)"),
+            profile.intern_string("(hidden: __interceptor_*)"),
+        ];
+
+        // app is mapped at 0x10000, libclang_rt.asan.so at 0x20000. The
+        // interceptors are at 0x100..0x200 in app.
+        let app = add_lib(&mut profile, "app");
+        let asan = add_lib(&mut profile, "libclang_rt.asan.so");
+        let hidden = [
+            HiddenFrameRanges::new(None, vec![(0x100..0x200, 1)]),
+            HiddenFrameRanges::new(Some(0), Vec::new()),
+        ];
+        let mut ops = LibMappingOpQueue::default();
+        for (i, (lib_handle, hidden_frames)) in [app, asan].into_iter().zip(hidden).enumerate() {
+            let start_avma = (i as u64 + 1) * 0x10000;
+            ops.push(
+                0,
+                LibMappingOp::Add(LibMappingAdd {
+                    start_avma,
+                    end_avma: start_avma + 0x10000,
+                    relative_address_at_start: 0,
+                    info: LibMappingInfo {
+                        hidden_frames: Some(Arc::new(hidden_frames)),
+                        ..LibMappingInfo::new_lib(lib_handle)
+                    },
+                }),
+            );
+        }
+        let mut lib_mappings = LibMappingsHierarchy::new(ops);
+        lib_mappings.process_ops(0);
+
+        let convert = |stack: &[StackFrame]| {
+            let hidden_frames = HiddenFrameConversion {
+                filter: &filter,
+                labels: &labels,
+            };
+            StackConverter::new(
+                user_category,
+                kernel_category,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(hidden_frames),
+            )
+            .convert_stack(stack, &lib_mappings)
+            .map(|frame| frame.frame)
+            .collect::<Vec<_>>()
+        };
+        let user = |addr| StackFrame::ReturnAddress(addr, StackMode::User);
+        let leaf = |addr| StackFrame::InstructionPointer(addr, StackMode::User);
+
+        // Leaf first: app -> interceptor -> asan -> app -> main.
+        let callback_stack = [
+            leaf(0x10800),
+            user(0x20400),
+            user(0x10150),
+            user(0x10600),
+            user(0x10400),
+        ];
+        assert_eq!(
+            convert(&callback_stack),
+            vec![
+                Frame::RelativeAddressFromReturnAddress(app, 0x3ff),
+                Frame::RelativeAddressFromReturnAddress(app, 0x5ff),
+                Frame::Label(labels[1]),
+                Frame::RelativeAddressFromInstructionPointer(app, 0x800),
+            ]
+        );
+        // Sampled inside the interceptors: the caller becomes the leaf.
+        let leaf_stack = [leaf(0x20400), user(0x10150), user(0x10400)];
+        assert_eq!(
+            convert(&leaf_stack),
+            vec![Frame::RelativeAddressFromReturnAddress(app, 0x3ff)]
+        );
+        // Only hidden frames: the sample keeps a placeholder.
+        assert_eq!(convert(&[leaf(0x20400)]), vec![Frame::Label(labels[0])]);
+        assert_eq!(filter.hidden_frame_count(0), 3);
+        assert_eq!(filter.hidden_frame_count(1), 2);
     }
 }
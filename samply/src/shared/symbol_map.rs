@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use debugid::CodeId;
+use fxprof_processed_profile::{Symbol, SymbolTable};
+use tracing::warn;
+
+use super::frame_filter::LibraryGlob;
+
+/// Parses a `--symbol-map` argument of the form LIB_GLOB=FILE.
+pub fn parse_symbol_map_arg(s: &str) -> Result<(LibraryGlob, PathBuf), String> {
+    let (glob, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected LIB_GLOB=FILE, got {s:?}"))?;
+    if path.is_empty() {
+        return Err(format!("expected LIB_GLOB=FILE, got {s:?}"));
+    }
+    Ok((LibraryGlob::new(glob)?, PathBuf::from(path)))
+}
+
+/// The format of a `--symbol-map` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolMapFormat {
+    /// A GNU ld or LLD map file, from `-Map`. Its addresses are SVMAs.
+    LinkerMap,
+    /// Lines of `hex_offset,name`, where the offsets are relative addresses,
+    /// i.e. relative to the library's relative address base.
+    Csv,
+}
+
+impl SymbolMapFormat {
+    /// `.csv` files are CSV, everything else is a linker map.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => SymbolMapFormat::Csv,
+            _ => SymbolMapFormat::LinkerMap,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MapSymbol {
+    address: u64,
+    size: Option<u64>,
+    name: String,
+}
+
+/// The symbols of a `--symbol-map` file, for libraries which have no symbols
+/// of their own, e.g. stripped proprietary binaries.
+#[derive(Debug)]
+pub struct SymbolMap {
+    glob: LibraryGlob,
+    format: SymbolMapFormat,
+    /// Sorted by address, without duplicate addresses or overlaps.
+    symbols: Vec<MapSymbol>,
+    /// The symbol tables which were created, by the library's relative
+    /// address base.
+    symbol_tables: HashMap<u64, Arc<SymbolTable>>,
+}
+
+impl SymbolMap {
+    pub fn from_file(glob: LibraryGlob, path: &Path) -> std::io::Result<Self> {
+        let data = std::fs::read(path)?;
+        let format = SymbolMapFormat::from_path(path);
+        let source = path.display().to_string();
+        Ok(Self::parse(
+            glob,
+            &String::from_utf8_lossy(&data),
+            format,
+            &source,
+        ))
+    }
+
+    /// `source` names the file in the warnings about its entries.
+    pub fn parse(glob: LibraryGlob, data: &str, format: SymbolMapFormat, source: &str) -> Self {
+        let symbols = match format {
+            SymbolMapFormat::LinkerMap => parse_linker_map(data),
+            SymbolMapFormat::Csv => parse_csv(data, source),
+        };
+        if symbols.is_empty() {
+            warn!(source, "The symbol map {source} has no symbols");
+        }
+        Self {
+            glob,
+            format,
+            symbols: normalize(symbols, source),
+            symbol_tables: HashMap::new(),
+        }
+    }
+
+    /// The glob is matched against the library's file name, or against its
+    /// path if it contains a slash, or against its build ID in hex.
+    pub fn matches(&self, name: &str, path: &str, build_id: Option<&[u8]>) -> bool {
+        if self.glob.matches(name, path) {
+            return true;
+        }
+        let Some(build_id) = build_id else {
+            return false;
+        };
+        let build_id = CodeId::from_binary(build_id).to_string();
+        self.glob.matches(&build_id, &build_id)
+    }
+
+    /// Returns the symbol table with addresses relative to `base_svma`, the
+    /// library's relative address base. The addresses of CSV files are
+    /// already relative. Symbols below the base are dropped.
+    pub fn symbol_table(&mut self, base_svma: u64) -> Arc<SymbolTable> {
+        let base_svma = match self.format {
+            SymbolMapFormat::LinkerMap => base_svma,
+            SymbolMapFormat::Csv => 0,
+        };
+        let symbols = &self.symbols;
+        self.symbol_tables
+            .entry(base_svma)
+            .or_insert_with(|| {
+                let symbols = symbols
+                    .iter()
+                    .filter_map(|symbol| {
+                        let address = u32::try_from(symbol.address.checked_sub(base_svma)?).ok()?;
+                        Some(Symbol {
+                            address,
+                            size: symbol.size.and_then(|size| u32::try_from(size).ok()),
+                            name: symbol.name.clone(),
+                        })
+                    })
+                    .collect();
+                Arc::new(SymbolTable::new(symbols))
+            })
+            .clone()
+    }
+}
+
+/// The `--symbol-map` files. The first map which matches a library wins.
+#[derive(Debug, Default)]
+pub struct SymbolMaps(Vec<SymbolMap>);
+
+impl SymbolMaps {
+    pub fn new(maps: Vec<SymbolMap>) -> Self {
+        Self(maps)
+    }
+
+    /// Returns the symbol table of the first map which matches the library,
+    /// which takes precedence over the library's `existing` symbol table.
+    pub fn symbol_table_for(
+        &mut self,
+        name: &str,
+        path: &str,
+        build_id: Option<&[u8]>,
+        base_svma: u64,
+        existing: Option<Arc<SymbolTable>>,
+    ) -> Option<Arc<SymbolTable>> {
+        match self
+            .0
+            .iter_mut()
+            .find(|map| map.matches(name, path, build_id))
+        {
+            Some(map) => Some(map.symbol_table(base_svma)),
+            None => existing,
+        }
+    }
+}
+
+/// Parses the symbol lines of GNU ld and LLD map files.
+///
+/// GNU ld symbol lines have just an address and a name, e.g.
+/// `                0x0000000000001139                main`.
+/// LLD symbol lines have the VMA, LMA, size and alignment, then the name,
+/// e.g. `          201139           201139       1b     1                 main`.
+/// Section lines, input file lines and assignments are skipped.
+fn parse_linker_map(data: &str) -> Vec<MapSymbol> {
+    let is_lld = data
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .map_or(false, |header| {
+            header.split_whitespace().next() == Some("VMA")
+        });
+    data.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match (is_lld, fields.as_slice()) {
+                (false, [address, name]) => {
+                    let address = u64::from_str_radix(address.strip_prefix("0x")?, 16).ok()?;
+                    symbol_name(name).map(|name| MapSymbol {
+                        address,
+                        size: None,
+                        name,
+                    })
+                }
+                (true, [vma, _lma, size, _align, name]) => {
+                    let address = u64::from_str_radix(vma, 16).ok()?;
+                    let size = u64::from_str_radix(size, 16).ok()?;
+                    symbol_name(name).map(|name| MapSymbol {
+                        address,
+                        size: (size != 0).then(|| size),
+                        name,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Returns None for the names of sections, input files and file globs.
+fn symbol_name(name: &str) -> Option<String> {
+    if name.starts_with('.') || name.starts_with('*') || name.contains(":(") {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Parses lines of `hex_offset,name`. The offset can have a 0x prefix, and
+/// the name can contain commas. Empty lines, `#` comments and a header line
+/// are skipped.
+fn parse_csv(data: &str, source: &str) -> Vec<MapSymbol> {
+    let mut symbols = Vec::new();
+    for (line_index, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line.split_once(',').and_then(|(offset, name)| {
+            let offset = offset.trim();
+            let offset = offset.strip_prefix("0x").unwrap_or(offset);
+            let address = u64::from_str_radix(offset, 16).ok()?;
+            let name = name.trim();
+            (!name.is_empty()).then(|| MapSymbol {
+                address,
+                size: None,
+                name: name.to_string(),
+            })
+        });
+        match parsed {
+            Some(symbol) => symbols.push(symbol),
+            None if line_index == 0 => {
+                // A header line, e.g. "offset,name".
+            }
+            None => {
+                let line_number = line_index + 1;
+                warn!(
+                    source,
+                    "Ignoring line {line_number} of the symbol map {source}: {line:?}"
+                );
+            }
+        }
+    }
+    symbols
+}
+
+/// Sorts the symbols by address, keeps the first name for each address,
+/// and shortens symbols which overlap the next one.
+fn normalize(mut symbols: Vec<MapSymbol>, source: &str) -> Vec<MapSymbol> {
+    if symbols
+        .windows(2)
+        .any(|pair| pair[0].address > pair[1].address)
+    {
+        warn!(
+            source,
+            "The symbols in the symbol map {source} aren't sorted by address"
+        );
+        symbols.sort_by_key(|symbol| symbol.address);
+    }
+    let len_before_dedup = symbols.len();
+    symbols.dedup_by_key(|symbol| symbol.address);
+    let duplicate_count = len_before_dedup - symbols.len();
+    if duplicate_count != 0 {
+        warn!(
+            source,
+            "Ignoring {duplicate_count} symbols in the symbol map {source} whose address already has a symbol"
+        );
+    }
+    let mut overlap_count = 0;
+    for i in 1..symbols.len() {
+        let next_address = symbols[i].address;
+        let symbol = &mut symbols[i - 1];
+        if let Some(size) = symbol.size {
+            if symbol.address + size > next_address {
+                symbol.size = Some(next_address - symbol.address);
+                overlap_count += 1;
+            }
+        }
+    }
+    if overlap_count != 0 {
+        warn!(
+            source,
+            "Shortened {overlap_count} symbols in the symbol map {source} which overlap the next symbol"
+        );
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GNU_MAP: &str = "
+Memory Configuration
+
+Linker script and memory map
+
+LOAD /usr/lib/crt1.o
+ .text          0x0000000000401040       0x26 /usr/lib/crt1.o
+                0x0000000000401040                _start
+ *(.text.unlikely .text.*_unlikely .text.unlikely.*)
+ .text.blob_decrypt
+                0x0000000000401070       0x40 blob.o
+                0x0000000000401070                blob_decrypt
+ .text          0x00000000004010b0       0x30 blob.o
+                0x00000000004010c0                blob_verify
+                0x00000000004010b0                blob_init
+                0x00000000004010b0                blob_init_alias
+                0x0000000000402000                . = ALIGN (0x8)
+";
+
+    const LLD_MAP: &str = "
+             VMA              LMA     Size Align Out     In      Symbol
+          401040           401040       30    16 .text
+          401040           401040       30    16         blob.o:(.text)
+          401040           401040       20     1                 blob_init
+          401050           401050       20     1                 blob_verify
+";
+
+    const CSV: &str = "offset,name
+# From the vendor's map.
+0x1070,blob_decrypt
+1040,_start
+10b0,std::pair<int, int> blob_init()
+zz,broken
+";
+
+    fn map(glob: &str, data: &str, format: SymbolMapFormat) -> SymbolMap {
+        SymbolMap::parse(LibraryGlob::new(glob).unwrap(), data, format, "test")
+    }
+
+    fn names(table: &SymbolTable, addresses: &[u32]) -> Vec<Option<String>> {
+        addresses
+            .iter()
+            .map(|&address| table.lookup(address).map(|symbol| symbol.name.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_gnu_ld_map() {
+        let mut map = map("libblob.so", GNU_MAP, SymbolMapFormat::LinkerMap);
+        let table = map.symbol_table(0x400000);
+        assert_eq!(
+            names(&table, &[0x1045, 0x1080, 0x10b4, 0x10c8, 0xfff]),
+            vec![
+                Some("_start".to_string()),
+                Some("blob_decrypt".to_string()),
+                Some("blob_init".to_string()),
+                Some("blob_verify".to_string()),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_lld_map() {
+        let mut map = map("libblob.so", LLD_MAP, SymbolMapFormat::LinkerMap);
+        let table = map.symbol_table(0x400000);
+        // blob_init is shortened so that it doesn't overlap blob_verify.
+        assert_eq!(
+            names(&table, &[0x1048, 0x1058, 0x1070]),
+            vec![
+                Some("blob_init".to_string()),
+                Some("blob_verify".to_string()),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_csv_with_relative_addresses() {
+        let mut map = map("libblob.so", CSV, SymbolMapFormat::Csv);
+        // CSV offsets are already relative, whatever the base.
+        let table = map.symbol_table(0x400000);
+        assert_eq!(
+            names(&table, &[0x1040, 0x1075, 0x10b0]),
+            vec![
+                Some("_start".to_string()),
+                Some("blob_decrypt".to_string()),
+                Some("std::pair<int, int> blob_init()".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn takes_precedence_over_existing_symbol_table() {
+        let existing = Arc::new(SymbolTable::new(vec![Symbol {
+            address: 0x1040,
+            size: None,
+            name: "existing".to_string(),
+        }]));
+        let build_id = [0xab, 0xcd, 0xef];
+        let mut maps = SymbolMaps::new(vec![
+            map("abcdef*", CSV, SymbolMapFormat::Csv),
+            map("libblob*.so", GNU_MAP, SymbolMapFormat::LinkerMap),
+        ]);
+
+        let lookup = |maps: &mut SymbolMaps, name: &str, build_id: Option<&[u8]>| {
+            let table = maps.symbol_table_for(
+                name,
+                &format!("/opt/vendor/{name}"),
+                build_id,
+                0x400000,
+                Some(existing.clone()),
+            );
+            names(&table.unwrap(), &[0x10b0])[0].clone().unwrap()
+        };
+        assert_eq!(lookup(&mut maps, "libblob.so", None), "blob_init");
+        assert_eq!(
+            lookup(&mut maps, "libother.so", Some(&build_id)),
+            "std::pair<int, int> blob_init()"
+        );
+        assert_eq!(lookup(&mut maps, "libother.so", None), "existing");
+    }
+
+    #[test]
+    fn parses_symbol_map_arg() {
+        let (glob, path) = parse_symbol_map_arg("libblob*.so=/maps/blob.map").unwrap();
+        assert!(glob.matches("libblob.so.1.so", "/usr/lib/libblob.so.1.so"));
+        assert_eq!(path, PathBuf::from("/maps/blob.map"));
+        assert!(parse_symbol_map_arg("libblob.so").is_err());
+        assert!(parse_symbol_map_arg("libblob.so=").is_err());
+        assert!(parse_symbol_map_arg("=blob.map").is_err());
+    }
+}
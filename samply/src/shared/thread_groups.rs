@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use fxprof_processed_profile::{Profile, ThreadHandle};
+use regex::Regex;
+
+/// Thread groups for the thread families of common runtimes. User-specified
+/// groups are checked first.
+pub const BUILTIN_THREAD_GROUPS: &[(&str, &str)] = &[
+    ("GC", r".*GC.*|.*gc worker.*"),
+    ("Render", r".*Render.*|.*Compositor.*|.*Raster.*|.*Paint.*"),
+    ("IO", r".*\bIO\b.*|.*I/O.*|iou-.*|.*io_uring.*"),
+];
+
+/// Parses a `<name>=<regex>` thread group specification. The regex has to
+/// match the whole thread name.
+pub fn parse_thread_group(s: &str) -> Result<(String, Regex), String> {
+    let (name, pattern) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <name>=<regex>, got {s:?}"))?;
+    Ok((name.to_string(), full_match_regex(pattern)?))
+}
+
+/// Returns the built-in thread groups.
+pub fn builtin_thread_groups() -> Vec<(String, Regex)> {
+    BUILTIN_THREAD_GROUPS
+        .iter()
+        .map(|(name, pattern)| (name.to_string(), full_match_regex(pattern).unwrap()))
+        .collect()
+}
+
+fn full_match_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{pattern})$")).map_err(|e| e.to_string())
+}
+
+/// Assigns threads to named groups based on their names, and keeps the
+/// profile's thread groups up-to-date as threads get renamed.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadGroups {
+    groups: Vec<(String, Regex)>,
+    group_index_by_thread: HashMap<ThreadHandle, usize>,
+}
+
+impl ThreadGroups {
+    pub fn new(groups: Vec<(String, Regex)>) -> Self {
+        Self {
+            groups,
+            group_index_by_thread: HashMap::new(),
+        }
+    }
+
+    fn group_index_for_name(&self, thread_name: &str) -> Option<usize> {
+        self.groups
+            .iter()
+            .position(|(_, regex)| regex.is_match(thread_name))
+    }
+
+    /// Called whenever the name of a thread changes.
+    pub fn on_thread_name_change(
+        &mut self,
+        profile: &mut Profile,
+        thread: ThreadHandle,
+        thread_name: &str,
+    ) {
+        if self.groups.is_empty() {
+            return;
+        }
+        let group_index = self.group_index_for_name(thread_name);
+        let prev_group_index = match group_index {
+            Some(group_index) => self.group_index_by_thread.insert(thread, group_index),
+            None => self.group_index_by_thread.remove(&thread),
+        };
+        if group_index != prev_group_index {
+            let group_name = group_index.map(|i| self.groups[i].0.as_str());
+            profile.set_thread_group(thread, group_name);
+        }
+    }
+
+    /// Returns the number of threads in each non-empty group, in the order
+    /// in which the groups were specified.
+    pub fn group_sizes(&self) -> Vec<(&str, usize)> {
+        let mut counts = vec![0; self.groups.len()];
+        for group_index in self.group_index_by_thread.values() {
+            counts[*group_index] += 1;
+        }
+        self.groups
+            .iter()
+            .zip(counts)
+            .filter(|(_, count)| *count != 0)
+            .map(|((name, _), count)| (name.as_str(), count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_whole_names() {
+        let mut groups = vec![parse_thread_group("Pool=pool-\\d+").unwrap()];
+        groups.extend(builtin_thread_groups());
+        let groups = ThreadGroups::new(groups);
+        let group = |name: &str| {
+            groups
+                .group_index_for_name(name)
+                .map(|i| &*groups.groups[i].0)
+        };
+        assert_eq!(group("pool-12"), Some("Pool"));
+        assert_eq!(group("my-pool-12"), None);
+        assert_eq!(group("G1 Young RemSet GC Thread"), Some("GC"));
+        assert_eq!(group("gc worker 3"), Some("GC"));
+        assert_eq!(group("Compositor"), Some("Render"));
+        assert_eq!(group("Socket IO Thread"), Some("IO"));
+        assert_eq!(group("Radio"), None);
+        assert!(parse_thread_group("no-equals-sign").is_err());
+        assert!(parse_thread_group("Bad=(").is_err());
+    }
+}
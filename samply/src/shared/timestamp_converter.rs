@@ -9,6 +9,13 @@ impl TimestampConverter {
         Self { reference_ns }
     }
 
+    /// The timestamp, in the clock of the input, which is converted to zero.
+    pub fn reference_timestamp(&self) -> u64 {
+        self.reference_ns
+    }
+
+    /// Timestamps which are earlier than the reference timestamp are converted
+    /// to zero.
     pub fn convert_time(&self, ktime_ns: u64) -> Timestamp {
         Timestamp::from_nanos_since_reference(ktime_ns.saturating_sub(self.reference_ns))
     }
@@ -15,6 +15,10 @@ pub type FastHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FxHasher>>;
 pub enum StackMode {
     User,
     Kernel,
+    /// User code in a KVM guest.
+    GuestUser,
+    /// Kernel code in a KVM guest.
+    GuestKernel,
 }
 
 impl StackMode {
@@ -24,18 +28,28 @@ impl StackMode {
     /// which are `>= PERF_CONTEXT_MAX`.
     pub fn from_context_frame(frame: u64) -> Option<Self> {
         match frame {
-            PERF_CONTEXT_KERNEL | PERF_CONTEXT_GUEST_KERNEL => Some(Self::Kernel),
-            PERF_CONTEXT_USER | PERF_CONTEXT_GUEST | PERF_CONTEXT_GUEST_USER => Some(Self::User),
+            PERF_CONTEXT_KERNEL => Some(Self::Kernel),
+            PERF_CONTEXT_USER => Some(Self::User),
+            // PERF_CONTEXT_GUEST is usually followed by a more specific guest
+            // context frame. Until then, assume we're in the guest kernel.
+            PERF_CONTEXT_GUEST | PERF_CONTEXT_GUEST_KERNEL => Some(Self::GuestKernel),
+            PERF_CONTEXT_GUEST_USER => Some(Self::GuestUser),
             _ => None,
         }
     }
+
+    pub fn is_guest(&self) -> bool {
+        matches!(self, Self::GuestUser | Self::GuestKernel)
+    }
 }
 
 impl From<CpuMode> for StackMode {
     /// Convert CpuMode into StackMode.
     fn from(cpu_mode: CpuMode) -> Self {
         match cpu_mode {
-            CpuMode::Kernel | CpuMode::GuestKernel => Self::Kernel,
+            CpuMode::Kernel => Self::Kernel,
+            CpuMode::GuestKernel => Self::GuestKernel,
+            CpuMode::GuestUser => Self::GuestUser,
             _ => Self::User,
         }
     }
@@ -4,7 +4,10 @@ use fxprof_processed_profile::{CpuDelta, ThreadHandle, Timestamp};
 
 use crate::shared::types::{FastHashMap, StackFrame};
 
-use super::{process_sample_data::RssStatMember, types::StackMode};
+use super::{
+    marker_addresses::MarkerValue, memory_access::MemoryAccessKind,
+    process_sample_data::RssStatMember, types::StackMode,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct UnresolvedSamples {
@@ -27,6 +30,40 @@ impl UnresolvedSamples {
         self.samples_and_markers.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.samples_and_markers.len()
+    }
+
+    pub fn has_samples_for_thread(&self, thread_handle: ThreadHandle) -> bool {
+        self.prev_sample_info_per_thread
+            .contains_key(&thread_handle)
+    }
+
+    /// The stack of the most recent sample on this thread, if it has any samples.
+    pub fn last_sample_stack(&self, thread_handle: ThreadHandle) -> Option<UnresolvedStackHandle> {
+        self.prev_sample_info_per_thread
+            .get(&thread_handle)
+            .map(|info| info.stack)
+    }
+
+    /// Add a sample which wasn't measured, but which shows the last known stack
+    /// of a thread which didn't get any real samples.
+    pub fn add_synthesized_sample(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            sample_or_marker: SampleOrMarker::SynthesizedSample,
+        });
+    }
+
     pub fn add_sample(
         &mut self,
         thread_handle: ThreadHandle,
@@ -36,13 +73,74 @@ impl UnresolvedSamples {
         cpu_delta: CpuDelta,
         weight: i32,
     ) {
+        let data = SampleData {
+            cpu_delta,
+            weight,
+            is_cow_fault: false,
+            memory_access: None,
+            request_span: None,
+        };
+        self.push_sample(thread_handle, timestamp, timestamp_mono, stack, data);
+    }
+
+    /// Add a sample which was taken while the thread was handling a write
+    /// fault on a copy-on-write page.
+    pub fn add_cow_fault_sample(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        cpu_delta: CpuDelta,
+        weight: i32,
+    ) {
+        let data = SampleData {
+            cpu_delta,
+            weight,
+            is_cow_fault: true,
+            memory_access: None,
+            request_span: None,
+        };
+        self.push_sample(thread_handle, timestamp, timestamp_mono, stack, data);
+    }
+
+    /// Add a sample of a memory access, e.g. from ARM SPE, whose weight is
+    /// the latency of the access.
+    pub fn add_memory_access_sample(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        latency: i32,
+        kind: MemoryAccessKind,
+    ) {
+        let data = SampleData {
+            cpu_delta: CpuDelta::ZERO,
+            weight: latency,
+            is_cow_fault: false,
+            memory_access: Some(kind),
+            request_span: None,
+        };
+        self.push_sample(thread_handle, timestamp, timestamp_mono, stack, data);
+    }
+
+    fn push_sample(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        data: SampleData,
+    ) {
+        let cpu_delta = data.cpu_delta;
         let sample_index = self.samples_and_markers.len();
         self.samples_and_markers.push(UnresolvedSampleOrMarker {
             thread_handle,
             timestamp,
             timestamp_mono,
             stack,
-            sample_or_marker: SampleOrMarker::Sample(SampleData { weight, cpu_delta }),
+            sample_or_marker: SampleOrMarker::Sample(data),
         });
         self.prev_sample_info_per_thread.insert(
             thread_handle,
@@ -54,6 +152,19 @@ impl UnresolvedSamples {
         );
     }
 
+    /// Labels the sample which was added last with the request of a
+    /// [`RequestSpans`](super::request_spans::RequestSpans) span.
+    pub fn set_request_span_of_last_sample(&mut self, request_span: Option<u32>) {
+        let Some(UnresolvedSampleOrMarker {
+            sample_or_marker: SampleOrMarker::Sample(data),
+            ..
+        }) = self.samples_and_markers.last_mut()
+        else {
+            return;
+        };
+        data.request_span = request_span;
+    }
+
     #[allow(unused)]
     pub fn add_sample_same_stack_zero_cpu(
         &mut self,
@@ -81,6 +192,9 @@ impl UnresolvedSamples {
                         sample_or_marker: SampleOrMarker::Sample(SampleData {
                             weight,
                             cpu_delta: CpuDelta::ZERO,
+                            is_cow_fault: false,
+                            memory_access: None,
+                            request_span: None,
                         }),
                     });
                     sample_info.prev_sample_index_if_zero_cpu = Some(sample_index);
@@ -97,6 +211,9 @@ impl UnresolvedSamples {
                     sample_or_marker: SampleOrMarker::Sample(SampleData {
                         weight,
                         cpu_delta: CpuDelta::ZERO,
+                        is_cow_fault: false,
+                        memory_access: None,
+                        request_span: None,
                     }),
                 });
                 entry.insert(PreviousSampleInfo {
@@ -131,6 +248,110 @@ impl UnresolvedSamples {
         });
     }
 
+    pub fn add_large_mmap_marker(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        length: u64,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            sample_or_marker: SampleOrMarker::LargeMmapMarker(LargeMmapMarkerData { length }),
+        });
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_futex_wait_marker(
+        &mut self,
+        thread_handle: ThreadHandle,
+        start_timestamp: Timestamp,
+        start_timestamp_mono: u64,
+        end_timestamp: Timestamp,
+        end_timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        address: u64,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp: start_timestamp,
+            timestamp_mono: start_timestamp_mono,
+            stack,
+            sample_or_marker: SampleOrMarker::FutexWaitMarker(FutexWaitMarkerData {
+                end_timestamp,
+                duration_ns: end_timestamp_mono - start_timestamp_mono,
+                address,
+            }),
+        });
+    }
+
+    pub fn add_futex_wake_marker(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        address: u64,
+        woken_count: u64,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            sample_or_marker: SampleOrMarker::FutexWakeMarker(FutexWakeMarkerData {
+                address,
+                woken_count,
+            }),
+        });
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_signal_marker(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        signal_name: String,
+        code: i32,
+        fatal: bool,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            sample_or_marker: SampleOrMarker::SignalMarker(SignalMarkerData {
+                signal_name,
+                code,
+                fatal,
+            }),
+        });
+    }
+
+    pub fn add_probe_marker(
+        &mut self,
+        thread_handle: ThreadHandle,
+        timestamp: Timestamp,
+        timestamp_mono: u64,
+        stack: UnresolvedStackHandle,
+        event_name: String,
+        args: Vec<(String, MarkerValue)>,
+    ) {
+        self.samples_and_markers.push(UnresolvedSampleOrMarker {
+            thread_handle,
+            timestamp,
+            timestamp_mono,
+            stack,
+            sample_or_marker: SampleOrMarker::ProbeMarker(ProbeMarkerData { event_name, args }),
+        });
+    }
+
     pub fn add_other_event_marker(
         &mut self,
         thread_handle: ThreadHandle,
@@ -161,7 +382,14 @@ pub struct UnresolvedSampleOrMarker {
 #[derive(Debug, Clone)]
 pub enum SampleOrMarker {
     Sample(SampleData),
+    /// A sample which wasn't measured, see [`UnresolvedSamples::add_synthesized_sample`].
+    SynthesizedSample,
     RssStatMarker(RssStatMarkerData),
+    LargeMmapMarker(LargeMmapMarkerData),
+    FutexWaitMarker(FutexWaitMarkerData),
+    FutexWakeMarker(FutexWakeMarkerData),
+    SignalMarker(SignalMarkerData),
+    ProbeMarker(ProbeMarkerData),
     OtherEventMarker(OtherEventMarkerData),
 }
 
@@ -169,6 +397,15 @@ pub enum SampleOrMarker {
 pub struct SampleData {
     pub cpu_delta: CpuDelta,
     pub weight: i32,
+    /// Whether the sample was taken in a copy-on-write page fault. See
+    /// [`UnresolvedSamples::add_cow_fault_sample`].
+    pub is_cow_fault: bool,
+    /// The kind of a memory access sample. See
+    /// [`UnresolvedSamples::add_memory_access_sample`].
+    pub memory_access: Option<MemoryAccessKind>,
+    /// The request of the `--request-spans` span which the sample was taken
+    /// in. See [`UnresolvedSamples::set_request_span_of_last_sample`].
+    pub request_span: Option<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -178,6 +415,42 @@ pub struct RssStatMarkerData {
     pub delta: i64,
 }
 
+#[derive(Debug, Clone)]
+pub struct LargeMmapMarkerData {
+    pub length: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FutexWaitMarkerData {
+    pub end_timestamp: Timestamp,
+    pub duration_ns: u64,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FutexWakeMarkerData {
+    pub address: u64,
+    pub woken_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignalMarkerData {
+    pub signal_name: String,
+    /// The si_code of the signal, e.g. SEGV_MAPERR.
+    pub code: i32,
+    /// Whether the signal terminated the process.
+    pub fatal: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeMarkerData {
+    /// The name of the probe event, e.g. "probe:my_function".
+    pub event_name: String,
+    /// The decoded arguments, as names and values. Empty if the event has no
+    /// arguments or if its format is unknown.
+    pub args: Vec<(String, MarkerValue)>,
+}
+
 #[derive(Debug, Clone)]
 pub struct OtherEventMarkerData {
     pub attr_index: usize,
@@ -203,26 +476,198 @@ impl UnresolvedStackHandle {
 pub struct UnresolvedStacks {
     pub stacks: Vec<(UnresolvedStackHandle, StackFrame)>, // (prefix, frame)
     pub stack_lookup: FastHashMap<(UnresolvedStackHandle, StackFrame), UnresolvedStackHandle>, // (prefix, frame) -> stack index
+    /// The number of nodes after which new stacks are cut off, for
+    /// `--max-stack-nodes`.
+    max_nodes: Option<usize>,
+    /// Whether each node is the full stack of something, e.g. a sample, as
+    /// opposed to only the prefix of other stacks.
+    is_full_stack: Vec<bool>,
+    unique_stack_count: usize,
+    /// The number of stacks which were cut off because the table was full.
+    truncated_stack_count: u64,
+}
+
+/// The size of an [`UnresolvedStacks`] table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackTableStats {
+    /// The number of (prefix, frame) nodes.
+    pub node_count: usize,
+    /// The number of distinct stacks which were converted, not counting the
+    /// ones which only occur as the prefix of others.
+    pub unique_stack_count: usize,
+    /// The number of stacks which were cut off because of the node limit.
+    /// Each conversion of a stack counts, whether it's the stack of a
+    /// sample, of a marker or of a thread's off-CPU state, so this can be
+    /// more than the number of samples with a cut off stack.
+    pub truncated_stack_count: u64,
+}
+
+/// The stack which was most recently converted for a thread, together with the
+/// handle of each of its prefixes.
+///
+/// Consecutive samples of a thread usually share most of their stack, so
+/// [`UnresolvedStacks::convert_with_cache`] can take the handles of the shared
+/// prefix from here instead of looking up every frame again. A cache must only
+/// be used with a single `UnresolvedStacks`.
+#[derive(Debug, Clone, Default)]
+pub struct LastStackCache {
+    /// The frames of the stack, caller-most first.
+    frames: Vec<StackFrame>,
+    /// `handles[i]` is the handle for the stack `frames[..=i]`.
+    handles: Vec<UnresolvedStackHandle>,
+}
+
+impl LastStackCache {
+    fn truncate(&mut self, len: usize) {
+        self.frames.truncate(len);
+        self.handles.truncate(len);
+    }
 }
 
 impl UnresolvedStacks {
+    /// Creates a table which stops growing once it has `max_nodes` nodes.
+    /// After that, each stack with new frames ends at the deepest prefix
+    /// which is in the table already, followed by a
+    /// [`StackFrame::TruncatedStackMarker`]. The marker nodes don't count
+    /// towards the limit, but there's at most one for each node. Which
+    /// stacks are cut off only depends on the order of the conversions.
+    pub fn with_max_nodes(max_nodes: Option<usize>) -> Self {
+        Self {
+            max_nodes,
+            ..Default::default()
+        }
+    }
+
+    pub fn stats(&self) -> StackTableStats {
+        StackTableStats {
+            node_count: self.stacks.len(),
+            unique_stack_count: self.unique_stack_count,
+            truncated_stack_count: self.truncated_stack_count,
+        }
+    }
+
+    /// Returns whether any of the stacks has a frame from a KVM guest. Host
+    /// samples can have guest frames, e.g. from a callchain which crosses
+    /// into the guest, even if there are no guest samples.
+    pub fn has_guest_frames(&self) -> bool {
+        self.stacks.iter().any(|(_, frame)| match frame {
+            StackFrame::InstructionPointer(_, mode) | StackFrame::ReturnAddress(_, mode) => {
+                mode.is_guest()
+            }
+            StackFrame::TruncatedStackMarker => false,
+        })
+    }
+
+    /// Returns whether the stack was cut off because the table was full.
+    pub fn is_truncated(&self, stack: UnresolvedStackHandle) -> bool {
+        stack != UnresolvedStackHandle::EMPTY
+            && self.stacks[stack.0 as usize].1 == StackFrame::TruncatedStackMarker
+    }
+
     /// Get the `UnresolvedStackHandle` for a stack. The stack must be ordered from
     /// caller-most to callee-most ("outside to inside").
     pub fn convert(&mut self, frames: impl Iterator<Item = StackFrame>) -> UnresolvedStackHandle {
         let mut prefix = UnresolvedStackHandle::EMPTY;
         for frame in frames {
-            let x = (prefix, frame);
-            let node = *self.stack_lookup.entry(x).or_insert_with(|| {
-                let new_index = self.stacks.len() as u32;
-                self.stacks.push(x);
-                UnresolvedStackHandle(new_index)
-            });
-            prefix = node;
+            match self.intern(prefix, frame) {
+                Some(stack) => prefix = stack,
+                None => {
+                    prefix = self.truncate_at(prefix);
+                    break;
+                }
+            }
+        }
+        self.count_full_stack(prefix)
+    }
+
+    /// Like [`UnresolvedStacks::convert`], but reuses the prefix which the stack
+    /// shares with the previous stack that was converted with the same `cache`.
+    /// The cache is updated to the new stack.
+    pub fn convert_with_cache(
+        &mut self,
+        frames: impl Iterator<Item = StackFrame>,
+        cache: &mut LastStackCache,
+    ) -> UnresolvedStackHandle {
+        let mut prefix = UnresolvedStackHandle::EMPTY;
+        let mut depth = 0;
+        for frame in frames {
+            if depth < cache.frames.len() {
+                if cache.frames[depth] == frame {
+                    prefix = cache.handles[depth];
+                    depth += 1;
+                    continue;
+                }
+                cache.truncate(depth);
+            }
+            let Some(stack) = self.intern(prefix, frame) else {
+                // The marker is cached like a frame: a later stack with a
+                // marker at this depth has the same node.
+                prefix = self.truncate_at(prefix);
+                cache.frames.push(StackFrame::TruncatedStackMarker);
+                cache.handles.push(prefix);
+                depth += 1;
+                break;
+            };
+            prefix = stack;
+            cache.frames.push(frame);
+            cache.handles.push(prefix);
+            depth += 1;
         }
-        prefix
+        cache.truncate(depth);
+        self.count_full_stack(prefix)
     }
 
-    /// Get the `UnresolvedStackHandle` for a stack, skipping any kernel frames.
+    fn is_full(&self) -> bool {
+        matches!(self.max_nodes, Some(max_nodes) if self.stacks.len() >= max_nodes)
+    }
+
+    /// Returns None if the node is new and the table is full.
+    fn intern(
+        &mut self,
+        prefix: UnresolvedStackHandle,
+        frame: StackFrame,
+    ) -> Option<UnresolvedStackHandle> {
+        let x = (prefix, frame);
+        if let Some(&stack) = self.stack_lookup.get(&x) {
+            return Some(stack);
+        }
+        if self.is_full() {
+            return None;
+        }
+        Some(self.push(x))
+    }
+
+    fn push(&mut self, x: (UnresolvedStackHandle, StackFrame)) -> UnresolvedStackHandle {
+        let stack = UnresolvedStackHandle(self.stacks.len() as u32);
+        self.stacks.push(x);
+        self.is_full_stack.push(false);
+        self.stack_lookup.insert(x, stack);
+        stack
+    }
+
+    /// Ends a stack which doesn't fit into the full table at `prefix`.
+    fn truncate_at(&mut self, prefix: UnresolvedStackHandle) -> UnresolvedStackHandle {
+        self.truncated_stack_count += 1;
+        let x = (prefix, StackFrame::TruncatedStackMarker);
+        match self.stack_lookup.get(&x) {
+            Some(&stack) => stack,
+            None => self.push(x),
+        }
+    }
+
+    fn count_full_stack(&mut self, stack: UnresolvedStackHandle) -> UnresolvedStackHandle {
+        if stack != UnresolvedStackHandle::EMPTY {
+            let is_full_stack = &mut self.is_full_stack[stack.0 as usize];
+            if !*is_full_stack {
+                *is_full_stack = true;
+                self.unique_stack_count += 1;
+            }
+        }
+        stack
+    }
+
+    /// Get the `UnresolvedStackHandle` for a stack, skipping any kernel frames
+    /// (host or guest).
     /// The stack must be ordered from caller-most to callee-most ("outside to inside").
     pub fn convert_no_kernel(
         &mut self,
@@ -231,19 +676,23 @@ impl UnresolvedStacks {
         let mut prefix = UnresolvedStackHandle::EMPTY;
         for frame in frames {
             match frame {
-                StackFrame::InstructionPointer(_, StackMode::Kernel) => continue,
-                StackFrame::ReturnAddress(_, StackMode::Kernel) => continue,
+                StackFrame::InstructionPointer(_, StackMode::Kernel | StackMode::GuestKernel) => {
+                    continue
+                }
+                StackFrame::ReturnAddress(_, StackMode::Kernel | StackMode::GuestKernel) => {
+                    continue
+                }
                 _ => {}
             }
-            let x = (prefix, frame);
-            let node = *self.stack_lookup.entry(x).or_insert_with(|| {
-                let new_index = self.stacks.len() as u32;
-                self.stacks.push(x);
-                UnresolvedStackHandle(new_index)
-            });
-            prefix = node;
+            match self.intern(prefix, frame) {
+                Some(stack) => prefix = stack,
+                None => {
+                    prefix = self.truncate_at(prefix);
+                    break;
+                }
+            }
         }
-        prefix
+        self.count_full_stack(prefix)
     }
 
     pub fn convert_back(&self, mut stack_index: UnresolvedStackHandle, buf: &mut Vec<StackFrame>) {
@@ -254,3 +703,215 @@ impl UnresolvedStacks {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::shared::types::StackMode;
+
+    /// Generates deep stacks which share varying prefixes, like the stacks of
+    /// consecutive samples.
+    fn generate_stacks(count: usize) -> Vec<Vec<StackFrame>> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut stack: Vec<StackFrame> = Vec::new();
+        let mut stacks = Vec::new();
+        for _ in 0..count {
+            let keep = (next() as usize) % (stack.len() + 1);
+            stack.truncate(keep);
+            let depth = 20 + (next() as usize) % 40;
+            while stack.len() < depth {
+                let address = 0x1000 + (next() % 64) * 0x10;
+                stack.push(StackFrame::ReturnAddress(address, StackMode::User));
+            }
+            stacks.push(stack.clone());
+        }
+        stacks
+    }
+
+    #[test]
+    fn convert_with_cache_matches_convert() {
+        let stacks = generate_stacks(2000);
+        let mut expected = UnresolvedStacks::default();
+        let mut actual = UnresolvedStacks::default();
+        // Alternate between two caches, as if the samples came from two threads.
+        let mut caches = [LastStackCache::default(), LastStackCache::default()];
+        for (i, stack) in stacks.iter().enumerate() {
+            let expected_handle = expected.convert(stack.iter().cloned());
+            let actual_handle =
+                actual.convert_with_cache(stack.iter().cloned(), &mut caches[i % 2]);
+            assert_eq!(actual_handle, expected_handle);
+        }
+        assert_eq!(actual.stacks, expected.stacks);
+
+        let mut cache = LastStackCache::default();
+        assert_eq!(
+            actual.convert_with_cache(std::iter::empty(), &mut cache),
+            UnresolvedStackHandle::EMPTY
+        );
+    }
+
+    /// The sample stacks of the threads of the `ls` profile fixture, caller-most
+    /// frame first, in the order of the sample times, with the index of the
+    /// thread of each sample. Frames with an address in a library become
+    /// return addresses which are the same in all threads.
+    fn fixture_sample_stacks() -> Vec<(usize, Vec<StackFrame>)> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures/other/ls-linux/ls-profile.json");
+        let profile = crate::profile_json::read_profile(&path).unwrap();
+        let mut samples = Vec::new();
+        for (thread_index, thread) in profile.threads.iter().enumerate() {
+            let frame_address = |frame: usize| {
+                let func = thread.frame_table.func[frame];
+                match (thread.func_lib(func), thread.frame_table.address[frame]) {
+                    (Some(lib), address) if address >= 0 => {
+                        ((lib as u64 + 1) << 32) | address as u64
+                    }
+                    _ => (1 << 63) | ((thread_index as u64) << 32) | frame as u64,
+                }
+            };
+            for sample in thread.samples() {
+                let mut stack: Vec<StackFrame> = thread
+                    .stack_frames(sample.stack)
+                    .map(|frame| StackFrame::ReturnAddress(frame_address(frame), StackMode::User))
+                    .collect();
+                stack.reverse();
+                samples.push((sample.time.unwrap_or(0.0), thread_index, stack));
+            }
+        }
+        samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        samples
+            .into_iter()
+            .map(|(_, thread_index, stack)| (thread_index, stack))
+            .collect()
+    }
+
+    #[test]
+    fn convert_with_cache_matches_convert_for_a_recording() {
+        let samples = fixture_sample_stacks();
+        assert!(samples.len() > 1000);
+        let mut expected = UnresolvedStacks::default();
+        let mut actual = UnresolvedStacks::default();
+        let mut caches: FastHashMap<usize, LastStackCache> = FastHashMap::default();
+        for (thread_index, stack) in &samples {
+            let expected_handle = expected.convert(stack.iter().cloned());
+            let cache = caches.entry(*thread_index).or_default();
+            let actual_handle = actual.convert_with_cache(stack.iter().cloned(), cache);
+            assert_eq!(actual_handle, expected_handle);
+        }
+        assert_eq!(actual.stacks, expected.stacks);
+        assert_eq!(actual.stats(), expected.stats());
+    }
+
+    /// Compares the time it takes to intern the fixture's stacks with and
+    /// without the cache. Run it with
+    /// `cargo test --release -p samply convert_with_cache_timing -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn convert_with_cache_timing() {
+        const ROUNDS: usize = 50;
+        let samples = fixture_sample_stacks();
+        let time = |use_cache: bool| {
+            let start = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                let mut stacks = UnresolvedStacks::default();
+                let mut caches: FastHashMap<usize, LastStackCache> = FastHashMap::default();
+                for (thread_index, stack) in &samples {
+                    let frames = stack.iter().cloned();
+                    if use_cache {
+                        let cache = caches.entry(*thread_index).or_default();
+                        stacks.convert_with_cache(frames, cache);
+                    } else {
+                        stacks.convert(frames);
+                    }
+                }
+            }
+            start.elapsed()
+        };
+        let without_cache = time(false);
+        let with_cache = time(true);
+        println!(
+            "Interned {} stacks {ROUNDS} times: {without_cache:?} without the cache, \
+             {with_cache:?} with the cache",
+            samples.len()
+        );
+    }
+
+    fn frames(addresses: &[u64]) -> Vec<StackFrame> {
+        addresses
+            .iter()
+            .map(|&address| StackFrame::ReturnAddress(address, StackMode::User))
+            .collect()
+    }
+
+    #[test]
+    fn stats_count_nodes_and_unique_stacks() {
+        let mut stacks = UnresolvedStacks::default();
+        stacks.convert(frames(&[1, 2, 3]).into_iter());
+        stacks.convert(frames(&[1, 2, 3]).into_iter());
+        stacks.convert(frames(&[1, 2]).into_iter());
+        stacks.convert(frames(&[1, 4]).into_iter());
+        assert_eq!(
+            stacks.stats(),
+            StackTableStats {
+                node_count: 4,
+                unique_stack_count: 3,
+                truncated_stack_count: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn full_table_cuts_off_new_stacks_at_their_known_prefix() {
+        let mut stacks = UnresolvedStacks::with_max_nodes(Some(3));
+        let full = stacks.convert(frames(&[1, 2, 3]).into_iter());
+        // Hits the limit after the shared prefix [1, 2].
+        let cut = stacks.convert(frames(&[1, 2, 4, 5]).into_iter());
+        let mut buf = Vec::new();
+        stacks.convert_back(cut, &mut buf);
+        assert_eq!(
+            buf,
+            vec![
+                StackFrame::TruncatedStackMarker,
+                StackFrame::ReturnAddress(2, StackMode::User),
+                StackFrame::ReturnAddress(1, StackMode::User),
+            ]
+        );
+        // Stacks which are in the table already are unaffected, and other
+        // stacks which are cut off at the same prefix share the marker node.
+        assert_eq!(stacks.convert(frames(&[1, 2, 3]).into_iter()), full);
+        assert_eq!(stacks.convert(frames(&[1, 2, 6]).into_iter()), cut);
+        assert_eq!(
+            stacks.stats(),
+            StackTableStats {
+                node_count: 4,
+                unique_stack_count: 2,
+                truncated_stack_count: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn cut_off_stacks_are_deterministic() {
+        let stacks = generate_stacks(2000);
+        let mut first = UnresolvedStacks::with_max_nodes(Some(500));
+        let mut second = UnresolvedStacks::with_max_nodes(Some(500));
+        let mut cache = LastStackCache::default();
+        for stack in &stacks {
+            let expected_handle = first.convert(stack.iter().cloned());
+            let actual_handle = second.convert_with_cache(stack.iter().cloned(), &mut cache);
+            assert_eq!(actual_handle, expected_handle);
+        }
+        assert_eq!(first.stacks, second.stacks);
+        assert_eq!(first.stats(), second.stats());
+        let stats = first.stats();
+        assert!(stats.truncated_stack_count > 0);
+        // At most one marker node for each of the 500 other nodes.
+        assert!(stats.node_count <= 1000);
+    }
+}
@@ -5,15 +5,39 @@ use fxprof_processed_profile::{LibraryHandle, LibraryInfo, Profile};
 use linux_perf_data::jitdump::JitDumpHeader;
 use wholesym::samply_symbols::debug_id_and_code_id_for_jitdump;
 
+use super::path_map::PathMap;
+
+/// Opens the file at `path`, or at its `--path-map` translation, or the file
+/// with the same name in `extra_dir`, and returns the path which was opened.
+/// The translation is tried first.
 pub fn open_file_with_fallback(
     path: &Path,
     extra_dir: Option<&Path>,
+    path_map: Option<&PathMap>,
+) -> std::io::Result<(std::fs::File, PathBuf)> {
+    open_file_with_fallback_using(path, extra_dir, path_map, |path| std::fs::File::open(path))
+}
+
+/// Like [`open_file_with_fallback`], but opens each candidate path with `open`.
+pub fn open_file_with_fallback_using(
+    path: &Path,
+    extra_dir: Option<&Path>,
+    path_map: Option<&PathMap>,
+    mut open: impl FnMut(&Path) -> std::io::Result<std::fs::File>,
 ) -> std::io::Result<(std::fs::File, PathBuf)> {
-    match (std::fs::File::open(path), extra_dir, path.file_name()) {
+    if let Some(path_map) = path_map {
+        if let Some(translated_path) = path_map.translate(path) {
+            if let Ok(file) = open(&translated_path) {
+                path_map.record_applied(path, &translated_path);
+                return Ok((file, translated_path));
+            }
+        }
+    }
+    match (open(path), extra_dir, path.file_name()) {
         (Ok(file), _, _) => Ok((file, path.to_owned())),
         (Err(_), Some(extra_dir), Some(filename)) => {
             let p: PathBuf = [extra_dir, Path::new(filename)].iter().collect();
-            std::fs::File::open(&p).map(|file| (file, p))
+            open(&p).map(|file| (file, p))
         }
         (Err(e), _, _) => Err(e),
     }
@@ -0,0 +1,120 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use fxprof_processed_profile::{CategoryPairHandle, StringHandle};
+use object::{BinaryFormat, Object, ObjectSymbol};
+use wholesym::samply_symbols::object;
+
+/// The functions through which Wine switches between the Windows side (PE
+/// modules) and the Unix side (ELF modules like ntdll.so). Compiler-generated
+/// suffixes like ".constprop.0" are ignored.
+const WINE_TRANSITION_FUNCTIONS: &[&str] = &[
+    // Windows to Unix: system calls and __wine_unix_call.
+    "__wine_syscall_dispatcher",
+    "__wine_syscall_dispatcher_return",
+    "__wine_unix_call_dispatcher",
+    "__wine_unix_call",
+    "__wine_syscall",
+    "wine_syscall_dispatcher",
+    // Unix to Windows: callbacks, APCs and exceptions.
+    "KiUserCallbackDispatcher",
+    "KiUserApcDispatcher",
+    "KiUserExceptionDispatcher",
+    "call_user_mode_callback",
+    "call_user_apc_dispatcher",
+    "call_user_exception_dispatcher",
+];
+
+/// Whether a module is part of Wine, e.g. "/usr/lib/wine/x86_64-unix/ntdll.so".
+/// Only Wine's modules are searched for transition functions.
+fn is_wine_module_path(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    path.contains("/wine/") || name == "ntdll.so" || name == "ntdll.dll"
+}
+
+/// What the Wine frame conversion needs to know about a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WineModuleInfo {
+    /// Whether the module is a PE image, i.e. Windows-side code.
+    is_pe: bool,
+    /// The transition functions in the module, as relative addresses.
+    transitions: Vec<Range<u32>>,
+}
+
+impl WineModuleInfo {
+    pub fn new(is_pe: bool, transitions: Vec<Range<u32>>) -> Self {
+        Self { is_pe, transitions }
+    }
+
+    /// Returns None for modules which are neither PE images nor Wine modules
+    /// with transition functions. Only symbols with a size are used, so
+    /// transition functions in PE images are usually not found.
+    pub fn from_object(file: &object::File, base_svma: u64, path: &str) -> Option<Arc<Self>> {
+        let is_pe = file.format() == BinaryFormat::Pe;
+        let transitions = if is_wine_module_path(path) {
+            file.symbols()
+                .chain(file.dynamic_symbols())
+                .filter(|symbol| symbol.size() != 0)
+                .filter(|symbol| symbol.name().map_or(false, is_wine_transition_function))
+                .filter_map(|symbol| {
+                    let start = symbol.address().checked_sub(base_svma)?;
+                    Some(start as u32..(start + symbol.size()) as u32)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if !is_pe && transitions.is_empty() {
+            return None;
+        }
+        Some(Arc::new(Self::new(is_pe, transitions)))
+    }
+
+    pub fn is_pe(&self) -> bool {
+        self.is_pe
+    }
+
+    pub fn is_transition(&self, relative_address: u32) -> bool {
+        self.transitions
+            .iter()
+            .any(|range| range.contains(&relative_address))
+    }
+}
+
+fn is_wine_transition_function(name: &str) -> bool {
+    let name = name.split('.').next().unwrap_or(name);
+    WINE_TRANSITION_FUNCTIONS.contains(&name)
+}
+
+/// How frames in Wine's transition functions are converted.
+#[derive(Debug, Clone, Copy)]
+pub struct WineFrameConversion {
+    pub category: CategoryPairHandle,
+    /// If set, a transition frame which is called by a PE frame is replaced
+    /// by a single `syscall_label` frame, together with the Unix-side frames
+    /// it calls, if those frames lead back into PE code, e.g. for a window
+    /// procedure callback. The Windows call chain then reads like it would
+    /// in a Windows profiler.
+    pub fold_syscalls: bool,
+    pub syscall_label: StringHandle,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn recognizes_transition_functions() {
+        assert!(is_wine_transition_function("__wine_syscall_dispatcher"));
+        assert!(is_wine_transition_function("__wine_unix_call.constprop.0"));
+        assert!(!is_wine_transition_function("NtReadFile"));
+        assert!(is_wine_module_path("/usr/lib/wine/x86_64-unix/ntdll.so"));
+        assert!(is_wine_module_path("/opt/proton/lib64/ntdll.so"));
+        assert!(!is_wine_module_path("/usr/lib/libc.so.6"));
+
+        let info = WineModuleInfo::new(false, vec![0x1000..0x1100]);
+        assert!(info.is_transition(0x1080));
+        assert!(!info.is_transition(0x1100));
+    }
+}
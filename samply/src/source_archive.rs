@@ -0,0 +1,482 @@
+use serde_derive::{Deserialize, Serialize};
+use wholesym::debugid::DebugId;
+use wholesym::{to_api_file_path, FramesLookupResult, SymbolManager};
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cli_error::CliError;
+use crate::profile_json::{read_profile, ProfileJson};
+use crate::server::{libinfo_map_entry_for_lib, symbol_manager_config};
+
+/// The default of `--archive-sources-budget`: 64 MiB of source files.
+pub const DEFAULT_SOURCE_ARCHIVE_BUDGET: u64 = 64 * 1024 * 1024;
+
+/// The archive entry with the [`SourceArchiveIndex`].
+const INDEX_ENTRY_NAME: &str = "index.json";
+
+/// What `--archive-sources` writes.
+#[derive(Debug, Clone)]
+pub struct SourceArchiveOptions {
+    pub output: PathBuf,
+    /// The maximum total size of the archived source files, before
+    /// compression.
+    pub budget: u64,
+}
+
+/// Copies the source files of the sampled functions into a source archive,
+/// so that the source view still works once the build tree is gone, see
+/// [`SourceArchive`]. Library addresses are symbolicated with the same symbol
+/// manager setup as the local server, and the files of the functions with
+/// the most sample weight are archived first, until the budget is used up.
+#[tokio::main]
+pub async fn write_source_archive_main(
+    profile_paths: &[PathBuf],
+    binaries_dirs: &[PathBuf],
+    options: &SourceArchiveOptions,
+    verbose: bool,
+) -> Result<(), CliError> {
+    let mut symbols = HashMap::new();
+    for path in profile_paths {
+        let profile = read_profile(path)?;
+        add_sampled_symbols(&profile, binaries_dirs, verbose, &mut symbols).await;
+    }
+    let mut symbols: Vec<SampledSymbol> = symbols.into_values().collect();
+    symbols.sort_by_key(|symbol| std::cmp::Reverse(symbol.weight));
+
+    let mut writer = SourceArchiveWriter::new(options.budget);
+    for symbol in &symbols {
+        writer.add_symbol_files(&symbol.files, verbose);
+    }
+    writer
+        .write(&options.output)
+        .map_err(|err| CliError::io(format!("Could not write {:?}", options.output), &err))?;
+    writer.report(&options.output);
+    Ok(())
+}
+
+/// A symbol with samples, and the source files which its debug info refers
+/// to, including the files of the functions which were inlined into it.
+#[derive(Debug, Default)]
+struct SampledSymbol {
+    /// The weight of the samples with the symbol in their stack.
+    weight: i64,
+    files: Vec<SourceFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SourceFile {
+    /// The breakpad debug ID of the library.
+    debug_id: String,
+    /// The path which the source view requests, see [`to_api_file_path`].
+    api_path: String,
+    /// The path in the debug info, from which the file is read.
+    raw_path: String,
+    /// Whether the profiler can fetch the file from the web, e.g. for a
+    /// crates.io crate, so that it doesn't matter if it doesn't exist.
+    has_mapped_path: bool,
+}
+
+/// Looks up the symbols and source files of the sampled library frames, and
+/// adds them to `symbols`, by (debug ID, symbol name).
+async fn add_sampled_symbols(
+    profile: &ProfileJson,
+    binaries_dirs: &[PathBuf],
+    verbose: bool,
+    symbols: &mut HashMap<(String, String), SampledSymbol>,
+) {
+    let mut addresses_by_lib: HashMap<usize, Vec<(u32, i64)>> = HashMap::new();
+    for ((lib, address), weight) in address_weights(profile) {
+        addresses_by_lib
+            .entry(lib)
+            .or_default()
+            .push((address, weight));
+    }
+
+    let mut symbol_manager =
+        SymbolManager::with_config(symbol_manager_config(binaries_dirs, verbose));
+    let mut lib_infos = HashMap::new();
+    for &lib in addresses_by_lib.keys() {
+        if let Some(lib_info) = profile.libs.get(lib).and_then(libinfo_map_entry_for_lib) {
+            symbol_manager.add_known_library(lib_info.clone());
+            lib_infos.insert(lib, lib_info);
+        }
+    }
+
+    for (lib, mut addresses) in addresses_by_lib {
+        let Some(lib_info) = lib_infos.get(&lib) else {
+            continue;
+        };
+        // libinfo_map_entry_for_lib only returns libraries with both.
+        let debug_name = lib_info.debug_name.as_deref().unwrap();
+        let debug_id = lib_info.debug_id.unwrap();
+        let symbol_map = match symbol_manager.load_symbol_map(debug_name, debug_id).await {
+            Ok(symbol_map) => symbol_map,
+            Err(err) => {
+                if verbose {
+                    eprintln!("Could not load symbols for {debug_name}: {err}");
+                }
+                continue;
+            }
+        };
+        let debug_id = debug_id.breakpad().to_string();
+        // Sorted addresses make the external file lookups hit the cache.
+        addresses.sort_unstable();
+        for (address, weight) in addresses {
+            let Some(info) = symbol_map.lookup_relative_address(address) else {
+                continue;
+            };
+            let frames = match info.frames {
+                FramesLookupResult::Available(frames) => frames,
+                FramesLookupResult::External(external) => symbol_manager
+                    .lookup_external(&symbol_map.symbol_file_origin(), &external)
+                    .await
+                    .unwrap_or_default(),
+                FramesLookupResult::Unavailable => Vec::new(),
+            };
+            let symbol = symbols
+                .entry((debug_id.clone(), info.symbol.name))
+                .or_default();
+            symbol.weight += weight;
+            for file_path in frames.into_iter().filter_map(|frame| frame.file_path) {
+                let file = SourceFile {
+                    debug_id: debug_id.clone(),
+                    api_path: to_api_file_path(&file_path),
+                    has_mapped_path: file_path.mapped_path().is_some(),
+                    raw_path: file_path.into_raw_path(),
+                };
+                if !symbol.files.contains(&file) {
+                    symbol.files.push(file);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the weight of the samples with each library frame in their stack,
+/// by (lib index, relative address).
+fn address_weights(profile: &ProfileJson) -> HashMap<(usize, u32), i64> {
+    let mut weights = HashMap::new();
+    for thread in &profile.threads {
+        let stack_table = &thread.stack_table;
+        let mut stack_weights = vec![0; stack_table.frame.len()];
+        for sample in thread.samples() {
+            if let Some(weight) = sample.stack.and_then(|stack| stack_weights.get_mut(stack)) {
+                *weight += sample.weight;
+            }
+        }
+        // Prefixes come before the stacks which extend them, so the weights
+        // of the stacks can be added to their prefixes from the back.
+        for stack in (0..stack_weights.len()).rev() {
+            if let Some(prefix) = stack_table.prefix[stack] {
+                stack_weights[prefix] += stack_weights[stack];
+            }
+        }
+        for (stack, &weight) in stack_weights.iter().enumerate() {
+            let frame = stack_table.frame[stack];
+            let Ok(address) = u32::try_from(thread.frame_table.address[frame]) else {
+                continue;
+            };
+            if weight == 0 {
+                continue;
+            }
+            if let Some(lib) = thread.func_lib(thread.frame_table.func[frame]) {
+                *weights.entry((lib, address)).or_default() += weight;
+            }
+        }
+    }
+    weights
+}
+
+/// The `index.json` entry of a source archive.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SourceArchiveIndex {
+    /// The entry names of the source files, by the breakpad debug ID of the
+    /// library and by the path which the source view requests.
+    libraries: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Collects the source files of a source archive, within a budget. A file
+/// which several libraries refer to is only archived once.
+#[derive(Debug)]
+struct SourceArchiveWriter {
+    budget: u64,
+    archived_bytes: u64,
+    /// The entry names and contents of the archived files.
+    entries: Vec<(String, Vec<u8>)>,
+    /// The entry of each file which was read, by raw path. None if the file
+    /// doesn't exist or didn't fit into the budget.
+    entry_by_raw_path: HashMap<String, Option<String>>,
+    entry_names: HashSet<String>,
+    index: SourceArchiveIndex,
+    missing_file_count: usize,
+    over_budget_file_count: usize,
+}
+
+impl SourceArchiveWriter {
+    fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            archived_bytes: 0,
+            entries: Vec::new(),
+            entry_by_raw_path: HashMap::new(),
+            entry_names: HashSet::new(),
+            index: SourceArchiveIndex::default(),
+            missing_file_count: 0,
+            over_budget_file_count: 0,
+        }
+    }
+
+    /// Archives the files of a symbol. Call this for the hottest symbol
+    /// first.
+    fn add_symbol_files(&mut self, files: &[SourceFile], verbose: bool) {
+        for file in files {
+            let entry_name = match self.entry_by_raw_path.get(&file.raw_path) {
+                Some(entry_name) => entry_name.clone(),
+                None => {
+                    let entry_name = self.read_file(file, verbose);
+                    self.entry_by_raw_path
+                        .insert(file.raw_path.clone(), entry_name.clone());
+                    entry_name
+                }
+            };
+            if let Some(entry_name) = entry_name {
+                self.index
+                    .libraries
+                    .entry(file.debug_id.clone())
+                    .or_default()
+                    .insert(file.api_path.clone(), entry_name);
+            }
+        }
+    }
+
+    /// Returns the entry name of the file if it exists and fits into the
+    /// budget.
+    fn read_file(&mut self, file: &SourceFile, verbose: bool) -> Option<String> {
+        let contents = match std::fs::read(&file.raw_path) {
+            Ok(contents) => contents,
+            Err(_) if file.has_mapped_path => return None,
+            Err(err) => {
+                if verbose {
+                    eprintln!("Could not read the source file {}: {err}", file.raw_path);
+                }
+                self.missing_file_count += 1;
+                return None;
+            }
+        };
+        let size = contents.len() as u64;
+        if self.archived_bytes + size > self.budget {
+            self.over_budget_file_count += 1;
+            return None;
+        }
+        self.archived_bytes += size;
+        let entry_name = self.unique_entry_name(&file.raw_path);
+        self.entries.push((entry_name.clone(), contents));
+        Some(entry_name)
+    }
+
+    /// Returns "sources/" followed by the path without its root, "." and ".."
+    /// components, with a suffix if another path already has that name.
+    fn unique_entry_name(&mut self, raw_path: &str) -> String {
+        let components: Vec<&str> = raw_path
+            .split(['/', '\\'])
+            .filter(|component| !matches!(*component, "" | "." | ".."))
+            .collect();
+        let base_name = format!("sources/{}", components.join("/"));
+        let mut entry_name = base_name.clone();
+        let mut suffix = 1;
+        while !self.entry_names.insert(entry_name.clone()) {
+            suffix += 1;
+            entry_name = format!("{base_name}~{suffix}");
+        }
+        entry_name
+    }
+
+    /// Writes the index and the files as a zstd-compressed tar archive.
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        let file = File::create(path)?;
+        let encoder = Encoder::new(BufWriter::new(file), zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        let mut builder = tar::Builder::new(encoder);
+        let index = serde_json::to_vec(&self.index)?;
+        append_entry(&mut builder, INDEX_ENTRY_NAME, &index)?;
+        for (entry_name, contents) in &self.entries {
+            append_entry(&mut builder, entry_name, contents)?;
+        }
+        builder.into_inner()?.finish()?.flush()
+    }
+
+    fn report(&self, output: &Path) {
+        eprintln!(
+            "Archived {} source files ({} bytes) to {output:?}.",
+            self.entries.len(),
+            self.archived_bytes
+        );
+        if self.missing_file_count != 0 {
+            eprintln!(
+                "{} source files don't exist anymore and weren't archived.",
+                self.missing_file_count
+            );
+        }
+        if self.over_budget_file_count != 0 {
+            eprintln!(
+                "{} source files of less sampled functions didn't fit into the budget of {} \
+                 bytes.",
+                self.over_budget_file_count, self.budget
+            );
+        }
+    }
+}
+
+fn append_entry(
+    builder: &mut tar::Builder<impl Write>,
+    entry_name: &str,
+    contents: &[u8],
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, entry_name, contents)
+}
+
+/// A source archive which `--archive-sources` wrote: a zstd-compressed tar
+/// archive with the source files of the sampled functions, and an index
+/// which maps the files which each library's debug info refers to to the
+/// entries with their contents. `samply serve --source-archive` answers the
+/// source view's requests from it when the files don't exist anymore.
+#[derive(Debug, Default)]
+pub struct SourceArchive {
+    /// The entry name of each file, by (breakpad debug ID, requested path).
+    entry_names: HashMap<(String, String), String>,
+    /// The contents of each entry, by entry name.
+    sources: HashMap<String, String>,
+}
+
+impl SourceArchive {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read(reader: impl Read) -> std::io::Result<Self> {
+        let mut archive = tar::Archive::new(Decoder::new(reader)?);
+        let mut index = None;
+        let mut sources = HashMap::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_name = entry.path()?.to_string_lossy().into_owned();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            if entry_name == INDEX_ENTRY_NAME {
+                index = Some(serde_json::from_slice::<SourceArchiveIndex>(&contents)?);
+            } else {
+                sources.insert(entry_name, String::from_utf8_lossy(&contents).into_owned());
+            }
+        }
+        let index = index.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the archive has no {INDEX_ENTRY_NAME}"),
+            )
+        })?;
+        let entry_names = index
+            .libraries
+            .into_iter()
+            .flat_map(|(debug_id, files)| {
+                files
+                    .into_iter()
+                    .map(move |(path, entry_name)| ((debug_id.clone(), path), entry_name))
+            })
+            .collect();
+        Ok(Self {
+            entry_names,
+            sources,
+        })
+    }
+
+    /// Returns the contents of a file which the library's debug info refers
+    /// to, if it was archived.
+    pub fn source(&self, debug_id: DebugId, path: &str) -> Option<&str> {
+        let key = (debug_id.breakpad().to_string(), path.to_string());
+        let entry_name = self.entry_names.get(&key)?;
+        self.sources.get(entry_name).map(String::as_str)
+    }
+}
+
+/// The fields of a `/source/v1` request which the archives need.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SourceRequest {
+    debug_id: String,
+    file: String,
+}
+
+/// Answers a `/source/v1` request from the archives. Returns None if none of
+/// them has the file.
+pub fn query_source_api_json_from_archives(
+    request_json: &str,
+    archives: &[SourceArchive],
+) -> Option<String> {
+    let request: SourceRequest = serde_json::from_str(request_json).ok()?;
+    let debug_id = DebugId::from_breakpad(&request.debug_id).ok()?;
+    let source = archives
+        .iter()
+        .find_map(|archive| archive.source(debug_id, &request.file))?;
+    let response = serde_json::json!({
+        "symbolsLastModified": null,
+        "sourceLastModified": null,
+        "file": request.file,
+        "source": source,
+    });
+    Some(response.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_files_of_the_hottest_symbols_are_archived_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let hot = dir.path().join("src/hot.rs");
+        let cold = dir.path().join("src/cold.rs");
+        let deleted = dir.path().join("src/deleted.rs");
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(&hot, "fn hot() {}\n").unwrap();
+        std::fs::write(&cold, "fn cold() {}\n").unwrap();
+
+        let debug_id = DebugId::from_breakpad("000102030405060708090A0B0C0D0E0F0").unwrap();
+        let file = |path: &Path| SourceFile {
+            debug_id: debug_id.breakpad().to_string(),
+            api_path: path.display().to_string(),
+            raw_path: path.display().to_string(),
+            has_mapped_path: false,
+        };
+        let mut writer = SourceArchiveWriter::new(20);
+        writer.add_symbol_files(&[file(&hot), file(&deleted)], false);
+        writer.add_symbol_files(&[file(&cold), file(&hot)], false);
+        assert_eq!(writer.archived_bytes, 12);
+        assert_eq!(writer.missing_file_count, 1);
+        assert_eq!(writer.over_budget_file_count, 1);
+
+        let output = dir.path().join("sources.tar.zst");
+        writer.write(&output).unwrap();
+        let archive = SourceArchive::open(&output).unwrap();
+        let hot_path = hot.display().to_string();
+        assert_eq!(archive.source(debug_id, &hot_path), Some("fn hot() {}\n"));
+        assert_eq!(archive.source(debug_id, &cold.display().to_string()), None);
+
+        let request = serde_json::json!({
+            "debugName": "app",
+            "debugId": "000102030405060708090A0B0C0D0E0F0",
+            "moduleOffset": "0x10",
+            "file": hot_path,
+        });
+        let response =
+            query_source_api_json_from_archives(&request.to_string(), &[archive]).unwrap();
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["source"], "fn hot() {}\n");
+    }
+}
@@ -0,0 +1,333 @@
+use serde_derive::Serialize;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::cli_error::CliError;
+use crate::profile_json::{
+    read_profile, symbolicate, MarkerPhase, ProfileJson, SymbolNames, ThreadJson,
+};
+
+/// The format in which `samply load` writes the profile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The processed profile, which is opened in the Firefox profiler.
+    #[default]
+    Firefox,
+    /// A JSON array of Trace Event Format events, for chrome://tracing
+    /// and Perfetto.
+    TraceEvent,
+}
+
+/// Parses an `--output-format` argument.
+pub fn parse_output_format(s: &str) -> Result<OutputFormat, String> {
+    match s {
+        "firefox" => Ok(OutputFormat::Firefox),
+        "trace-event" => Ok(OutputFormat::TraceEvent),
+        _ => Err(format!("expected firefox or trace-event, got {s:?}")),
+    }
+}
+
+/// What `--output-format trace-event` writes.
+#[derive(Debug, Clone)]
+pub struct TraceEventOptions {
+    pub output: PathBuf,
+    /// Also write each sample as a duration event named by its leaf symbol.
+    pub samples: bool,
+}
+
+/// Writes the markers and counters of the profiles, and optionally their
+/// samples, as Trace Event Format events to `options.output`. Timestamps
+/// are in microseconds since the start time of the processed profile, so
+/// that they match the times in the profiler UI.
+#[tokio::main]
+pub async fn write_trace_event_main(
+    profile_paths: &[PathBuf],
+    binaries_dirs: &[PathBuf],
+    options: &TraceEventOptions,
+    verbose: bool,
+) -> Result<(), CliError> {
+    let mut exporter = TraceEventExporter::default();
+    for path in profile_paths {
+        let profile = read_profile(path)?;
+        let symbol_names = if options.samples {
+            symbolicate(&profile, binaries_dirs, verbose).await
+        } else {
+            SymbolNames::new()
+        };
+        exporter.add_profile(&profile, &symbol_names, options.samples);
+    }
+    let events = exporter.events;
+
+    let file = File::create(&options.output)
+        .map_err(|err| CliError::io(format!("Could not create {:?}", options.output), &err))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &events)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writer.flush())
+        .map_err(|err| CliError::io(format!("Could not write {:?}", options.output), &err))?;
+    eprintln!(
+        "Wrote {} trace events to {:?}.",
+        events.len(),
+        options.output
+    );
+    Ok(())
+}
+
+/// One event of the Trace Event Format.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cat: Option<String>,
+    pub ph: &'static str,
+    /// In microseconds.
+    pub ts: f64,
+    /// In microseconds, for "X" events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dur: Option<f64>,
+    pub pid: u64,
+    /// Missing for process-wide events, e.g. counters.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tid: Option<u64>,
+    /// The scope of "i" events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<serde_json::Value>,
+}
+
+impl TraceEvent {
+    fn new(name: String, ph: &'static str, ts_ms: f64, pid: u64, tid: Option<u64>) -> Self {
+        Self {
+            name,
+            cat: None,
+            ph,
+            ts: ts_ms * 1000.0,
+            dur: None,
+            pid,
+            tid,
+            s: None,
+            args: None,
+        }
+    }
+}
+
+/// Converts processed profiles into Trace Event Format events.
+#[derive(Debug, Default)]
+pub struct TraceEventExporter {
+    events: Vec<TraceEvent>,
+    /// The processes which already have a process_name event.
+    named_processes: HashSet<u64>,
+    /// Synthetic ids for pids and tids which aren't numbers, e.g. for
+    /// imported profiles.
+    synthetic_ids: HashMap<String, u64>,
+}
+
+impl TraceEventExporter {
+    fn add_profile(&mut self, profile: &ProfileJson, symbol_names: &SymbolNames, samples: bool) {
+        for thread in &profile.threads {
+            let pid = self.id(thread.pid.as_deref());
+            let tid = self.id(thread.tid.as_deref());
+            self.add_thread_names(thread, pid, tid);
+            self.add_markers(profile, thread, pid, tid);
+            if samples {
+                self.add_samples(profile, thread, symbol_names, pid, tid);
+            }
+        }
+        for counter in &profile.counters {
+            let pid = self.id(Some(&counter.pid));
+            // Counter samples are deltas, and counter events take the value.
+            let mut value = 0.0;
+            for group in &counter.sample_groups {
+                for (&time, &delta) in group.samples.time.iter().zip(&group.samples.count) {
+                    value += delta;
+                    let mut event = TraceEvent::new(counter.name.clone(), "C", time, pid, None);
+                    event.cat = Some(counter.category.clone());
+                    let mut args = serde_json::Map::new();
+                    args.insert(counter.name.clone(), value.into());
+                    event.args = Some(args.into());
+                    self.events.push(event);
+                }
+            }
+        }
+    }
+
+    /// Returns the numeric id of a pid or tid, or a synthetic id above the
+    /// range of real ids for ids which aren't numbers.
+    fn id(&mut self, id: Option<&str>) -> u64 {
+        let id = id.unwrap_or_default();
+        if let Ok(id) = id.parse() {
+            return id;
+        }
+        let next_id = (1u64 << 32) + self.synthetic_ids.len() as u64;
+        *self.synthetic_ids.entry(id.to_string()).or_insert(next_id)
+    }
+
+    fn add_thread_names(&mut self, thread: &ThreadJson, pid: u64, tid: u64) {
+        if let Some(process_name) = &thread.process_name {
+            if self.named_processes.insert(pid) {
+                let mut event = TraceEvent::new("process_name".into(), "M", 0.0, pid, None);
+                event.args = Some(serde_json::json!({ "name": process_name }));
+                self.events.push(event);
+            }
+        }
+        if let Some(thread_name) = &thread.name {
+            let mut event = TraceEvent::new("thread_name".into(), "M", 0.0, pid, Some(tid));
+            event.args = Some(serde_json::json!({ "name": thread_name }));
+            self.events.push(event);
+        }
+    }
+
+    fn add_markers(&mut self, profile: &ProfileJson, thread: &ThreadJson, pid: u64, tid: u64) {
+        for marker in thread.markers() {
+            let (ph, time) = match marker.phase {
+                MarkerPhase::Instant => ("i", marker.start_time),
+                MarkerPhase::Interval => ("X", marker.start_time),
+                MarkerPhase::IntervalStart => ("B", marker.start_time),
+                MarkerPhase::IntervalEnd => ("E", marker.end_time),
+            };
+            let Some(time) = time else {
+                continue;
+            };
+            let mut event = TraceEvent::new(marker.name.to_string(), ph, time, pid, Some(tid));
+            event.cat = marker
+                .category
+                .and_then(|category| profile.meta.category_name(category))
+                .map(ToOwned::to_owned);
+            match ph {
+                "i" => event.s = Some("t"),
+                "X" => {
+                    let end = marker.end_time.unwrap_or(time);
+                    event.dur = Some((end - time).max(0.0) * 1000.0);
+                }
+                _ => {}
+            }
+            event.args = marker.data.cloned();
+            self.events.push(event);
+        }
+    }
+
+    fn add_samples(
+        &mut self,
+        profile: &ProfileJson,
+        thread: &ThreadJson,
+        symbol_names: &SymbolNames,
+        pid: u64,
+        tid: u64,
+    ) {
+        for sample in thread.samples() {
+            let Some(time) = sample.time else {
+                continue;
+            };
+            let Some(name) = thread.stack_frame_names(sample.stack, symbol_names).next() else {
+                continue;
+            };
+            let mut event = TraceEvent::new(name.to_string(), "X", time, pid, Some(tid));
+            event.cat = Some("Sample".into());
+            event.dur = Some(profile.meta.interval * 1000.0);
+            self.events.push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// One thread with an instant, an interval, an interval start and end,
+    /// two samples, and a counter.
+    const PROFILE: &str = r#"{
+        "meta": { "interval": 0.5, "categories": [{ "name": "Other" }, { "name": "IO" }] },
+        "libs": [],
+        "threads": [{
+            "name": "worker",
+            "pid": "12",
+            "tid": "34",
+            "processName": "app",
+            "samples": { "stack": [1, null], "time": [1.5, 2.0] },
+            "markers": {
+                "category": [1, 1, 0, 0],
+                "data": [null, { "type": "read", "fd": 3 }, null, null],
+                "name": [2, 3, 4, 4],
+                "phase": [0, 1, 2, 3],
+                "startTime": [1.0, 2.0, 3.0, 0.0],
+                "endTime": [0.0, 2.25, 0.0, 4.0]
+            },
+            "stackTable": { "prefix": [null, 0], "frame": [0, 1] },
+            "frameTable": { "address": [-1, -1], "category": [0, 0], "func": [0, 1] },
+            "funcTable": { "name": [0, 1], "resource": [-1, -1] },
+            "resourceTable": { "lib": [] },
+            "stringArray": ["main", "leaf", "start", "read", "paint"]
+        }],
+        "counters": [{
+            "name": "Memory",
+            "category": "Memory",
+            "pid": "12",
+            "sampleGroups": [{ "samples": { "time": [1.0, 2.0], "count": [100, -40] } }]
+        }]
+    }"#;
+
+    fn export(samples: bool) -> Vec<TraceEvent> {
+        let profile: ProfileJson = serde_json::from_str(PROFILE).unwrap();
+        let mut exporter = TraceEventExporter::default();
+        exporter.add_profile(&profile, &SymbolNames::new(), samples);
+        exporter.events
+    }
+
+    #[test]
+    fn exports_markers_and_counters() {
+        let events = export(false);
+        let summary: Vec<(&str, &str, f64, Option<f64>)> = events
+            .iter()
+            .map(|e| (e.ph, e.name.as_str(), e.ts, e.dur))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("M", "process_name", 0.0, None),
+                ("M", "thread_name", 0.0, None),
+                ("i", "start", 1000.0, None),
+                ("X", "read", 2000.0, Some(250.0)),
+                ("B", "paint", 3000.0, None),
+                ("E", "paint", 4000.0, None),
+                ("C", "Memory", 1000.0, None),
+                ("C", "Memory", 2000.0, None),
+            ]
+        );
+        assert!(events[2..6]
+            .iter()
+            .all(|e| (e.pid, e.tid) == (12, Some(34))));
+        assert_eq!(events[3].cat.as_deref(), Some("IO"));
+        assert_eq!(
+            events[3].args,
+            Some(serde_json::json!({ "type": "read", "fd": 3 }))
+        );
+        assert_eq!(events[7].args, Some(serde_json::json!({ "Memory": 60.0 })));
+        assert_eq!(events[7].tid, None);
+    }
+
+    #[test]
+    fn exports_samples_by_leaf_name() {
+        let events = export(true);
+        let samples: Vec<&TraceEvent> = events
+            .iter()
+            .filter(|e| e.cat.as_deref() == Some("Sample"))
+            .collect();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].name, "leaf");
+        assert_eq!((samples[0].ts, samples[0].dur), (1500.0, Some(500.0)));
+    }
+
+    #[test]
+    fn gives_synthetic_ids_to_non_numeric_ids() {
+        let mut exporter = TraceEventExporter::default();
+        assert_eq!(exporter.id(Some("42")), 42);
+        let id = exporter.id(Some("0.1"));
+        assert!(id > u32::MAX.into());
+        assert_eq!(exporter.id(Some("0.1")), id);
+        assert_ne!(exporter.id(None), id);
+    }
+}
@@ -0,0 +1,423 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::cli_error::CliError;
+use crate::profile_json::{read_profile, MarkersJson, ProfileJson, ThreadJson};
+
+/// `samply validate` prints at most this many violations.
+const MAX_PRINTED_VIOLATIONS: usize = 100;
+
+/// A broken invariant of a processed profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Where the offending value is, as the table, the row and the column,
+    /// e.g. `threads[2].stackTable[17].frame`.
+    pub location: String,
+    /// What's wrong with the value, including the value itself.
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Checks the profile at `path` and prints its violations, for
+/// `samply validate`.
+pub fn validate_main(path: &Path) -> Result<(), CliError> {
+    let profile = read_profile(path)?;
+    let violations = validate_profile(&profile);
+    if violations.is_empty() {
+        eprintln!("{path:?} is a valid profile.");
+        return Ok(());
+    }
+    for violation in violations.iter().take(MAX_PRINTED_VIOLATIONS) {
+        println!("{violation}");
+    }
+    if violations.len() > MAX_PRINTED_VIOLATIONS {
+        println!("... and {} more", violations.len() - MAX_PRINTED_VIOLATIONS);
+    }
+    Err(CliError::user_input(format!(
+        "{path:?} violates {} invariants of the processed profile format.",
+        violations.len()
+    )))
+}
+
+/// Checks the profile which a conversion produced, in debug builds, so that
+/// corrupt output fails the tests instead of showing up in the UI.
+#[cfg(debug_assertions)]
+pub fn debug_assert_valid(profile: &fxprof_processed_profile::Profile) {
+    let profile: ProfileJson = serde_json::to_value(profile)
+        .and_then(serde_json::from_value)
+        .expect("the converted profile should deserialize as a ProfileJson");
+    let violations = validate_profile(&profile);
+    debug_assert!(
+        violations.is_empty(),
+        "The converted profile violates {} invariants, e.g.:\n{}",
+        violations.len(),
+        violations
+            .iter()
+            .take(10)
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+}
+
+/// Checks the references between the tables, the order of the timestamps,
+/// the sample timestamps against the thread and process lifetimes, the
+/// marker intervals and the category indexes.
+pub fn validate_profile(profile: &ProfileJson) -> Vec<Violation> {
+    let mut checker = Checker {
+        category_count: profile.meta.categories.len(),
+        violations: Vec::new(),
+    };
+    for (thread_index, thread) in profile.threads.iter().enumerate() {
+        checker.check_thread(
+            &format!("threads[{thread_index}]"),
+            thread,
+            profile.libs.len(),
+        );
+    }
+    for (counter_index, counter) in profile.counters.iter().enumerate() {
+        let location = format!("counters[{counter_index}]");
+        if let Some(thread) = counter.main_thread_index {
+            checker.check_index(
+                || format!("{location}.mainThreadIndex"),
+                thread,
+                "threads",
+                profile.threads.len(),
+            );
+        }
+        for (group_index, group) in counter.sample_groups.iter().enumerate() {
+            let location = format!("{location}.sampleGroups[{group_index}].samples");
+            let samples = &group.samples;
+            checker.check_len(&location, "count", samples.count.len(), samples.time.len());
+            checker.check_ordered(&location, "time", &samples.time);
+        }
+    }
+    checker.violations
+}
+
+struct Checker {
+    category_count: usize,
+    violations: Vec<Violation>,
+}
+
+impl Checker {
+    fn report(&mut self, location: String, message: String) {
+        self.violations.push(Violation { location, message });
+    }
+
+    fn check_index(
+        &mut self,
+        location: impl FnOnce() -> String,
+        index: usize,
+        table: &str,
+        len: usize,
+    ) {
+        if index >= len {
+            self.report(
+                location(),
+                format!("{index} is not a row of the {table}, which has {len} rows"),
+            );
+        }
+    }
+
+    fn check_category(&mut self, location: impl FnOnce() -> String, category: usize) {
+        self.check_index(location, category, "categories", self.category_count);
+    }
+
+    fn check_len(&mut self, table: &str, column: &str, len: usize, expected_len: usize) {
+        if len != expected_len {
+            self.report(
+                format!("{table}.{column}"),
+                format!("has {len} rows, but the table has {expected_len}"),
+            );
+        }
+    }
+
+    fn check_ordered(&mut self, table: &str, column: &str, times: &[f64]) {
+        for (row, pair) in times.windows(2).enumerate() {
+            if pair[1] < pair[0] {
+                self.report(
+                    format!("{table}[{}].{column}", row + 1),
+                    format!("{} is before the previous row's {}", pair[1], pair[0]),
+                );
+            }
+        }
+    }
+
+    fn check_thread(&mut self, location: &str, thread: &ThreadJson, lib_count: usize) {
+        let strings = thread.string_array.len();
+        let resources = thread.resource_table.lib.len();
+        let funcs = thread.func_table.name.len();
+        let frames = thread.frame_table.func.len();
+        let stacks = thread.stack_table.frame.len();
+
+        let table = format!("{location}.resourceTable");
+        for (row, lib) in thread.resource_table.lib.iter().enumerate() {
+            if let Some(lib) = *lib {
+                self.check_index(|| format!("{table}[{row}].lib"), lib, "libs", lib_count);
+            }
+        }
+
+        let table = format!("{location}.funcTable");
+        self.check_len(&table, "resource", thread.func_table.resource.len(), funcs);
+        for (row, &name) in thread.func_table.name.iter().enumerate() {
+            self.check_index(
+                || format!("{table}[{row}].name"),
+                name,
+                "stringArray",
+                strings,
+            );
+        }
+        for (row, &resource) in thread.func_table.resource.iter().enumerate() {
+            match usize::try_from(resource) {
+                Ok(resource) => self.check_index(
+                    || format!("{table}[{row}].resource"),
+                    resource,
+                    "resourceTable",
+                    resources,
+                ),
+                Err(_) if resource == -1 => {}
+                Err(_) => self.report(
+                    format!("{table}[{row}].resource"),
+                    format!("{resource} is neither -1 nor a row of the resourceTable"),
+                ),
+            }
+        }
+
+        let table = format!("{location}.frameTable");
+        self.check_len(&table, "address", thread.frame_table.address.len(), frames);
+        self.check_len(
+            &table,
+            "category",
+            thread.frame_table.category.len(),
+            frames,
+        );
+        for (row, &func) in thread.frame_table.func.iter().enumerate() {
+            self.check_index(|| format!("{table}[{row}].func"), func, "funcTable", funcs);
+        }
+        for (row, category) in thread.frame_table.category.iter().enumerate() {
+            if let Some(category) = *category {
+                self.check_category(|| format!("{table}[{row}].category"), category);
+            }
+        }
+
+        let table = format!("{location}.stackTable");
+        self.check_len(&table, "prefix", thread.stack_table.prefix.len(), stacks);
+        for (row, &frame) in thread.stack_table.frame.iter().enumerate() {
+            self.check_index(
+                || format!("{table}[{row}].frame"),
+                frame,
+                "frameTable",
+                frames,
+            );
+        }
+        for (row, prefix) in thread.stack_table.prefix.iter().enumerate() {
+            // A prefix always comes before the stacks which extend it.
+            match *prefix {
+                Some(prefix) if prefix >= row => self.report(
+                    format!("{table}[{row}].prefix"),
+                    format!("{prefix} is not one of the stacks before this one"),
+                ),
+                _ => {}
+            }
+        }
+        for (row, &category) in thread.stack_table.category.iter().enumerate() {
+            self.check_category(|| format!("{table}[{row}].category"), category);
+        }
+
+        let table = format!("{location}.samples");
+        let samples = &thread.samples;
+        self.check_len(&table, "time", samples.time.len(), samples.stack.len());
+        if let Some(weight) = &samples.weight {
+            self.check_len(&table, "weight", weight.len(), samples.stack.len());
+        }
+        for (row, stack) in samples.stack.iter().enumerate() {
+            if let Some(stack) = *stack {
+                self.check_index(
+                    || format!("{table}[{row}].stack"),
+                    stack,
+                    "stackTable",
+                    stacks,
+                );
+            }
+        }
+        self.check_ordered(&table, "time", &samples.time);
+        let lifetimes = [
+            ("thread", thread.register_time, thread.unregister_time),
+            (
+                "process",
+                thread.process_startup_time,
+                thread.process_shutdown_time,
+            ),
+        ];
+        for (row, &time) in samples.time.iter().enumerate() {
+            for (name, start, end) in lifetimes {
+                if time < start || end.map_or(false, |end| time > end) {
+                    let end = end.map_or_else(|| "the end".to_string(), |end| end.to_string());
+                    self.report(
+                        format!("{table}[{row}].time"),
+                        format!("{time} is outside of the {name}'s lifetime, {start} to {end}"),
+                    );
+                }
+            }
+        }
+
+        self.check_markers(&format!("{location}.markers"), &thread.markers, strings);
+    }
+
+    fn check_markers(&mut self, table: &str, markers: &MarkersJson, strings: usize) {
+        let len = markers.name.len();
+        self.check_len(table, "phase", markers.phase.len(), len);
+        self.check_len(table, "startTime", markers.start_time.len(), len);
+        self.check_len(table, "endTime", markers.end_time.len(), len);
+        for (row, &name) in markers.name.iter().enumerate() {
+            self.check_index(
+                || format!("{table}[{row}].name"),
+                name,
+                "stringArray",
+                strings,
+            );
+        }
+        for (row, &category) in markers.category.iter().enumerate() {
+            self.check_category(|| format!("{table}[{row}].category"), category);
+        }
+        for (row, &phase) in markers.phase.iter().enumerate() {
+            let start = markers.start_time.get(row).copied().flatten();
+            let end = markers.end_time.get(row).copied().flatten();
+            let (needs_start, needs_end) = match phase {
+                0 | 2 => (true, false),
+                1 => (true, true),
+                3 => (false, true),
+                _ => {
+                    self.report(
+                        format!("{table}[{row}].phase"),
+                        format!("{phase} is not a marker phase"),
+                    );
+                    continue;
+                }
+            };
+            if needs_start && start.is_none() {
+                self.report(
+                    format!("{table}[{row}].startTime"),
+                    format!("is missing for phase {phase}"),
+                );
+            }
+            if needs_end && end.is_none() {
+                self.report(
+                    format!("{table}[{row}].endTime"),
+                    format!("is missing for phase {phase}"),
+                );
+            }
+            if let (1, Some(start), Some(end)) = (phase, start, end) {
+                if end < start {
+                    self.report(
+                        format!("{table}[{row}].endTime"),
+                        format!("{end} is before the start time {start}"),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validate(profile: &str) -> Vec<String> {
+        let profile: ProfileJson = serde_json::from_str(profile).unwrap();
+        validate_profile(&profile)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn consistent_tables_are_valid() {
+        let violations = validate(
+            r#"{
+                "meta": { "categories": [{ "name": "Other" }] },
+                "threads": [{
+                    "registerTime": 0, "unregisterTime": 10,
+                    "samples": { "stack": [1, null], "time": [1, 2] },
+                    "markers": {
+                        "name": [0], "category": [0], "phase": [1],
+                        "startTime": [1], "endTime": [3]
+                    },
+                    "stackTable": { "prefix": [null, 0], "frame": [0, 0], "category": [0, 0] },
+                    "frameTable": { "address": [-1], "category": [0], "func": [0] },
+                    "funcTable": { "name": [0], "resource": [-1] },
+                    "resourceTable": { "lib": [] },
+                    "stringArray": ["main"]
+                }]
+            }"#,
+        );
+        assert_eq!(violations, Vec::<String>::new());
+    }
+
+    #[test]
+    fn violations_name_the_table_row_and_value() {
+        let violations = validate(
+            r#"{
+                "meta": { "categories": [{ "name": "Other" }] },
+                "threads": [{
+                    "registerTime": 0, "unregisterTime": 10,
+                    "samples": { "stack": [2, 0], "time": [5, 11] },
+                    "markers": {
+                        "name": [0], "category": [1], "phase": [1],
+                        "startTime": [4], "endTime": [3]
+                    },
+                    "stackTable": { "prefix": [1, null], "frame": [0, 0] },
+                    "frameTable": { "address": [-1], "category": [0], "func": [0] },
+                    "funcTable": { "name": [0], "resource": [-1] },
+                    "resourceTable": { "lib": [] },
+                    "stringArray": ["main"]
+                }],
+                "counters": [{
+                    "name": "Memory", "category": "Memory", "pid": "1", "mainThreadIndex": 1,
+                    "sampleGroups": [{ "samples": { "time": [2, 1], "count": [5, 5] } }]
+                }]
+            }"#,
+        );
+        assert_eq!(
+            violations,
+            vec![
+                "threads[0].stackTable[0].prefix: 1 is not one of the stacks before this one",
+                "threads[0].samples[0].stack: 2 is not a row of the stackTable, which has 2 rows",
+                "threads[0].samples[1].time: 11 is outside of the thread's lifetime, 0 to 10",
+                "threads[0].markers[0].category: 1 is not a row of the categories, which has 1 rows",
+                "threads[0].markers[0].endTime: 3 is before the start time 4",
+                "counters[0].mainThreadIndex: 1 is not a row of the threads, which has 1 rows",
+                "counters[0].sampleGroups[0].samples[1].time: 1 is before the previous row's 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_subcommand_exit_codes() {
+        use crate::cli_error::ErrorKind;
+
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../fixtures/other/ls-linux/ls-profile.json");
+        assert!(validate_main(&fixture).is_ok());
+
+        // The same profile with a sample whose stack doesn't exist.
+        let mut profile: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&fixture).unwrap()).unwrap();
+        profile["threads"][1]["samples"]["stack"][0] = serde_json::json!(1_000_000);
+        let dir = tempfile::tempdir().unwrap();
+        let corrupted = dir.path().join("corrupted.json");
+        std::fs::write(&corrupted, profile.to_string()).unwrap();
+        let err = validate_main(&corrupted).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UserInput);
+        assert_eq!(err.kind().exit_code(), 2);
+        assert!(err
+            .to_string()
+            .ends_with("violates 1 invariants of the processed profile format."));
+    }
+}
@@ -12,6 +12,7 @@ pub struct SymbolManagerConfig {
     pub(crate) respect_nt_symbol_path: bool,
     pub(crate) default_nt_symbol_path: Option<String>,
     pub(crate) breakpad_directories_readonly: Vec<PathBuf>,
+    pub(crate) binaries_directories: Vec<PathBuf>,
     pub(crate) breakpad_servers: Vec<(String, PathBuf)>,
     pub(crate) breakpad_symindex_cache_dir: Option<PathBuf>,
     pub(crate) windows_servers: Vec<(String, PathBuf)>,
@@ -97,6 +98,20 @@ impl SymbolManagerConfig {
         self
     }
 
+    /// Add a directory to search for binaries and debug files by file name.
+    ///
+    /// This is useful if the binaries are no longer present at the paths that
+    /// were recorded in the profile, for example because they were moved to an
+    /// archive directory. Files found in these directories are only used if their
+    /// debug ID / code ID matches. ELF debug files in a `.build-id` subdirectory
+    /// are found as well.
+    ///
+    /// The first-added directory will be searched first.
+    pub fn binaries_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.binaries_directories.push(dir.into());
+        self
+    }
+
     /// Add a server to search for breakpad symbol files, along with a local cache directory.
     ///
     /// This method can be called multiple times; the servers and caches will be tried in the order of those calls.
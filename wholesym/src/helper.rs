@@ -13,7 +13,7 @@ use uuid::Uuid;
 use std::{
     collections::HashMap,
     fs::{self, File},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     pin::Pin,
     sync::{Arc, Mutex},
 };
@@ -614,6 +614,46 @@ impl<'h> FileAndPathHelper<'h> for Helper {
             }
         }
 
+        // Search the user-supplied binaries directories, both for debug files and
+        // for the binaries themselves.
+        for dir in &self.config.binaries_directories {
+            if let Some(CodeId::ElfBuildId(build_id)) = &info.code_id {
+                let build_id = build_id.to_string();
+                if build_id.len() > 2 {
+                    let (two_chars, rest) = build_id.split_at(2);
+                    paths.push(CandidatePathInfo::SingleFile(
+                        WholesymFileLocation::LocalFile(
+                            dir.join(".build-id")
+                                .join(two_chars)
+                                .join(format!("{rest}.debug")),
+                        ),
+                    ));
+                }
+            }
+            // The names come from the profile, so they must not be able to
+            // point outside of the directory.
+            if let Some(debug_name) = info
+                .debug_name
+                .as_ref()
+                .filter(|name| is_single_path_component(name))
+            {
+                paths.push(CandidatePathInfo::SingleFile(
+                    WholesymFileLocation::LocalFile(dir.join(debug_name)),
+                ));
+            }
+            if let Some(name) = info
+                .name
+                .as_ref()
+                .filter(|name| is_single_path_component(name))
+            {
+                if info.debug_name.as_ref() != Some(name) {
+                    paths.push(CandidatePathInfo::SingleFile(
+                        WholesymFileLocation::LocalFile(dir.join(name)),
+                    ));
+                }
+            }
+        }
+
         Ok(paths)
     }
 
@@ -693,6 +733,19 @@ impl<'h> FileAndPathHelper<'h> for Helper {
             }
         }
 
+        // Search the user-supplied binaries directories.
+        if let Some(name) = info
+            .name
+            .as_ref()
+            .filter(|name| is_single_path_component(name))
+        {
+            for dir in &self.config.binaries_directories {
+                paths.push(CandidatePathInfo::SingleFile(
+                    WholesymFileLocation::LocalFile(dir.join(name)),
+                ));
+            }
+        }
+
         Ok(paths)
     }
 
@@ -783,6 +836,16 @@ fn get_dyld_shared_cache_paths(arch: Option<&str>) -> Vec<WholesymFileLocation>
     }
 }
 
+/// Returns whether `name` is a plain file name, which can be joined to a
+/// directory without leaving it: no separators, no `..`, no root or prefix.
+fn is_single_path_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(component)), None) if component == name
+    )
+}
+
 /// Used to filter out files like `jitted-12345-12.so`, to avoid hammering debuginfod servers.
 fn might_be_perf_jit_so_file(info: &LibraryInfo) -> bool {
     matches!(&info.name, Some(name) if name.starts_with("jitted-") && name.ends_with(".so"))
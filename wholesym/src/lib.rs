@@ -149,6 +149,7 @@ mod moria_mac_spotlight;
 mod symbol_manager;
 
 pub use config::SymbolManagerConfig;
+pub use samply_api::{query_asm_api_json_for_code, to_api_file_path};
 pub use samply_api::samply_symbols;
 pub use samply_api::samply_symbols::{
     AddressInfo, CodeId, ElfBuildId, Error, ExternalFileAddressInFileRef, ExternalFileAddressRef,